@@ -13,13 +13,17 @@ use std::env;
 use std::vec::Vec;
 use std::error::Error;
 use std::fs::File;
-use std::io::{Read,Write};
+use std::io::{Cursor,Read,Write};
 use std::thread;
 use std::path::Path;
 use std::process::exit;
 
+use zwreec::backend::attest::Manifest;
+use zwreec::backend::zcode::capabilities::CapabilityReport;
 use zwreec::config;
 use zwreec::config::Config;
+use zwreec::utils::json;
+use zwreec::outputs::{InputMode, OutputTarget, output_target, plan_outputs};
 
 mod logger;
 
@@ -40,9 +44,20 @@ fn short_options() -> getopts::Options {
     let mut opts = getopts::Options::new();
     opts.optflagmulti("v", "verbose", "Be more verbose. Can be used multiple times.");
     opts.optflag("q", "quiet", "Be quiet");
+    opts.optflag("s", "summary", "Print a single summary line with the capabilities the story needs after a successful compile");
     opts.optflag("w", "overwrite", "Overwrite output file if necessary.");
     opts.optflagopt("l", "logfile", "Specify log file (additionally to logging on stderr)", "LOGFILE");
-    opts.optopt("o", "", "Name of the output file", "FILE");
+    opts.optopt("o", "", "Name of the output file, or a directory for the outputs named after the inputs", "FILE");
+    opts.optflag("", "batch", "Compile every INPUT to its own story instead of merging them");
+    opts.optflag("", "create-dirs", "Create the output directory given by -o if it does not exist");
+    opts.optopt("", "emit-ast-json", "Additionally write the AST as JSON to FILE", "FILE");
+    opts.optopt("", "passage-sizes", "Write the bytes of code and strings of every passage to FILE, the largest passage first, as JSON if FILE ends with .json", "FILE");
+    opts.optopt("", "attest", "Compile without the easter egg and debugging functions and write the origin of every routine to FILE, as JSON if FILE ends with .json. Fails if a routine or other bytes of the code are neither part of a passage nor of the runtime", "FILE");
+    opts.optflag("", "compat-report", "List the constructs of INPUT that zwreec does not support instead of compiling");
+    opts.optflag("", "list-lints", "List the ids of all warnings with their default levels for -A, -W and -D and exit");
+    opts.optopt("", "init", "Create a new story project with a main.twee, a snippets.twee and a zwreec.config in DIRECTORY and exit", "DIRECTORY");
+    opts.optflag("", "force-init", "Overwrite the files of an existing project with --init");
+    opts.optopt("", "config", "Read more options from FILE, one option and its value per line, # starts a comment. zwreec --init writes such a file with the defaults", "FILE");
     opts.optflag("h", "help", "Display this help and exit");
     opts.optflag("V", "version", "Display version");
 
@@ -55,7 +70,7 @@ fn short_options() -> getopts::Options {
 fn usage(verbose: bool) {
     let options = short_options();
 
-    let brief = format!("Usage: zwreec [-hV] [-vqwf] [-l [LOGFILE]] [-o OUTPUT] INPUT");
+    let brief = format!("Usage: zwreec [-hV] [-vqswf] [-l [LOGFILE]] [-o OUTPUT] [--batch] INPUT...");
 
     println!("{}", config::zwreec_usage(verbose, options, &brief));
 }
@@ -95,7 +110,7 @@ fn parse_arguments(args: Vec<String>, opts: getopts::Options) -> (getopts::Match
         exit(1);
     }
 
-    let matches = match opts.parse(&args[1..]) {
+    let mut matches = match opts.parse(&args[1..]) {
         Ok(m)  => m,
         Err(f) => {
             // parsing error
@@ -105,6 +120,28 @@ fn parse_arguments(args: Vec<String>, opts: getopts::Options) -> (getopts::Match
         }
     };
 
+    if let Some(path) = matches.opt_str("config") {
+        let mut text = String::new();
+        if let Err(why) = File::open(Path::new(&path)).and_then(|mut file| file.read_to_string(&mut text)) {
+            print_stderr!("Couldn't read the options in {}: {}\n", path, Error::description(&why));
+            exit(1);
+        }
+        let mut args = args[1..].to_vec();
+        args.extend(config::config_file_args(&text).into_iter());
+        matches = match opts.parse(&args) {
+            Ok(m) => m,
+            Err(f) => {
+                print_stderr!("{} in {} or on the command line\n", f.to_string(), path);
+                exit(1);
+            }
+        };
+    }
+
+    if let Err(why) = config::check_lint_ids(&matches) {
+        print_stderr!("{}\n", why);
+        exit(1);
+    }
+
     if matches.opt_present("help") {
         usage(matches.opt_present("verbose"));
         exit(0);
@@ -161,9 +198,23 @@ fn parse_arguments(args: Vec<String>, opts: getopts::Options) -> (getopts::Match
     (matches, cfg)
 }
 
-fn parse_input(matches: &getopts::Matches) -> Option<Box<Read>> {
-    if matches.free.len() == 1 {
-        let path = Path::new(&matches.free[0]);
+/// Opens the inputs of one story. Several inputs are read completely and merged, without any
+/// input the story is read from stdin.
+fn parse_input(inputs: &[String]) -> Option<Box<Read>> {
+    if inputs.len() > 1 {
+        let mut source: Vec<u8> = Vec::new();
+        for input in inputs.iter() {
+            if let Err(why) = File::open(Path::new(input)).and_then(|mut file| file.read_to_end(&mut source)) {
+                error!("Couldn't read {}: {}", input, Error::description(&why));
+                return None;
+            }
+            // the next passage has to start on a new line
+            source.push(b'\n');
+            info!("Read input: {}", input);
+        }
+        Some(Box::new(Cursor::new(source)))
+    } else if inputs.len() == 1 {
+        let path = Path::new(&inputs[0]);
         match File::open(path) {
             Err(why) => {
                 error!("Couldn't open {}: {}",
@@ -184,14 +235,24 @@ fn parse_input(matches: &getopts::Matches) -> Option<Box<Read>> {
     }
 }
 
-fn parse_path<'a>(matches: &'a getopts::Matches) -> Option<String> {
-    let name = matches.opt_str("o").unwrap_or("a.z8".to_string());
-
-    if name == "-" {
-        None
-    } else {
-        Some(name)
+/// Creates the directory of a directory target if `--create-dirs` is given. Returns false if the
+/// directory is missing.
+fn prepare_directory(matches: &getopts::Matches, target: &OutputTarget) -> bool {
+    if let &OutputTarget::Directory(ref directory) = target {
+        let path = Path::new(directory);
+        if !path.is_dir() {
+            if !matches.opt_present("create-dirs") {
+                error!("Output directory {} does not exist. Use '--create-dirs' to create it!", directory);
+                return false;
+            }
+            if let Err(why) = std::fs::create_dir_all(path) {
+                error!("Couldn't create {}: {}", directory, Error::description(&why));
+                return false;
+            }
+            info!("Created output directory {}", directory);
+        }
     }
+    true
 }
 
 fn parse_output(matches: &getopts::Matches, path: Option<String>) -> Option<Box<Write>> {
@@ -246,9 +307,111 @@ fn parse_output(matches: &getopts::Matches, path: Option<String>) -> Option<Box<
     }
 }
 
+fn write_ast_json(cfg: Config, source: &[u8], path: &str) {
+    let source_map = zwreec::frontend::lexer::SourceMap::from_source(source, cfg.tab_width);
+    let ast = zwreec::frontend_ast(cfg, &mut Cursor::new(source));
+    let json = zwreec::frontend::export::ast_to_json_with_source_map(&ast, &source_map);
+
+    match File::create(Path::new(path)).and_then(|mut file| file.write_all(json.as_bytes())) {
+        Err(why) => panic!("Couldn't write AST to {}: {}", path, Error::description(&why)),
+        Ok(_) => info!("Wrote AST to {}", path),
+    }
+}
+
+/// Compiles the input, with `--attest` without optional content and writes the manifest of the
+/// output, with `--passage-sizes` writes the bytes of every passage. Returns the capabilities of
+/// the interpreter the story needs.
+fn compile_input<R: Read, W: Write>(matches: &getopts::Matches, cfg: Config, input: &mut R, output: &mut W) -> CapabilityReport {
+    let sizes_path = matches.opt_str("passage-sizes");
+    let path = match matches.opt_str("attest") {
+        Some(path) => path,
+        None => {
+            let sizes_path = match sizes_path {
+                Some(sizes_path) => sizes_path,
+                None => return zwreec::compile(cfg, input, output)
+            };
+            let manifest = zwreec::compile_with_manifest(cfg, input, output);
+            write_passage_sizes(&manifest, &sizes_path);
+            return manifest.capabilities;
+        }
+    };
+
+    let manifest = zwreec::attest(cfg, input, output);
+    if let Some(sizes_path) = sizes_path {
+        write_passage_sizes(&manifest, &sizes_path);
+    }
+    let text = if path.ends_with(".json") { manifest.to_json() } else { format!("{}", manifest) };
+    match File::create(Path::new(&path)).and_then(|mut file| file.write_all(format!("{}\n", text).as_bytes())) {
+        Err(why) => panic!("Couldn't write manifest to {}: {}", path, Error::description(&why)),
+        Ok(_) => info!("Wrote manifest to {}", path),
+    }
+
+    let unattributed: Vec<String> = manifest.unattributed().iter().map(|entry| format!("{} at {:#x}", entry.name, entry.address)).collect();
+    if !unattributed.is_empty() {
+        error!("{} routine(s) or byte range(s) are neither part of a passage nor of the runtime: {}", unattributed.len(), unattributed.join(", "));
+        panic!("attestation failed");
+    }
+    manifest.capabilities
+}
+
+/// Writes the bytes of every passage of the manifest to `path`, as JSON if it ends with .json.
+fn write_passage_sizes(manifest: &Manifest, path: &str) {
+    let text = if path.ends_with(".json") { format!("{}\n", manifest.passage_sizes_json()) } else { manifest.passage_report() };
+    match File::create(Path::new(path)).and_then(|mut file| file.write_all(text.as_bytes())) {
+        Err(why) => panic!("Couldn't write passage sizes to {}: {}", path, Error::description(&why)),
+        Ok(_) => info!("Wrote passage sizes to {}", path),
+    }
+}
+
+/// Prints the compatibility report of the input and returns the exit code, which is 1 if the
+/// input uses constructs that stop the compilation.
+fn compat_report(cfg: Config, matches: getopts::Matches) -> i32 {
+    match thread::spawn(move || {
+        match parse_input(&matches.free) {
+            Some(mut input) => {
+                let report = zwreec::compat_report(cfg, &mut input);
+                println!("{}", report);
+                report.is_blocking()
+            },
+            None => panic!(MainError::NoInput)
+        }
+    }).join() {
+        Ok(false) => 0,
+        Ok(true) => 1,
+        Err(x) => {
+            if let Some(&MainError::NoInput) = x.downcast_ref::<MainError>() {
+                error!("Missing input!");
+                usage(false);
+            } else {
+                error!("Compatibility report failed");
+            }
+            1
+        }
+    }
+}
+
+/// Creates the project for `--init` and returns the exit code.
+fn init_project(directory: &str, force: bool) -> i32 {
+    match zwreec::scaffold::init_project(Path::new(directory), force) {
+        Ok(files) => {
+            for file in files.iter() {
+                info!("Created {}", file.display());
+            }
+            let title = zwreec::scaffold::project_title(Path::new(directory));
+            println!("Created the story project in {}, build it there with: {}", directory, zwreec::scaffold::build_command(&title));
+            0
+        },
+        Err(why) => {
+            error!("{}", why);
+            1
+        }
+    }
+}
+
 enum MainError {
     NoInput,
     NoOutput,
+    TestCaseFailed,
 }
 
 fn main() {
@@ -258,11 +421,75 @@ fn main() {
         config::zwreec_options(short_options())
     );
 
-    let path = parse_path(&matches);
+    if matches.opt_present("compat-report") {
+        exit(compat_report(cfg, matches));
+    }
+
+    if matches.opt_present("list-lints") {
+        print!("{}", zwreec::utils::diagnostics::lint_list());
+        exit(0);
+    }
+
+    if let Some(directory) = matches.opt_str("init") {
+        exit(init_project(&directory, matches.opt_present("force-init")));
+    }
+
+    let mode = if matches.opt_present("batch") {
+        InputMode::Batch
+    } else if matches.free.len() > 1 {
+        InputMode::Concatenate
+    } else {
+        InputMode::Single
+    };
+    let target = output_target(matches.opt_str("o"));
+    let plan = match plan_outputs(&matches.free, target.clone(), mode) {
+        Ok(plan) => plan,
+        Err(why) => {
+            error!("{}", why);
+            exit(1);
+        }
+    };
+    if !prepare_directory(&matches, &target) {
+        exit(1);
+    }
+
+    let mut code = 0;
+    for (inputs, path) in plan {
+        let result = compile_story(&matches, cfg.clone(), inputs, path);
+        if result > code {
+            code = result;
+        }
+    }
 
+    std::process::exit(code);
+}
+
+/// Compiles the inputs of one story to the output path and returns the exit code.
+fn compile_story(matches: &getopts::Matches, mut cfg: Config, inputs: Vec<String>, path: Option<String>) -> i32 {
+    // the locations of merged inputs do not belong to one file
+    if inputs.len() == 1 {
+        cfg.source_name = Some(inputs[0].clone());
+    }
+
+    // a successful compile only prints the capabilities the story needs, with the files if a
+    // summary was requested
+    let quiet = matches.opt_present("quiet");
+    let error_format = cfg.error_format;
+    let summary = if matches.opt_present("summary") {
+        Some(format!("zwreec: compiled {} to {}",
+                     if inputs.is_empty() { "stdin".to_string() } else { inputs.join(", ") },
+                     path.as_ref().map(|s| &s[..]).unwrap_or("stdout")))
+    } else {
+        None
+    };
+
+    let matches = matches.clone();
     let path_copy = path.clone();
-    let code = match thread::spawn(move || {
-        let mut input = parse_input(&matches);
+    // the clone shares the error count with the config of the compiler thread
+    let errors = cfg.clone();
+    let ignored_before = errors.error_count();
+    match thread::spawn(move || {
+        let mut input = parse_input(&inputs);
         let mut output = parse_output(&matches, path_copy);
 
         debug!("Parsed command line options");
@@ -270,7 +497,11 @@ fn main() {
 
         // call library
         if !cfg.test_cases.is_empty() {
-            zwreec::test_library(cfg, &mut input, &mut output);
+            if let Err(why) = zwreec::test_library(cfg, &mut input, &mut output) {
+                error!("{}", why);
+                panic!(MainError::TestCaseFailed);
+            }
+            None
         } else {
             // unwrap input and output
             let mut _input = match input {
@@ -281,7 +512,19 @@ fn main() {
                 Some(o) => o,
                 None => panic!(MainError::NoOutput)
             };
-            zwreec::compile(cfg, &mut _input, &mut _output);
+            match matches.opt_str("emit-ast-json") {
+                Some(ast_path) => {
+                    // the input is needed twice, so keep it in memory
+                    let mut source = Vec::new();
+                    if let Err(why) = _input.read_to_end(&mut source) {
+                        error!("Couldn't read input: {}", Error::description(&why));
+                        panic!(MainError::NoInput);
+                    }
+                    write_ast_json(cfg.clone(), &source, &ast_path);
+                    Some(compile_input(&matches, cfg, &mut Cursor::new(source), &mut _output))
+                },
+                None => Some(compile_input(&matches, cfg, &mut _input, &mut _output)),
+            }
         }
     }).join() {
         Err(x) => {
@@ -295,7 +538,8 @@ fn main() {
                         &MainError::NoInput => {
                             error!("Missing input!");
                             usage(false);
-                        }
+                        },
+                        &MainError::TestCaseFailed => {}
                     }
                 },
                 None => {
@@ -312,11 +556,24 @@ fn main() {
             };
             1
         },
-        _ => {
+        Ok(capabilities) => {
             info!("Compiler finished");
-            0
+            match (summary, capabilities) {
+                (_, Some(ref capabilities)) if error_format == config::ErrorFormat::Json =>
+                    print_stderr!("{}\n", json::object(&[("capabilities", capabilities.to_json())])),
+                (Some(summary), Some(capabilities)) => print_stderr!("{} ({})\n", summary, capabilities),
+                (Some(summary), None) => print_stderr!("{}\n", summary),
+                (None, Some(capabilities)) => if !quiet { print_stderr!("zwreec: the story {}\n", capabilities) },
+                (None, None) => {}
+            }
+            // the output was written, but --force ignored errors on the way
+            let ignored = errors.error_count() - ignored_before;
+            if ignored > 0 {
+                warn!("Ignored {} error(s), the output may not work as intended", ignored);
+                2
+            } else {
+                0
+            }
         }
-    };
-
-    std::process::exit(code);
+    }
 }
@@ -0,0 +1,430 @@
+//! Lists where every routine of a compiled story comes from.
+//!
+//! The manifest shows that a story holds nothing but its own passages and the documented
+//! runtime. Every routine is attributed to the passage it was generated for or to a component
+//! of the runtime in `RUNTIME_ROUTINES`. A routine that is neither, like the routines of the
+//! easter egg, is unattributed and fails the attestation.
+//!
+//! The code in front of the first routine, which starts the story, belongs to the runtime as
+//! well. The size of a routine covers its own instructions. Behind the routines follow the
+//! zstrings of the string table, the blobs of `--embed` with their index and the source of
+//! `--embed-source`, which are listed as embedded. Besides these only the zero padding in front
+//! of a routine, a string or a blob may be in the file, every other byte up to the end of the
+//! file is listed as unattributed as well. The last line lists the capabilities of the
+//! interpreter the story needs, see `backend::zcode::capabilities`.
+//!
+//! `Manifest::to_json` writes the same entries for tools, like the custom interpreters that
+//! read the blobs of `--embed`. Besides the routines the manifest of a compiled story holds the
+//! bytes of code and strings of every passage, see `Manifest::passage_report`.
+//!
+//! # Example
+//!
+//! ```
+//! # extern crate zwreec;
+//! use std::io::Cursor;
+//!
+//! let cfg = zwreec::config::Config::default_config();
+//! let mut input = Cursor::new("::Start\nHello World".to_string().into_bytes());
+//! let mut output = Cursor::new(Vec::new());
+//!
+//! let manifest = zwreec::attest(cfg, &mut input, &mut output);
+//! assert!(manifest.is_attributed());
+//! assert!(manifest.entries.iter().any(|entry| entry.name == "Start"));
+//! ```
+
+use std::fmt;
+
+use backend::codegen::PassageSize;
+use backend::zcode::capabilities::CapabilityReport;
+use backend::zcode::zfile::{Origin, Zfile};
+use utils::json;
+
+/// The routines of the runtime with the component they belong to.
+pub static RUNTIME_ROUTINES: &'static [(&'static str, &'static str)] = &[
+    ("system_start", "main loop"),
+    ("system_run", "main loop"),
+    ("system_check_links", "main loop"),
+    ("system_check_more", "main loop"),
+    ("system_transition_tick", "main loop"),
+    ("system_add_link", "links"),
+    ("system_follow_link", "links"),
+    ("system_set_onexit", "links"),
+    ("system_print_separator", "links"),
+    ("system_toc", "links"),
+    ("rt_prompt", "input"),
+    ("rt_confirm", "input"),
+    ("rt_at", "timers"),
+    ("rt_at_end", "timers"),
+    ("rt_countdown", "timers"),
+    ("rt_countdown_tick", "timers"),
+    ("print_unicode", "output"),
+    ("print_var", "output"),
+    ("print_char", "output"),
+    ("itoa", "output"),
+    ("bar", "output"),
+    ("malloc_init", "heap"),
+    ("malloc", "heap"),
+    ("mem_free", "heap"),
+    ("rt_collect", "heap"),
+    ("manual_free", "heap"),
+    ("rt_heap_stats", "heap"),
+    ("strcpy", "strings"),
+    ("strcmp", "strings"),
+    ("strcat", "strings"),
+    ("change_case", "strings"),
+    ("substr", "strings"),
+    ("add_types", "types"),
+    ("draw", "arrays"),
+    ("join", "arrays"),
+    ("rt_clear", "arrays"),
+    ("map_get", "maps"),
+    ("map_holds", "maps"),
+    ("print_map", "maps"),
+    ("rt_error", "errors"),
+    ("system_error_run", "errors"),
+];
+
+/// The name of the code in front of the first routine.
+pub static ENTRY_NAME: &'static str = "(entry)";
+
+/// The name of the bytes between the routines that are neither a string nor padding.
+pub static BYTES_NAME: &'static str = "(bytes)";
+
+/// What a routine was attributed to.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Attribution {
+    /// The passage with this name
+    Passage(String),
+
+    /// The component of the runtime
+    Runtime(&'static str),
+
+    /// A blob, the index of the blobs or the source embedded behind the strings
+    Embedded,
+
+    /// Neither a passage nor a documented part of the runtime
+    Unattributed,
+}
+
+/// A routine or an embedded region of the manifest.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ManifestEntry {
+    /// The name of the routine or the embedded region
+    pub name: String,
+
+    /// The address of the routine or the embedded region
+    pub address: u32,
+
+    /// The bytes of the instructions of the routine, or of the embedded region
+    pub size: u32,
+
+    /// Where the routine comes from
+    pub attribution: Attribution,
+}
+
+/// The routines of a compiled story in the order of their addresses.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Manifest {
+    /// Every routine of the story
+    pub entries: Vec<ManifestEntry>,
+
+    /// The capabilities of the interpreter the story needs
+    pub capabilities: CapabilityReport,
+
+    /// The bytes of every passage, the largest first
+    pub passage_sizes: Vec<PassageSize>,
+}
+
+impl Manifest {
+    /// Attributes the routines of a file `end()` was called on.
+    pub fn from_zfile(zfile: &Zfile) -> Manifest {
+        let mut entries: Vec<ManifestEntry> = vec![ManifestEntry {
+            name: ENTRY_NAME.to_string(),
+            address: zfile.program_addr as u32,
+            size: zfile.entry_size,
+            attribution: Attribution::Runtime("main loop"),
+        }];
+        for routine in zfile.routine_origins.iter() {
+            let attribution = match routine.origin {
+                Origin::Passage(ref passage) => Attribution::Passage(passage.clone()),
+                Origin::Runtime => match RUNTIME_ROUTINES.iter().find(|&&(name, _)| name == routine.name) {
+                    Some(&(_, component)) => Attribution::Runtime(component),
+                    None => Attribution::Unattributed
+                },
+                Origin::Unknown => Attribution::Unattributed
+            };
+            entries.push(ManifestEntry {
+                name: routine.name.clone(),
+                address: routine.address,
+                size: routine.size,
+                attribution: attribution,
+            });
+        }
+
+        for &(ref name, address, size) in zfile.embedded_regions.iter() {
+            entries.push(ManifestEntry {
+                name: name.clone(),
+                address: address,
+                size: size,
+                attribution: Attribution::Embedded,
+            });
+        }
+
+        let mut covered: Vec<(u32, u32)> = entries.iter().map(|entry| (entry.address, entry.size)).collect();
+        covered.extend(zfile.high_memory_strings().into_iter());
+        for (address, size) in unattributed_bytes(&zfile.data.bytes, covered, zfile.data.len() as u32) {
+            entries.push(ManifestEntry {
+                name: BYTES_NAME.to_string(),
+                address: address,
+                size: size,
+                attribution: Attribution::Unattributed,
+            });
+        }
+        entries.sort_by(|a, b| a.address.cmp(&b.address));
+
+        Manifest {
+            entries: entries,
+            capabilities: zfile.capability_report(),
+            passage_sizes: vec![],
+        }
+    }
+
+    /// Returns true if every routine comes from a passage or the runtime.
+    pub fn is_attributed(&self) -> bool {
+        self.unattributed().is_empty()
+    }
+
+    /// Returns the routines that come from neither a passage nor the runtime.
+    pub fn unattributed(&self) -> Vec<&ManifestEntry> {
+        self.entries.iter().filter(|entry| entry.attribution == Attribution::Unattributed).collect()
+    }
+
+    /// Returns the bytes of every passage, one line per passage with the total, the bytes of
+    /// code and of strings and the name, the largest passage first.
+    pub fn passage_report(&self) -> String {
+        let mut report = format!("{:>6} {:>6} {:>7} passage\n", "total", "code", "strings");
+        for size in self.passage_sizes.iter() {
+            report.push_str(&format!("{:6} {:6} {:7} {}\n", size.total(), size.code_bytes, size.string_bytes, size.name));
+        }
+        report
+    }
+
+    /// Returns the bytes of every passage as a JSON array, the largest passage first.
+    ///
+    /// Every passage has its `name`, the bytes of `code` and of `strings` and the `total`.
+    pub fn passage_sizes_json(&self) -> String {
+        let passages: Vec<String> = self.passage_sizes.iter().map(|size| json::object(&[
+            ("name", json::string(&size.name)),
+            ("code", size.code_bytes.to_string()),
+            ("strings", size.string_bytes.to_string()),
+            ("total", size.total().to_string()),
+        ])).collect();
+        json::array(&passages)
+    }
+
+    /// Returns the manifest as a JSON object with the `entries`, the `capabilities` and the
+    /// `passages` of `passage_sizes_json`.
+    ///
+    /// Every entry has its `name`, `address`, `size` and `attribution`, which is `passage`,
+    /// `runtime`, `embedded` or `unattributed`. The entries of passages also have the `passage`,
+    /// those of the runtime the `component`.
+    pub fn to_json(&self) -> String {
+        let entries: Vec<String> = self.entries.iter().map(|entry| {
+            let mut members = vec![
+                ("name", json::string(&entry.name)),
+                ("address", entry.address.to_string()),
+                ("size", entry.size.to_string()),
+            ];
+            match entry.attribution {
+                Attribution::Passage(ref passage) => {
+                    members.push(("attribution", json::string("passage")));
+                    members.push(("passage", json::string(passage)));
+                },
+                Attribution::Runtime(component) => {
+                    members.push(("attribution", json::string("runtime")));
+                    members.push(("component", json::string(component)));
+                },
+                Attribution::Embedded => members.push(("attribution", json::string("embedded"))),
+                Attribution::Unattributed => members.push(("attribution", json::string("unattributed"))),
+            }
+            json::object(&members)
+        }).collect();
+        json::object(&[("entries", json::array(&entries)), ("capabilities", self.capabilities.to_json()),
+            ("passages", self.passage_sizes_json())])
+    }
+}
+
+/// Returns the address and the length of the bytes which are not `covered` up to `end`.
+///
+/// The gaps in front of a covered range are padding if they are shorter than 8 bytes and hold
+/// nothing but zeros.
+fn unattributed_bytes(bytes: &[u8], mut covered: Vec<(u32, u32)>, end: u32) -> Vec<(u32, u32)> {
+    covered.sort();
+    let mut gaps: Vec<(u32, u32)> = vec![];
+    let mut position = match covered.first() {
+        Some(&(address, _)) => address,
+        None => return gaps
+    };
+    for (address, size) in covered.into_iter().chain(Some((end, 0))) {
+        if address > position {
+            let padding = address - position < 8 && bytes[position as usize..address as usize].iter().all(|&byte| byte == 0);
+            if !padding {
+                gaps.push((position, address - position));
+            }
+        }
+        if address + size > position {
+            position = address + size;
+        }
+    }
+    gaps
+}
+
+impl fmt::Display for Manifest {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for entry in self.entries.iter() {
+            let attribution = match entry.attribution {
+                Attribution::Passage(ref passage) => format!("passage '{}'", passage),
+                Attribution::Runtime(component) => format!("runtime: {}", component),
+                Attribution::Embedded => "embedded".to_string(),
+                Attribution::Unattributed => "UNATTRIBUTED".to_string(),
+            };
+            try!(writeln!(f, "{:#07x} {:6} {} ({})", entry.address, entry.size, entry.name, attribution));
+        }
+        let unattributed = self.unattributed();
+        let routines = self.entries.iter().filter(|entry| entry.name != BYTES_NAME && entry.attribution != Attribution::Embedded).count();
+        try!(writeln!(f, "{} routines, {} unattributed", routines, unattributed.len()));
+        write!(f, "{}", self.capabilities)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{unattributed_bytes, Attribution, Manifest, BYTES_NAME, ENTRY_NAME, RUNTIME_ROUTINES};
+    use backend::zcode::zfile::{Operand, Origin, ZOP, Zfile};
+
+    /// Writes a file with a passage `Start` and the routines `extra` adds behind it.
+    fn manifest_with<F: Fn(&mut Zfile)>(extra: F) -> Manifest {
+        let mut zfile = Zfile::new();
+        zfile.start();
+        zfile.origin = Origin::Passage("Start".to_string());
+        zfile.emit(vec![
+            ZOP::Routine{name: "Start".to_string(), count_variables: 0},
+            ZOP::Print{text: "Hello".to_string()},
+            ZOP::Ret{value: Operand::new_const(0)}
+        ]);
+        extra(&mut zfile);
+        zfile.end();
+        Manifest::from_zfile(&zfile)
+    }
+
+    #[test]
+    fn test_manifest_attribution() {
+        let manifest = manifest_with(|_| {});
+        assert!(manifest.is_attributed(), "{}", manifest);
+        assert_eq!(manifest.entries[0].name, ENTRY_NAME);
+        let start = manifest.entries.iter().find(|entry| entry.name == "Start").unwrap();
+        assert_eq!(start.attribution, Attribution::Passage("Start".to_string()));
+
+        // every routine ends in front of the next one, at most the padding is between them
+        for pair in manifest.entries.windows(2) {
+            assert!(pair[0].size > 0, "{}", manifest);
+            assert!(pair[0].address + pair[0].size <= pair[1].address && pair[1].address - pair[0].address - pair[0].size < 8, "{}", manifest);
+        }
+    }
+
+    #[test]
+    fn test_manifest_strings_between_routines() {
+        // write_string writes the zstrings used before it behind the routine
+        let manifest = manifest_with(|zfile| {
+            zfile.emit(vec![
+                ZOP::Routine{name: "Cellar".to_string(), count_variables: 0},
+                ZOP::PrintOps{text: "A dark cellar".to_string()},
+                ZOP::Ret{value: Operand::new_const(0)}
+            ]);
+            zfile.write_string("ÄÖÜ");
+        });
+        assert!(manifest.is_attributed(), "{}", manifest);
+    }
+
+    #[test]
+    fn test_manifest_unattributed() {
+        // a routine written without an origin, and one in the runtime that is not documented
+        let manifest = manifest_with(|zfile| {
+            zfile.origin = Origin::Unknown;
+            zfile.emit(vec![ZOP::Routine{name: "hidden".to_string(), count_variables: 0}, ZOP::Quit]);
+            zfile.origin = Origin::Runtime;
+            zfile.emit(vec![ZOP::Routine{name: "undocumented".to_string(), count_variables: 0}, ZOP::Quit]);
+        });
+        let names: Vec<&str> = manifest.unattributed().iter().map(|entry| &entry.name[..]).collect();
+        assert_eq!(names, vec!["hidden", "undocumented"]);
+        assert!(!manifest.is_attributed());
+
+        // the injected routine and the bytes behind it, which the size of no routine covers
+        let manifest = manifest_with(|zfile| zfile.inject_unattributed());
+        let names: Vec<&str> = manifest.unattributed().iter().map(|entry| &entry.name[..]).collect();
+        assert_eq!(names, vec!["injected", BYTES_NAME]);
+        assert!(manifest.unattributed()[1].size >= 4, "{}", manifest);
+    }
+
+    #[test]
+    fn test_runtime_routines_are_listed() {
+        // every option that adds runtime routines, the easter egg is unattributed on purpose
+        let mut zfile = Zfile::new();
+        zfile.easter_egg = false;
+        zfile.oom_collect = true;
+        zfile.heap_stats = true;
+        zfile.transition_pause = true;
+        zfile.error_passage = Some("Error".to_string());
+        zfile.start();
+        for passage in ["Start", "Error"].iter() {
+            zfile.origin = Origin::Passage(passage.to_string());
+            zfile.emit(vec![ZOP::Routine{name: passage.to_string(), count_variables: 0}, ZOP::Ret{value: Operand::new_const(0)}]);
+        }
+        zfile.end();
+
+        let missing: Vec<&str> = zfile.routine_origins.iter()
+            .filter(|routine| routine.origin == Origin::Runtime && !RUNTIME_ROUTINES.iter().any(|&(name, _)| name == routine.name))
+            .map(|routine| &routine.name[..]).collect();
+        assert!(missing.is_empty(), "{:?} are missing in RUNTIME_ROUTINES", missing);
+        for name in ["system_run", "system_error_run", "rt_collect"].iter() {
+            assert!(zfile.routine_origins.iter().any(|routine| routine.name == *name), "{} was not written", name);
+        }
+    }
+
+    #[test]
+    fn test_manifest_embedded() {
+        // the blob and its index behind the strings are embedded, nothing behind them is left over
+        let mut zfile = Zfile::new();
+        zfile.embedded_blobs.push(("map".to_string(), vec![1, 2, 3]));
+        zfile.embedded_source = Some(b"::Start\nHello".to_vec());
+        zfile.start();
+        zfile.origin = Origin::Passage("Start".to_string());
+        zfile.emit(vec![
+            ZOP::Routine{name: "Start".to_string(), count_variables: 0},
+            ZOP::Print{text: "Hello".to_string()},
+            ZOP::Ret{value: Operand::new_const(0)}
+        ]);
+        zfile.end();
+        let manifest = Manifest::from_zfile(&zfile);
+        assert!(manifest.is_attributed(), "{}", manifest);
+        let embedded: Vec<&str> = manifest.entries.iter().filter(|entry| entry.attribution == Attribution::Embedded).map(|entry| &entry.name[..]).collect();
+        assert_eq!(embedded, vec!["map", "(blob index)", "(source)"]);
+        let map = manifest.entries.iter().find(|entry| entry.name == "map").unwrap();
+        assert!(manifest.to_json().contains(&format!(r#"{{"name":"map","address":{},"size":3,"attribution":"embedded"}}"#, map.address)),
+            "{}", manifest.to_json());
+
+        // bytes behind the source are flagged
+        zfile.data.append_bytes(&[0xb2, 0x11, 0xaa, 0x46]);
+        let manifest = Manifest::from_zfile(&zfile);
+        assert_eq!(manifest.unattributed().iter().map(|entry| &entry.name[..]).collect::<Vec<&str>>(), vec![BYTES_NAME]);
+    }
+
+    #[test]
+    fn test_unattributed_bytes() {
+        let bytes = vec![1, 1, 0, 0, 2, 2, 3, 0, 0, 0, 0, 0, 0, 0, 0, 4];
+        // zero padding shorter than 8 bytes, a byte that is not zero and 8 zeros
+        assert_eq!(unattributed_bytes(&bytes, vec![(4, 2), (0, 2), (15, 1)], 16), vec![(6, 9)]);
+        assert_eq!(unattributed_bytes(&bytes, vec![(0, 2), (4, 3), (15, 1)], 16), vec![(7, 8)]);
+        assert_eq!(unattributed_bytes(&bytes, vec![(0, 2), (4, 3), (8, 8)], 16), vec![]);
+    }
+}
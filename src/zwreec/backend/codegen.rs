@@ -8,12 +8,16 @@ use std::collections::{HashMap, HashSet};
 use std::error::Error;
 use std::io::Write;
 
-use backend::zcode::zfile::{Constant, FormattingState, Operand, Variable, ZOP, Zfile, Type};
-use config::Config;
-use frontend::ast::ASTNode;
-use frontend::evaluate_expression::{evaluate_expression, EvaluateExpressionError};
-use frontend::lexer::Token;
+use backend::zcode::peephole;
+use backend::attest::Manifest;
+use backend::zcode::zfile::{Constant, FormattingState, Operand, Origin, Variable, ZOP, Zfile, Type};
+use config::{Config, MainLoop, TestCase};
+use frontend::ast::{ASTNode, NodeDefault};
+use frontend::evaluate_expression::{evaluate_expression, evaluate_expressions, check_divisor, pop_temp_id, static_range, EvaluateExpressionError, ExpressionCache};
+use frontend::lexer::{differently_normalized, normalize_passage_name, Token};
 use frontend::lexer::Token::*;
+use frontend::plugins::MacroCall;
+use utils::diagnostics::{Diagnostics, Warning, WARNINGS};
 
 /// All the errors that can occur during code generation.
 #[derive(Debug)]
@@ -31,9 +35,21 @@ pub enum CodeGenError {
     /// Start passage does not exist
     NoStartPassage,
 
+    /// A passage contains more than one `<<onexit>>` block
+    MultipleOnExit { passage: String, first: (u64, u64), second: (u64, u64) },
+
+    /// A macro argument is not the name of a passage in quotes
+    PassageNameExpected { name: String, index: usize, location: (u64, u64) },
+
     /// Unkown passage was referenced
     PassageDoesNotExist { name: String },
 
+    /// Unknown passage was referenced, but a passage with the name in another normalization form exists
+    DifferentlyNormalizedPassage { name: String, candidate: String },
+
+    /// A displayed passage does not exist in a story with widgets, like a misspelled widget
+    UnknownMacro { name: String, widgets: Vec<String> },
+
     /// Expression not supported
     UnsupportedExpression { token: Token },
 
@@ -57,15 +73,42 @@ pub enum CodeGenError {
 
     /// Symbol could not be found in symbol table
     CouldNotFindSymbolId { id: u8 },
+
+    /// An expression of the passage could not be evaluated
+    CouldNotEvaluateExpression { error: EvaluateExpressionError },
+
+    /// The link table of the passage with the most links does not fit in the heap
+    TooManyLinks { links: u16, capacity: u16 },
+
+    /// There is no global variable left for a variable of the story
+    TooManyVariables { name: String },
+
+    /// A string of high memory starts too far up for a packed address
+    StringBeyondPackedAddresses { text: String, address: u32, version: u8 },
+
+    /// The story is too large for the file length of the header to cover the source behind it
+    SourceDoesNotFit { end: u32, limit: u32 },
+
+    /// A warning denied with `-D`
+    DeniedWarning { id: &'static str, passage: String, message: String },
+}
+
+impl From<EvaluateExpressionError> for CodeGenError {
+    fn from(error: EvaluateExpressionError) -> CodeGenError {
+        CodeGenError::CouldNotEvaluateExpression { error: error }
+    }
 }
 
 /// Create Codegen state and generate Z-Code from the specified AST passage iterator.
 ///
+/// Returns the manifest of the routines in the output, see `backend::attest`.
+///
 /// # Panics
 /// This panics when an error occurs and the `force` option in the config is not set.
-/// It also panics even when that option is set for invalid expressions, missing passages and other
-/// unrecoverable errors.
-pub fn generate_zcode<W: Write, I: Iterator<Item=ASTNode>>(cfg: &Config, ast: I, output: &mut W) {
+/// With that option a passage that fails to compile is replaced by a stub, which prints the
+/// error when the passage is entered. It still panics for missing passages and other errors
+/// outside of single passages.
+pub fn generate_zcode<W: Write, I: Iterator<Item=ASTNode>>(cfg: &Config, ast: I, output: &mut W) -> Manifest {
     info!("Started code generation");
 
     let mut codegenerator = Codegen::new(cfg);
@@ -78,6 +121,127 @@ pub fn generate_zcode<W: Write, I: Iterator<Item=ASTNode>>(cfg: &Config, ast: I,
             info!("Wrote Z-Code to output");
         }
     };
+    let mut manifest = Manifest::from_zfile(&codegenerator.zfile);
+    manifest.passage_sizes = codegenerator.passage_sizes.clone();
+    info!("The story {}", manifest.capabilities);
+    manifest
+}
+
+/// Generates Z-Code from the specified AST passage iterator and returns the decoded text of all
+/// strings in high memory instead of the Z-Code.
+///
+/// See `Zfile::decoded_strings`.
+pub fn generate_strings<I: Iterator<Item=ASTNode>>(cfg: &Config, ast: I) -> Vec<String> {
+    let mut codegenerator = Codegen::new(cfg);
+    codegenerator.start_codegen(ast);
+    codegenerator.zfile.decoded_strings()
+}
+
+/// Generates the instructions of every passage of the AST without writing them to a Z-Code
+/// file.
+///
+/// These are the instructions `generate_zcode` emits for the passages, after the peephole
+/// optimizations, in the order of the passages.
+pub fn generate_ops<I: Iterator<Item=ASTNode>>(cfg: &Config, ast: I) -> Vec<(String, Vec<ZOP>)> {
+    let mut codegenerator = Codegen::new(cfg);
+    codegenerator.zfile.start();
+
+    let mut manager = codegenerator.new_manager();
+    let ast: Vec<ASTNode> = ast.collect();
+    manager.uses_passage_name = uses_function(&ast, "passage");
+    let mut passages: Vec<(String, Vec<ZOP>)> = ast.into_iter().filter_map(|child| codegenerator.passage_ops(child, &mut manager)).collect();
+    if manager.uses_toc {
+        passages.push(("system_toc".to_string(), toc_routine(cfg, &manager.toc_passages)));
+    }
+    manager.validate_passages();
+    manager.warn_lexer_findings();
+    manager.warn_dead_ends();
+    manager.warn_unlinked_passages();
+    manager.warn_missing_map_keys();
+    codegenerator.report_stubbed_passages();
+    manager.diagnostics.report_suppressed();
+    report_denied(cfg, &manager.diagnostics);
+    passages
+}
+
+/// The instructions of a whole story, see `generate_story_ops`.
+pub struct StoryOps {
+    /// Every instruction in the order it was written, from the code in front of the first
+    /// routine to the runtime routines
+    pub ops: Vec<ZOP>,
+
+    /// The Z-Code the instructions were written to
+    pub zcode: Vec<u8>,
+
+    /// The packed address of every label
+    pub labels: Vec<(String, u16)>,
+}
+
+/// Generates Z-Code like `generate_zcode` and returns every instruction written on the way,
+/// including the runtime routines, with the Z-Code.
+///
+/// Tests run the instructions on a simulator, which needs the memory of the Z-Code for the
+/// strings and the heap and the addresses of the routines for the links.
+pub fn generate_story_ops<I: Iterator<Item=ASTNode>>(cfg: &Config, ast: I) -> StoryOps {
+    let mut codegenerator = Codegen::new(cfg);
+    codegenerator.zfile.record_ops();
+    codegenerator.start_codegen(ast);
+    StoryOps {
+        ops: codegenerator.zfile.recorded_ops(),
+        zcode: codegenerator.zfile_bytes().clone(),
+        labels: codegenerator.zfile.label_addresses(),
+    }
+}
+
+/// The number of bytes generated for a single passage.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PassageSize {
+    /// The name of the passage
+    pub name: String,
+
+    /// The bytes of Z-Code emitted for the passage
+    pub code_bytes: usize,
+
+    /// The bytes of all strings first referenced from the passage
+    pub string_bytes: usize,
+}
+
+impl PassageSize {
+    /// Returns the total number of bytes attributable to the passage.
+    pub fn total(&self) -> usize {
+        self.code_bytes + self.string_bytes
+    }
+}
+
+/// Sorts the passage sizes descending, logs them and warns about passages above the
+/// `warn_passage_size` limit of the config.
+///
+/// Returns the names of all passages exceeding the limit, including the ones the warning is
+/// allowed in.
+pub fn report_passage_sizes(cfg: &Config, sizes: &mut Vec<PassageSize>, diagnostics: &mut Diagnostics) -> Vec<String> {
+    sizes.sort_by(|a, b| b.total().cmp(&a.total()));
+
+    let mut too_large: Vec<String> = vec![];
+    for size in sizes.iter() {
+        info!("Passage '{}': {} bytes ({} code, {} strings)", size.name, size.total(), size.code_bytes, size.string_bytes);
+        if cfg.warn_passage_size != 0 && size.total() > cfg.warn_passage_size as usize {
+            diagnostics.warn(Warning::PassageSize, &size.name, None,
+                format!("Passage '{}' needs {} bytes, more than the limit of {} bytes", size.name, size.total(), cfg.warn_passage_size));
+            too_large.push(size.name.clone());
+        }
+    }
+    too_large
+}
+
+/// Reports the warnings denied with `-D` as errors.
+///
+/// # Panics
+/// Panics unless the `force` option is set, like for any other error.
+fn report_denied(cfg: &Config, diagnostics: &Diagnostics) {
+    for diagnostic in diagnostics.denied() {
+        error_panic!(cfg => CodeGenError::DeniedWarning { id: diagnostic.warning.id(), passage: diagnostic.passage.clone(),
+            message: diagnostic.message.clone() });
+    }
 }
 
 /// Code generator state.
@@ -87,7 +251,73 @@ struct Codegen<'a> {
     cfg: &'a Config,
 
     /// The output file
-    zfile: Zfile
+    zfile: Zfile,
+
+    /// The sizes of all generated passages
+    passage_sizes: Vec<PassageSize>,
+
+    /// The links every passage adds and the passages it displays
+    passage_links: Vec<PassageLinks>,
+
+    /// The passages replaced by a stub because they failed to compile
+    stubbed_passages: Vec<String>
+}
+
+/// The links a passage adds to the link table.
+#[derive(Debug, Clone)]
+pub struct PassageLinks {
+    /// The name of the passage
+    pub name: String,
+
+    /// The number of links the passage itself adds, links in every branch of an `<<if>>`
+    /// count
+    pub links: u16,
+
+    /// The routines the passage calls, including the passages of `<<display>>`, whose links
+    /// are added to the same table
+    pub calls: Vec<String>,
+}
+
+impl PassageLinks {
+    /// Counts the links and displays in the instructions of a passage.
+    pub fn from_ops(name: &str, code: &[ZOP]) -> PassageLinks {
+        let mut links = PassageLinks { name: name.to_string(), links: 0, calls: vec![] };
+        for op in code.iter() {
+            match op {
+                &ZOP::Call2NWithAddress{ref jump_to_label, ..} if jump_to_label == "system_add_link" => links.links += 1,
+                &ZOP::Call1N{ref jump_to_label} => links.calls.push(jump_to_label.clone()),
+                _ => {}
+            }
+        }
+        links
+    }
+}
+
+/// Returns the largest number of links a single passage can add, including the links of the
+/// passages it displays.
+///
+/// Calls of routines that are not passages are ignored. A passage that displays itself,
+/// directly or through other passages, is only counted once on the way.
+pub fn max_links(passages: &[PassageLinks]) -> u16 {
+    fn links_of(passages: &[PassageLinks], name: &str, path: &mut Vec<String>) -> u16 {
+        if path.iter().any(|passage| passage == name) {
+            return 0;
+        }
+
+        match passages.iter().find(|passage| passage.name == name) {
+            Some(passage) => {
+                path.push(name.to_string());
+                let links = passage.calls.iter().fold(passage.links, |links, call| {
+                    links.saturating_add(links_of(passages, call, path))
+                });
+                path.pop();
+                links
+            },
+            None => 0
+        }
+    }
+
+    passages.iter().map(|passage| links_of(passages, &passage.name, &mut vec![])).max().unwrap_or(0)
 }
 
 impl<'a> Codegen<'a> {
@@ -95,17 +325,27 @@ impl<'a> Codegen<'a> {
     pub fn new(cfg: &'a Config) -> Codegen<'a> {
         Codegen {
             cfg: cfg,
-            zfile: Zfile::new_with_cfg(cfg)
+            zfile: Zfile::new_with_cfg(cfg),
+            passage_sizes: Vec::new(),
+            passage_links: Vec::new(),
+            stubbed_passages: Vec::new()
         }
     }
 
     /// Starts the code-generation.
     pub fn start_codegen<I: Iterator<Item=ASTNode>>(&mut self, ast: I) {
+        if self.cfg.source_size > 0 {
+            let size = self.zfile.estimated_size(self.cfg.source_size);
+            self.zfile.reserve(size);
+        }
         self.zfile.start();
 
         self.ast_to_zcode(ast);
 
-        self.zfile.op_quit();
+        self.zfile.emit(vec![ZOP::Quit]);
+        if self.cfg.test_cases.contains(&TestCase::InjectUnattributed) {
+            self.zfile.inject_unattributed();
+        }
 
         self.zfile.end();
     }
@@ -115,25 +355,122 @@ impl<'a> Codegen<'a> {
         &self.zfile.data.bytes
     }
 
-    /// Convert AST to Z-Code.
-    pub fn ast_to_zcode<I: Iterator<Item=ASTNode>>(&mut self, ast: I) {
+    /// Creates the manager shared by all passages.
+    fn new_manager(&self) -> CodeGenManager<'a> {
         let mut manager = CodeGenManager::new(self.cfg);
 
         // Insert temp variables for internal calculations
         manager.symbol_table.insert_new_symbol("int0".to_string(), Type::Integer);
+        manager
+    }
+
+    /// Generates the optimized instructions of a passage, without emitting them.
+    ///
+    /// Returns the name of the passage and its instructions or `None` for skipped passages.
+    /// With `force` a passage that fails to compile gets the instructions of `stub_passage`.
+    fn passage_ops(&mut self, child: ASTNode, manager: &mut CodeGenManager<'a>) -> Option<(String, Vec<ZOP>)> {
+        let name = match child.category() {
+            TokPassage { name, .. } => name,
+            _ => String::new()
+        };
+
+        let format_state = manager.format_state;
+        let code = match gen_zcode(child, &mut self.zfile, manager) {
+            Ok(code) => code,
+            Err(error) => {
+                error_panic!(self.cfg => error);
+                manager.abort_passage(format_state);
+                self.stubbed_passages.push(name.clone());
+                stub_passage(&name, &error)
+            }
+        };
+        if code.is_empty() {
+            // skipped passage
+            None
+        } else {
+            Some((name, peephole::inline_constant_stores(code)))
+        }
+    }
+
+    /// Convert AST to Z-Code.
+    pub fn ast_to_zcode<I: Iterator<Item=ASTNode>>(&mut self, ast: I) {
+        let mut manager = self.new_manager();
+        let ast: Vec<ASTNode> = ast.collect();
+        manager.uses_passage_name = uses_function(&ast, "passage");
+
+        for child in ast.into_iter() {
+            let first_string = self.zfile.string_count();
+            let (name, code) = match self.passage_ops(child, &mut manager) {
+                Some(passage) => passage,
+                None => continue
+            };
+            self.passage_links.push(PassageLinks::from_ops(&name, &code));
+            let code_start = self.zfile.data.len();
+            self.zfile.origin = Origin::Passage(name.clone());
+            self.zfile.emit_merging_routines(code);
+
+            self.passage_sizes.push(PassageSize {
+                name: name,
+                code_bytes: self.zfile.data.len() - code_start,
+                string_bytes: self.zfile.string_bytes_since(first_string)
+            });
+        }
 
-        for child in ast {
-            let code = gen_zcode(child, &mut self.zfile, &mut manager);
+        if manager.uses_toc {
+            let code = toc_routine(self.cfg, &manager.toc_passages);
+            self.passage_links.push(PassageLinks::from_ops("system_toc", &code));
+            self.zfile.origin = Origin::Runtime;
             self.zfile.emit(code);
         }
 
+        if self.zfile.merged_routines > 0 {
+            info!("Merged {} routines into identical ones", self.zfile.merged_routines);
+        }
         manager.validate_passages();
+        manager.warn_lexer_findings();
+        manager.warn_dead_ends();
+        manager.warn_unlinked_passages();
+        manager.warn_missing_map_keys();
+        self.report_stubbed_passages();
+        self.zfile.start_passage = manager.start_passage();
+        self.zfile.error_passage = manager.error_passage.clone();
+        let links = max_links(&self.passage_links);
+        if !self.zfile.reserve_links(links) {
+            error_panic!(self.cfg => CodeGenError::TooManyLinks { links: links, capacity: self.zfile.link_capacity });
+        }
+        report_passage_sizes(self.cfg, &mut self.passage_sizes, &mut manager.diagnostics);
+        manager.diagnostics.report_suppressed();
+        report_denied(self.cfg, &manager.diagnostics);
     }
+
+    /// Warns about all passages that were replaced by a stub.
+    fn report_stubbed_passages(&self) {
+        if !self.stubbed_passages.is_empty() {
+            warn!("{} passage(s) failed to compile and only print the error: {}",
+                self.stubbed_passages.len(), self.stubbed_passages.join(", "));
+        }
+    }
+}
+
+/// Returns the instructions of a passage that failed to compile.
+///
+/// The stub keeps the name of the passage, so the links to it still work, and prints the reason
+/// of the failure.
+fn stub_passage(name: &str, error: &CodeGenError) -> Vec<ZOP> {
+    // the last line of an error is the message without the header
+    let message = format!("{}", error);
+    let reason = message.lines().last().unwrap_or("").trim_left_matches("[!!!] ");
+    vec![
+        ZOP::Routine{name: name.to_string(), count_variables: 0},
+        ZOP::PrintOps{text: format!("[[passage '{}' failed to compile: {}]]", name, reason)},
+        ZOP::Newline,
+        ZOP::Ret{value: Operand::new_const(0)}
+    ]
 }
 
 
 /// Generate Z-Code based on the ASTNode and its children.
-pub fn gen_zcode(node: ASTNode, mut out: &mut Zfile, mut manager: &mut CodeGenManager) -> Vec<ZOP> {
+pub fn gen_zcode(node: ASTNode, mut out: &mut Zfile, mut manager: &mut CodeGenManager) -> Result<Vec<ZOP>, CodeGenError> {
     let mut state_copy = manager.format_state.clone();
     let mut set_formatting = false;
     let mut force_skip_childs = false;
@@ -142,27 +479,148 @@ pub fn gen_zcode(node: ASTNode, mut out: &mut Zfile, mut manager: &mut CodeGenMa
     match node {
         ASTNode::Passage(ref node) => {
             let mut code: Vec<ZOP> = vec![];
+            let mut passage_id = 0;
             match &node.category {
                 &TokPassage {ref name, .. } => {
+                    if name == "StoryData" {
+                        manager.story_start = story_data_start(&node.childs);
+                        info!("Skipping passage 'StoryData', start passage: {:?}", manager.story_start);
+                        return Ok(vec![]);
+                    }
+                    if is_special_passage(name, &node.childs) {
+                        info!("Skipping passage '{}', stylesheets and scripts are not supported by Z-Code", name);
+                        return Ok(vec![]);
+                    }
+
+                    manager.current_passage = name.clone();
+                    collect_allows(name, &node.childs, true, &mut manager.diagnostics);
+                    if may_be_dead_end(&node.childs) {
+                        manager.diagnostics.warn(Warning::ConditionalLinks, name, None,
+                            format!("All links of passage '{}' are conditional, it is a dead end if none of them is shown. Add an <<otherwise [[text|Target]]>> link as fallback", name));
+                    }
+                    if cfg.main_loop == MainLoop::Links && !has_tag(&node.childs, "ending") && !has_output_or_links(&node.childs) {
+                        manager.silent_passages.push(name.clone());
+                    }
+
                     manager.visited_passages.insert(name.clone());
+                    if !TOC_EXCLUDED_PASSAGES.contains(&&name[..]) {
+                        manager.toc_passages.push((name.clone(), has_tag(&node.childs, "menu")));
+                    }
+                    if manager.first_passage.is_none() {
+                        manager.first_passage = Some(name.clone());
+                    }
+                    if name == "RuntimeError" || has_tag(&node.childs, "error-handler") {
+                        match manager.error_passage {
+                            Some(ref error_passage) => warn!("Passage '{}' is another error passage, only '{}' is called on runtime errors", name, error_passage),
+                            None => manager.error_passage = Some(name.clone())
+                        }
+                    }
                     code.push(ZOP::Routine{name: name.to_string(), count_variables: 15});
+
+                    let mut hooks: Vec<(u64, u64)> = vec![];
+                    collect_onexit_locations(&node.childs, &mut hooks);
+                    if hooks.len() > 1 {
+                        error_panic!(cfg => CodeGenError::MultipleOnExit { passage: name.clone(), first: hooks[0], second: hooks[1] });
+                    }
+
+                    // remember the exit routine of this passage in global 18,
+                    // unless it was only called through <<display>>
+                    let id = manager.ids_onexit.start_next();
+                    passage_id = id;
+                    let entry_label = format!("passage_entry{}", id);
+                    code.push(ZOP::JE{operand1: Operand::new_var(17), operand2: Operand::new_const(1), jump_to_label: entry_label.to_string()});
+                    if hooks.len() > 0 {
+                        let routine_name = format!("passage_onexit{}", id);
+                        code.push(ZOP::Call2NWithAddress{jump_to_label: "system_set_onexit".to_string(), address: routine_name.to_string()});
+                        manager.onexit_routine = Some(routine_name);
+                    } else {
+                        code.push(ZOP::StoreVariable{variable: Variable::new(18), value: Operand::new_const(0)});
+                        manager.onexit_routine = None;
+                    }
+                    // global 19 holds the name of the current passage for passage()
+                    if manager.uses_passage_name {
+                        code.push(ZOP::StoreVariable{variable: Variable::new(19), value: Operand::new_string_ref(out.write_string(name))});
+                        code.push(ZOP::SetVarType{variable: Variable::new(19), vartype: Type::String});
+                    }
+                    // global 22 tells system_check_links that a passage without links is intended
+                    if has_tag(&node.childs, "ending") {
+                        code.push(ZOP::StoreVariable{variable: Variable::new(22), value: Operand::new_const(1)});
+                    }
+                    // styles of the links or of the previous passage must not carry over,
+                    // a displayed passage keeps the style it is displayed in
+                    let style = default_format_state(cfg);
+                    code.push(ZOP::SetTextStyle{bold: false, reverse: false, monospace: false, italic: false});
+                    if style.bold || style.inverted || style.mono || style.italic {
+                        code.push(ZOP::SetTextStyle{bold: style.bold, reverse: style.inverted, monospace: style.mono, italic: style.italic});
+                    }
+                    code.push(ZOP::Label{name: entry_label});
                 },
                 _ => {
                     error_panic!(cfg => CodeGenError::InvalidAST);
                 }
             };
 
-            for child in node.childs.clone().into_iter() {
-                for instr in gen_zcode(child, out, manager) {
+            manager.is_verbatim = has_tag(&node.childs, "verbatim");
+            manager.newline_run = 0;
+            manager.expression_cache.start_passage(&node.childs);
+            manager.variable_ranges.clear();
+            let mut nested = HashSet::new();
+            manager.nested_assignments = if collect_nested_assignments(&node.childs, true, &mut nested) { Some(nested) } else { None };
+            let childs = if has_tag(&node.childs, "trim") && !manager.is_verbatim {
+                trim_passage(&node.childs)
+            } else {
+                node.childs.clone()
+            };
+            let mut unreachable = false;
+            for child in childs.into_iter() {
+                if unreachable || ends_with_terminal(&code) {
+                    // the routine of an <<onexit>> block is registered at the start of the passage
+                    let category = child.category();
+                    let is_onexit = match category {
+                        TokMacroOnExit { .. } => true,
+                        _ => false
+                    };
+                    if !is_onexit {
+                        if !unreachable && !is_blank_line_content(&category) {
+                            let line = category.location().0;
+                            manager.diagnostics.warn(Warning::UnreachableContent, &manager.current_passage, Some(line),
+                                format!("The content of passage '{}' from line {} on is never shown, the passage is left before it", manager.current_passage, line));
+                            unreachable = true;
+                        }
+                        continue;
+                    }
+                    unreachable = true;
+                }
+                for instr in try!(gen_zcode(child, out, manager)) {
                     code.push(instr);
                 }
             }
 
-            code.push(ZOP::Call1N{jump_to_label: "mem_free".to_string()});
-            code.push(ZOP::Ret{value: Operand::new_const(0)});
-            code
+            // the fallback links are only added if none of the other links was
+            let otherwise_links: Vec<ZOP> = manager.otherwise_links.drain(..).collect();
+            if !otherwise_links.is_empty() {
+                let otherwise_label = format!("passage_otherwise{}", passage_id);
+                let after_otherwise_label = format!("passage_after_otherwise{}", passage_id);
+                code.push(ZOP::JE{operand1: Operand::new_var(16), operand2: Operand::new_const(0), jump_to_label: otherwise_label.to_string()});
+                code.push(ZOP::Jump{jump_to_label: after_otherwise_label.to_string()});
+                code.push(ZOP::Label{name: otherwise_label});
+                code.extend(otherwise_links);
+                code.push(ZOP::Label{name: after_otherwise_label});
+            }
+
+            add_passage_epilogue(&mut code);
+            code.extend(manager.onexit_code.drain(..));
+            Ok(code)
         },
         ASTNode::Default(t) => {
+            let keeps_newline_run = is_blank_line_content(&t.category);
+            if !keeps_newline_run {
+                manager.newline_run = 0;
+            }
+            let straight = runs_straight_through(&t);
+            if !straight {
+                manager.expression_cache.clear();
+            }
             let mut code: Vec<ZOP> = match t.category {
                 TokText {ref text, .. } => {
                     if !manager.is_silent {
@@ -172,13 +630,24 @@ pub fn gen_zcode(node: ASTNode, mut out: &mut Zfile, mut manager: &mut CodeGenMa
                     }
                 },
                 TokNewLine { .. } => {
-                    if !manager.is_silent && !manager.is_nobr {
+                    manager.newline_run += 1;
+                    // more than two line breaks in a row would print more than one blank line
+                    let collapse = cfg.collapse_blank_lines && !manager.is_verbatim &&
+                        !manager.format_state.mono && manager.newline_run > 2;
+                    if !manager.is_silent && !manager.is_nobr && !collapse {
                         vec![ZOP::Newline]
                     } else {
                         vec![]
                     }
 
                 },
+                TokMacroBr { .. } => {
+                    if !manager.is_silent {
+                        vec![ZOP::Newline]
+                    } else {
+                        vec![]
+                    }
+                },
                 TokFormatHorizontalLine { .. } => {
                     if !manager.is_silent && !manager.is_nobr {
                         vec![
@@ -247,7 +716,7 @@ pub fn gen_zcode(node: ASTNode, mut out: &mut Zfile, mut manager: &mut CodeGenMa
                         code.push(ZOP::PrintOps{text: "____".to_string()});
                     }
                     for child in t.childs.clone().into_iter() {
-                        for instr in gen_zcode(child, out, manager) {
+                        for instr in try!(gen_zcode(child, out, manager)) {
                             code.push(instr);
                         }
                     }
@@ -262,7 +731,7 @@ pub fn gen_zcode(node: ASTNode, mut out: &mut Zfile, mut manager: &mut CodeGenMa
                         code.push(ZOP::PrintOps{text: "====".to_string()});
                     }
                     for child in t.childs.clone().into_iter() {
-                        for instr in gen_zcode(child, out, manager) {
+                        for instr in try!(gen_zcode(child, out, manager)) {
                             code.push(instr);
                         }
                     }
@@ -277,7 +746,7 @@ pub fn gen_zcode(node: ASTNode, mut out: &mut Zfile, mut manager: &mut CodeGenMa
                         code.push(ZOP::PrintOps{text: "_{".to_string()});
                     }
                     for child in t.childs.clone().into_iter() {
-                        for instr in gen_zcode(child, out, manager) {
+                        for instr in try!(gen_zcode(child, out, manager)) {
                             code.push(instr);
                         }
                     }
@@ -292,7 +761,7 @@ pub fn gen_zcode(node: ASTNode, mut out: &mut Zfile, mut manager: &mut CodeGenMa
                         code.push(ZOP::PrintOps{text: "^{".to_string()});
                     }
                     for child in t.childs.clone().into_iter() {
-                        for instr in gen_zcode(child, out, manager) {
+                        for instr in try!(gen_zcode(child, out, manager)) {
                             code.push(instr);
                         }
                     }
@@ -305,7 +774,7 @@ pub fn gen_zcode(node: ASTNode, mut out: &mut Zfile, mut manager: &mut CodeGenMa
                     manager.is_silent = true;
                     let mut code: Vec<ZOP> = vec![];
                     for child in t.childs.clone().into_iter() {
-                        for instr in gen_zcode(child, out, manager) {
+                        for instr in try!(gen_zcode(child, out, manager)) {
                             code.push(instr);
                         }
                     }
@@ -319,7 +788,7 @@ pub fn gen_zcode(node: ASTNode, mut out: &mut Zfile, mut manager: &mut CodeGenMa
                     manager.is_nobr = true;
                     let mut code: Vec<ZOP> = vec![];
                     for child in t.childs.clone().into_iter() {
-                        for instr in gen_zcode(child, out, manager) {
+                        for instr in try!(gen_zcode(child, out, manager)) {
                             code.push(instr);
                         }
                     }
@@ -329,6 +798,144 @@ pub fn gen_zcode(node: ASTNode, mut out: &mut Zfile, mut manager: &mut CodeGenMa
                     manager.is_nobr = false;
                     vec![]
                 },
+                TokMacroOnExit { .. } => {
+                    let mut code: Vec<ZOP> = vec![];
+
+                    // only the first block of a passage gets a routine, it is emitted after
+                    // the routine of the passage
+                    if let Some(routine_name) = manager.onexit_routine.take() {
+                        code.push(ZOP::Routine{name: routine_name, count_variables: 15});
+                        for child in t.childs.clone().into_iter() {
+                            for instr in try!(gen_zcode(child, out, manager)) {
+                                code.push(instr);
+                            }
+                        }
+                        code.push(ZOP::Call1N{jump_to_label: "mem_free".to_string()});
+                        code.push(ZOP::Ret{value: Operand::new_const(0)});
+                        manager.onexit_code = code;
+                    }
+                    vec![]
+                },
+                TokMacroEndOnExit { .. } => {
+                    vec![]
+                },
+                TokMacroOtherwise { .. } => {
+                    // the link is generated here, but added at the end of the passage
+                    for child in t.childs.clone().into_iter() {
+                        let link = try!(gen_zcode(child, out, manager));
+                        manager.otherwise_links.extend(link);
+                    }
+                    vec![]
+                },
+                TokMacroAt { .. } => {
+                    if t.childs.len() < 2 {
+                        return Err(CodeGenError::InvalidAST);
+                    }
+
+                    let mut code: Vec<ZOP> = vec![];
+                    let row = try!(evaluate_expression(t.childs[0].clone().as_default().childs[0].clone(), &mut code, manager, &mut out));
+                    let col = try!(evaluate_expression(t.childs[1].clone().as_default().childs[0].clone(), &mut code, manager, &mut out));
+                    code.push(ZOP::CallVNA2{jump_to_label: "rt_at".to_string(), arg1: row, arg2: col});
+                    // the content ends with <<endat>>, which restores the cursor
+                    for child in t.childs[2..].to_vec().into_iter() {
+                        for instr in try!(gen_zcode(child, out, manager)) {
+                            code.push(instr);
+                        }
+                    }
+                    code
+                },
+                TokMacroEndAt { .. } => {
+                    vec![ZOP::Call1N{jump_to_label: "rt_at_end".to_string()}]
+                },
+                TokMacroCountdown { location } => {
+                    if t.childs.len() < 2 || t.childs.len() > 3 {
+                        return Err(CodeGenError::InvalidAST);
+                    }
+
+                    let mut passages: Vec<String> = vec![];
+                    for (index, child) in t.childs[1..].iter().enumerate() {
+                        match child.clone().as_default().childs[0] {
+                            ASTNode::Default(NodeDefault { category: TokString { ref value, .. }, .. }) => passages.push(normalize_passage_name(value)),
+                            _ => return Err(CodeGenError::PassageNameExpected { name: "countdown".to_string(), index: index + 2, location: location })
+                        }
+                    }
+                    manager.required_passages.extend(passages.iter().cloned());
+
+                    // the passage is left like through a chosen link, with its links dropped,
+                    // the screen cleared and its <<onexit>> routine called
+                    let mut code: Vec<ZOP> = vec![];
+                    let seconds = try!(evaluate_expression(t.childs[0].clone().as_default().childs[0].clone(), &mut code, manager, &mut out));
+                    if passages.len() > 1 {
+                        // rt_countdown returns 1 if a key cancelled the countdown
+                        let cancel_label = format!("countdown_cancel{}", manager.ids_expr.start_next());
+                        code.push(ZOP::CallVSA2{jump_to_label: "rt_countdown".to_string(), arg1: seconds, arg2: Operand::new_const(1), result: Variable::new(0)});
+                        code.push(ZOP::JE{operand1: Operand::new_var(0), operand2: Operand::new_const(1), jump_to_label: cancel_label.to_string()});
+                        code.push(ZOP::Call1N{jump_to_label: "mem_free".to_string()});
+                        code.push(ZOP::Call2NWithAddress{jump_to_label: "system_follow_link".to_string(), address: passages[0].to_string()});
+                        code.push(ZOP::Ret{value: Operand::new_const(0)});
+                        code.push(ZOP::Label{name: cancel_label});
+                        code.push(ZOP::Call1N{jump_to_label: "mem_free".to_string()});
+                        code.push(ZOP::Call2NWithAddress{jump_to_label: "system_follow_link".to_string(), address: passages[1].to_string()});
+                    } else {
+                        code.push(ZOP::CallVNA2{jump_to_label: "rt_countdown".to_string(), arg1: seconds, arg2: Operand::new_const(0)});
+                        code.push(ZOP::Call1N{jump_to_label: "mem_free".to_string()});
+                        code.push(ZOP::Call2NWithAddress{jump_to_label: "system_follow_link".to_string(), address: passages[0].to_string()});
+                    }
+                    code.push(ZOP::Ret{value: Operand::new_const(0)});
+                    code
+                },
+                TokTag { .. } => {
+                    vec![]
+                },
+                TokPrecondition { .. } => {
+                    // [requires: condition -> Target] enters the target instead of the passage,
+                    // if the condition is false
+                    if t.childs.len() != 2 {
+                        return Err(CodeGenError::InvalidAST);
+                    }
+                    let target = match t.childs[1].category() {
+                        TokPreconditionTarget { passage_name, .. } => passage_name,
+                        _ => return Err(CodeGenError::InvalidAST)
+                    };
+                    manager.required_passages.push(target.clone());
+
+                    let mut code: Vec<ZOP> = vec![];
+                    let expression = t.childs[0].clone().as_default();
+                    let result = try!(evaluate_expression(expression.childs[0].clone(), &mut code, manager, &mut out));
+
+                    // a constant condition is either always met or always redirects
+                    let constant = if result.is_const() { Some(result.const_value() != 0) } else { None };
+                    if constant != Some(true) {
+                        let met_label = format!("precondition_met{}", manager.ids_precondition.start_next());
+                        if constant.is_none() {
+                            code.push(ZOP::JNE{operand1: result, operand2: Operand::new_const(0), jump_to_label: met_label.to_string()});
+                        }
+                        code.push(ZOP::Call1N{jump_to_label: "mem_free".to_string()});
+                        code.push(ZOP::Call1N{jump_to_label: target.to_string()});
+                        code.push(ZOP::Ret{value: Operand::new_const(0)});
+                        if constant.is_none() {
+                            code.push(ZOP::Label{name: met_label});
+                        }
+                    }
+                    code
+                },
+                TokPreconditionTarget { .. } => {
+                    vec![]
+                },
+                TokMacroAllow { .. } => {
+                    // registered by collect_allows before the passage was generated
+                    vec![]
+                },
+                TokMacroToc { .. } => {
+                    if !manager.is_silent {
+                        // the passages are only known at the end, see toc_routine
+                        set_formatting = true;
+                        manager.uses_toc = true;
+                        vec![ZOP::Call1N{jump_to_label: "system_toc".to_string()}]
+                    } else {
+                        vec![]
+                    }
+                },
                 TokPassageLink {ref display_name, ref passage_name, .. } => {
                     if !manager.is_silent {
                         set_formatting = true;
@@ -343,7 +950,7 @@ pub fn gen_zcode(node: ASTNode, mut out: &mut Zfile, mut manager: &mut CodeGenMa
                             code.push(ZOP::Jump{jump_to_label: continue_label.to_string()});
                             code.push(ZOP::Routine{name: routine_name.to_string(), count_variables: 15});
                             for child in t.childs.clone().into_iter() {
-                                for zop in gen_zcode(child, out, manager).into_iter() {
+                                for zop in try!(gen_zcode(child, out, manager)).into_iter() {
                                     code.push(zop);
                                 }
                             }
@@ -358,34 +965,30 @@ pub fn gen_zcode(node: ASTNode, mut out: &mut Zfile, mut manager: &mut CodeGenMa
                             code.push(ZOP::Call2NWithAddress{jump_to_label: "system_add_link".to_string(), address: passage_name.to_string()});
                         }
 
-                        let foreground: u8 = if manager.cfg.bright_mode { 2 } else { 9 };
-                        let background: u8 = if manager.cfg.bright_mode { 9 } else { 2 };
-                        let link_color: u8 = if manager.cfg.bright_mode { 6 } else { 8 };
-
-                        code.push(ZOP::SetColor{foreground: link_color, background: background});
-                        code.push(ZOP::PrintOps{text: format!("{}[", display_name)});
-                        code.push(ZOP::PrintNumVar{variable: Variable::new(16)});
-                        code.push(ZOP::Print{text: "]".to_string()});
-                        code.push(ZOP::SetColor{foreground: foreground, background: background});
-
+                        code.extend(link_label(manager.cfg, display_name));
                         code
                     } else {
                         vec![]
                     }
                 },
-                TokAssign {var_name, op_name, .. } => {
+                TokAssign {var_name, op_name, location } => {
                     let mut code: Vec<ZOP> = vec![];
                     if t.childs.len() != 1 {
-                        return vec![];
+                        return Ok(vec![]);
                     }
                     let expression_node = t.childs[0].clone().as_default();
+                    let range = assigned_range(&var_name, &op_name, location, &expression_node, manager);
+                    let reshuffle = match expression_node.childs.get(0).map(|child| child.category()) {
+                        Some(TokFunction{ref name, ..}) => name == "shuffle" && (op_name == "=" || op_name == "to"),
+                        _ => false
+                    };
                     let result = match expression_node.category {
                         TokExpression => {
                             if expression_node.childs.len() != 1 {
                                 error_panic!(cfg => CodeGenError::UnsupportedExpression { token: expression_node.category.clone() } );
                             }
-                            evaluate_expression(expression_node.childs[0].clone(), &mut code, manager, &mut out)
-                        }, _ => error_force_panic!(CodeGenError::UnsupportedExpression { token: expression_node.category.clone() } )
+                            try!(evaluate_expression(expression_node.childs[0].clone(), &mut code, manager, &mut out))
+                        }, _ => return Err(CodeGenError::UnsupportedExpression { token: expression_node.category.clone() })
                     };
                     if !manager.symbol_table.is_known_symbol(&var_name) {
                         let vartype = match result {
@@ -397,6 +1000,11 @@ pub fn gen_zcode(node: ASTNode, mut out: &mut Zfile, mut manager: &mut CodeGenMa
                         manager.symbol_table.insert_new_symbol(var_name.clone(), vartype);
                     }
                     let symbol_id = manager.symbol_table.get_symbol_id(&var_name);
+                    manager.expression_cache.invalidate(&var_name);
+                    match range {
+                        Some(range) => { manager.variable_ranges.insert(var_name.clone(), range); },
+                        None => { manager.variable_ranges.remove(&var_name); }
+                    }
                     match &*op_name {
                         "=" | "to" => { code.push(ZOP::StoreVariable{variable: symbol_id.clone(), value: result.clone()});
                                         code.push(ZOP::CopyVarType{variable: symbol_id.clone(), from: result});
@@ -413,11 +1021,24 @@ pub fn gen_zcode(node: ASTNode, mut out: &mut Zfile, mut manager: &mut CodeGenMa
                                   code.push(ZOP::SetVarType{variable: Variable::new(symbol_id.id), vartype: Type::Integer}); },
                         "*=" => { code.push(ZOP::Mul{operand1: Operand::new_var(symbol_id.id), operand2: result, save_variable: symbol_id.clone()});
                                   code.push(ZOP::SetVarType{variable: Variable::new(symbol_id.id), vartype: Type::Integer}); },
-                        "/=" =>  {code.push(ZOP::Div{operand1: Operand::new_var(symbol_id.id), operand2: result, save_variable: symbol_id.clone()});
+                        "/=" =>  {check_divisor(&result, &mut code, manager, &mut out);
+                                  code.push(ZOP::Div{operand1: Operand::new_var(symbol_id.id), operand2: result, save_variable: symbol_id.clone()});
                                   code.push(ZOP::SetVarType{variable: Variable::new(symbol_id.id), vartype: Type::Integer}); },
                         _ => {}
                     };
 
+                    // the old deck is garbage once the new one is stored, free it before the next reshuffle
+                    if reshuffle {
+                        manager.expression_cache.clear();
+                        code.push(ZOP::Call1N{jump_to_label: "mem_free".to_string()});
+                    }
+
+                    // no temporary value is alive behind an assignment, besides the kept ones
+                    if cfg.oom_collect {
+                        manager.expression_cache.clear();
+                        code.push(ZOP::Call1N{jump_to_label: "rt_collect".to_string()});
+                    }
+
                     code
                 },
                 TokMacroIf { .. } => {
@@ -430,35 +1051,49 @@ pub fn gen_zcode(node: ASTNode, mut out: &mut Zfile, mut manager: &mut CodeGenMa
                     let expression_node = match default.category {
                         TokExpression => default,
                         _ =>  {
-                            error_force_panic!(CodeGenError::UnsupportedIfExpression { token: t.category.clone() } );
+                            return Err(CodeGenError::UnsupportedIfExpression { token: t.category.clone() });
                         }
                     };
 
                     let mut code: Vec<ZOP> = vec![];
 
                     // Evaluate the contained expression
-                    let result = evaluate_expression(expression_node.childs[0].clone(), &mut code, manager, &mut out);
+                    let result = try!(evaluate_expression(expression_node.childs[0].clone(), &mut code, manager, &mut out));
 
                     let if_id = manager.ids_if.start_next();
                     let if_label = format!("if_{}", if_id);
                     let after_if_label = format!("after_if_{}", if_id);
                     let after_else_label = format!("after_else_{}", if_id);
-                    code.push(ZOP::JNE{operand1: result, operand2: Operand::new_const(0), jump_to_label: if_label.to_string()});
-                    code.push(ZOP::Jump{jump_to_label: after_if_label.to_string()});
-                    code.push(ZOP::Label{name: if_label.to_string()});
-
-                    let mut childs = t.childs.clone();
-                    childs.remove(0);
-                    for child in childs.into_iter() {
-                        for instr in gen_zcode(child, out, manager) {
-                            code.push(instr);
+
+                    // a constant condition only compiles the taken branch, the others are left out
+                    let constant = if result.is_const() { Some(result.const_value() != 0) } else { None };
+                    manager.static_ifs.push(constant == Some(true));
+                    if constant != Some(false) {
+                        if constant.is_none() {
+                            code.push(ZOP::JNE{operand1: result, operand2: Operand::new_const(0), jump_to_label: if_label.to_string()});
+                            code.push(ZOP::Jump{jump_to_label: after_if_label.to_string()});
+                            code.push(ZOP::Label{name: if_label.to_string()});
+                        }
+
+                        let mut childs = t.childs.clone();
+                        childs.remove(0);
+                        for child in childs.into_iter() {
+                            for instr in try!(gen_zcode(child, out, manager)) {
+                                code.push(instr);
+                            }
                         }
-                    }
 
-                    code.push(ZOP::Jump{jump_to_label: after_else_label});
-                    code.push(ZOP::Label{name: after_if_label});
+                        if constant.is_none() {
+                            code.push(ZOP::Jump{jump_to_label: after_else_label});
+                            code.push(ZOP::Label{name: after_if_label});
+                        }
+                    }
                     code
                 },
+                TokMacroElseIf { .. } | TokMacroElse { .. } if manager.static_ifs.last() == Some(&true) => {
+                    // an earlier branch is always taken
+                    vec![]
+                },
                 TokMacroElseIf { .. } => {
                     if t.childs.len() < 2 {
                         error_panic!(cfg => CodeGenError::UnsupportedElseIfExpression { token: t.category.clone() } );
@@ -471,52 +1106,92 @@ pub fn gen_zcode(node: ASTNode, mut out: &mut Zfile, mut manager: &mut CodeGenMa
                     let expression_node = match default.category {
                         TokExpression => default,
                         _ => {
-                            error_force_panic!(CodeGenError::UnsupportedElseIfExpression { token: t.category.clone() } );
+                            return Err(CodeGenError::UnsupportedElseIfExpression { token: t.category.clone() });
                         }
                     };
 
                     // Evaluate the contained expression
-                    let result = evaluate_expression(expression_node.childs[0].clone(), &mut code, manager, &mut out);
+                    let result = try!(evaluate_expression(expression_node.childs[0].clone(), &mut code, manager, &mut out));
 
                     let if_id = manager.ids_if.start_next();
 
                     let if_label = format!("if_{}", if_id);
                     let after_if_label = format!("after_if_{}", manager.ids_if.pop_id());
                     let after_else_label = format!("after_else_{}", manager.ids_if.peek());
-                    code.push(ZOP::JNE{operand1: result, operand2: Operand::new_const(0), jump_to_label: if_label.to_string()});
-                    code.push(ZOP::Jump{jump_to_label: after_if_label.to_string()});
-                    code.push(ZOP::Label{name: if_label.to_string()});
-
-                    let mut childs = t.childs.clone();
-                    childs.remove(0);
-                    for child in childs.into_iter() {
-                        for instr in gen_zcode(child, out, manager) {
-                            code.push(instr);
+
+                    let constant = if result.is_const() { Some(result.const_value() != 0) } else { None };
+                    if constant != Some(false) {
+                        if constant == Some(true) {
+                            if let Some(taken) = manager.static_ifs.last_mut() {
+                                *taken = true;
+                            }
+                        } else {
+                            code.push(ZOP::JNE{operand1: result, operand2: Operand::new_const(0), jump_to_label: if_label.to_string()});
+                            code.push(ZOP::Jump{jump_to_label: after_if_label.to_string()});
+                            code.push(ZOP::Label{name: if_label.to_string()});
+                        }
+
+                        let mut childs = t.childs.clone();
+                        childs.remove(0);
+                        for child in childs.into_iter() {
+                            for instr in try!(gen_zcode(child, out, manager)) {
+                                code.push(instr);
+                            }
                         }
-                    }
 
-                    code.push(ZOP::Jump{jump_to_label: after_else_label});
-                    code.push(ZOP::Label{name: after_if_label});
+                        if constant.is_none() {
+                            code.push(ZOP::Jump{jump_to_label: after_else_label});
+                            code.push(ZOP::Label{name: after_if_label});
+                        }
+                    }
                     code
                 },
                 TokMacroElse { .. } => {
                     let mut code: Vec<ZOP> = vec![];
                     for child in t.childs.clone().into_iter() {
-                        for instr in gen_zcode(child, out, manager) {
+                        for instr in try!(gen_zcode(child, out, manager)) {
                             code.push(instr);
                         }
                     }
                     code
                 },
                 TokMacroEndIf { .. } => {
+                    manager.static_ifs.pop();
                     let after_else_label = format!("after_else_{}", manager.ids_if.pop_id());
                     vec![ZOP::Label{name: after_else_label}]
                 },
 
+                TokMacroPlugin {ref name, location} => {
+                    let handler = match cfg.macros.get(name) {
+                        Some(handler) => handler,
+                        None => return Err(CodeGenError::NoMatch { token: t.category.clone() }),
+                    };
+
+                    // the arguments are evaluated in order and keep their temporaries until the
+                    // handler emits the code of the macro
+                    let mut code: Vec<ZOP> = vec![];
+                    let mut nodes: Vec<ASTNode> = vec![];
+                    for child in t.childs.iter() {
+                        let child = child.clone().as_default();
+                        match child.category {
+                            TokExpression => nodes.push(child.childs[0].clone()),
+                            _ => {
+                                error_panic!(cfg => CodeGenError::UnsupportedExpression { token: child.category.clone() } );
+                            }
+                        }
+                    }
+                    let (args, mut free_ids) = try!(evaluate_expressions(nodes, &mut code, manager, &mut out));
+                    let temp = Variable::new(try!(pop_temp_id(&mut free_ids)));
+                    let id = manager.ids_expr.start_next();
+                    let call = MacroCall { name: name.clone(), location: location, args: args, silent: manager.is_silent, id: id, temp: temp };
+                    code.extend(handler(&call));
+                    code
+                },
                 TokMacroDisplay {ref passage_name, .. } => {
                     let var = Variable::new(17);
 
                     manager.required_passages.push(passage_name.clone());
+                    manager.displayed_passages.push(passage_name.clone());
 
                     vec![
                     // activates the display-mode
@@ -529,7 +1204,7 @@ pub fn gen_zcode(node: ASTNode, mut out: &mut Zfile, mut manager: &mut CodeGenMa
                 },
                 TokMacroPrint { .. } => {
                     if t.childs.len() != 1 {
-                        error_force_panic!(CodeGenError::UnsupportedLongExpression { name: "print".to_string(), token: t.category.clone() });
+                        return Err(CodeGenError::UnsupportedLongExpression { name: "print".to_string(), token: t.category.clone() });
                     }
 
                     let mut code: Vec<ZOP> = vec![];
@@ -539,10 +1214,17 @@ pub fn gen_zcode(node: ASTNode, mut out: &mut Zfile, mut manager: &mut CodeGenMa
 
                         match child.category {
                             TokExpression => {
-                                let eval = evaluate_expression(child.childs[0].clone(), &mut code, manager, &mut out);
+                                let range = static_range(&child.childs[0], &manager.variable_ranges);
+                                let eval = try!(evaluate_expression(child.childs[0].clone(), &mut code, manager, &mut out));
                                 match eval {
-                                    Operand::Var(var) => code.push(ZOP::PrintVar{variable: var}),
-                                    Operand::StringRef(addr) => code.push(ZOP::PrintUnicodeStr{address: Operand::new_large_const(addr.value)}),
+                                    // a bounded expression is always an integer, so the type lookup of print_var can be skipped
+                                    Operand::Var(var) => if range.is_some() {
+                                        code.push(ZOP::PrintNumVar{variable: var})
+                                    } else {
+                                        code.push(ZOP::PrintVar{variable: var})
+                                    },
+                                    Operand::StringRef(addr) => code.push(ZOP::PrintUnicodeStr{address: Operand::new_pointer(addr.value)}),
+                                    Operand::Pointer(addr) => code.push(ZOP::Print{text: format!("{}", addr.value)}),
                                     Operand::Const(c) => code.push(ZOP::Print{text: format!("{}", c.value)}),
                                     Operand::LargeConst(c) => code.push(ZOP::Print{text: format!("{}", c.value)}),
                                     Operand::BoolConst(c) => if c.value == 0 { code.push(ZOP::Print{text: "false".to_string()}); } else { code.push(ZOP::Print{text: "true".to_string()}); } ,
@@ -555,6 +1237,46 @@ pub fn gen_zcode(node: ASTNode, mut out: &mut Zfile, mut manager: &mut CodeGenMa
                     }
                     code
                 },
+                TokMacroConfirm { location } => {
+                    if t.childs.len() != 1 {
+                        return Err(CodeGenError::UnsupportedLongExpression { name: "confirm".to_string(), token: t.category.clone() });
+                    }
+
+                    // <<confirm "question">> is a statement for pause_confirm("question")
+                    let mut code: Vec<ZOP> = vec![];
+                    if !manager.is_silent {
+                        let function = ASTNode::Default(NodeDefault {
+                            category: TokFunction { location: location, name: "pause_confirm".to_string() },
+                            childs: t.childs.clone()
+                        });
+                        try!(evaluate_expression(function, &mut code, manager, &mut out));
+                    }
+                    code
+                },
+                TokMacroClear { .. } => {
+                    if t.childs.len() != 1 {
+                        return Err(CodeGenError::UnsupportedLongExpression { name: "clear".to_string(), token: t.category.clone() });
+                    }
+
+                    // the array is cleared even in silent passages, only its values change
+                    let mut code: Vec<ZOP> = vec![];
+                    let child = t.childs[0].clone().as_default();
+                    match child.category {
+                        TokExpression => {
+                            match try!(evaluate_expression(child.childs[0].clone(), &mut code, manager, &mut out)) {
+                                Operand::Var(var) => {
+                                    let varid = Operand::new_const(var.id);
+                                    code.push(ZOP::CallVNA2{jump_to_label: "rt_clear".to_string(), arg1: Operand::Var(var), arg2: varid});
+                                },
+                                _ => return Err(CodeGenError::UnsupportedExpressionType { name: "clear".to_string() }),
+                            }
+                        },
+                        _ => {
+                            error_panic!(cfg => CodeGenError::UnsupportedExpression { token: child.category.clone() } );
+                        }
+                    };
+                    code
+                },
                 TokMacroContentVar {var_name, .. } => {
                     let var_id = manager.symbol_table.get_and_add_symbol_id(var_name);
                     vec![ZOP::PrintVar{variable: var_id}]
@@ -567,7 +1289,7 @@ pub fn gen_zcode(node: ASTNode, mut out: &mut Zfile, mut manager: &mut CodeGenMa
             if set_formatting {
                 if !force_skip_childs {
                     for child in t.childs.clone().into_iter() {
-                        for instr in gen_zcode(child, out, manager) {
+                        for instr in try!(gen_zcode(child, out, manager)) {
                             code.push(instr);
                         }
                     }
@@ -576,32 +1298,454 @@ pub fn gen_zcode(node: ASTNode, mut out: &mut Zfile, mut manager: &mut CodeGenMa
                 let state = manager.format_state;
                 code.push(ZOP::SetTextStyle{bold: state.bold, reverse: state.inverted, monospace: state.mono, italic: state.italic});
             }
-            code
+            // line breaks inside of a macro do not continue outside of it
+            if !keeps_newline_run {
+                manager.newline_run = 0;
+            }
+            if !straight {
+                manager.expression_cache.clear();
+            }
+            Ok(code)
         }
     }
 }
 
-/// This generates code for the function `random(from, to) -> zcode op_random(0, range)`.
-pub fn function_random(manager: &CodeGenManager, arg_from: &Operand, arg_to: &Operand,
-        code: &mut Vec<ZOP>, temp_ids: &mut Vec<u8>, location: (u64, u64)) -> Operand {
+/// Prints the text of a link with its number from global 16 in the link colour.
+fn link_label(cfg: &Config, display_name: &str) -> Vec<ZOP> {
+    let foreground: u8 = if cfg.bright_mode { 2 } else { 9 };
+    let background: u8 = if cfg.bright_mode { 9 } else { 2 };
+    let link_color: u8 = if cfg.bright_mode { 6 } else { 8 };
+
+    vec![
+        ZOP::SetColor{foreground: link_color, background: background},
+        ZOP::PrintOps{text: format!("{}[", display_name)},
+        ZOP::PrintNumVar{variable: Variable::new(16)},
+        ZOP::Print{text: "]".to_string()},
+        ZOP::SetColor{foreground: foreground, background: background},
+    ]
+}
 
-    let range_var: Variable = match temp_ids.pop() {
-        Some(var) => Variable::new(var),
-        None      => error_force_panic!(EvaluateExpressionError::NoTempIdLeftOnStack)
-    };
+/// The passages of a Twine story that hold settings instead of story text, `<<toc>>` leaves them
+/// out. The stylesheets, scripts and `StoryData` are skipped before.
+static TOC_EXCLUDED_PASSAGES: &'static [&'static str] = &[
+    "StoryAuthor", "StoryIncludes", "StoryInit", "StoryMenu", "StorySettings", "StorySubtitle", "StoryTitle"];
 
-    match arg_from {
-        &Operand::Var(ref var) => {
-            if var.vartype != Type::Integer {
-                error_panic!(manager.cfg =>EvaluateExpressionError::UnsupportedFunctionArgType { name: "random".to_string(),
-                    index: 0, location: location } );
-                return Operand::Const(Constant { value: 0 })
-            }
+/// Returns the routine `system_toc` of `<<toc>>`, which adds a link to every passage on its own
+/// line.
+///
+/// If any passage is tagged `[menu]`, only the tagged passages are listed, in the order of the
+/// input.
+fn toc_routine(cfg: &Config, passages: &[(String, bool)]) -> Vec<ZOP> {
+    let menu_only = passages.iter().any(|&(_, menu)| menu);
+    let mut code = vec![ZOP::Routine{name: "system_toc".to_string(), count_variables: 0}];
+    for &(ref name, menu) in passages.iter() {
+        if menu_only && !menu {
+            continue;
         }
-        &Operand::StringRef(_) => {
-            error_panic!(manager.cfg =>EvaluateExpressionError::UnsupportedFunctionArgType { name: "random".to_string(),
-                index: 0, location: location } );
-            return Operand::Const(Constant { value: 0 })
+        code.push(ZOP::Call2NWithAddress{jump_to_label: "system_add_link".to_string(), address: name.to_string()});
+        code.extend(link_label(cfg, name));
+        code.push(ZOP::Newline);
+    }
+    code.push(ZOP::Ret{value: Operand::new_const(0)});
+    code
+}
+
+/// Checks if the passage is a `StoryStylesheet` or `StoryScript` passage of a Twine export,
+/// either by its name or by a `stylesheet` or `script` tag.
+fn is_special_passage(name: &str, childs: &Vec<ASTNode>) -> bool {
+    if name == "StoryStylesheet" || name == "StoryScript" {
+        return true;
+    }
+
+    has_tag(childs, "stylesheet") || has_tag(childs, "script")
+}
+
+/// Checks if the code of the node runs from its start to its end in the routine of the passage,
+/// the values of expressions kept before it are still valid inside and behind it.
+fn runs_straight_through(node: &NodeDefault) -> bool {
+    match node.category {
+        TokText { .. } | TokNewLine { .. } | TokMacroBr { .. } | TokFormatHorizontalLine { .. } |
+        TokFormatHeading { .. } | TokFormatBoldStart { .. } | TokFormatMonoStart { .. } |
+        TokFormatItalicStart { .. } | TokFormatUnderStart { .. } | TokFormatStrikeStart { .. } |
+        TokFormatSubStart { .. } | TokFormatSupStart { .. } | TokMacroSilently { .. } |
+        TokMacroEndSilently { .. } | TokMacroNoBr { .. } | TokMacroEndNoBr { .. } | TokTag { .. } |
+        TokPrecondition { .. } | TokPreconditionTarget { .. } | TokMacroAllow { .. } |
+        TokAssign { .. } | TokMacroPrint { .. } | TokMacroContentVar { .. } => true,
+        // the assignments of a link run in a routine of their own
+        TokPassageLink { .. } => node.childs.is_empty(),
+        _ => false
+    }
+}
+
+/// Checks if the token keeps a run of line breaks going, i.e. it is a line break itself or text
+/// without anything but whitespace.
+fn is_blank_line_content(token: &Token) -> bool {
+    match token {
+        &TokNewLine { .. } => true,
+        &TokText { ref text, .. } => text.trim().is_empty(),
+        _ => false
+    }
+}
+
+/// Checks if the execution cannot continue behind the last instruction of the code, because it
+/// returns, quits or jumps to a label that is followed by a return.
+fn ends_with_terminal(code: &[ZOP]) -> bool {
+    let mut last = code.last();
+    // a chain of jumps that is longer than the code is a loop
+    for _ in 0..code.len() {
+        match last {
+            Some(&ZOP::Ret{..}) | Some(&ZOP::Throw{..}) | Some(&ZOP::Quit) => return true,
+            Some(&ZOP::Jump{ref jump_to_label}) => {
+                last = code.iter()
+                    .skip_while(|op| match op { &&ZOP::Label{ref name} => name != jump_to_label, _ => true })
+                    .find(|op| match op { &&ZOP::Label{..} => false, _ => true });
+            },
+            _ => return false
+        }
+    }
+    false
+}
+
+/// Frees the heap and returns from a passage, unless the code of the passage ends with a return
+/// already. Without it the execution would fall through into the next routine.
+fn add_passage_epilogue(code: &mut Vec<ZOP>) {
+    if !ends_with_terminal(code) {
+        code.push(ZOP::Call1N{jump_to_label: "mem_free".to_string()});
+        code.push(ZOP::Ret{value: Operand::new_const(0)});
+    }
+}
+
+/// Checks if the passage with these childs is tagged with `tag`.
+fn has_tag(childs: &Vec<ASTNode>, tag: &str) -> bool {
+    childs.iter().any(|child| match child {
+        &ASTNode::Default(ref t) => match t.category {
+            TokTag { ref tag_name, .. } => tag_name == tag,
+            _ => false
+        },
+        _ => false
+    })
+}
+
+/// Trims the content of a passage tagged with `trim`.
+///
+/// Leading and trailing blank lines and whitespace are removed and runs of blank lines between
+/// paragraphs collapse into a single one. Whitespace within a line is kept. Only the top level of
+/// the passage is trimmed, the branches of an `<<if>>` are left untouched. A `verbatim` tag keeps
+/// the passage as it is.
+fn trim_passage(childs: &Vec<ASTNode>) -> Vec<ASTNode> {
+    let category = |child: &ASTNode| match child {
+        &ASTNode::Default(ref t) => Some(t.category.clone()),
+        _ => None
+    };
+
+    let mut trimmed: Vec<ASTNode> = vec![];
+    // whitespace since the last content, emitted as it is if it contains no line break
+    let mut pending: Vec<ASTNode> = vec![];
+    let mut newlines: Vec<ASTNode> = vec![];
+    let mut started = false;
+    for child in childs.iter() {
+        match category(child) {
+            Some(TokTag { .. }) | Some(TokPrecondition { .. }) => {
+                trimmed.push(child.clone());
+                continue;
+            },
+            Some(TokNewLine { .. }) => {
+                newlines.push(child.clone());
+                continue;
+            },
+            Some(TokText { ref text, .. }) if text.trim().is_empty() => {
+                pending.push(child.clone());
+                continue;
+            },
+            _ => {}
+        }
+
+        if started {
+            if newlines.is_empty() {
+                trimmed.extend(pending.drain(..));
+            } else {
+                // one line break ends the line, a second one leaves a blank line
+                let count = if newlines.len() > 2 { 2 } else { newlines.len() };
+                trimmed.extend(newlines.drain(..).take(count));
+            }
+        }
+        pending.clear();
+        newlines.clear();
+
+        let child = match child {
+            &ASTNode::Default(ref t) => match t.category {
+                TokText { ref location, ref text } if !started => {
+                    ASTNode::Default(NodeDefault { category: TokText { location: *location, text: text.trim_left().to_string() }, childs: vec![] })
+                },
+                _ => child.clone()
+            },
+            _ => child.clone()
+        };
+        started = true;
+        trimmed.push(child);
+    }
+
+    // trailing whitespace of the last line of content
+    if let Some(index) = trimmed.iter().rposition(|child| match category(child) { Some(TokTag { .. }) | Some(TokPrecondition { .. }) => false, _ => true }) {
+        let last = match trimmed[index] {
+            ASTNode::Default(ref t) => match t.category {
+                TokText { ref location, ref text } => Some(TokText { location: *location, text: text.trim_right().to_string() }),
+                _ => None
+            },
+            _ => None
+        };
+        if let Some(category) = last {
+            trimmed[index] = ASTNode::Default(NodeDefault { category: category, childs: vec![] });
+        }
+    }
+
+    trimmed
+}
+
+/// Returns the value of the `start` entry of the Twee 3 `StoryData` passage, e.g. `"Intro"` for
+/// `{"ifid": "...", "start": "Intro"}`.
+fn story_data_start(childs: &Vec<ASTNode>) -> Option<String> {
+    let mut json = String::new();
+    for child in childs.iter() {
+        if let &ASTNode::Default(ref t) = child {
+            if let TokText { ref text, .. } = t.category {
+                json.push_str(text);
+            }
+        }
+    }
+
+    let key = match json.find("\"start\"") {
+        Some(index) => index + "\"start\"".len(),
+        None => return None
+    };
+    let rest = json[key..].trim_left();
+    if !rest.starts_with(":") {
+        return None;
+    }
+    let rest = rest[1..].trim_left();
+    if !rest.starts_with("\"") {
+        return None;
+    }
+
+    let mut value = String::new();
+    let mut escaped = false;
+    for c in rest[1..].chars() {
+        match c {
+            '\\' if !escaped => escaped = true,
+            '"' if !escaped => return Some(value),
+            _ => {
+                value.push(c);
+                escaped = false;
+            }
+        }
+    }
+    None
+}
+
+/// Collects the variables assigned below the top level of a passage, like in the branches of an
+/// `<<if>>` or in the setters of links.
+///
+/// Returns false if the nodes contain a `<<display>>` or a plugin macro, which may change any
+/// variable.
+fn collect_nested_assignments(nodes: &Vec<ASTNode>, top_level: bool, assigned: &mut HashSet<String>) -> bool {
+    for node in nodes.iter() {
+        if let &ASTNode::Default(ref t) = node {
+            match t.category {
+                TokMacroDisplay { .. } | TokMacroPlugin { .. } => return false,
+                TokAssign { ref var_name, .. } if !top_level => { assigned.insert(var_name.clone()); },
+                _ => {}
+            }
+            if !collect_nested_assignments(&t.childs, false, assigned) {
+                return false;
+            }
+        }
+    }
+    true
+}
+
+/// Returns the range of a variable behind an assignment of the current passage, if it is known.
+///
+/// Only the variables assigned on the top level of the passage are followed, they have the same
+/// value on every way through it. `+=`, `-=`, `*=` and `/=` combine the range of the variable
+/// with the one of the expression.
+fn assigned_range(var_name: &str, op_name: &str, location: (u64, u64), expression: &NodeDefault, manager: &CodeGenManager) -> Option<(i32, i32)> {
+    match manager.nested_assignments {
+        Some(ref nested) if !nested.contains(var_name) => {},
+        _ => return None
+    }
+    if expression.childs.len() != 1 {
+        return None;
+    }
+    let value = expression.childs[0].clone();
+    let node = match op_name {
+        "=" | "to" => value,
+        "+=" | "-=" | "*=" | "/=" => ASTNode::Default(NodeDefault {
+            category: TokNumOp { location: location, op_name: op_name[..1].to_string() },
+            childs: vec![ASTNode::Default(NodeDefault { category: TokVariable { location: location, name: var_name.to_string() }, childs: vec![] }), value]
+        }),
+        _ => return None
+    };
+    static_range(&node, &manager.variable_ranges)
+}
+
+/// Collects the locations of all `<<onexit>>` blocks below the specified nodes.
+fn collect_onexit_locations(nodes: &Vec<ASTNode>, locations: &mut Vec<(u64, u64)>) {
+    for node in nodes.iter() {
+        if let &ASTNode::Default(ref t) = node {
+            if let TokMacroOnExit { location } = t.category {
+                locations.push(location);
+            }
+            collect_onexit_locations(&t.childs, locations);
+        }
+    }
+}
+
+/// Checks if the function `name` is called anywhere below the specified nodes.
+fn uses_function(nodes: &Vec<ASTNode>, name: &str) -> bool {
+    nodes.iter().any(|node| match node {
+        &ASTNode::Default(ref t) => match t.category {
+            TokFunction { name: ref function, .. } if function == name => true,
+            _ => uses_function(&t.childs, name)
+        },
+        &ASTNode::Passage(ref t) => uses_function(&t.childs, name)
+    })
+}
+
+/// Allows the warnings of the `<<zwreec-allow>>` macros below the specified nodes.
+///
+/// The macros before any other content of a passage, except for tags and blank lines, allow the
+/// warning in the whole passage, all others on their line and the next one.
+fn collect_allows(passage: &str, nodes: &Vec<ASTNode>, at_start: bool, diagnostics: &mut Diagnostics) {
+    let mut at_start = at_start;
+    for node in nodes.iter() {
+        if let &ASTNode::Default(ref t) = node {
+            match t.category {
+                TokMacroAllow { ref warning, location } => {
+                    let line = if at_start { None } else { Some(location.0) };
+                    match Warning::from_id(warning) {
+                        Some(allowed) => diagnostics.allow(allowed, passage, line),
+                        None => {
+                            let ids: Vec<&str> = WARNINGS.iter().map(|known| known.id()).collect();
+                            diagnostics.warn(Warning::UnknownWarning, passage, Some(location.0),
+                                format!("Unknown warning '{}' in <<zwreec-allow>> at {}:{}, the warnings are {}", warning, location.0, location.1, ids.join(", ")));
+                        }
+                    }
+                },
+                TokTag { .. } | TokPrecondition { .. } => {},
+                ref token if is_blank_line_content(token) => {},
+                _ => {
+                    at_start = false;
+                    collect_allows(passage, &t.childs, false, diagnostics);
+                }
+            }
+        }
+    }
+}
+
+/// Returns the text style every passage starts with, as set by the `default-*` features.
+fn default_format_state(cfg: &Config) -> FormattingState {
+    FormattingState {bold: cfg.default_bold, italic: cfg.default_italic, mono: cfg.default_mono, inverted: cfg.default_reverse}
+}
+
+/// Checks if a passage has links, but all of them are inside of `<<if>>` blocks and there is no
+/// `<<otherwise>>` link to fall back to.
+fn may_be_dead_end(nodes: &Vec<ASTNode>) -> bool {
+    let (mut unconditional, mut conditional) = (0, 0);
+    count_links(nodes, false, &mut unconditional, &mut conditional);
+    unconditional == 0 && conditional > 0
+}
+
+/// Checks if any of the nodes prints text or leads to another passage by a link, `<<display>>`
+/// or `<<countdown>>`.
+///
+/// `<<silently>>` and `<<onexit>>` blocks print nothing in the passage itself, so they do not
+/// count, neither do variables outside of `<<print>>`.
+fn has_output_or_links(nodes: &Vec<ASTNode>) -> bool {
+    nodes.iter().any(|node| match node {
+        &ASTNode::Default(ref t) => match t.category {
+            TokPassageLink { .. } | TokMacroOtherwise { .. } | TokMacroDisplay { .. } | TokMacroCountdown { .. } |
+            TokMacroPrint { .. } | TokMacroContentVar { .. } | TokMacroToc { .. } |
+            TokMacroPlugin { .. } => true,
+            TokText { ref text, .. } => !text.trim().is_empty(),
+            TokMacroSilently { .. } | TokMacroOnExit { .. } | TokMacroSet { .. } => false,
+            _ => has_output_or_links(&t.childs)
+        },
+        _ => false
+    })
+}
+
+/// Counts the links below the specified nodes, an `<<otherwise>>` link counts as unconditional.
+fn count_links(nodes: &Vec<ASTNode>, inside_if: bool, unconditional: &mut usize, conditional: &mut usize) {
+    for node in nodes.iter() {
+        if let &ASTNode::Default(ref t) = node {
+            match t.category {
+                TokPassageLink { .. } | TokMacroToc { .. } if inside_if => *conditional += 1,
+                TokPassageLink { .. } | TokMacroToc { .. } | TokMacroOtherwise { .. } => *unconditional += 1,
+                TokMacroIf { .. } | TokMacroElseIf { .. } | TokMacroElse { .. } => {
+                    count_links(&t.childs, true, unconditional, conditional);
+                },
+                _ => count_links(&t.childs, inside_if, unconditional, conditional)
+            }
+        }
+    }
+}
+
+/// This generates code for the functions `confirm(question)` and `pause_confirm(question)`.
+///
+/// Both call `rt_confirm`, which prints in monospace, so the current text style is set again
+/// afterwards. `confirm` returns the answer as bool, `pause_confirm` only waits for a key and
+/// returns 0.
+pub fn function_confirm(manager: &CodeGenManager, question: &Operand, wait_only: bool,
+        code: &mut Vec<ZOP>, temp_ids: &mut Vec<u8>, location: (u64, u64)) -> Result<Operand, EvaluateExpressionError> {
+
+    let name = if wait_only { "pause_confirm" } else { "confirm" };
+    let is_string = match question {
+        &Operand::StringRef(_) => true,
+        &Operand::Var(ref var) => var.vartype == Type::String,
+        _ => false
+    };
+    if !is_string {
+        error_panic!(manager.cfg => EvaluateExpressionError::UnsupportedFunctionArgType { name: name.to_string(),
+            index: 0, location: location } );
+        return Ok(Operand::Const(Constant { value: 0 }))
+    }
+
+    let result = if wait_only {
+        code.push(ZOP::CallVNA2{jump_to_label: "rt_confirm".to_string(), arg1: question.clone(), arg2: Operand::new_const(1)});
+        Operand::Const(Constant { value: 0 })
+    } else {
+        let answer: Variable = Variable::new_bool(try!(pop_temp_id(temp_ids)));
+        code.push(ZOP::CallVSA2{jump_to_label: "rt_confirm".to_string(), arg1: question.clone(), arg2: Operand::new_const(0), result: answer.clone()});
+        code.push(ZOP::SetVarType{variable: answer.clone(), vartype: Type::Bool});
+        Operand::new_var_bool(answer.id)
+    };
+
+    let state = manager.format_state;
+    code.push(ZOP::SetTextStyle{bold: state.bold, reverse: state.inverted, monospace: state.mono, italic: state.italic});
+    Ok(result)
+}
+
+/// This generates code for the function `random(from, to) -> zcode op_random(0, range)`.
+///
+/// A range that is not positive would seed the generator instead, so constant bounds in the
+/// wrong order are an error and other bounds are swapped at runtime.
+pub fn function_random(manager: &mut CodeGenManager, arg_from: &Operand, arg_to: &Operand,
+        code: &mut Vec<ZOP>, temp_ids: &mut Vec<u8>, location: (u64, u64)) -> Result<Operand, EvaluateExpressionError> {
+
+    let range_var: Variable = Variable::new(try!(pop_temp_id(temp_ids)));
+
+    match arg_from {
+        &Operand::Var(ref var) => {
+            if var.vartype != Type::Integer {
+                error_panic!(manager.cfg =>EvaluateExpressionError::UnsupportedFunctionArgType { name: "random".to_string(),
+                    index: 0, location: location } );
+                return Ok(Operand::Const(Constant { value: 0 }))
+            }
+        }
+        &Operand::StringRef(_) => {
+            error_panic!(manager.cfg =>EvaluateExpressionError::UnsupportedFunctionArgType { name: "random".to_string(),
+                index: 0, location: location } );
+            return Ok(Operand::Const(Constant { value: 0 }))
         }
         _ => {
             // type from is fine
@@ -613,19 +1757,28 @@ pub fn function_random(manager: &CodeGenManager, arg_from: &Operand, arg_to: &Op
             if var.vartype != Type::Integer {
                 error_panic!(manager.cfg =>EvaluateExpressionError::UnsupportedFunctionArgType { name: "random".to_string(),
                     index: 1, location: location } );
-                return Operand::Const(Constant { value: 0 })
+                return Ok(Operand::Const(Constant { value: 0 }))
             }
         }
         &Operand::StringRef(_) => {
             error_panic!(manager.cfg =>EvaluateExpressionError::UnsupportedFunctionArgType { name: "random".to_string(),
                 index: 0, location: location } );
-            return Operand::Const(Constant { value: 0 })
+            return Ok(Operand::Const(Constant { value: 0 }))
         }
         _ => {
             // type to is fine
         }
     }
 
+    let constant = arg_from.is_const() && arg_to.is_const();
+    let (arg_from, arg_to) = if constant && arg_from.const_value() > arg_to.const_value() {
+        error_panic!(manager.cfg => EvaluateExpressionError::InvalidRandomRange { from: arg_from.const_value(),
+            to: arg_to.const_value(), location: location });
+        (arg_to, arg_from)
+    } else {
+        (arg_from, arg_to)
+    };
+
     // Calculate range = to - from + 1
     code.push(ZOP::Sub{
         operand1: arg_to.clone(),
@@ -638,18 +1791,29 @@ pub fn function_random(manager: &CodeGenManager, arg_from: &Operand, arg_to: &Op
         save_variable: range_var.clone()
     });
 
-    let var: Variable = match temp_ids.pop() {
-        Some(var) => Variable::new(var),
-        None      => error_force_panic!(EvaluateExpressionError::NoTempIdLeftOnStack)
+    let low = if constant {
+        arg_from.clone()
+    } else {
+        // with to < from the range is from - to + 1 = 2 - range and starts at to
+        let low_var: Variable = Variable::new(try!(pop_temp_id(temp_ids)));
+        let ordered_label = format!("random_ordered{}", manager.ids_expr.start_next());
+        code.push(ZOP::StoreVariable{variable: low_var.clone(), value: arg_from.clone()});
+        code.push(ZOP::JG{operand1: Operand::new_var(range_var.id), operand2: Operand::new_const(0), jump_to_label: ordered_label.clone()});
+        code.push(ZOP::StoreVariable{variable: low_var.clone(), value: arg_to.clone()});
+        code.push(ZOP::Sub{operand1: Operand::new_const(2), operand2: Operand::new_var(range_var.id), save_variable: range_var.clone()});
+        code.push(ZOP::Label{name: ordered_label});
+        Operand::new_var(low_var.id)
     };
 
+    let var: Variable = Variable::new(try!(pop_temp_id(temp_ids)));
+
     // get a random number between 1 and range
     code.push(ZOP::Random {range: Operand::new_var(range_var.id), variable: var.clone()} );
 
-    // add (arg_from - 1) to range (because min. random is 1 not 0)
+    // add (low - 1) to range (because min. random is 1 not 0)
     code.push(ZOP::Add{
         operand1: Operand::new_var(var.id),
-        operand2: arg_from.clone(),
+        operand2: low.clone(),
         save_variable: var.clone()
     });
      code.push(ZOP::Sub{
@@ -658,8 +1822,27 @@ pub fn function_random(manager: &CodeGenManager, arg_from: &Operand, arg_to: &Op
         save_variable: var.clone()
     });
     code.push(ZOP::SetVarType{variable: var.clone(), vartype: Type::Integer});
+    if let Operand::Var(ref low_var) = low {
+        temp_ids.push(low_var.id);
+    }
     temp_ids.push(range_var.id);
-    Operand::new_var(var.id)
+    Ok(Operand::new_var(var.id))
+}
+
+/// Returns the timestamp used for `buildtime()`.
+///
+/// In reproducible mode this is always the start of the unix epoch.
+pub fn build_time(cfg: &Config) -> String {
+    let tm = if cfg.reproducible {
+        ::time::at_utc(::time::Timespec::new(0, 0))
+    } else {
+        ::time::now()
+    };
+
+    match ::time::strftime("%Y-%m-%d %H:%M", &tm) {
+        Ok(s) => s,
+        Err(_) => "unknown".to_string()
+    }
 }
 
 /// The manager that contains a lot of state for the code generation.
@@ -676,15 +1859,49 @@ pub struct CodeGenManager<'a> {
     /// The ID provider for set variable operations
     pub ids_link_var_set: IdentifierProvider,
 
+    /// The ID provider for passage entries and their exit routines
+    pub ids_onexit: IdentifierProvider,
+
+    /// The ID provider for the preconditions of passages
+    pub ids_precondition: IdentifierProvider,
+
+    /// The not yet emitted exit routine of the current passage
+    pub onexit_routine: Option<String>,
+
+    /// The code of the exit routine of the current passage, emitted after the passage routine
+    pub onexit_code: Vec<ZOP>,
+
+    /// The code of the `<<otherwise>>` links of the current passage, emitted after all other
+    /// content of the passage
+    pub otherwise_links: Vec<ZOP>,
+
     /// The passages already processed by Codegen
     pub visited_passages: HashSet<String>,
 
     /// All passages that are linked to (including Start)
     pub required_passages: Vec<String>,
 
+    /// The passages of `<<display>>`, which may be misspelled widgets
+    pub displayed_passages: Vec<String>,
+
+    /// The passages without output and links that are not tagged `[ending]`
+    pub silent_passages: Vec<String>,
+
     /// The symbol table
     pub symbol_table: SymbolTable,
 
+    /// The values of repeated expressions in the current passage
+    pub expression_cache: ExpressionCache,
+
+    /// The ranges of the variables the current passage assigned a bounded value so far, see
+    /// `static_range`
+    pub variable_ranges: HashMap<String, (i32, i32)>,
+
+    /// The variables the current passage assigns below its top level, like in an `<<if>>`, whose
+    /// range is unknown. `None` if the passage may change any variable through `<<display>>` or
+    /// a plugin macro.
+    pub nested_assignments: Option<HashSet<String>>,
+
     /// The current formatting options
     pub format_state: FormattingState,
 
@@ -692,11 +1909,57 @@ pub struct CodeGenManager<'a> {
     pub is_silent: bool,
 
     /// Is this inside a nobr tag? (no line breaks)
-    pub is_nobr: bool
+    pub is_nobr: bool,
+
+    /// Is the current passage tagged with verbatim? (whitespace is kept as is)
+    pub is_verbatim: bool,
+
+    /// The count of directly consecutive line breaks printed so far
+    pub newline_run: u32,
+
+    /// The time of compilation as returned by `buildtime()`
+    pub build_time: String,
+
+    /// The first passage of the story
+    pub first_passage: Option<String>,
+
+    /// The start passage named in `StoryData`
+    pub story_start: Option<String>,
+
+    /// The name of the passage the code is generated for
+    pub current_passage: String,
+
+    /// The passage named `RuntimeError` or tagged `error-handler`, called on runtime errors
+    pub error_passage: Option<String>,
+
+    /// The warnings of all passages and the ones allowed by `<<zwreec-allow>>`
+    pub diagnostics: Diagnostics,
+
+    /// The passages a `<<toc>>` lists and whether they are tagged `[menu]`
+    pub toc_passages: Vec<(String, bool)>,
+
+    /// Is there a `<<toc>>`, so the routine `system_toc` has to be generated
+    pub uses_toc: bool,
+
+    /// Is `passage()` called anywhere, so every passage stores its name in global 19
+    pub uses_passage_name: bool,
+
+    /// The keys of all map literals
+    pub map_keys: HashSet<String>,
+
+    /// The passage, the line and the key of every map access
+    pub map_accesses: Vec<(String, u64, String)>,
+
+    /// For every open `<<if>>`: is one of its branches always taken, so the following
+    /// `<<elseif>>` and `<<else>>` branches are left out
+    pub static_ifs: Vec<bool>
 }
 
 /// A generator for unique IDs.
 pub struct IdentifierProvider {
+    /// The config the errors are reported to
+    cfg: Config,
+
     /// The next to be issued ID
     current_id: u32,
 
@@ -706,8 +1969,11 @@ pub struct IdentifierProvider {
 
 /// The symbol table.
 pub struct SymbolTable {
-    /// The ID of the last symbol
-    current_id: u8,
+    /// The config the errors are reported to
+    cfg: Config,
+
+    /// The ID of the next symbol, which is past the globals once it exceeds 255
+    current_id: u16,
 
     /// A map of all variables and their type
     symbol_map: HashMap<String, (Variable, Type)>
@@ -718,15 +1984,39 @@ impl <'a> CodeGenManager<'a> {
     pub fn new(cfg: &'a Config) -> CodeGenManager<'a> {
         CodeGenManager {
             cfg: cfg,
-            ids_if: IdentifierProvider::new(),
-            ids_expr: IdentifierProvider::new(),
-            ids_link_var_set: IdentifierProvider::new(),
+            ids_if: IdentifierProvider::new(cfg),
+            ids_expr: IdentifierProvider::new(cfg),
+            ids_link_var_set: IdentifierProvider::new(cfg),
+            ids_onexit: IdentifierProvider::new(cfg),
+            ids_precondition: IdentifierProvider::new(cfg),
+            onexit_routine: None,
+            onexit_code: vec![],
+            otherwise_links: Vec::new(),
             visited_passages: HashSet::new(),
             required_passages: Vec::new(),
-            symbol_table: SymbolTable::new(),
-            format_state: FormattingState {bold: false, italic: false, mono: false, inverted: false},
+            displayed_passages: Vec::new(),
+            silent_passages: Vec::new(),
+            symbol_table: SymbolTable::new(cfg),
+            expression_cache: ExpressionCache::new(),
+            variable_ranges: HashMap::new(),
+            nested_assignments: None,
+            format_state: default_format_state(cfg),
             is_silent: false,
-            is_nobr: false
+            is_nobr: false,
+            is_verbatim: false,
+            newline_run: 0,
+            build_time: build_time(cfg),
+            first_passage: None,
+            story_start: None,
+            current_passage: String::new(),
+            error_passage: None,
+            diagnostics: Diagnostics::with_levels(&cfg.lint_levels, cfg.pedantic).with_source_map(cfg.source_map.clone()),
+            toc_passages: Vec::new(),
+            uses_toc: false,
+            uses_passage_name: false,
+            map_keys: HashSet::new(),
+            map_accesses: Vec::new(),
+            static_ifs: Vec::new()
         }
     }
 
@@ -736,28 +2026,143 @@ impl <'a> CodeGenManager<'a> {
         (2..15).collect()
     }
 
+    /// Resets the state a passage left behind after it failed to compile, formatting that was
+    /// started in the passage is reset to `format_state`.
+    pub fn abort_passage(&mut self, format_state: FormattingState) {
+        self.format_state = format_state;
+        self.onexit_routine = None;
+        self.onexit_code.clear();
+        self.otherwise_links.clear();
+        self.is_silent = false;
+        self.is_nobr = false;
+        self.is_verbatim = false;
+        self.newline_run = 0;
+        self.static_ifs.clear();
+        self.expression_cache.clear();
+    }
+
     /// Tells whether a variable is a temporary (true) or global variable (false).
     pub fn is_temp_var(var: &Variable) -> bool{
         var.id > 1 && var.id < 16
     }
 
+    /// Returns the name of the start passage.
+    ///
+    /// The passage set in the config wins over the one named in `StoryData`, then comes `Start`.
+    /// Without a `Start` passage the first passage is used if `first_passage_fallback` is set.
+    pub fn start_passage(&self) -> String {
+        if let Some(ref name) = self.cfg.start_passage {
+            return normalize_passage_name(name);
+        }
+        if let Some(ref name) = self.story_start {
+            return normalize_passage_name(name);
+        }
+
+        let start = "Start".to_string();
+        if self.cfg.first_passage_fallback && !self.visited_passages.contains(&start) {
+            if let Some(ref name) = self.first_passage {
+                return name.clone();
+            }
+        }
+        start
+    }
+
     /// Checks for Twee invariants (Start passage must exist, all linked passages must exist).
     pub fn validate_passages(&self) {
-        if !self.visited_passages.contains(&("Start".to_string())) {
-            error_force_panic!(CodeGenError::NoStartPassage);
+        let start = self.start_passage();
+        if !self.visited_passages.contains(&start) {
+            if start == "Start" {
+                error_force_panic!(self.cfg => CodeGenError::NoStartPassage);
+            } else {
+                error_force_panic!(self.cfg => self.missing_passage(&start));
+            }
         }
         for passage in self.required_passages.iter() {
             if !self.visited_passages.contains(passage) {
-                error_force_panic!(CodeGenError::PassageDoesNotExist { name: passage.clone() });
+                if !self.cfg.widgets.is_empty() && self.displayed_passages.contains(passage) {
+                    error_force_panic!(self.cfg => CodeGenError::UnknownMacro { name: passage.clone(), widgets: self.cfg.widgets.clone() });
+                }
+                error_force_panic!(self.cfg => self.missing_passage(passage));
+            }
+        }
+        if let MainLoop::Passage(ref name) = self.cfg.main_loop {
+            if !self.visited_passages.contains(name) {
+                error_force_panic!(self.cfg => self.missing_passage(name));
+            }
+        }
+    }
+
+    /// Warns about the map accesses with a key that is in none of the map literals, at runtime
+    /// they return the integer 0.
+    pub fn warn_missing_map_keys(&mut self) {
+        for &(ref passage, line, ref key) in self.map_accesses.iter() {
+            if !self.map_keys.contains(key) {
+                self.diagnostics.warn(Warning::MissingMapKey, passage, Some(line),
+                    format!("The key \"{}\" at line {} is in no map, the access returns 0", key, line));
+            }
+        }
+    }
+
+    /// Hands the warnings of the lexer to the diagnostics, which knows the `<<zwreec-allow>>`
+    /// macros of all passages by now.
+    pub fn warn_lexer_findings(&mut self) {
+        for (warning, passage, line, message) in self.cfg.take_lexer_warnings() {
+            self.diagnostics.warn(warning, &passage, line, message);
+        }
+    }
+
+    /// Warns about the passages that print nothing and have no links. A passage that is only
+    /// shown through `<<display>>` is part of another passage, which goes on behind it.
+    pub fn warn_dead_ends(&mut self) {
+        for passage in self.silent_passages.iter() {
+            if !self.displayed_passages.contains(passage) {
+                self.diagnostics.warn(Warning::DeadEnd, passage, None,
+                    format!("Passage '{}' prints nothing and has no links, the story ends without a word when it is entered. Tag it with [ending] if this is intended", passage));
             }
         }
     }
+
+    /// Warns about the passages that are neither linked nor displayed, started or called by the
+    /// runtime.
+    pub fn warn_unlinked_passages(&mut self) {
+        // <<toc>> links every passage besides the special ones
+        if self.uses_toc {
+            return;
+        }
+
+        let mut reached: Vec<String> = self.required_passages.clone();
+        reached.push(self.start_passage());
+        reached.extend(self.error_passage.clone());
+        if let MainLoop::Passage(ref name) = self.cfg.main_loop {
+            reached.push(name.clone());
+        }
+
+        let mut unlinked: Vec<String> = self.visited_passages.iter()
+            .filter(|passage| !reached.contains(passage) && !TOC_EXCLUDED_PASSAGES.contains(&&passage[..])).cloned().collect();
+        unlinked.sort();
+        for passage in unlinked.iter() {
+            self.diagnostics.warn(Warning::UnlinkedPassage, passage, None,
+                format!("No link, <<display>> or option leads to passage '{}', it is never shown", passage));
+        }
+    }
+
+    /// Returns the error for a referenced passage that does not exist.
+    ///
+    /// `normalize_passage_name` only composes some accents, so a passage whose name looks the
+    /// same but is in another normalization form is named in the error.
+    fn missing_passage(&self, name: &str) -> CodeGenError {
+        match self.visited_passages.iter().filter(|passage| differently_normalized(name, passage)).min() {
+            Some(candidate) => CodeGenError::DifferentlyNormalizedPassage { name: name.to_string(), candidate: candidate.clone() },
+            None => CodeGenError::PassageDoesNotExist { name: name.to_string() }
+        }
+    }
 }
 
 impl IdentifierProvider {
-    /// Creates a new ID provider.
-    pub fn new() -> IdentifierProvider {
+    /// Creates a new ID provider reporting its errors to the config.
+    pub fn new(cfg: &Config) -> IdentifierProvider {
         IdentifierProvider {
+            cfg: cfg.clone(),
             current_id: 0,
             id_stack: Vec::new()
         }
@@ -777,7 +2182,7 @@ impl IdentifierProvider {
             return temp.clone()
         }
 
-        error_force_panic!(CodeGenError::IdentifierStackEmpty);
+        error_force_panic!(self.cfg => CodeGenError::IdentifierStackEmpty);
     }
 
     /// Pops the last id from the stack.
@@ -786,23 +2191,31 @@ impl IdentifierProvider {
             return temp.clone()
         }
 
-        error_force_panic!(CodeGenError::IdentifierStackEmpty);
+        error_force_panic!(self.cfg => CodeGenError::IdentifierStackEmpty);
     }
 }
 
 impl SymbolTable {
-    /// Creates a new symbol table.
-    pub fn new() -> SymbolTable {
+    /// Creates a new symbol table reporting its errors to the config.
+    pub fn new(cfg: &Config) -> SymbolTable {
+        let mut symbol_map = HashMap::<String, (Variable, Type)>::new();
+        // the error code and message stored by rt_error for the error passage
+        symbol_map.insert("$error".to_string(), (Variable{id: 20, vartype: Type::Integer}, Type::Integer));
+        symbol_map.insert("$errorMessage".to_string(), (Variable{id: 21, vartype: Type::String}, Type::String));
         SymbolTable {
+            cfg: cfg.clone(),
             current_id: 25,
-            symbol_map: HashMap::<String, (Variable, Type)>::new()
+            symbol_map: symbol_map
         }
     }
 
     /// Inserts a symbol into the table, assigning a new id.
     pub fn insert_new_symbol(&mut self, symbol: String, t: Type) {
+        if self.current_id > 255 {
+            error_force_panic!(self.cfg => CodeGenError::TooManyVariables { name: symbol });
+        }
         debug!("Assigned id {} to variable {}", self.current_id, symbol);
-        self.symbol_map.insert(symbol, (Variable{id: self.current_id, vartype: t.clone()}, t));
+        self.symbol_map.insert(symbol, (Variable{id: self.current_id as u8, vartype: t.clone()}, t));
         self.current_id += 1;
     }
 
@@ -820,7 +2233,7 @@ impl SymbolTable {
             return temp.0.clone()
         }
 
-        error_force_panic!(CodeGenError::SymbolNotFound { name: symbol.clone() })
+        error_force_panic!(self.cfg => CodeGenError::SymbolNotFound { name: symbol.clone() })
     }
 
     /// Returns the id for a given symbol.
@@ -833,7 +2246,7 @@ impl SymbolTable {
         if let Some(temp) = self.symbol_map.get(&symbol) {
             return temp.0.clone()
         }
-        error_force_panic!(CodeGenError::SymbolNotFound { name: symbol.clone() })
+        error_force_panic!(self.cfg => CodeGenError::SymbolNotFound { name: symbol.clone() })
     }
 
     /// Returns the Type of the specified symbol.
@@ -845,7 +2258,7 @@ impl SymbolTable {
             return temp.1.clone()
         }
 
-        error_force_panic!(CodeGenError::SymbolNotFound { name: symbol.clone() })
+        error_force_panic!(self.cfg => CodeGenError::SymbolNotFound { name: symbol.clone() })
     }
 
     /// Checks if the symbol table contains a variable with the specified id.
@@ -871,6 +2284,612 @@ impl SymbolTable {
             }
         }
 
-        error_force_panic!(CodeGenError::CouldNotFindSymbolId { id: id });
+        error_force_panic!(self.cfg => CodeGenError::CouldNotFindSymbolId { id: id });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use backend::zcode::zfile::{Operand, ZOP, Zfile, MIN_LINK_CAPACITY};
+    use config::{Config, MainLoop};
+    use frontend::*;
+    use frontend::ast::{ASTBuilder, ASTNode};
+    use utils::diagnostics::{Diagnostics, Warning};
+
+    use super::{Codegen, CodeGenManager, PassageLinks, PassageSize, add_passage_epilogue, build_time, gen_zcode, generate_ops, generate_zcode,
+                max_links, may_be_dead_end, report_passage_sizes, toc_routine};
+
+    /// Creates the AST for the input str.
+    fn test_ast(cfg: &Config, input: &str) -> Vec<ASTNode> {
+        let mut cursor: Cursor<Vec<u8>> = Cursor::new(input.to_string().into_bytes());
+        let tokens = lexer::lex(cfg.clone(), &mut cursor);
+        let ast_ops = parser::Parser::new(cfg.clone()).parse(tokens);
+        ASTBuilder::build(cfg.clone(), ast_ops).collect()
+    }
+
+    #[test]
+    fn test_max_links() {
+        let cfg = Config::default_config();
+        let ast = test_ast(&cfg, "::Start\n[[A]] <<if $x>>[[B]]<<else>>[[C]]<<endif>>\n<<display \"Menu\">><<display \"Menu\">>\n::Menu\n[[A]] [[B]] <<display \"Menu\">>\n::A\n::B\n::C\n");
+
+        let mut zfile = Zfile::new_with_cfg(&cfg);
+        let mut manager = CodeGenManager::new(&cfg);
+        let passages: Vec<PassageLinks> = ast.into_iter().map(|passage| {
+            let name = match passage.category() {
+                lexer::Token::TokPassage{name, ..} => name,
+                _ => String::new()
+            };
+            let code = gen_zcode(passage, &mut zfile, &mut manager).unwrap();
+            PassageLinks::from_ops(&name, &code)
+        }).collect();
+
+        // both branches of the if count, Menu is displayed twice but does not count itself again
+        assert_eq!((passages[0].links, passages[1].links), (3, 2));
+        assert_eq!(max_links(&passages), 7);
+    }
+
+    #[test]
+    fn test_link_table_capacity() {
+        let cfg = Config::default_config();
+        let mut many_links = "::Start\n".to_string();
+        for i in 0..60 {
+            many_links.push_str(&format!("[[Room {}|End]]\n", i));
+        }
+        many_links.push_str("::End\nThe end.");
+
+        let mut codegen = Codegen::new(&cfg);
+        codegen.start_codegen(test_ast(&cfg, &many_links).into_iter());
+        assert!(codegen.zfile.link_capacity >= 60);
+
+        let mut codegen = Codegen::new(&cfg);
+        codegen.start_codegen(test_ast(&cfg, "::Start\n[[End]]\n::End\nThe end.").into_iter());
+        assert_eq!(codegen.zfile.link_capacity, MIN_LINK_CAPACITY);
+        assert_eq!(codegen.zfile.link_table, 1 + codegen.zfile.object_addr);
+    }
+
+    #[test]
+    fn test_merge_identical_link_routines() {
+        let cfg = Config::default_config();
+        let input = "::Start\n[[Go|End][$x = 1]]\n::Other\n[[Go|End][$x = 1]] [[Stay|End][$x = 2]]\n::End\nThe end.";
+
+        let mut codegen = Codegen::new(&cfg);
+        codegen.start_codegen(test_ast(&cfg, input).into_iter());
+        assert_eq!(codegen.zfile.merged_routines, 1);
+    }
+
+    /// Generates Z-Code for the input str and returns the passage sizes.
+    fn test_passage_sizes(cfg: &Config, input: &str) -> Vec<PassageSize> {
+        let ast = test_ast(cfg, input);
+
+        let mut codegen = Codegen::new(cfg);
+        codegen.start_codegen(ast.into_iter());
+        codegen.passage_sizes
+    }
+
+    #[test]
+    fn test_single_line_if_link() {
+        let cfg = Config::default_config();
+        let ast = test_ast(&cfg, "::Start\n<<if $k>>[[Open|Door]]<<endif>> [[Leave|Exit]]\n::Door\n::Exit\n");
+
+        let mut zfile = Zfile::new_with_cfg(&cfg);
+        let mut manager = CodeGenManager::new(&cfg);
+        let code = gen_zcode(ast[0].clone(), &mut zfile, &mut manager).unwrap();
+
+        let label = |wanted: &str| code.iter().position(|zop| match zop {
+            &ZOP::Label{ref name} => name == wanted,
+            _ => false
+        }).unwrap();
+        let add_link = |passage: &str| code.iter().position(|zop| match zop {
+            &ZOP::Call2NWithAddress{ref jump_to_label, ref address} => jump_to_label == "system_add_link" && address == passage,
+            _ => false
+        }).unwrap();
+
+        let if_start = label("if_0");
+        let if_end = label("after_if_0");
+
+        // only the link to Door is part of the conditional branch
+        assert!(if_start < add_link("Door") && add_link("Door") < if_end);
+        assert!(add_link("Exit") > if_end);
+    }
+
+    #[test]
+    fn test_otherwise_link_after_other_links() {
+        let cfg = Config::default_config();
+        let ast = test_ast(&cfg, "::Start\n<<otherwise [[Give up|End]]>><<if $k>>[[Open|Door]]<<endif>>\n::Door\n::End\n");
+
+        let mut zfile = Zfile::new_with_cfg(&cfg);
+        let mut manager = CodeGenManager::new(&cfg);
+        let code = gen_zcode(ast[0].clone(), &mut zfile, &mut manager).unwrap();
+
+        let add_link = |passage: &str| code.iter().position(|zop| match zop {
+            &ZOP::Call2NWithAddress{ref jump_to_label, ref address} => jump_to_label == "system_add_link" && address == passage,
+            _ => false
+        }).unwrap();
+        let guard = code.iter().position(|zop| match zop {
+            &ZOP::JE{operand1: Operand::Var(ref var), ref jump_to_label, ..} => var.id == 16 && jump_to_label.starts_with("passage_otherwise"),
+            _ => false
+        }).unwrap();
+
+        // the fallback is added at the end, if the link count is still zero
+        assert!(add_link("Door") < guard && guard < add_link("End"));
+        assert!(manager.otherwise_links.is_empty());
+    }
+
+    #[test]
+    fn test_may_be_dead_end() {
+        let cfg = Config::default_config();
+        let passages = test_ast(&cfg, "::A\n<<if $k>>[[Open|Door]]<<else>>[[Knock|Door]]<<endif>>\n\
+            ::B\n<<if $k>>[[Open|Door]]<<endif>>[[Leave|Exit]]\n\
+            ::C\n<<if $k>>[[Open|Door]]<<endif>><<otherwise [[Leave|Exit]]>>\n\
+            ::D\nNo links.\n");
+
+        let dead_ends: Vec<bool> = passages.iter().map(|passage| match passage {
+            &ASTNode::Passage(ref node) => may_be_dead_end(&node.childs),
+            _ => false
+        }).collect();
+        assert_eq!(dead_ends, vec![true, false, false, false]);
+    }
+
+    /// Generates the passages of the input str and returns the manager with their warnings.
+    fn test_diagnostics<'a>(cfg: &'a Config, input: &str) -> CodeGenManager<'a> {
+        let mut zfile = Zfile::new_with_cfg(cfg);
+        let mut manager = CodeGenManager::new(cfg);
+        for passage in test_ast(cfg, input) {
+            gen_zcode(passage, &mut zfile, &mut manager).unwrap();
+        }
+        manager
+    }
+
+    #[test]
+    fn test_allow_next_line() {
+        let cfg = Config::default_config();
+        let manager = test_diagnostics(&cfg, "::Start
+<<zwreec-allow substr-bounds>>
+<<print substr(\"Hello\", 3, 10)>>
+\
+            Text
+<<print substr(\"Hello\", 3, 10)>>
+");
+
+        // only the warning on the line after the macro is allowed
+        assert_eq!(manager.diagnostics.suppressed().iter().map(|d| d.line).collect::<Vec<_>>(), vec![Some(3)]);
+        assert_eq!(manager.diagnostics.shown().iter().map(|d| d.line).collect::<Vec<_>>(), vec![Some(5)]);
+    }
+
+    #[test]
+    fn test_allow_passage() {
+        let cfg = Config::default_config();
+        let manager = test_diagnostics(&cfg, "::Start [tag]
+<<zwreec-allow conditional-links>>
+<<zwreec-allow \"substr-bounds\">>
+\
+            <<if $k>>[[Open|Door]]<<endif>>
+Text
+\
+            <<print substr(\"Hello\", 3, 10)>>
+\
+            ::Door
+<<if $k>>[[Start]]<<endif>>
+");
+
+        // the passage-level allows do not apply to Door
+        let suppressed: Vec<Warning> = manager.diagnostics.suppressed().iter().map(|d| d.warning).collect();
+        assert_eq!(suppressed, vec![Warning::ConditionalLinks, Warning::SubstrBounds]);
+        let shown: Vec<(Warning, String)> = manager.diagnostics.shown().iter().map(|d| (d.warning, d.passage.clone())).collect();
+        assert_eq!(shown, vec![(Warning::ConditionalLinks, "Door".to_string())]);
+    }
+
+    #[test]
+    fn test_dead_end_warning() {
+        let cfg = Config::default_config();
+        let mut manager = test_diagnostics(&cfg, "::Start\nYou see a cellar.\n<<display \"Setup\">>[[Cellar]] [[Vault]]\n\
+            ::Cellar\n<<set $x to 1>>\n<<silently>>Hidden<<endsilently>>\n\
+            ::Vault [ending]\n\n\
+            ::Hall\n<<print $x>>\n\
+            ::Setup\n<<set $y to 2>>\n");
+        manager.warn_dead_ends();
+
+        // Vault is an intended ending, Hall prints something, Setup is only displayed
+        let shown: Vec<(Warning, String)> = manager.diagnostics.shown().iter().map(|d| (d.warning, d.passage.clone())).collect();
+        assert_eq!(shown, vec![(Warning::DeadEnd, "Cellar".to_string())]);
+
+        let mut cfg = Config::default_config();
+        cfg.main_loop = MainLoop::Quit;
+        let mut manager = test_diagnostics(&cfg, "::Start\n<<set $x to 1>>\n");
+        manager.warn_dead_ends();
+        assert!(manager.diagnostics.shown().is_empty());
+    }
+
+    #[test]
+    fn test_open_comment_warning() {
+        use utils::diagnostics::Level;
+
+        let source = "::Start\nA /% open\n::Door\n<<zwreec-allow open-comment>>\nB /% open\n::End\nC\n";
+        let cfg = Config::default_config();
+        let mut manager = test_diagnostics(&cfg, source);
+        manager.warn_lexer_findings();
+
+        // the allow of Door covers its comment, which is closed by End
+        let shown: Vec<(Warning, String, Option<u64>)> = manager.diagnostics.shown().iter().map(|d| (d.warning, d.passage.clone(), d.line)).collect();
+        assert_eq!(shown, vec![(Warning::OpenComment, "Start".to_string(), Some(2))]);
+        assert_eq!(manager.diagnostics.suppressed().len(), 1);
+
+        let mut cfg = Config::default_config();
+        cfg.lint_levels = vec![(Warning::OpenComment, Level::Error)];
+        let mut manager = test_diagnostics(&cfg, source);
+        manager.warn_lexer_findings();
+        assert!(manager.diagnostics.shown().is_empty());
+        assert_eq!(manager.diagnostics.denied().len(), 1);
+    }
+
+    #[test]
+    fn test_missing_map_key_warning() {
+        let cfg = Config::default_config();
+        let mut manager = test_diagnostics(&cfg, "::Start\n<<print $m[\"hp\"]>>\n<<print $m[\"mp\"]>>\n\
+            ::Setup\n<<set $m to {\"hp\": 10, \"stats\": {\"mp\": 3}}>>\n<<print $m[\"xp\"]>>\n[[Start]]\n");
+        manager.warn_missing_map_keys();
+
+        // the keys of nested maps count as well, the literal may come after the access
+        let shown: Vec<(Warning, String, Option<u64>)> = manager.diagnostics.shown().iter()
+            .filter(|d| d.warning == Warning::MissingMapKey).map(|d| (d.warning, d.passage.clone(), d.line)).collect();
+        assert_eq!(shown, vec![(Warning::MissingMapKey, "Setup".to_string(), Some(3))]);
+    }
+
+    #[test]
+    fn test_lint_levels() {
+        use utils::diagnostics::Level;
+
+        let source = "::Start\nYou see a cellar.\n[[Cellar]]\n::Cellar\n<<set $x to 1>>\n::Attic\nDust.\n";
+        let mut cfg = Config::default_config();
+        cfg.lint_levels = vec![(Warning::DeadEnd, Level::Allow)];
+        let mut manager = test_diagnostics(&cfg, source);
+        manager.warn_dead_ends();
+        manager.warn_unlinked_passages();
+        assert!(manager.diagnostics.shown().is_empty());
+
+        // Attic is unlinked, which is pedantic
+        cfg.pedantic = true;
+        let mut manager = test_diagnostics(&cfg, source);
+        manager.warn_dead_ends();
+        manager.warn_unlinked_passages();
+        let shown: Vec<(Warning, String)> = manager.diagnostics.shown().iter().map(|d| (d.warning, d.passage.clone())).collect();
+        assert_eq!(shown, vec![(Warning::UnlinkedPassage, "Attic".to_string())]);
+
+        cfg.lint_levels.push((Warning::UnlinkedPassage, Level::Error));
+        let mut manager = test_diagnostics(&cfg, source);
+        manager.warn_dead_ends();
+        manager.warn_unlinked_passages();
+        assert!(manager.diagnostics.shown().is_empty());
+        assert_eq!(manager.diagnostics.denied().len(), 1);
+    }
+
+    #[test]
+    fn test_passage_epilogue() {
+        let epilogue = |mut code: Vec<ZOP>| {
+            let len = code.len();
+            add_passage_epilogue(&mut code);
+            code.len() - len
+        };
+        let ret = || ZOP::Ret{value: Operand::new_const(0)};
+        let label = |name: &str| ZOP::Label{name: name.to_string()};
+        let jump = |name: &str| ZOP::Jump{jump_to_label: name.to_string()};
+
+        assert_eq!(epilogue(vec![ZOP::Print{text: "Hello".to_string()}]), 2);
+        assert_eq!(epilogue(vec![ZOP::Print{text: "Hello".to_string()}, ret()]), 0);
+        assert_eq!(epilogue(vec![ZOP::Quit]), 0);
+
+        // a jump to a return ends the passage as well, a jump behind the last instruction does not
+        assert_eq!(epilogue(vec![label("a"), label("b"), ret(), jump("a")]), 0);
+        assert_eq!(epilogue(vec![label("a"), jump("b"), label("b"), ret(), jump("a")]), 0);
+        assert_eq!(epilogue(vec![ret(), jump("end"), label("end")]), 2);
+        assert_eq!(epilogue(vec![jump("missing")]), 2);
+        assert_eq!(epilogue(vec![label("a"), jump("b"), label("b"), jump("a")]), 2);
+    }
+
+    #[test]
+    fn test_unreachable_content_warning() {
+        let cfg = Config::default_config();
+        let input = "::Start\nWait: <<countdown 3 \"End\">>\nNever shown\n<<onexit>>Bye<<endonexit>>\n\
+            ::End\n<<countdown 3 \"Start\">>\n";
+        let manager = test_diagnostics(&cfg, input);
+        let shown: Vec<(Warning, String, Option<u64>)> = manager.diagnostics.shown().iter()
+            .map(|d| (d.warning, d.passage.clone(), d.line)).collect();
+        assert_eq!(shown, vec![(Warning::UnreachableContent, "Start".to_string(), Some(3))]);
+
+        // the skipped text is not generated, the routine of the <<onexit>> block is and follows
+        // the routine of the passage
+        let mut zfile = Zfile::new_with_cfg(&cfg);
+        let mut manager = CodeGenManager::new(&cfg);
+        let code = gen_zcode(test_ast(&cfg, input).remove(0), &mut zfile, &mut manager).unwrap();
+        assert!(!code.iter().any(|op| match op {
+            &ZOP::Print{ref text} => text.contains("Never"),
+            _ => false
+        }));
+        let returns = code.iter().filter(|op| match op { &&ZOP::Ret{..} => true, _ => false }).count();
+        assert_eq!(returns, 3);
+        let onexit = code.iter().position(|op| match op {
+            &ZOP::Routine{ref name, ..} => name.starts_with("passage_onexit"),
+            _ => false
+        }).expect("Expected the routine of the <<onexit>> block");
+        match code[onexit - 1] {
+            ZOP::Ret{..} => (),
+            ref other => panic!("Expected the passage to return before its exit routine, got {:?}", other),
+        }
+        match code.last() {
+            Some(&ZOP::Ret{..}) => (),
+            other => panic!("Expected the exit routine to end with a return, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_allow_unknown_warning() {
+        let cfg = Config::default_config();
+        let manager = test_diagnostics(&cfg, "::Start
+Text
+<<zwreec-allow no-such-warning>>
+");
+
+        let shown = manager.diagnostics.shown();
+        assert_eq!(shown.len(), 1);
+        assert_eq!((shown[0].warning, shown[0].line), (Warning::UnknownWarning, Some(3)));
+        assert!(shown[0].message.contains("no-such-warning"));
+    }
+
+    #[test]
+    fn test_print_bounded_expressions() {
+        let cfg = Config::default_config();
+        let ast = test_ast(&cfg, "::Start\n<<print 7>><<print random(1, 6)>><<print random(1, 6) * 10>><<print $x>><<print $x * 10>>\n");
+
+        let mut zfile = Zfile::new_with_cfg(&cfg);
+        let mut manager = CodeGenManager::new(&cfg);
+        let code = gen_zcode(ast[0].clone(), &mut zfile, &mut manager).unwrap();
+
+        let prints: Vec<&str> = code.iter().filter_map(|zop| match zop {
+            &ZOP::Print{ref text} if text == "7" => Some("inline"),
+            &ZOP::PrintNumVar{..} => Some("num"),
+            &ZOP::PrintVar{..} => Some("var"),
+            _ => None
+        }).collect();
+
+        // constants are printed inline, bounded expressions skip the runtime type lookup
+        assert_eq!(prints, vec!["inline", "num", "num", "var", "var"]);
+    }
+
+    #[test]
+    fn test_print_bounded_variables() {
+        let prints = |input: &str| -> Vec<&'static str> {
+            let cfg = Config::default_config();
+            let ast = test_ast(&cfg, input);
+            let mut zfile = Zfile::new_with_cfg(&cfg);
+            let mut manager = CodeGenManager::new(&cfg);
+            let code = gen_zcode(ast[0].clone(), &mut zfile, &mut manager).unwrap();
+            code.iter().filter_map(|zop| match zop {
+                &ZOP::PrintNumVar{..} => Some("num"),
+                &ZOP::PrintVar{..} => Some("var"),
+                _ => None
+            }).collect()
+        };
+
+        // a counter assigned on the top level, before and after it is set the range is unknown
+        assert_eq!(prints("::Start\n<<print $n>><<set $n to random(1, 6)>><<set $n += 2>><<print $n>><<print $n * 3>><<set $n to $m>><<print $n>>\n"),
+            vec!["var", "num", "num", "var"]);
+        // an assignment in a branch or a displayed passage may change it on the way
+        assert_eq!(prints("::Start\n<<set $n to 1>><<if $k>><<set $n to \"one\">><<endif>><<print $n>>\n"), vec!["var"]);
+        assert_eq!(prints("::Start\n<<set $n to 1>><<display \"Other\">><<print $n>>\n"), vec!["var"]);
+    }
+
+    #[test]
+    fn test_random_bounds_order() {
+        let cfg = Config::default_config();
+        let ast = test_ast(&cfg, "::Start\n<<print random(1, 6)>><<print random(linkcount(), 1)>>\n");
+
+        let mut zfile = Zfile::new_with_cfg(&cfg);
+        let mut manager = CodeGenManager::new(&cfg);
+        let code = gen_zcode(ast[0].clone(), &mut zfile, &mut manager).unwrap();
+
+        // only the bounds unknown at compile time are swapped at runtime if necessary
+        let guards = code.iter().filter(|zop| match zop {
+            &ZOP::Label{ref name} => name.starts_with("random_ordered"),
+            _ => false
+        }).count();
+        assert_eq!(guards, 1);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_random_empty_range() {
+        let cfg = Config::default_config();
+        let ast = test_ast(&cfg, "::Start\n<<print random(6, 1)>>\n");
+        gen_zcode(ast[0].clone(), &mut Zfile::new_with_cfg(&cfg), &mut CodeGenManager::new(&cfg)).unwrap();
+    }
+
+    #[test]
+    fn test_trim_and_verbatim_passages() {
+        let cfg = Config::default_config();
+        let passage = |name: &str, tag: &str| format!("::{} [{}]\n\n   roses\n\n\n\nviolets <<print 1>> <<print 2>>  \n\n\n", name, tag);
+        let input = passage("Prose", "trim") + &passage("Art", "verbatim") + &passage("Both", "trim verbatim");
+        let ast = test_ast(&cfg, &input);
+
+        let mut zfile = Zfile::new_with_cfg(&cfg);
+        let mut manager = CodeGenManager::new(&cfg);
+        let mut code = |index: usize| gen_zcode(ast[index].clone(), &mut zfile, &mut manager).unwrap();
+        let newlines = |code: &Vec<ZOP>| code.iter().filter(|zop| match zop { &ZOP::Newline => true, _ => false }).count();
+        let texts = |code: &Vec<ZOP>| code.iter().filter_map(|zop| match zop {
+            &ZOP::PrintOps{ref text} => Some(text.clone()),
+            _ => None
+        }).collect::<Vec<String>>();
+
+        let trimmed = code(0);
+        assert_eq!(newlines(&trimmed), 2);
+        assert_eq!(texts(&trimmed), vec!["roses".to_string(), "violets ".to_string(), " ".to_string()]);
+
+        // verbatim wins over trim
+        for index in 1..3 {
+            let verbatim = code(index);
+            assert_eq!(newlines(&verbatim), 8);
+            assert_eq!(texts(&verbatim)[0], "   roses");
+        }
+    }
+
+    #[test]
+    fn test_confirm_macro_and_function() {
+        let cfg = Config::default_config();
+        let ast = test_ast(&cfg, "::Start\n<<confirm \"Continue?\">><<if confirm(\"Sure?\")>>yes<<endif>>");
+
+        let mut zfile = Zfile::new_with_cfg(&cfg);
+        let mut manager = CodeGenManager::new(&cfg);
+        let code = gen_zcode(ast[0].clone(), &mut zfile, &mut manager).unwrap();
+
+        let calls: Vec<bool> = code.iter().filter_map(|zop| match zop {
+            &ZOP::CallVNA2{ref jump_to_label, ..} if jump_to_label == "rt_confirm" => Some(false),
+            &ZOP::CallVSA2{ref jump_to_label, ref result, ..} if jump_to_label == "rt_confirm" => {
+                assert_eq!(result.vartype, Type::Bool);
+                Some(true)
+            },
+            _ => None
+        }).collect();
+        assert_eq!(calls, vec![false, true]);
+    }
+
+    #[test]
+    fn test_at_macro_brackets_content() {
+        let cfg = Config::default_config();
+        let ast = test_ast(&cfg, "::Start\nbefore<<at 1 1>>HUD<<endat>>after");
+
+        let mut zfile = Zfile::new_with_cfg(&cfg);
+        let mut manager = CodeGenManager::new(&cfg);
+        let code = gen_zcode(ast[0].clone(), &mut zfile, &mut manager).unwrap();
+
+        let order: Vec<String> = code.iter().filter_map(|zop| match zop {
+            &ZOP::CallVNA2{ref jump_to_label, ..} if jump_to_label == "rt_at" => Some(jump_to_label.to_string()),
+            &ZOP::Call1N{ref jump_to_label} if jump_to_label == "rt_at_end" => Some(jump_to_label.to_string()),
+            &ZOP::PrintOps{ref text} => Some(text.to_string()),
+            _ => None
+        }).collect();
+        assert_eq!(order, vec!["before", "rt_at", "HUD", "rt_at_end", "after"]);
+    }
+
+    #[test]
+    fn test_collapse_blank_lines() {
+        fn count_newlines(cfg: &Config, input: &str) -> usize {
+            let ast = test_ast(cfg, input);
+            let mut zfile = Zfile::new_with_cfg(cfg);
+            let mut manager = CodeGenManager::new(cfg);
+            gen_zcode(ast[0].clone(), &mut zfile, &mut manager).unwrap().iter().filter(|zop| match zop {
+                &ZOP::Newline => true,
+                _ => false
+            }).count()
+        }
+        let blank_lines = |count: usize| format!("::Start\nabove{}below", "\n".repeat(count + 1));
+
+        let mut cfg = Config::default_config();
+        assert_eq!(count_newlines(&cfg, &blank_lines(0)), 1);
+        assert_eq!(count_newlines(&cfg, &blank_lines(1)), 2);
+        assert_eq!(count_newlines(&cfg, &blank_lines(2)), 2);
+        assert_eq!(count_newlines(&cfg, &blank_lines(5)), 2);
+        assert_eq!(count_newlines(&cfg, "::Start\na\n\n\n\nb<<br>><<br>><html><br><br /></html>c"), 6);
+        assert_eq!(count_newlines(&cfg, "::Start\na\n\n<<if true>>\n<<endif>>\nb"), 4);
+        assert_eq!(count_newlines(&cfg, "::Start\n{{{a\n\n\n\n\n\nb}}}"), 0);
+        assert_eq!(count_newlines(&cfg, "::Start [verbatim]\na\n\n\n\n\n\nb"), 6);
+
+        cfg.collapse_blank_lines = false;
+        assert_eq!(count_newlines(&cfg, &blank_lines(0)), 1);
+        assert_eq!(count_newlines(&cfg, &blank_lines(1)), 2);
+        assert_eq!(count_newlines(&cfg, &blank_lines(2)), 3);
+        assert_eq!(count_newlines(&cfg, &blank_lines(5)), 6);
+        assert_eq!(count_newlines(&cfg, "::Start\na\n\n\n\nb<<br>><<br>><html><br><br /></html>c"), 8);
+        assert_eq!(count_newlines(&cfg, "::Start\n{{{a\n\n\n\n\n\nb}}}"), 0);
+    }
+
+    #[test]
+    fn test_skip_story_script() {
+        let cfg = Config::default_config();
+        let input = "::Start\nHello\n::StoryScript\nwindow.story = 1;\n::StoryStylesheet\nbody { color: red; }\n::Style [stylesheet]\np { color: blue; }\n";
+
+        let sizes = test_passage_sizes(&cfg, input);
+        let names: Vec<String> = sizes.iter().map(|size| size.name.clone()).collect();
+        assert_eq!(names, vec!["Start".to_string()]);
+    }
+
+    #[test]
+    fn test_build_time() {
+        let mut cfg = Config::default_config();
+        assert!(build_time(&cfg).len() > 0);
+
+        cfg.reproducible = true;
+        assert_eq!(build_time(&cfg), "1970-01-01 00:00");
+    }
+
+    #[test]
+    fn test_largest_passage_first() {
+        let mut cfg = Config::default_config();
+        cfg.warn_passage_size = 1000;
+
+        let mut huge = String::new();
+        for i in 0..200 {
+            huge.push_str(&format!("This is line number {} of a very long passage.\n", i));
+        }
+        let input = format!("::Start\nShort text\n[[Huge]]\n::Huge\n{}[[Short]]\n::Short\nThe end\n", huge);
+
+        let mut sizes = test_passage_sizes(&cfg, &input);
+        assert_eq!(sizes.len(), 3);
+
+        let too_large = report_passage_sizes(&cfg, &mut sizes, &mut Diagnostics::new());
+        assert_eq!(sizes[0].name, "Huge");
+        assert!(sizes[0].total() > 1000);
+        assert_eq!(too_large, vec!["Huge".to_string()]);
+    }
+
+    #[test]
+    fn test_passage_name_only_with_passage_function() {
+        let cfg = Config::default_config();
+        let stores_name = |input: &str| -> Vec<bool> {
+            generate_ops(&cfg, test_ast(&cfg, input).into_iter()).iter().map(|&(_, ref code)| code.iter().any(|op| match op {
+                &ZOP::StoreVariable{ref variable, ..} => variable.id == 19,
+                _ => false
+            })).collect()
+        };
+
+        assert_eq!(stores_name("::Start\nHello\n[[Next]]\n::Next\nBye\n"), vec![false, false]);
+        // the name is needed in every passage, even if only a later one calls passage()
+        assert_eq!(stores_name("::Start\nHello\n[[Next]]\n::Next\n<<print passage()>>\n"), vec![true, true]);
+    }
+
+    #[test]
+    fn test_passage_sizes_in_manifest() {
+        let cfg = Config::default_config();
+        let mut huge = String::new();
+        for i in 0..200 {
+            huge.push_str(&format!("This is line number {} of a very long passage.\n", i));
+        }
+        let input = format!("::Start\nShort text\n[[Huge]]\n::Huge\n{}[[Start]]\n", huge);
+
+        let manifest = generate_zcode(&cfg, test_ast(&cfg, &input).into_iter(), &mut Cursor::new(Vec::new()));
+        let names: Vec<&str> = manifest.passage_sizes.iter().map(|size| &size.name[..]).collect();
+        assert_eq!(names, vec!["Huge", "Start"]);
+
+        let huge_size = &manifest.passage_sizes[0];
+        assert!(manifest.passage_report().lines().nth(1).unwrap().ends_with(" Huge"), "{}", manifest.passage_report());
+        let json = format!(r#"[{{"name":"Huge","code":{},"strings":{},"total":{}}},"#,
+            huge_size.code_bytes, huge_size.string_bytes, huge_size.total());
+        assert!(manifest.passage_sizes_json().starts_with(&json), "{}", manifest.passage_sizes_json());
+        assert!(manifest.to_json().contains(&format!(r#""passages":{}"#, manifest.passage_sizes_json())));
+    }
+
+    #[test]
+    fn test_toc_routine() {
+        let cfg = Config::default_config();
+        let links = |passages: &[(String, bool)]| toc_routine(&cfg, passages).into_iter().filter_map(|op| match op {
+            ZOP::Call2NWithAddress{address, ..} => Some(address),
+            _ => None
+        }).collect::<Vec<String>>();
+
+        // without a [menu] tag every passage is listed, with one only the tagged ones
+        let untagged = vec![("Start".to_string(), false), ("Cellar".to_string(), false)];
+        assert_eq!(links(&untagged), vec!["Start", "Cellar"]);
+        let tagged = vec![("Start".to_string(), false), ("Cellar".to_string(), true), ("Attic".to_string(), true)];
+        assert_eq!(links(&tagged), vec!["Cellar", "Attic"]);
     }
 }
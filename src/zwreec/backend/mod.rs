@@ -18,7 +18,7 @@
 //! # let mut twee = Cursor::new("::Start\nHello World".to_string().into_bytes());
 //! #
 //! # // Clean Input
-//! # let mut cursor = zwreec::frontend::screener::handle_bom_encoding(&mut twee);
+//! # let mut cursor = zwreec::frontend::screener::handle_bom_encoding(&mut twee).unwrap();
 //! #
 //! # // Generate Token Stream
 //! # let tokens = zwreec::frontend::lexer::lex(cfg.clone(), &mut cursor);
@@ -35,5 +35,6 @@
 //! zwreec::backend::codegen::generate_zcode(&cfg, ast, &mut output);
 //! ```
 
+pub mod attest;
 pub mod zcode;
 pub mod codegen;
@@ -0,0 +1,82 @@
+//! The Twee source appended behind the story with `--embed-source`.
+//!
+//! The chunk starts at the file length of the header, which covers the Z-Code, the strings and
+//! the embedded blobs, so interpreters neither load nor verify it. It has two lines of ASCII text,
+//! followed by the source compressed with `utils::lzss`:
+//!
+//! ```text
+//! ZWREEC-SOURCE 1 lzss <length of the source> <length of the compressed source>
+//! The Twee source of this story, it can be recovered with zwreec::extract_source.
+//! ```
+//!
+//! The `1` is the version of the format.
+
+use utils::lzss;
+
+/// The first word of the chunk
+pub static SOURCE_MAGIC: &'static str = "ZWREEC-SOURCE";
+
+/// The version of the format of the chunk
+pub static SOURCE_FORMAT_VERSION: u32 = 1;
+
+/// Returns the chunk with the compressed source.
+pub fn source_chunk(source: &[u8]) -> Vec<u8> {
+    let compressed = lzss::compress(source);
+    let mut chunk = format!("{} {} lzss {} {}\nThe Twee source of this story, it can be recovered with zwreec::extract_source.\n",
+        SOURCE_MAGIC, SOURCE_FORMAT_VERSION, source.len(), compressed.len()).into_bytes();
+    chunk.extend(compressed.into_iter());
+    chunk
+}
+
+/// Returns the unit the file length in the header is counted in for the Z-Machine version.
+///
+/// The length is counted in units of 2 bytes in version 1 to 3, of 4 bytes in version 4 to 7
+/// and of 8 bytes in version 8.
+pub fn file_length_scale(version: u8) -> usize {
+    match version {
+        1 ... 3 => 2,
+        4 ... 7 => 4,
+        _ => 8
+    }
+}
+
+/// Returns the offset the file length in the header of the story points to, see
+/// `file_length_scale`.
+pub fn file_length(story: &[u8]) -> Option<usize> {
+    if story.len() < 0x40 {
+        return None;
+    }
+    Some(((story[0x1a] as usize) << 8 | story[0x1b] as usize) * file_length_scale(story[0]))
+}
+
+/// Returns the source of the chunk behind the story, or `None` if there is none or it is damaged.
+pub fn extract_source(story: &[u8]) -> Option<Vec<u8>> {
+    let start = match file_length(story) {
+        Some(length) if length > 0 && length < story.len() => length,
+        _ => return None
+    };
+    let chunk = &story[start..];
+
+    // the two lines of text in front of the compressed source
+    let newlines: Vec<usize> = chunk.iter().enumerate().filter(|&(_, &byte)| byte == b'\n').map(|(index, _)| index).take(2).collect();
+    if newlines.len() < 2 {
+        return None;
+    }
+    let description = String::from_utf8_lossy(&chunk[..newlines[0]]).into_owned();
+    let header_length = newlines[1] + 1;
+
+    let fields: Vec<&str> = description.split(' ').collect();
+    if fields.len() != 5 || fields[0] != SOURCE_MAGIC || fields[1] != SOURCE_FORMAT_VERSION.to_string() || fields[2] != "lzss" {
+        return None;
+    }
+    let (source_length, compressed_length): (usize, usize) = match (fields[3].parse(), fields[4].parse()) {
+        (Ok(source_length), Ok(compressed_length)) => (source_length, compressed_length),
+        _ => return None
+    };
+    if header_length + compressed_length > chunk.len() {
+        return None;
+    }
+
+    lzss::decompress(&chunk[header_length .. header_length + compressed_length])
+        .and_then(|source| if source.len() == source_length { Some(source) } else { None })
+}
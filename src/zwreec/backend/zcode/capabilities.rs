@@ -0,0 +1,212 @@
+//! The optional features of the Z-Machine a story uses.
+//!
+//! Not every interpreter supports everything the Z-Machine standard describes, so `Zfile`
+//! records which of these features the written opcodes use. The report only lists the features
+//! of code that can run: the code in front of the first routine, the routines of the passages
+//! and every routine they call. A feature is optional if the runtime checks that the
+//! interpreter supports it and goes on without it otherwise, like timed input.
+//!
+//! # Example
+//!
+//! ```
+//! use zwreec::backend::zcode::capabilities::{Capability, CapabilitySet, CapabilityReport};
+//!
+//! let mut used = CapabilitySet::new();
+//! used.insert(Capability::Unicode);
+//! used.insert(Capability::TimedInput);
+//!
+//! let report = CapabilityReport::new(used);
+//! assert_eq!(format!("{}", report), "requires: unicode output; optional: timed input");
+//! ```
+
+use std::fmt;
+
+use utils::json;
+
+/// A feature of the Z-Machine that an interpreter may not support.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Capability {
+    /// `print_unicode` for characters outside of the unicode translation table
+    Unicode,
+
+    /// `set_colour`
+    Colours,
+
+    /// `set_true_colour`
+    TrueColour,
+
+    /// `read_char` with a timeout
+    TimedInput,
+
+    /// `sound_effect`
+    Sound,
+
+    /// `split_window`, `set_window` and `set_cursor`
+    Windows,
+
+    /// `save` and `restore`
+    SaveRestore,
+}
+
+/// All capabilities in the order of the report.
+pub static CAPABILITIES: &'static [Capability] = &[
+    Capability::Unicode, Capability::Colours, Capability::TrueColour, Capability::TimedInput, Capability::Sound,
+    Capability::Windows, Capability::SaveRestore];
+
+impl Capability {
+    /// Returns the name of the capability in the report.
+    pub fn name(&self) -> &'static str {
+        match self {
+            &Capability::Unicode => "unicode output",
+            &Capability::Colours => "colour",
+            &Capability::TrueColour => "true colour",
+            &Capability::TimedInput => "timed input",
+            &Capability::Sound => "sound effects",
+            &Capability::Windows => "upper window",
+            &Capability::SaveRestore => "save/restore",
+        }
+    }
+
+    /// Checks if the runtime works without the capability.
+    ///
+    /// The runtime only uses timed input if bit 7 of Flags 1 says the interpreter supports it.
+    pub fn is_optional(&self) -> bool {
+        match self {
+            &Capability::TimedInput => true,
+            _ => false
+        }
+    }
+
+    fn bit(&self) -> u8 {
+        1 << (*self as u8)
+    }
+}
+
+/// A set of capabilities.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CapabilitySet {
+    bits: u8,
+}
+
+impl CapabilitySet {
+    /// Creates an empty set.
+    pub fn new() -> CapabilitySet {
+        CapabilitySet { bits: 0 }
+    }
+
+    /// Adds the capability to the set.
+    pub fn insert(&mut self, capability: Capability) {
+        self.bits |= capability.bit();
+    }
+
+    /// Checks if the capability is in the set.
+    pub fn contains(&self, capability: Capability) -> bool {
+        self.bits & capability.bit() != 0
+    }
+
+    /// Returns the capabilities in either of the sets.
+    pub fn union(&self, other: CapabilitySet) -> CapabilitySet {
+        CapabilitySet { bits: self.bits | other.bits }
+    }
+
+    /// Returns the capabilities of the set in the order of `CAPABILITIES`.
+    pub fn capabilities(&self) -> Vec<Capability> {
+        CAPABILITIES.iter().filter(|capability| self.contains(**capability)).map(|capability| *capability).collect()
+    }
+}
+
+/// The capabilities a compiled story needs from the interpreter.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CapabilityReport {
+    /// The story does not work without these
+    pub required: Vec<Capability>,
+
+    /// The story uses these if the interpreter supports them
+    pub optional: Vec<Capability>,
+}
+
+impl CapabilityReport {
+    /// Sorts the used capabilities into required and optional ones.
+    pub fn new(used: CapabilitySet) -> CapabilityReport {
+        let (optional, required): (Vec<Capability>, Vec<Capability>) =
+            used.capabilities().into_iter().partition(|capability| capability.is_optional());
+        CapabilityReport { required: required, optional: optional }
+    }
+
+    /// Checks if the story uses the capability, required or optional.
+    pub fn uses(&self, capability: Capability) -> bool {
+        self.required.contains(&capability) || self.optional.contains(&capability)
+    }
+
+    /// Returns the report as a JSON object with the names of the `required` and `optional`
+    /// capabilities.
+    pub fn to_json(&self) -> String {
+        let names = |capabilities: &[Capability]| json::array(&capabilities.iter().map(|capability| json::string(capability.name())).collect::<Vec<String>>());
+        json::object(&[("required", names(&self.required)), ("optional", names(&self.optional))])
+    }
+}
+
+impl fmt::Display for CapabilityReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let names = |capabilities: &[Capability]| capabilities.iter().map(|capability| capability.name()).collect::<Vec<&str>>().join(", ");
+        if self.required.is_empty() {
+            try!(write!(f, "requires: nothing"));
+        } else {
+            try!(write!(f, "requires: {}", names(&self.required)));
+        }
+        if !self.optional.is_empty() {
+            try!(write!(f, "; optional: {}", names(&self.optional)));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Capability, CapabilitySet, CapabilityReport};
+
+    #[test]
+    fn test_capability_set() {
+        let mut used = CapabilitySet::new();
+        assert!(used.capabilities().is_empty());
+        used.insert(Capability::SaveRestore);
+        used.insert(Capability::Colours);
+        used.insert(Capability::Colours);
+        assert!(used.contains(Capability::Colours));
+        assert!(!used.contains(Capability::Unicode));
+        assert_eq!(used.capabilities(), vec![Capability::Colours, Capability::SaveRestore]);
+
+        let mut other = CapabilitySet::new();
+        other.insert(Capability::Unicode);
+        assert_eq!(used.union(other).capabilities(), vec![Capability::Unicode, Capability::Colours, Capability::SaveRestore]);
+    }
+
+    #[test]
+    fn test_capability_report() {
+        assert_eq!(format!("{}", CapabilityReport::new(CapabilitySet::new())), "requires: nothing");
+
+        let mut used = CapabilitySet::new();
+        used.insert(Capability::TimedInput);
+        let report = CapabilityReport::new(used);
+        assert_eq!(format!("{}", report), "requires: nothing; optional: timed input");
+        assert!(report.uses(Capability::TimedInput));
+
+        used.insert(Capability::Colours);
+        used.insert(Capability::Unicode);
+        assert_eq!(format!("{}", CapabilityReport::new(used)), "requires: unicode output, colour; optional: timed input");
+
+        used.insert(Capability::Sound);
+        used.insert(Capability::TrueColour);
+        assert_eq!(format!("{}", CapabilityReport::new(used)), "requires: unicode output, colour, true colour, sound effects; optional: timed input");
+    }
+
+    #[test]
+    fn test_capability_report_json() {
+        assert_eq!(CapabilityReport::new(CapabilitySet::new()).to_json(), r#"{"required":[],"optional":[]}"#);
+
+        let mut used = CapabilitySet::new();
+        used.insert(Capability::TimedInput);
+        used.insert(Capability::Sound);
+        assert_eq!(CapabilityReport::new(used).to_json(), r#"{"required":["sound effects"],"optional":["timed input"]}"#);
+    }
+}
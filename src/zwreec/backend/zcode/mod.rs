@@ -5,9 +5,15 @@
 //! It is split into multiple parts: The [zfile](./zfile/index.html) module contains all high-level
 //! features to generate Z-Code files. [zbytes](./zbytes/index.html) and [op](./op/index.html)
 //! contain the code that deals with low-level encodings
-//! and op-codes. [ee](./ee/index.html) contains an easter egg.
+//! and op-codes. [peephole](./peephole/index.html) optimizes the generated op-codes before they
+//! are written. [capabilities](./capabilities/index.html) lists the features of the interpreter
+//! a story uses. [archive](./archive/index.html) appends the Twee source behind the story.
+//! [ee](./ee/index.html) contains an easter egg.
 
+pub mod archive;
+pub mod capabilities;
 pub mod op;
+pub mod peephole;
 pub mod zbytes;
 pub mod zfile;
 pub mod ztext;
@@ -33,8 +39,9 @@ pub fn temp_create_zcode_example<W: Write>(output: &mut W) {
         ZOP::Call2S{jump_to_label: "itoa".to_string(), arg: Operand::new_large_const(1337), result: Variable::new(2)},
         ZOP::SetVarType{variable: Variable::new(1), vartype: Type::Integer},
         ZOP::SetVarType{variable: Variable::new(2), vartype: Type::String},
-        ZOP::AddTypes{operand1: Operand::new_var(1), operand2: Operand::new_var(2), tmp1: Variable::new(3), tmp2: Variable::new(4), save_variable: Variable::new(1)},
-        ZOP::AddTypes{operand1: Operand::new_var(1), operand2: Operand::new_var(2), tmp1: Variable::new(3), tmp2: Variable::new(4), save_variable: Variable::new(1)},
+        ZOP::AddTypes{operand1: Operand::new_var(1), operand2: Operand::new_var(2), tmp1: Variable::new(3), tmp2: Variable::new(4), save_variable: Variable::new(5)},
+        ZOP::PrintUnicodeStr{address: Operand::new_var(5)},
+        ZOP::Newline,
         ZOP::PrintUnicodeStr{address: Operand::new_var(1)},
         ZOP::Newline,
         ZOP::PrintVar{variable: Variable::new(1)},
@@ -42,6 +49,37 @@ pub fn temp_create_zcode_example<W: Write>(output: &mut W) {
         ]);
     zfile.end();
 
+    write_example(&zfile, output);
+}
+
+/// An example calling the string routines, it prints `1337-42`, `0` and `1`.
+///
+/// `itoa` creates both strings, `strcat` joins them and `strcmp` compares the first string with
+/// itself and with the second string.
+pub fn string_routines_example<W: Write>(output: &mut W) {
+    let mut zfile: Zfile = zfile::Zfile::new();
+
+    zfile.start();
+    zfile.emit(vec![
+        ZOP::Routine{name: "Start".to_string(), count_variables: 4},
+        ZOP::Call2S{jump_to_label: "itoa".to_string(), arg: Operand::new_large_const(1337), result: Variable::new(1)},
+        ZOP::Call2S{jump_to_label: "itoa".to_string(), arg: Operand::new_large_const(-42), result: Variable::new(2)},
+        ZOP::CallVSA2{jump_to_label: "strcat".to_string(), arg1: Operand::new_var(1), arg2: Operand::new_var(2), result: Variable::new(3)},
+        ZOP::PrintUnicodeStr{address: Operand::new_var(3)},
+        ZOP::Newline,
+        ZOP::CallVSA2{jump_to_label: "strcmp".to_string(), arg1: Operand::new_var(1), arg2: Operand::new_var(1), result: Variable::new(4)},
+        ZOP::PrintNumVar{variable: Variable::new(4)},
+        ZOP::Newline,
+        ZOP::CallVSA2{jump_to_label: "strcmp".to_string(), arg1: Operand::new_var(1), arg2: Operand::new_var(2), result: Variable::new(4)},
+        ZOP::PrintNumVar{variable: Variable::new(4)},
+        ZOP::Quit,
+        ]);
+    zfile.end();
+
+    write_example(&zfile, output);
+}
+
+fn write_example<W: Write>(zfile: &Zfile, output: &mut W) {
     match output.write_all(&(*zfile.data.bytes)) {
         Err(why) => {
             panic!("Could not write to output: {}", Error::description(&why));
@@ -1,151 +1,166 @@
 //! Contains most of the Z-Code opcodes (the opcodes that use jumps or labels
 //! are still in zfile)
+//!
+//! The opcodes are appended to the given byte vector, usually the data of the `Zfile`, so
+//! encoding an instruction allocates nothing by itself.
 
 pub use super::zfile::ArgType;
 pub use super::zfile::JumpType;
 pub use super::zfile::Zjump;
 pub use super::zfile::Zfile;
-pub use super::zfile::{ Operand, Variable, Constant, LargeConstant };
+pub use super::zfile::{ Operand, Variable, Constant, LargeConstant, Address };
 
 /// Clears specified window
-pub fn op_erase_window(value: i8) -> Vec<u8> {
-    let args: Vec<ArgType> = vec![ArgType::LargeConst, ArgType::Nothing, ArgType::Nothing, ArgType::Nothing];
-    let mut bytes = op_var(0x0d, args);
+pub fn op_erase_window(value: i8, bytes: &mut Vec<u8>) {
+    let args = [ArgType::LargeConst, ArgType::Nothing, ArgType::Nothing, ArgType::Nothing];
+    op_var(0x0d, &args, bytes);
 
     // signed to unsigned value
-    write_u16(value as u16, &mut bytes);
-    bytes
+    write_u16(value as u16, bytes);
+}
+
+/// Splits the screen, the upper window gets `lines` lines
+pub fn op_split_window(lines: u8, bytes: &mut Vec<u8>) {
+    let args = [ArgType::SmallConst, ArgType::Nothing, ArgType::Nothing, ArgType::Nothing];
+    op_var(0x0a, &args, bytes);
+    bytes.push(lines);
+}
+
+/// Splits the screen, the upper window gets the lines of `lines`, like a variable
+pub fn op_split_window_operand(lines: &Operand, bytes: &mut Vec<u8>) {
+    let args = [arg_type(lines), ArgType::Nothing, ArgType::Nothing, ArgType::Nothing];
+    op_var(0x0a, &args, bytes);
+    write_argument(lines, bytes);
+}
+
+/// Selects the window which receives the output
+pub fn op_set_window(window: u8, bytes: &mut Vec<u8>) {
+    let args = [ArgType::SmallConst, ArgType::Nothing, ArgType::Nothing, ArgType::Nothing];
+    op_var(0x0b, &args, bytes);
+    bytes.push(window);
 }
 
 /// Stores row and column as two u16 words to the given addr
-pub fn op_get_cursor(store_addr: &Operand) -> Vec<u8> {
-    let args: Vec<ArgType> = vec![arg_type(&store_addr), ArgType::Nothing, ArgType::Nothing, ArgType::Nothing];
-    let mut bytes = op_var(0x10, args);
+pub fn op_get_cursor(store_addr: &Operand, bytes: &mut Vec<u8>) {
+    let args = [arg_type(&store_addr), ArgType::Nothing, ArgType::Nothing, ArgType::Nothing];
+    op_var(0x10, &args, bytes);
     // array address
-    write_argument(store_addr, &mut bytes);
-    bytes
+    write_argument(store_addr, bytes);
 }
 
 /// Calls a routine (the address is stored in a variable)
-pub fn op_call_1n_var(variable: u8) -> Vec<u8> {
-    let mut bytes = op_1(0x0f, ArgType::Variable);
+pub fn op_call_1n_var(variable: u8, bytes: &mut Vec<u8>) {
+    op_1(0x0f, ArgType::Variable, bytes);
     bytes.push(variable);
-    bytes
 }
 
 
 /// Stores a value to an array
 ///
 /// Stores the value of the variable to the address in: `array_address + 2*index`
-pub fn op_storew(array_address: &Operand, index: &Variable, variable: &Variable) -> Vec<u8> {
+pub fn op_storew(array_address: &Operand, index: &Variable, variable: &Variable, bytes: &mut Vec<u8>) {
     // assert!(array_address > 0, "not allowed array-address, becouse in _some_ interpreters (for example zoom) it crahs. -.-");
-    let args: Vec<ArgType> = vec![arg_type(&array_address), ArgType::Variable, ArgType::Variable, ArgType::Nothing];
-    let mut bytes = op_var(0x01, args);
+    let args = [arg_type(&array_address), ArgType::Variable, ArgType::Variable, ArgType::Nothing];
+    op_var(0x01, &args, bytes);
 
     // array address
-    write_argument(array_address, &mut bytes);
+    write_argument(array_address, bytes);
 
     // array index
     bytes.push(index.id);
 
     // value
     bytes.push(variable.id);
-    bytes
 }
 
 
 /// Stores a value to an array
 ///
 /// Stores the value of the variable to the address in: `array_address + index`
-pub fn op_storeb(array_address: &Operand, index: &Variable, variable: &Variable) -> Vec<u8> {
+pub fn op_storeb(array_address: &Operand, index: &Variable, variable: &Variable, bytes: &mut Vec<u8>) {
     // assert!(array_address > 0, "not allowed array-address, becouse in _some_ interpreters (for example zoom) it crahs. -.-");
-    let args: Vec<ArgType> = vec![arg_type(&array_address), ArgType::Variable, ArgType::Variable, ArgType::Nothing];
-    let mut bytes = op_var(0x02, args);
+    let args = [arg_type(&array_address), ArgType::Variable, ArgType::Variable, ArgType::Nothing];
+    op_var(0x02, &args, bytes);
 
     // array address
-    write_argument(array_address, &mut bytes);
+    write_argument(array_address, bytes);
 
     // array index
     bytes.push(index.id);
 
     // value
     bytes.push(variable.id);
-    bytes
 }
 
 /// Stores a value to an array
 ///
 /// Stores the value of operand to the address in: `array_address + index`
-pub fn op_storeboperand(array_address: &Operand, index: &Operand, operand: &Operand) -> Vec<u8> {
+pub fn op_storeboperand(array_address: &Operand, index: &Operand, operand: &Operand, bytes: &mut Vec<u8>) {
     // assert!(array_address > 0, "not allowed array-address, becouse in _some_ interpreters (for example zoom) it crahs. -.-");
-    let args: Vec<ArgType> = vec![arg_type(&array_address), arg_type(&index), arg_type(&operand), ArgType::Nothing];
-    let mut bytes = op_var(0x02, args);
+    let args = [arg_type(&array_address), arg_type(&index), arg_type(&operand), ArgType::Nothing];
+    op_var(0x02, &args, bytes);
 
     // array address
-    write_argument(array_address, &mut bytes);
+    write_argument(array_address, bytes);
 
     // array index
-    write_argument(index, &mut bytes);
+    write_argument(index, bytes);
 
     // value
-    write_argument(operand, &mut bytes);
-    bytes
+    write_argument(operand, bytes);
 }
 
 /// Loads a byte from an array in a variable
 ///
 /// `loadb` is a 2op, BUT with 3 ops -.-
-pub fn op_loadb(array_address: &Operand, index: &Operand, variable: &Variable) -> Vec<u8> {
-    let mut bytes = op_2(0x10, vec![arg_type(&array_address), arg_type(&index)]);
+pub fn op_loadb(array_address: &Operand, index: &Operand, variable: &Variable, bytes: &mut Vec<u8>) {
+    op_2(0x10, &[arg_type(&array_address), arg_type(&index)], bytes);
 
     // array address
-    write_argument(array_address, &mut bytes);
+    write_argument(array_address, bytes);
     // array index
-    write_argument(index, &mut bytes);
+    write_argument(index, bytes);
 
     // variable
     bytes.push(variable.id);
-    bytes
 }
 
 
 /// Loads a word from an array in a variable
 ///
 /// `loadw` is a 2op, BUT with 3 ops -.-
-pub fn op_loadw(array_address: &Operand, index: &Variable, variable: &Variable) -> Vec<u8> {
-    let mut bytes = op_2(0x0f, vec![arg_type(&array_address), ArgType::Variable]);
+pub fn op_loadw(array_address: &Operand, index: &Variable, variable: &Variable, bytes: &mut Vec<u8>) {
+    op_2(0x0f, &[arg_type(&array_address), ArgType::Variable], bytes);
 
     // array address
-    write_argument(array_address, &mut bytes);
+    write_argument(array_address, bytes);
     // array index
     bytes.push(index.id);
 
     // variable
     bytes.push(variable.id);
-    bytes
 }
 
 
 /// Reads keys from the keyboard and saves the asci-value in `local_var_id`
 ///
 /// read_char is VAROP
-pub fn op_read_char(local_var_id: u8) -> Vec<u8> {
-    let args: Vec<ArgType> = vec![ArgType::SmallConst, ArgType::Nothing, ArgType::Nothing, ArgType::Nothing];
-    let mut bytes = op_var(0x16, args);
+pub fn op_read_char(local_var_id: u8, bytes: &mut Vec<u8>) {
+    let args = [ArgType::SmallConst, ArgType::Nothing, ArgType::Nothing, ArgType::Nothing];
+    op_var(0x16, &args, bytes);
 
     // write argument value
     bytes.push(0x01);
 
     // write varible id
     bytes.push(local_var_id);
-    bytes
 }
 
 
 /// Set the style of the text to `bold`, `reverse` (inverse colors), `monospace` and `italic`
-pub fn op_set_text_style(bold: bool, reverse: bool, monospace: bool, italic: bool) -> Vec<u8> {
-    let args: Vec<ArgType> = vec![ArgType::SmallConst, ArgType::Nothing, ArgType::Nothing, ArgType::Nothing];
-    let mut bytes = op_var(0x11, args);
+pub fn op_set_text_style(bold: bool, reverse: bool, monospace: bool, italic: bool, bytes: &mut Vec<u8>) {
+    let args = [ArgType::SmallConst, ArgType::Nothing, ArgType::Nothing, ArgType::Nothing];
+    op_var(0x11, &args, bytes);
 
     let mut style_byte : u8;
     style_byte = 0x00;
@@ -162,260 +177,302 @@ pub fn op_set_text_style(bold: bool, reverse: bool, monospace: bool, italic: boo
         style_byte |=0x04
     }
     bytes.push(style_byte);
-    bytes
 }
 
 /// Positions the cursor at the specified `line` and `column`
-pub fn op_set_cursor(line: u8, col: u8) -> Vec<u8> {
-    let args: Vec<ArgType> = vec![ArgType::SmallConst, ArgType::SmallConst, ArgType::Nothing, ArgType::Nothing];
-    let mut bytes = op_var(0xF, args);
+pub fn op_set_cursor(line: u8, col: u8, bytes: &mut Vec<u8>) {
+    let args = [ArgType::SmallConst, ArgType::SmallConst, ArgType::Nothing, ArgType::Nothing];
+    op_var(0xF, &args, bytes);
 
     // write argument values
     bytes.push(line);
     bytes.push(col);
-    bytes
 }
 
 /// Positions the cursor at the `line` and `column` in the given Operands
-pub fn op_set_cursor_operand(row: &Operand, col: &Operand) -> Vec<u8> {
-    let args: Vec<ArgType> = vec![arg_type(&row), arg_type(&col), ArgType::Nothing, ArgType::Nothing];
-    let mut bytes = op_var(0xF, args);
+pub fn op_set_cursor_operand(row: &Operand, col: &Operand, bytes: &mut Vec<u8>) {
+    let args = [arg_type(&row), arg_type(&col), ArgType::Nothing, ArgType::Nothing];
+    op_var(0xF, &args, bytes);
     // write argument values
-    write_argument(row, &mut bytes);
-    write_argument(col, &mut bytes);
-    bytes
+    write_argument(row, bytes);
+    write_argument(col, bytes);
 }
 
 /// Erases the rest of the current line starting from the cursor
-pub fn op_erase_line() -> Vec<u8> {
-    let args: Vec<ArgType> = vec![ArgType::SmallConst, ArgType::Nothing, ArgType::Nothing, ArgType::Nothing];
-    let mut bytes = op_var(0xE, args);
+pub fn op_erase_line(bytes: &mut Vec<u8>) {
+    let args = [ArgType::SmallConst, ArgType::Nothing, ArgType::Nothing, ArgType::Nothing];
+    op_var(0xE, &args, bytes);
     bytes.push(1);
-    bytes
 }
 
 /// Prints the value of an integer variable
-pub fn op_print_num_var(variable: &Variable) -> Vec<u8> {
-    let args: Vec<ArgType> = vec![ArgType::Variable, ArgType::Nothing, ArgType::Nothing, ArgType::Nothing];
-    let mut bytes = op_var(0x06, args);
+pub fn op_print_num_var(variable: &Variable, bytes: &mut Vec<u8>) {
+    let args = [ArgType::Variable, ArgType::Nothing, ArgType::Nothing, ArgType::Nothing];
+    op_var(0x06, &args, bytes);
     bytes.push(variable.id);
-    bytes
 }
 
 /// Pulls value off the stack to a variable
 ///
 /// SmallConst because pull takes a reference to a variable
-pub fn op_pull(variable: u8) -> Vec<u8> {
-    let args: Vec<ArgType> = vec![ArgType::SmallConst, ArgType::Nothing, ArgType::Nothing, ArgType::Nothing];
-    let mut bytes = op_var(0x09, args);
+pub fn op_pull(variable: u8, bytes: &mut Vec<u8>) {
+    let args = [ArgType::SmallConst, ArgType::Nothing, ArgType::Nothing, ArgType::Nothing];
+    op_var(0x09, &args, bytes);
     bytes.push(variable);
-    bytes
 }
 
 /// Calculates a random number from 1 to range
-pub fn op_random(range: &Operand, variable: &Variable) -> Vec<u8> {
-    let args: Vec<ArgType> = vec![arg_type(range), ArgType::Nothing, ArgType::Nothing, ArgType::Nothing];
-    let mut bytes = op_var(0x07, args);
-    write_argument(range, &mut bytes);
+pub fn op_random(range: &Operand, variable: &Variable, bytes: &mut Vec<u8>) {
+    let args = [arg_type(range), ArgType::Nothing, ArgType::Nothing, ArgType::Nothing];
+    op_var(0x07, &args, bytes);
+    write_argument(range, bytes);
     bytes.push(variable.id);
-    bytes
 }
 
 /// Pushes a u16 value (for example an address) on the stack
-pub fn op_push_u16(value: u16) -> Vec<u8> {
-    let args: Vec<ArgType> = vec![ArgType::LargeConst, ArgType::Nothing, ArgType::Nothing, ArgType::Nothing];
-    let mut bytes = op_var(0x08, args);
-    write_u16(value, &mut bytes);
-    bytes
+pub fn op_push_u16(value: u16, bytes: &mut Vec<u8>) {
+    let args = [ArgType::LargeConst, ArgType::Nothing, ArgType::Nothing, ArgType::Nothing];
+    op_var(0x08, &args, bytes);
+    write_u16(value, bytes);
 }
 
 /// Pushes a variable on the stack
-pub fn op_push_var(variable: &Variable) -> Vec<u8> {
-    let args: Vec<ArgType> = vec![ArgType::Variable, ArgType::Nothing, ArgType::Nothing, ArgType::Nothing];
-    let mut bytes = op_var(0x08, args);
+pub fn op_push_var(variable: &Variable, bytes: &mut Vec<u8>) {
+    let args = [ArgType::Variable, ArgType::Nothing, ArgType::Nothing, ArgType::Nothing];
+    op_var(0x08, &args, bytes);
     bytes.push(variable.id);
-    bytes
 }
 
 /// Sets the colors of the foreground (font) and background to the values in the variables
-pub fn op_set_color_var(foreground: u8, background: u8) -> Vec<u8> {
-    let args: Vec<ArgType> = vec![ArgType::Variable, ArgType::Variable];
-    let mut bytes = op_2(0x1b, args);
+pub fn op_set_color_var(foreground: u8, background: u8, bytes: &mut Vec<u8>) {
+    let args = [ArgType::Variable, ArgType::Variable];
+    op_2(0x1b, &args, bytes);
     bytes.push(foreground);
     bytes.push(background);
-    bytes
 }
 
 /// Sets the colors of the foreground (font) and background to the constants specified
-pub fn op_set_color(foreground: u8, background: u8) -> Vec<u8> {
-    let args: Vec<ArgType> = vec![ArgType::SmallConst, ArgType::SmallConst];
-    let mut bytes = op_2(0x1b, args);
+pub fn op_set_color(foreground: u8, background: u8, bytes: &mut Vec<u8>) {
+    let args = [ArgType::SmallConst, ArgType::SmallConst];
+    op_2(0x1b, &args, bytes);
     bytes.push(foreground);
     bytes.push(background);
-    bytes
+}
+
+/// Sets the foreground and background colour to 15 bit RGB values (`0bbbbbgggggrrrrr`)
+///
+/// -1 keeps the current colour and -2 restores the default colour.
+pub fn op_set_true_color(foreground: &Operand, background: &Operand, bytes: &mut Vec<u8>) {
+    op_ext(0x0d, &[arg_type(foreground), arg_type(background), ArgType::Nothing, ArgType::Nothing], bytes);
+    write_argument(foreground, bytes);
+    write_argument(background, bytes);
+}
+
+/// Plays the sound effect `number`, the numbers 1 and 2 are a high and a low beep
+pub fn op_sound_effect(number: &Operand, bytes: &mut Vec<u8>) {
+    let args = [arg_type(number), ArgType::Nothing, ArgType::Nothing, ArgType::Nothing];
+    op_var(0x15, &args, bytes);
+    write_argument(number, bytes);
 }
 
 /// Prints string at given packed address
 ///
 /// (which is then multiplied by 8 by the Z-Machine for the real address)
-pub fn op_print_paddr(address: &Operand) -> Vec<u8> {
-   let mut bytes = op_1(0x0D, arg_type(&address));
-   write_argument(address, &mut bytes);
-   bytes
+pub fn op_print_paddr(address: &Operand, bytes: &mut Vec<u8>) {
+   op_1(0x0D, arg_type(&address), bytes);
+   write_argument(address, bytes);
 }
 
 /// Prints string at given adress
-pub fn op_print_addr(address: &Operand) -> Vec<u8> {
-   let mut bytes = op_1(0x07, arg_type(&address));
-   write_argument(address, &mut bytes);
-   bytes
+pub fn op_print_addr(address: &Operand, bytes: &mut Vec<u8>) {
+   op_1(0x07, arg_type(&address), bytes);
+   write_argument(address, bytes);
 }
 
 /// Returns a LargeConst
-pub fn op_ret(value: &Operand) -> Vec<u8> {
-    let mut bytes = op_1(0x0b, arg_type(&value));
-    write_argument(value, &mut bytes);
-    bytes
+pub fn op_ret(value: &Operand, bytes: &mut Vec<u8>) {
+    op_1(0x0b, arg_type(&value), bytes);
+    write_argument(value, bytes);
 }
 
 /// Saves an operand to the specified variable
-pub fn op_store_var(variable: &Variable, value: &Operand) -> Vec<u8> {
-    let args: Vec<ArgType> = vec![ArgType::Reference, arg_type(&value)];
-    let mut bytes = op_2(0x0d, args);
+pub fn op_store_var(variable: &Variable, value: &Operand, bytes: &mut Vec<u8>) {
+    let args = [ArgType::Reference, arg_type(&value)];
+    op_2(0x0d, &args, bytes);
     bytes.push(variable.id);
-    write_argument(value, &mut bytes);
-    bytes
+    write_argument(value, bytes);
 }
 
 /// Bitwise OR operation:
 /// `save_variable = operand1 | operand2`
-pub fn op_or(operand1: &Operand, operand2: &Operand, save_variable: &Variable) -> Vec<u8> {
-    let args: Vec<ArgType> = vec![arg_type(operand1), arg_type(operand2)];
-    let mut bytes = op_2(0x08, args);
-    write_argument(operand1, &mut bytes);
-    write_argument(operand2, &mut bytes);
+pub fn op_or(operand1: &Operand, operand2: &Operand, save_variable: &Variable, bytes: &mut Vec<u8>) {
+    let args = [arg_type(operand1), arg_type(operand2)];
+    op_2(0x08, &args, bytes);
+    write_argument(operand1, bytes);
+    write_argument(operand2, bytes);
     bytes.push(save_variable.id);
-    bytes
 }
 
 /// Bitwise AND:
 /// `save_variable = operand1 & operand2`
-pub fn op_and(operand1: &Operand, operand2: &Operand, save_variable: &Variable) -> Vec<u8> {
-    let args: Vec<ArgType> = vec![arg_type(operand1), arg_type(operand2)];
-    let mut bytes = op_2(0x09, args);
-    write_argument(operand1, &mut bytes);
-    write_argument(operand2, &mut bytes);
+pub fn op_and(operand1: &Operand, operand2: &Operand, save_variable: &Variable, bytes: &mut Vec<u8>) {
+    let args = [arg_type(operand1), arg_type(operand2)];
+    op_2(0x09, &args, bytes);
+    write_argument(operand1, bytes);
+    write_argument(operand2, bytes);
     bytes.push(save_variable.id);
-    bytes
 }
 
 /// Bitwise NOT:
 /// `variable = ~arg`
-pub fn op_not(arg: &Operand, variable: &Variable) -> Vec<u8> {
-    let args: Vec<ArgType> = vec![arg_type(arg), ArgType::Nothing, ArgType::Nothing, ArgType::Nothing];
-    let mut bytes = op_var(0x18, args);
-    write_argument(arg, &mut bytes);
+pub fn op_not(arg: &Operand, variable: &Variable, bytes: &mut Vec<u8>) {
+    let args = [arg_type(arg), ArgType::Nothing, ArgType::Nothing, ArgType::Nothing];
+    op_var(0x18, &args, bytes);
+    write_argument(arg, bytes);
+    bytes.push(variable.id);
+}
+
+/// Copies `size` bytes from the table at `first` to the table at `second`
+///
+/// A `second` of 0 zeroes the bytes at `first` instead. A positive size copies correctly even if
+/// the tables overlap, a negative size always copies forwards, so a shifted copy repeats the
+/// first bytes.
+pub fn op_copy_table(first: &Operand, second: &Operand, size: &Operand, bytes: &mut Vec<u8>) {
+    let args = [arg_type(first), arg_type(second), arg_type(size), ArgType::Nothing];
+    op_var(0x1d, &args, bytes);
+    write_argument(first, bytes);
+    write_argument(second, bytes);
+    write_argument(size, bytes);
+}
+
+/// Saves the game, the interpreter asks the player for the file or slot.
+///
+/// Stores 0 in `variable` if saving failed, 1 if it succeeded and 2 when the game continues here
+/// after a restore.
+pub fn op_save(variable: &Variable, bytes: &mut Vec<u8>) {
+    op_ext(0x00, &[ArgType::Nothing, ArgType::Nothing, ArgType::Nothing, ArgType::Nothing], bytes);
+    bytes.push(variable.id);
+}
+
+/// Restores a saved game, the interpreter asks the player for the file or slot.
+///
+/// Only continues if restoring failed, then 0 is stored in `variable`.
+pub fn op_restore(variable: &Variable, bytes: &mut Vec<u8>) {
+    op_ext(0x01, &[ArgType::Nothing, ArgType::Nothing, ArgType::Nothing, ArgType::Nothing], bytes);
     bytes.push(variable.id);
-    bytes
 }
 
 /// Subtraction operation:
 /// `save_variable = operand1 - operand2`
-pub fn op_sub(operand1: &Operand, operand2: &Operand, save_variable: &Variable) -> Vec<u8> {
-    let args: Vec<ArgType> = vec![arg_type(operand1), arg_type(operand2)];
-    let mut bytes = op_2(0x15, args);
-    write_argument(operand1, &mut bytes);
-    write_argument(operand2, &mut bytes);
+pub fn op_sub(operand1: &Operand, operand2: &Operand, save_variable: &Variable, bytes: &mut Vec<u8>) {
+    let args = [arg_type(operand1), arg_type(operand2)];
+    op_2(0x15, &args, bytes);
+    write_argument(operand1, bytes);
+    write_argument(operand2, bytes);
     bytes.push(save_variable.id);
-    bytes
 }
 
 /// Addition:
 /// `save_variable = operand1 + operand2`
-pub fn op_add(operand1: &Operand, operand2: &Operand, save_variable: &Variable) -> Vec<u8> {
-    let args: Vec<ArgType> = vec![arg_type(operand1), arg_type(operand2)];
-    let mut bytes = op_2(0x14, args);
-    write_argument(operand1, &mut bytes);
-    write_argument(operand2, &mut bytes);
+pub fn op_add(operand1: &Operand, operand2: &Operand, save_variable: &Variable, bytes: &mut Vec<u8>) {
+    let args = [arg_type(operand1), arg_type(operand2)];
+    op_2(0x14, &args, bytes);
+    write_argument(operand1, bytes);
+    write_argument(operand2, bytes);
     bytes.push(save_variable.id);
-    bytes
 }
 
 /// Multiplikation:
 /// `save_variable = operand1 * operand2`
-pub fn op_mul(operand1: &Operand, operand2: &Operand, save_variable: &Variable) -> Vec<u8> {
-    let args: Vec<ArgType> = vec![arg_type(operand1), arg_type(operand2)];
-    let mut bytes = op_2(0x16, args);
-    write_argument(operand1, &mut bytes);
-    write_argument(operand2, &mut bytes);
+pub fn op_mul(operand1: &Operand, operand2: &Operand, save_variable: &Variable, bytes: &mut Vec<u8>) {
+    let args = [arg_type(operand1), arg_type(operand2)];
+    op_2(0x16, &args, bytes);
+    write_argument(operand1, bytes);
+    write_argument(operand2, bytes);
     bytes.push(save_variable.id);
-    bytes
 }
 
 /// Division:
 /// `save_variable = operand1 / operand2`
-pub fn op_div(operand1: &Operand, operand2: &Operand, save_variable: &Variable) -> Vec<u8> {
-    let args: Vec<ArgType> = vec![arg_type(operand1), arg_type(operand2)];
-    let mut bytes = op_2(0x17, args);
-    write_argument(operand1, &mut bytes);
-    write_argument(operand2, &mut bytes);
+pub fn op_div(operand1: &Operand, operand2: &Operand, save_variable: &Variable, bytes: &mut Vec<u8>) {
+    let args = [arg_type(operand1), arg_type(operand2)];
+    op_2(0x17, &args, bytes);
+    write_argument(operand1, bytes);
+    write_argument(operand2, bytes);
     bytes.push(save_variable.id);
-    bytes
 }
 
 /// Modulo:
 /// `save_variable = operand1 % operand2`
-pub fn op_mod(operand1: &Operand, operand2: &Operand, save_variable: &Variable) -> Vec<u8> {
-    let args: Vec<ArgType> = vec![arg_type(operand1), arg_type(operand2)];
-    let mut bytes = op_2(0x18, args);
-    write_argument(operand1, &mut bytes);
-    write_argument(operand2, &mut bytes);
+pub fn op_mod(operand1: &Operand, operand2: &Operand, save_variable: &Variable, bytes: &mut Vec<u8>) {
+    let args = [arg_type(operand1), arg_type(operand2)];
+    op_2(0x18, &args, bytes);
+    write_argument(operand1, bytes);
+    write_argument(operand2, bytes);
     bytes.push(save_variable.id);
-    bytes
 }
 
 /// Decrements the value of the variable:
 /// `variable -= 1`
-pub fn op_dec(variable: u8) -> Vec<u8> {
-    let mut bytes = op_1(0x06, ArgType::Reference);
+pub fn op_dec(variable: u8, bytes: &mut Vec<u8>) {
+    op_1(0x06, ArgType::Reference, bytes);
     bytes.push(variable);
-    bytes
 }
 
 /// Increments the value of the variable:
 /// `variable += 1`.
-pub fn op_inc( variable: u8) -> Vec<u8> {
-    let mut bytes = op_1(0x05, ArgType::Reference);
+pub fn op_inc(variable: u8, bytes: &mut Vec<u8>) {
+    op_1(0x05, ArgType::Reference, bytes);
     bytes.push(variable);
-    bytes
 }
 
 /// Prints a newline character.
-pub fn op_newline() -> Vec<u8> {
-    op_0(0x0b)
+pub fn op_newline(bytes: &mut Vec<u8>) {
+    op_0(0x0b, bytes);
+}
+
+/// Stores the current stack frame in the variable, `throw` returns from the routine to it.
+pub fn op_catch(variable: &Variable, bytes: &mut Vec<u8>) {
+    op_0(0x09, bytes);
+    bytes.push(variable.id);
+}
+
+/// Returns the value from the routine whose stack frame `catch` stored, all routines it called
+/// are left as well.
+pub fn op_throw(value: &Operand, frame: &Operand, bytes: &mut Vec<u8>) {
+    let args = [arg_type(value), arg_type(frame)];
+    op_2(0x1c, &args, bytes);
+    write_argument(value, bytes);
+    write_argument(frame, bytes);
 }
 
 /// Quits the Z-Code program immediately.
-pub fn quit() -> Vec<u8> {
-    op_0(0x0a)
+pub fn quit(bytes: &mut Vec<u8>) {
+    op_0(0x0a, bytes);
 }
 
 /// Byte encoding for op-codes with 0 operators.
 ///
 /// `$b0 -- $bf  short     0OP`
-pub fn op_0(value: u8) -> Vec<u8> {
-    let byte = value | 0xb0;
-    vec![byte]
+pub fn op_0(value: u8, bytes: &mut Vec<u8>) {
+    bytes.push(value | 0xb0);
 }
 
 /// Byte encoding for op-codes with variable operators (up to 4 are possible).
 ///
 /// `$e0 -- $ff  variable  VAR     (operand types in next byte(s))`
-pub fn op_var(value: u8, arg_types: Vec<ArgType>) -> Vec<u8> {
-	let mut ret = Vec::new();
-	ret.push(value | 0xe0);
-    ret.push(encode_variable_arguments(arg_types));
-    ret
+pub fn op_var(value: u8, arg_types: &[ArgType], bytes: &mut Vec<u8>) {
+    bytes.push(value | 0xe0);
+    bytes.push(encode_variable_arguments(arg_types));
+}
+
+/// Byte encoding for extended op-codes (operand types in the byte after the op-code).
+///
+/// `$be  extended  EXT  op-code in next byte`
+pub fn op_ext(value: u8, arg_types: &[ArgType], bytes: &mut Vec<u8>) {
+    bytes.push(0xbe);
+    bytes.push(value);
+    bytes.push(encode_variable_arguments(arg_types));
 }
 
 /// Byte encoding for op-codes with 1 operator.
@@ -425,7 +482,7 @@ pub fn op_var(value: u8, arg_types: Vec<ArgType>) -> Vec<u8> {
 /// $90 -- $9f  short     1OP     small constant
 /// $a0 -- $af  short     1OP     variable
 /// ```
-pub fn op_1( value: u8, arg_type: ArgType) -> Vec<u8> {
+pub fn op_1(value: u8, arg_type: ArgType, bytes: &mut Vec<u8>) {
     let byte: u8 = match arg_type {
         ArgType::Reference  => 0x90 | value,  // same as SmallConst
         ArgType::Variable   => 0xa0 | value,
@@ -434,7 +491,7 @@ pub fn op_1( value: u8, arg_type: ArgType) -> Vec<u8> {
         _                   => panic!("no possible 1OP")
     };
 
-    vec![byte]
+    bytes.push(byte);
 }
 
 /// Byte encoding for op-codes with 2 operators.
@@ -449,10 +506,9 @@ pub fn op_1( value: u8, arg_type: ArgType) -> Vec<u8> {
 /// ```
 ///
 /// not handled here: $be  extended opcode given in next byte
-pub fn op_2( value: u8, arg_types: Vec<ArgType>) -> Vec<u8> {
+pub fn op_2(value: u8, arg_types: &[ArgType], bytes: &mut Vec<u8>) {
     let mut byte: u8 = 0x00;
     let mut is_variable: bool = false;
-    let mut ret = Vec::new();
     for (i, arg_type) in arg_types.iter().enumerate() {
         let shift: u8 = 6 - i as u8;
         match arg_type {
@@ -467,20 +523,22 @@ pub fn op_2( value: u8, arg_types: Vec<ArgType>) -> Vec<u8> {
     if is_variable {
         let mut byte: u8 = 0xc0 | value;
         byte = byte | value;
-        ret.push(byte);
+        bytes.push(byte);
 
         let mut byte2 = encode_variable_arguments(arg_types);
         byte2 = byte2 | 0xf;
-        ret.push(byte2)
+        bytes.push(byte2)
     } else {
         byte = byte | value;
-        ret.push(byte)
+        bytes.push(byte)
     }
-    ret
 }
 
 /// Encode the variable arguments specified by `arg_types`.
-pub fn encode_variable_arguments( arg_types: Vec<ArgType>) -> u8 {
+///
+/// Missing entries are encoded as `0b00` (large constant), not as omitted, so pass all four
+/// types and use `ArgType::Nothing` for the unused ones.
+pub fn encode_variable_arguments(arg_types: &[ArgType]) -> u8 {
     let mut byte: u8 = 0x00;
     for (i, arg_type) in arg_types.iter().enumerate() {
         let shift: u8 = 6 - 2 * i as u8;
@@ -505,6 +563,7 @@ pub fn arg_type(operand: &Operand) -> ArgType {
         &Operand::BoolConst(_) => ArgType::SmallConst,
         &Operand::LargeConst(_) => ArgType::LargeConst,
         &Operand::StringRef(_) => ArgType::LargeConst,
+        &Operand::Pointer(_) => ArgType::LargeConst,
     }
 }
 
@@ -515,7 +574,8 @@ pub fn write_argument(operand: &Operand, v: &mut Vec<u8>){
         &Operand::Const(ref constant) => v.push(constant.value),
         &Operand::BoolConst(ref constant) => v.push(constant.value),
         &Operand::LargeConst(ref constant) => write_i16(constant.value, v),
-        &Operand::StringRef(ref constant) => write_i16(constant.value, v),
+        &Operand::StringRef(ref address) => write_u16(address.value, v),
+        &Operand::Pointer(ref address) => write_u16(address.value, v),
     };
 }
 
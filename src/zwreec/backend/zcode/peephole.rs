@@ -0,0 +1,291 @@
+//! The `peephole` module contains small optimizations on the generated `ZOP` stream.
+//!
+//! The expression evaluation often stores a constant in a temporary variable only to read it
+//! in the very next instruction. `inline_constant_stores` removes these stores and uses the
+//! constant directly, which saves the store instruction and the variable byte.
+
+use backend::codegen::CodeGenManager;
+use backend::zcode::zfile::{Operand, ZOP};
+
+/// Replaces `StoreVariable{v, constant}` directly followed by the only use of `v` with the
+/// constant.
+///
+/// A store is only removed if `v` is a temporary variable and is not mentioned anywhere else in
+/// the same routine, so that jumps between labels can't observe the missing value.
+pub fn inline_constant_stores(code: Vec<ZOP>) -> Vec<ZOP> {
+    let mut replacements: Vec<(usize, ZOP)> = Vec::new();
+    let mut routine_start = 0;
+    let mut i = 0;
+    while i < code.len() {
+        if let ZOP::Routine{..} = code[i] {
+            routine_start = i;
+        }
+
+        match inline_store(&code, routine_start, i) {
+            Some(inlined) => {
+                replacements.push((i, inlined));
+                i += 2;
+            },
+            None => i += 1
+        }
+    }
+
+    if replacements.is_empty() {
+        return code;
+    }
+    debug!("Inlined {} constant stores", replacements.len());
+
+    let mut replacements = replacements.into_iter().peekable();
+    let mut optimized = Vec::with_capacity(code.len());
+    let mut skip = false;
+    for (index, zop) in code.into_iter().enumerate() {
+        if skip {
+            // the instruction after the store has been replaced
+            skip = false;
+            continue;
+        }
+
+        let is_store = match replacements.peek() {
+            Some(&(store, _)) => store == index,
+            None => false
+        };
+        if is_store {
+            let (_, inlined) = replacements.next().unwrap();
+            optimized.push(inlined);
+            skip = true;
+        } else {
+            optimized.push(zop);
+        }
+    }
+    optimized
+}
+
+/// Returns the replacement for the instruction after `code[index]` if the store at `index`
+/// can be removed.
+fn inline_store(code: &[ZOP], routine_start: usize, index: usize) -> Option<ZOP> {
+    let (id, value) = match &code[index] {
+        &ZOP::StoreVariable{ref variable, ref value} if CodeGenManager::is_temp_var(variable) => {
+            match value {
+                &Operand::Const(_) | &Operand::LargeConst(_) | &Operand::BoolConst(_) => (variable.id, value),
+                _ => return None
+            }
+        },
+        _ => return None
+    };
+
+    let next = match code.get(index + 1) {
+        Some(next) => next,
+        None => return None
+    };
+    if mentioned_variables(next).iter().filter(|&&var| var == id).count() != 1 {
+        return None;
+    }
+
+    let routine_end = code[index + 1..].iter()
+        .position(|zop| match zop { &ZOP::Routine{..} => true, _ => false })
+        .map(|pos| index + 1 + pos)
+        .unwrap_or(code.len());
+    let used_elsewhere = code[routine_start..index].iter()
+        .chain(code[index + 2..routine_end].iter())
+        .any(|zop| mentioned_variables(zop).contains(&id));
+    if used_elsewhere {
+        return None;
+    }
+
+    let inlined = match substitute(next, id, value) {
+        Some(inlined) => inlined,
+        None => return None
+    };
+
+    // the only mention might have been a result variable, which can't be replaced
+    if mentioned_variables(&inlined).contains(&id) {
+        None
+    } else {
+        Some(inlined)
+    }
+}
+
+/// Returns `value` if `operand` is the variable `id`, otherwise a copy of `operand`.
+fn replace(operand: &Operand, id: u8, value: &Operand) -> Operand {
+    match operand {
+        &Operand::Var(ref var) if var.id == id => value.clone(),
+        _ => operand.clone()
+    }
+}
+
+/// Rebuilds `zop` with `value` instead of the variable `id` in its operands.
+///
+/// Returns `None` for instructions that only take variables or depend on the operand type.
+fn substitute(zop: &ZOP, id: u8, value: &Operand) -> Option<ZOP> {
+    let r = |operand: &Operand| replace(operand, id, value);
+    Some(match zop {
+        &ZOP::StoreVariable{ref variable, ref value} => ZOP::StoreVariable{variable: variable.clone(), value: r(value)},
+        &ZOP::Add{ref operand1, ref operand2, ref save_variable} => ZOP::Add{operand1: r(operand1), operand2: r(operand2), save_variable: save_variable.clone()},
+        &ZOP::Sub{ref operand1, ref operand2, ref save_variable} => ZOP::Sub{operand1: r(operand1), operand2: r(operand2), save_variable: save_variable.clone()},
+        &ZOP::Mul{ref operand1, ref operand2, ref save_variable} => ZOP::Mul{operand1: r(operand1), operand2: r(operand2), save_variable: save_variable.clone()},
+        &ZOP::Div{ref operand1, ref operand2, ref save_variable} => ZOP::Div{operand1: r(operand1), operand2: r(operand2), save_variable: save_variable.clone()},
+        &ZOP::Mod{ref operand1, ref operand2, ref save_variable} => ZOP::Mod{operand1: r(operand1), operand2: r(operand2), save_variable: save_variable.clone()},
+        &ZOP::Or{ref operand1, ref operand2, ref save_variable} => ZOP::Or{operand1: r(operand1), operand2: r(operand2), save_variable: save_variable.clone()},
+        &ZOP::And{ref operand1, ref operand2, ref save_variable} => ZOP::And{operand1: r(operand1), operand2: r(operand2), save_variable: save_variable.clone()},
+        &ZOP::Not{ref operand, ref result} => ZOP::Not{operand: r(operand), result: result.clone()},
+        &ZOP::JE{ref operand1, ref operand2, ref jump_to_label} => ZOP::JE{operand1: r(operand1), operand2: r(operand2), jump_to_label: jump_to_label.clone()},
+        &ZOP::JNE{ref operand1, ref operand2, ref jump_to_label} => ZOP::JNE{operand1: r(operand1), operand2: r(operand2), jump_to_label: jump_to_label.clone()},
+        &ZOP::JL{ref operand1, ref operand2, ref jump_to_label} => ZOP::JL{operand1: r(operand1), operand2: r(operand2), jump_to_label: jump_to_label.clone()},
+        &ZOP::JLE{ref operand1, ref operand2, ref jump_to_label} => ZOP::JLE{operand1: r(operand1), operand2: r(operand2), jump_to_label: jump_to_label.clone()},
+        &ZOP::JG{ref operand1, ref operand2, ref jump_to_label} => ZOP::JG{operand1: r(operand1), operand2: r(operand2), jump_to_label: jump_to_label.clone()},
+        &ZOP::JGE{ref operand1, ref operand2, ref jump_to_label} => ZOP::JGE{operand1: r(operand1), operand2: r(operand2), jump_to_label: jump_to_label.clone()},
+        &ZOP::Ret{ref value} => ZOP::Ret{value: r(value)},
+        &ZOP::Random{ref range, ref variable} => ZOP::Random{range: r(range), variable: variable.clone()},
+        &ZOP::Call2NWithArg{ref jump_to_label, ref arg} => ZOP::Call2NWithArg{jump_to_label: jump_to_label.clone(), arg: r(arg)},
+        &ZOP::Call2S{ref jump_to_label, ref arg, ref result} => ZOP::Call2S{jump_to_label: jump_to_label.clone(), arg: r(arg), result: result.clone()},
+        &ZOP::CallVNA2{ref jump_to_label, ref arg1, ref arg2} => ZOP::CallVNA2{jump_to_label: jump_to_label.clone(), arg1: r(arg1), arg2: r(arg2)},
+        &ZOP::CallVNA3{ref jump_to_label, ref arg1, ref arg2, ref arg3} => ZOP::CallVNA3{jump_to_label: jump_to_label.clone(), arg1: r(arg1), arg2: r(arg2), arg3: r(arg3)},
+        &ZOP::CallVSA2{ref jump_to_label, ref arg1, ref arg2, ref result} => ZOP::CallVSA2{jump_to_label: jump_to_label.clone(), arg1: r(arg1), arg2: r(arg2), result: result.clone()},
+        &ZOP::CallVSA3{ref jump_to_label, ref arg1, ref arg2, ref arg3, ref result} => ZOP::CallVSA3{jump_to_label: jump_to_label.clone(), arg1: r(arg1), arg2: r(arg2), arg3: r(arg3), result: result.clone()},
+        &ZOP::SetCursorOperand{ref row, ref col} => ZOP::SetCursorOperand{row: r(row), col: r(col)},
+        &ZOP::CopyTable{ref first, ref second, ref size} => ZOP::CopyTable{first: r(first), second: r(second), size: r(size)},
+        _ => return None
+    })
+}
+
+fn operand_variable(operand: &Operand) -> Vec<u8> {
+    match operand {
+        &Operand::Var(ref var) => vec![var.id],
+        _ => vec![]
+    }
+}
+
+/// Returns the ids of all variables an instruction reads or writes.
+///
+/// This match is exhaustive on purpose, new instructions have to be added here.
+pub fn mentioned_variables(zop: &ZOP) -> Vec<u8> {
+    let ov = operand_variable;
+    match zop {
+        &ZOP::PrintUnicode{..} |
+        &ZOP::Print{..} |
+        &ZOP::PrintOps{..} |
+        &ZOP::Call1N{..} |
+        &ZOP::Call2NWithAddress{..} |
+        &ZOP::Routine{..} |
+        &ZOP::Label{..} |
+        &ZOP::Newline |
+        &ZOP::SetColor{..} |
+        &ZOP::SetTextStyle{..} |
+        &ZOP::Jump{..} |
+        &ZOP::Piracy{..} |
+        &ZOP::SetCursor{..} |
+        &ZOP::UpdateCursorPos |
+        &ZOP::EraseWindow{..} |
+        &ZOP::SplitWindow{..} |
+        &ZOP::SetWindow{..} |
+        &ZOP::EraseLine |
+        &ZOP::Quit => vec![],
+        &ZOP::PrintUnicodeVar{ref var} |
+        &ZOP::PrintChar{ref var} => vec![var.id],
+        &ZOP::PrintNumVar{ref variable} |
+        &ZOP::PrintVar{ref variable} |
+        &ZOP::PushVar{ref variable} |
+        &ZOP::PullVar{ref variable} |
+        &ZOP::SetVarType{ref variable, ..} => vec![variable.id],
+        &ZOP::PrintUnicodeStr{ref address} |
+        &ZOP::PrintPaddr{ref address} |
+        &ZOP::PrintAddr{ref address} => ov(address),
+        &ZOP::Call2NWithArg{ref arg, ..} => ov(arg),
+        &ZOP::Call1NVar{variable} |
+        &ZOP::Inc{variable} |
+        &ZOP::Dec{variable} => vec![variable],
+        &ZOP::Call2S{ref arg, ref result, ..} => [ov(arg), vec![result.id]].concat(),
+        &ZOP::CallVNA2{ref arg1, ref arg2, ..} => [ov(arg1), ov(arg2)].concat(),
+        &ZOP::CallVNA3{ref arg1, ref arg2, ref arg3, ..} => [ov(arg1), ov(arg2), ov(arg3)].concat(),
+        &ZOP::CallVSA2{ref arg1, ref arg2, ref result, ..} => [ov(arg1), ov(arg2), vec![result.id]].concat(),
+        &ZOP::CallVSA3{ref arg1, ref arg2, ref arg3, ref result, ..} => [ov(arg1), ov(arg2), ov(arg3), vec![result.id]].concat(),
+        &ZOP::CallVS2A5{ref arg1, ref arg2, ref arg3, ref arg4, ref arg5, ref result, ..} =>
+            [ov(arg1), ov(arg2), ov(arg3), ov(arg4), ov(arg5), vec![result.id]].concat(),
+        &ZOP::SetColorVar{foreground, background} => vec![foreground, background],
+        &ZOP::SetTrueColor{ref foreground, ref background} => [ov(foreground), ov(background)].concat(),
+        &ZOP::SoundEffect{ref number} => ov(number),
+        &ZOP::StoreVariable{ref variable, ref value} => [vec![variable.id], ov(value)].concat(),
+        &ZOP::StoreW{ref array_address, ref index, ref variable} |
+        &ZOP::StoreB{ref array_address, ref index, ref variable} |
+        &ZOP::LoadW{ref array_address, ref index, ref variable} => [ov(array_address), vec![index.id, variable.id]].concat(),
+        &ZOP::StoreBOperand{ref array_address, ref index, ref operand} => [ov(array_address), ov(index), ov(operand)].concat(),
+        &ZOP::LoadBOperand{ref array_address, ref index, ref variable} => [ov(array_address), ov(index), vec![variable.id]].concat(),
+        &ZOP::Ret{ref value} => ov(value),
+        &ZOP::Catch{ref result} => vec![result.id],
+        &ZOP::Throw{ref value, ref frame} => [ov(value), ov(frame)].concat(),
+        &ZOP::JE{ref operand1, ref operand2, ..} |
+        &ZOP::JNE{ref operand1, ref operand2, ..} |
+        &ZOP::JL{ref operand1, ref operand2, ..} |
+        &ZOP::JLE{ref operand1, ref operand2, ..} |
+        &ZOP::JG{ref operand1, ref operand2, ..} |
+        &ZOP::JGE{ref operand1, ref operand2, ..} => [ov(operand1), ov(operand2)].concat(),
+        &ZOP::Random{ref range, ref variable} => [ov(range), vec![variable.id]].concat(),
+        &ZOP::Save{ref result} | &ZOP::Restore{ref result} => vec![result.id],
+        &ZOP::ReadChar{local_var_id} |
+        &ZOP::ReadCharTimer{local_var_id, ..} => vec![local_var_id],
+        &ZOP::AddTypes{ref operand1, ref operand2, ref tmp1, ref tmp2, ref save_variable} =>
+            [ov(operand1), ov(operand2), vec![tmp1.id, tmp2.id, save_variable.id]].concat(),
+        &ZOP::Add{ref operand1, ref operand2, ref save_variable} |
+        &ZOP::Sub{ref operand1, ref operand2, ref save_variable} |
+        &ZOP::Mul{ref operand1, ref operand2, ref save_variable} |
+        &ZOP::Div{ref operand1, ref operand2, ref save_variable} |
+        &ZOP::Mod{ref operand1, ref operand2, ref save_variable} |
+        &ZOP::Or{ref operand1, ref operand2, ref save_variable} |
+        &ZOP::And{ref operand1, ref operand2, ref save_variable} => [ov(operand1), ov(operand2), vec![save_variable.id]].concat(),
+        &ZOP::Not{ref operand, ref result} => [ov(operand), vec![result.id]].concat(),
+        &ZOP::SetCursorOperand{ref row, ref col} => [ov(row), ov(col)].concat(),
+        &ZOP::CopyTable{ref first, ref second, ref size} => [ov(first), ov(second), ov(size)].concat(),
+        &ZOP::GetCursor{ref store_addr} => ov(store_addr),
+        &ZOP::CopyVarType{ref variable, ref from} => [vec![variable.id], ov(from)].concat(),
+        &ZOP::GetVarType{ref variable, ref result} => vec![variable.id, result.id],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use backend::zcode::zfile::{Operand, Variable, ZOP};
+
+    #[test]
+    fn test_inline_constant_store() {
+        let code = inline_constant_stores(vec![
+            ZOP::Routine{name: "test".to_string(), count_variables: 15},
+            ZOP::StoreVariable{variable: Variable::new(3), value: Operand::new_large_const(1000)},
+            ZOP::Add{operand1: Operand::new_var(30), operand2: Operand::new_var(3), save_variable: Variable::new(4)},
+            ZOP::PrintNumVar{variable: Variable::new(4)},
+        ]);
+
+        assert_eq!(code.len(), 3);
+        match &code[1] {
+            &ZOP::Add{operand1: Operand::Var(ref var), operand2: Operand::LargeConst(ref constant), ref save_variable} => {
+                assert_eq!(var.id, 30);
+                assert_eq!(constant.value, 1000);
+                assert_eq!(save_variable.id, 4);
+            },
+            other => panic!("store was not inlined: {:?}", other)
+        }
+    }
+
+    #[test]
+    fn test_keep_store_used_twice() {
+        let code = inline_constant_stores(vec![
+            ZOP::Routine{name: "test".to_string(), count_variables: 15},
+            ZOP::StoreVariable{variable: Variable::new(3), value: Operand::new_const(5)},
+            ZOP::Add{operand1: Operand::new_var(30), operand2: Operand::new_var(3), save_variable: Variable::new(4)},
+            ZOP::Label{name: "loop".to_string()},
+            ZOP::PrintNumVar{variable: Variable::new(3)},
+        ]);
+        assert_eq!(code.len(), 5);
+    }
+
+    #[test]
+    fn test_keep_store_to_global_or_result() {
+        let code = inline_constant_stores(vec![
+            ZOP::StoreVariable{variable: Variable::new(30), value: Operand::new_const(5)},
+            ZOP::PrintNumVar{variable: Variable::new(30)},
+            ZOP::StoreVariable{variable: Variable::new(3), value: Operand::new_const(5)},
+            ZOP::Add{operand1: Operand::new_const(1), operand2: Operand::new_const(2), save_variable: Variable::new(3)},
+        ]);
+        assert_eq!(code.len(), 4);
+    }
+}
@@ -7,7 +7,24 @@ pub use super::zbytes::Bytes;
 pub use super::ztext;
 pub use super::ee::routine_easteregg;
 pub use super::op;
-use config::Config;
+use super::archive;
+use super::capabilities::{Capability, CapabilityReport, CapabilitySet};
+use super::peephole::mentioned_variables;
+use backend::codegen::CodeGenError;
+use config::{Config, MainLoop};
+use std::collections::HashMap;
+
+/// The length of the pause before the next passage in tenths of a second
+pub const TRANSITION_PAUSE: u8 = 5;
+
+/// The length of the line above the links if the screen width is unknown
+pub const SEPARATOR_LENGTH: u16 = 39;
+
+/// The number of links the link table can store at least
+pub const MIN_LINK_CAPACITY: u16 = 32;
+
+/// Roughly the bytes of the routines of the runtime, for `Zfile::estimated_size`
+const RUNTIME_SIZE: usize = 0x2000;
 
 /// A variable type.
 #[derive(Clone, PartialEq, Debug)]
@@ -24,6 +41,9 @@ pub enum Type {
 
     /// This is a string
     String = 3,
+
+    /// This is a map from strings to values
+    Map = 4,
 }
 
 /// A variable.
@@ -50,6 +70,16 @@ pub struct LargeConstant {
     pub value: i16
 }
 
+/// An unsigned 16-bit byte address.
+///
+/// Addresses can be above 0x7fff (e.g. static strings right below `program_addr`), so they must
+/// never pass through an `i16`.
+#[derive(Debug,Clone)]
+pub struct Address {
+    /// The address
+    pub value: u16
+}
+
 /// There are three Operands in Z-Code:
 /// Variables, SmallConsts and LargeConsts.
 ///
@@ -68,7 +98,10 @@ pub enum Operand {
     /// A string reference
     ///
     /// This is internally stored as a large constant containing the string address
-    StringRef(LargeConstant),
+    StringRef(Address),
+
+    /// A pointer to memory (stored as an unsigned large constant)
+    Pointer(Address),
 
     /// A boolean constant (stored as a small integer constant)
     BoolConst(Constant),
@@ -86,8 +119,13 @@ impl Operand {
     }
 
     /// Creates a new string reference.
-    pub fn new_string_ref(value: i16) -> Operand {
-        Operand::StringRef(LargeConstant { value: value })
+    pub fn new_string_ref(address: u16) -> Operand {
+        Operand::StringRef(Address { value: address })
+    }
+
+    /// Creates a new pointer to the specified address.
+    pub fn new_pointer(address: u16) -> Operand {
+        Operand::Pointer(Address { value: address })
     }
 
     /// Creates a new variable.
@@ -118,6 +156,18 @@ impl Operand {
         }
     }
 
+    /// Returns the unsigned address of a string reference or pointer.
+    ///
+    /// # Panics
+    /// Panics if the Operand is not an address.
+    pub fn address_value(&self) -> u16 {
+        match self {
+            &Operand::StringRef(ref address) => address.value,
+            &Operand::Pointer(ref address) => address.value,
+            _ => panic!("Operand must be an address!")
+        }
+    }
+
     /// Returns whether the Operand is a constant.
     pub fn is_const(&self) -> bool {
         match self {
@@ -153,7 +203,7 @@ impl Variable {
 ///
 /// These structs contain instructions to generate Z-Machine op-codes.
 /// To generate the op-codes see `backend::zcode::zfile::Zfile::emit`.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 #[allow(missing_docs)]
 pub enum ZOP {
     /// Prints a unicode character.
@@ -237,6 +287,13 @@ pub enum ZOP {
     /// Sets the foreground and background color to the variables with the IDs specified.
     SetColorVar{foreground: u8, background: u8},
 
+    /// Sets the foreground and background color to 15 bit RGB values, -1 keeps the current
+    /// colour and -2 restores the default.
+    SetTrueColor{foreground: Operand, background: Operand},
+
+    /// Plays a sound effect, 1 is a high and 2 a low beep.
+    SoundEffect{number: Operand},
+
     /// Set text style to `bold`, `reverse` (inverse colors), `monospace` and `italic`.
     SetTextStyle{bold: bool, reverse: bool, monospace: bool, italic: bool},
 
@@ -270,6 +327,13 @@ pub enum ZOP {
     /// Return from the Z-Routine with the value in the specified Operand.
     Ret{value: Operand},
 
+    /// Stores the stack frame of the current routine in `result`.
+    Catch{result: Variable},
+
+    /// Returns `value` from the routine whose stack frame `Catch` stored in `frame`, leaving all
+    /// routines it called.
+    Throw{value: Operand, frame: Operand},
+
     /// Jump if `operand1 == operand2`.
     JE{operand1: Operand, operand2: Operand, jump_to_label: String},
 
@@ -291,6 +355,12 @@ pub enum ZOP {
     /// Store a random number between 1 and `range` in `variable`.
     Random{range: Operand, variable: Variable},
 
+    /// Save the game, `result` is 0 if it failed, 1 if it was saved and 2 after a restore.
+    Save{result: Variable},
+
+    /// Restore a saved game, only continues with `result` = 0 if it failed.
+    Restore{result: Variable},
+
     /// Read a character from standard input in the variable.
     ReadChar{local_var_id: u8},
 
@@ -330,6 +400,11 @@ pub enum ZOP {
     /// Loads a word: `variable = array_address[index]`.
     LoadW{array_address: Operand, index: Variable, variable: Variable},
 
+    /// Copies `size` bytes from `first` to `second`, a `second` of 0 zeroes them instead.
+    ///
+    /// A negative `size` copies forwards even if the tables overlap.
+    CopyTable{first: Operand, second: Operand, size: Operand},
+
     /// Positions the cursor at the specified `line` and `column`.
     SetCursor{line: u8, col: u8},
 
@@ -343,8 +418,17 @@ pub enum ZOP {
     GetCursor{store_addr: Operand},
 
     /// Erase the entire window with the specified id.
+    ///
+    /// `0` is the lower and `1` the upper window, `-2` erases both. `-1` also erases both but
+    /// unsplits the screen, so the `SplitWindow` that ran last is restored afterwards.
     EraseWindow{value: i8},
 
+    /// Splits the screen: the upper window (window 1) gets the first `lines` lines.
+    SplitWindow{lines: u8},
+
+    /// Selects the window for the following output, `0` is the lower and `1` the upper window.
+    SetWindow{window: u8},
+
     /// Erase the current line starting from the cursor.
     EraseLine,
 
@@ -359,6 +443,12 @@ pub enum ZOP {
 
     /// Quits the Z-Machine interpreter immediately.
     Quit,
+
+    /// Branches to the label if the game is genuine.
+    ///
+    /// Interpreters are asked by the standard to treat every game as genuine, so in practice this
+    /// always branches and an anti-tamper check built on it never fails.
+    Piracy{jump_to_label: String},
 }
 
 /// Zcode has the jump-types:
@@ -366,7 +456,7 @@ pub enum ZOP {
 /// jumps (to a label),
 /// branches (to a label, from a compare-op like je, ...),
 /// routine (to a routine-address)
-#[derive(Debug, PartialEq, Clone)]
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
 pub enum JumpType {
     /// Jump to a label (address)
     Jump,
@@ -396,6 +486,38 @@ pub enum ArgType {
     Nothing
 }
 
+/// Where the code of a routine comes from.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Origin {
+    /// Generated for the passage with this name, including the routines of its links
+    Passage(String),
+
+    /// Part of the runtime written by `end()` or the `<<toc>>` of the story
+    Runtime,
+
+    /// Written without saying where it comes from
+    Unknown,
+}
+
+/// A routine written to the file, see `Zfile::routine_origins`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RoutineOrigin {
+    /// The name of the routine
+    pub name: String,
+
+    /// The address of the routine
+    pub address: u32,
+
+    /// What the routine was written for
+    pub origin: Origin,
+
+    /// The bytes of its instructions, without the padding and the strings behind it
+    pub size: u32,
+
+    /// The capabilities of the interpreter the opcodes of the routine use
+    pub capabilities: CapabilitySet,
+}
+
 /// The definition of a Z-Code file.
 pub struct Zfile {
     /// The output data
@@ -410,15 +532,25 @@ pub struct Zfile {
     /// A list of all labels
     labels: Vec<Zlabel>,
 
-    /// A list of all strings (used to find duplicate strings)
+    /// A list of all strings in the order they are written
     strings: Vec<Zstring>,
 
+    /// The index of the first string in `strings` for the text and the unicode flag of every
+    /// string, the duplicates reuse its address
+    string_index: HashMap<(String, bool), usize>,
+
+    /// The number of strings at the start of `strings` which are written with their references
+    strings_written: usize,
+
     /// The beginning of executable code
-    program_addr: u16,
+    pub program_addr: u16,
 
     /// The address of the unicode translation table
     unicode_table_addr: u16,
 
+    /// The number of characters `create_header` reserves room for in the unicode table
+    pub unicode_table_size: u16,
+
     /// The address of the global variables
     global_addr: u16,
 
@@ -437,6 +569,22 @@ pub struct Zfile {
     /// Location of the cursor position
     pub cursor_pos: u16,
 
+    /// Location of the cursor position and the remaining seconds of `<<countdown>>`
+    pub countdown_pos: u16,
+
+    /// Location of the number of allocations, of the blocks freed by `mem_free` and of the
+    /// request of `malloc` to run `rt_collect`
+    pub heap_stats_pos: u16,
+
+    /// Location of the lines of the upper window set by the last `SplitWindow` that ran
+    pub split_window_pos: u16,
+
+    /// Location of the link table with the addresses of the links of the current passage
+    pub link_table: u16,
+
+    /// The number of links the link table can store
+    pub link_capacity: u16,
+
     /// Start of dynamic memory
     pub heap_start: u16,
 
@@ -454,6 +602,120 @@ pub struct Zfile {
 
     /// Disable unicode completely
     pub no_unicode: bool,
+
+    /// Refill an empty deck in `draw()` instead of returning 0
+    pub auto_reshuffle: bool,
+
+    /// Pause briefly before the screen is cleared for the next passage
+    pub transition_pause: bool,
+
+    /// Wait for a key after a passage without links instead of quitting immediately
+    pub no_dead_end_quit: bool,
+
+    /// The character of the line above the links
+    pub separator_char: char,
+
+    /// The length of the line above the links, 0 fits it to the screen width
+    pub separator_length: u16,
+
+    /// The Z-Machine version of the file (7 or 8)
+    pub version: u8,
+
+    /// The routine called when the game starts
+    pub start_passage: String,
+
+    /// The loop run after the start passage returned
+    pub main_loop: MainLoop,
+
+    /// The passage called on runtime errors instead of quitting, see `routine_error`
+    pub error_passage: Option<String>,
+
+    /// The message of `malloc` when the heap is exhausted
+    pub oom_message: String,
+
+    /// Run `mem_free` behind assignments once the heap is three quarters full
+    pub oom_collect: bool,
+
+    /// Print the heap statistics when `H` is pressed at the links
+    pub heap_stats: bool,
+
+    /// The size of the upper window set by the last `SplitWindow` written, only used to warn
+    /// about erasing the upper window before any split
+    upper_window_lines: u8,
+
+    /// The routines written by `emit_merging_routines` with their addresses
+    routine_bodies: HashMap<RoutineBody, u32>,
+
+    /// The number of routines that were merged into an earlier identical one
+    pub merged_routines: usize,
+
+    /// The capabilities of the interpreter used by any opcode written so far
+    pub capabilities: CapabilitySet,
+
+    /// The capabilities used by the code in front of the first routine
+    entry_capabilities: CapabilitySet,
+
+    /// The number of local variables of the routine written last
+    routine_locals: u8,
+
+    /// The data appended behind the strings by `write_embedded_blobs`, with their names
+    pub embedded_blobs: Vec<(String, Vec<u8>)>,
+
+    /// The Twee source `append_source` writes behind the story, `None` without `--embed-source`
+    pub embedded_source: Option<Vec<u8>>,
+
+    /// The config the errors are reported to
+    cfg: Config,
+
+    /// The name, the address and the length of every blob, of the index of the blobs and of the
+    /// appended source, written by `end()`
+    pub embedded_regions: Vec<(String, u32, u32)>,
+
+    /// The address of the seventh word of the header extension table, the address of the index
+    /// of the embedded blobs divided by 8 is written there
+    blob_index_word: u16,
+
+    /// The origin of the routines written next
+    pub origin: Origin,
+
+    /// Every routine in the file with its origin, merged routines are left out
+    pub routine_origins: Vec<RoutineOrigin>,
+
+    /// The address behind the last routine, set by `end()` before the strings are written
+    pub code_end: u32,
+
+    /// The bytes of the instructions in front of the first routine
+    pub entry_size: u32,
+
+    /// The instructions written since `record_ops`, `None` if they are not recorded
+    recorded_ops: Option<Vec<ZOP>>,
+
+    /// The number of instructions `write_zop` is writing at the moment, the ones written by
+    /// another instruction are only recorded if it is turned into them
+    zop_depth: usize,
+}
+
+/// The target of a jump inside a routine body.
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
+enum JumpTarget {
+    /// A label of the routine itself, relative to the routine address
+    Inside(u32),
+
+    /// Any other label
+    Outside(String),
+}
+
+/// The written bytes of a routine, with all addresses relative to the routine address.
+///
+/// Two routines with the same body behave the same, no matter what their labels are called.
+/// The addresses of the strings are only written by `write_strings`, so their texts are part
+/// of the body.
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
+struct RoutineBody {
+    bytes: Vec<u8>,
+    jumps: Vec<(u32, JumpTarget, JumpType)>,
+    labels: Vec<u32>,
+    strings: Vec<(u32, String, bool)>,
 }
 
 /// A jump.
@@ -517,6 +779,66 @@ pub struct FormattingState {
     pub inverted: bool
 }
 
+/// The bits the story sets in Flags 1 and Flags 2 of the header.
+///
+/// Interpreters overwrite Flags 1 with what they support, it only matters for interpreters that
+/// do not. Flags 2 tells the interpreter what the story needs.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct HeaderFlags {
+    /// The story sets colours
+    pub colours: bool,
+
+    /// The transcript to the printer is on from the start
+    pub transcript: bool,
+
+    /// All text is printed in a fixed-pitch font
+    pub fixed_pitch: bool,
+
+    /// The story uses `save_undo` and `restore_undo`
+    pub undo: bool,
+
+    /// The story plays sound effects
+    pub sound: bool,
+}
+
+impl HeaderFlags {
+    /// Returns Flags 1 (from right to left):
+    /// 0: colours available,
+    /// 1: picture,
+    /// 2: bold,
+    /// 3: italic,
+    /// 4: fixed
+    pub fn flags1(&self) -> u8 {
+        if self.colours { 0x1d } else { 0x1c }
+    }
+
+    /// Returns Flags 2 (from right to left):
+    /// 0: transcripting is on,
+    /// 1: force printing in a fixed-pitch font,
+    /// 4: game wants to use UNDO opcodes,
+    /// 6: game wants to use colours,
+    /// 7: game wants to use sound effects
+    pub fn flags2(&self) -> u16 {
+        let mut flags: u16 = 0;
+        if self.transcript {
+            flags |= 0x01;
+        }
+        if self.fixed_pitch {
+            flags |= 0x02;
+        }
+        if self.undo {
+            flags |= 0x10;
+        }
+        if self.colours {
+            flags |= 0x40;
+        }
+        if self.sound {
+            flags |= 0x80;
+        }
+        flags
+    }
+}
+
 
 impl Zfile {
     /// Creates a new zfile with default options.
@@ -532,26 +854,163 @@ impl Zfile {
             jumps: Vec::new(),
             labels: Vec::new(),
             strings: Vec::new(),
+            string_index: HashMap::new(),
+            strings_written: 0,
             program_addr: if half_memory { 0x7918 } else { 0xfff8 },
             unicode_table_addr: 0,
+            unicode_table_size: ztext::UNICODE_TABLE_SIZE as u16,
             global_addr: 0,
             object_addr: 0,
             static_addr: 0,
             last_static_written: if half_memory { 0x4000 } else { 0x8000 },
             heap_start: 0x600,
             cursor_pos: 0x502,  // set by UpdateCursorPos
+            countdown_pos: 0x506,
+            heap_stats_pos: 0x50c,
+            split_window_pos: 0x512,
+            link_table: 0,      // set by create_header
+            link_capacity: MIN_LINK_CAPACITY,
             type_store: 0x400,
             bright_mode: bright_mode,
             force_unicode: force_unicode,
             easter_egg: easter_egg,
             no_colours: no_colours,
             no_unicode: no_unicode,
+            auto_reshuffle: false,
+            transition_pause: false,
+            no_dead_end_quit: false,
+            separator_char: '-',
+            separator_length: SEPARATOR_LENGTH,
+            version: 8,
+            start_passage: "Start".to_string(),
+            main_loop: MainLoop::Links,
+            error_passage: None,
+            oom_message: "MALLOC-FAIL".to_string(),
+            oom_collect: false,
+            heap_stats: false,
+            upper_window_lines: 0,
+            routine_bodies: HashMap::new(),
+            merged_routines: 0,
+            capabilities: CapabilitySet::new(),
+            entry_capabilities: CapabilitySet::new(),
+            routine_locals: 0,
+            embedded_blobs: Vec::new(),
+            embedded_source: None,
+            cfg: Config::default_config(),
+            embedded_regions: Vec::new(),
+            blob_index_word: 0,
+            origin: Origin::Unknown,
+            routine_origins: Vec::new(),
+            code_end: 0,
+            entry_size: 0,
+            recorded_ops: None,
+            zop_depth: 0,
         }
     }
 
     /// Creates a new zfile with the specified config.
     pub fn new_with_cfg(cfg: &Config) -> Zfile {
-        Zfile::new_with_options(cfg.bright_mode, cfg.force_unicode, cfg.easter_egg, cfg.no_colours, cfg.half_memory, cfg.no_unicode)
+        let mut zfile = Zfile::new_with_options(cfg.bright_mode, cfg.force_unicode, cfg.easter_egg, cfg.no_colours, cfg.half_memory, cfg.no_unicode);
+        zfile.version = cfg.zcode_version;
+        zfile.auto_reshuffle = cfg.auto_reshuffle;
+        zfile.transition_pause = cfg.transition_pause;
+        zfile.no_dead_end_quit = cfg.no_dead_end_quit;
+        zfile.separator_char = cfg.separator_char;
+        zfile.separator_length = cfg.separator_length;
+        zfile.main_loop = cfg.main_loop.clone();
+        zfile.oom_message = cfg.oom_message.clone();
+        zfile.oom_collect = cfg.oom_collect;
+        zfile.heap_stats = cfg.heap_stats;
+        zfile.embedded_blobs = cfg.embedded_blobs.clone();
+        if cfg.embed_source {
+            zfile.embedded_source = Some(cfg.source.clone());
+        }
+        zfile.cfg = cfg.clone();
+        zfile
+    }
+
+    /// Returns the routine and string offset (`R_O` and `S_O`) used for packed addresses in
+    /// version 7 files.
+    ///
+    /// All routines and high memory strings are placed behind `program_addr`, so packed addresses
+    /// are counted from there.
+    pub fn packed_offset(&self) -> u16 {
+        self.program_addr / 8
+    }
+
+    /// Converts a byte address of a routine or string to a packed address.
+    ///
+    /// Version 8 uses `address = 8 * P`, version 7 uses `address = 4 * P + 8 * R_O`.
+    pub fn packed_address(&self, address: u32) -> u16 {
+        packed_address(self.version, self.packed_offset(), address)
+    }
+
+    /// Reserves room for `links` links in the link table, but at least for `MIN_LINK_CAPACITY`.
+    ///
+    /// The link table stays behind the object table, as long as it fits in front of the type
+    /// storage. A larger table is moved to the start of the heap, which shrinks the heap. This has
+    /// to be called after `start()` and before `end()`.
+    ///
+    /// Returns false if the heap has no room for the table, then only as many links as fit are
+    /// reserved and the rest is dropped at runtime.
+    pub fn reserve_links(&mut self, links: u16) -> bool {
+        self.link_capacity = if links > MIN_LINK_CAPACITY { links } else { MIN_LINK_CAPACITY };
+
+        let compact = 1 + self.object_addr;
+        if compact as u32 + 2 * self.link_capacity as u32 <= self.type_store as u32 {
+            self.link_table = compact;
+            return true;
+        }
+
+        // the heap keeps at least one byte in front of the static memory
+        let room = self.static_addr.saturating_sub(self.heap_start + 1) / 2;
+        let fits = self.link_capacity <= room;
+        if !fits {
+            self.link_capacity = room;
+        }
+        self.link_table = self.heap_start;
+        self.heap_start += 2 * self.link_capacity;
+        fits
+    }
+
+    /// Returns the regions of dynamic memory with their start address and size in bytes.
+    pub fn memory_map(&self) -> Vec<(&'static str, u16, u16)> {
+        let mut regions = vec![
+            ("header", 0, self.unicode_table_addr),
+            ("unicode table", self.unicode_table_addr, 1 + 2 * self.unicode_table_size),
+            ("globals", self.global_addr, 480),
+            ("objects", self.object_addr, 1),
+            ("links", self.link_table, 2 * self.link_capacity),
+            ("types", self.type_store, 0x100),
+            ("cursor", self.cursor_pos, 4),
+            ("countdown", self.countdown_pos, 6),
+            ("heap stats", self.heap_stats_pos, 6),
+            ("upper window", self.split_window_pos, 1),
+            ("heap", self.heap_start, self.static_addr.saturating_sub(self.heap_start)),
+        ];
+        regions.sort_by(|a, b| a.1.cmp(&b.1));
+        regions
+    }
+
+    /// Checks that the regions of `memory_map` follow each other without overlapping and end in
+    /// front of static memory.
+    ///
+    /// The addresses of the regions are computed from each other, so a region that grows can
+    /// move the next one onto a fixed address like the one of the type storage.
+    ///
+    /// # Panics
+    /// Panics with the names and addresses of the first two regions that overlap.
+    pub fn check_layout(&self) {
+        let regions = self.memory_map();
+        for pair in regions.windows(2) {
+            let ((name, start, size), (next, next_start, _)) = (pair[0], pair[1]);
+            assert!(start as u32 + size as u32 <= next_start as u32,
+                "invalid memory layout: {} at {:#06x} with {} bytes overlaps {} at {:#06x}", name, start, size, next, next_start);
+        }
+        if let Some(&(name, start, size)) = regions.last() {
+            assert!(start as u32 + size as u32 <= self.static_addr as u32,
+                "invalid memory layout: {} at {:#06x} with {} bytes overlaps static memory at {:#06x}", name, start, size, self.static_addr);
+        }
     }
 
     /// Creates the header of a zfile.
@@ -561,27 +1020,28 @@ impl Zfile {
 
         let alpha_addr: u16 = 0x40;
         let extension_addr: u16 = alpha_addr + 78;
-        self.unicode_table_addr = extension_addr as u16 + 8;
+
+        // the index of the embedded blobs needs the words 4 to 7 of the extension table
+        let extension_words: u16 = if self.embedded_blobs.is_empty() { 3 } else { 7 };
+        self.unicode_table_addr = extension_addr + 2 + 2 * extension_words;
 
         // 1 byte for the unicode count, 97 possible chars with 2 bytes
-        self.global_addr = self.unicode_table_addr + 195;
+        self.global_addr = self.unicode_table_addr + 1 + 2 * self.unicode_table_size;
 
         // 480 because there are 240 global 2-bytes variables
         self.object_addr = self.global_addr + 480;
+        self.link_table = 1 + self.object_addr;
         let high_memory_addr: u16 = self.program_addr;
         self.static_addr = self.last_static_written;
         let dictionary_addr: u16 = self.last_static_written;
+        self.check_layout();
 
         // version
-        self.data.write_byte(8, 0x00);
+        assert!(self.version == 7 || self.version == 8, "only Z-Machine versions 7 and 8 are supported");
+        self.data.write_byte(self.version, 0x00);
 
-        // flag1 (from right to left):
-        // 0: colours available,
-        // 1: picture,
-        // 2: bold,
-        // 3: italic,
-        // 4: fixed
-        self.data.write_byte(if self.no_colours { 0x1c } else { 0x1d } , 0x01);
+        // flags 1 and 2, written again by end() once the used features are known
+        self.write_header_flags();
 
         // release version (0x02 und 0x03)
         self.data.write_u16(0, 0x02);
@@ -595,11 +1055,6 @@ impl Zfile {
         // location of dictionary (byte address) (0x08 and 0x09)
         self.data.write_u16(dictionary_addr, 0x08);
 
-        // flag2 (from right to left)
-        // 6: game want to use colours
-        // 0000000001000000
-        self.data.write_u16(0x40, 0x10);
-
         // location of object table (byte address) (0x0a and 0x0b)
         self.data.write_u16(self.object_addr, 0x0a);
 
@@ -615,19 +1070,129 @@ impl Zfile {
         // header extension table address (bytes) - its 0x36 and 0x37, why not only 0x36?
         self.data.write_u16(extension_addr, 0x36);
 
+        if self.version == 7 {
+            // routines offset (0x28 and 0x29) and static strings offset (0x2a and 0x2b)
+            let offset = self.packed_offset();
+            self.data.write_u16(offset, 0x28);
+            self.data.write_u16(offset, 0x2a);
+        }
+
         // alphabet
         self.write_alphabet(alpha_addr as usize);
 
         // header extension table
-        self.data.write_u16(3, extension_addr as usize);     // Number of further words in table
+        self.data.write_u16(extension_words, extension_addr as usize); // Number of further words in table
         self.data.write_u16(0, extension_addr as usize + 2); // x-coordinate of mouse after a click
         self.data.write_u16(0, extension_addr as usize + 4); // y-coordinate of mouse after a click
         self.data.write_u16(self.unicode_table_addr, extension_addr as usize + 6); // if != 0: unicode translation table address (optional)
+        if extension_words == 7 {
+            // flags 3 and the true default colours stay 0, they are set by the interpreter
+            self.data.write_zero_until(extension_addr as usize + 14);
+            self.blob_index_word = extension_addr + 14;
+            self.data.write_u16(0, self.blob_index_word as usize); // set by write_embedded_blobs
+        }
 
         // global variables
         // ...
     }
 
+    /// Returns the header flags for the enabled features and the capabilities the story uses,
+    /// see `capability_report`.
+    ///
+    /// zwreec has no transcript, fixed-pitch or UNDO feature, so these bits stay off.
+    pub fn header_flags(&self) -> HeaderFlags {
+        let report = self.capability_report();
+        HeaderFlags {
+            colours: !self.no_colours && (report.uses(Capability::Colours) || report.uses(Capability::TrueColour)),
+            transcript: false,
+            fixed_pitch: false,
+            undo: false,
+            sound: report.uses(Capability::Sound),
+        }
+    }
+
+    /// Writes Flags 1 (0x01) and Flags 2 (0x10 and 0x11) of the header.
+    fn write_header_flags(&mut self) {
+        let flags = self.header_flags();
+        self.data.write_byte(flags.flags1(), 0x01);
+        self.data.write_u16(flags.flags2(), 0x10);
+    }
+
+    /// Adds the capability to the file and to the routine written last.
+    fn use_capability(&mut self, capability: Capability) {
+        self.capabilities.insert(capability);
+        match self.routine_origins.last_mut() {
+            Some(routine) => routine.capabilities.insert(capability),
+            None => self.entry_capabilities.insert(capability)
+        }
+    }
+
+    /// Returns the capabilities of the interpreter the story needs.
+    ///
+    /// Only the code in front of the first routine, the routines of the passages and the
+    /// routines called from these count, a runtime routine that is never called does not.
+    /// The calls are only known completely after `end()`.
+    pub fn capability_report(&self) -> CapabilityReport {
+        // the routine an address belongs to, none for the code in front of the first routine
+        let routine_at = |addr: u32| self.routine_origins.iter().rposition(|routine| routine.address <= addr);
+
+        let mut reached: Vec<bool> = self.routine_origins.iter().map(|routine| routine.origin != Origin::Runtime).collect();
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for jump in self.jumps.iter().filter(|jump| jump.jump_type == JumpType::Routine) {
+                let caller_reached = match routine_at(jump.from_addr) {
+                    Some(caller) => reached[caller],
+                    None => true
+                };
+                // merged routines are called by the name of their label in the identical one
+                let callee = self.labels.iter().find(|label| label.name == jump.name).and_then(|label| routine_at(label.to_addr));
+                if let (true, Some(callee)) = (caller_reached, callee) {
+                    if !reached[callee] {
+                        reached[callee] = true;
+                        changed = true;
+                    }
+                }
+            }
+        }
+
+        let used = self.routine_origins.iter().zip(reached.iter())
+            .filter(|&(_, reached)| *reached)
+            .fold(self.entry_capabilities, |used, (routine, _)| used.union(routine.capabilities));
+        CapabilityReport::new(used)
+    }
+
+    /// Returns the mismatches between the flags written to the header and the capabilities the
+    /// story uses.
+    pub fn header_mismatches(&self) -> Vec<String> {
+        let report = self.capability_report();
+        let flags1 = self.data.bytes[0x01];
+        let flags2 = self.data.bytes[0x11];
+        let sets_colours = report.uses(Capability::Colours) || report.uses(Capability::TrueColour);
+        let wants_colours = flags1 & 0x01 != 0 && flags2 & 0x40 != 0;
+        let wants_sound = flags2 & 0x80 != 0;
+
+        let mut mismatches = Vec::new();
+        if sets_colours && !wants_colours {
+            mismatches.push("The story sets colours, but the header does not ask the interpreter for them".to_string());
+        } else if wants_colours && !sets_colours {
+            mismatches.push("The header asks the interpreter for colours, but the story never sets them".to_string());
+        }
+        if report.uses(Capability::Sound) && !wants_sound {
+            mismatches.push("The story plays sound effects, but the header does not ask the interpreter for them".to_string());
+        } else if wants_sound && !report.uses(Capability::Sound) {
+            mismatches.push("The header asks the interpreter for sound effects, but the story never plays them".to_string());
+        }
+        mismatches
+    }
+
+    /// Warns if the flags written to the header do not match the capabilities the story uses.
+    fn check_header_capabilities(&self) {
+        for mismatch in self.header_mismatches() {
+            warn!("{}", mismatch);
+        }
+    }
+
     /// Writes the alphabet to index.
     fn write_alphabet(&mut self, index: usize) {
         // TODO: is it possible to do this with map?
@@ -653,6 +1218,7 @@ impl Zfile {
     /// This iterates through all jumps and labels and if they have the same name
     /// it writes the "where to jump"-adress of the label to the position of the jump.
     fn write_jumps(&mut self) {
+        let offset = self.packed_offset();
         for jump in self.jumps.iter_mut() {
             let mut label_found = false;
 
@@ -661,7 +1227,7 @@ impl Zfile {
                     label_found = true;
                     match jump.jump_type {
                         JumpType::Routine => {
-                            let new_addr: u16 = (label.to_addr / 8) as u16;
+                            let new_addr: u16 = packed_address(self.version, offset, label.to_addr);
                             self.data.write_u16(new_addr, jump.from_addr as usize);
                         },
                         JumpType::Branch => {
@@ -684,13 +1250,85 @@ impl Zfile {
         }
     }
 
+    /// Returns the number of strings added so far.
+    pub fn string_count(&self) -> usize {
+        self.strings.len()
+    }
+
+    /// Returns the text of all strings stored in high memory, decoded from the bytes that are
+    /// written for them.
+    ///
+    /// Short texts printed directly by the print opcode are not part of this.
+    pub fn decoded_strings(&self) -> Vec<String> {
+        self.strings.iter().map(|string| if string.unicode {
+            let chars: Vec<u16> = string.chars[2..].chunks(2).map(|c| (c[0] as u16) << 8 | c[1] as u16).collect();
+            String::from_utf16_lossy(&chars)
+        } else {
+            ztext::decode(&string.chars, &self.unicode_table)
+        }).collect()
+    }
+
+    /// Returns the size in bytes of the strings added after the first `from` strings.
+    ///
+    /// Strings which were already added before are not counted again, as they are only written
+    /// once.
+    pub fn string_bytes_since(&self, from: usize) -> usize {
+        let mut bytes: usize = 0;
+        for (i, string) in self.strings.iter().enumerate().skip(from) {
+            if self.first_string(string) == i {
+                bytes += string.chars.len();
+            }
+        }
+        bytes
+    }
+
+    /// Returns the address and the length of every zstring written to high memory.
+    pub fn high_memory_strings(&self) -> Vec<(u32, u32)> {
+        self.strings.iter()
+            .filter(|string| !string.unicode && string.written_addr != 0)
+            .map(|string| (string.written_addr, string.chars.len() as u32))
+            .collect()
+    }
+
+    /// Writes a routine without an origin and bytes outside of every routine, for
+    /// `TestCase::InjectUnattributed`. The manifest of `backend::attest` has to flag both.
+    pub fn inject_unattributed(&mut self) {
+        let origin = self.origin.clone();
+        self.origin = Origin::Unknown;
+        self.emit(vec![ZOP::Routine{name: "injected".to_string(), count_variables: 0}, ZOP::Quit]);
+        self.data.append_bytes(&[0xb2, 0x11, 0xaa, 0x46]);
+        self.origin = origin;
+    }
+
+    /// Returns the index of the first string with the same text and unicode flag.
+    fn first_string(&self, string: &Zstring) -> usize {
+        self.string_index[&(string.orig.clone(), string.unicode)]
+    }
+
+    /// Adds the string to the strings written by `end()`.
+    fn add_string(&mut self, string: Zstring) {
+        let index = self.strings.len();
+        self.string_index.entry((string.orig.clone(), string.unicode)).or_insert(index);
+        self.strings.push(string);
+    }
+
+    /// Writes the utf16 string to static memory and returns its address.
+    fn write_static_string(&mut self, chars: &[u8], orig: &str) -> u16 {
+        let str_addr: u16 = self.last_static_written;
+        assert!(str_addr >= self.object_addr && str_addr as usize + chars.len() < self.program_addr as usize, "invalid addr to store a string");
+        debug!("{:#x}: utf16 \"{}\"", str_addr, orig);
+        let hexstrs: Vec<String> = chars.iter().map(|b| format!("{:02X}", b)).collect();
+        trace!("{:#x}: {}", str_addr, hexstrs.connect(" "));
+        self.data.write_bytes(chars, str_addr as usize);
+        self.last_static_written = self.last_static_written + chars.len() as u16;
+        str_addr
+    }
+
     /// Saves the string to high memory.
     pub fn write_string(&mut self, newstring: &str) -> u16 {
         self.write_strings();
-        for string in self.strings.iter_mut() {
-            if string.orig == newstring && string.unicode {
-                return string.written_addr as u16;
-            }
+        if let Some(&first) = self.string_index.get(&(newstring.to_string(), true)) {
+            return self.strings[first].written_addr as u16;
         }
         let mut utf16bytes: Vec<u8> = vec![];
         for c in newstring.chars() {
@@ -701,62 +1339,117 @@ impl Zfile {
         let length: u16 = utf16bytes.len() as u16 / 2u16;
         utf16bytes.insert(0, (length >> 8) as u8);
         utf16bytes.insert(1, (length & 0xff) as u8);
-        let str_addr: u16 = self.last_static_written;
-        assert!(str_addr >= self.object_addr && str_addr + (utf16bytes.len() as u16) < self.program_addr, "invalid addr to store a string");
-        debug!("{:#x}: utf16 \"{}\"", str_addr, newstring);
-        let hexstrs: Vec<String> = utf16bytes.iter().map(|b| format!("{:02X}", b)).collect();
-        trace!("{:#x}: {}", str_addr, hexstrs.connect(" "));
-        self.data.write_bytes(&utf16bytes, str_addr as usize);
-        self.last_static_written = self.last_static_written + utf16bytes.len() as u16;
-        self.strings.push(Zstring{orig: newstring.to_string(), chars: utf16bytes, unicode: true, written_addr: str_addr as u32, from_addr: 0});
+        let str_addr = self.write_static_string(&utf16bytes, newstring);
+        self.add_string(Zstring{orig: newstring.to_string(), chars: utf16bytes, unicode: true, written_addr: str_addr as u32, from_addr: 0});
+        self.strings_written = self.strings.len();
         str_addr
     }
 
     /// Saves the zstrings to high memory and writes the resulting address to the
     /// print_paddr arguments which referencing the string.
+    ///
+    /// Every text is written once, in the order of its first use, the later uses get the same
+    /// address. Only the strings added since the last call are written.
     fn write_strings(&mut self) {
-        let mut prev_strings: Vec<(Zstring, u32)> = vec![];
-        for string in self.strings.iter_mut() {
-            // optimize to reuse strings if they are the same
-            let mut string_found = false;
-            for &(ref other, addr) in prev_strings.iter() {
-                if other.unicode == string.unicode && other.orig == string.orig {
-                    string_found = true;
-                    if string.unicode {
-                        self.data.write_u16(addr as u16, string.from_addr as usize);  // normal addr
-                    } else {
-                        self.data.write_u16((addr/8) as u16, string.from_addr as usize);  // packed addr
-                    }
-                    break;
-                }
-            }
-            if string_found == false {  // add new string to high mem
-                let n_str_addr: u32 = if string.unicode && string.written_addr == 0 {
-                    let str_addr: u16 = self.last_static_written;
-                    assert!(str_addr >= self.object_addr && str_addr + (string.chars.len() as u16) < self.program_addr, "invalid addr to store a string");
-                    debug!("{:#x}: utf16 \"{}\"", str_addr, string.orig);
-                    let hexstrs: Vec<String> = string.chars.iter().map(|b| format!("{:02X}", b)).collect();
-                    trace!("{:#x}: {}", str_addr, hexstrs.connect(" "));
-                    self.data.write_bytes(&string.chars, str_addr as usize);
-                    self.data.write_u16(str_addr as u16, string.from_addr as usize);  // normal addr
-                    self.last_static_written = self.last_static_written + string.chars.len() as u16;
-                    str_addr as u32
-                } else if string.unicode == false && string.written_addr == 0 {
+        let version = self.version;
+        let offset = self.packed_offset();
+        for i in self.strings_written..self.strings.len() {
+            let first = self.first_string(&self.strings[i]);
+            if first == i && self.strings[i].written_addr == 0 {  // add new string to high mem
+                let chars = self.strings[i].chars.clone();
+                let orig = self.strings[i].orig.clone();
+                let str_addr: u32 = if self.strings[i].unicode {
+                    self.write_static_string(&chars, &orig) as u32
+                } else {
                     let str_addr: u32 = align_address(self.data.len() as u32, 8);
                     self.data.write_zero_until(str_addr as usize);
-                    debug!("{:#x}: zstring \"{}\"", str_addr, string.orig);
-                    let hexstrs: Vec<String> = string.chars.iter().map(|b| format!("{:02X}", b)).collect();
+                    debug!("{:#x}: zstring \"{}\"", str_addr, orig);
+                    let hexstrs: Vec<String> = chars.iter().map(|b| format!("{:02X}", b)).collect();
                     trace!("{:#x}: {}", str_addr, hexstrs.connect(" "));
-                    self.data.append_bytes(&string.chars);
-                    self.data.write_u16((str_addr/8) as u16, string.from_addr as usize);  // packed addr
+                    if checked_packed_address(version, offset, str_addr).is_none() {
+                        error_force_panic!(self.cfg => CodeGenError::StringBeyondPackedAddresses {
+                            text: orig.chars().take(40).collect(), address: str_addr, version: version });
+                    }
+                    self.data.append_bytes(&chars);
                     str_addr
-                } else {
-                    string.written_addr
                 };
-                string.written_addr = n_str_addr;
-                prev_strings.push((string.clone(), n_str_addr));
+                self.strings[i].written_addr = str_addr;
+            }
+
+            // strings of write_string() have no instruction that references them
+            let (from_addr, unicode) = (self.strings[i].from_addr, self.strings[i].unicode);
+            if from_addr != 0 {
+                let addr = self.strings[first].written_addr;
+                if unicode {
+                    self.data.write_u16(addr as u16, from_addr as usize);  // normal addr
+                } else {
+                    self.data.write_u16(packed_address(version, offset, addr), from_addr as usize);  // packed addr
+                }
             }
         }
+        self.strings_written = self.strings.len();
+    }
+
+    /// Appends the embedded blobs and their index behind the strings.
+    ///
+    /// Every blob and the index start at an address divided by 8. The index has the number of
+    /// blobs as a word, followed for every blob by the length of its name as a byte, the name in
+    /// ASCII, the address and the length of the blob as two words each. The address of the index
+    /// divided by 8 is written to the seventh word of the header extension table. The file length
+    /// in the header stays 0 unless the source is appended behind the blobs, so interpreters load
+    /// the whole file and never run the blobs.
+    fn write_embedded_blobs(&mut self) {
+        let mut placements: Vec<(String, u32, u32)> = vec![];
+        for &(ref name, ref blob) in self.embedded_blobs.iter() {
+            let blob_addr = align_address(self.data.len() as u32, 8);
+            self.data.write_zero_until(blob_addr as usize);
+            self.data.append_bytes(blob);
+            info!("Blob: {} at {:#x}, {} bytes", name, blob_addr, blob.len());
+            placements.push((name.clone(), blob_addr, blob.len() as u32));
+        }
+
+        let index_addr = align_address(self.data.len() as u32, 8);
+        if index_addr / 8 > 0xffff {
+            panic!("The index of the embedded blobs starts at {:#x}, which is beyond the addresses of the header extension table - the blobs are too large", index_addr);
+        }
+        self.data.write_zero_until(index_addr as usize);
+        let mut index: Vec<u8> = vec![];
+        op::write_u16(placements.len() as u16, &mut index);
+        for &(ref name, blob_addr, length) in placements.iter() {
+            index.push(name.len() as u8);
+            index.extend(name.bytes());
+            op::write_u16((blob_addr >> 16) as u16, &mut index);
+            op::write_u16(blob_addr as u16, &mut index);
+            op::write_u16((length >> 16) as u16, &mut index);
+            op::write_u16(length as u16, &mut index);
+        }
+        self.data.append_bytes(&index);
+        self.data.write_u16((index_addr / 8) as u16, self.blob_index_word as usize);
+        self.embedded_regions.extend(placements.into_iter());
+        self.embedded_regions.push(("(blob index)".to_string(), index_addr, index.len() as u32));
+    }
+
+    /// Appends the compressed Twee source behind the story, see `archive` for the format.
+    ///
+    /// The story is padded to a multiple of the unit of the file length of its version, 4 bytes
+    /// for version 7 and 8 bytes for version 8, which is written as the file length to the
+    /// header, together with the checksum of the story for `verify`. Interpreters only load the
+    /// bytes up to the file length, so the story runs the same as without the source.
+    fn append_source(&mut self, source: &[u8]) {
+        let scale = archive::file_length_scale(self.version) as u32;
+        let length = align_address(self.data.len() as u32, scale);
+        if length / scale > 0xffff {
+            error_force_panic!(self.cfg => CodeGenError::SourceDoesNotFit { end: length, limit: 0xffff * scale });
+        }
+        self.data.write_zero_until(length as usize);
+        self.data.write_u16((length / scale) as u16, 0x1a);
+        let checksum = self.data.bytes[0x40..].iter().fold(0u16, |sum, &byte| sum.wrapping_add(byte as u16));
+        self.data.write_u16(checksum, 0x1c);
+
+        let chunk = archive::source_chunk(source);
+        info!("Source: at {:#x}, {} bytes for {} bytes of Twee", length, chunk.len(), source.len());
+        self.embedded_regions.push(("(source)".to_string(), length, chunk.len() as u32));
+        self.data.append_bytes(&chunk);
     }
 
     /// Adds a jump to write the jump-addresses after reading all commands.
@@ -791,6 +1484,121 @@ impl Zfile {
         }
     }
 
+    /// Records every instruction written from now on, see `recorded_ops`.
+    pub fn record_ops(&mut self) {
+        self.recorded_ops = Some(Vec::new());
+    }
+
+    /// Returns the instructions written since `record_ops`, in the order they were written.
+    ///
+    /// The instructions that only stand for others, like `SetVarType` or `AddTypes`, are
+    /// recorded as the instructions they are written as, all others as they were given. Merged
+    /// routines are recorded like the others, so the jumps to their labels can still be followed.
+    pub fn recorded_ops(&self) -> Vec<ZOP> {
+        self.recorded_ops.clone().unwrap_or(Vec::new())
+    }
+
+    /// Returns the packed address of every label, the labels of merged routines point into the
+    /// earlier routine.
+    pub fn label_addresses(&self) -> Vec<(String, u16)> {
+        self.labels.iter().map(|label| (label.name.clone(), self.packed_address(label.to_addr))).collect()
+    }
+
+    /// Write out the ZOP instructions like `emit`, but merges every routine which is identical
+    /// to one written earlier.
+    ///
+    /// A routine reaches from its `Routine` to the end of `code` or to the label that the code in
+    /// front of it jumps to, like the routines of links that set variables.
+    /// A routine followed by another one is never merged, since its code goes on after the
+    /// inner routine. All labels of a merged routine point into the earlier one, so every jump to
+    /// them still works.
+    pub fn emit_merging_routines(&mut self, code: Vec<ZOP>) {
+        let first_jump = self.jumps.len();
+        // the data length, label, jump and string count before the current routine
+        let mut routine: Option<(usize, usize, usize, usize)> = None;
+
+        for instr in code.into_iter() {
+            match &instr {
+                &ZOP::Routine{..} => {
+                    // the outer routine continues after this one
+                    routine = None;
+                },
+                &ZOP::Label{ref name} => {
+                    let closes = match routine {
+                        Some((_, _, jump_start, _)) => self.jumps[first_jump..jump_start].iter().any(|jump| &jump.name == name),
+                        None => false
+                    };
+                    if closes {
+                        let (data_start, label_start, jump_start, string_start) = routine.take().unwrap();
+                        self.merge_routine(data_start, label_start, jump_start, string_start);
+                    }
+                },
+                _ => {}
+            }
+
+            if let ZOP::Routine{..} = instr {
+                routine = Some((self.data.bytes.len(), self.labels.len(), self.jumps.len(), self.strings.len()));
+            }
+            self.emit(vec![instr]);
+        }
+
+        if let Some((data_start, label_start, jump_start, string_start)) = routine {
+            self.merge_routine(data_start, label_start, jump_start, string_start);
+        }
+    }
+
+    /// Removes the routine written since `data_start` again if an identical routine exists and
+    /// points its labels to the identical one instead.
+    ///
+    /// The strings of the removed routine are dropped with its jumps, the identical routine
+    /// references the same texts on its own.
+    fn merge_routine(&mut self, data_start: usize, label_start: usize, jump_start: usize, string_start: usize) {
+        let routine_addr = self.labels[label_start].to_addr;
+        let labels: Vec<Zlabel> = self.labels[label_start..].to_vec();
+        let jumps: Vec<(u32, JumpTarget, JumpType)> = self.jumps[jump_start..].iter().map(|jump| {
+            let target = match labels.iter().find(|label| label.name == jump.name) {
+                Some(label) => JumpTarget::Inside(label.to_addr - routine_addr),
+                None => JumpTarget::Outside(jump.name.clone())
+            };
+            (jump.from_addr - routine_addr, target, jump.jump_type.clone())
+        }).collect();
+        let body = RoutineBody {
+            bytes: self.data.bytes[routine_addr as usize..].to_vec(),
+            jumps: jumps,
+            labels: labels.iter().map(|label| label.to_addr - routine_addr).collect(),
+            strings: self.strings[string_start..].iter()
+                .map(|string| (string.from_addr - routine_addr, string.orig.clone(), string.unicode))
+                .collect(),
+        };
+
+        let earlier = self.routine_bodies.get(&body).cloned();
+        let earlier = match earlier {
+            Some(earlier) => earlier,
+            None => {
+                self.routine_bodies.insert(body, routine_addr);
+                return
+            }
+        };
+
+        debug!("Merging routine {} into the identical routine at {:#x}", labels[0].name, earlier);
+        self.data.bytes.truncate(data_start);
+        self.routine_origins.retain(|routine| (routine.address as usize) < data_start);
+        self.labels.truncate(label_start);
+        self.jumps.truncate(jump_start);
+        for string in self.strings.split_off(string_start).into_iter() {
+            let key = (string.orig, string.unicode);
+            if self.string_index[&key] >= string_start {
+                self.string_index.remove(&key);
+            }
+        }
+        self.strings_written = ::std::cmp::min(self.strings_written, string_start);
+        for label in labels.into_iter() {
+            let to_addr = earlier + label.to_addr - routine_addr;
+            self.add_label(label.name, to_addr);
+        }
+        self.merged_routines += 1;
+    }
+
     /// Write opcodes to data array but also return written bytes for testing purposes as well as
     /// the resulting new labels and jumps.
     pub fn write_zop(&mut self, instr: &ZOP, return_new_jumps: bool) -> (Vec<Zlabel>, Vec<Zjump>, Vec<u8>){
@@ -807,47 +1615,100 @@ impl Zfile {
             Vec::new()
         };
 
-        //self.data.write_bytes()
-        let bytes: Vec<u8> = match instr {
-            &ZOP::Quit => op::quit(),
-            &ZOP::Newline => op::op_newline(),
-            &ZOP::Dec{variable} => op::op_dec(variable),
-            &ZOP::Inc{variable} => op::op_inc(variable),
-            &ZOP::Add{ref operand1, ref operand2, ref save_variable} => op::op_add(operand1, operand2, save_variable),
-            &ZOP::Sub{ref operand1, ref operand2, ref save_variable} => op::op_sub(operand1, operand2, save_variable),
-            &ZOP::Mul{ref operand1, ref operand2, ref save_variable} => op::op_mul(operand1, operand2, save_variable),
-            &ZOP::Div{ref operand1, ref operand2, ref save_variable} => op::op_div(operand1, operand2, save_variable),
-            &ZOP::Or{ref operand1, ref operand2, ref save_variable} => op::op_or(operand1, operand2, save_variable),
-            &ZOP::And{ref operand1, ref operand2, ref save_variable} => op::op_and(operand1, operand2, save_variable),
-            &ZOP::Mod{ref operand1, ref operand2, ref save_variable} => op::op_mod(operand1, operand2, save_variable),
-            &ZOP::Not{ref operand, ref result} => op::op_not(operand, result),
-            &ZOP::StoreVariable{ref variable, ref value} => op::op_store_var(variable, value),
-            &ZOP::Ret{ref value} => op::op_ret(value),
-            &ZOP::PrintAddr{ref address} => op::op_print_addr(address),
-            &ZOP::PrintPaddr{ref address} => op::op_print_paddr(address),
-            &ZOP::SetColor{foreground, background} => if self.no_colours { Vec::new() } else { op::op_set_color(foreground, background) },
-            &ZOP::SetColorVar{foreground, background} => if self.no_colours { Vec::new() } else {  op::op_set_color_var(foreground, background) },
-            &ZOP::Random{ref range, ref variable} => op::op_random(range, variable),
-            &ZOP::PrintNumVar{ref variable} => op::op_print_num_var(variable),
-            &ZOP::SetTextStyle{bold, reverse, monospace, italic} => if self.no_colours { Vec::new() } else { op::op_set_text_style(bold, reverse, monospace, italic) },
-            &ZOP::ReadChar{local_var_id} => op::op_read_char(local_var_id),
-            &ZOP::LoadW{ref array_address, ref index, ref variable} => op::op_loadw(array_address, index, variable),
-            &ZOP::StoreW{ref array_address, ref index, ref variable} => op::op_storew(array_address, index, variable),
-            &ZOP::StoreB{ref array_address, ref index, ref variable} => op::op_storeb(array_address, index, variable),
-            &ZOP::StoreBOperand{ref array_address, ref index, ref operand} => op::op_storeboperand(array_address, index, operand),
-            &ZOP::LoadBOperand{ref array_address, ref index, ref variable} => op::op_loadb(array_address, index, variable),
-            &ZOP::Call1NVar{variable} => op::op_call_1n_var(variable),
-            &ZOP::EraseWindow{value} => op::op_erase_window(value),
-            &ZOP::EraseLine => op::op_erase_line(),
-            &ZOP::SetCursor{line, col} => op::op_set_cursor(line, col),
-            &ZOP::SetCursorOperand{ref row, ref col} => op::op_set_cursor_operand(row, col),
-            &ZOP::PushVar{ref variable} => op::op_push_var(variable),
-            &ZOP::PullVar{ref variable} => op::op_pull(variable.id.clone()),
-            &ZOP::GetCursor{ref store_addr} => op::op_get_cursor(store_addr),
-
-            _ => Vec::new()
+        if cfg!(debug_assertions) {
+            if let Some(problem) = operand_problem(instr, self.routine_locals) {
+                panic!("Invalid {:?}: {}", instr, problem);
+            }
+        }
+
+        let stands_for_others = match instr {
+            &ZOP::SetVarType{..} | &ZOP::CopyVarType{..} | &ZOP::GetVarType{..} | &ZOP::AddTypes{..} |
+            &ZOP::PrintVar{..} | &ZOP::PrintUnicodeStr{..} | &ZOP::UpdateCursorPos => true,
+            _ => false
+        };
+        if self.zop_depth == 0 && !stands_for_others {
+            if let Some(ref mut ops) = self.recorded_ops {
+                ops.push(instr.clone());
+            }
+        }
+        if !stands_for_others {
+            self.zop_depth += 1;
+        }
+
+        // the simple opcodes are written directly into the data
+        let bytes = &mut self.data.bytes;
+        match instr {
+            &ZOP::Quit => op::quit(bytes),
+            &ZOP::Newline => op::op_newline(bytes),
+            &ZOP::Dec{variable} => op::op_dec(variable, bytes),
+            &ZOP::Inc{variable} => op::op_inc(variable, bytes),
+            &ZOP::Add{ref operand1, ref operand2, ref save_variable} => op::op_add(operand1, operand2, save_variable, bytes),
+            &ZOP::Sub{ref operand1, ref operand2, ref save_variable} => op::op_sub(operand1, operand2, save_variable, bytes),
+            &ZOP::Mul{ref operand1, ref operand2, ref save_variable} => op::op_mul(operand1, operand2, save_variable, bytes),
+            &ZOP::Div{ref operand1, ref operand2, ref save_variable} => op::op_div(operand1, operand2, save_variable, bytes),
+            &ZOP::Or{ref operand1, ref operand2, ref save_variable} => op::op_or(operand1, operand2, save_variable, bytes),
+            &ZOP::And{ref operand1, ref operand2, ref save_variable} => op::op_and(operand1, operand2, save_variable, bytes),
+            &ZOP::Mod{ref operand1, ref operand2, ref save_variable} => op::op_mod(operand1, operand2, save_variable, bytes),
+            &ZOP::Not{ref operand, ref result} => op::op_not(operand, result, bytes),
+            &ZOP::StoreVariable{ref variable, ref value} => op::op_store_var(variable, value, bytes),
+            &ZOP::Ret{ref value} => op::op_ret(value, bytes),
+            &ZOP::Catch{ref result} => op::op_catch(result, bytes),
+            &ZOP::Throw{ref value, ref frame} => op::op_throw(value, frame, bytes),
+            &ZOP::PrintAddr{ref address} => op::op_print_addr(address, bytes),
+            &ZOP::PrintPaddr{ref address} => op::op_print_paddr(address, bytes),
+            &ZOP::SetColor{foreground, background} => if !self.no_colours { op::op_set_color(foreground, background, bytes) },
+            &ZOP::SetColorVar{foreground, background} => if !self.no_colours { op::op_set_color_var(foreground, background, bytes) },
+            &ZOP::SetTrueColor{ref foreground, ref background} => if !self.no_colours { op::op_set_true_color(foreground, background, bytes) },
+            &ZOP::SoundEffect{ref number} => op::op_sound_effect(number, bytes),
+            &ZOP::Random{ref range, ref variable} => op::op_random(range, variable, bytes),
+            &ZOP::PrintNumVar{ref variable} => op::op_print_num_var(variable, bytes),
+            &ZOP::SetTextStyle{bold, reverse, monospace, italic} => if !self.no_colours { op::op_set_text_style(bold, reverse, monospace, italic, bytes) },
+            &ZOP::Save{ref result} => op::op_save(result, bytes),
+            &ZOP::Restore{ref result} => op::op_restore(result, bytes),
+            &ZOP::ReadChar{local_var_id} => op::op_read_char(local_var_id, bytes),
+            &ZOP::LoadW{ref array_address, ref index, ref variable} => op::op_loadw(array_address, index, variable, bytes),
+            &ZOP::CopyTable{ref first, ref second, ref size} => op::op_copy_table(first, second, size, bytes),
+            &ZOP::StoreW{ref array_address, ref index, ref variable} => op::op_storew(array_address, index, variable, bytes),
+            &ZOP::StoreB{ref array_address, ref index, ref variable} => op::op_storeb(array_address, index, variable, bytes),
+            &ZOP::StoreBOperand{ref array_address, ref index, ref operand} => op::op_storeboperand(array_address, index, operand, bytes),
+            &ZOP::LoadBOperand{ref array_address, ref index, ref variable} => op::op_loadb(array_address, index, variable, bytes),
+            &ZOP::Call1NVar{variable} => op::op_call_1n_var(variable, bytes),
+            &ZOP::EraseWindow{value} => {
+                assert!(value >= -2 && value <= 1, "invalid window {} to erase", value);
+                if value == 1 && self.upper_window_lines == 0 {
+                    warn!("Erasing the upper window, but the screen is not split");
+                }
+                op::op_erase_window(value, bytes)
+            },
+            &ZOP::SplitWindow{lines} => op::op_split_window(lines, bytes),
+            &ZOP::SetWindow{window} => {
+                assert!(window <= 1, "invalid window {}, only 0 and 1 are supported", window);
+                op::op_set_window(window, bytes)
+            },
+            &ZOP::EraseLine => op::op_erase_line(bytes),
+            &ZOP::SetCursor{line, col} => op::op_set_cursor(line, col, bytes),
+            &ZOP::SetCursorOperand{ref row, ref col} => op::op_set_cursor_operand(row, col, bytes),
+            &ZOP::PushVar{ref variable} => op::op_push_var(variable, bytes),
+            &ZOP::PullVar{ref variable} => op::op_pull(variable.id.clone(), bytes),
+            &ZOP::GetCursor{ref store_addr} => op::op_get_cursor(store_addr, bytes),
+
+            _ => ()
+        }
+        // the capabilities of the opcodes that are actually written, see header_flags
+        let capability = match instr {
+            &ZOP::PrintUnicode{..} => Some(Capability::Unicode),
+            &ZOP::PrintUnicodeVar{..} if !self.no_unicode => Some(Capability::Unicode),
+            &ZOP::SetColor{..} | &ZOP::SetColorVar{..} if !self.no_colours => Some(Capability::Colours),
+            &ZOP::SetTrueColor{..} if !self.no_colours => Some(Capability::TrueColour),
+            &ZOP::SoundEffect{..} => Some(Capability::Sound),
+            &ZOP::ReadCharTimer{..} => Some(Capability::TimedInput),
+            &ZOP::SplitWindow{..} | &ZOP::SetWindow{..} | &ZOP::SetCursor{..} | &ZOP::SetCursorOperand{..} => Some(Capability::Windows),
+            &ZOP::Save{..} | &ZOP::Restore{..} => Some(Capability::SaveRestore),
+            _ => None
         };
-        self.data.append_bytes(&bytes);
+        if let Some(capability) = capability {
+            self.use_capability(capability);
+        }
         match instr {
             &ZOP::PrintUnicode{c} => self.op_print_unicode_char(c),
             &ZOP::PrintUnicodeVar{ref var} => if self.no_unicode == false { self.op_print_unicode_var(var) } else { self.op_call_2n_with_arg("print_char", &Operand::new_var(var.id.clone())) },
@@ -860,6 +1721,7 @@ impl Zfile {
             &ZOP::Routine{ref name, count_variables} => self.routine(name, count_variables),
             &ZOP::Label{ref name} => self.label(name),
             &ZOP::Jump{ref jump_to_label} => self.op_jump(jump_to_label),
+            &ZOP::Piracy{ref jump_to_label} => self.op_piracy(jump_to_label),
             &ZOP::ReadCharTimer{local_var_id, timer, ref routine} => self.op_read_char_timer(local_var_id, timer, routine),
             &ZOP::JL{ref operand1, ref operand2, ref jump_to_label} => self.op_jl(operand1, operand2, jump_to_label),
             &ZOP::JLE{ref operand1, ref operand2, ref jump_to_label} => self.op_jle(operand1, operand2, jump_to_label),
@@ -880,6 +1742,8 @@ impl Zfile {
             &ZOP::CopyVarType{ref variable, ref from} => self.copy_var_type(variable, from),
             &ZOP::GetVarType{ref variable, ref result} => self.get_var_type(variable, result),
             &ZOP::UpdateCursorPos => self.update_cursor_pos(),
+            &ZOP::EraseWindow{value} => self.restore_split_window(value),
+            &ZOP::SplitWindow{lines} => self.remember_split_window(lines),
             _ => ()
         }
         let mut new_jumps: Vec<Zjump> = vec![];
@@ -898,6 +1762,19 @@ impl Zfile {
             }
         }
 
+        if !stands_for_others {
+            self.zop_depth -= 1;
+        }
+
+        // the routine reaches to its last instruction, the strings written between the routines
+        // do not belong to it
+        let end = self.data.bytes.len() as u32;
+        match self.routine_origins.last_mut() {
+            Some(routine) => routine.size = end - routine.address,
+            None => if end > self.program_addr as u32 {
+                self.entry_size = end - self.program_addr as u32;
+            }
+        }
         (new_labels, new_jumps, self.data.bytes[beginning..self.data.bytes.len()].to_vec())
     }
 
@@ -924,7 +1801,7 @@ impl Zfile {
                     current_utf16.clear();
                     // unicode exists in table
                     current_text.push(character);
-                } else if self.force_unicode == false && self.unicode_table.len() < 96 {
+                } else if self.force_unicode == false && self.unicode_table.len() < ztext::UNICODE_TABLE_SIZE && self.unicode_table.len() < self.unicode_table_size as usize {
                     self.gen_write_out_unicode(current_utf16.to_string());  // write out utf16 string
                     current_utf16.clear();
                     // there is space in the unicode table
@@ -959,7 +1836,7 @@ impl Zfile {
                 utf16bytes.insert(0, (length >> 8) as u8);
                 utf16bytes.insert(1, (length & 0xff) as u8);
                 self.emit(vec![ZOP::Call2NWithArg{jump_to_label: "print_unicode".to_string(), arg: Operand::new_large_const(0)}]);
-                self.strings.push(Zstring{chars: utf16bytes, orig: current_utf16.to_string(), from_addr: (self.data.len()-2) as u32, unicode: true, written_addr: 0});
+                self.add_string(Zstring{chars: utf16bytes, orig: current_utf16.to_string(), from_addr: (self.data.len()-2) as u32, unicode: true, written_addr: 0});
             } else {
                 if let Some(temp) = current_utf16.chars().nth(0) {
                     self.emit(vec![ZOP::PrintUnicode{c: temp as u16}]);
@@ -987,7 +1864,7 @@ impl Zfile {
         self.emit(vec![ZOP::PrintPaddr{address: Operand::new_large_const(0)}]);  // dummy addr
         let mut text_bytes: Bytes = Bytes{bytes: Vec::new()};
         ztext::encode(&mut text_bytes, text, &self.unicode_table);
-        self.strings.push(
+        self.add_string(
             Zstring{
                 chars: text_bytes.bytes,
                 orig: text.to_string(),
@@ -1001,7 +1878,39 @@ impl Zfile {
     // ================================
     // no op-commands
 
-    /// Start of a zcode program.
+    /// Returns the number of bytes written so far.
+    pub fn bytes_len(&self) -> usize {
+        self.data.len()
+    }
+
+    /// Returns the number of bytes the file can hold before its memory is reallocated.
+    pub fn capacity(&self) -> usize {
+        self.data.bytes.capacity()
+    }
+
+    /// Reserves the memory for a file of `bytes` bytes.
+    ///
+    /// The file grows a byte at a time, in the vector of its bytes that doubles its capacity
+    /// whenever it is full. Without a reservation this reallocates and copies the file at least
+    /// 13 times while `start` fills the memory in front of the program, and once more for every
+    /// doubling after it. If the file stays smaller than `bytes`, it is never reallocated.
+    pub fn reserve(&mut self, bytes: usize) {
+        let len = self.data.len();
+        if bytes > len {
+            self.data.bytes.reserve(bytes - len);
+        }
+    }
+
+    /// Returns roughly how many bytes the file of a Twee source with `source_size` bytes needs.
+    ///
+    /// This is the memory in front of the program, the runtime and one byte of Z-Code for every
+    /// byte of the source. Text takes fewer bytes in Z-Code, the instructions of macros and
+    /// links more.
+    pub fn estimated_size(&self, source_size: usize) -> usize {
+        self.program_addr as usize + RUNTIME_SIZE + source_size
+    }
+
+    /// Start of a zcode program.
     ///
     /// Fills everything < program_addr with zeros.
     ///
@@ -1019,11 +1928,28 @@ impl Zfile {
             ZOP::SetColor{foreground: foreground, background: background},
             ZOP::EraseWindow{value: -1},
             ZOP::Call1N{jump_to_label: "malloc_init".to_string()},
-            ZOP::Call1N{jump_to_label: "Start".to_string()},
-            ZOP::Label{name: "mainloop".to_string()},
-            ZOP::Call1N{jump_to_label: "system_check_links".to_string()},
-            ZOP::Jump{jump_to_label: "mainloop".to_string()},
+            ZOP::Call1N{jump_to_label: "system_start".to_string()},
         ]);
+
+        let main_loop = self.main_loop_ops("mainloop");
+        self.emit(main_loop);
+    }
+
+    /// Returns the instructions of the `main_loop`, which never return.
+    fn main_loop_ops(&self, label: &str) -> Vec<ZOP> {
+        match self.main_loop {
+            MainLoop::Links => vec![
+                ZOP::Label{name: label.to_string()},
+                ZOP::Call1N{jump_to_label: "system_check_links".to_string()},
+                ZOP::Jump{jump_to_label: label.to_string()},
+            ],
+            MainLoop::Quit => vec![ZOP::Quit],
+            MainLoop::Passage(ref name) => vec![
+                ZOP::Label{name: label.to_string()},
+                ZOP::Call1N{jump_to_label: name.to_string()},
+                ZOP::Jump{jump_to_label: label.to_string()},
+            ],
+        }
     }
 
     /// Writes all stuff that couldn't be written directly.
@@ -1037,30 +1963,67 @@ impl Zfile {
 
         self.write_unicode_table();
 
+        for (name, start, size) in self.memory_map() {
+            info!("Memory: {} at {:#06x}, {} bytes", name, start, size);
+        }
+
         info!("Writing predefined routines");
+        self.origin = Origin::Runtime;
+        self.routine_start();
         self.routine_check_links();
+        self.routine_follow_link();
+        self.routine_print_separator();
         self.routine_add_link();
+        self.routine_set_onexit();
         self.routine_check_more();
         self.routine_prompt();
+        self.routine_confirm();
+        self.routine_at();
+        self.routine_countdown();
         self.routine_print_unicode();
         self.routine_mem_free();
+        self.routine_collect();
         self.routine_manual_free();
+        self.routine_heap_stats();
+        self.routine_clear();
         self.routine_malloc_init();
         self.routine_strcpy();
         self.routine_strcmp();
         self.routine_malloc();
+        self.routine_error();
         self.routine_strcat();
         self.routine_itoa();
         self.routine_print_var();
         self.routine_print_char();
         self.routine_add_types();
+        self.routine_draw();
+        self.routine_join();
+        self.routine_map_get();
+        self.routine_map_holds();
+        self.routine_print_map();
+        self.routine_bar();
+        self.routine_change_case();
+        self.routine_substr();
+        self.write_header_flags();
+        self.check_header_capabilities();
 
         info!("Writing jump addresses");
         self.write_jumps();
+        self.code_end = self.data.len() as u32;
 
         info!("Writing strings to high memory");
         self.write_strings();
 
+        if !self.embedded_blobs.is_empty() {
+            info!("Writing embedded blobs");
+            self.write_embedded_blobs();
+        }
+
+        if let Some(source) = self.embedded_source.take() {
+            info!("Appending the Twee source");
+            self.append_source(&source);
+        }
+
         info!("Finished writing Z-Code data");
     }
 
@@ -1072,7 +2035,9 @@ impl Zfile {
         assert!(index % 8 == 0, "adress of a routine must start at address % 8 == 0");
 
         self.add_label(name.to_string(), index);
+        self.routine_origins.push(RoutineOrigin { name: name.to_string(), address: index, origin: self.origin.clone(), size: 0, capabilities: CapabilitySet::new() });
         self.data.write_byte(count_variables, index as usize);
+        self.routine_locals = count_variables;
     }
 
     /// Command to create a label.
@@ -1084,17 +2049,79 @@ impl Zfile {
     // ================================
     // zcode routines
 
+    /// Routine to enter the start passage.
+    ///
+    /// The start passage is only known after all passages were read, so `start()` calls this
+    /// routine instead of the passage itself.
+    ///
+    /// With an `error_passage` the start passage and the main loop run in `system_run` and the
+    /// error passage in `system_error_run`. Both store their stack frame in global 24 with
+    /// `catch`, `rt_error` throws back to it and `system_start` calls the error passage again,
+    /// so a runtime error doesn't leave the routines of the failed passage on the stack.
+    pub fn routine_start(&mut self) {
+        let start_passage = self.start_passage.clone();
+        let error_passage = match self.error_passage.clone() {
+            Some(passage) => passage,
+            None => {
+                self.emit(vec![
+                    ZOP::Routine{name: "system_start".to_string(), count_variables: 0},
+                    ZOP::Call1N{jump_to_label: start_passage},
+                    ZOP::Ret{value: Operand::new_const(0)}
+                ]);
+                return
+            }
+        };
+
+        self.emit(vec![
+            ZOP::Routine{name: "system_start".to_string(), count_variables: 0},
+            ZOP::Call1N{jump_to_label: "system_run".to_string()},
+            ZOP::Label{name: "system_start_error".to_string()},
+            ZOP::Call1N{jump_to_label: "system_error_run".to_string()},
+            ZOP::Jump{jump_to_label: "system_start_error".to_string()},
+        ]);
+
+        let mut code = vec![
+            ZOP::Routine{name: "system_run".to_string(), count_variables: 0},
+            ZOP::Catch{result: Variable::new(24)},
+            ZOP::Call1N{jump_to_label: start_passage},
+        ];
+        code.extend(self.main_loop_ops("system_run_loop"));
+        code.extend(vec![
+            ZOP::Routine{name: "system_error_run".to_string(), count_variables: 0},
+            ZOP::Catch{result: Variable::new(24)},
+            ZOP::Newline,
+            ZOP::Call1N{jump_to_label: error_passage},
+        ]);
+        code.extend(self.main_loop_ops("system_error_run_loop"));
+        self.emit(code);
+    }
+
     /// Routine to add the address of a passage-link.
     pub fn routine_add_link(&mut self) {
-        let save_at_addr: u16 = 1 + self.object_addr;
+        let save_at_addr: u16 = self.link_table;
+        let capacity = self.link_capacity as i16;
         self.emit(vec![
             ZOP::Routine{name: "system_add_link".to_string(), count_variables: 1},
+            // drops the link if the table is full, this only happens for passages that
+            // display themselves
+            ZOP::JGE{operand1: Operand::new_var(16), operand2: Operand::new_large_const(capacity), jump_to_label: "system_add_link_full".to_string()},
+
             // saves routine-argument to array
-            ZOP::StoreW{array_address: Operand::new_large_const(save_at_addr as i16), index: Variable::new(16), variable: Variable::new(1)},
+            ZOP::StoreW{array_address: Operand::new_pointer(save_at_addr), index: Variable::new(16), variable: Variable::new(1)},
 
             // inc the count links
             ZOP::Inc{variable: 16},
 
+            ZOP::Label{name: "system_add_link_full".to_string()},
+            ZOP::Ret{value: Operand::new_const(0)}
+        ]);
+    }
+
+    /// Routine to store the address of the `<<onexit>>` routine of the current passage.
+    pub fn routine_set_onexit(&mut self) {
+        self.emit(vec![
+            ZOP::Routine{name: "system_set_onexit".to_string(), count_variables: 1},
+            ZOP::StoreVariable{variable: Variable::new(18), value: Operand::new_var(1)},
             ZOP::Ret{value: Operand::new_const(0)}
         ]);
     }
@@ -1115,8 +2142,14 @@ impl Zfile {
     ///
     /// if >=10: 99 links are supported, leading zeroes are not allowed.
     /// To jump to a link with a number smaller than 10 you have to press enter.
+    ///
+    /// Without any links the program quits, with `no_dead_end_quit` only after printing THE END
+    /// and waiting for a key. With an `error_passage` this is a runtime error, unless the passage
+    /// is tagged `[ending]`, which sets global 22.
     pub fn routine_check_links(&mut self) {
-        let save_at_addr: u16 = 1 + self.object_addr;
+        let save_at_addr: u16 = self.link_table;
+        let no_links_label = if self.no_dead_end_quit { "system_check_links_the_end" } else { "system_check_links_end_quit" };
+        let dead_end_label = if self.error_passage.is_some() { "system_check_links_dead_end" } else { no_links_label };
         self.emit(vec![
             ZOP::Routine{name: "system_check_links".to_string(), count_variables: 3},
             ZOP::Newline,
@@ -1125,9 +2158,9 @@ impl Zfile {
             ZOP::JE{operand1: Operand::new_var(17), operand2: Operand::new_const(0x01), jump_to_label: "system_check_links_end_ret".to_string()},
 
             // jumps to the end, if there a no links
-            ZOP::JE{operand1: Operand::new_var(16), operand2: Operand::new_const(0x00), jump_to_label: "system_check_links_end_quit".to_string()},
+            ZOP::JE{operand1: Operand::new_var(16), operand2: Operand::new_const(0x00), jump_to_label: dead_end_label.to_string()},
             ZOP::SetTextStyle{bold: false, reverse: false, monospace: true, italic: false},
-            ZOP::Print{text: "---------------------------------------".to_string()},
+            ZOP::Call1N{jump_to_label: "system_print_separator".to_string()},
             ZOP::Newline,
             ZOP::Print{text: "Please press a number to select a link (end with Q):".to_string()},
             ZOP::Newline,
@@ -1140,6 +2173,15 @@ impl Zfile {
             ZOP::ReadChar{local_var_id: 0x01},
             // Quit programme on Q
             ZOP::JE{operand1: Operand::new_var(0x01), operand2: Operand::new_const(81), jump_to_label: "system_check_links_end_quit".to_string()},
+        ]);
+        if self.heap_stats {
+            // H prints the usage of the heap
+            self.emit(vec![
+                ZOP::JE{operand1: Operand::new_var(0x01), operand2: Operand::new_const(72), jump_to_label: "system_check_links_heap_stats".to_string()},
+                ZOP::JE{operand1: Operand::new_var(0x01), operand2: Operand::new_const(104), jump_to_label: "system_check_links_heap_stats".to_string()},
+            ]);
+        }
+        self.emit(vec![
             // check for the start of the konami code
             ZOP::JE{operand1: Operand::new_var(0x01), operand2: Operand::new_const(129), jump_to_label: "system_check_links_jmp".to_string()},
             ZOP::Jump{jump_to_label: "system_check_links_after".to_string()},
@@ -1204,23 +2246,147 @@ impl Zfile {
             ZOP::SetTextStyle{bold: false, reverse: false, monospace: false, italic: false},
             // decrement 0x03 becouse the array starts at 0 and not at 1
             ZOP::Dec{variable: 3},
-            ZOP::LoadW{array_address: Operand::new_large_const(save_at_addr as i16), index: Variable::new(3), variable: Variable::new(2)},
-
-            // no more links exist
-            ZOP::StoreVariable{variable: Variable::new(16), value: Operand::new_const(0)},
+            ZOP::LoadW{array_address: Operand::new_pointer(save_at_addr), index: Variable::new(3), variable: Variable::new(2)},
             ZOP::Newline,
+        ]);
 
-            // clears window bevor jumping
-            ZOP::EraseWindow{value: -1},
-
+        self.transition_pause();
+        self.emit(vec![
             // jump to the new passage
-            ZOP::Call1NVar{variable: 0x02},
+            ZOP::Label{name: "system_check_links_clear".to_string()},
+            ZOP::Call2NWithArg{jump_to_label: "system_follow_link".to_string(), arg: Operand::new_var(0x02)},
             ZOP::Label{name: "system_check_links_end_ret".to_string()},
             ZOP::Ret{value: Operand::new_const(0)},
 
             ZOP::Label{name: "system_check_links_end_quit".to_string()},
             ZOP::Quit
         ]);
+
+        if self.heap_stats {
+            self.emit(vec![
+                ZOP::Label{name: "system_check_links_heap_stats".to_string()},
+                ZOP::Call1N{jump_to_label: "rt_heap_stats".to_string()},
+                ZOP::Jump{jump_to_label: "system_check_links_loop".to_string()},
+            ]);
+        }
+
+        if self.error_passage.is_some() {
+            let dead_end = self.write_string("DEAD-END");
+            self.emit(vec![
+                ZOP::Label{name: "system_check_links_dead_end".to_string()},
+                ZOP::JE{operand1: Operand::new_var(22), operand2: Operand::new_const(1), jump_to_label: no_links_label.to_string()},
+                ZOP::CallVNA2{jump_to_label: "rt_error".to_string(), arg1: Operand::new_const(2), arg2: Operand::new_string_ref(dead_end)},
+            ]);
+        }
+
+        if self.no_dead_end_quit {
+            // the story ended, leave the last passage on the screen until a key is pressed
+            self.emit(vec![
+                ZOP::Label{name: "system_check_links_the_end".to_string()},
+                ZOP::Newline,
+                ZOP::Print{text: "THE END".to_string()},
+                ZOP::Newline,
+                ZOP::ReadChar{local_var_id: 0x01},
+                ZOP::Quit
+            ]);
+        }
+
+        if self.transition_pause {
+            // stops the timed read of the pause after its first interval
+            self.emit(vec![
+                ZOP::Routine{name: "system_transition_tick".to_string(), count_variables: 0},
+                ZOP::Ret{value: Operand::new_const(1)},
+            ]);
+        }
+    }
+
+    /// Leaves the current passage for the passage at the address in the first argument, like
+    /// a chosen link does.
+    ///
+    /// The links of the current passage are dropped, the screen is cleared and the `<<onexit>>`
+    /// routine of the current passage runs before the new passage is called. `system_check_links`
+    /// and `<<countdown>>` both enter their passage through this routine.
+    pub fn routine_follow_link(&mut self) {
+        let mut code = vec![
+            ZOP::Routine{name: "system_follow_link".to_string(), count_variables: 1},
+            // no more links exist
+            ZOP::StoreVariable{variable: Variable::new(16), value: Operand::new_const(0)},
+        ];
+        if self.error_passage.is_some() {
+            // the next passage is no ending and errors are handled again
+            code.extend(vec![
+                ZOP::StoreVariable{variable: Variable::new(22), value: Operand::new_const(0)},
+                ZOP::StoreVariable{variable: Variable::new(23), value: Operand::new_const(0)},
+            ]);
+        }
+        code.extend(vec![
+            // clears window bevor jumping
+            ZOP::EraseWindow{value: -1},
+
+            // leave the current passage through its <<onexit>> routine
+            ZOP::JE{operand1: Operand::new_var(18), operand2: Operand::new_const(0), jump_to_label: "system_follow_link_enter".to_string()},
+            ZOP::Call1NVar{variable: 18},
+
+            ZOP::Label{name: "system_follow_link_enter".to_string()},
+            ZOP::Call1NVar{variable: 0x01},
+            ZOP::Ret{value: Operand::new_const(0)},
+        ]);
+        self.emit(code);
+    }
+
+    /// Prints the line above the links and the question of `confirm()`.
+    ///
+    /// A `separator_length` of 0 fits the line to the screen width in the header, one character
+    /// short so that the interpreter does not wrap it. Interpreters that do not set the width
+    /// get a line of `SEPARATOR_LENGTH` characters.
+    pub fn routine_print_separator(&mut self) {
+        let length = if self.separator_length > 0 { self.separator_length } else { SEPARATOR_LENGTH };
+        let fixed: String = ::std::iter::repeat(self.separator_char).take(length as usize).collect();
+        if self.separator_length > 0 {
+            self.emit(vec![
+                ZOP::Routine{name: "system_print_separator".to_string(), count_variables: 0},
+                ZOP::Print{text: fixed},
+                ZOP::Ret{value: Operand::new_const(0)},
+            ]);
+            return;
+        }
+
+        let width = Variable::new(1);
+        let character = Variable::new(2);
+        self.emit(vec![
+            ZOP::Routine{name: "system_print_separator".to_string(), count_variables: 2},
+            ZOP::LoadBOperand{array_address: Operand::new_const(0), index: Operand::new_const(0x21), variable: width.clone()},
+            ZOP::JE{operand1: Operand::new_var(width.id), operand2: Operand::new_const(0), jump_to_label: "system_print_separator_fixed".to_string()},
+            ZOP::StoreVariable{variable: character.clone(), value: Operand::new_const(self.separator_char as u8)},
+            ZOP::Label{name: "system_print_separator_loop".to_string()},
+            ZOP::Dec{variable: width.id},
+            ZOP::JL{operand1: Operand::new_var(width.id), operand2: Operand::new_const(1), jump_to_label: "system_print_separator_end".to_string()},
+            ZOP::PrintChar{var: character.clone()},
+            ZOP::Jump{jump_to_label: "system_print_separator_loop".to_string()},
+            ZOP::Label{name: "system_print_separator_fixed".to_string()},
+            ZOP::Print{text: fixed},
+            ZOP::Label{name: "system_print_separator_end".to_string()},
+            ZOP::Ret{value: Operand::new_const(0)},
+        ]);
+    }
+
+    /// Waits `TRANSITION_PAUSE` tenths of a second (or until a key is pressed) before
+    /// `system_check_links` follows the link and `system_follow_link` clears the screen.
+    ///
+    /// Interpreters announce timed input in bit 7 of Flags 1, without it the screen is cleared
+    /// immediately. Uses the local variable 1 of `system_check_links`, which holds the
+    /// already evaluated key.
+    fn transition_pause(&mut self) {
+        if !self.transition_pause {
+            return;
+        }
+
+        self.emit(vec![
+            ZOP::LoadBOperand{array_address: Operand::new_const(0), index: Operand::new_const(0x01), variable: Variable::new(1)},
+            ZOP::And{operand1: Operand::new_var(1), operand2: Operand::new_const(0x80), save_variable: Variable::new(1)},
+            ZOP::JE{operand1: Operand::new_var(1), operand2: Operand::new_const(0), jump_to_label: "system_check_links_clear".to_string()},
+            ZOP::ReadCharTimer{local_var_id: 1, timer: TRANSITION_PAUSE, routine: "system_transition_tick".to_string()},
+        ]);
     }
 
     /// Easter-egg, with konami-code to start.
@@ -1284,6 +2450,453 @@ impl Zfile {
         }
     }
 
+    /// draw Z-Routine: Removes a random value from a deck and returns it.
+    ///
+    /// Expects the address of a deck as created by `shuffle()`: the malloc length, the number of
+    /// remaining values and then the values. The drawn value is swapped behind the remaining ones,
+    /// so every value is drawn exactly once. An empty deck returns 0, or is refilled with all of
+    /// its values if `auto_reshuffle` is set.
+    pub fn routine_draw(&mut self) {
+        let deck = Operand::new_var(1);
+        let one = Variable::new(2);
+        let remaining = Variable::new(3);
+        let chosen = Variable::new(4);
+        let value = Variable::new(5);
+        let last = Variable::new(6);
+        let mut code = vec![
+            ZOP::Routine{name: "draw".to_string(), count_variables: 6},
+            ZOP::StoreVariable{variable: one.clone(), value: Operand::new_large_const(1)},
+            ZOP::LoadW{array_address: deck.clone(), index: one.clone(), variable: remaining.clone()},
+            ZOP::JE{operand1: Operand::new_var(remaining.id), operand2: Operand::new_large_const(0), jump_to_label: "draw_empty".to_string()},
+            ZOP::Label{name: "draw_pick".to_string()},
+            // random index in 1..remaining, shifted by the remaining-word to 2..remaining+1
+            ZOP::Random{range: Operand::new_var(remaining.id), variable: chosen.clone()},
+            ZOP::Inc{variable: chosen.id},
+            ZOP::Inc{variable: remaining.id},
+            // swap the chosen value with the last remaining one
+            ZOP::LoadW{array_address: deck.clone(), index: chosen.clone(), variable: value.clone()},
+            ZOP::LoadW{array_address: deck.clone(), index: remaining.clone(), variable: last.clone()},
+            ZOP::StoreW{array_address: deck.clone(), index: chosen.clone(), variable: last.clone()},
+            ZOP::StoreW{array_address: deck.clone(), index: remaining.clone(), variable: value.clone()},
+            ZOP::Dec{variable: remaining.id},
+            ZOP::Dec{variable: remaining.id},
+            ZOP::StoreW{array_address: deck.clone(), index: one.clone(), variable: remaining.clone()},
+            ZOP::Ret{value: Operand::new_var(value.id)},
+            ZOP::Label{name: "draw_empty".to_string()},
+        ];
+        if self.auto_reshuffle {
+            // all values are still in the deck, only the remaining count has to be reset to the
+            // malloc length minus the remaining-word. last is 0 at this point.
+            code.extend(vec![
+                ZOP::LoadW{array_address: deck.clone(), index: last.clone(), variable: remaining.clone()},
+                ZOP::Dec{variable: remaining.id},
+                ZOP::Jump{jump_to_label: "draw_pick".to_string()},
+            ]);
+        }
+        code.push(ZOP::Ret{value: Operand::new_const(0)});
+        self.emit(code);
+    }
+
+    /// join Z-Routine: Concatenates the values left in a deck to one string for
+    /// `join(deck, separator)`.
+    ///
+    /// Expects the address of a deck as created by `shuffle()`: the malloc length, the number
+    /// of remaining values and the values. The values are converted with `add_types` and put
+    /// behind each other with the separator in between, so an empty deck gives an empty string
+    /// and a single value has no separator. An address that is no block on the heap, or a
+    /// block with more remaining values than it has words, is joined like an empty deck.
+    pub fn routine_join(&mut self) {
+        let heap_start = self.heap_start;
+        let upper_bound = self.static_addr - 2;
+        let deck = Variable::new(1);
+        let separator = Variable::new(2);
+        let joined = Variable::new(3);
+        let index = Variable::new(4);
+        let last = Variable::new(5);
+        let value = Variable::new(6);
+        let pos = Variable::new(7);
+        let end = Variable::new(8);
+        let zero = Variable::new(9);
+        let length = Variable::new(10);
+        let empty = self.write_string("");
+        self.emit(vec![
+            ZOP::Routine{name: "join".to_string(), count_variables: 10},
+            ZOP::StoreVariable{variable: joined.clone(), value: Operand::new_pointer(empty)},
+            ZOP::JE{operand1: Operand::new_var(deck.id), operand2: Operand::new_const(0), jump_to_label: "join_return".to_string()},
+            // walks the blocks of the heap like rt_clear to find the deck
+            ZOP::LoadW{array_address: Operand::new_pointer(upper_bound), index: zero.clone(), variable: end.clone()},
+            ZOP::StoreVariable{variable: pos.clone(), value: Operand::new_pointer(heap_start)},
+            ZOP::Label{name: "join_find".to_string()},
+            ZOP::JE{operand1: Operand::new_var(pos.id), operand2: Operand::new_var(end.id), jump_to_label: "join_return".to_string()},
+            ZOP::LoadW{array_address: Operand::new_var(pos.id), index: zero.clone(), variable: length.clone()},
+            ZOP::JE{operand1: Operand::new_var(pos.id), operand2: Operand::new_var(deck.id), jump_to_label: "join_found".to_string()},
+            ZOP::Add{operand1: Operand::new_var(pos.id), operand2: Operand::new_const(2), save_variable: pos.clone()},
+            ZOP::JL{operand1: Operand::new_var(length.id), operand2: Operand::new_const(0), jump_to_label: "join_find".to_string()},
+            ZOP::Add{operand1: Operand::new_var(pos.id), operand2: Operand::new_var(length.id), save_variable: pos.clone()},
+            ZOP::Add{operand1: Operand::new_var(pos.id), operand2: Operand::new_var(length.id), save_variable: pos.clone()},
+            ZOP::Jump{jump_to_label: "join_find".to_string()},
+            ZOP::Label{name: "join_found".to_string()},
+            ZOP::StoreVariable{variable: index.clone(), value: Operand::new_large_const(1)},
+            ZOP::LoadW{array_address: Operand::new_var(deck.id), index: index.clone(), variable: last.clone()},
+            // the remaining values and the remaining-word have to fit into the block
+            ZOP::JL{operand1: Operand::new_var(last.id), operand2: Operand::new_const(0), jump_to_label: "join_return".to_string()},
+            ZOP::JGE{operand1: Operand::new_var(last.id), operand2: Operand::new_var(length.id), jump_to_label: "join_return".to_string()},
+            // the values are at 2..remaining+1
+            ZOP::Inc{variable: last.id},
+            ZOP::Label{name: "join_loop".to_string()},
+            ZOP::Inc{variable: index.id},
+            ZOP::JG{operand1: Operand::new_var(index.id), operand2: Operand::new_var(last.id), jump_to_label: "join_return".to_string()},
+            ZOP::JE{operand1: Operand::new_var(index.id), operand2: Operand::new_const(2), jump_to_label: "join_value".to_string()},
+            ZOP::CallVSA2{jump_to_label: "strcat".to_string(), arg1: Operand::new_var(joined.id), arg2: Operand::new_var(separator.id), result: joined.clone()},
+            ZOP::Label{name: "join_value".to_string()},
+            ZOP::LoadW{array_address: Operand::new_var(deck.id), index: index.clone(), variable: value.clone()},
+            ZOP::CallVS2A5{jump_to_label: "add_types".to_string(), arg1: Operand::new_var(joined.id), arg2: Operand::new_const(Type::String as u8),
+                arg3: Operand::new_var(value.id), arg4: Operand::new_const(Type::Integer as u8), arg5: Operand::new_const(joined.id), result: joined.clone()},
+            ZOP::Jump{jump_to_label: "join_loop".to_string()},
+            ZOP::Label{name: "join_return".to_string()},
+            ZOP::Ret{value: Operand::new_var(joined.id)}
+        ]);
+    }
+
+    /// map_get Z-Routine: Looks up the value of a key in a map.
+    ///
+    /// Expects the address of a map as created by a map literal: the malloc length, the number n
+    /// of entries, the n keys, the n values and the n types of the values. A nested map is
+    /// stored behind its parent in the same heap block. The keys are compared with `strcmp`.
+    /// The type of the value is written to the variable `varid` the result is stored in. A
+    /// missing key returns the integer 0, the compiler warns about keys that are in none of
+    /// the map literals.
+    pub fn routine_map_get(&mut self) {
+        let type_store = self.type_store;
+        let map = Variable::new(1);
+        let key = Variable::new(2);
+        let varid = Variable::new(3);
+        let count = Variable::new(4);
+        let index = Variable::new(5);
+        let found = Variable::new(6);
+        let last = Variable::new(7);
+        self.emit(vec![
+            ZOP::Routine{name: "map_get".to_string(), count_variables: 7},
+            ZOP::JE{operand1: Operand::new_var(map.id), operand2: Operand::new_const(0), jump_to_label: "map_get_missing".to_string()},
+            ZOP::StoreVariable{variable: index.clone(), value: Operand::new_large_const(1)},
+            ZOP::LoadW{array_address: Operand::new_var(map.id), index: index.clone(), variable: count.clone()},
+            // the keys are at 2..n+1
+            ZOP::Add{operand1: Operand::new_var(count.id), operand2: Operand::new_large_const(1), save_variable: last.clone()},
+            ZOP::Label{name: "map_get_loop".to_string()},
+            ZOP::Inc{variable: index.id},
+            ZOP::JG{operand1: Operand::new_var(index.id), operand2: Operand::new_var(last.id), jump_to_label: "map_get_missing".to_string()},
+            ZOP::LoadW{array_address: Operand::new_var(map.id), index: index.clone(), variable: found.clone()},
+            ZOP::CallVSA2{jump_to_label: "strcmp".to_string(), arg1: Operand::new_var(found.id), arg2: Operand::new_var(key.id), result: found.clone()},
+            ZOP::JE{operand1: Operand::new_var(found.id), operand2: Operand::new_const(0), jump_to_label: "map_get_found".to_string()},
+            ZOP::Jump{jump_to_label: "map_get_loop".to_string()},
+            ZOP::Label{name: "map_get_found".to_string()},
+            // the value is n words behind the key and its type n words behind the value
+            ZOP::Add{operand1: Operand::new_var(index.id), operand2: Operand::new_var(count.id), save_variable: index.clone()},
+            ZOP::LoadW{array_address: Operand::new_var(map.id), index: index.clone(), variable: found.clone()},
+            ZOP::Add{operand1: Operand::new_var(index.id), operand2: Operand::new_var(count.id), save_variable: index.clone()},
+            ZOP::LoadW{array_address: Operand::new_var(map.id), index: index.clone(), variable: last.clone()},
+            ZOP::StoreBOperand{array_address: Operand::new_pointer(type_store), index: Operand::new_var(varid.id), operand: Operand::new_var(last.id)},
+            ZOP::Ret{value: Operand::new_var(found.id)},
+            ZOP::Label{name: "map_get_missing".to_string()},
+            ZOP::StoreBOperand{array_address: Operand::new_pointer(type_store), index: Operand::new_var(varid.id), operand: Operand::new_const(Type::Integer as u8)},
+            ZOP::Ret{value: Operand::new_const(0)},
+        ]);
+    }
+
+    /// map_holds Z-Routine: Checks if a value of a map or of a map in it is the address.
+    ///
+    /// `mem_free` only sees the addresses in the global variables, so it asks this routine
+    /// whether a block is still used as a value of a map. Returns 1 if it is and 0 otherwise.
+    pub fn routine_map_holds(&mut self) {
+        let map = Variable::new(1);
+        let address = Variable::new(2);
+        let count = Variable::new(3);
+        let index = Variable::new(4);
+        let last = Variable::new(5);
+        let value = Variable::new(6);
+        let vartype = Variable::new(7);
+        self.emit(vec![
+            ZOP::Routine{name: "map_holds".to_string(), count_variables: 7},
+            ZOP::StoreVariable{variable: index.clone(), value: Operand::new_large_const(1)},
+            ZOP::LoadW{array_address: Operand::new_var(map.id), index: index.clone(), variable: count.clone()},
+            // the values are at n+2..2n+1
+            ZOP::Add{operand1: Operand::new_var(index.id), operand2: Operand::new_var(count.id), save_variable: index.clone()},
+            ZOP::Add{operand1: Operand::new_var(index.id), operand2: Operand::new_var(count.id), save_variable: last.clone()},
+            ZOP::Label{name: "map_holds_loop".to_string()},
+            ZOP::Inc{variable: index.id},
+            ZOP::JG{operand1: Operand::new_var(index.id), operand2: Operand::new_var(last.id), jump_to_label: "map_holds_none".to_string()},
+            ZOP::LoadW{array_address: Operand::new_var(map.id), index: index.clone(), variable: value.clone()},
+            ZOP::JE{operand1: Operand::new_var(value.id), operand2: Operand::new_var(address.id), jump_to_label: "map_holds_found".to_string()},
+            ZOP::Add{operand1: Operand::new_var(index.id), operand2: Operand::new_var(count.id), save_variable: vartype.clone()},
+            ZOP::LoadW{array_address: Operand::new_var(map.id), index: vartype.clone(), variable: vartype.clone()},
+            ZOP::JNE{operand1: Operand::new_var(vartype.id), operand2: Operand::new_const(Type::Map as u8), jump_to_label: "map_holds_loop".to_string()},
+            ZOP::CallVSA2{jump_to_label: "map_holds".to_string(), arg1: Operand::new_var(value.id), arg2: Operand::new_var(address.id), result: vartype.clone()},
+            ZOP::JE{operand1: Operand::new_var(vartype.id), operand2: Operand::new_const(1), jump_to_label: "map_holds_found".to_string()},
+            ZOP::Jump{jump_to_label: "map_holds_loop".to_string()},
+            ZOP::Label{name: "map_holds_found".to_string()},
+            ZOP::Ret{value: Operand::new_const(1)},
+            ZOP::Label{name: "map_holds_none".to_string()},
+            ZOP::Ret{value: Operand::new_const(0)},
+        ]);
+    }
+
+    /// print_map Z-Routine: Prints a map like `{hp: 10, name: Bob}`.
+    ///
+    /// The values are printed according to their types, nested maps the same way.
+    pub fn routine_print_map(&mut self) {
+        let map = Variable::new(1);
+        let count = Variable::new(2);
+        let index = Variable::new(3);
+        let last = Variable::new(4);
+        let position = Variable::new(5);
+        let value = Variable::new(6);
+        let vartype = Variable::new(7);
+        self.emit(vec![
+            ZOP::Routine{name: "print_map".to_string(), count_variables: 7},
+            ZOP::Print{text: "{".to_string()},
+            ZOP::StoreVariable{variable: index.clone(), value: Operand::new_large_const(1)},
+            ZOP::LoadW{array_address: Operand::new_var(map.id), index: index.clone(), variable: count.clone()},
+            ZOP::Add{operand1: Operand::new_var(count.id), operand2: Operand::new_large_const(1), save_variable: last.clone()},
+            ZOP::Label{name: "print_map_loop".to_string()},
+            ZOP::Inc{variable: index.id},
+            ZOP::JG{operand1: Operand::new_var(index.id), operand2: Operand::new_var(last.id), jump_to_label: "print_map_end".to_string()},
+            ZOP::JE{operand1: Operand::new_var(index.id), operand2: Operand::new_const(2), jump_to_label: "print_map_key".to_string()},
+            ZOP::Print{text: ", ".to_string()},
+            ZOP::Label{name: "print_map_key".to_string()},
+            ZOP::LoadW{array_address: Operand::new_var(map.id), index: index.clone(), variable: value.clone()},
+            ZOP::PrintUnicodeStr{address: Operand::new_var(value.id)},
+            ZOP::Print{text: ": ".to_string()},
+            ZOP::Add{operand1: Operand::new_var(index.id), operand2: Operand::new_var(count.id), save_variable: position.clone()},
+            ZOP::LoadW{array_address: Operand::new_var(map.id), index: position.clone(), variable: value.clone()},
+            ZOP::Add{operand1: Operand::new_var(position.id), operand2: Operand::new_var(count.id), save_variable: position.clone()},
+            ZOP::LoadW{array_address: Operand::new_var(map.id), index: position.clone(), variable: vartype.clone()},
+            ZOP::JE{operand1: Operand::new_var(vartype.id), operand2: Operand::new_const(Type::String as u8), jump_to_label: "print_map_string".to_string()},
+            ZOP::JE{operand1: Operand::new_var(vartype.id), operand2: Operand::new_const(Type::Bool as u8), jump_to_label: "print_map_bool".to_string()},
+            ZOP::JE{operand1: Operand::new_var(vartype.id), operand2: Operand::new_const(Type::Map as u8), jump_to_label: "print_map_map".to_string()},
+            ZOP::PrintNumVar{variable: value.clone()},
+            ZOP::Jump{jump_to_label: "print_map_loop".to_string()},
+            ZOP::Label{name: "print_map_string".to_string()},
+            ZOP::PrintUnicodeStr{address: Operand::new_var(value.id)},
+            ZOP::Jump{jump_to_label: "print_map_loop".to_string()},
+            ZOP::Label{name: "print_map_bool".to_string()},
+            ZOP::JE{operand1: Operand::new_var(value.id), operand2: Operand::new_const(0), jump_to_label: "print_map_false".to_string()},
+            ZOP::Print{text: "true".to_string()},
+            ZOP::Jump{jump_to_label: "print_map_loop".to_string()},
+            ZOP::Label{name: "print_map_false".to_string()},
+            ZOP::Print{text: "false".to_string()},
+            ZOP::Jump{jump_to_label: "print_map_loop".to_string()},
+            ZOP::Label{name: "print_map_map".to_string()},
+            ZOP::Call2NWithArg{jump_to_label: "print_map".to_string(), arg: Operand::new_var(value.id)},
+            ZOP::Jump{jump_to_label: "print_map_loop".to_string()},
+            ZOP::Label{name: "print_map_end".to_string()},
+            ZOP::Print{text: "}".to_string()},
+            ZOP::Ret{value: Operand::new_const(0)},
+        ]);
+    }
+
+    /// bar Z-Routine: Builds a string like `[###-------]` for `bar(value, max, width)`.
+    ///
+    /// The value is clamped to `0..max` and `value*width/max` of the `width` cells are filled. As
+    /// long as `max*width` does not fit into 16 bits, value and max are halved first, which is off
+    /// by at most one cell. A max below 1 gives an empty bar.
+    pub fn routine_bar(&mut self) {
+        let value = Variable::new(1);
+        let max = Variable::new(2);
+        let width = Variable::new(3);
+        let bar = Variable::new(4);
+        let index = Variable::new(5);
+        let filled = Variable::new(6);
+        let cell = Variable::new(7);
+        let limit = Variable::new(8);
+        self.emit(vec![
+            ZOP::Routine{name: "bar".to_string(), count_variables: 8},
+            ZOP::JG{operand1: Operand::new_var(width.id), operand2: Operand::new_large_const(0), jump_to_label: "bar_width_positive".to_string()},
+            ZOP::StoreVariable{variable: width.clone(), value: Operand::new_large_const(0)},
+            ZOP::Label{name: "bar_width_positive".to_string()},
+            // filled stays 0 for an empty max
+            ZOP::JL{operand1: Operand::new_var(max.id), operand2: Operand::new_large_const(1), jump_to_label: "bar_alloc".to_string()},
+            ZOP::JG{operand1: Operand::new_var(value.id), operand2: Operand::new_large_const(0), jump_to_label: "bar_value_positive".to_string()},
+            ZOP::StoreVariable{variable: value.clone(), value: Operand::new_large_const(0)},
+            ZOP::Label{name: "bar_value_positive".to_string()},
+            ZOP::JL{operand1: Operand::new_var(value.id), operand2: Operand::new_var(max.id), jump_to_label: "bar_value_clamped".to_string()},
+            ZOP::StoreVariable{variable: value.clone(), value: Operand::new_var(max.id)},
+            ZOP::Label{name: "bar_value_clamped".to_string()},
+            ZOP::JE{operand1: Operand::new_var(width.id), operand2: Operand::new_large_const(0), jump_to_label: "bar_alloc".to_string()},
+            ZOP::Div{operand1: Operand::new_large_const(i16::max_value()), operand2: Operand::new_var(width.id), save_variable: limit.clone()},
+            ZOP::Label{name: "bar_scale".to_string()},
+            ZOP::JGE{operand1: Operand::new_var(limit.id), operand2: Operand::new_var(max.id), jump_to_label: "bar_fill".to_string()},
+            ZOP::Div{operand1: Operand::new_var(value.id), operand2: Operand::new_large_const(2), save_variable: value.clone()},
+            ZOP::Div{operand1: Operand::new_var(max.id), operand2: Operand::new_large_const(2), save_variable: max.clone()},
+            ZOP::Jump{jump_to_label: "bar_scale".to_string()},
+            ZOP::Label{name: "bar_fill".to_string()},
+            ZOP::Mul{operand1: Operand::new_var(value.id), operand2: Operand::new_var(width.id), save_variable: filled.clone()},
+            ZOP::Div{operand1: Operand::new_var(filled.id), operand2: Operand::new_var(max.id), save_variable: filled.clone()},
+            ZOP::Label{name: "bar_alloc".to_string()},
+            // the length word, both brackets and the cells
+            ZOP::Add{operand1: Operand::new_var(width.id), operand2: Operand::new_large_const(3), save_variable: bar.clone()},
+            ZOP::Call2S{jump_to_label: "malloc".to_string(), arg: Operand::new_var(bar.id), result: bar.clone()},
+            ZOP::Add{operand1: Operand::new_var(width.id), operand2: Operand::new_large_const(2), save_variable: cell.clone()},
+            ZOP::StoreVariable{variable: index.clone(), value: Operand::new_large_const(0)},
+            ZOP::StoreW{array_address: Operand::new_var(bar.id), index: index.clone(), variable: cell.clone()},
+            ZOP::Inc{variable: index.id},
+            ZOP::StoreVariable{variable: cell.clone(), value: Operand::new_large_const('[' as i16)},
+            ZOP::StoreW{array_address: Operand::new_var(bar.id), index: index.clone(), variable: cell.clone()},
+            // the cell at index i+1 is the i-th cell
+            ZOP::Label{name: "bar_loop".to_string()},
+            ZOP::JG{operand1: Operand::new_var(index.id), operand2: Operand::new_var(width.id), jump_to_label: "bar_close".to_string()},
+            ZOP::StoreVariable{variable: cell.clone(), value: Operand::new_large_const('-' as i16)},
+            ZOP::JG{operand1: Operand::new_var(index.id), operand2: Operand::new_var(filled.id), jump_to_label: "bar_store".to_string()},
+            ZOP::StoreVariable{variable: cell.clone(), value: Operand::new_large_const('#' as i16)},
+            ZOP::Label{name: "bar_store".to_string()},
+            ZOP::Inc{variable: index.id},
+            ZOP::StoreW{array_address: Operand::new_var(bar.id), index: index.clone(), variable: cell.clone()},
+            ZOP::Jump{jump_to_label: "bar_loop".to_string()},
+            ZOP::Label{name: "bar_close".to_string()},
+            ZOP::Inc{variable: index.id},
+            ZOP::StoreVariable{variable: cell.clone(), value: Operand::new_large_const(']' as i16)},
+            ZOP::StoreW{array_address: Operand::new_var(bar.id), index: index.clone(), variable: cell.clone()},
+            ZOP::Ret{value: Operand::new_var(bar.id)}
+        ]);
+    }
+
+    /// change_case Z-Routine: Copies a value as string with converted letters for `upper()` and
+    /// `lower()`.
+    ///
+    /// Takes the value, its type and 1 to convert to uppercase or 0 to convert to lowercase.
+    /// Numbers and booleans are converted to strings first. Only the ASCII letters are converted,
+    /// all other characters like umlauts are copied unchanged.
+    pub fn routine_change_case(&mut self) {
+        let value = Variable::new(1);
+        let vartype = Variable::new(2);
+        let upper = Variable::new(3);
+        let result = Variable::new(4);
+        let length = Variable::new(5);
+        let index = Variable::new(6);
+        let character = Variable::new(7);
+        let zero = Variable::new(8);  // stays 0 to access the length
+        let falsestr = self.write_string("false");
+        let truestr = self.write_string("true");
+        self.emit(vec![
+            ZOP::Routine{name: "change_case".to_string(), count_variables: 8},
+            ZOP::JE{operand1: Operand::new_var(vartype.id), operand2: Operand::new_const(Type::String as u8), jump_to_label: "change_case_copy".to_string()},
+            ZOP::JE{operand1: Operand::new_var(vartype.id), operand2: Operand::new_const(Type::Bool as u8), jump_to_label: "change_case_bool".to_string()},
+            ZOP::Call2S{jump_to_label: "itoa".to_string(), arg: Operand::new_var(value.id), result: value.clone()},
+            ZOP::Jump{jump_to_label: "change_case_copy".to_string()},
+            ZOP::Label{name: "change_case_bool".to_string()},
+            ZOP::JE{operand1: Operand::new_var(value.id), operand2: Operand::new_const(0), jump_to_label: "change_case_false".to_string()},
+            ZOP::StoreVariable{variable: value.clone(), value: Operand::new_pointer(truestr)},
+            ZOP::Jump{jump_to_label: "change_case_copy".to_string()},
+            ZOP::Label{name: "change_case_false".to_string()},
+            ZOP::StoreVariable{variable: value.clone(), value: Operand::new_pointer(falsestr)},
+            ZOP::Label{name: "change_case_copy".to_string()},
+            ZOP::LoadW{array_address: Operand::new_var(value.id), index: zero.clone(), variable: length.clone()},
+            // the length word and the characters
+            ZOP::Add{operand1: Operand::new_var(length.id), operand2: Operand::new_large_const(1), save_variable: result.clone()},
+            ZOP::Call2S{jump_to_label: "malloc".to_string(), arg: Operand::new_var(result.id), result: result.clone()},
+            ZOP::StoreW{array_address: Operand::new_var(result.id), index: zero.clone(), variable: length.clone()},
+            // the character at index i is the i-th character
+            ZOP::Label{name: "change_case_loop".to_string()},
+            ZOP::Inc{variable: index.id},
+            ZOP::JG{operand1: Operand::new_var(index.id), operand2: Operand::new_var(length.id), jump_to_label: "change_case_return".to_string()},
+            ZOP::LoadW{array_address: Operand::new_var(value.id), index: index.clone(), variable: character.clone()},
+            ZOP::JE{operand1: Operand::new_var(upper.id), operand2: Operand::new_const(0), jump_to_label: "change_case_lower".to_string()},
+            ZOP::JL{operand1: Operand::new_var(character.id), operand2: Operand::new_large_const('a' as i16), jump_to_label: "change_case_store".to_string()},
+            ZOP::JG{operand1: Operand::new_var(character.id), operand2: Operand::new_large_const('z' as i16), jump_to_label: "change_case_store".to_string()},
+            ZOP::Sub{operand1: Operand::new_var(character.id), operand2: Operand::new_large_const(32), save_variable: character.clone()},
+            ZOP::Jump{jump_to_label: "change_case_store".to_string()},
+            ZOP::Label{name: "change_case_lower".to_string()},
+            ZOP::JL{operand1: Operand::new_var(character.id), operand2: Operand::new_large_const('A' as i16), jump_to_label: "change_case_store".to_string()},
+            ZOP::JG{operand1: Operand::new_var(character.id), operand2: Operand::new_large_const('Z' as i16), jump_to_label: "change_case_store".to_string()},
+            ZOP::Add{operand1: Operand::new_var(character.id), operand2: Operand::new_large_const(32), save_variable: character.clone()},
+            ZOP::Label{name: "change_case_store".to_string()},
+            ZOP::StoreW{array_address: Operand::new_var(result.id), index: index.clone(), variable: character.clone()},
+            ZOP::Jump{jump_to_label: "change_case_loop".to_string()},
+            ZOP::Label{name: "change_case_return".to_string()},
+            ZOP::Ret{value: Operand::new_var(result.id)}
+        ]);
+    }
+
+    /// substr Z-Routine: Copies `len` characters of a value as string from `start` on for
+    /// `substr()`.
+    ///
+    /// Takes the value, its type, start, len and the line of the call. Numbers and booleans are
+    /// converted to strings first. If start or len are out of the bounds of the string they are
+    /// clamped and a warning with the line is printed.
+    pub fn routine_substr(&mut self) {
+        let value = Variable::new(1);
+        let vartype = Variable::new(2);
+        let start = Variable::new(3);
+        let len = Variable::new(4);
+        let line = Variable::new(5);
+        let length = Variable::new(6);
+        let result = Variable::new(7);
+        let index = Variable::new(8);
+        let character = Variable::new(9);
+        let zero = Variable::new(10);  // stays 0 to access the length
+        let clamped = Variable::new(11);
+        let falsestr = self.write_string("false");
+        let truestr = self.write_string("true");
+        self.emit(vec![
+            ZOP::Routine{name: "substr".to_string(), count_variables: 11},
+            ZOP::JE{operand1: Operand::new_var(vartype.id), operand2: Operand::new_const(Type::String as u8), jump_to_label: "substr_start".to_string()},
+            ZOP::JE{operand1: Operand::new_var(vartype.id), operand2: Operand::new_const(Type::Bool as u8), jump_to_label: "substr_bool".to_string()},
+            ZOP::Call2S{jump_to_label: "itoa".to_string(), arg: Operand::new_var(value.id), result: value.clone()},
+            ZOP::Jump{jump_to_label: "substr_start".to_string()},
+            ZOP::Label{name: "substr_bool".to_string()},
+            ZOP::JE{operand1: Operand::new_var(value.id), operand2: Operand::new_const(0), jump_to_label: "substr_false".to_string()},
+            ZOP::StoreVariable{variable: value.clone(), value: Operand::new_pointer(truestr)},
+            ZOP::Jump{jump_to_label: "substr_start".to_string()},
+            ZOP::Label{name: "substr_false".to_string()},
+            ZOP::StoreVariable{variable: value.clone(), value: Operand::new_pointer(falsestr)},
+            // 0 <= start <= length
+            ZOP::Label{name: "substr_start".to_string()},
+            ZOP::LoadW{array_address: Operand::new_var(value.id), index: zero.clone(), variable: length.clone()},
+            ZOP::JL{operand1: Operand::new_var(start.id), operand2: Operand::new_const(0), jump_to_label: "substr_start_low".to_string()},
+            ZOP::JG{operand1: Operand::new_var(start.id), operand2: Operand::new_var(length.id), jump_to_label: "substr_start_high".to_string()},
+            ZOP::Jump{jump_to_label: "substr_len".to_string()},
+            ZOP::Label{name: "substr_start_low".to_string()},
+            ZOP::StoreVariable{variable: start.clone(), value: Operand::new_const(0)},
+            ZOP::StoreVariable{variable: clamped.clone(), value: Operand::new_const(1)},
+            ZOP::Jump{jump_to_label: "substr_len".to_string()},
+            ZOP::Label{name: "substr_start_high".to_string()},
+            ZOP::StoreVariable{variable: start.clone(), value: Operand::new_var(length.id)},
+            ZOP::StoreVariable{variable: clamped.clone(), value: Operand::new_const(1)},
+            // 0 <= len <= length - start
+            ZOP::Label{name: "substr_len".to_string()},
+            ZOP::Sub{operand1: Operand::new_var(length.id), operand2: Operand::new_var(start.id), save_variable: character.clone()},
+            ZOP::JL{operand1: Operand::new_var(len.id), operand2: Operand::new_const(0), jump_to_label: "substr_len_low".to_string()},
+            ZOP::JG{operand1: Operand::new_var(len.id), operand2: Operand::new_var(character.id), jump_to_label: "substr_len_high".to_string()},
+            ZOP::Jump{jump_to_label: "substr_warn".to_string()},
+            ZOP::Label{name: "substr_len_low".to_string()},
+            ZOP::StoreVariable{variable: len.clone(), value: Operand::new_const(0)},
+            ZOP::StoreVariable{variable: clamped.clone(), value: Operand::new_const(1)},
+            ZOP::Jump{jump_to_label: "substr_warn".to_string()},
+            ZOP::Label{name: "substr_len_high".to_string()},
+            ZOP::StoreVariable{variable: len.clone(), value: Operand::new_var(character.id)},
+            ZOP::StoreVariable{variable: clamped.clone(), value: Operand::new_const(1)},
+            ZOP::Label{name: "substr_warn".to_string()},
+            ZOP::JE{operand1: Operand::new_var(clamped.id), operand2: Operand::new_const(0), jump_to_label: "substr_copy".to_string()},
+            ZOP::Print{text: "[substr() in line ".to_string()},
+            ZOP::PrintNumVar{variable: line.clone()},
+            ZOP::Print{text: " is out of range]".to_string()},
+            // the length word and the characters
+            ZOP::Label{name: "substr_copy".to_string()},
+            ZOP::Add{operand1: Operand::new_var(len.id), operand2: Operand::new_large_const(1), save_variable: result.clone()},
+            ZOP::Call2S{jump_to_label: "malloc".to_string(), arg: Operand::new_var(result.id), result: result.clone()},
+            ZOP::StoreW{array_address: Operand::new_var(result.id), index: zero.clone(), variable: len.clone()},
+            // the character at index i is the character at start + i of the value
+            ZOP::Label{name: "substr_loop".to_string()},
+            ZOP::Inc{variable: index.id},
+            ZOP::JG{operand1: Operand::new_var(index.id), operand2: Operand::new_var(len.id), jump_to_label: "substr_return".to_string()},
+            ZOP::Add{operand1: Operand::new_var(start.id), operand2: Operand::new_var(index.id), save_variable: character.clone()},
+            ZOP::LoadW{array_address: Operand::new_var(value.id), index: character.clone(), variable: character.clone()},
+            ZOP::StoreW{array_address: Operand::new_var(result.id), index: index.clone(), variable: character.clone()},
+            ZOP::Jump{jump_to_label: "substr_loop".to_string()},
+            ZOP::Label{name: "substr_return".to_string()},
+            ZOP::Ret{value: Operand::new_var(result.id)}
+        ]);
+    }
+
     /// Print UTF-16 string at addr.
     ///
     /// Expects an address as argument where the first u16 stored is the length of the string as the
@@ -1318,7 +2931,113 @@ impl Zfile {
     /// Update the cursor position in the global cursor_pos variable.
     pub fn update_cursor_pos(&mut self) {
         let cursor_pos = self.cursor_pos;
-        self.emit(vec![ZOP::GetCursor{store_addr: Operand::new_large_const(cursor_pos as i16)}]);
+        self.emit(vec![ZOP::GetCursor{store_addr: Operand::new_pointer(cursor_pos)}]);
+    }
+
+    /// Routines for `<<at row col>>`: `rt_at` saves the cursor to `cursor_pos` and moves it to the
+    /// row and column, `rt_at_end` moves it back.
+    ///
+    /// Positions outside of the screen size in the header are clamped to the screen. A screen
+    /// size of 0 is treated as unknown.
+    pub fn routine_at(&mut self) {
+        let cursor_pos = Operand::new_pointer(self.cursor_pos);
+        let header = Operand::new_pointer(0);
+        let row = Variable::new(1);
+        let col = Variable::new(2);
+        let size = Variable::new(3);
+        let mut code = vec![
+            ZOP::Routine{name: "rt_at".to_string(), count_variables: 3},
+            ZOP::GetCursor{store_addr: cursor_pos.clone()},
+        ];
+        for &(ref var, size_addr) in [(row.clone(), 0x20), (col.clone(), 0x21)].iter() {
+            let label = format!("rt_at_clamped_{}", var.id);
+            let above_label = format!("rt_at_above_1_{}", var.id);
+            code.extend(vec![
+                ZOP::JG{operand1: Operand::new_var(var.id), operand2: Operand::new_const(0), jump_to_label: above_label.to_string()},
+                ZOP::StoreVariable{variable: var.clone(), value: Operand::new_const(1)},
+                ZOP::Label{name: above_label},
+                ZOP::LoadBOperand{array_address: header.clone(), index: Operand::new_const(size_addr), variable: size.clone()},
+                ZOP::JE{operand1: Operand::new_var(size.id), operand2: Operand::new_const(0), jump_to_label: label.to_string()},
+                ZOP::JG{operand1: Operand::new_var(size.id), operand2: Operand::new_var(var.id), jump_to_label: label.to_string()},
+                ZOP::StoreVariable{variable: var.clone(), value: Operand::new_var(size.id)},
+                ZOP::Label{name: label},
+            ]);
+        }
+        code.extend(vec![
+            ZOP::SetCursorOperand{row: Operand::new_var(row.id), col: Operand::new_var(col.id)},
+            ZOP::Ret{value: Operand::new_const(0)},
+
+            ZOP::Routine{name: "rt_at_end".to_string(), count_variables: 3},
+            ZOP::LoadW{array_address: cursor_pos.clone(), index: size.clone(), variable: row.clone()},
+            ZOP::Inc{variable: size.id},
+            ZOP::LoadW{array_address: cursor_pos.clone(), index: size.clone(), variable: col.clone()},
+            ZOP::SetCursorOperand{row: Operand::new_var(row.id), col: Operand::new_var(col.id)},
+            ZOP::Ret{value: Operand::new_const(0)},
+        ]);
+        self.emit(code);
+    }
+
+    /// Routines for `<<countdown seconds "passage">>`: `rt_countdown` prints the remaining
+    /// seconds and waits a second for a key with a timed `read_char`, until no seconds are left.
+    ///
+    /// The cursor position of the countdown and the remaining seconds are kept at
+    /// `countdown_pos`, every second the number is erased and printed again at that position.
+    /// `rt_countdown_tick` is called when the read times out, it decrements the seconds and ends
+    /// the read. The second argument is 1 if a key cancels the countdown, otherwise a key only
+    /// starts the current second again. Returns 1 if it was cancelled and 0 if the time is up.
+    ///
+    /// Without timed input (bit 7 of Flags 1) it asks for a single key, which cancels the
+    /// countdown if it can be cancelled and ends it otherwise.
+    pub fn routine_countdown(&mut self) {
+        let code = self.countdown_routines();
+        self.emit(code);
+    }
+
+    /// Returns the instructions of `rt_countdown` and `rt_countdown_tick`, which
+    /// `routine_countdown` writes.
+    pub fn countdown_routines(&self) -> Vec<ZOP> {
+        let countdown_pos = Operand::new_pointer(self.countdown_pos);
+        let seconds = Variable::new(1);
+        let cancellable = Variable::new(2);
+        let index = Variable::new(3);
+        let row = Variable::new(4);
+        let col = Variable::new(5);
+        vec![
+            ZOP::Routine{name: "rt_countdown".to_string(), count_variables: 5},
+            ZOP::GetCursor{store_addr: countdown_pos.clone()},
+            ZOP::LoadW{array_address: countdown_pos.clone(), index: index.clone(), variable: row.clone()},
+            ZOP::Inc{variable: index.id},
+            ZOP::LoadW{array_address: countdown_pos.clone(), index: index.clone(), variable: col.clone()},
+            ZOP::Inc{variable: index.id},
+            ZOP::StoreW{array_address: countdown_pos.clone(), index: index.clone(), variable: seconds.clone()},
+            ZOP::Label{name: "rt_countdown_loop".to_string()},
+            ZOP::SetCursorOperand{row: Operand::new_var(row.id), col: Operand::new_var(col.id)},
+            ZOP::EraseLine,
+            ZOP::StoreVariable{variable: index.clone(), value: Operand::new_const(2)},
+            ZOP::LoadW{array_address: countdown_pos.clone(), index: index.clone(), variable: seconds.clone()},
+            ZOP::PrintNumVar{variable: seconds.clone()},
+            ZOP::JG{operand1: Operand::new_var(seconds.id), operand2: Operand::new_const(0), jump_to_label: "rt_countdown_wait".to_string()},
+            ZOP::Ret{value: Operand::new_const(0)},
+            ZOP::Label{name: "rt_countdown_wait".to_string()},
+            ZOP::LoadBOperand{array_address: Operand::new_const(0), index: Operand::new_const(0x01), variable: index.clone()},
+            ZOP::And{operand1: Operand::new_var(index.id), operand2: Operand::new_const(0x80), save_variable: index.clone()},
+            ZOP::JE{operand1: Operand::new_var(index.id), operand2: Operand::new_const(0), jump_to_label: "rt_countdown_untimed".to_string()},
+            ZOP::ReadCharTimer{local_var_id: index.id, timer: 10, routine: "rt_countdown_tick".to_string()},
+            ZOP::JE{operand1: Operand::new_var(index.id), operand2: Operand::new_const(0), jump_to_label: "rt_countdown_loop".to_string()},
+            ZOP::JE{operand1: Operand::new_var(cancellable.id), operand2: Operand::new_const(0), jump_to_label: "rt_countdown_loop".to_string()},
+            ZOP::Ret{value: Operand::new_const(1)},
+            ZOP::Label{name: "rt_countdown_untimed".to_string()},
+            ZOP::Print{text: " (press a key)".to_string()},
+            ZOP::ReadChar{local_var_id: index.id},
+            ZOP::Ret{value: Operand::new_var(cancellable.id)},
+
+            ZOP::Routine{name: "rt_countdown_tick".to_string(), count_variables: 2},
+            ZOP::StoreVariable{variable: Variable::new(1), value: Operand::new_const(2)},
+            ZOP::LoadW{array_address: countdown_pos.clone(), index: Variable::new(1), variable: Variable::new(2)},
+            ZOP::Dec{variable: 2},
+            ZOP::StoreW{array_address: countdown_pos, index: Variable::new(1), variable: Variable::new(2)},
+            ZOP::Ret{value: Operand::new_const(1)},
+        ]
     }
 
     /// Needed to simulate a javascript browser input dialog, receives a prompt message and a
@@ -1391,7 +3110,7 @@ impl Zfile {
             // but rightnow it does not work and behaves strange. this is why we have a Newline here instead
             // ZOP::UpdateCursorPos,
             // read current row
-            // ZOP::LoadW{array_address: Operand::new_large_const(cursor_pos as i16), index: t.clone(), variable: a.clone()},
+            // ZOP::LoadW{array_address: Operand::new_pointer(cursor_pos), index: t.clone(), variable: a.clone()},
             // ZOP::SetCursorOperand{row: a_op.clone(), col: Operand::new_const(1)},
             // ZOP::EraseLine,
             ZOP::EraseWindow{value: -1},
@@ -1406,6 +3125,52 @@ impl Zfile {
         ]);
     }
 
+    /// Asks a question in the style of the link menu, receives the question as string argument.
+    ///
+    /// Returns 1 for the 1-key and 0 for the 2-key, other keys are ignored. If the second
+    /// argument is 1 any key continues. The text style is reset to normal afterwards.
+    pub fn routine_confirm(&mut self) {
+        let question = Operand::new_var(1);
+        let wait_only = Operand::new_var(2);
+        let key = Variable::new(3);
+        let foreground: u8 = if self.bright_mode { 2 } else { 9 };
+        let background: u8 = if self.bright_mode { 9 } else { 2 };
+        let link_color: u8 = if self.bright_mode { 6 } else { 8 };
+        self.emit(vec![
+            ZOP::Routine{name: "rt_confirm".to_string(), count_variables: 3},
+            ZOP::Newline,
+            ZOP::SetTextStyle{bold: false, reverse: false, monospace: true, italic: false},
+            ZOP::Call1N{jump_to_label: "system_print_separator".to_string()},
+            ZOP::Newline,
+            ZOP::PrintUnicodeStr{address: question},
+            ZOP::Newline,
+            ZOP::JE{operand1: wait_only, operand2: Operand::new_const(1), jump_to_label: "rt_confirm_pause".to_string()},
+            ZOP::SetColor{foreground: link_color, background: background},
+            ZOP::Print{text: "Yes[1]".to_string()},
+            ZOP::SetColor{foreground: foreground, background: background},
+            ZOP::Print{text: " ".to_string()},
+            ZOP::SetColor{foreground: link_color, background: background},
+            ZOP::Print{text: "No[2]".to_string()},
+            ZOP::SetColor{foreground: foreground, background: background},
+            ZOP::Newline,
+            ZOP::Label{name: "rt_confirm_loop".to_string()},
+            ZOP::ReadChar{local_var_id: key.id},
+            ZOP::JE{operand1: Operand::new_var(key.id), operand2: Operand::new_const(49), jump_to_label: "rt_confirm_yes".to_string()},
+            ZOP::JE{operand1: Operand::new_var(key.id), operand2: Operand::new_const(50), jump_to_label: "rt_confirm_no".to_string()},
+            ZOP::Jump{jump_to_label: "rt_confirm_loop".to_string()},
+            ZOP::Label{name: "rt_confirm_yes".to_string()},
+            ZOP::SetTextStyle{bold: false, reverse: false, monospace: false, italic: false},
+            ZOP::Ret{value: Operand::new_const(1)},
+            ZOP::Label{name: "rt_confirm_pause".to_string()},
+            ZOP::Print{text: "Press any key to continue".to_string()},
+            ZOP::Newline,
+            ZOP::ReadChar{local_var_id: key.id},
+            ZOP::Label{name: "rt_confirm_no".to_string()},
+            ZOP::SetTextStyle{bold: false, reverse: false, monospace: false, italic: false},
+            ZOP::Ret{value: Operand::new_const(0)},
+        ]);
+    }
+
     /// malloc Z-Routine: Allocate a specified number of words of dynamic memory.
     ///
     /// `argument`: amount of u16 to allocate
@@ -1416,13 +3181,26 @@ impl Zfile {
     /// the 'freed' u16s at the end. increasing it is not allowed.
     /// memory will be freed after each passage if there is no global
     /// variable pointing to it.
+    ///
+    /// The block is zeroed with `copy_table` before it is returned, so a new array starts
+    /// cleared instead of with the -1 of the freed words it reuses.
+    ///
+    /// If no block is large enough, `rt_heap_stats` prints the usage of the heap and `rt_error`
+    /// reports the `oom_message`. With `heap_stats` every allocation is counted in the first word
+    /// of `heap_stats_pos`. With `oom_collect` an allocation ending behind `collect_threshold`
+    /// sets the third word, so that `rt_collect` runs `mem_free` at the end of the statement.
+    /// `malloc` itself never collects, the temporary values of the current expression are only
+    /// kept in local variables, which `mem_free` does not know.
     pub fn routine_malloc(&mut self) {
         let heap_start = self.heap_start;
+        let stats = Operand::new_pointer(self.heap_stats_pos);
+        let message = self.oom_message.clone();
+        let out_of_memory = self.write_string(&message);
         let static_addr = self.static_addr - 2; // we'll write u16 before static_addr where we
                                                 // store the maximum of upper bounds of allocations
                                                 // so that the garbage collector does not need to clean
                                                 // if the memory was untouched
-        self.emit(vec![
+        let mut code = vec![
             ZOP::Routine{name: "malloc".to_string(), count_variables: 15},
             // var1 is the allocation size given in needed amount of u16
             // var4 is the possible memory address
@@ -1432,13 +3210,13 @@ impl Zfile {
             // var6 contains the need_to_clean_up_to entry
             // var7 is used for temporary calculation of the pointer within the possible alloc block
             // init var4 with heap_start
-            ZOP::StoreVariable{variable: Variable::new(4), value: Operand::new_large_const(heap_start as i16)},
+            ZOP::StoreVariable{variable: Variable::new(4), value: Operand::new_pointer(heap_start)},
             // calc var5
-            ZOP::StoreVariable{variable: Variable::new(5), value: Operand::new_large_const(static_addr as i16)},
+            ZOP::StoreVariable{variable: Variable::new(5), value: Operand::new_pointer(static_addr)},
             ZOP::Sub{operand1: Operand::new_var(5), operand2: Operand::new_var(1), save_variable: Variable::new(5)},
             ZOP::Sub{operand1: Operand::new_var(5), operand2: Operand::new_var(1), save_variable: Variable::new(5)},
             // load need_to_clean_up_to
-            ZOP::LoadW{array_address: Operand::new_large_const(static_addr as i16), index: Variable::new(6), variable: Variable::new(6)},
+            ZOP::LoadW{array_address: Operand::new_pointer(static_addr), index: Variable::new(6), variable: Variable::new(6)},
             ZOP::Label{name: "malloc_loop".to_string()},
             // check if we have to give up and quit
             ZOP::JE{operand1: Operand::new_var(4), operand2: Operand::new_var(5), jump_to_label: "malloc_fail".to_string()},
@@ -1476,6 +3254,8 @@ impl Zfile {
             ZOP::Add{operand1: Operand::new_var(4), operand2: Operand::new_var(3), save_variable: Variable::new(4)},
             ZOP::Jump{jump_to_label: "malloc_loop".to_string()},
             ZOP::Label{name: "malloc_return".to_string()},
+            ZOP::Add{operand1: Operand::new_var(1), operand2: Operand::new_var(1), save_variable: Variable::new(7)},
+            ZOP::CopyTable{first: Operand::new_var(4), second: Operand::new_const(0), size: Operand::new_var(7)},
             // save upper bound to the last u16 before (real) static_addr
             // add up allocation address and allocation length*2 (as it is amount of u16)
             ZOP::Add{operand1: Operand::new_var(4), operand2: Operand::new_var(1), save_variable: Variable::new(2)},
@@ -1483,14 +3263,81 @@ impl Zfile {
             // only set need_to_clean_up_to entry if we allocated behind it
             ZOP::JL{operand1: Operand::new_var(2), operand2: Operand::new_var(6), jump_to_label: "malloc_return_not_set_need_to_clean_up".to_string()},
             ZOP::StoreVariable{variable: Variable::new(3), value: Operand::new_const(0)},
-            ZOP::StoreW{array_address: Operand::new_large_const(static_addr as i16), index: Variable::new(3), variable: Variable::new(2)},
+            ZOP::StoreW{array_address: Operand::new_pointer(static_addr), index: Variable::new(3), variable: Variable::new(2)},
             ZOP::Label{name: "malloc_return_not_set_need_to_clean_up".to_string()},
+        ];
+        if self.oom_collect {
+            // the addresses are compared with the sign bit flipped, since they are unsigned
+            let threshold = (self.collect_threshold() as i32 - 0x8000) as i16;
+            code.extend(vec![
+                ZOP::Add{operand1: Operand::new_var(2), operand2: Operand::new_large_const(-0x8000), save_variable: Variable::new(2)},
+                ZOP::JL{operand1: Operand::new_var(2), operand2: Operand::new_large_const(threshold), jump_to_label: "malloc_return_count".to_string()},
+                // asks rt_collect to run mem_free at the end of the statement
+                ZOP::StoreVariable{variable: Variable::new(3), value: Operand::new_const(2)},
+                ZOP::StoreVariable{variable: Variable::new(2), value: Operand::new_const(1)},
+                ZOP::StoreW{array_address: stats.clone(), index: Variable::new(3), variable: Variable::new(2)},
+                ZOP::Label{name: "malloc_return_count".to_string()},
+            ]);
+        }
+        if self.heap_stats {
+            code.extend(vec![
+                // count the allocation in var9, the counter stops at its maximum
+                ZOP::StoreVariable{variable: Variable::new(3), value: Operand::new_const(0)},
+                ZOP::LoadW{array_address: stats.clone(), index: Variable::new(3), variable: Variable::new(9)},
+                ZOP::JE{operand1: Operand::new_var(9), operand2: Operand::new_large_const(0x7fff), jump_to_label: "malloc_return_counted".to_string()},
+                ZOP::Inc{variable: 9},
+                ZOP::StoreW{array_address: stats.clone(), index: Variable::new(3), variable: Variable::new(9)},
+                ZOP::Label{name: "malloc_return_counted".to_string()},
+            ]);
+        }
+        code.extend(vec![
             // return allocation addr
             ZOP::Ret{value: Operand::new_var(4)},
             ZOP::Label{name: "malloc_fail".to_string()},
-            ZOP::Print{text: "MALLOC-FAIL".to_string()},
-            ZOP::Quit,
         ]);
+        // rt_error does not return
+        code.push(ZOP::Call1N{jump_to_label: "rt_heap_stats".to_string()});
+        code.push(ZOP::CallVNA2{jump_to_label: "rt_error".to_string(), arg1: Operand::new_const(1), arg2: Operand::new_string_ref(out_of_memory)});
+        self.emit(code);
+    }
+
+    /// rt_error Z-Routine: Handles a runtime error, it does not return.
+    ///
+    /// Takes the error code and the address of the message. Without an `error_passage` it prints
+    /// the message and quits. Otherwise the code is stored in `$error` (global 20) and the
+    /// message in `$errorMessage` (global 21), the links of the failed passage are dropped and
+    /// the routine throws to the stack frame in global 24, after which `system_start` calls the
+    /// error passage. An error in the error passage itself, before one of its links was chosen,
+    /// quits like the one without it.
+    ///
+    /// The codes are 1 for an exhausted heap, 2 for a passage without links that is not tagged
+    /// `[ending]`, which is only checked with an error passage, 3 for a division by zero and 4
+    /// for an index outside of an array.
+    pub fn routine_error(&mut self) {
+        let mut code = vec![
+            ZOP::Routine{name: "rt_error".to_string(), count_variables: 2},
+            ZOP::StoreVariable{variable: Variable::new(20), value: Operand::new_var(1)},
+            ZOP::SetVarType{variable: Variable::new(20), vartype: Type::Integer},
+            ZOP::StoreVariable{variable: Variable::new(21), value: Operand::new_var(2)},
+            ZOP::SetVarType{variable: Variable::new(21), vartype: Type::String},
+        ];
+        if self.error_passage.is_some() {
+            code.extend(vec![
+                // global 23 is set while the error passage is shown
+                ZOP::JE{operand1: Operand::new_var(23), operand2: Operand::new_const(1), jump_to_label: "rt_error_quit".to_string()},
+                ZOP::StoreVariable{variable: Variable::new(23), value: Operand::new_const(1)},
+                ZOP::StoreVariable{variable: Variable::new(16), value: Operand::new_const(0)},
+                ZOP::StoreVariable{variable: Variable::new(17), value: Operand::new_const(0)},
+                // leaves the routines of the failed passage
+                ZOP::Throw{value: Operand::new_const(0), frame: Operand::new_var(24)},
+                ZOP::Label{name: "rt_error_quit".to_string()},
+            ]);
+        }
+        code.extend(vec![
+            ZOP::Call2NWithArg{jump_to_label: "print_unicode".to_string(), arg: Operand::new_var(2)},
+            ZOP::Quit
+        ]);
+        self.emit(code);
     }
 
     /// strcpy Z-Routine: Copy a string.
@@ -1503,18 +3350,13 @@ impl Zfile {
             ZOP::Routine{name: "strcpy".to_string(), count_variables: 15},
             // var1 has the from_addr where first u16 is the length
             // var2 has the to_addr where we do *not* write the length in the first u16
-            // var4 is the index and equals to number of u16 written
-            // var5 has the character to copy
+            // var4 is 0 to read the length
             // load length to var3
             ZOP::LoadW{array_address: Operand::new_var(1), index: Variable::new(4), variable: Variable::new(3)},
             ZOP::Inc{variable: 1}, ZOP::Inc{variable: 1},  // point to first source byte
-            ZOP::Label{name: "strcpy_loop".to_string()},
-            ZOP::JE{operand1: Operand::new_var(4), operand2: Operand::new_var(3), jump_to_label: "strcpy_return".to_string()},
-            ZOP::LoadW{array_address: Operand::new_var(1), index: Variable::new(4), variable: Variable::new(5)},
-            ZOP::StoreW{array_address: Operand::new_var(2), index: Variable::new(4), variable: Variable::new(5)},
-            ZOP::Inc{variable: 4},  // point to next byte at dest and source
-            ZOP::Jump{jump_to_label: "strcpy_loop".to_string()},
-            ZOP::Label{name: "strcpy_return".to_string()},
+            // copy the characters with 2 bytes each at once
+            ZOP::Add{operand1: Operand::new_var(3), operand2: Operand::new_var(3), save_variable: Variable::new(3)},
+            ZOP::CopyTable{first: Operand::new_var(1), second: Operand::new_var(2), size: Operand::new_var(3)},
             ZOP::Ret{value: Operand::new_const(0)}
         ]);
     }
@@ -1625,24 +3467,28 @@ impl Zfile {
             // var3 stays 0
             // heap_start is in var1 and will be increased during loop
             // var2 stays -1
-            ZOP::StoreVariable{variable: Variable::new(1), value: Operand::new_large_const(heap_start as i16)},
+            ZOP::StoreVariable{variable: Variable::new(1), value: Operand::new_pointer(heap_start)},
             // write heap start as last used addr
-            ZOP::StoreW{array_address: Operand::new_large_const(static_addr as i16), index: Variable::new(3), variable: Variable::new(1)},
+            ZOP::StoreW{array_address: Operand::new_pointer(static_addr), index: Variable::new(3), variable: Variable::new(1)},
             // init with -1 not needed as we use need_to_clean_up_to entry
             //ZOP::StoreVariable{variable: Variable::new(2), value: Operand::new_large_const(-1i16)},
             //ZOP::Label{name: "malloc_init_loop".to_string()},
             //ZOP::StoreW{array_address: Operand::new_var(1), index: Variable::new(3), variable: Variable::new(2)},
             //ZOP::Inc{variable: 1}, ZOP::Inc{variable: 1},
-            //ZOP::JNE{operand1: Operand::new_var(1), operand2: Operand::new_large_const(static_addr as i16), jump_to_label: "malloc_init_loop".to_string()},
+            //ZOP::JNE{operand1: Operand::new_var(1), operand2: Operand::new_pointer(static_addr), jump_to_label: "malloc_init_loop".to_string()},
             ZOP::Ret{value: Operand::new_const(0)}
         ]);
     }
 
     /// mem_free Z-Routine: Free unused dynamic memory.
     ///
-    /// This is implemented as a simple tracing garbage collector.
+    /// This is implemented as a simple tracing garbage collector. With `heap_stats` the freed
+    /// blocks are counted in the second word of `heap_stats_pos`. A block is kept if a global variable points to
+    /// it, if a map in a global variable holds it as a value or if a map in a global variable
+    /// points into it, like a nested map taken out of its parent.
     pub fn routine_mem_free(&mut self) {
         let heap_start = self.heap_start;
+        let stats = Operand::new_pointer(self.heap_stats_pos);
         let static_addr = self.static_addr - 2;  // the last u16 contains the highest addr of allocated space
         let global_addr = self.global_addr;
         let type_store = self.type_store;
@@ -1654,20 +3500,25 @@ impl Zfile {
         let varid = Variable::new(6);
         let varcontent = Variable::new(7);
         let need_to_clean_up_to = Variable::new(8);  // @IMPROVEMENT: consider reducing it again if last element was freed
-        self.emit(vec![
+        let size = Variable::new(9);
+        let one = Variable::new(10);
+        let freed = Variable::new(11);
+        let inner = Variable::new(12);
+        let end = Variable::new(13);
+        let mut code = vec![
             ZOP::Routine{name: "mem_free".to_string(), count_variables: 15},
-            ZOP::LoadW{array_address: Operand::new_large_const(static_addr as i16), index: zero.clone(), variable: need_to_clean_up_to.clone()},
+            ZOP::LoadW{array_address: Operand::new_pointer(static_addr), index: zero.clone(), variable: need_to_clean_up_to.clone()},
             // set m to -1
             ZOP::StoreVariable{variable: m.clone(), value: Operand::new_large_const(-1i16)},
             // set pos to current position
-            ZOP::StoreVariable{variable: pos.clone(), value: Operand::new_large_const(heap_start as i16)},
+            ZOP::StoreVariable{variable: pos.clone(), value: Operand::new_pointer(heap_start)},
             ZOP::Dec{variable: pos.id},
             ZOP::Dec{variable: pos.id},
             ZOP::Label{name: "mem_free_loop".to_string()},
             ZOP::Inc{variable: pos.id},
             ZOP::Inc{variable: pos.id},
             // exit at end of mem
-            ZOP::JE{operand1: Operand::new_var(pos.id), operand2: Operand::new_large_const(static_addr as i16), jump_to_label: "mem_free_exit".to_string()},
+            ZOP::JE{operand1: Operand::new_var(pos.id), operand2: Operand::new_pointer(static_addr), jump_to_label: "mem_free_exit".to_string()},
             // or also exit at end of up-to-now allocated memory
             ZOP::JE{operand1: Operand::new_var(pos.id), operand2: Operand::new_var(need_to_clean_up_to.id), jump_to_label: "mem_free_exit".to_string()},
             // read entry to c
@@ -1681,30 +3532,53 @@ impl Zfile {
             ZOP::StoreVariable{variable: varid.clone(), value: Operand::new_large_const(15i16)},
             ZOP::Label{name: "mem_free_check".to_string()},
             ZOP::Inc{variable: varid.id},
-            ZOP::LoadW{array_address: Operand::new_large_const(global_addr as i16 - 32i16), index: varid.clone(), variable: varcontent.clone()},
+            ZOP::LoadW{array_address: Operand::new_pointer(global_addr - 32), index: varid.clone(), variable: varcontent.clone()},
             // ZOP::PrintNumVar{variable: varid.clone()}, ZOP::Print{text: ":".to_string()},
             // ZOP::PrintNumVar{variable: varcontent.clone()},
             // ZOP::Print{text: " ".to_string()},
             // check if entry at pos is not referenced by a global variable, then we free it, otherwise jump down
             ZOP::JE{operand1: Operand::new_var(pos.id), operand2: Operand::new_var(varcontent.id), jump_to_label: "mem_free_continue".to_string()},
+            // a map keeps the strings and maps of its values as well
+            ZOP::LoadBOperand{array_address: Operand::new_pointer(type_store), index: Operand::new_var(varid.id), variable: t.clone()},
+            ZOP::JNE{operand1: Operand::new_var(t.id), operand2: Operand::new_const(Type::Map as u8), jump_to_label: "mem_free_next".to_string()},
+            // a nested map taken out of its parent points into the block of the parent, the
+            // addresses are compared with the sign bit flipped, since they are unsigned
+            ZOP::Add{operand1: Operand::new_var(varcontent.id), operand2: Operand::new_large_const(-0x8000), save_variable: inner.clone()},
+            ZOP::Add{operand1: Operand::new_var(pos.id), operand2: Operand::new_large_const(-0x8000), save_variable: end.clone()},
+            ZOP::JL{operand1: Operand::new_var(inner.id), operand2: Operand::new_var(end.id), jump_to_label: "mem_free_holds".to_string()},
+            ZOP::Add{operand1: Operand::new_var(end.id), operand2: Operand::new_var(c.id), save_variable: end.clone()},
+            ZOP::Add{operand1: Operand::new_var(end.id), operand2: Operand::new_var(c.id), save_variable: end.clone()},
+            ZOP::JLE{operand1: Operand::new_var(inner.id), operand2: Operand::new_var(end.id), jump_to_label: "mem_free_continue".to_string()},
+            ZOP::Label{name: "mem_free_holds".to_string()},
+            ZOP::CallVSA2{jump_to_label: "map_holds".to_string(), arg1: Operand::new_var(varcontent.id), arg2: Operand::new_var(pos.id), result: t.clone()},
+            ZOP::JE{operand1: Operand::new_var(t.id), operand2: Operand::new_const(1), jump_to_label: "mem_free_continue".to_string()},
+            ZOP::Label{name: "mem_free_next".to_string()},
             ZOP::JL{operand1: Operand::new_var(varid.id), operand2: Operand::new_large_const(255i16), jump_to_label: "mem_free_check".to_string()},
             // finished loop for checking
-            // set t to position after the whole entry so now we skip length*2 (content)
-            ZOP::Add{operand1: Operand::new_var(pos.id), operand2: Operand::new_var(c.id), save_variable: t.clone()},
-            ZOP::Add{operand1: Operand::new_var(t.id), operand2: Operand::new_var(c.id), save_variable: t.clone()},
-            ZOP::Dec{variable: pos.id},
-            ZOP::Dec{variable: pos.id},
             // ZOP::Print{text: "DELETE".to_string()},
-            ZOP::Label{name: "mem_free_delete".to_string()},
-            // continue until pos is at position t
-            ZOP::JE{operand1: Operand::new_var(pos.id), operand2: Operand::new_var(t.id), jump_to_label: "mem_free_loop".to_string()},
-            ZOP::Inc{variable: pos.id},
-            ZOP::Inc{variable: pos.id},
-            // exit at end of mem
-            ZOP::JE{operand1: Operand::new_var(pos.id), operand2: Operand::new_large_const(static_addr as i16), jump_to_label: "mem_free_exit".to_string()},
-            // write -1 to pos
+        ];
+        if self.heap_stats {
+            code.extend(vec![
+                // count the freed block, the counter stops at its maximum
+                ZOP::StoreVariable{variable: one.clone(), value: Operand::new_const(1)},
+                ZOP::LoadW{array_address: stats.clone(), index: one.clone(), variable: freed.clone()},
+                ZOP::JE{operand1: Operand::new_var(freed.id), operand2: Operand::new_large_const(0x7fff), jump_to_label: "mem_free_counted".to_string()},
+                ZOP::Inc{variable: freed.id},
+                ZOP::StoreW{array_address: stats.clone(), index: one.clone(), variable: freed.clone()},
+                ZOP::Label{name: "mem_free_counted".to_string()},
+            ]);
+        }
+        code.extend(vec![
+            // write -1 to the length word, copying it forwards with a negative size writes -1 to the content
             ZOP::StoreW{array_address: Operand::new_var(pos.id), index: zero.clone(), variable: m.clone()},
-            ZOP::Jump{jump_to_label: "mem_free_delete".to_string()},
+            ZOP::Add{operand1: Operand::new_var(pos.id), operand2: Operand::new_large_const(2), save_variable: t.clone()},
+            ZOP::Sub{operand1: Operand::new_var(zero.id), operand2: Operand::new_var(c.id), save_variable: size.clone()},
+            ZOP::Sub{operand1: Operand::new_var(size.id), operand2: Operand::new_var(c.id), save_variable: size.clone()},
+            ZOP::CopyTable{first: Operand::new_var(pos.id), second: Operand::new_var(t.id), size: Operand::new_var(size.id)},
+            // continue behind the freed entry
+            ZOP::Add{operand1: Operand::new_var(pos.id), operand2: Operand::new_var(c.id), save_variable: pos.clone()},
+            ZOP::Add{operand1: Operand::new_var(pos.id), operand2: Operand::new_var(c.id), save_variable: pos.clone()},
+            ZOP::Jump{jump_to_label: "mem_free_loop".to_string()},
             ZOP::Label{name: "mem_free_continue".to_string()},
             // ZOP::Print{text: "IS-USED".to_string()},
             // mem is not free but tells us the length of the entry
@@ -1714,13 +3588,82 @@ impl Zfile {
             ZOP::Jump{jump_to_label: "mem_free_loop".to_string()},
             ZOP::Label{name: "mem_free_exit".to_string()},
             // set type entries variables 0-15 of type_store to 0 for no type
-            ZOP::StoreVariable{variable: pos.clone(), value: Operand::new_large_const(0)},
-            ZOP::Label{name: "mem_free_uninit_local_var_types".to_string()},
-            ZOP::StoreB{array_address: Operand::new_large_const(type_store as i16), index: pos.clone(), variable: zero.clone()},
-            ZOP::Inc{variable: pos.id},
-            ZOP::JL{operand1: Operand::new_var(pos.id), operand2: Operand::new_large_const(16i16), jump_to_label: "mem_free_uninit_local_var_types".to_string()},
+            ZOP::CopyTable{first: Operand::new_pointer(type_store), second: Operand::new_const(0), size: Operand::new_const(16)},
+            ZOP::Ret{value: Operand::new_const(0)}
+        ]);
+        self.emit(code);
+    }
+
+    /// Returns the address behind which an allocation asks `rt_collect` to run `mem_free`,
+    /// three quarters into the heap.
+    pub fn collect_threshold(&self) -> u16 {
+        let heap_start = self.heap_start as u32;
+        (heap_start + (self.static_addr as u32 - 2 - heap_start) / 4 * 3) as u16
+    }
+
+    /// rt_collect Z-Routine: Runs `mem_free` if `malloc` asked for it.
+    ///
+    /// With `oom_collect` codegen calls it behind every assignment, where no temporary value
+    /// is alive, so only values in global variables are left on the heap.
+    pub fn routine_collect(&mut self) {
+        if !self.oom_collect {
+            return;
+        }
+        let stats = Operand::new_pointer(self.heap_stats_pos);
+        let index = Variable::new(1);
+        let requested = Variable::new(2);
+        self.emit(vec![
+            ZOP::Routine{name: "rt_collect".to_string(), count_variables: 2},
+            ZOP::StoreVariable{variable: index.clone(), value: Operand::new_const(2)},
+            ZOP::LoadW{array_address: stats.clone(), index: index.clone(), variable: requested.clone()},
+            ZOP::JE{operand1: Operand::new_var(requested.id), operand2: Operand::new_const(0), jump_to_label: "rt_collect_return".to_string()},
+            ZOP::StoreVariable{variable: requested.clone(), value: Operand::new_const(0)},
+            ZOP::StoreW{array_address: stats, index: index, variable: requested},
+            ZOP::Call1N{jump_to_label: "mem_free".to_string()},
+            ZOP::Label{name: "rt_collect_return".to_string()},
+            ZOP::Ret{value: Operand::new_const(0)}
+        ]);
+    }
+
+    /// rt_heap_stats Z-Routine: Prints the usage of the heap.
+    ///
+    /// The line reads "heap: high water X of Y words, N allocs, M GC frees". The high water mark
+    /// is the end of the highest allocation, which `malloc` keeps in the word in front of static
+    /// memory, the counters are the words at `heap_stats_pos`. They are only kept with
+    /// `heap_stats`, without it the line ends behind the size of the heap.
+    pub fn routine_heap_stats(&mut self) {
+        let heap_start = self.heap_start;
+        let upper_bound = self.static_addr - 2;
+        let stats = Operand::new_pointer(self.heap_stats_pos);
+        let index = Variable::new(1);
+        let value = Variable::new(2);
+        let mut code = vec![
+            ZOP::Routine{name: "rt_heap_stats".to_string(), count_variables: 2},
+            ZOP::Print{text: "heap: high water ".to_string()},
+            ZOP::StoreVariable{variable: index.clone(), value: Operand::new_const(0)},
+            ZOP::LoadW{array_address: Operand::new_pointer(upper_bound), index: index.clone(), variable: value.clone()},
+            ZOP::Sub{operand1: Operand::new_var(value.id), operand2: Operand::new_pointer(heap_start), save_variable: value.clone()},
+            ZOP::Div{operand1: Operand::new_var(value.id), operand2: Operand::new_const(2), save_variable: value.clone()},
+            ZOP::PrintNumVar{variable: value.clone()},
+            ZOP::Print{text: format!(" of {} words", (upper_bound - heap_start) / 2)},
+        ];
+        if self.heap_stats {
+            code.extend(vec![
+                ZOP::Print{text: ", ".to_string()},
+                ZOP::LoadW{array_address: stats.clone(), index: index.clone(), variable: value.clone()},
+                ZOP::PrintNumVar{variable: value.clone()},
+                ZOP::Print{text: " allocs, ".to_string()},
+                ZOP::Inc{variable: index.id},
+                ZOP::LoadW{array_address: stats, index: index.clone(), variable: value.clone()},
+                ZOP::PrintNumVar{variable: value.clone()},
+                ZOP::Print{text: " GC frees".to_string()},
+            ]);
+        }
+        code.extend(vec![
+            ZOP::Newline,
             ZOP::Ret{value: Operand::new_const(0)}
         ]);
+        self.emit(code);
     }
 
     /// manual_free Z-Routine: manual free call to erase used heap memory if you can not wait for
@@ -1728,19 +3671,66 @@ impl Zfile {
     pub fn routine_manual_free(&mut self) {
         let addr_op = Operand::new_var(1);
         let index = Variable::new(2);
-        let index_op = Operand::new_var(index.id);
         let length = Variable::new(3);
         let length_op = Operand::new_var(length.id);
         let del = Variable::new(4);
+        let content = Variable::new(5);
         self.emit(vec![
-            ZOP::Routine{name: "manual_free".to_string(), count_variables: 4},
+            ZOP::Routine{name: "manual_free".to_string(), count_variables: 5},
             ZOP::StoreVariable{variable: del.clone(), value: Operand::new_large_const(-1i16)},
             // load length
             ZOP::LoadW{array_address: addr_op.clone(), index: index.clone(), variable: length.clone()},
-            ZOP::Label{name: "manual_free_loop".to_string()},
             ZOP::StoreW{array_address: addr_op.clone(), index: index.clone(), variable: del.clone()},
-            ZOP::Inc{variable: index.id},
-            ZOP::JLE{operand1: index_op.clone(), operand2: length_op.clone(), jump_to_label: "manual_free_loop".to_string()},
+            // copy the -1 of the length word forwards over the content, so size is -2 * length
+            ZOP::Add{operand1: addr_op.clone(), operand2: Operand::new_large_const(2), save_variable: content.clone()},
+            ZOP::Add{operand1: length_op.clone(), operand2: length_op.clone(), save_variable: length.clone()},
+            ZOP::Sub{operand1: Operand::new_const(0), operand2: length_op.clone(), save_variable: length.clone()},
+            ZOP::CopyTable{first: addr_op.clone(), second: Operand::new_var(content.id), size: length_op.clone()},
+            ZOP::Ret{value: Operand::new_const(0)},
+        ]);
+    }
+
+    /// rt_clear Z-Routine: sets all values of the array at the first argument to 0 for
+    /// `<<clear $arr>>`.
+    ///
+    /// The second argument is the id of the variable with the array, only strings and the
+    /// integers of decks are cleared, and only if they are the address of a block on the heap.
+    /// The length in the first word is kept, a variable without an array is left alone.
+    pub fn routine_clear(&mut self) {
+        let type_store = self.type_store;
+        let heap_start = self.heap_start;
+        let upper_bound = self.static_addr - 2;
+        let array = Variable::new(1);
+        let varid = Variable::new(2);
+        let length = Variable::new(3);
+        let pos = Variable::new(4);
+        let end = Variable::new(5);
+        let zero = Variable::new(6);
+        self.emit(vec![
+            ZOP::Routine{name: "rt_clear".to_string(), count_variables: 6},
+            ZOP::LoadBOperand{array_address: Operand::new_pointer(type_store), index: Operand::new_var(varid.id), variable: length.clone()},
+            ZOP::JE{operand1: Operand::new_var(length.id), operand2: Operand::new_const(Type::String as u8), jump_to_label: "rt_clear_find".to_string()},
+            ZOP::JNE{operand1: Operand::new_var(length.id), operand2: Operand::new_const(Type::Integer as u8), jump_to_label: "rt_clear_return".to_string()},
+            // walks the blocks of the heap like mem_free, up to the highest allocation
+            ZOP::Label{name: "rt_clear_find".to_string()},
+            ZOP::LoadW{array_address: Operand::new_pointer(upper_bound), index: zero.clone(), variable: end.clone()},
+            ZOP::StoreVariable{variable: pos.clone(), value: Operand::new_pointer(heap_start)},
+            ZOP::Label{name: "rt_clear_loop".to_string()},
+            ZOP::JE{operand1: Operand::new_var(pos.id), operand2: Operand::new_var(end.id), jump_to_label: "rt_clear_return".to_string()},
+            ZOP::LoadW{array_address: Operand::new_var(pos.id), index: zero.clone(), variable: length.clone()},
+            ZOP::JE{operand1: Operand::new_var(pos.id), operand2: Operand::new_var(array.id), jump_to_label: "rt_clear_found".to_string()},
+            ZOP::Add{operand1: Operand::new_var(pos.id), operand2: Operand::new_const(2), save_variable: pos.clone()},
+            // a free word is -1, a block is skipped with its content
+            ZOP::JL{operand1: Operand::new_var(length.id), operand2: Operand::new_const(0), jump_to_label: "rt_clear_loop".to_string()},
+            ZOP::Add{operand1: Operand::new_var(pos.id), operand2: Operand::new_var(length.id), save_variable: pos.clone()},
+            ZOP::Add{operand1: Operand::new_var(pos.id), operand2: Operand::new_var(length.id), save_variable: pos.clone()},
+            ZOP::Jump{jump_to_label: "rt_clear_loop".to_string()},
+            ZOP::Label{name: "rt_clear_found".to_string()},
+            ZOP::JL{operand1: Operand::new_var(length.id), operand2: Operand::new_const(0), jump_to_label: "rt_clear_return".to_string()},
+            ZOP::Add{operand1: Operand::new_var(length.id), operand2: Operand::new_var(length.id), save_variable: length.clone()},
+            ZOP::Add{operand1: Operand::new_var(array.id), operand2: Operand::new_const(2), save_variable: array.clone()},
+            ZOP::CopyTable{first: Operand::new_var(array.id), second: Operand::new_const(0), size: Operand::new_var(length.id)},
+            ZOP::Label{name: "rt_clear_return".to_string()},
             ZOP::Ret{value: Operand::new_const(0)},
         ]);
     }
@@ -1802,9 +3792,10 @@ impl Zfile {
         self.emit(vec![
             ZOP::Routine{name: "print_var".to_string(), count_variables: 4},
             // get vartype
-            ZOP::LoadBOperand{array_address: Operand::new_large_const(type_store as i16), index: Operand::new_var(varid.id), variable: vartype.clone()},
+            ZOP::LoadBOperand{array_address: Operand::new_pointer(type_store), index: Operand::new_var(varid.id), variable: vartype.clone()},
             ZOP::JE{operand1: Operand::new_var(vartype.id), operand2: Operand::new_const(Type::String as u8), jump_to_label: "print_var_string".to_string()},
             ZOP::JE{operand1: Operand::new_var(vartype.id), operand2: Operand::new_const(Type::Bool as u8), jump_to_label: "print_var_bool".to_string()},
+            ZOP::JE{operand1: Operand::new_var(vartype.id), operand2: Operand::new_const(Type::Map as u8), jump_to_label: "print_var_map".to_string()},
             // print number
             ZOP::PrintNumVar{variable: varcontent.clone()},
             ZOP::Ret{value: Operand::new_const(0)},
@@ -1819,6 +3810,9 @@ impl Zfile {
             // print var string
             ZOP::PrintUnicodeStr{address: Operand::new_var(varcontent.id)},
             ZOP::Ret{value: Operand::new_const(0)},
+            ZOP::Label{name: "print_var_map".to_string()},
+            ZOP::Call2NWithArg{jump_to_label: "print_map".to_string(), arg: Operand::new_var(varcontent.id)},
+            ZOP::Ret{value: Operand::new_const(0)},
         ]);
     }
 
@@ -1833,7 +3827,7 @@ impl Zfile {
     fn set_var_type(&mut self, variable: &Variable, vartype: &Type) {
         let type_store = self.type_store;
         self.emit(vec![
-            ZOP::StoreBOperand{array_address: Operand::new_large_const(type_store as i16), index: Operand::new_const(variable.id), operand: Operand::new_const(vartype.clone() as u8)},
+            ZOP::StoreBOperand{array_address: Operand::new_pointer(type_store), index: Operand::new_const(variable.id), operand: Operand::new_const(vartype.clone() as u8)},
         ]);
     }
 
@@ -1851,7 +3845,7 @@ impl Zfile {
                 self.emit(vec![
                     ZOP::PushVar{variable: variable.clone()},
                     ZOP::GetVarType{variable: var.clone(), result: variable.clone()},
-                    ZOP::StoreBOperand{array_address: Operand::new_large_const(type_store as i16), index: Operand::new_const(variable.id), operand: Operand::new_var(variable.id)},
+                    ZOP::StoreBOperand{array_address: Operand::new_pointer(type_store), index: Operand::new_const(variable.id), operand: Operand::new_var(variable.id)},
                     ZOP::PullVar{variable: variable.clone()},
                     ]);
                 },
@@ -1865,7 +3859,7 @@ impl Zfile {
     fn get_var_type(&mut self, variable: &Variable, result: &Variable) {
         let type_store = self.type_store;
         self.emit(vec![
-            ZOP::LoadBOperand{array_address: Operand::new_large_const(type_store as i16), index: Operand::new_const(variable.id), variable: result.clone()},
+            ZOP::LoadBOperand{array_address: Operand::new_pointer(type_store), index: Operand::new_const(variable.id), variable: result.clone()},
         ]);
     }
 
@@ -1887,7 +3881,7 @@ impl Zfile {
             ZOP::JE{operand1: Operand::new_var(type2.id), operand2: Operand::new_const(Type::String as u8), jump_to_label: "add_types_resultstring".to_string()},
             ZOP::Add{operand1: Operand::new_var(val1.id), operand2: Operand::new_var(val2.id), save_variable: result.clone()},
             // store type integer for savevarid
-            ZOP::StoreBOperand{array_address: Operand::new_large_const(type_store as i16), index: Operand::new_var(savevarid.id), operand: Operand::new_const(Type::Integer as u8)},
+            ZOP::StoreBOperand{array_address: Operand::new_pointer(type_store), index: Operand::new_var(savevarid.id), operand: Operand::new_const(Type::Integer as u8)},
             ZOP::Ret{value: Operand::new_var(result.id)},
             ZOP::Label{name: "add_types_resultstring".to_string()},
             // if val1 is string jump to val1isstring
@@ -1899,10 +3893,10 @@ impl Zfile {
             ZOP::Label{name: "add_types_val1isbool".to_string()},
             ZOP::JE{operand1: Operand::new_var(val1.id), operand2: Operand::new_const(0), jump_to_label: "add_types_val1isfalse".to_string()},
             // set to "true"
-            ZOP::StoreVariable{variable: val1.clone(), value: Operand::new_large_const(truestr as i16)},
+            ZOP::StoreVariable{variable: val1.clone(), value: Operand::new_pointer(truestr)},
             ZOP::Jump{jump_to_label: "add_types_val1isstring".to_string()},
             ZOP::Label{name: "add_types_val1isfalse".to_string()},
-            ZOP::StoreVariable{variable: val1.clone(), value: Operand::new_large_const(falsestr as i16)},
+            ZOP::StoreVariable{variable: val1.clone(), value: Operand::new_pointer(falsestr)},
             ZOP::Label{name: "add_types_val1isstring".to_string()},
             // if val2 is string jump to val2isstring
             ZOP::JE{operand1: Operand::new_var(type2.id), operand2: Operand::new_const(Type::String as u8), jump_to_label: "add_types_val2isstring".to_string()},
@@ -1913,25 +3907,55 @@ impl Zfile {
             ZOP::Label{name: "add_types_val2isbool".to_string()},
             ZOP::JE{operand1: Operand::new_var(val2.id), operand2: Operand::new_const(0), jump_to_label: "add_types_val2isfalse".to_string()},
             // set to "true"
-            ZOP::StoreVariable{variable: val2.clone(), value: Operand::new_large_const(truestr as i16)},
+            ZOP::StoreVariable{variable: val2.clone(), value: Operand::new_pointer(truestr)},
             ZOP::Jump{jump_to_label: "add_types_val2isstring".to_string()},
             ZOP::Label{name: "add_types_val2isfalse".to_string()},
-            ZOP::StoreVariable{variable: val2.clone(), value: Operand::new_large_const(falsestr as i16)},
+            ZOP::StoreVariable{variable: val2.clone(), value: Operand::new_pointer(falsestr)},
             ZOP::Label{name: "add_types_val2isstring".to_string()},
             // add strings
             ZOP::CallVSA2{jump_to_label: "strcat".to_string(), arg1: Operand::new_var(val1.id), arg2: Operand::new_var(val2.id), result: result.clone()},
             // store type string for savevarid
-            ZOP::StoreBOperand{array_address: Operand::new_large_const(type_store as i16), index: Operand::new_var(savevarid.id), operand: Operand::new_const(Type::String as u8)},
+            ZOP::StoreBOperand{array_address: Operand::new_pointer(type_store), index: Operand::new_var(savevarid.id), operand: Operand::new_const(Type::String as u8)},
             ZOP::Ret{value: Operand::new_var(result.id)},
         ]);
     }
 
+    /// Keeps the lines of a split in `split_window_pos`, where `restore_split_window` reads them.
+    fn remember_split_window(&mut self, lines: u8) {
+        self.upper_window_lines = lines;
+        op::op_storeboperand(&Operand::new_pointer(self.split_window_pos), &Operand::new_const(0), &Operand::new_const(lines), &mut self.data.bytes);
+    }
+
+    /// Splits the screen again after it was unsplit by erasing window -1.
+    ///
+    /// Interpreters reset the upper window to zero lines in this case, which would hide the
+    /// content written to it afterwards. The lines are those of the split that ran last, which
+    /// may be emitted anywhere in the file, so they are read from `split_window_pos` at runtime.
+    /// Without a split they are zero and the screen stays unsplit.
+    fn restore_split_window(&mut self, value: i8) {
+        if value == -1 {
+            // the lines go through the stack
+            op::op_loadb(&Operand::new_pointer(self.split_window_pos), &Operand::new_const(0), &Variable::new(0), &mut self.data.bytes);
+            op::op_split_window_operand(&Operand::new_var(0), &mut self.data.bytes);
+        }
+    }
+
     /// Helper function to add two values according to their types.
+    ///
+    /// The types of variable operands are loaded into `tmp1` and `tmp2` before the call, so the
+    /// temporaries must not alias the operands (see `add_types_aliasing`).
     fn add_types(&mut self, operand1: &Operand, operand2: &Operand, tmp1: &Variable, tmp2: &Variable, save_variable: &Variable) {
+        if cfg!(debug_assertions) {
+            if let Some(problem) = add_types_aliasing(operand1, operand2, tmp1, tmp2, save_variable) {
+                panic!("Invalid AddTypes: {}", problem);
+            }
+        }
+
         let type1op = match operand1 {
             &Operand::StringRef(_) => Operand::new_const(Type::String as u8),
             &Operand::BoolConst(_) => Operand::new_const(Type::Bool as u8),
             &Operand::LargeConst(_) => Operand::new_const(Type::Integer as u8),
+            &Operand::Pointer(_) => Operand::new_const(Type::Integer as u8),
             &Operand::Const(_) => Operand::new_const(Type::Integer as u8),
             &Operand::Var(ref var) => {
                     self.emit(vec![ZOP::GetVarType{variable: var.clone(), result: tmp1.clone()}]);
@@ -1942,6 +3966,7 @@ impl Zfile {
             &Operand::StringRef(_) => Operand::new_const(Type::String as u8),
             &Operand::BoolConst(_) => Operand::new_const(Type::Bool as u8),
             &Operand::LargeConst(_) => Operand::new_const(Type::Integer as u8),
+            &Operand::Pointer(_) => Operand::new_const(Type::Integer as u8),
             &Operand::Const(_) => Operand::new_const(Type::Integer as u8),
             &Operand::Var(ref var) => {
                     self.emit(vec![ZOP::GetVarType{variable: var.clone(), result: tmp2.clone()}]);
@@ -1990,6 +4015,13 @@ impl Zfile {
         self.add_jump(jump_to_label.to_string(), JumpType::Jump);
     }
 
+    /// Branches to a label if the interpreter considers the game genuine.
+    ///
+    /// piracy is 0OP, followed only by the branch.
+    pub fn op_piracy(&mut self, jump_to_label: &str) {
+        self.op_0(0x0f);
+        self.add_jump(jump_to_label.to_string(), JumpType::Branch);
+    }
 
 
     /// Calls a routine.
@@ -2007,8 +4039,8 @@ impl Zfile {
     ///
     /// call_2n is 2OP.
     pub fn op_call_2n_with_address(&mut self, jump_to_label: &str, address: &str) {
-        let args: Vec<ArgType> = vec![ArgType::LargeConst, ArgType::LargeConst];
-        self.op_2(0x1a, args);
+        let args = [ArgType::LargeConst, ArgType::LargeConst];
+        self.op_2(0x1a, &args);
 
         // the address of the jump_to_label
         self.add_jump(jump_to_label.to_string(), JumpType::Routine);
@@ -2021,8 +4053,8 @@ impl Zfile {
     ///
     /// call_2n is 2OP.
     pub fn op_call_2n_with_arg(&mut self, jump_to_label: &str, arg: &Operand) {
-        let args: Vec<ArgType> = vec![ArgType::LargeConst, op::arg_type(&arg)];
-        self.op_2(0x1a, args);
+        let args = [ArgType::LargeConst, op::arg_type(&arg)];
+        self.op_2(0x1a, &args);
 
         // the address of the jump_to_label
         self.add_jump(jump_to_label.to_string(), JumpType::Routine);
@@ -2034,8 +4066,8 @@ impl Zfile {
     ///
     /// call_2s is 2OP.
     pub fn op_call_2s(&mut self, jump_to_label: &str, arg: &Operand, result: &Variable) {
-        let args: Vec<ArgType> = vec![ArgType::LargeConst, op::arg_type(&arg), ArgType::Variable];
-        self.op_2(0x19, args);
+        let args = [ArgType::LargeConst, op::arg_type(&arg), ArgType::Variable];
+        self.op_2(0x19, &args);
 
         // the address of the jump_to_label
         self.add_jump(jump_to_label.to_string(), JumpType::Routine);
@@ -2048,8 +4080,8 @@ impl Zfile {
     ///
     /// call_vn is VAROP.
     pub fn op_call_vn_a2(&mut self, jump_to_label: &str, arg1: &Operand, arg2: &Operand) {
-        let args: Vec<ArgType> = vec![ArgType::LargeConst, op::arg_type(&arg1), op::arg_type(&arg2), ArgType::Nothing];
-        self.op_var(0x19, args);
+        let args = [ArgType::LargeConst, op::arg_type(&arg1), op::arg_type(&arg2), ArgType::Nothing];
+        self.op_var(0x19, &args);
 
         // the address of the jump_to_label
         self.add_jump(jump_to_label.to_string(), JumpType::Routine);
@@ -2062,8 +4094,8 @@ impl Zfile {
     ///
     /// call_vn is VAROP.
     pub fn op_call_vn_a3(&mut self, jump_to_label: &str, arg1: &Operand, arg2: &Operand, arg3: &Operand) {
-        let args: Vec<ArgType> = vec![ArgType::LargeConst, op::arg_type(&arg1), op::arg_type(&arg2), op::arg_type(&arg3)];
-        self.op_var(0x19, args);
+        let args = [ArgType::LargeConst, op::arg_type(&arg1), op::arg_type(&arg2), op::arg_type(&arg3)];
+        self.op_var(0x19, &args);
 
         // the address of the jump_to_label
         self.add_jump(jump_to_label.to_string(), JumpType::Routine);
@@ -2077,8 +4109,8 @@ impl Zfile {
     ///
     /// call_vs is VAROP.
     pub fn op_call_vs_a2(&mut self, jump_to_label: &str, arg1: &Operand, arg2: &Operand, result: &Variable) {
-        let args: Vec<ArgType> = vec![ArgType::LargeConst, op::arg_type(&arg1), op::arg_type(&arg2), ArgType::Nothing];
-        self.op_var(0x0, args);
+        let args = [ArgType::LargeConst, op::arg_type(&arg1), op::arg_type(&arg2), ArgType::Nothing];
+        self.op_var(0x0, &args);
 
         // the address of the jump_to_label
         self.add_jump(jump_to_label.to_string(), JumpType::Routine);
@@ -2092,8 +4124,8 @@ impl Zfile {
     ///
     /// call_vs is VAROP.
     pub fn op_call_vs_a3(&mut self, jump_to_label: &str, arg1: &Operand, arg2: &Operand, arg3: &Operand, result: &Variable) {
-        let args: Vec<ArgType> = vec![ArgType::LargeConst, op::arg_type(&arg1), op::arg_type(&arg2), op::arg_type(&arg3)];
-        self.op_var(0x0, args);
+        let args = [ArgType::LargeConst, op::arg_type(&arg1), op::arg_type(&arg2), op::arg_type(&arg3)];
+        self.op_var(0x0, &args);
 
         // the address of the jump_to_label
         self.add_jump(jump_to_label.to_string(), JumpType::Routine);
@@ -2108,10 +4140,10 @@ impl Zfile {
     ///
     /// call_vs2 is VAROP with additional types-byte.
     pub fn op_call_vs2_a5(&mut self, jump_to_label: &str, arg1: &Operand, arg2: &Operand, arg3: &Operand, arg4: &Operand, arg5: &Operand, result: &Variable) {
-        let args1: Vec<ArgType> = vec![ArgType::LargeConst, op::arg_type(&arg1), op::arg_type(&arg2), op::arg_type(&arg3)];
-        let args2: Vec<ArgType> = vec![op::arg_type(&arg4), op::arg_type(&arg5), ArgType::Nothing, ArgType::Nothing];
-        self.op_var(0xC, args1);
-        self.data.append_byte(op::encode_variable_arguments(args2));
+        let args1 = [ArgType::LargeConst, op::arg_type(&arg1), op::arg_type(&arg2), op::arg_type(&arg3)];
+        let args2 = [op::arg_type(&arg4), op::arg_type(&arg5), ArgType::Nothing, ArgType::Nothing];
+        self.op_var(0xC, &args1);
+        self.data.append_byte(op::encode_variable_arguments(&args2));
         // the address of the jump_to_label
         self.add_jump(jump_to_label.to_string(), JumpType::Routine);
 
@@ -2126,8 +4158,8 @@ impl Zfile {
     /// Jumps to a label if the value of operand1 is equal to operand2.
     pub fn op_je(&mut self, operand1: &Operand, operand2: &Operand, jump_to_label: &str) {
 
-        let args: Vec<ArgType> = vec![op::arg_type(operand1), op::arg_type(operand2)];
-        self.op_2(0x01, args);
+        let args = [op::arg_type(operand1), op::arg_type(operand2)];
+        self.op_2(0x01, &args);
 
         op::write_argument(operand1, &mut self.data.bytes);
         op::write_argument(operand2, &mut self.data.bytes);
@@ -2147,8 +4179,8 @@ impl Zfile {
     /// Jumps to a label if the value of operand1 is lower than operand2 (compared as i16).
     pub fn op_jl(&mut self, operand1: &Operand, operand2: &Operand, jump_to_label: &str) {
 
-        let args: Vec<ArgType> = vec![op::arg_type(operand1), op::arg_type(operand2)];
-        self.op_2(0x02, args);
+        let args = [op::arg_type(operand1), op::arg_type(operand2)];
+        self.op_2(0x02, &args);
 
         op::write_argument(operand1, &mut self.data.bytes);
         op::write_argument(operand2, &mut self.data.bytes);
@@ -2176,8 +4208,8 @@ impl Zfile {
     /// Jumps to a label if the value of operand1 is greater than operand2.
     pub fn op_jg(&mut self, operand1: &Operand, operand2: &Operand, jump_to_label: &str) {
 
-        let args: Vec<ArgType> = vec![op::arg_type(operand1), op::arg_type(operand2)];
-        self.op_2(0x03, args);
+        let args = [op::arg_type(operand1), op::arg_type(operand2)];
+        self.op_2(0x03, &args);
 
         op::write_argument(operand1, &mut self.data.bytes);
         op::write_argument(operand2, &mut self.data.bytes);
@@ -2190,8 +4222,8 @@ impl Zfile {
     ///
     /// read_char is VAROP.
     pub fn op_read_char_timer(&mut self, local_var_id: u8, timer: u8, routine: &str) {
-        let args: Vec<ArgType> = vec![ArgType::SmallConst, ArgType::SmallConst, ArgType::LargeConst, ArgType::Nothing];
-        self.op_var(0x16, args);
+        let args = [ArgType::SmallConst, ArgType::SmallConst, ArgType::LargeConst, ArgType::Nothing];
+        self.op_var(0x16, &args);
 
         // write argument value
         self.data.append_byte(0x01);
@@ -2236,8 +4268,8 @@ impl Zfile {
 
     /// Prints a ZSCII character.
     pub fn op_print_char(&mut self, variable: &Variable) {
-        let args: Vec<ArgType> = vec![ArgType::Variable, ArgType::Nothing, ArgType::Nothing, ArgType::Nothing];
-        self.op_var(0x5, args);
+        let args = [ArgType::Variable, ArgType::Nothing, ArgType::Nothing, ArgType::Nothing];
+        self.op_var(0x5, &args);
         self.data.append_byte(variable.id);
     }
 
@@ -2246,22 +4278,22 @@ impl Zfile {
 
     /// Binary representation for op-codes with 0 operators.
     fn op_0(&mut self, value: u8) {
-        self.data.append_bytes(&op::op_0(value));
+        op::op_0(value, &mut self.data.bytes);
     }
 
     /// Binary representation for op-codes with 1 operator.
     fn op_1(&mut self, value: u8, arg_type: ArgType) {
-        self.data.append_bytes(&op::op_1(value, arg_type));
+        op::op_1(value, arg_type, &mut self.data.bytes);
     }
 
     /// Binary representation for op-codes with 1 operator.
-    fn op_2(&mut self, value: u8, arg_types: Vec<ArgType>) {
-        self.data.append_bytes(&op::op_2(value, arg_types));
+    fn op_2(&mut self, value: u8, arg_types: &[ArgType]) {
+        op::op_2(value, arg_types, &mut self.data.bytes);
     }
 
     /// Binary representation for a variable.
-    fn op_var(&mut self, value: u8, arg_types: Vec<ArgType>) {
-        self.data.append_bytes(&op::op_var(value, arg_types));
+    fn op_var(&mut self, value: u8, arg_types: &[ArgType]) {
+        op::op_var(value, arg_types, &mut self.data.bytes);
     }
 }
 
@@ -2270,6 +4302,30 @@ fn align_address(address: u32, align: u32) -> u32 {
     address + (align - (address % align)) % align
 }
 
+/// Packs a byte address like `packed_address`, but returns `None` if the packed address does not
+/// fit into 16 bits.
+fn checked_packed_address(version: u8, offset: u16, address: u32) -> Option<u16> {
+    let packed = if version == 7 {
+        (address - 8 * offset as u32) / 4
+    } else {
+        address / 8
+    };
+    if packed > 0xffff {
+        None
+    } else {
+        Some(packed as u16)
+    }
+}
+
+/// Packs a byte address for the specified version, `offset` is only used by version 7.
+fn packed_address(version: u8, offset: u16, address: u32) -> u16 {
+    if version == 7 {
+        ((address - 8 * offset as u32) / 4) as u16
+    } else {
+        (address / 8) as u16
+    }
+}
+
 /// Returns the routine address, should be `adress % 8 == 0` (because its a packed address).
 fn routine_address(address: u32) -> u32 {
     return align_address(address, 8);
@@ -2278,10 +4334,71 @@ fn routine_address(address: u32) -> u32 {
 // ================================
 // Test functions
 
+/// Checks the variables of an `AddTypes` instruction for aliasing.
+///
+/// `tmp1`, `tmp2` and `save_variable` have to be distinct and the temporaries must not be one of
+/// the variable operands, otherwise loading a type overwrites a value that is still needed.
+/// `save_variable` may be one of the operands, it is only written after the call.
+fn add_types_aliasing(operand1: &Operand, operand2: &Operand, tmp1: &Variable, tmp2: &Variable, save_variable: &Variable) -> Option<String> {
+    if tmp1.id == tmp2.id {
+        return Some(format!("tmp1 and tmp2 are both variable {}", tmp1.id));
+    }
+    for tmp in &[tmp1, tmp2] {
+        if tmp.id == save_variable.id {
+            return Some(format!("the result variable {} is also a temporary", tmp.id));
+        }
+        for operand in &[operand1, operand2] {
+            if let &&Operand::Var(ref var) = operand {
+                if var.id == tmp.id {
+                    return Some(format!("the temporary {} is also an operand", tmp.id));
+                }
+            }
+        }
+    }
+    None
+}
+
+/// Checks the operands of an instruction against what its opcode can encode.
+///
+/// The op-code functions truncate values that are out of range without any warning, so debug
+/// builds check every instruction written by `write_zop`. `locals` is the number of local
+/// variables of the current routine, the variables 1 to 15 above it don't exist.
+fn operand_problem(instr: &ZOP, locals: u8) -> Option<String> {
+    if let &ZOP::Routine{..} = instr {
+        return None;
+    }
+    if let Some(id) = mentioned_variables(instr).into_iter().find(|&id| id >= 1 && id <= 15 && id > locals) {
+        return Some(format!("the local variable {} does not exist, the routine has {} local variable(s)", id, locals));
+    }
+
+    match instr {
+        // 13 to 255 are reserved, 0 and 1 keep and reset the colour
+        &ZOP::SetColor{foreground, background} if foreground > 12 || background > 12 =>
+            Some(format!("the colours {} and {} are not between 0 and 12", foreground, background)),
+        &ZOP::SetWindow{window} if window > 1 =>
+            Some(format!("there is no window {}, only the lower window 0 and the upper window 1", window)),
+        &ZOP::EraseWindow{value} if value < -2 || value > 1 =>
+            Some(format!("{} is neither a window nor -1 or -2", value)),
+        &ZOP::SetCursor{line, col} if line == 0 || col == 0 =>
+            Some(format!("the cursor position {}:{} does not start at 1:1", line, col)),
+        &ZOP::StoreBOperand{operand: Operand::LargeConst(ref constant), ..} if constant.value < 0 || constant.value > 255 =>
+            Some(format!("{} does not fit into the stored byte", constant.value)),
+        _ => None
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use super::{routine_address, align_address};
+    use super::{routine_address, align_address, packed_address, add_types_aliasing, operand_problem};
     use super::*;
+    use backend::zcode::capabilities::Capability;
+
+    /// Returns the bytes an opcode of the `op` module appends.
+    fn encode<F: FnOnce(&mut Vec<u8>)>(write: F) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        write(&mut bytes);
+        bytes
+    }
 
     #[test]
     fn test_align_address() {
@@ -2295,6 +4412,64 @@ mod tests {
         assert_eq!(align_address(0x21, 32), 0x40);
     }
 
+    #[test]
+    fn test_reserve() {
+        let mut zfile = Zfile::new();
+        let size = zfile.estimated_size(0x4000);
+        zfile.reserve(size);
+        assert!(zfile.capacity() >= size);
+        assert_eq!(zfile.bytes_len(), 0);
+
+        // start fills the memory in front of the program without reallocating
+        let capacity = zfile.capacity();
+        zfile.start();
+        assert_eq!(zfile.capacity(), capacity);
+        assert!(zfile.bytes_len() >= zfile.program_addr as usize);
+    }
+
+    /// Writes the memory in front of the program like `start`, then a routine with `prints`
+    /// texts, and returns how often the file was reallocated.
+    fn count_reallocations(reserve: Option<usize>, prints: usize) -> usize {
+        let mut zfile = Zfile::new();
+        if let Some(source_size) = reserve {
+            let size = zfile.estimated_size(source_size);
+            zfile.reserve(size);
+        }
+        let mut capacity = zfile.capacity();
+        let mut reallocations = 0;
+        {
+            let mut count = |zfile: &Zfile| if zfile.capacity() != capacity {
+                capacity = zfile.capacity();
+                reallocations += 1;
+            };
+
+            zfile.create_header();
+            count(&zfile);
+            while zfile.bytes_len() < zfile.program_addr as usize {
+                let next = zfile.bytes_len() + 1;
+                zfile.data.write_zero_until(next);
+                count(&zfile);
+            }
+            zfile.emit(vec![ZOP::Routine{name: "Start".to_string(), count_variables: 0}]);
+            for _ in 0..prints {
+                zfile.emit(vec![ZOP::Print{text: "Hello".to_string()}]);
+                count(&zfile);
+            }
+        }
+        reallocations
+    }
+
+    #[test]
+    fn test_reserve_reallocations() {
+        // the vector doubles its capacity from a few bytes to the 64 kB in front of the program
+        let unreserved = count_reallocations(None, 2000);
+        assert!(unreserved >= 13, "reallocated only {} times", unreserved);
+
+        // 2000 prints of five letters need less than one byte for each of 20000 bytes of source
+        assert_eq!(count_reallocations(Some(20000), 2000), 0);
+        assert!(count_reallocations(Some(0), 2000) > 0);
+    }
+
     #[test]
     fn test_routine_address() {
         assert_eq!(routine_address(8), 8);
@@ -2304,6 +4479,468 @@ mod tests {
         assert_eq!(routine_address(17), 24);
     }
 
+    #[test]
+    fn test_checked_packed_address() {
+        assert_eq!(checked_packed_address(8, 0x1fff, 0x7fff8), Some(0xffff));
+        assert_eq!(checked_packed_address(8, 0x1fff, 0x80000), None);
+        assert_eq!(checked_packed_address(7, 0x1fff, 0x4fff4), Some(0xffff));
+        assert_eq!(checked_packed_address(7, 0x1fff, 0x4fff8), None);
+    }
+
+    #[test]
+    fn test_long_string_fits() {
+        let mut zfile: Zfile = Zfile::new();
+        zfile.start();
+        let text: String = ::std::iter::repeat("a long paragraph ").take(4000).collect();
+        zfile.emit(vec![ZOP::PrintOps{text: text.to_string()}, ZOP::PrintOps{text: "after it".to_string()}]);
+        zfile.end();
+
+        assert!(zfile.decoded_strings().contains(&text));
+    }
+
+    #[test]
+    #[should_panic(expected="beyond the packed addresses of Z-Machine version 7")]
+    fn test_string_beyond_packed_addresses() {
+        let mut zfile: Zfile = Zfile::new();
+        zfile.version = 7;
+        zfile.start();
+        // about 330kB of zstrings, the string behind it starts too far up for version 7
+        let text: String = ::std::iter::repeat("a").take(500000).collect();
+        zfile.emit(vec![ZOP::PrintOps{text: text}, ZOP::PrintOps{text: "the next string".to_string()}]);
+        zfile.end();
+    }
+
+    #[test]
+    #[should_panic(expected="the story is too large to append its source with --embed-source")]
+    fn test_source_beyond_file_length() {
+        let mut zfile: Zfile = Zfile::new();
+        zfile.start();
+        // the file length of the header counts 8 bytes in version 8, so it ends at 0x7fff8
+        zfile.data.write_zero_until(0x80000);
+        zfile.append_source(b"::Start\nHello");
+    }
+
+    #[test]
+    fn test_zfile_header_flags() {
+        let flags = |colours: bool, transcript: bool, fixed_pitch: bool, undo: bool, sound: bool| {
+            let flags = HeaderFlags{colours: colours, transcript: transcript, fixed_pitch: fixed_pitch, undo: undo, sound: sound};
+            (flags.flags1(), flags.flags2())
+        };
+        assert_eq!(flags(true, false, false, false, false), (0x1d, 0x40));
+        assert_eq!(flags(false, false, false, false, false), (0x1c, 0x00));
+        assert_eq!(flags(false, true, false, true, false), (0x1c, 0x11));
+        assert_eq!(flags(false, false, false, false, true), (0x1c, 0x80));
+        assert_eq!(flags(true, true, true, true, true), (0x1d, 0xd3));
+
+        // the links set colours, unless they are disabled
+        for &(no_colours, flags1, flags2) in [(false, 0x1d, 0x40), (true, 0x1c, 0x00)].iter() {
+            let mut zfile: Zfile = Zfile::new();
+            zfile.no_colours = no_colours;
+            zfile.start();
+            zfile.end();
+            assert_eq!(zfile.data.bytes[0x01], flags1);
+            assert_eq!((zfile.data.bytes[0x10], zfile.data.bytes[0x11]), (0x00, flags2));
+        }
+    }
+
+    /// Writes a passage `Start` with the instructions of `start` behind the entry code, and the
+    /// runtime routines `called`, which Start calls, and `unused`.
+    fn capabilities_with(no_colours: bool, start: Vec<ZOP>, called: Vec<ZOP>, unused: Vec<ZOP>) -> Zfile {
+        let mut zfile: Zfile = Zfile::new();
+        zfile.no_colours = no_colours;
+        zfile.start();
+        zfile.origin = Origin::Passage("Start".to_string());
+        zfile.emit(vec![ZOP::Routine{name: "Start".to_string(), count_variables: 2}]);
+        zfile.emit(start);
+        zfile.emit(vec![ZOP::Call1N{jump_to_label: "rt_called".to_string()}, ZOP::Ret{value: Operand::new_const(0)}]);
+        zfile.origin = Origin::Runtime;
+        zfile.emit(vec![ZOP::Routine{name: "rt_called".to_string(), count_variables: 2}]);
+        zfile.emit(called);
+        zfile.emit(vec![ZOP::Ret{value: Operand::new_const(0)}, ZOP::Routine{name: "rt_unused".to_string(), count_variables: 2}]);
+        zfile.emit(unused);
+        zfile.emit(vec![ZOP::Ret{value: Operand::new_const(0)}]);
+        zfile
+    }
+
+    #[test]
+    fn test_zfile_capabilities() {
+        let report = |zfile: &Zfile| {
+            let report = zfile.capability_report();
+            (report.required, report.optional)
+        };
+        let var = Variable::new(1);
+
+        // the entry code sets the default colours
+        let zfile = capabilities_with(false, vec![], vec![], vec![]);
+        assert_eq!(report(&zfile), (vec![Capability::Colours], vec![]));
+
+        // timed input is optional, the routines that are never called do not count
+        let zfile = capabilities_with(true,
+            vec![ZOP::PrintUnicode{c: 0x263a}],
+            vec![ZOP::ReadCharTimer{local_var_id: 1, timer: 10, routine: "rt_called".to_string()}],
+            vec![ZOP::Save{result: var.clone()}]);
+        assert_eq!(report(&zfile), (vec![Capability::Unicode], vec![Capability::TimedInput]));
+        assert!(zfile.capabilities.contains(Capability::SaveRestore));
+
+        // called through the timer routine, the save counts
+        let zfile = capabilities_with(true,
+            vec![ZOP::SplitWindow{lines: 1}, ZOP::SetWindow{window: 0}],
+            vec![ZOP::ReadCharTimer{local_var_id: 1, timer: 10, routine: "rt_unused".to_string()}],
+            vec![ZOP::Restore{result: var.clone()}]);
+        assert_eq!(report(&zfile), (vec![Capability::Windows, Capability::SaveRestore], vec![Capability::TimedInput]));
+
+        // without colours and unicode the opcodes are not written and do not count either
+        let mut zfile = Zfile::new();
+        zfile.no_colours = true;
+        zfile.no_unicode = true;
+        zfile.start();
+        zfile.origin = Origin::Passage("Start".to_string());
+        zfile.emit(vec![
+            ZOP::Routine{name: "Start".to_string(), count_variables: 2},
+            ZOP::SetColor{foreground: 3, background: 2},
+            ZOP::PrintUnicodeVar{var: var.clone()},
+            ZOP::Ret{value: Operand::new_const(0)},
+            ZOP::Routine{name: "print_char".to_string(), count_variables: 1},
+            ZOP::Ret{value: Operand::new_const(0)},
+        ]);
+        assert_eq!(report(&zfile), (vec![], vec![]));
+        assert!(!zfile.header_flags().colours);
+
+        // sound effects and true colours are tracked as well
+        let zfile = capabilities_with(false,
+            vec![ZOP::SoundEffect{number: Operand::new_const(1)}],
+            vec![ZOP::SetTrueColor{foreground: Operand::new_large_const(0x001f), background: Operand::new_large_const(-2)}],
+            vec![]);
+        assert_eq!(report(&zfile), (vec![Capability::Colours, Capability::TrueColour, Capability::Sound], vec![]));
+    }
+
+    #[test]
+    fn test_zfile_header_capabilities() {
+        // the header only asks for what reachable code uses, here only the unused routine plays sounds
+        let mut zfile = capabilities_with(false, vec![],
+            vec![ZOP::SetTrueColor{foreground: Operand::new_large_const(0x001f), background: Operand::new_large_const(-2)}],
+            vec![ZOP::SoundEffect{number: Operand::new_const(2)}]);
+        zfile.end();
+        assert!(zfile.capabilities.contains(Capability::Sound));
+        assert_eq!((zfile.data.bytes[0x01], zfile.data.bytes[0x11]), (0x1d, 0x40));
+        assert!(zfile.header_mismatches().is_empty());
+
+        let mut zfile = capabilities_with(true, vec![ZOP::SoundEffect{number: Operand::new_const(1)}], vec![], vec![]);
+        zfile.end();
+        assert_eq!((zfile.data.bytes[0x01], zfile.data.bytes[0x11]), (0x1c, 0x80));
+        assert!(zfile.header_mismatches().is_empty());
+
+        // the check reads the written header, not the flags it was computed from
+        zfile.data.bytes[0x11] = 0x40;
+        zfile.data.bytes[0x01] = 0x1d;
+        assert_eq!(zfile.header_mismatches(), vec![
+            "The header asks the interpreter for colours, but the story never sets them".to_string(),
+            "The story plays sound effects, but the header does not ask the interpreter for them".to_string()]);
+    }
+
+    #[test]
+    fn test_packed_address() {
+        assert_eq!(packed_address(8, 0x1fff, 0x10000), 0x2000);
+        assert_eq!(packed_address(7, 0x1fff, 0xfff8), 0);
+        assert_eq!(packed_address(7, 0x1fff, 0x10000), 2);
+        assert_eq!(packed_address(7, 0x1fff, 0x4fff4), 0xffff);
+    }
+
+    #[test]
+    fn test_zfile_version_7_routine_address() {
+        let mut zfile: Zfile = Zfile::new();
+        zfile.version = 7;
+        zfile.start();
+        let (_, jumps, _) = zfile.write_zop(&ZOP::Call1N{jump_to_label: "Start".to_string()}, true);
+        let (labels, _, _) = zfile.write_zop(&ZOP::Routine{name: "Start".to_string(), count_variables: 0}, true);
+        zfile.emit(vec![ZOP::Ret{value: Operand::new_const(0)}]);
+        zfile.end();
+
+        assert_eq!(zfile.data.bytes[0], 7);
+        let offset = (zfile.data.bytes[0x28] as u32) << 8 | zfile.data.bytes[0x29] as u32;
+        assert_eq!(offset, zfile.packed_offset() as u32);
+        assert_eq!(zfile.data.bytes[0x28..0x2a].to_vec(), zfile.data.bytes[0x2a..0x2c].to_vec());
+
+        let from = jumps[0].from_addr as usize;
+        let packed = (zfile.data.bytes[from] as u32) << 8 | zfile.data.bytes[from + 1] as u32;
+        assert_eq!(4 * packed + 8 * offset, labels[0].to_addr);
+    }
+
+    #[test]
+    fn test_zfile_main_loop() {
+        fn jump_targets(main_loop: MainLoop) -> Vec<String> {
+            let mut zfile: Zfile = Zfile::new();
+            zfile.main_loop = main_loop;
+            zfile.start();
+            zfile.jumps.iter().map(|jump| jump.name.to_string()).collect()
+        }
+
+        assert_eq!(jump_targets(MainLoop::Links), vec!["malloc_init", "system_start", "system_check_links", "mainloop"]);
+        assert_eq!(jump_targets(MainLoop::Quit), vec!["malloc_init", "system_start"]);
+        assert_eq!(jump_targets(MainLoop::Passage("Turn".to_string())), vec!["malloc_init", "system_start", "Turn", "mainloop"]);
+    }
+
+    #[test]
+    fn test_zfile_routine_error() {
+        fn jump_targets(error_passage: Option<&str>) -> Vec<String> {
+            let mut zfile: Zfile = Zfile::new();
+            zfile.error_passage = error_passage.map(|name| name.to_string());
+            zfile.routine_error();
+            zfile.jumps.iter().map(|jump| jump.name.to_string()).collect()
+        }
+
+        // without an error passage the message is printed before quitting
+        assert_eq!(jump_targets(None), vec!["print_unicode"]);
+        // the error passage is called by system_start after the throw
+        assert_eq!(jump_targets(Some("RuntimeError")), vec!["rt_error_quit", "print_unicode"]);
+    }
+
+    #[test]
+    fn test_zfile_routine_start() {
+        fn jump_targets(error_passage: Option<&str>) -> Vec<String> {
+            let mut zfile: Zfile = Zfile::new();
+            zfile.start_passage = "Start".to_string();
+            zfile.error_passage = error_passage.map(|name| name.to_string());
+            zfile.routine_start();
+            zfile.jumps.iter().map(|jump| jump.name.to_string()).collect()
+        }
+
+        assert_eq!(jump_targets(None), vec!["Start"]);
+        assert_eq!(jump_targets(Some("RuntimeError")), vec!["system_run", "system_error_run", "system_start_error",
+            "Start", "system_check_links", "system_run_loop", "RuntimeError", "system_check_links", "system_error_run_loop"]);
+    }
+
+    #[test]
+    fn test_zfile_merge_routines() {
+        // a routine skipped by the passage code, like the routine of a link setting a variable
+        fn helper(id: u8) -> Vec<ZOP> {
+            vec![
+                ZOP::Jump{jump_to_label: format!("continue{}", id)},
+                ZOP::Routine{name: format!("helper{}", id), count_variables: 1},
+                ZOP::Label{name: format!("loop{}", id)},
+                ZOP::Inc{variable: 1},
+                ZOP::JL{operand1: Operand::new_var(1), operand2: Operand::new_const(3), jump_to_label: format!("loop{}", id)},
+                ZOP::Call1N{jump_to_label: "mem_free".to_string()},
+                ZOP::Ret{value: Operand::new_const(0)},
+                ZOP::Label{name: format!("continue{}", id)},
+                ZOP::Call2NWithAddress{jump_to_label: "system_add_link".to_string(), address: format!("helper{}", id)},
+            ]
+        }
+
+        fn address(zfile: &Zfile, name: &str) -> u32 {
+            zfile.labels.iter().find(|label| label.name == name).unwrap().to_addr
+        }
+
+        let mut zfile: Zfile = Zfile::new();
+        zfile.emit_merging_routines(helper(0));
+        let first_len = zfile.data.len();
+        zfile.emit_merging_routines(helper(1));
+        assert_eq!(zfile.merged_routines, 1);
+
+        // only the jump and the call are written again, both names point to the first routine
+        assert!(zfile.data.len() - first_len < first_len);
+        assert_eq!(address(&zfile, "helper0"), address(&zfile, "helper1"));
+        assert_eq!(address(&zfile, "loop0"), address(&zfile, "loop1"));
+        assert!(address(&zfile, "continue0") != address(&zfile, "continue1"));
+
+        // a different body is kept
+        let mut other = helper(2);
+        other[3] = ZOP::Dec{variable: 1};
+        zfile.emit_merging_routines(other);
+        assert_eq!(zfile.merged_routines, 1);
+        assert!(address(&zfile, "helper2") != address(&zfile, "helper0"));
+    }
+
+    #[test]
+    fn test_zfile_merge_routines_strings() {
+        fn helper(id: u8, text: &str) -> Vec<ZOP> {
+            vec![
+                ZOP::Jump{jump_to_label: format!("continue{}", id)},
+                ZOP::Routine{name: format!("helper{}", id), count_variables: 0},
+                ZOP::PrintOps{text: text.to_string()},
+                ZOP::Ret{value: Operand::new_const(0)},
+                ZOP::Label{name: format!("continue{}", id)},
+            ]
+        }
+
+        let mut zfile: Zfile = Zfile::new();
+        zfile.emit_merging_routines(helper(0, "the same text"));
+        let strings = zfile.string_count();
+        zfile.emit_merging_routines(helper(1, "the same text"));
+        assert_eq!(zfile.merged_routines, 1);
+        assert_eq!(zfile.string_count(), strings);
+
+        // the code in place of the merged routine keeps its bytes when the strings are written
+        let after = zfile.data.len();
+        zfile.emit(vec![ZOP::Quit; 16]);
+        zfile.write_strings();
+        assert!(zfile.data.bytes[after..after + 16].iter().all(|&byte| byte == 0xba));
+
+        // a routine printing another text is kept
+        zfile.emit_merging_routines(helper(2, "another text"));
+        assert_eq!(zfile.merged_routines, 1);
+        assert_eq!(zfile.string_count(), strings + 1);
+    }
+
+    #[test]
+    fn test_zfile_layout() {
+        // the header extension of embedded blobs moves everything behind it
+        let mut zfile: Zfile = Zfile::new();
+        zfile.embedded_blobs.push(("map".to_string(), vec![1, 2, 3]));
+        zfile.create_header();
+        zfile.check_layout();
+        let regions: Vec<&str> = zfile.memory_map().iter().map(|region| region.0).collect();
+        assert_eq!(&regions[..4], &["header", "unicode table", "globals", "objects"]);
+    }
+
+    #[test]
+    #[should_panic(expected="invalid memory layout: globals")]
+    fn test_zfile_layout_unicode_table_too_large() {
+        // the globals behind the table would reach into the type storage
+        let mut zfile: Zfile = Zfile::new();
+        zfile.unicode_table_size = 300;
+        zfile.create_header();
+    }
+
+    #[test]
+    fn test_zfile_reserve_links() {
+        let mut zfile: Zfile = Zfile::new();
+        zfile.start();
+        let compact = 1 + zfile.object_addr;
+        let heap_start = zfile.heap_start;
+
+        assert!(zfile.reserve_links(3));
+        assert_eq!((zfile.link_table, zfile.link_capacity), (compact, MIN_LINK_CAPACITY));
+
+        assert!(zfile.reserve_links(60));
+        assert_eq!((zfile.link_table, zfile.link_capacity), (compact, 60));
+        assert_eq!(zfile.heap_start, heap_start);
+
+        // too large for the room in front of the type storage
+        assert!(zfile.reserve_links(200));
+        assert_eq!((zfile.link_table, zfile.link_capacity), (heap_start, 200));
+        assert_eq!(zfile.heap_start, heap_start + 400);
+
+        let map = zfile.memory_map();
+        for regions in map.windows(2) {
+            assert!(regions[0].1 + regions[0].2 <= regions[1].1, "{} overlaps {}", regions[0].0, regions[1].0);
+        }
+    }
+
+    #[test]
+    fn test_zfile_reserve_too_many_links() {
+        let mut zfile: Zfile = Zfile::new();
+        zfile.start();
+        let heap_start = zfile.heap_start;
+
+        // the table takes what is left of the heap
+        assert!(!zfile.reserve_links(0x7fff));
+        assert_eq!(zfile.link_table, heap_start);
+        assert!(zfile.link_capacity > 200 && zfile.link_capacity < 0x7fff);
+        assert!(zfile.heap_start < zfile.static_addr);
+    }
+
+    #[test]
+    fn test_zfile_print_separator() {
+        fn encoded(text: &str) -> Vec<u8> {
+            let mut bytes = Bytes{bytes: Vec::new()};
+            ztext::encode(&mut bytes, text, &Vec::new());
+            bytes.bytes
+        }
+        fn contains(zfile: &Zfile, bytes: &[u8]) -> bool {
+            zfile.data.bytes.windows(bytes.len()).any(|window| window == bytes)
+        }
+        let loadb_width = encode(|bytes| op::op_loadb(&Operand::new_const(0), &Operand::new_const(0x21), &Variable::new(1), bytes));
+
+        let mut zfile: Zfile = Zfile::new();
+        zfile.separator_char = '=';
+        zfile.separator_length = 12;
+        zfile.routine_print_separator();
+        assert!(contains(&zfile, &encoded("============")));
+        assert!(!contains(&zfile, &loadb_width));
+
+        // fitted to the screen width, with the fixed length as fallback
+        let mut zfile: Zfile = Zfile::new();
+        zfile.separator_length = 0;
+        zfile.routine_print_separator();
+        assert!(contains(&zfile, &loadb_width));
+        assert!(contains(&zfile, &encoded(&::std::iter::repeat('-').take(SEPARATOR_LENGTH as usize).collect::<String>())));
+    }
+
+    #[test]
+    fn test_zfile_heap_stats() {
+        fn contains(zfile: &Zfile, text: &str) -> bool {
+            let mut bytes = Bytes{bytes: Vec::new()};
+            ztext::encode(&mut bytes, text, &Vec::new());
+            zfile.data.bytes.windows(bytes.bytes.len()).any(|window| window == &bytes.bytes[..])
+        }
+
+        // an exhausted heap always prints the high water mark, the counters need heap_stats
+        for &heap_stats in [false, true].iter() {
+            let mut zfile: Zfile = Zfile::new();
+            zfile.heap_stats = heap_stats;
+            zfile.start();
+            zfile.emit(vec![
+                ZOP::Routine{name: "Start".to_string(), count_variables: 0},
+                ZOP::Ret{value: Operand::new_const(0)}
+            ]);
+            zfile.end();
+            let heap_words = (zfile.static_addr - 2 - zfile.heap_start) / 2;
+            assert!(heap_words > 0);
+            assert!(contains(&zfile, "heap: high water "));
+            assert!(contains(&zfile, &format!(" of {} words", heap_words)));
+            assert_eq!(contains(&zfile, " allocs, "), heap_stats);
+            assert_eq!(contains(&zfile, " GC frees"), heap_stats);
+        }
+
+        // H only prints them at the links with heap_stats
+        let mut zfile: Zfile = Zfile::new();
+        zfile.routine_check_links();
+        assert!(!zfile.jumps.iter().any(|jump| jump.name == "system_check_links_heap_stats"));
+
+        let mut zfile: Zfile = Zfile::new();
+        zfile.heap_stats = true;
+        zfile.routine_check_links();
+        assert_eq!(zfile.jumps.iter().filter(|jump| jump.name == "system_check_links_heap_stats").count(), 2);
+        assert!(zfile.labels.iter().any(|label| label.name == "system_check_links_heap_stats"));
+    }
+
+    #[test]
+    fn test_zfile_no_dead_end_quit() {
+        let mut zfile: Zfile = Zfile::new();
+        zfile.routine_check_links();
+        assert!(!zfile.jumps.iter().any(|jump| jump.name == "system_check_links_the_end"));
+        assert!(!zfile.labels.iter().any(|label| label.name == "system_check_links_the_end"));
+
+        // only the check of the link count jumps to THE END, Q still quits immediately
+        let mut zfile: Zfile = Zfile::new();
+        zfile.no_dead_end_quit = true;
+        zfile.routine_check_links();
+        assert_eq!(zfile.jumps.iter().filter(|jump| jump.name == "system_check_links_the_end").count(), 1);
+        assert!(zfile.jumps.iter().any(|jump| jump.name == "system_check_links_end_quit"));
+        assert!(zfile.labels.iter().any(|label| label.name == "system_check_links_the_end"));
+    }
+
+    #[test]
+    fn test_zfile_transition_pause() {
+        // read_char with a timer: var-form, two small constants, the routine and the variable
+        let timed_read = [0xf6, 0x53, 0x01, TRANSITION_PAUSE];
+
+        let mut zfile: Zfile = Zfile::new();
+        zfile.routine_check_links();
+        assert!(!zfile.data.bytes.windows(4).any(|window| window == &timed_read[..]));
+        assert!(!zfile.jumps.iter().any(|jump| jump.name == "system_transition_tick"));
+
+        let mut zfile: Zfile = Zfile::new();
+        zfile.transition_pause = true;
+        zfile.routine_check_links();
+        let bytes = &zfile.data.bytes;
+        let pause = bytes.windows(4).position(|window| window == &timed_read[..]).expect("no timed read_char");
+        // call_2n in the var form: the opcode, the types and then the address of the routine
+        assert!(zfile.jumps.iter().any(|jump| jump.name == "system_follow_link" && jump.from_addr as usize == pause + 9));
+        assert!(zfile.labels.iter().any(|label| label.name == "system_transition_tick"));
+    }
+
     #[test]
     fn test_zfile_write_jumps_length() {
         let mut zfile: Zfile = Zfile::new();
@@ -2318,6 +4955,30 @@ mod tests {
         assert_eq!(zfile.data.len(), 3);
     }
 
+    #[test]
+    fn test_write_zop_in_place() {
+        let add = ZOP::Add{operand1: Operand::new_var(1), operand2: Operand::new_large_const(1000), save_variable: Variable::new(3)};
+        let store = ZOP::StoreW{array_address: Operand::new_large_const(0x800), index: Variable::new(2), variable: Variable::new(3)};
+        let expected = [
+            encode(|bytes| op::op_add(&Operand::new_var(1), &Operand::new_large_const(1000), &Variable::new(3), bytes)),
+            encode(|bytes| op::op_storew(&Operand::new_large_const(0x800), &Variable::new(2), &Variable::new(3), bytes)),
+        ].concat();
+        assert_eq!(expected, vec![0xd4, 0x8f, 0x01, 0x03, 0xe8, 0x03, 0xe1, 0x2b, 0x08, 0x00, 0x02, 0x03]);
+
+        // many instructions after other data end up exactly behind each other
+        let mut zfile: Zfile = Zfile::new();
+        zfile.routine_locals = 3;
+        zfile.data.append_bytes(&[0xaa, 0xbb]);
+        for _ in 0..5000 {
+            let (_, _, bytes) = zfile.write_zop(&add, false);
+            assert_eq!(bytes, &expected[..6]);
+            zfile.write_zop(&store, false);
+        }
+        assert_eq!(zfile.data.len(), 2 + 5000 * expected.len());
+        assert_eq!(&zfile.data.bytes[..2], &[0xaa, 0xbb]);
+        assert!(zfile.data.bytes[2..].chunks(expected.len()).all(|chunk| chunk == &expected[..]));
+    }
+
     #[test]
     fn test_zfile_general_op_length() {
         let mut zfile: Zfile = Zfile::new();
@@ -2327,8 +4988,8 @@ mod tests {
         assert_eq!(zfile.data.len(), 2);
         zfile.op_1(0x00, ArgType::Reference);
         assert_eq!(zfile.data.len(), 3);
-        let args: Vec<ArgType> = vec![ArgType::SmallConst, ArgType::Nothing, ArgType::Nothing, ArgType::Nothing];
-        zfile.data.append_bytes(&op::op_var(0x00, args));
+        let args = [ArgType::SmallConst, ArgType::Nothing, ArgType::Nothing, ArgType::Nothing];
+        op::op_var(0x00, &args, &mut zfile.data.bytes);
         assert_eq!(zfile.data.len(), 5);
     }
 
@@ -2355,203 +5016,684 @@ mod tests {
         assert_eq!(-1 as i16, rel_addr);  // this is the expected result, jump one address back
     }
 
+    #[test]
+    fn test_zfile_piracy_branches_on_true() {
+        let mut zfile: Zfile = Zfile::new();
+        zfile.start();
+        let (_, jumps, bytes) = zfile.write_zop(&ZOP::Piracy{jump_to_label: "Genuine".to_string()}, true);
+        assert_eq!(bytes.len(), 3);
+        assert_eq!(bytes[0], 0xbf);
+        assert_eq!(jumps.len(), 1);
+        assert_eq!(jumps[0].jump_type, JumpType::Branch);
+        let pos = jumps[0].from_addr as usize;
+        zfile.write_zop(&ZOP::Quit, false);
+        let (labels, _, _) = zfile.write_zop(&ZOP::Label{name: "Genuine".to_string()}, true);
+        zfile.end();
+
+        // the branch is taken if the condition is true and lands on the label
+        let branch = zfile.data.bytes[pos] as u16 * 256 + zfile.data.bytes[pos+1] as u16;
+        assert_eq!(branch & 0x8000, 0x8000);
+        let offset = (branch & 0x3fff) as u32;
+        assert_eq!(pos as u32 + 2 + offset - 2, labels[0].to_addr);
+    }
+
+    #[test]
+    fn test_string_pool_reuses_strings() {
+        // force_unicode prints the umlauts below with print_unicode
+        let mut zfile: Zfile = Zfile::new_with_options(false, true, false, false, false, false);
+        zfile.start();
+        zfile.emit(vec![ZOP::PrintOps{text: "the same text".to_string()}, ZOP::PrintOps{text: "another text".to_string()},
+                        ZOP::PrintOps{text: "the same text".to_string()}]);
+        let first = zfile.strings[0].from_addr as usize;
+        let third = zfile.strings[2].from_addr as usize;
+        assert_eq!(zfile.string_bytes_since(0), zfile.strings[0].chars.len() + zfile.strings[1].chars.len());
+        assert_eq!(zfile.string_bytes_since(2), 0);
+
+        // write_string writes the strings used before it in front of its own, as they always were,
+        // and returns the address of a text printed with print_unicode before
+        zfile.emit(vec![ZOP::PrintOps{text: "ÄÖÜ".to_string()}]);
+        let addr = zfile.write_string("ÄÖÜ");
+        assert!(zfile.data.bytes[first..first + 2] != [0, 0]);
+        assert_eq!(zfile.strings.len(), 4);
+        assert_eq!(zfile.write_string("ÄÖÜ"), addr);
+        let unicode = zfile.strings[3].from_addr as usize;
+        zfile.end();
+
+        assert!(zfile.data.bytes[first..first + 2] == zfile.data.bytes[third..third + 2]);
+        assert_eq!(zfile.data.bytes[unicode..unicode + 2].to_vec(), vec![(addr >> 8) as u8, (addr & 0xff) as u8]);
+    }
+
+    #[test]
+    fn test_embedded_blobs() {
+        let mut zfile: Zfile = Zfile::new();
+        zfile.embedded_blobs = vec![("map".to_string(), vec![1, 2, 3]), ("tiles".to_string(), vec![0xff; 10])];
+        zfile.start();
+        zfile.end();
+        let bytes = &zfile.data.bytes;
+        let word = |addr: usize| (bytes[addr] as u32) << 8 | bytes[addr + 1] as u32;
+        let long = |addr: usize| word(addr) << 16 | word(addr + 2);
+
+        let extension = word(0x36) as usize;
+        assert_eq!(word(extension), 7);
+        assert_eq!(word(extension + 6), zfile.unicode_table_addr as u32);
+        let index = 8 * word(extension + 14) as usize;
+        assert_eq!(word(index), 2);
+
+        assert_eq!(bytes[index + 2], 3);
+        assert_eq!(&bytes[index + 3..index + 6], b"map");
+        let map = long(index + 6) as usize;
+        assert_eq!(long(index + 10), 3);
+        assert_eq!(bytes[map..map + 3].to_vec(), vec![1, 2, 3]);
+
+        assert_eq!(bytes[index + 14], 5);
+        assert_eq!(&bytes[index + 15..index + 20], b"tiles");
+        let tiles = long(index + 20) as usize;
+        assert_eq!(long(index + 24), 10);
+        assert_eq!(tiles % 8, 0);
+        assert_eq!(bytes[tiles..tiles + 10].to_vec(), vec![0xff; 10]);
+        assert_eq!(bytes.len(), index + 28);
+
+        // files without blobs keep the extension table of three words
+        let mut zfile: Zfile = Zfile::new();
+        zfile.start();
+        zfile.end();
+        assert_eq!(zfile.data.bytes[0x40 + 78 + 1], 3);
+    }
+
+    #[test]
+    fn test_string_pool_scaling() {
+        // generated stories have tens of thousands of different texts, each one used twice here
+        let mut zfile: Zfile = Zfile::new();
+        zfile.data.append_bytes(&[0; 0x40]);
+        let start = ::time::precise_time_ns();
+        for i in 0..50000 {
+            for _ in 0..2 {
+                zfile.add_string(Zstring{chars: vec![0x94, 0xa5], orig: format!("text {}", i), from_addr: 0x10, unicode: false, written_addr: 0});
+            }
+        }
+        assert_eq!(zfile.string_bytes_since(0), 50000 * 2);
+        zfile.write_strings();
+        let millis = (::time::precise_time_ns() - start) / 1_000_000;
+
+        assert_eq!(zfile.data.len(), 0x40 + 49999 * 8 + 2);
+        assert!(millis < 5000, "writing 50000 strings took {} ms", millis);
+    }
+
+    #[test]
+    fn test_string_ref_above_0x8000() {
+        let mut zfile: Zfile = Zfile::new();
+        zfile.start();
+        let addr = zfile.write_string("a string stored in the upper half of the address space");
+        assert!(addr >= 0x8000);
+        assert_eq!(Operand::new_string_ref(addr).address_value(), addr);
+
+        let (_, _, bytes) = zfile.write_zop(&ZOP::Call2NWithArg{jump_to_label: "print_unicode".to_string(), arg: Operand::new_string_ref(addr)}, false);
+        assert_eq!(bytes[bytes.len()-2..].to_vec(), vec![(addr >> 8) as u8, (addr & 0xff) as u8]);
+    }
+
+    #[test]
+    fn test_write_argument_pointer() {
+        let mut bytes = vec![];
+        op::write_argument(&Operand::new_pointer(0xfff0), &mut bytes);
+        assert_eq!(bytes, vec![0xff, 0xf0]);
+    }
+
     #[test]
     fn test_op_inc() {
-        assert_eq!(op::op_inc(1),vec![0x95,0x01]);
+        assert_eq!(encode(|bytes| op::op_inc(1, bytes)),vec![0x95,0x01]);
     }
 
     #[test]
     fn test_op_dec() {
-        assert_eq!(op::op_dec(1),vec![0x96,0x01]);
+        assert_eq!(encode(|bytes| op::op_dec(1, bytes)),vec![0x96,0x01]);
     }
 
     #[test]
     fn test_op_newline() {
-        assert_eq!(op::op_newline(),vec![0xbb]);
+        assert_eq!(encode(|bytes| op::op_newline(bytes)),vec![0xbb]);
     }
 
     #[test]
     fn test_op_quit() {
-        assert_eq!(op::quit(),vec![0xba]);
+        assert_eq!(encode(|bytes| op::quit(bytes)),vec![0xba]);
     }
 
     #[test]
     fn test_op_add() {
-        assert_eq!(op::op_add(&Operand::new_var(1),&Operand::new_var(2),&Variable::new(3)),vec![0x74,0x01,0x02,0x03]);
+        assert_eq!(encode(|bytes| op::op_add(&Operand::new_var(1),&Operand::new_var(2),&Variable::new(3), bytes)),vec![0x74,0x01,0x02,0x03]);
     }
     #[test]
     fn test_op_sub() {
-        assert_eq!(op::op_sub(&Operand::new_var(1),&Operand::new_var(2),&Variable::new(3)),vec![0x75,0x01,0x02,0x03]);
+        assert_eq!(encode(|bytes| op::op_sub(&Operand::new_var(1),&Operand::new_var(2),&Variable::new(3), bytes)),vec![0x75,0x01,0x02,0x03]);
     }
 
     #[test]
     fn test_op_mul() {
-        assert_eq!(op::op_mul(&Operand::new_var(1),&Operand::new_var(2),&Variable::new(3)),vec![0x76,0x01,0x02,0x03]);
+        assert_eq!(encode(|bytes| op::op_mul(&Operand::new_var(1),&Operand::new_var(2),&Variable::new(3), bytes)),vec![0x76,0x01,0x02,0x03]);
     }
 
     #[test]
     fn test_op_div() {
-        assert_eq!(op::op_div(&Operand::new_var(1),&Operand::new_var(2),&Variable::new(3)),vec![0x77,0x01,0x02,0x03]);
+        assert_eq!(encode(|bytes| op::op_div(&Operand::new_var(1),&Operand::new_var(2),&Variable::new(3), bytes)),vec![0x77,0x01,0x02,0x03]);
     }
 
     #[test]
     fn test_op_mod() {
-        assert_eq!(op::op_mod(&Operand::new_var(1),&Operand::new_var(2),&Variable::new(3)),vec![0x78,0x01,0x02,0x03]);
+        assert_eq!(encode(|bytes| op::op_mod(&Operand::new_var(1),&Operand::new_var(2),&Variable::new(3), bytes)),vec![0x78,0x01,0x02,0x03]);
     }
 
     #[test]
     fn test_op_and() {
-        assert_eq!(op::op_and(&Operand::new_var(1),&Operand::new_var(2),&Variable::new(3)),vec![0x69,0x01,0x02,0x03]);
+        assert_eq!(encode(|bytes| op::op_and(&Operand::new_var(1),&Operand::new_var(2),&Variable::new(3), bytes)),vec![0x69,0x01,0x02,0x03]);
     }
 
     #[test]
     fn test_op_or() {
-        assert_eq!(op::op_or(&Operand::new_var(1),&Operand::new_var(2),&Variable::new(3)),vec![0x68,0x01,0x02,0x03]);
+        assert_eq!(encode(|bytes| op::op_or(&Operand::new_var(1),&Operand::new_var(2),&Variable::new(3), bytes)),vec![0x68,0x01,0x02,0x03]);
     }
 
     #[test]
     fn test_op_set_color() {
-        assert_eq!(op::op_set_color(0x15,0x20),vec![0x1B,0x15,0x20]);
+        assert_eq!(encode(|bytes| op::op_set_color(0x15,0x20, bytes)),vec![0x1B,0x15,0x20]);
     }
 
     #[test]
     fn test_op_set_color_var() {
-        assert_eq!(op::op_set_color_var(0x01,0x02),vec![0x7B,0x01,0x02]);
+        assert_eq!(encode(|bytes| op::op_set_color_var(0x01,0x02, bytes)),vec![0x7B,0x01,0x02]);
     }
 
     #[test]
     fn test_op_push_u16() {
-        assert_eq!(op::op_push_u16(0x0101),vec![0xE8,0x3F,0x01,0x01]);
+        assert_eq!(encode(|bytes| op::op_push_u16(0x0101, bytes)),vec![0xE8,0x3F,0x01,0x01]);
     }
 
     #[test]
     fn test_op_pull() {
-        assert_eq!(op::op_pull(0x01),vec![0xE9,0x7F,0x01]);
+        assert_eq!(encode(|bytes| op::op_pull(0x01, bytes)),vec![0xE9,0x7F,0x01]);
     }
 
     #[test]
     fn test_op_random() {
-        assert_eq!(op::op_random(&Operand::new_var(10),&Variable::new(3)),vec![0xE7,0xBF,0x0a,0x03]);
+        assert_eq!(encode(|bytes| op::op_random(&Operand::new_var(10),&Variable::new(3), bytes)),vec![0xE7,0xBF,0x0a,0x03]);
     }
 
     #[test]
     fn test_op_print_num_var() {
-        assert_eq!(op::op_print_num_var(&Variable::new(3)),vec![0xE6,0xBF,0x03]);
+        assert_eq!(encode(|bytes| op::op_print_num_var(&Variable::new(3), bytes)),vec![0xE6,0xBF,0x03]);
+    }
+
+    #[test]
+    fn test_op_save_restore() {
+        assert_eq!(encode(|bytes| op::op_save(&Variable::new(3), bytes)),vec![0xBE,0x00,0xFF,0x03]);
+        assert_eq!(encode(|bytes| op::op_restore(&Variable::new(0), bytes)),vec![0xBE,0x01,0xFF,0x00]);
+    }
+
+    #[test]
+    fn test_op_copy_table() {
+        assert_eq!(encode(|bytes| op::op_copy_table(&Operand::new_var(1), &Operand::new_var(2), &Operand::new_large_const(100), bytes)),
+            vec![0xFD,0xA3,0x01,0x02,0x00,0x64]);
+        // a second table of 0 zeroes the first one
+        assert_eq!(encode(|bytes| op::op_copy_table(&Operand::new_large_const(0x800), &Operand::new_const(0), &Operand::new_const(16), bytes)),
+            vec![0xFD,0x17,0x08,0x00,0x00,0x10]);
     }
 
     #[test]
     fn test_op_set_text_style() {
-        assert_eq!(op::op_set_text_style(true,true,true,true),vec![0xF1,0x7F,0x0F]);
-        assert_eq!(op::op_set_text_style(true,false,false,false),vec![0xF1,0x7F,0x02]);
-        assert_eq!(op::op_set_text_style(false,true,false,false),vec![0xF1,0x7F,0x01]);
-        assert_eq!(op::op_set_text_style(false,false,true,false),vec![0xF1,0x7F,0x08]);
-        assert_eq!(op::op_set_text_style(false,false,false,true),vec![0xF1,0x7F,0x04]);
-        assert_eq!(op::op_set_text_style(false,false,false,false),vec![0xF1,0x7F,0x00]);
+        assert_eq!(encode(|bytes| op::op_set_text_style(true,true,true,true, bytes)),vec![0xF1,0x7F,0x0F]);
+        assert_eq!(encode(|bytes| op::op_set_text_style(true,false,false,false, bytes)),vec![0xF1,0x7F,0x02]);
+        assert_eq!(encode(|bytes| op::op_set_text_style(false,true,false,false, bytes)),vec![0xF1,0x7F,0x01]);
+        assert_eq!(encode(|bytes| op::op_set_text_style(false,false,true,false, bytes)),vec![0xF1,0x7F,0x08]);
+        assert_eq!(encode(|bytes| op::op_set_text_style(false,false,false,true, bytes)),vec![0xF1,0x7F,0x04]);
+        assert_eq!(encode(|bytes| op::op_set_text_style(false,false,false,false, bytes)),vec![0xF1,0x7F,0x00]);
     }
 
     #[test]
     fn test_op_read_char() {
-        assert_eq!(op::op_read_char(0x01),vec![0xF6,0x7F,0x01,0x01]);
+        assert_eq!(encode(|bytes| op::op_read_char(0x01, bytes)),vec![0xF6,0x7F,0x01,0x01]);
     }
 
     #[test]
     fn test_op_loadw() {
-        assert_eq!(op::op_loadw(&Operand::new_var(1),&Variable::new(2),&Variable::new(3)),vec![0x6F,0x01,0x02,0x03]);
+        assert_eq!(encode(|bytes| op::op_loadw(&Operand::new_var(1),&Variable::new(2),&Variable::new(3), bytes)),vec![0x6F,0x01,0x02,0x03]);
     }
 
     #[test]
     fn test_op_storew() {
-        assert_eq!(op::op_storew(&Operand::new_var(1),&Variable::new(2),&Variable::new(3)),vec![0xE1,0xAB,0x01,0x02,0x03]);
+        assert_eq!(encode(|bytes| op::op_storew(&Operand::new_var(1),&Variable::new(2),&Variable::new(3), bytes)),vec![0xE1,0xAB,0x01,0x02,0x03]);
     }
 
     #[test]
     fn test_op_erase_window() {
-        assert_eq!(op::op_erase_window(0x01),vec![0xED,0x3F,0x00,0x01]);
+        assert_eq!(encode(|bytes| op::op_erase_window(0x01, bytes)),vec![0xED,0x3F,0x00,0x01]);
     }
 
     #[test]
     fn test_op_call_1n_var() {
-        assert_eq!(op::op_call_1n_var(0x01),vec![0xAF,0x01]);
+        assert_eq!(encode(|bytes| op::op_call_1n_var(0x01, bytes)),vec![0xAF,0x01]);
     }
 
     #[test]
     fn test_op_print_paddr() {
-        assert_eq!(op::op_print_paddr(&Operand::new_var(10)),vec![0xAD,0x0a]);
+        assert_eq!(encode(|bytes| op::op_print_paddr(&Operand::new_var(10), bytes)),vec![0xAD,0x0a]);
     }
 
     #[test]
     fn test_op_print_addr() {
-        assert_eq!(op::op_print_addr(&Operand::new_var(10)),vec![0xA7,0x0a]);
+        assert_eq!(encode(|bytes| op::op_print_addr(&Operand::new_var(10), bytes)),vec![0xA7,0x0a]);
     }
 
     #[test]
     fn test_op_ret() {
-        assert_eq!(op::op_ret(&Operand::new_large_const(0x0101 as i16)),vec![0x8B,0x01,0x01]);
+        assert_eq!(encode(|bytes| op::op_ret(&Operand::new_large_const(0x0101 as i16), bytes)),vec![0x8B,0x01,0x01]);
     }
 
     #[test]
     fn test_op_store_var() {
-        assert_eq!(op::op_store_var(&Variable::new(2),&Operand::new_var(10)),vec![0x2d,0x02,0x0a]);
+        assert_eq!(encode(|bytes| op::op_store_var(&Variable::new(2),&Operand::new_var(10), bytes)),vec![0x2d,0x02,0x0a]);
     }
 
     #[test]
     fn test_encode_variable_arguments() {
-        assert_eq!(op::encode_variable_arguments(vec![ArgType::Variable]),0x80);
-        assert_eq!(op::encode_variable_arguments(vec![ArgType::SmallConst]),0x40);
-        assert_eq!(op::encode_variable_arguments(vec![ArgType::LargeConst]),0x00);
-        assert_eq!(op::encode_variable_arguments(vec![ArgType::Nothing]),0xc0);
-        assert_eq!(op::encode_variable_arguments(vec![ArgType::Reference]),0x40);
+        assert_eq!(op::encode_variable_arguments(&[ArgType::Variable]),0x80);
+        assert_eq!(op::encode_variable_arguments(&[ArgType::SmallConst]),0x40);
+        assert_eq!(op::encode_variable_arguments(&[ArgType::LargeConst]),0x00);
+        assert_eq!(op::encode_variable_arguments(&[ArgType::Nothing]),0xc0);
+        assert_eq!(op::encode_variable_arguments(&[ArgType::Reference]),0x40);
     }
 
     #[test]
     fn test_op_2() {
-        assert_eq!(op::op_2(0x02,vec![ArgType::Variable]),vec![0x42]);
-        assert_eq!(op::op_2(0x02,vec![ArgType::LargeConst]),vec![0xc2,0x0f]);
-        assert_eq!(op::op_2(0x02,vec![ArgType::SmallConst]),vec![0x02]);
-        assert_eq!(op::op_2(0x02,vec![ArgType::Reference]),vec![0x02]);
+        assert_eq!(encode(|bytes| op::op_2(0x02,&[ArgType::Variable], bytes)),vec![0x42]);
+        assert_eq!(encode(|bytes| op::op_2(0x02,&[ArgType::LargeConst], bytes)),vec![0xc2,0x0f]);
+        assert_eq!(encode(|bytes| op::op_2(0x02,&[ArgType::SmallConst], bytes)),vec![0x02]);
+        assert_eq!(encode(|bytes| op::op_2(0x02,&[ArgType::Reference], bytes)),vec![0x02]);
     }
 
     #[test]
     fn test_op_1() {
-        assert_eq!(op::op_1(0x02,ArgType::Variable),vec![0xa2]);
-        assert_eq!(op::op_1(0x02,ArgType::LargeConst),vec![0x82]);
-        assert_eq!(op::op_1(0x02,ArgType::SmallConst),vec![0x92]);
-        assert_eq!(op::op_1(0x02,ArgType::Reference),vec![0x92]);
+        assert_eq!(encode(|bytes| op::op_1(0x02,ArgType::Variable, bytes)),vec![0xa2]);
+        assert_eq!(encode(|bytes| op::op_1(0x02,ArgType::LargeConst, bytes)),vec![0x82]);
+        assert_eq!(encode(|bytes| op::op_1(0x02,ArgType::SmallConst, bytes)),vec![0x92]);
+        assert_eq!(encode(|bytes| op::op_1(0x02,ArgType::Reference, bytes)),vec![0x92]);
     }
 
     #[test]
     fn test_op_var() {
-        assert_eq!(op::op_var(0x02,vec![ArgType::Variable]),vec![0xe2,0x80]);
-        assert_eq!(op::op_var(0x02,vec![ArgType::LargeConst]),vec![0xe2,0x00]);
-        assert_eq!(op::op_var(0x02,vec![ArgType::SmallConst]),vec![0xe2,0x40]);
-        assert_eq!(op::op_var(0x02,vec![ArgType::Reference]),vec![0xe2,0x40]);
+        assert_eq!(encode(|bytes| op::op_var(0x02,&[ArgType::Variable], bytes)),vec![0xe2,0x80]);
+        assert_eq!(encode(|bytes| op::op_var(0x02,&[ArgType::LargeConst], bytes)),vec![0xe2,0x00]);
+        assert_eq!(encode(|bytes| op::op_var(0x02,&[ArgType::SmallConst], bytes)),vec![0xe2,0x40]);
+        assert_eq!(encode(|bytes| op::op_var(0x02,&[ArgType::Reference], bytes)),vec![0xe2,0x40]);
+    }
+
+    /// The instruction forms of the Z-Machine (section 4.3 of the standard), named after their
+    /// operand counts.
+    #[derive(Debug, PartialEq)]
+    enum Form { Op0, Op1, Op2, Var, Ext }
+
+    /// Decodes form and opcode number of the instruction at the beginning of `bytes`.
+    fn decode_form(bytes: &[u8]) -> (Form, u8) {
+        let b = bytes[0];
+        if b == 0xbe {
+            (Form::Ext, bytes[1])
+        } else if b < 0x80 {
+            (Form::Op2, b & 0x1f)
+        } else if b < 0xb0 {
+            (Form::Op1, b & 0x0f)
+        } else if b < 0xc0 {
+            (Form::Op0, b & 0x0f)
+        } else if b < 0xe0 {
+            (Form::Op2, b & 0x1f)
+        } else {
+            (Form::Var, b & 0x1f)
+        }
+    }
+
+    /// Returns the operand types of an instruction with types bytes, `0b11` marks an omitted
+    /// operand.
+    fn operand_types(bytes: &[u8]) -> Vec<u8> {
+        let (form, opcode) = decode_form(bytes);
+        let (start, count) = match form {
+            Form::Ext => (2, 1),
+            // call_vs2 and call_vn2 have a second types byte
+            Form::Var if opcode == 0x0c || opcode == 0x1a => (1, 2),
+            _ => (1, 1)
+        };
+        let mut types = vec![];
+        for byte in bytes[start..start + count].iter() {
+            for i in 0..4 {
+                types.push((byte >> (6 - 2 * i)) & 0x03);
+            }
+        }
+        types
+    }
+
+    /// The form and opcode number the first instruction of `zop` has to be encoded with.
+    ///
+    /// This is an exhaustive match, so a new `ZOP` can not be added without its encoding.
+    /// Instructions which are emitted as several Z-Machine instructions are classified by their
+    /// first one.
+    fn expected_form(zop: &ZOP) -> Option<(Form, u8)> {
+        match zop {
+            &ZOP::PrintUnicode{..} | &ZOP::PrintUnicodeVar{..} => Some((Form::Ext, 0x0b)),   // print_unicode
+            &ZOP::PrintUnicodeStr{..} => Some((Form::Op2, 0x1a)),                            // call_2n print_unicode
+            &ZOP::PrintChar{..} => Some((Form::Var, 0x05)),                                  // print_char
+            &ZOP::Print{..} | &ZOP::PrintOps{..} => Some((Form::Op0, 0x02)),                 // print
+            &ZOP::PrintNumVar{..} => Some((Form::Var, 0x06)),                                // print_num
+            &ZOP::PrintVar{..} => Some((Form::Var, 0x19)),                                   // call_vn print_var
+            &ZOP::PrintPaddr{..} => Some((Form::Op1, 0x0d)),                                 // print_paddr
+            &ZOP::PrintAddr{..} => Some((Form::Op1, 0x07)),                                  // print_addr
+            &ZOP::Call1N{..} | &ZOP::Call1NVar{..} => Some((Form::Op1, 0x0f)),               // call_1n
+            &ZOP::Call2NWithAddress{..} | &ZOP::Call2NWithArg{..} => Some((Form::Op2, 0x1a)), // call_2n
+            &ZOP::Call2S{..} => Some((Form::Op2, 0x19)),                                     // call_2s
+            &ZOP::CallVNA2{..} | &ZOP::CallVNA3{..} => Some((Form::Var, 0x19)),              // call_vn
+            &ZOP::CallVSA2{..} | &ZOP::CallVSA3{..} => Some((Form::Var, 0x00)),              // call_vs
+            &ZOP::CallVS2A5{..} | &ZOP::AddTypes{..} => Some((Form::Var, 0x0c)),             // call_vs2
+            &ZOP::Routine{..} | &ZOP::Label{..} => None,
+            &ZOP::Newline => Some((Form::Op0, 0x0b)),                                        // new_line
+            &ZOP::SetColor{..} | &ZOP::SetColorVar{..} => Some((Form::Op2, 0x1b)),           // set_colour
+            &ZOP::SetTrueColor{..} => Some((Form::Ext, 0x0d)),                               // set_true_colour
+            &ZOP::SoundEffect{..} => Some((Form::Var, 0x15)),                                // sound_effect
+            &ZOP::SetTextStyle{..} => Some((Form::Var, 0x11)),                               // set_text_style
+            &ZOP::StoreVariable{..} => Some((Form::Op2, 0x0d)),                              // store
+            &ZOP::StoreW{..} => Some((Form::Var, 0x01)),                                     // storew
+            &ZOP::StoreB{..} | &ZOP::StoreBOperand{..} | &ZOP::SetVarType{..} => Some((Form::Var, 0x02)), // storeb
+            &ZOP::LoadBOperand{..} | &ZOP::GetVarType{..} => Some((Form::Op2, 0x10)),        // loadb
+            &ZOP::PushVar{..} | &ZOP::CopyVarType{..} => Some((Form::Var, 0x08)),            // push
+            &ZOP::PullVar{..} => Some((Form::Var, 0x09)),                                    // pull
+            &ZOP::Inc{..} => Some((Form::Op1, 0x05)),                                        // inc
+            &ZOP::Dec{..} => Some((Form::Op1, 0x06)),                                        // dec
+            &ZOP::Ret{..} => Some((Form::Op1, 0x0b)),                                        // ret
+            &ZOP::Catch{..} => Some((Form::Op0, 0x09)),                                      // catch
+            &ZOP::Throw{..} => Some((Form::Op2, 0x1c)),                                      // throw
+            &ZOP::JE{..} => Some((Form::Op2, 0x01)),                                         // je
+            &ZOP::JNE{..} | &ZOP::JL{..} | &ZOP::JLE{..} => Some((Form::Op2, 0x02)),         // jl
+            &ZOP::JG{..} | &ZOP::JGE{..} => Some((Form::Op2, 0x03)),                         // jg
+            &ZOP::Random{..} => Some((Form::Var, 0x07)),                                     // random
+            &ZOP::ReadChar{..} | &ZOP::ReadCharTimer{..} => Some((Form::Var, 0x16)),         // read_char
+            &ZOP::Add{..} => Some((Form::Op2, 0x14)),                                        // add
+            &ZOP::Sub{..} => Some((Form::Op2, 0x15)),                                        // sub
+            &ZOP::Mul{..} => Some((Form::Op2, 0x16)),                                        // mul
+            &ZOP::Div{..} => Some((Form::Op2, 0x17)),                                        // div
+            &ZOP::Mod{..} => Some((Form::Op2, 0x18)),                                        // mod
+            &ZOP::Or{..} => Some((Form::Op2, 0x08)),                                         // or
+            &ZOP::And{..} => Some((Form::Op2, 0x09)),                                        // and
+            &ZOP::Not{..} => Some((Form::Var, 0x18)),                                        // not (VAR since version 5)
+            &ZOP::Jump{..} => Some((Form::Op1, 0x0c)),                                       // jump
+            &ZOP::LoadW{..} => Some((Form::Op2, 0x0f)),                                      // loadw
+            &ZOP::CopyTable{..} => Some((Form::Var, 0x1d)),                                  // copy_table
+            &ZOP::Save{..} => Some((Form::Ext, 0x00)),                                       // save
+            &ZOP::Restore{..} => Some((Form::Ext, 0x01)),                                    // restore
+            &ZOP::SetCursor{..} | &ZOP::SetCursorOperand{..} => Some((Form::Var, 0x0f)),     // set_cursor
+            &ZOP::UpdateCursorPos | &ZOP::GetCursor{..} => Some((Form::Var, 0x10)),          // get_cursor
+            &ZOP::EraseWindow{..} => Some((Form::Var, 0x0d)),                                // erase_window
+            &ZOP::SplitWindow{..} => Some((Form::Var, 0x0a)),                                // split_window
+            &ZOP::SetWindow{..} => Some((Form::Var, 0x0b)),                                  // set_window
+            &ZOP::EraseLine => Some((Form::Var, 0x0e)),                                      // erase_line
+            &ZOP::Quit => Some((Form::Op0, 0x0a)),                                           // quit
+            &ZOP::Piracy{..} => Some((Form::Op0, 0x0f)),                                     // piracy
+        }
+    }
+
+    #[test]
+    fn test_zop_encoding_forms() {
+        let var = Variable::new(3);
+        let var_op = Operand::new_var(4);
+        let small = Operand::new_const(5);
+        let large = Operand::new_large_const(1000);
+        let label = "label".to_string();
+        let zops = vec![
+            ZOP::Routine{name: "encoding".to_string(), count_variables: 6},
+            ZOP::PrintUnicode{c: 0x263a},
+            ZOP::PrintUnicodeVar{var: var.clone()},
+            ZOP::PrintUnicodeStr{address: Operand::new_pointer(0x1000)},
+            ZOP::PrintChar{var: var.clone()},
+            ZOP::Print{text: "text".to_string()},
+            ZOP::PrintNumVar{variable: var.clone()},
+            ZOP::PrintVar{variable: var.clone()},
+            ZOP::PrintPaddr{address: large.clone()},
+            ZOP::PrintAddr{address: large.clone()},
+            ZOP::PrintOps{text: "text".to_string()},
+            ZOP::Call1N{jump_to_label: label.clone()},
+            ZOP::Call2NWithAddress{jump_to_label: label.clone(), address: label.clone()},
+            ZOP::Call2NWithArg{jump_to_label: label.clone(), arg: var_op.clone()},
+            ZOP::Call1NVar{variable: 3},
+            ZOP::Call2S{jump_to_label: label.clone(), arg: small.clone(), result: var.clone()},
+            ZOP::CallVNA2{jump_to_label: label.clone(), arg1: small.clone(), arg2: var_op.clone()},
+            ZOP::CallVNA3{jump_to_label: label.clone(), arg1: small.clone(), arg2: var_op.clone(), arg3: large.clone()},
+            ZOP::CallVSA2{jump_to_label: label.clone(), arg1: small.clone(), arg2: var_op.clone(), result: var.clone()},
+            ZOP::CallVSA3{jump_to_label: label.clone(), arg1: small.clone(), arg2: var_op.clone(), arg3: large.clone(), result: var.clone()},
+            ZOP::CallVS2A5{jump_to_label: label.clone(), arg1: small.clone(), arg2: var_op.clone(), arg3: large.clone(), arg4: small.clone(), arg5: var_op.clone(), result: var.clone()},
+            ZOP::Newline,
+            ZOP::SetColor{foreground: 2, background: 9},
+            ZOP::SetColorVar{foreground: 3, background: 4},
+            ZOP::SetTrueColor{foreground: large.clone(), background: var_op.clone()},
+            ZOP::SoundEffect{number: small.clone()},
+            ZOP::SetTextStyle{bold: true, reverse: false, monospace: false, italic: true},
+            ZOP::StoreVariable{variable: var.clone(), value: large.clone()},
+            ZOP::StoreW{array_address: large.clone(), index: var.clone(), variable: var.clone()},
+            ZOP::StoreB{array_address: large.clone(), index: var.clone(), variable: var.clone()},
+            ZOP::StoreBOperand{array_address: large.clone(), index: small.clone(), operand: var_op.clone()},
+            ZOP::LoadBOperand{array_address: large.clone(), index: small.clone(), variable: var.clone()},
+            ZOP::PushVar{variable: var.clone()},
+            ZOP::PullVar{variable: var.clone()},
+            ZOP::Inc{variable: 3},
+            ZOP::Dec{variable: 3},
+            ZOP::Ret{value: small.clone()},
+            ZOP::Catch{result: var.clone()},
+            ZOP::Throw{value: small.clone(), frame: var_op.clone()},
+            ZOP::JE{operand1: var_op.clone(), operand2: small.clone(), jump_to_label: label.clone()},
+            ZOP::JNE{operand1: var_op.clone(), operand2: small.clone(), jump_to_label: label.clone()},
+            ZOP::JL{operand1: var_op.clone(), operand2: large.clone(), jump_to_label: label.clone()},
+            ZOP::JLE{operand1: var_op.clone(), operand2: small.clone(), jump_to_label: label.clone()},
+            ZOP::JG{operand1: var_op.clone(), operand2: small.clone(), jump_to_label: label.clone()},
+            ZOP::JGE{operand1: var_op.clone(), operand2: small.clone(), jump_to_label: label.clone()},
+            ZOP::Random{range: small.clone(), variable: var.clone()},
+            ZOP::Save{result: var.clone()},
+            ZOP::Restore{result: var.clone()},
+            ZOP::ReadChar{local_var_id: 3},
+            ZOP::ReadCharTimer{local_var_id: 3, timer: 10, routine: label.clone()},
+            ZOP::AddTypes{operand1: large.clone(), operand2: small.clone(), tmp1: Variable::new(5), tmp2: Variable::new(6), save_variable: var.clone()},
+            ZOP::Add{operand1: var_op.clone(), operand2: small.clone(), save_variable: var.clone()},
+            ZOP::Sub{operand1: var_op.clone(), operand2: large.clone(), save_variable: var.clone()},
+            ZOP::Mul{operand1: small.clone(), operand2: var_op.clone(), save_variable: var.clone()},
+            ZOP::Div{operand1: var_op.clone(), operand2: var_op.clone(), save_variable: var.clone()},
+            ZOP::Mod{operand1: small.clone(), operand2: small.clone(), save_variable: var.clone()},
+            ZOP::Or{operand1: var_op.clone(), operand2: small.clone(), save_variable: var.clone()},
+            ZOP::And{operand1: var_op.clone(), operand2: small.clone(), save_variable: var.clone()},
+            ZOP::Not{operand: var_op.clone(), result: var.clone()},
+            ZOP::Jump{jump_to_label: label.clone()},
+            ZOP::LoadW{array_address: large.clone(), index: var.clone(), variable: var.clone()},
+            ZOP::CopyTable{first: var_op.clone(), second: small.clone(), size: large.clone()},
+            ZOP::SetCursor{line: 1, col: 1},
+            ZOP::SetCursorOperand{row: var_op.clone(), col: small.clone()},
+            ZOP::UpdateCursorPos,
+            ZOP::GetCursor{store_addr: large.clone()},
+            ZOP::EraseWindow{value: -1},
+            ZOP::SplitWindow{lines: 2},
+            ZOP::SetWindow{window: 1},
+            ZOP::EraseLine,
+            ZOP::SetVarType{variable: var.clone(), vartype: Type::Integer},
+            ZOP::CopyVarType{variable: var.clone(), from: var_op.clone()},
+            ZOP::GetVarType{variable: var.clone(), result: Variable::new(5)},
+            ZOP::Quit,
+            ZOP::Piracy{jump_to_label: label.clone()},
+        ];
+
+        let mut zfile = Zfile::new();
+        for zop in zops.iter() {
+            let (_, _, bytes) = zfile.write_zop(zop, false);
+            let (form, opcode) = match expected_form(zop) {
+                Some(expected) => expected,
+                None => continue
+            };
+
+            assert_eq!(decode_form(&bytes), (form, opcode), "{:?} is encoded as {:?}", zop, bytes);
+
+            // operands are given from left to right, only the last ones may be omitted
+            let (form, _) = decode_form(&bytes);
+            if bytes[0] >= 0xc0 || form == Form::Ext {
+                let types = operand_types(&bytes);
+                let given = types.iter().take_while(|&&t| t != 0x03).count();
+                assert!(types[given..].iter().all(|&t| t == 0x03), "{:?} has a gap in its operand types {:?}", zop, types);
+                if form == Form::Op2 {
+                    assert_eq!(given, 2, "{:?} is a 2OP with {} operands", zop, given);
+                }
+            }
+        }
     }
 
     #[test]
     fn test_op_0() {
-        assert_eq!(op::op_0(0x02),vec![0xb2]);
-        assert_eq!(op::op_0(0x04),vec![0xb4]);
-        assert_eq!(op::op_0(0x08),vec![0xb8]);
-        assert_eq!(op::op_0(0x03),vec![0xb3]);
+        assert_eq!(encode(|bytes| op::op_0(0x02, bytes)),vec![0xb2]);
+        assert_eq!(encode(|bytes| op::op_0(0x04, bytes)),vec![0xb4]);
+        assert_eq!(encode(|bytes| op::op_0(0x08, bytes)),vec![0xb8]);
+        assert_eq!(encode(|bytes| op::op_0(0x03, bytes)),vec![0xb3]);
     }
 
     #[test]
     fn test_op_not() {
-            assert_eq!(op::op_not(&Operand::new_var(1),&Variable::new(2)),vec![0xf8,0xbf,0x01,0x02]);
+            assert_eq!(encode(|bytes| op::op_not(&Operand::new_var(1),&Variable::new(2), bytes)),vec![0xf8,0xbf,0x01,0x02]);
     }
 
     #[test]
     fn test_op_get_cursor() {
-            assert_eq!(op::op_get_cursor(&Operand::new_var(1)),vec![0xf0,0xbf,0x01]);
+            assert_eq!(encode(|bytes| op::op_get_cursor(&Operand::new_var(1), bytes)),vec![0xf0,0xbf,0x01]);
     }
 
     #[test]
     fn test_op_set_cursor_operand() {
-            assert_eq!(op::op_set_cursor_operand(&Operand::new_var(1), &Operand::new_var(2)),vec![0xef,0xaf,0x01,0x02]);
+            assert_eq!(encode(|bytes| op::op_set_cursor_operand(&Operand::new_var(1), &Operand::new_var(2), bytes)),vec![0xef,0xaf,0x01,0x02]);
     }
 
     #[test]
     fn test_op_erase_line() {
-            assert_eq!(op::op_erase_line(),vec![0xee,0x7f,0x01]);
+            assert_eq!(encode(|bytes| op::op_erase_line(bytes)),vec![0xee,0x7f,0x01]);
+    }
+
+    #[test]
+    fn test_add_types_aliasing() {
+        let var = |id| Variable::new(id);
+        let op = |id| Operand::new_var(id);
+
+        assert_eq!(add_types_aliasing(&op(1), &op(2), &var(3), &var(4), &var(5)), None);
+        // the result may replace an operand
+        assert_eq!(add_types_aliasing(&op(1), &op(2), &var(3), &var(4), &var(1)), None);
+        assert_eq!(add_types_aliasing(&Operand::new_const(1), &op(2), &var(3), &var(4), &var(2)), None);
+
+        assert!(add_types_aliasing(&op(1), &op(2), &var(3), &var(3), &var(5)).is_some());
+        assert!(add_types_aliasing(&op(1), &op(2), &var(3), &var(4), &var(3)).is_some());
+        assert!(add_types_aliasing(&op(1), &op(2), &var(3), &var(4), &var(4)).is_some());
+        assert!(add_types_aliasing(&op(1), &op(2), &var(2), &var(4), &var(5)).is_some());
+        assert!(add_types_aliasing(&op(1), &op(2), &var(3), &var(1), &var(5)).is_some());
+    }
+
+    #[test]
+    fn test_operand_problem() {
+        let store = |value: i16| ZOP::StoreBOperand{array_address: Operand::new_pointer(0x100), index: Operand::new_const(0), operand: Operand::new_large_const(value)};
+        assert_eq!(operand_problem(&store(255), 0), None);
+        assert!(operand_problem(&store(256), 0).is_some());
+        assert!(operand_problem(&store(-1), 0).is_some());
+
+        // the stack and the globals always exist
+        let add = ZOP::Add{operand1: Operand::new_var(3), operand2: Operand::new_var(16), save_variable: Variable::new(0)};
+        assert_eq!(operand_problem(&add, 3), None);
+        assert!(operand_problem(&add, 2).is_some());
+        assert!(operand_problem(&ZOP::Inc{variable: 1}, 0).is_some());
+        assert_eq!(operand_problem(&ZOP::Inc{variable: 255}, 0), None);
+
+        assert_eq!(operand_problem(&ZOP::SetColor{foreground: 12, background: 2}, 0), None);
+        assert!(operand_problem(&ZOP::SetColor{foreground: 2, background: 13}, 0).is_some());
+        assert!(operand_problem(&ZOP::SetWindow{window: 2}, 0).is_some());
+        assert_eq!(operand_problem(&ZOP::EraseWindow{value: -2}, 0), None);
+        assert!(operand_problem(&ZOP::EraseWindow{value: 3}, 0).is_some());
+        assert!(operand_problem(&ZOP::SetCursor{line: 0, col: 1}, 0).is_some());
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "the local variable 2 does not exist")]
+    fn test_write_zop_checks_operands() {
+        let mut zfile = Zfile::new();
+        zfile.write_zop(&ZOP::Routine{name: "small".to_string(), count_variables: 1}, false);
+        zfile.write_zop(&ZOP::Inc{variable: 1}, false);
+        zfile.write_zop(&ZOP::Inc{variable: 2}, false);
+    }
+
+    #[test]
+    fn test_add_types_distinct_variables() {
+        let mut zfile: Zfile = Zfile::new();
+        zfile.routine_locals = 4;
+        let (_, _, bytes) = zfile.write_zop(&ZOP::AddTypes{operand1: Operand::new_var(1), operand2: Operand::new_var(2),
+            tmp1: Variable::new(3), tmp2: Variable::new(4), save_variable: Variable::new(1)}, false);
+        assert!(bytes.len() > 0);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "Invalid AddTypes: the result variable 3 is also a temporary")]
+    fn test_add_types_result_is_temporary() {
+        // all variables exist, so only the aliasing check can fail
+        let mut zfile: Zfile = Zfile::new();
+        zfile.routine_locals = 4;
+        zfile.write_zop(&ZOP::AddTypes{operand1: Operand::new_var(1), operand2: Operand::new_var(2),
+            tmp1: Variable::new(3), tmp2: Variable::new(4), save_variable: Variable::new(3)}, false);
+    }
+
+    #[test]
+    fn test_op_split_and_set_window() {
+        assert_eq!(encode(|bytes| op::op_split_window(3, bytes)),vec![0xEA,0x7F,0x03]);
+        assert_eq!(encode(|bytes| op::op_split_window_operand(&Operand::new_var(0), bytes)),vec![0xEA,0xBF,0x00]);
+        assert_eq!(encode(|bytes| op::op_set_window(1, bytes)),vec![0xEB,0x7F,0x01]);
+    }
+
+    #[test]
+    fn test_erase_lower_window_keeps_upper_window() {
+        let mut zfile: Zfile = Zfile::new();
+        let mut bytes: Vec<u8> = vec![];
+        for zop in vec![
+            ZOP::SplitWindow{lines: 2},
+            ZOP::SetWindow{window: 1},
+            ZOP::Print{text: "status".to_string()},
+            ZOP::SetWindow{window: 0},
+            ZOP::EraseWindow{value: 0},
+        ] {
+            let (_, _, zop_bytes) = zfile.write_zop(&zop, false);
+            bytes.extend(zop_bytes);
+        }
+
+        // only the lower window is erased, there is no erase of window 1/-1/-2 and no unsplit
+        let erase_lower = encode(|bytes| op::op_erase_window(0, bytes));
+        assert!(bytes.ends_with(&erase_lower));
+        for value in vec![1, -1, -2] {
+            let erase = encode(|bytes| op::op_erase_window(value, bytes));
+            assert!(!bytes.windows(erase.len()).any(|window| window == &erase[..]));
+        }
+        assert_eq!(bytes.windows(3).filter(|window| *window == &encode(|bytes| op::op_split_window(2, bytes))[..]).count(), 1);
+    }
+
+    #[test]
+    fn test_erase_all_windows_restores_split() {
+        let mut zfile: Zfile = Zfile::new();
+        let lines = Operand::new_pointer(zfile.split_window_pos);
+        let (_, _, bytes) = zfile.write_zop(&ZOP::SplitWindow{lines: 2}, false);
+        assert_eq!(bytes, [encode(|bytes| op::op_split_window(2, bytes)),
+            encode(|bytes| op::op_storeboperand(&lines, &Operand::new_const(0), &Operand::new_const(2), bytes))].concat());
+
+        // the lines of the split that ran last are read at runtime
+        let (_, _, bytes) = zfile.write_zop(&ZOP::EraseWindow{value: -1}, false);
+        assert_eq!(bytes, [encode(|bytes| op::op_erase_window(-1, bytes)),
+            encode(|bytes| op::op_loadb(&lines, &Operand::new_const(0), &Variable::new(0), bytes)),
+            encode(|bytes| op::op_split_window_operand(&Operand::new_var(0), bytes))].concat());
+
+        // the other windows are erased without restoring anything
+        let (_, _, bytes) = zfile.write_zop(&ZOP::EraseWindow{value: -2}, false);
+        assert_eq!(bytes, encode(|bytes| op::op_erase_window(-2, bytes)));
+    }
+
+    #[test]
+    #[should_panic(expected = "invalid window")]
+    fn test_set_invalid_window() {
+        let mut zfile: Zfile = Zfile::new();
+        zfile.write_zop(&ZOP::SetWindow{window: 2}, false);
     }
 }
@@ -14,16 +14,29 @@ pub static ALPHABET: [char; 78] = [
     ' ', '\n', '0', '1', '2', '3', '4', '5', '6', '7', '8', '9', '.',
     ',', '!', '?', '_', '#', '\'','"', '/', '\\','-', ':', '(', ')'];
 
+/// The number of characters in the unicode translation table.
+///
+/// The table maps the ZSCII codes 155 to 251, which are 97 codes. The header reserves a byte for
+/// the length and two bytes for each character.
+pub const UNICODE_TABLE_SIZE: usize = 97;
 
 /// Encodes a string to z-characters
 /// and returns the length of the used bytes.
-/// TODO: Only works with lower case letters.
+///
+/// Characters above 126 are encoded with their ZSCII code from the unicode translation table.
+/// Characters that are not in the first `UNICODE_TABLE_SIZE` entries of the table have no ZSCII
+/// code and are encoded as `?`, they have to be printed with `print_unicode` instead.
 ///
 /// # Examples
 ///
-/// ```ignore
-/// let data = Bytes{bytes: Vec::new()};
-/// let byteLength = data.encode("hello");
+/// ```
+/// use zwreec::backend::zcode::zbytes::Bytes;
+/// use zwreec::backend::zcode::ztext;
+///
+/// let mut data = Bytes{bytes: Vec::new()};
+/// let byte_length = ztext::encode(&mut data, "hello", &Vec::new());
+/// assert_eq!(byte_length, 4);
+/// assert_eq!(ztext::decode(&data.bytes, &Vec::new()), "hello");
 /// ```
 pub fn encode(data: &mut Bytes, content: &str, unicode_table: &Vec<u16>) -> u16 {
     let zchars: Vec<u8> = string_to_zchar(content, unicode_table);
@@ -62,16 +75,75 @@ pub fn encode(data: &mut Bytes, content: &str, unicode_table: &Vec<u16>) -> u16
     data.bytes.len() as u16
 }
 
+/// Decodes z-characters as written by `encode` back to a string.
+///
+/// Characters above 154 are looked up in the unicode translation table. Decoding stops after the
+/// word with the end bit set.
+pub fn decode(bytes: &[u8], unicode_table: &Vec<u16>) -> String {
+    let mut zchars: Vec<u8> = Vec::new();
+    for word in bytes.chunks(2) {
+        if word.len() < 2 {
+            break;
+        }
+        let two_bytes: u16 = (word[0] as u16) << 8 | word[1] as u16;
+        for position in 0..3 {
+            zchars.push((two_bytes >> (10 - position * 5)) as u8 & 0x1f);
+        }
+        if two_bytes & 0x8000 != 0 {
+            break;
+        }
+    }
+
+    let mut content = String::new();
+    let mut alphabet: usize = 0;
+    let mut i = 0;
+    while i < zchars.len() {
+        let zchar = zchars[i];
+        i += 1;
+        match (alphabet, zchar) {
+            (_, 0) => content.push(' '),
+            (_, 4) => { alphabet = 1; continue; },
+            (_, 5) => { alphabet = 2; continue; },
+            (2, 6) => {
+                // 10 bit character, padding at the end of the string may cut it off
+                if i + 2 > zchars.len() {
+                    break;
+                }
+                let byte = (zchars[i] as u16) << 5 | zchars[i + 1] as u16;
+                i += 2;
+                if byte >= 155 && ((byte - 155) as usize) < unicode_table.len() {
+                    if let Some(character) = ::std::char::from_u32(unicode_table[(byte - 155) as usize] as u32) {
+                        content.push(character);
+                    }
+                } else {
+                    content.push(byte as u8 as char);
+                }
+            },
+            (2, 7) => content.push('\n'),
+            (_, zchar) if zchar >= 6 => content.push(ALPHABET[alphabet * 26 + zchar as usize - 6]),
+            _ => {}
+        }
+        alphabet = 0;
+    }
+    content
+}
+
 /// Reads the content and converts it to a Z-ASCII vector.
+///
+/// Lowercase letters and the space take one z-char. Uppercase letters are shifted to A1 with a 4,
+/// digits, punctuation and the newline to A2 with a 5, so they take two. Since version 3 the shift
+/// only applies to the next z-char and there is no shift lock, so a run of uppercase letters
+/// needs a shift for every letter. All other characters take four z-chars as 10 bit ZSCII codes.
 fn string_to_zchar(content: &str, unicode_table: &Vec<u16>) -> Vec<u8> {
     //let string_bytes = content.to_string().into_bytes();
     let mut zchars: Vec<u8> = Vec::new();
 
     for character in content.chars() {
+        let character = if has_zscii_code(character, unicode_table) { character } else { '?' };
 
         let mut byte: u8 = character as u8;
         let alpha_index = pos_in_alpha(byte as u8);
-        if character as u16 <= 126 && alpha_index != -1 {
+        if character as u32 <= 126 && alpha_index != -1 {
 
             if byte == 0x0A {
                 // newline
@@ -83,7 +155,7 @@ fn string_to_zchar(content: &str, unicode_table: &Vec<u16>) -> Vec<u8> {
             } else {
                 if alpha_index > 51 {
                     // in A2
-                    zchars.push(0x05);  
+                    zchars.push(0x05);
                     zchars.push(alpha_index as u8 % 26 + 6);
                 } else if alpha_index < 26 {
                     // in A0
@@ -92,7 +164,7 @@ fn string_to_zchar(content: &str, unicode_table: &Vec<u16>) -> Vec<u8> {
                     // in A1
                     zchars.push(0x04);
                     zchars.push(alpha_index as u8 % 26 + 6);
-                } 
+                }
             }
         } else {
             // not in alphabet or unicode
@@ -104,7 +176,7 @@ fn string_to_zchar(content: &str, unicode_table: &Vec<u16>) -> Vec<u8> {
             zchars.push(0x06);
 
             //let mut byte: u8;
-            if character as u16 <= 126 {
+            if character as u32 <= 126 {
                 // not in alphabet, but still ascii
                 byte = character as u8;
             } else {
@@ -120,12 +192,25 @@ fn string_to_zchar(content: &str, unicode_table: &Vec<u16>) -> Vec<u8> {
     zchars
 }
 
+/// Returns whether the character is ASCII or in the part of the unicode table that has ZSCII
+/// codes.
+fn has_zscii_code(character: char, unicode_table: &Vec<u16>) -> bool {
+    if character as u32 <= 126 {
+        return true;
+    }
+    if character as u32 > 0xFFFF {
+        return false;
+    }
+    let index = pos_in_unicode(character as u16, unicode_table);
+    index >= 0 && (index as usize) < UNICODE_TABLE_SIZE
+}
+
 /// Shifts the z-char in a 2 bytes-array to the right position.
 ///
 /// shift_length has 3 possibilities: 10, 5, 0.
 /// a z-char use 5 bytes.
 /// look into two bytes:
-/// ```ignore
+/// ```text
 /// ____byte one____ ____byte two____
 /// 1 2 3 4 5 6  7 8 1 2 3  4 5 6 7 8
 ///   ^          ^          1
@@ -182,7 +267,8 @@ fn pos_to_index(position: usize) -> usize {
 
 #[cfg(test)]
 mod tests {
-    use super::{pos_in_alpha, pos_to_index, shift, string_to_zchar};
+    use super::{decode, encode, pos_in_alpha, pos_to_index, shift, string_to_zchar, UNICODE_TABLE_SIZE};
+    use super::super::zbytes::Bytes;
 
     #[test]
     fn test_pos_in_alpha() {
@@ -199,6 +285,82 @@ mod tests {
         assert_eq!(pos_to_index(5), 2);
     }
 
+    #[test]
+    fn test_decode() {
+        let unicode_table: Vec<u16> = vec!['€' as u16];
+        for text in &["hello", "Hello World", "i am a string, please test me", "line\nbreak [1] {2} 5%", "nasty char: €", "ab"] {
+            let mut data = Bytes{bytes: Vec::new()};
+            encode(&mut data, text, &unicode_table);
+            assert_eq!(decode(&data.bytes, &unicode_table), text.to_string());
+        }
+    }
+
+    #[test]
+    fn test_encode() {
+        // a table with 'é' at index 0, the last index with a ZSCII code and one index too far
+        let mut table: Vec<u16> = vec!['é' as u16];
+        for i in 1..UNICODE_TABLE_SIZE as u16 - 1 {
+            table.push(0x100 + i);
+        }
+        table.push('ñ' as u16);
+        table.push('ß' as u16);
+        assert_eq!(table.len(), UNICODE_TABLE_SIZE + 1);
+
+        let cases: Vec<(&str, Vec<u8>)> = vec![
+            // A0: zchars 6 7 8
+            ("abc", vec![0x98, 0xe8]),
+            // A1: shift 4, zchar 6, padding 5
+            ("A", vec![0x90, 0xc5]),
+            // A2: shift 5, zchar 9, padding 5
+            ("1", vec![0x95, 0x25]),
+            // space is zchar 0 and newline is A2 zchar 7
+            ("a b", vec![0x98, 0x07]),
+            ("\n", vec![0x94, 0xe5]),
+            // ascii without a zchar: 10 bit escape 5 6 with '[' = 91 = 2 27
+            ("[", vec![0x14, 0xc2, 0xec, 0xa5]),
+            // first table character: 155 = 4 27
+            ("é", vec![0x14, 0xc4, 0xec, 0xa5]),
+            // last table character with a ZSCII code: 251 = 7 27
+            ("ñ", vec![0x14, 0xc7, 0xec, 0xa5]),
+            // beyond 251 and not in the table at all: '?' is A2 zchar 21
+            ("ß", vec![0x96, 0xa5]),
+            ("€", vec![0x96, 0xa5]),
+            // mixed: 6 | 5 6 4 27 | 5 9
+            ("aé1", vec![0x18, 0xa6, 0x13, 0x65, 0xa4, 0xa5]),
+        ];
+
+        for (text, expected) in cases.into_iter() {
+            let mut data = Bytes{bytes: Vec::new()};
+            encode(&mut data, text, &table);
+            assert!(data.bytes == expected, "{:?} was encoded as {:?}, expected {:?}", text, data.bytes, expected);
+        }
+    }
+
+    #[test]
+    fn test_encode_shifts() {
+        let table: Vec<u16> = Vec::new();
+
+        // H e l l o , _ W o r l d !
+        // 4 13 | 10 | 17 | 17 | 20 | 5 19 | 0 | 4 28 | 20 | 23 | 17 | 9 | 5 20 and one padding 5
+        let mut data = Bytes{bytes: Vec::new()};
+        assert_eq!(encode(&mut data, "Hello, World!", &table), 12);
+        assert_eq!(data.bytes, vec![0x11, 0xaa, 0x46, 0x34, 0x16, 0x60, 0x13, 0x94, 0x5e, 0x29, 0x96, 0x85]);
+
+        // every character of A1 and A2 needs exactly one shift, only those without a z-char are escaped
+        for code in 32..127u8 {
+            let character = code as char;
+            let expected = match character {
+                'a'...'z' | ' ' => 1,
+                'A'...'Z' | '0'...'9' => 2,
+                '.' | ',' | '!' | '?' | '_' | '#' | '\'' | '"' | '/' | '\\' | '-' | ':' | '(' | ')' => 2,
+                _ => 4
+            };
+            let zchars = string_to_zchar(&character.to_string(), &table);
+            assert!(zchars.len() == expected, "{:?} takes {} z-chars instead of {}", character, zchars.len(), expected);
+        }
+        assert_eq!(string_to_zchar("\n", &table), vec![5, 7]);
+    }
+
     #[test]
     fn test_shift() {
         assert_eq!(shift(6,2), 6);
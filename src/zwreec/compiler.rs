@@ -0,0 +1,97 @@
+//! A reusable compiler for edit-compile loops.
+//!
+//! `zwreec::compile` processes an input once. Editors and watch modes compile the same story
+//! over and over, so `Compiler` keeps the configuration, the parsed passages and the last
+//! generated Z-Code between runs and skips the work if the source did not change.
+//!
+//! # Example
+//!
+//! ```
+//! # extern crate zwreec;
+//! let cfg = zwreec::config::Config::default_config();
+//! let mut compiler = zwreec::compiler::Compiler::new(cfg);
+//!
+//! assert_eq!(compiler.compile_str("::Start\nHello World"), 0);
+//! assert_eq!(compiler.passages().len(), 1);
+//!
+//! // after an edit
+//! compiler.compile_str("::Start\nHello [[World]]\n::World\nHi!");
+//! assert_eq!(compiler.passages().len(), 2);
+//! assert_eq!(compiler.zcode()[0], 8);
+//! ```
+
+use std::io::{Cursor, Read};
+
+use backend::codegen;
+use config::Config;
+use frontend::ast::ASTNode;
+
+/// Holds the state of the last compilation.
+pub struct Compiler {
+    cfg: Config,
+    source: Option<Vec<u8>>,
+    passages: Vec<ASTNode>,
+    zcode: Vec<u8>,
+    errors: usize,
+}
+
+impl Compiler {
+    /// Creates a new compiler that uses `cfg` for every compilation.
+    pub fn new(cfg: Config) -> Compiler {
+        Compiler {
+            cfg: cfg,
+            source: None,
+            passages: Vec::new(),
+            zcode: Vec::new(),
+            errors: 0,
+        }
+    }
+
+    /// Compiles the Twee source read from `input` and returns the number of errors reported.
+    ///
+    /// If the source is the same as in the last call, the previous result is kept. Errors are
+    /// only counted in force mode, otherwise the first error panics just like `zwreec::compile`.
+    /// Every compilation counts its errors from 0, so `max_errors` applies to each of them.
+    pub fn compile<R: Read>(&mut self, input: &mut R) -> usize {
+        let mut source = Vec::new();
+        if let Err(why) = input.read_to_end(&mut source) {
+            panic!("Could not read input: {}", why);
+        }
+
+        if self.source.as_ref() == Some(&source) {
+            debug!("Source did not change, skipping compilation");
+            return self.errors;
+        }
+
+        // every compilation looks up the widgets and keeps the source for --embed-source again
+        let mut cfg = self.cfg.with_fresh_errors();
+        let mut cursor = ::prepare_input(&mut cfg, &mut Cursor::new(&source[..]));
+        self.passages = ::build_ast(&cfg, &mut cursor);
+        self.zcode.clear();
+        codegen::generate_zcode(&cfg, self.passages.clone().into_iter(), &mut self.zcode);
+
+        self.errors = cfg.error_count();
+        self.source = Some(source);
+        self.errors
+    }
+
+    /// Compiles the Twee source in `source`, see `compile`.
+    pub fn compile_str(&mut self, source: &str) -> usize {
+        self.compile(&mut Cursor::new(source.as_bytes()))
+    }
+
+    /// Returns the passages parsed by the last compilation.
+    pub fn passages(&self) -> &[ASTNode] {
+        &self.passages
+    }
+
+    /// Returns the Z-Code generated by the last compilation.
+    pub fn zcode(&self) -> &[u8] {
+        &self.zcode
+    }
+
+    /// Returns the number of errors reported by the last compilation.
+    pub fn errors(&self) -> usize {
+        self.errors
+    }
+}
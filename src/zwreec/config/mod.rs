@@ -35,6 +35,17 @@
 //! }
 //! ```
 //!
+//! Programs that do not read a command line, like tests or tools embedding the compiler, can
+//! pass the arguments directly:
+//!
+//! ```
+//! use zwreec::config::Config;
+//!
+//! let cfg = Config::from_args(&["--tab-width", "4", "-N", "easter-egg"]).unwrap();
+//! assert_eq!(cfg.tab_width, 4);
+//! assert!(!cfg.easter_egg);
+//! ```
+//!
 //! # Appending this module (Development)
 //!
 //! To add new compiler flags for the compiler, you need to change three parts
@@ -79,43 +90,19 @@
 //! #   fn main() { let cfg = default_config(); }
 //!     ```
 //!
-//! 2. Parse your flag in `from_matches()`
+//! 2. Add your flag to the `features!` list
 //!
-//!     `from_matches` uses the Options `-F` and `-N` to enable or disable boolean flags.
-//!     Therefore, if you added a new boolean flag, you can now simply add it to the match
-//!     statement.
+//!     `from_matches` uses the Options `-F` and `-N` to enable or disable boolean flags. The
+//!     names, defaults and help texts of these flags are listed once in the `features!`
+//!     invocation, which builds the `FEATURES` table used for matching the arguments, for
+//!     setting the defaults in `default_config()` and for the feature list of the usage.
 //!
 //!     ```ignore
-//!     for s in matches.opt_strs("F") {
-//!         match s.as_ref() {
-//!             "easter-egg" => {
-//!                  cfg.easter_egg = true;
-//!                  debug!("enabled easter-egg");
-//!             },
-//!             "italics" => {
-//!                 cfg.italics = true;
-//!                 debug!("enabbled italics");
-//!             },
-//!             _ => {
-//!                 error!("Cannot enable feature {} - feature not known.", s);
-//!             }
-//!         }
-//!     }
-//!
-//!     for s in matches.opt_strs("N") {
-//!         match s.as_ref() {
-//!             "easter-egg" => {
-//!                 cfg.easter_egg = false;
-//!                 debug!("disabled easter-egg");
-//!             },
-//!             "italics" => {
-//!                 cfg.italics = false;
-//!                 debug:("disalbed italics");
-//!             }
-//!             _ => {
-//!                 error!("Cannot disable feature {} - feature not known.", s);
-//!             }
-//!         }
+//!     features! {
+//!         easter_egg => "easter-egg", true,
+//!             "Enables the generation of easter egg code";
+//!         italics => "italics", true,
+//!             "Prints emphasized text in italics";
 //!     }
 //!     ```
 //!
@@ -123,36 +110,80 @@
 //!
 //!     If you need to add an option that is *not* a boolean, you will need to append the struct
 //!     and `default_config()` accordingly (e.g. `pub notaflag: String,`). Then you need to add a new
-//!     `getopts::Option` to `zwreec_options()`:
-//!
-//!     ```ignore
-//! pub fn zwreec_options(mut opts: getopts::Options) -> getopts::Options {
-//!     opts.optmulti("F", "feature", "", "FEAT");
-//!     opts.optmulti("N", "no-feature", "enable or disable a feature (can occur multiple times).
-//!                         List of supported features (default):
-//!                             easter-egg (enabled)", "FEAT");
-//!     opts.optflag("e", "generate-sample-zcode", "writes out a sample zcode file, input file is not used and can be omitted");
-//!
-//!     opts.optopt("n", "notaflag", "notaflag", "SOMETHING");
+//!     entry to the `OPTIONS` table used by `zwreec_options()`, choosing the section of the
+//!     verbose usage it is listed in:
 //!
-//!     opts
-//! }
+//!     ```
+//! #   enum UsageSection { General }
+//! #   enum OptionKind { Opt }
+//! #   struct OptionInfo { section: UsageSection, short: &'static str, long: &'static str,
+//! #       hint: &'static str, kind: OptionKind, description: &'static str }
+//! #   static OPTIONS: &'static [OptionInfo] = &[
+//!     OptionInfo { section: UsageSection::General, short: "n", long: "notaflag", hint: "SOMETHING",
+//!         kind: OptionKind::Opt, description: "notaflag" },
+//! #   ];
+//! #   fn main() { assert_eq!(OPTIONS[0].long, "notaflag"); }
 //!     ```
 //!
 //!     Now you can append `Config::from_matches()` to analyse the provided matches for your new
 //!     option and set the `Config` accordingly:
 //!
-//!     ```ignore
-//!     if let s = matches.opt_str("n") {
+//!     ```
+//! #   extern crate getopts;
+//! #   struct Config { notaflag: String }
+//! #   fn main() {
+//! #   let mut opts = getopts::Options::new();
+//! #   opts.optopt("n", "notaflag", "notaflag", "SOMETHING");
+//! #   let matches = opts.parse(&["-n".to_string(), "value".to_string()]).unwrap();
+//! #   let mut cfg = Config { notaflag: String::new() };
+//!     if let Some(s) = matches.opt_str("n") {
 //!         cfg.notaflag = s;
 //!     }
+//! #   assert_eq!(cfg.notaflag, "value");
+//! #   }
 //!     ```
 //!
 //! 4. Now you can use the new flag inside the compiler.
+//!
+//! # Features of library users
+//!
+//! Programs using zwreec as a library can add features of their own without changing the
+//! `Config`. A `FeatureSpec` sets the fields of the `Config` it stands for, e.g. a `retro`
+//! feature for old interpreters, and is passed to `Config::from_matches_with_features()` and
+//! `zwreec_usage_with_features()`:
+//!
+//! ```
+//! # extern crate getopts;
+//! # extern crate zwreec;
+//! use zwreec::config::{Config, FeatureSpec};
+//!
+//! # fn main() {
+//! let retro = FeatureSpec::new("retro", "Targets interpreters without colours and unicode", |cfg, enabled| {
+//!     cfg.no_colours = enabled;
+//!     cfg.no_unicode = enabled;
+//! });
+//!
+//! let opts = zwreec::config::zwreec_options(getopts::Options::new());
+//! let matches = opts.parse(&["-F".to_string(), "retro".to_string()]).unwrap();
+//! let cfg = Config::from_matches_with_features(&matches, &[retro]);
+//! assert!(cfg.no_colours && cfg.no_unicode);
+//! # }
+//! ```
 use getopts;
 
+use std::error::Error;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::vec::Vec;
 
+use frontend::lexer::SourceMap;
+use frontend::plugins::MacroRegistry;
+use utils::diagnostics::{Level, Warning};
+use utils::error::ErrorRecord;
+
 
 /// Represents the configuration for the compiler.
 ///
@@ -172,32 +203,152 @@ use std::vec::Vec;
 /// ```
 #[derive(Clone)]
 pub struct Config {
+    /// Refill an empty deck in `draw()` instead of returning 0
+    pub auto_reshuffle: bool,
+
     /// Force a bright background and dark text
     pub bright_mode: bool,
 
+    /// Collapse runs of more than one blank line into a single blank line
+    pub collapse_blank_lines: bool,
+
+    /// Enable debugging functions like `addressof()`
+    pub debug_build: bool,
+
+    /// Start every passage in bold text
+    pub default_bold: bool,
+
+    /// Start every passage in italic text
+    pub default_italic: bool,
+
+    /// Start every passage in monospace text
+    pub default_mono: bool,
+
+    /// Start every passage with inverted colours
+    pub default_reverse: bool,
+
+    /// The story format whose formatting markers, macro aliases and line breaks the lexer
+    /// follows
+    pub dialect: Dialect,
+
     /// Add easter egg to compiler
     pub easter_egg: bool,
 
+    /// Append the Twee source compressed behind the story, see `backend::zcode::archive`
+    pub embed_source: bool,
+
+    /// Data appended to the output file with their names, listed in an index behind them
+    pub embedded_blobs: Vec<(String, Vec<u8>)>,
+
+    /// How errors are printed, as text or as one JSON object per line
+    pub error_format: ErrorFormat,
+
     /// Force compilation despite errors
     pub force: bool,
 
     /// Force generation of print_uncode opcodes
     pub force_unicode: bool,
 
+    /// Use the first passage as start passage if there is no `Start` passage
+    pub first_passage_fallback: bool,
+
     /// Divide memory usage by 2
     pub half_memory: bool,
 
+    /// Count the allocations and frees and print the usage of the heap when `H` is pressed at
+    /// the links
+    pub heap_stats: bool,
+
+    /// The levels of warnings set with `-A`, `-W` and `-D`, later ones win (see
+    /// `utils::diagnostics`)
+    pub lint_levels: Vec<(Warning, Level)>,
+
+    /// What the story does after the start passage returned
+    pub main_loop: MainLoop,
+
+    /// The plugin macros and their handlers
+    pub macros: MacroRegistry,
+
+    /// Abort after this many errors were reported in force mode (0 disables the limit)
+    pub max_errors: u32,
+
     /// Disable colours
     pub no_colours: bool,
 
+    /// Print THE END instead of quitting immediately after a passage without links
+    pub no_dead_end_quit: bool,
+
     /// Disable unicode support
     pub no_unicode: bool,
 
+    /// Run the garbage collector behind assignments once the heap is three quarters full
+    pub oom_collect: bool,
+
+    /// The message printed when the heap is exhausted
+    pub oom_message: String,
+
+    /// Show the pedantic warnings of the `Info` level
+    pub pedantic: bool,
+
+    /// Generate the same output for the same input (e.g. a fixed `buildtime()`)
+    pub reproducible: bool,
+
+    /// The character of the line printed above the links
+    pub separator_char: char,
+
+    /// The Twee source, set by `compile` if `embed_source` is set
+    pub source: Vec<u8>,
+
+    /// The name of the input file in the errors of `--error-format json`, `None` for stdin and
+    /// several merged inputs
+    pub source_name: Option<String>,
+
+    /// The size of the Twee source in bytes, set by `compile` to reserve the memory of the
+    /// Z-Code in advance (0 does not reserve any)
+    pub source_size: usize,
+
+    /// The map of the Twee source to byte offsets, set by `compile` for the byte offsets of
+    /// the errors and warnings
+    pub source_map: Option<Arc<SourceMap>>,
+
+    /// The length of the line above the links (0 fits it to the screen width)
+    pub separator_length: u16,
+
+    /// The name of the start passage, overrides `Start` and the `start` entry of `StoryData`
+    pub start_passage: Option<String>,
+
+    /// The number of columns a tab advances to in the locations of diagnostics (1 counts a tab
+    /// as a single column)
+    pub tab_width: u32,
+
+    /// Pause before clearing the screen for the next passage
+    pub transition_pause: bool,
+
     /// Enable Formatting Simulation
     pub unsupported_formatting: bool,
 
     /// Instruct compiler to run these test-cases
     pub test_cases: Vec<TestCase>,
+
+    /// Warn about passages needing more than this many bytes (0 disables the warning)
+    pub warn_passage_size: u32,
+
+    /// The names of the widgets of the story, the lexer reads `<<name>>` as a call of one
+    /// (see `frontend::widgets`)
+    pub widgets: Vec<String>,
+
+    /// The Z-Machine version of the output file (7 or 8)
+    pub zcode_version: u8,
+
+    /// Number of errors reported so far, shared between all clones of this config
+    error_count: Arc<AtomicUsize>,
+
+    /// The errors reported so far, shared like `error_count`
+    reported_errors: Arc<Mutex<Vec<ErrorRecord>>>,
+
+    /// The warnings of the lexer with their passage, line and message, which codegen hands to
+    /// its `Diagnostics`
+    lexer_warnings: Arc<Mutex<Vec<(Warning, String, Option<u64>, String)>>>,
 }
 
 impl Config {
@@ -210,16 +361,144 @@ impl Config {
     /// let cfg = Config::default_config();
     /// ```
     pub fn default_config() -> Config {
-        Config{
+        // the defaults of the features are set from `FEATURES` below
+        let mut cfg = Config{
+            auto_reshuffle: false,
             bright_mode: false,
+            collapse_blank_lines: true,
+            debug_build: false,
+            default_bold: false,
+            default_italic: false,
+            default_mono: false,
+            default_reverse: false,
+            dialect: Dialect::Legacy,
             easter_egg: true,
+            embed_source: false,
+            embedded_blobs: Vec::new(),
+            error_format: ErrorFormat::Text,
             force: false,
             force_unicode: false,
+            first_passage_fallback: false,
             half_memory: false,
+            heap_stats: false,
+            lint_levels: Vec::new(),
+            main_loop: MainLoop::Links,
+            macros: MacroRegistry::new(),
+            max_errors: 50,
             no_colours: false,
+            no_dead_end_quit: false,
             no_unicode: false,
+            oom_collect: false,
+            oom_message: "MALLOC-FAIL".to_string(),
+            pedantic: false,
+            reproducible: false,
+            separator_char: '-',
+            source: Vec::new(),
+            source_name: None,
+            source_size: 0,
+            source_map: None,
+            separator_length: 39,
+            start_passage: None,
+            tab_width: 1,
+            transition_pause: false,
             unsupported_formatting: false,
             test_cases: Vec::new(),
+            warn_passage_size: 0,
+            widgets: Vec::new(),
+            zcode_version: 8,
+            error_count: Arc::new(AtomicUsize::new(0)),
+            reported_errors: Arc::new(Mutex::new(Vec::new())),
+            lexer_warnings: Arc::new(Mutex::new(Vec::new())),
+        };
+        for feature in FEATURES.iter() {
+            if let Some(value) = cfg.feature_mut(feature.name) {
+                *value = feature.default_on;
+            }
+        }
+        cfg
+    }
+
+    /// Counts a reported error and returns whether the `max_errors` limit was exceeded.
+    ///
+    /// The counter is shared between all clones of a `Config`, so errors reported by the
+    /// different stages of the compiler chain add up.
+    pub fn report_error(&self) -> bool {
+        let count = self.error_count.fetch_add(1, Ordering::SeqCst) + 1;
+        self.max_errors != 0 && count >= self.max_errors as usize
+    }
+
+    /// Returns the number of errors reported so far.
+    pub fn error_count(&self) -> usize {
+        self.error_count.load(Ordering::SeqCst)
+    }
+
+    /// Returns a clone of the config that counts and keeps its errors separately, for another
+    /// compilation with the same options.
+    pub fn with_fresh_errors(&self) -> Config {
+        let mut cfg = self.clone();
+        cfg.error_count = Arc::new(AtomicUsize::new(0));
+        cfg.reported_errors = Arc::new(Mutex::new(Vec::new()));
+        cfg.lexer_warnings = Arc::new(Mutex::new(Vec::new()));
+        cfg
+    }
+
+    /// Keeps a warning of the lexer, which has no `Diagnostics` of its own.
+    pub fn record_lexer_warning(&self, warning: Warning, passage: &str, line: Option<u64>, message: String) {
+        self.lexer_warnings.lock().unwrap().push((warning, passage.to_string(), line, message));
+    }
+
+    /// Returns the warnings of the lexer kept so far and forgets them.
+    pub fn take_lexer_warnings(&self) -> Vec<(Warning, String, Option<u64>, String)> {
+        ::std::mem::replace(&mut *self.lexer_warnings.lock().unwrap(), Vec::new())
+    }
+
+    /// Keeps a reported error, so it can be looked at after the compilation.
+    pub fn record_error(&self, record: ErrorRecord) {
+        self.reported_errors.lock().unwrap().push(record);
+    }
+
+    /// Returns the errors reported so far, by all clones of this config.
+    pub fn reported_errors(&self) -> Vec<ErrorRecord> {
+        self.reported_errors.lock().unwrap().clone()
+    }
+
+    /// Returns the offset of the byte at the `(line, column)` location of a token, if the
+    /// config has the `source_map`.
+    pub fn byte_offset(&self, location: (u64, u64)) -> Option<u64> {
+        self.source_map.as_ref().map(|source_map| source_map.location(location).byte_offset)
+    }
+
+    /// Disables everything zwreec adds to a story without the story asking for it, the easter
+    /// egg and the debugging functions like `addressof()`.
+    ///
+    /// zwreec never turns the transcript on, so there is no option for it.
+    pub fn disable_optional_content(&mut self) {
+        self.easter_egg = false;
+        self.debug_build = false;
+    }
+
+    /// Returns a `Config` for command line arguments without the name of the program.
+    ///
+    /// The arguments are parsed with the options of `zwreec_options()`, the error of `getopts`
+    /// is returned as text.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use zwreec::config::Config;
+    ///
+    /// let cfg = Config::from_args(&["--force", "--main-loop", "none"]).unwrap();
+    /// assert!(cfg.force);
+    /// assert!(Config::from_args(&["--no-such-option"]).is_err());
+    /// ```
+    pub fn from_args<S: AsRef<str>>(args: &[S]) -> Result<Config, String> {
+        let args: Vec<String> = args.iter().map(|arg| arg.as_ref().to_string()).collect();
+        match zwreec_options(getopts::Options::new()).parse(&args) {
+            Ok(matches) => {
+                try!(check_lint_ids(&matches));
+                Ok(Config::from_matches(&matches))
+            },
+            Err(f) => Err(f.to_string()),
         }
     }
 
@@ -244,6 +523,14 @@ impl Config {
     /// let cfg = zwreec::config::Config::from_matches(&matches);
     /// ```
     pub fn from_matches(matches: &getopts::Matches) -> Config {
+        Config::from_matches_with_features(matches, &[])
+    }
+
+    /// Returns a `Config` like `from_matches()`, but `-F` and `-N` also accept the names of
+    /// `features`.
+    ///
+    /// The features of zwreec win over those with the same name in `features`.
+    pub fn from_matches_with_features(matches: &getopts::Matches, features: &[FeatureSpec]) -> Config {
         // load defaults
         let mut cfg = Config::default_config();
 
@@ -251,110 +538,451 @@ impl Config {
             cfg.test_cases.push(TestCase::ZcodeBackend);
         }
 
+        for s in matches.opt_strs("test-case") {
+            match TestCase::from_name(&s) {
+                Some(case) => cfg.test_cases.push(case),
+                None => {
+                    error!("Unknown test case {} - use zcode-backend, expression-folding, string-routines or inject-unattributed.", s);
+                }
+            }
+        }
+
         if matches.opt_present("f") {
             cfg.force = true;
         }
 
-        // TODO: Find a way to make these two loops somewhat less.. repetitive
-        for s in matches.opt_strs("F") {
-            match s.as_ref() {
-                "bright-mode" => {
-                     cfg.bright_mode = true;
-                     debug!("enabled bright-mode");
-                },
-                "easter-egg" => {
-                     cfg.easter_egg = true;
-                     debug!("enabled easter-egg");
-                },
-                "force-unicode" => {
-                     cfg.force_unicode = true;
-                     debug!("enabled force-unicode");
+        if matches.opt_present("reproducible") {
+            cfg.reproducible = true;
+        }
+
+        if matches.opt_present("embed-source") {
+            cfg.embed_source = true;
+        }
+
+        if matches.opt_present("debug-build") {
+            cfg.debug_build = true;
+        }
+
+        if let Some(s) = matches.opt_str("start-passage") {
+            debug!("set start-passage to {}", s);
+            cfg.start_passage = Some(s);
+        }
+
+        if matches.opt_present("first-passage-fallback") {
+            cfg.first_passage_fallback = true;
+        }
+
+        if let Some(s) = matches.opt_str("main-loop") {
+            cfg.main_loop = match s.as_ref() {
+                "links" => MainLoop::Links,
+                "none" => MainLoop::Quit,
+                _ => MainLoop::Passage(s.clone()),
+            };
+            debug!("set main-loop to {:?}", cfg.main_loop);
+        }
+
+        if let Some(s) = matches.opt_str("dialect") {
+            match Dialect::from_name(&s) {
+                Some(dialect) => {
+                    cfg.dialect = dialect;
+                    debug!("set dialect to {:?}", dialect);
                 },
-                "no-colours" => {
-                     cfg.no_colours = true;
-                     debug!("enabled no-colours");
+                None => {
+                    error!("Unknown dialect {} - use legacy, harlowe or sugarcube.", s);
+                }
+            }
+        }
+
+        if let Some(s) = matches.opt_str("error-format") {
+            match ErrorFormat::from_name(&s) {
+                Some(format) => {
+                    cfg.error_format = format;
+                    debug!("set error-format to {:?}", format);
                 },
-                "half-memory" => {
-                     cfg.half_memory = true;
-                     debug!("enabled half-memory");
+                None => {
+                    error!("Unknown error format {} - use text or json.", s);
+                }
+            }
+        }
+
+        if let Some(s) = matches.opt_str("max-errors") {
+            match s.parse::<u32>() {
+                Ok(n) => {
+                    cfg.max_errors = n;
+                    debug!("set max-errors to {}", n);
                 },
-                "no-unicode" => {
-                     cfg.no_unicode = true;
-                     debug!("enabled no-unicode");
+                Err(_) => {
+                    error!("Cannot set max-errors to {} - not a number.", s);
+                }
+            }
+        }
+
+        if let Some(s) = matches.opt_str("warn-passage-size") {
+            match s.parse::<u32>() {
+                Ok(n) => {
+                    cfg.warn_passage_size = n;
+                    debug!("set warn-passage-size to {}", n);
                 },
-                "unsupported-formatting" => {
-                    cfg.unsupported_formatting = true;
-                    debug!("enabled unsupported-formatting");
+                Err(_) => {
+                    error!("Cannot set warn-passage-size to {} - not a number.", s);
+                }
+            }
+        }
+
+        // the stronger levels come later, so -D wins over -W, which wins over -A
+        for &(option, level) in [("A", Level::Allow), ("W", Level::Warning), ("D", Level::Error)].iter() {
+            for s in matches.opt_strs(option) {
+                match Warning::from_id(&s) {
+                    Some(warning) => {
+                        cfg.lint_levels.push((warning, level));
+                        debug!("set level of {} to {}", s, level.name());
+                    },
+                    None => {
+                        error!("Unknown warning {} - run --list-lints to see the warnings.", s);
+                    }
+                }
+            }
+        }
+
+        if matches.opt_present("pedantic") {
+            cfg.pedantic = true;
+            debug!("enabled pedantic warnings");
+        }
+
+        if let Some(s) = matches.opt_str("tab-width") {
+            match s.parse::<u32>() {
+                Ok(n) if n > 0 => {
+                    cfg.tab_width = n;
+                    debug!("set tab-width to {}", n);
                 },
                 _ => {
-                    error!("Cannot enable feature {} - feature not known.", s);
+                    error!("Cannot set tab-width to {} - not a positive number.", s);
                 }
             }
         }
 
-        for s in matches.opt_strs("N") {
-            match s.as_ref() {
-                "bright-mode" => {
-                     cfg.bright_mode = false;
-                     debug!("disabled bright-mode");
-                },
-                "easter-egg" => {
-                    cfg.easter_egg = false;
-                    debug!("disabled easter-egg");
-                },
-                "force-unicode" => {
-                     cfg.force_unicode = false;
-                     debug!("disabled force-unicode");
-                },
-                "no-colours" => {
-                     cfg.no_colours = false;
-                     debug!("disabled no-colours");
+        if let Some(s) = matches.opt_str("oom-message") {
+            if s.is_empty() {
+                error!("Cannot set oom-message to an empty text.");
+            } else {
+                debug!("set oom-message to {}", s);
+                cfg.oom_message = s;
+            }
+        }
+
+        if let Some(s) = matches.opt_str("separator") {
+            let mut chars = s.chars();
+            match (chars.next(), chars.next()) {
+                (Some(c), None) if c >= ' ' && c <= '~' => {
+                    cfg.separator_char = c;
+                    debug!("set separator to {}", c);
                 },
-                "half-memory" => {
-                     cfg.half_memory = false;
-                     debug!("disabled half-memory");
+                _ => {
+                    error!("Cannot set separator to {} - not a single printable ASCII character.", s);
+                }
+            }
+        }
+
+        if let Some(s) = matches.opt_str("separator-length") {
+            match s.parse::<u16>() {
+                Ok(n) => {
+                    cfg.separator_length = n;
+                    debug!("set separator-length to {}", n);
                 },
-                "no-unicode" => {
-                     cfg.no_unicode = false;
-                     debug!("disabled no-unicode");
+                Err(_) => {
+                    error!("Cannot set separator-length to {} - not a number.", s);
+                }
+            }
+        }
+
+        for s in matches.opt_strs("embed") {
+            match s.find('=') {
+                Some(pos) if pos > 0 && pos < 256 && s[..pos].bytes().all(|b| b < 0x80) => {
+                    let (name, path) = (&s[..pos], &s[pos + 1..]);
+                    let mut blob: Vec<u8> = vec![];
+                    match File::open(Path::new(path)).and_then(|mut file| file.read_to_end(&mut blob)) {
+                        Ok(_) => {
+                            debug!("embedding {} with {} bytes of {}", name, blob.len(), path);
+                            cfg.embedded_blobs.push((name.to_string(), blob));
+                        },
+                        Err(why) => {
+                            error!("Cannot embed {} - reading {} failed: {}", name, path, Error::description(&why));
+                        }
+                    }
                 },
-                "unsupported-formatting" => {
-                    cfg.unsupported_formatting = false;
-                    debug!("disabled unsupported-formatting");
+                _ => {
+                    error!("Cannot embed {} - use NAME=PATH with an ASCII name of at most 255 characters.", s);
                 }
+            }
+        }
+
+        if let Some(s) = matches.opt_str("zcode-version") {
+            match s.as_ref() {
+                "7" => cfg.zcode_version = 7,
+                "8" => cfg.zcode_version = 8,
                 _ => {
-                    error!("Cannot disable feature {} - feature not known.", s);
+                    error!("Cannot generate Z-Code version {} - only versions 7 and 8 are supported.", s);
+                }
+            }
+            debug!("set zcode-version to {}", cfg.zcode_version);
+        }
+
+        // disabling comes second, so -N wins over -F
+        for &(option, enabled, action) in [("F", true, "enable"), ("N", false, "disable")].iter() {
+            for s in matches.opt_strs(option) {
+                if cfg.set_feature(&s, enabled, features) {
+                    debug!("{}d {}", action, s);
+                } else {
+                    error!("{}", unknown_feature_message(action, &s, features));
                 }
             }
         }
 
         cfg
     }
+
+    /// Enables or disables the feature called `name`, a feature of zwreec or one of `features`.
+    /// Returns false if there is no such feature.
+    fn set_feature(&mut self, name: &str, enabled: bool, features: &[FeatureSpec]) -> bool {
+        if let Some(value) = self.feature_mut(name) {
+            *value = enabled;
+            return true;
+        }
+        match features.iter().find(|feature| feature.name == name) {
+            Some(feature) => {
+                (feature.setter)(self, enabled);
+                true
+            },
+            None => false
+        }
+    }
+}
+
+/// A boolean option of `Config` that can be enabled with `-F` and disabled with `-N`.
+pub struct FeatureInfo {
+    /// The name used on the command line
+    pub name: &'static str,
+
+    /// Whether the feature is enabled in `Config::default_config()`
+    pub default_on: bool,
+
+    /// The help text shown in the verbose usage
+    pub description: &'static str,
+}
+
+/// A feature of a library user for `-F` and `-N`, see `Config::from_matches_with_features()`.
+pub struct FeatureSpec {
+    /// The name used on the command line
+    pub name: String,
+
+    /// The help text shown in the verbose usage
+    pub description: String,
+
+    /// Sets the `Config` for the feature being enabled (true) or disabled (false)
+    setter: Box<Fn(&mut Config, bool)>,
+}
+
+impl FeatureSpec {
+    /// Creates a feature that calls `setter` when it is enabled or disabled.
+    pub fn new<F>(name: &str, description: &str, setter: F) -> FeatureSpec where F: Fn(&mut Config, bool) + 'static {
+        FeatureSpec { name: name.to_string(), description: description.to_string(), setter: Box::new(setter) }
+    }
+}
+
+/// Builds `FEATURES` and `Config::feature_mut()` from one list, so a feature only has to be
+/// added in a single place.
+macro_rules! features {
+    ($($field:ident => $name:tt, $default:expr, $description:expr;)*) => {
+        /// All features, in the order they are listed in the verbose usage.
+        pub static FEATURES: &'static [FeatureInfo] = &[
+            $(FeatureInfo { name: $name, default_on: $default, description: $description },)*
+        ];
+
+        impl Config {
+            /// Returns the field backing the feature called `name`.
+            fn feature_mut(&mut self, name: &str) -> Option<&mut bool> {
+                match name {
+                    $($name => Some(&mut self.$field),)*
+                    _ => None
+                }
+            }
+
+            /// Returns whether the feature called `name` is enabled, `None` for an unknown name.
+            pub fn feature(&self, name: &str) -> Option<bool> {
+                match name {
+                    $($name => Some(self.$field),)*
+                    _ => None
+                }
+            }
+        }
+    }
+}
+
+features! {
+    auto_reshuffle => "auto-reshuffle", false,
+        "Refills a deck created with shuffle() once all of its values were drawn. By default draw() returns 0 for an empty deck";
+    bright_mode => "bright-mode", false,
+        "Enables a bright background and a dark text color";
+    collapse_blank_lines => "collapse-blank-lines", true,
+        "Prints runs of blank lines as a single blank line, except in passages tagged with verbatim. Use <<br>> for intended gaps";
+    default_bold => "default-bold", false,
+        "Starts every passage in bold text. Every passage resets the text style, so styles of the links or the previous passage do not carry over";
+    default_italic => "default-italic", false,
+        "Starts every passage in italic text";
+    default_mono => "default-monospace", false,
+        "Starts every passage in monospace text";
+    default_reverse => "default-reverse", false,
+        "Starts every passage with inverted foreground and background colours";
+    easter_egg => "easter-egg", true,
+        "Enables the generation of easter egg code. Enter the secret combination in your Z-machine interpreter to activate the easter egg. This requires some extra space - disable this if your output file is getting too large";
+    force_unicode => "force-unicode", false,
+        "Force the generation of print_unicode opcodes every time a unicode character is encountered. This disables the generation of the unicode translation table";
+    half_memory => "half-memory", false,
+        "Cut down space for static variable strings and heap in order to have binaries probably smaller than 64kB as only DZIP32.exe on DOS can handle larger files, but DZIP.exe has a limit on 64kB. If your file is still large, consider disabling the easter-egg flag";
+    heap_stats => "heap-stats", false,
+        "Counts the allocations and the blocks freed by the garbage collector and prints them with the high water mark of the heap when H is pressed at the links. An exhausted heap always prints the high water mark";
+    no_colours => "no-colours", false,
+        "Suppress generation of set_colour and set_text_style opcodes and disable the colour bit in the second byte of the header - this is required for some old interpreters like for DZIP on DOS/Atari";
+    no_dead_end_quit => "no-dead-end-quit", false,
+        "Prints THE END and waits for a key after a passage without links, instead of quitting immediately";
+    no_unicode => "no-unicode", false,
+        "Replaces opcode print_unicode with print_char to let it run on interpreters without unicode support like JZIP";
+    oom_collect => "oom-collect", false,
+        "Runs the garbage collector behind an assignment once the heap is three quarters full, instead of only at the end of a passage";
+    transition_pause => "transition-pause", false,
+        "Pauses half a second before the screen is cleared for the next passage. Interpreters without timed input clear the screen immediately";
+    unsupported_formatting => "unsupported-formatting", false,
+        "Tries to simulate formatting that is not available in the Z-machine like underscore, strikethrough as well as sub- and superscript by adding indicators around them. The default behavior is to discard those characters.";
+}
+
+/// The control loop the story runs once the start passage returned.
+#[derive(PartialEq,Clone,Debug)]
+pub enum MainLoop {
+    /// Show the links of the current passage and follow the chosen one (default)
+    Links,
+
+    /// Quit as soon as the start passage returned
+    Quit,
+
+    /// Call the passage with this name over and over again
+    Passage(String),
+}
+
+/// The story format a story was written for.
+///
+/// The dialects differ in the formatting markers, the other names of macros and whether a line
+/// break right after a macro is printed. The lexer looks the differences up in the tables of
+/// `frontend::lexer::dialect_rules`.
+#[derive(PartialEq,Clone,Copy,Debug)]
+pub enum Dialect {
+    /// The behavior of zwreec before there were dialects, which reads Twee 1.4 and the
+    /// Sugarcane and Jonah formats of Twine 1 (default)
+    Legacy,
+
+    /// Close to Harlowe, only the markup shared with Twee 1.4 is recognized
+    Harlowe,
+
+    /// Close to SugarCube, line breaks after macros are swallowed
+    SugarCube,
+}
+
+impl Dialect {
+    /// Returns the dialect with the name used by `--dialect`.
+    pub fn from_name(name: &str) -> Option<Dialect> {
+        match name {
+            "legacy" => Some(Dialect::Legacy),
+            "harlowe" => Some(Dialect::Harlowe),
+            "sugarcube" => Some(Dialect::SugarCube),
+            _ => None
+        }
+    }
+}
+
+/// How the errors of the compiler are printed.
+#[derive(PartialEq,Clone,Copy,Debug)]
+pub enum ErrorFormat {
+    /// Log messages for people (default)
+    Text,
+
+    /// One JSON object per error on stderr with the keys `file`, `line`, `col`, `byte_offset`,
+    /// `severity`, `message` and `code`, for editors. A successful compile ends with an object with the key
+    /// `capabilities`, see `CapabilityReport::to_json`
+    Json,
+}
+
+impl ErrorFormat {
+    /// Returns the format with the name used by `--error-format`.
+    pub fn from_name(name: &str) -> Option<ErrorFormat> {
+        match name {
+            "text" => Some(ErrorFormat::Text),
+            "json" => Some(ErrorFormat::Json),
+            _ => None
+        }
+    }
 }
 
-// TODO: If this stays only one Test Case, enum should be removed
 /// The Type used to define backend tests for the compiler.
-#[derive(PartialEq,Clone)]
+#[derive(PartialEq,Clone,Debug)]
 pub enum TestCase {
     /// Skips the normal compiler chain and builds an example zcode file by
     /// using every opcode.
     ZcodeBackend,
+
+    /// Checks that constant expressions are evaluated at compile time.
+    ExpressionFolding,
+
+    /// Builds an example zcode file that calls the string routines.
+    StringRoutines,
+
+    /// Writes a routine and bytes which belong to neither a passage nor the runtime behind the
+    /// passages and checks that `attest` flags both.
+    InjectUnattributed,
+}
+
+impl TestCase {
+    /// Returns the test case with the name used by `--test-case`.
+    pub fn from_name(name: &str) -> Option<TestCase> {
+        match name {
+            "zcode-backend" => Some(TestCase::ZcodeBackend),
+            "expression-folding" => Some(TestCase::ExpressionFolding),
+            "string-routines" => Some(TestCase::StringRoutines),
+            "inject-unattributed" => Some(TestCase::InjectUnattributed),
+            _ => None
+        }
+    }
 }
 
 
+/// Checks that the ids of `-A`, `-W` and `-D` are known warnings.
+///
+/// `Config::from_matches()` skips unknown ids, so this has to be called on the matches first.
+///
+/// # Example
+///
+/// ```
+/// # extern crate getopts;
+/// # extern crate zwreec;
+/// let opts = zwreec::config::zwreec_options(getopts::Options::new());
+/// let matches = opts.parse(&["-D", "no-such-warning"]).unwrap();
+/// assert!(zwreec::config::check_lint_ids(&matches).is_err());
+/// ```
+pub fn check_lint_ids(matches: &getopts::Matches) -> Result<(), String> {
+    for option in ["A", "W", "D"].iter() {
+        for s in matches.opt_strs(option) {
+            if Warning::from_id(&s).is_none() {
+                return Err(format!("Unknown warning {} for -{} - run --list-lints to see the warnings.", s, option));
+            }
+        }
+    }
+    Ok(())
+}
+
 /// Appends a `getopts::Options` with compiler specific flags.
 ///
 /// The method `Config::from_matches()` looks for very specific `getopts::Matches`.
 /// This function takes a `getopts::Options` to append it with Options required
-/// by `from_matches`. It currently adds three fields:
-///
-/// ```ignore
-/// opts.optmulti("F", "feature", "", "FEAT");
-/// opts.optmulti("N", "no-feature", "enable or disable a feature (can occur multiple times).
-///                     List of supported features (default):
-///                         easter-egg (enabled)", "FEAT");
-/// opts.optflag("e", "generate-sample-zcode", "writes out a sample zcode file, input file is not used and can be omitted");
-/// ```
+/// by `from_matches`. The options are listed in the `OPTIONS` table, which is also used to
+/// group the verbose usage into sections.
 ///
 /// # Example
 ///
@@ -400,66 +1028,282 @@ pub enum TestCase {
 /// As you can see, `options()` returns your own command line options, which are then conditionally
 /// expanded by using `zwreec_options()`.
 pub fn zwreec_options(mut opts: getopts::Options) -> getopts::Options {
-    opts.optflag("f", "force", "Try ignoring any errors that may occur and generate Z-Code anyways.
-        This feature is highly unstable and may lead to corrupt output files.");
-    opts.optmulti("F", "feature", "", "FEAT");
-    opts.optmulti("N", "no-feature", "Enable or disable a feature (can occur multiple times).
-        For more information about the supported features run --help with -v and see the feature
-        list at the end of the output", "FEAT");
-    opts.optflag("e", "generate-sample-zcode", "Write out a sample zcode file, input file is not used and can be omitted");
+    for option in OPTIONS.iter() {
+        match option.kind {
+            OptionKind::Flag => opts.optflag(option.short, option.long, option.description),
+            OptionKind::Opt => opts.optopt(option.short, option.long, option.description, option.hint),
+            OptionKind::Multi => opts.optmulti(option.short, option.long, option.description, option.hint),
+        };
+    }
 
     opts
 }
 
+/// Returns the arguments of the options in the text of a config file, like
+/// `scaffold::ZWREEC_CONFIG`.
+///
+/// Every line holds an option and its value, which is the rest of the line and may contain
+/// spaces. A `#` starts a comment up to the end of the line.
+///
+/// # Example
+///
+/// ```
+/// let args = zwreec::config::config_file_args("# the defaults\n--oom-message Out of memory\n--pedantic # style\n");
+/// assert_eq!(args, vec!["--oom-message", "Out of memory", "--pedantic"]);
+/// ```
+pub fn config_file_args(text: &str) -> Vec<String> {
+    let mut args: Vec<String> = vec![];
+    for line in text.lines() {
+        let line = match line.find('#') {
+            Some(comment) => &line[..comment],
+            None => line
+        };
+        for arg in line.trim().splitn(2, char::is_whitespace) {
+            if !arg.trim().is_empty() {
+                args.push(arg.trim().to_string());
+            }
+        }
+    }
+    args
+}
+
+/// The sections of the verbose usage.
+#[derive(PartialEq,Clone,Copy,Debug)]
+enum UsageSection {
+    /// Options of the program and options that change the compilation as a whole
+    General,
+
+    /// Options that change the generated file
+    Output,
+
+    /// The levels of the warnings
+    Warnings,
+
+    /// `-F`/`-N` and the list of features
+    Features,
+
+    /// Options for debugging the compiler
+    Debugging,
+}
+
+/// How an option is passed to `getopts`.
+enum OptionKind {
+    Flag,
+    Opt,
+    Multi,
+}
+
+/// A command line option added by `zwreec_options()`.
+struct OptionInfo {
+    section: UsageSection,
+    short: &'static str,
+    long: &'static str,
+    hint: &'static str,
+    description: &'static str,
+    kind: OptionKind,
+}
+
+/// All options added by `zwreec_options()`, in the order they are shown in their section.
+static OPTIONS: &'static [OptionInfo] = &[
+    OptionInfo { section: UsageSection::General, short: "f", long: "force", hint: "", kind: OptionKind::Flag,
+        description: "Try ignoring any errors that may occur and generate Z-Code anyways. Passages that fail to compile only print the error. Exits with 2 if any error was ignored. This feature is highly unstable and may lead to corrupt output files." },
+    OptionInfo { section: UsageSection::General, short: "", long: "max-errors", hint: "N", kind: OptionKind::Opt,
+        description: "Stop after N errors were reported while using --force (default 50, 0 means no limit)" },
+    OptionInfo { section: UsageSection::General, short: "", long: "error-format", hint: "FORMAT", kind: OptionKind::Opt,
+        description: "Print errors as 'text' (default) or 'json', one object per line on stderr with the file, line, col, severity, message and code of the error" },
+    OptionInfo { section: UsageSection::General, short: "", long: "start-passage", hint: "NAME", kind: OptionKind::Opt,
+        description: "Start the story with this passage instead of 'Start' or the start passage named in StoryData" },
+    OptionInfo { section: UsageSection::General, short: "", long: "first-passage-fallback", hint: "", kind: OptionKind::Flag,
+        description: "Start with the first passage if the story has no 'Start' passage" },
+    OptionInfo { section: UsageSection::General, short: "", long: "main-loop", hint: "MODE", kind: OptionKind::Opt,
+        description: "What to do after the start passage: 'links' waits for a link to be chosen (default), 'none' quits and any other value is the name of a passage that is called in a loop. Use 'none' or a passage for stories that drive themselves with <<display>>" },
+    OptionInfo { section: UsageSection::General, short: "", long: "dialect", hint: "FORMAT", kind: OptionKind::Opt,
+        description: "Read the story like the story format FORMAT: 'legacy' (default, Twee 1.4), 'harlowe' or 'sugarcube'. The formats differ in the formatting markers, the other names of macros like <<=>> for <<print>> and whether a line break after a macro is printed" },
+    OptionInfo { section: UsageSection::General, short: "", long: "tab-width", hint: "N", kind: OptionKind::Opt,
+        description: "Count a tab as advancing to the next multiple of N columns in the locations of errors and warnings, like an editor shows them (default 1)" },
+    OptionInfo { section: UsageSection::Output, short: "", long: "zcode-version", hint: "VERSION", kind: OptionKind::Opt,
+        description: "Z-Machine version of the output file, 7 or 8 (default 8). Version 7 files can be up to 320kB and suit interpreters without version 8 support, version 8 files can be up to 512kB" },
+    OptionInfo { section: UsageSection::Output, short: "", long: "reproducible", hint: "", kind: OptionKind::Flag,
+        description: "Generate identical output for identical input. buildtime() returns a fixed date instead of the time of compilation" },
+    OptionInfo { section: UsageSection::Output, short: "", long: "oom-message", hint: "TEXT", kind: OptionKind::Opt,
+        description: "Print TEXT instead of MALLOC-FAIL when the story runs out of memory, e.g. in the language of the story. With an error passage it is the value of $errorMessage" },
+    OptionInfo { section: UsageSection::Output, short: "", long: "separator", hint: "CHAR", kind: OptionKind::Opt,
+        description: "Print the line above the links and the question of confirm() with this character (default -)" },
+    OptionInfo { section: UsageSection::Output, short: "", long: "separator-length", hint: "N", kind: OptionKind::Opt,
+        description: "Print the line above the links N characters long (default 39). 0 fits it to the screen width of the interpreter and falls back to 39 if the interpreter does not tell the width" },
+    OptionInfo { section: UsageSection::Output, short: "", long: "embed", hint: "NAME=PATH", kind: OptionKind::Multi,
+        description: "Append the file PATH as a data blob called NAME to the output (can occur multiple times). An index behind the blobs lists their names, addresses and lengths, its address divided by 8 is the seventh word of the header extension table" },
+    OptionInfo { section: UsageSection::Output, short: "", long: "embed-source", hint: "", kind: OptionKind::Flag,
+        description: "Append the Twee source compressed behind the story, so the story file carries its own source. Interpreters do not load it, zwreec::extract_source reads it back" },
+    OptionInfo { section: UsageSection::Output, short: "", long: "warn-passage-size", hint: "N", kind: OptionKind::Opt,
+        description: "Warn about every passage whose code and strings need more than N bytes. Run with -v to see the size of all passages" },
+    OptionInfo { section: UsageSection::Warnings, short: "A", long: "allow", hint: "LINT", kind: OptionKind::Multi,
+        description: "Do not show the warning with the id LINT (can occur multiple times). <<zwreec-allow LINT>> allows it in a part of the story only" },
+    OptionInfo { section: UsageSection::Warnings, short: "W", long: "warn", hint: "LINT", kind: OptionKind::Multi,
+        description: "Show the warning with the id LINT, also if it is pedantic (can occur multiple times)" },
+    OptionInfo { section: UsageSection::Warnings, short: "D", long: "deny", hint: "LINT", kind: OptionKind::Multi,
+        description: "Report the warning with the id LINT as an error, which stops the compilation unless --force is set (can occur multiple times). -D wins over -W and -A" },
+    OptionInfo { section: UsageSection::Warnings, short: "", long: "pedantic", hint: "", kind: OptionKind::Flag,
+        description: "Also show the pedantic warnings, which are hints that are often intended. Run zwreec --list-lints to see the warnings and their levels" },
+    OptionInfo { section: UsageSection::Features, short: "F", long: "feature", hint: "FEAT", kind: OptionKind::Multi,
+        description: "Enable a feature (can occur multiple times)" },
+    OptionInfo { section: UsageSection::Features, short: "N", long: "no-feature", hint: "FEAT", kind: OptionKind::Multi,
+        description: "Disable a feature (can occur multiple times). Run --help with -v to see the list of features" },
+    OptionInfo { section: UsageSection::Debugging, short: "e", long: "generate-sample-zcode", hint: "", kind: OptionKind::Flag,
+        description: "Write out a sample zcode file, input file is not used and can be omitted" },
+    OptionInfo { section: UsageSection::Debugging, short: "", long: "test-case", hint: "CASE", kind: OptionKind::Multi,
+        description: "Run a backend test case instead of compiling (can occur multiple times): zcode-backend, expression-folding, string-routines or inject-unattributed" },
+    OptionInfo { section: UsageSection::Debugging, short: "", long: "debug-build", hint: "", kind: OptionKind::Flag,
+        description: "Enable functions for debugging the compiler, e.g. addressof($var) to print the address stored in a variable" },
+];
+
+/// The column the descriptions of options start at.
+const DESCRIPTION_COLUMN: usize = 32;
+
 /// Prints a usage
 ///
 /// This takes your options and prints a usage for those options.
-/// It also includes zwreec_options and a feature list if a verbose usage was requested.
-pub fn zwreec_usage(verbose: bool, mut opts: getopts::Options, brief: &str) -> String {
-    use std::fmt::format;
-
-    if verbose {
-        opts = zwreec_options(opts);
-    }
-
-    let options_usage = opts.usage(brief);
-
-    let features_usage = if verbose {
-        "List of supported features (default value in parenthesis)
-    bright-mode (disabled)
-        Enables a bright background and a dark text color
-    easter-egg (enabled)
-        Enables the generation of easter egg code. Enter the secret combination
-        in your Z-machine interpreter to activate the easter egg. This requires
-        some extra space - disable this if your output file is getting too large
-    force-unicode (disabled)
-        Force the generation of print_unicode opcodes every time a unicode
-        character is encountered. This disables the generation of the unicode
-        translation table
-    half-memory (disabled)
-        Cut down space for static variable strings and heap in order to have
-        binaries probably smaller than 64kB as only DZIP32.exe on DOS can handle
-        larger files, but DZIP.exe has a limit on 64kB. If your file is still
-        large, consider disabling the easter-egg flag
-    no-colours (disabled)
-        Suppress generation of set_colour and set_text_style opcodes and disable
-        the colour bit in the second byte of the header - this is required for
-        some old interpreters like for DZIP on DOS/Atari
-    no-unicode (disabled)
-        Replaces opcode print_unicode with print_char to let it run on
-        interpreters without unicode support like JZIP
-    unsupported-formatting (disabled)
-        Tries to simulate formatting that is not available in the Z-machine like
-        underscore, strikethrough as well as sub- and superscript by adding
-        indicators around them. The default behavior is to discard those
-        characters.
-    "
+/// If a verbose usage was requested, it also includes the options of `zwreec_options()` and the
+/// list of features, grouped into sections and wrapped to the width of the terminal.
+pub fn zwreec_usage(verbose: bool, opts: getopts::Options, brief: &str) -> String {
+    usage_with_width(verbose, opts, brief, &[], terminal_width())
+}
+
+/// Prints a usage like `zwreec_usage()`, the verbose usage also lists `features`.
+pub fn zwreec_usage_with_features(verbose: bool, opts: getopts::Options, brief: &str, features: &[FeatureSpec]) -> String {
+    usage_with_width(verbose, opts, brief, features, terminal_width())
+}
+
+/// Returns the width of the terminal as set in `$COLUMNS`, or 80.
+fn terminal_width() -> usize {
+    match ::std::env::var("COLUMNS").ok().and_then(|columns| columns.parse::<usize>().ok()) {
+        Some(width) if width > 0 => width,
+        _ => 80
+    }
+}
+
+/// Builds the usage of `zwreec_usage()` for a terminal that is `width` columns wide.
+fn usage_with_width(verbose: bool, opts: getopts::Options, brief: &str, features: &[FeatureSpec], width: usize) -> String {
+    if !verbose {
+        return format!("{}\nAdditional help:\n    --help -v           Print the full set of options zwreec accepts\n", opts.usage(brief));
+    }
+
+    // getopts lists the options of the caller, which form the general section
+    let mut usage = opts.usage(brief).replacen("Options:", "General:", 1);
+    let sections = [
+        (UsageSection::General, ""),
+        (UsageSection::Output, "Output:"),
+        (UsageSection::Warnings, "Warnings:"),
+        (UsageSection::Features, "Features:"),
+        (UsageSection::Debugging, "Debugging:"),
+    ];
+    for &(section, title) in sections.iter() {
+        if !title.is_empty() {
+            usage.push_str(&format!("\n{}\n", title));
+        }
+        for option in OPTIONS.iter().filter(|option| option.section == section) {
+            usage.push_str(&format_option(option, width));
+        }
+        if section == UsageSection::Features {
+            usage.push_str("\n    List of supported features (default value in parenthesis)\n");
+            for feature in FEATURES.iter() {
+                let default = if feature.default_on { "enabled" } else { "disabled" };
+                usage.push_str(&format!("    {} ({})\n", feature.name, default));
+                for line in wrap_text(feature.description, width.saturating_sub(8)) {
+                    usage.push_str(&format!("        {}\n", line));
+                }
+            }
+            for feature in features.iter() {
+                usage.push_str(&format!("    {}\n", feature.name));
+                for line in wrap_text(&feature.description, width.saturating_sub(8)) {
+                    usage.push_str(&format!("        {}\n", line));
+                }
+            }
+        }
+    }
+
+    usage
+}
+
+/// Formats an option like getopts does, but with the description wrapped to `width`.
+fn format_option(option: &OptionInfo, width: usize) -> String {
+    let mut head = if option.short.is_empty() {
+        format!("        --{}", option.long)
     } else {
-        "Additional help:
-    --help -v           Print the full set of options zwreec accepts"
+        format!("    -{}, --{}", option.short, option.long)
     };
+    if !option.hint.is_empty() {
+        head.push_str(&format!(" {}", option.hint));
+    }
 
-    format(format_args!("{}\n{}\n", options_usage, features_usage.to_string()))
+    let mut lines = String::new();
+    if head.len() + 2 > DESCRIPTION_COLUMN {
+        lines.push_str(&format!("{}\n", head));
+        head = String::new();
+    }
+    for line in wrap_text(option.description, width.saturating_sub(DESCRIPTION_COLUMN)) {
+        lines.push_str(&format!("{:2$}{}\n", head, line, DESCRIPTION_COLUMN));
+        head = String::new();
+    }
+    lines
+}
+
+/// Splits `text` into lines of at most `width` characters. Words longer than a line are kept
+/// whole and very narrow widths are raised to 20 columns.
+fn wrap_text(text: &str, width: usize) -> Vec<String> {
+    let width = if width < 20 { 20 } else { width };
+    let mut lines: Vec<String> = vec![];
+    let mut line = String::new();
+    for word in text.split_whitespace() {
+        if !line.is_empty() && line.chars().count() + 1 + word.chars().count() > width {
+            lines.push(line);
+            line = String::new();
+        }
+        if !line.is_empty() {
+            line.push(' ');
+        }
+        line.push_str(word);
+    }
+    if !line.is_empty() {
+        lines.push(line);
+    }
+    lines
+}
+
+/// Returns the error message for an unknown feature given to `-F` or `-N`, suggesting the closest
+/// feature name if there is one.
+fn unknown_feature_message(action: &str, name: &str, features: &[FeatureSpec]) -> String {
+    let message = format!("Cannot {} feature {} - feature not known.", action, name);
+    match closest_feature(name, features) {
+        Some(feature) => format!("{} Did you mean {}?", message, feature),
+        None => message
+    }
+}
+
+/// Returns the name of the feature closest to `name`, if it is close enough to be a typo.
+fn closest_feature<'a>(name: &str, features: &'a [FeatureSpec]) -> Option<&'a str> {
+    let limit = ::std::cmp::max(2, name.len() / 3);
+    FEATURES.iter().map(|feature| feature.name).chain(features.iter().map(|feature| &feature.name[..]))
+        .map(|feature| (edit_distance(name, feature), feature))
+        .filter(|&(distance, _)| distance <= limit)
+        .min_by_key(|&(distance, _)| distance)
+        .map(|(_, feature)| feature)
+}
+
+/// The Levenshtein distance between `a` and `b`.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..b.len() + 1).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + if ca == *cb { 0 } else { 1 };
+            let value = ::std::cmp::min(substitution, ::std::cmp::min(previous[j + 1], current[j]) + 1);
+            current.push(value);
+        }
+        previous = current;
+    }
+    previous[b.len()]
 }
 
 #[cfg(test)]
@@ -501,6 +1345,18 @@ mod tests {
         assert_eq!(cfg.easter_egg, false);
     }
 
+    #[test]
+    fn test_feature_auto_reshuffle() {
+        assert_eq!(config_from_args(vec![]).auto_reshuffle, false);
+        assert_eq!(config_from_args(vec!["-F".to_string(), "auto-reshuffle".to_string()]).auto_reshuffle, true);
+    }
+
+    #[test]
+    fn test_feature_collapse_blank_lines() {
+        assert_eq!(config_from_args(vec![]).collapse_blank_lines, true);
+        assert_eq!(config_from_args(vec!["-N".to_string(), "collapse-blank-lines".to_string()]).collapse_blank_lines, false);
+    }
+
     #[test]
     fn test_feature_bright_mode_true() {
         let cfg = config_from_args(vec!["-F".to_string(), "bright-mode".to_string()]);
@@ -515,6 +1371,278 @@ mod tests {
         assert_eq!(cfg.bright_mode, false);
     }
 
+    #[test]
+    fn test_features_match_config_fields() {
+        fn fields(cfg: &Config) -> Vec<(&'static str, bool)> {
+            vec![
+                ("auto-reshuffle", cfg.auto_reshuffle),
+                ("bright-mode", cfg.bright_mode),
+                ("collapse-blank-lines", cfg.collapse_blank_lines),
+                ("default-bold", cfg.default_bold),
+                ("default-italic", cfg.default_italic),
+                ("default-monospace", cfg.default_mono),
+                ("default-reverse", cfg.default_reverse),
+                ("easter-egg", cfg.easter_egg),
+                ("force-unicode", cfg.force_unicode),
+                ("half-memory", cfg.half_memory),
+                ("heap-stats", cfg.heap_stats),
+                ("no-colours", cfg.no_colours),
+                ("no-dead-end-quit", cfg.no_dead_end_quit),
+                ("no-unicode", cfg.no_unicode),
+                ("oom-collect", cfg.oom_collect),
+                ("transition-pause", cfg.transition_pause),
+                ("unsupported-formatting", cfg.unsupported_formatting),
+            ]
+        }
+        let defaults = vec![false, false, true, false, false, false, false, true, false, false, false, false, false, false, false, false, false];
+
+        let names: Vec<&str> = FEATURES.iter().map(|feature| feature.name).collect();
+        assert_eq!(names, fields(&Config::default_config()).iter().map(|&(name, _)| name).collect::<Vec<&str>>());
+
+        for (i, (name, value)) in fields(&Config::default_config()).into_iter().enumerate() {
+            assert!(value == defaults[i], "default of {} changed", name);
+            assert_eq!(FEATURES[i].default_on, defaults[i]);
+
+            let enabled = config_from_args(vec!["-F".to_string(), name.to_string()]);
+            let disabled = config_from_args(vec!["-N".to_string(), name.to_string()]);
+            for (j, ((_, on), (_, off))) in fields(&enabled).into_iter().zip(fields(&disabled).into_iter()).enumerate() {
+                assert_eq!(on, if i == j { true } else { defaults[j] });
+                assert_eq!(off, if i == j { false } else { defaults[j] });
+            }
+        }
+    }
+
+    #[test]
+    fn test_unknown_feature_message() {
+        assert_eq!(unknown_feature_message("enable", "brigth-mode", &[]),
+            "Cannot enable feature brigth-mode - feature not known. Did you mean bright-mode?");
+        assert_eq!(unknown_feature_message("disable", "easteregg", &[]),
+            "Cannot disable feature easteregg - feature not known. Did you mean easter-egg?");
+        assert_eq!(unknown_feature_message("enable", "italics", &[]),
+            "Cannot enable feature italics - feature not known.");
+
+        let cfg = config_from_args(vec!["-F".to_string(), "brigth-mode".to_string()]);
+        assert_eq!(cfg.bright_mode, false);
+    }
+
+    #[test]
+    fn test_custom_feature() {
+        let features = vec![
+            FeatureSpec::new("retro", "Targets old interpreters", |cfg, enabled| {
+                cfg.no_colours = enabled;
+                cfg.no_unicode = enabled;
+            }),
+            // the features of zwreec win
+            FeatureSpec::new("easter-egg", "Shadowed", |cfg, _| cfg.bright_mode = true),
+        ];
+        let parse = |args: &[&str]| {
+            let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+            Config::from_matches_with_features(&zwreec_options(getopts::Options::new()).parse(&args).unwrap(), &features)
+        };
+
+        let cfg = parse(&["-F", "retro", "-N", "easter-egg"]);
+        assert!(cfg.no_colours && cfg.no_unicode);
+        assert!(!cfg.easter_egg && !cfg.bright_mode);
+        let cfg = parse(&["-F", "retro", "-N", "retro"]);
+        assert!(!cfg.no_colours && !cfg.no_unicode);
+
+        // without the registration it is unknown
+        let args = vec!["-F".to_string(), "retro".to_string()];
+        assert!(!config_from_args(args).no_colours);
+        assert_eq!(unknown_feature_message("enable", "rertro", &features),
+            "Cannot enable feature rertro - feature not known. Did you mean retro?");
+
+        let usage = usage_with_width(true, getopts::Options::new(), "Usage: zwreec", &features, 80);
+        assert!(usage.contains("    retro\n        Targets old interpreters\n"));
+    }
+
+    #[test]
+    fn test_usage_sections() {
+        let usage = usage_with_width(true, getopts::Options::new(), "Usage: zwreec", &[], 80);
+        for feature in FEATURES.iter() {
+            let default = if feature.default_on { "enabled" } else { "disabled" };
+            assert!(usage.contains(&format!("    {} ({})", feature.name, default)), "{} is missing", feature.name);
+        }
+        for option in OPTIONS.iter() {
+            assert!(usage.contains(&format!("--{}", option.long)), "--{} is missing", option.long);
+        }
+
+        let general = usage.find("General:").unwrap();
+        let output = usage.find("Output:").unwrap();
+        let features = usage.find("Features:").unwrap();
+        let debugging = usage.find("Debugging:").unwrap();
+        assert!(general < output && output < features && features < debugging);
+        assert!(usage.find("--start-passage").unwrap() < output);
+        assert!(usage.find("--debug-build").unwrap() > debugging);
+
+        for width in vec![60, 80, 120] {
+            for line in usage_with_width(true, getopts::Options::new(), "Usage: zwreec", &[], width).lines() {
+                assert!(line.chars().count() <= width, "{:?} is longer than {}", line, width);
+            }
+        }
+
+        let short = usage_with_width(false, getopts::Options::new(), "Usage: zwreec", &[], 80);
+        assert!(!short.contains("--start-passage"));
+        assert!(short.contains("--help -v"));
+    }
+
+    #[test]
+    fn test_max_errors() {
+        let cfg = config_from_args(vec!["--max-errors".to_string(), "3".to_string()]);
+
+        assert_eq!(cfg.max_errors, 3);
+        assert_eq!(cfg.report_error(), false);
+        assert_eq!(cfg.clone().report_error(), false);
+        assert_eq!(cfg.report_error(), true);
+        assert_eq!(cfg.error_count(), 3);
+    }
+
+    #[test]
+    fn test_max_errors_default() {
+        let cfg = config_from_args(vec![]);
+
+        assert_eq!(cfg.max_errors, 50);
+    }
+
+    #[test]
+    fn test_start_passage() {
+        let cfg = config_from_args(vec!["--start-passage".to_string(), "Intro".to_string(), "--first-passage-fallback".to_string()]);
+        assert_eq!(cfg.start_passage, Some("Intro".to_string()));
+        assert_eq!(cfg.first_passage_fallback, true);
+
+        let cfg = config_from_args(vec![]);
+        assert_eq!(cfg.start_passage, None);
+        assert_eq!(cfg.first_passage_fallback, false);
+    }
+
+    #[test]
+    fn test_main_loop() {
+        assert_eq!(config_from_args(vec![]).main_loop, MainLoop::Links);
+        assert_eq!(config_from_args(vec!["--main-loop".to_string(), "links".to_string()]).main_loop, MainLoop::Links);
+        assert_eq!(config_from_args(vec!["--main-loop".to_string(), "none".to_string()]).main_loop, MainLoop::Quit);
+        assert_eq!(config_from_args(vec!["--main-loop".to_string(), "Turn".to_string()]).main_loop, MainLoop::Passage("Turn".to_string()));
+    }
+
+    #[test]
+    fn test_from_args() {
+        let cfg = Config::from_args(&["-F", "no-colours", "--max-errors", "3"]).unwrap();
+        assert!(cfg.no_colours);
+        assert_eq!(cfg.max_errors, 3);
+        assert_eq!(Config::from_args::<&str>(&[]).unwrap().max_errors, Config::default_config().max_errors);
+        assert!(Config::from_args(&["--max-errors"]).is_err());
+    }
+
+    #[test]
+    fn test_dialect() {
+        assert_eq!(config_from_args(vec![]).dialect, Dialect::Legacy);
+        assert_eq!(config_from_args(vec!["--dialect".to_string(), "sugarcube".to_string()]).dialect, Dialect::SugarCube);
+        assert_eq!(config_from_args(vec!["--dialect".to_string(), "harlowe".to_string()]).dialect, Dialect::Harlowe);
+        assert_eq!(config_from_args(vec!["--dialect".to_string(), "snowman".to_string()]).dialect, Dialect::Legacy);
+    }
+
+    #[test]
+    fn test_error_format() {
+        assert_eq!(config_from_args(vec![]).error_format, ErrorFormat::Text);
+        assert_eq!(config_from_args(vec!["--error-format".to_string(), "json".to_string()]).error_format, ErrorFormat::Json);
+        assert_eq!(config_from_args(vec!["--error-format".to_string(), "xml".to_string()]).error_format, ErrorFormat::Text);
+    }
+
+    #[test]
+    fn test_embed() {
+        use std::env;
+        use std::fs::File;
+        use std::io::Write;
+
+        let path = env::temp_dir().join("zwreec_test_embed.bin");
+        File::create(&path).unwrap().write_all(&[1, 2, 3]).unwrap();
+        let cfg = config_from_args(vec!["--embed".to_string(), format!("map={}", path.display()),
+                                        "--embed".to_string(), "no-equals-sign".to_string()]);
+        assert_eq!(cfg.embedded_blobs, vec![("map".to_string(), vec![1, 2, 3])]);
+        assert!(config_from_args(vec![]).embedded_blobs.is_empty());
+    }
+
+    #[test]
+    fn test_embed_source() {
+        assert!(config_from_args(vec!["--embed-source".to_string()]).embed_source);
+        assert!(!config_from_args(vec![]).embed_source);
+    }
+
+    #[test]
+    fn test_oom_message() {
+        assert_eq!(config_from_args(vec![]).oom_message, "MALLOC-FAIL");
+        assert_eq!(config_from_args(vec!["--oom-message".to_string(), "Kein Speicher mehr".to_string()]).oom_message, "Kein Speicher mehr");
+        assert_eq!(config_from_args(vec!["--oom-message".to_string(), "".to_string()]).oom_message, "MALLOC-FAIL");
+    }
+
+    #[test]
+    fn test_tab_width() {
+        assert_eq!(config_from_args(vec![]).tab_width, 1);
+        assert_eq!(config_from_args(vec!["--tab-width".to_string(), "8".to_string()]).tab_width, 8);
+        assert_eq!(config_from_args(vec!["--tab-width".to_string(), "0".to_string()]).tab_width, 1);
+    }
+
+    #[test]
+    fn test_disable_optional_content() {
+        let mut cfg = config_from_args(vec!["--debug-build".to_string(), "-F".to_string(), "easter-egg".to_string()]);
+        cfg.disable_optional_content();
+        assert!(!cfg.easter_egg && !cfg.debug_build);
+    }
+
+    #[test]
+    fn test_debug_build() {
+        let cfg = config_from_args(vec!["--debug-build".to_string()]);
+        assert_eq!(cfg.debug_build, true);
+
+        let cfg = config_from_args(vec![]);
+        assert_eq!(cfg.debug_build, false);
+    }
+
+    #[test]
+    fn test_reproducible() {
+        let cfg = config_from_args(vec!["--reproducible".to_string()]);
+        assert_eq!(cfg.reproducible, true);
+
+        let cfg = config_from_args(vec![]);
+        assert_eq!(cfg.reproducible, false);
+    }
+
+    #[test]
+    fn test_warn_passage_size() {
+        let cfg = config_from_args(vec!["--warn-passage-size".to_string(), "1024".to_string()]);
+        assert_eq!(cfg.warn_passage_size, 1024);
+
+        let cfg = config_from_args(vec![]);
+        assert_eq!(cfg.warn_passage_size, 0);
+    }
+
+    #[test]
+    fn test_lint_levels() {
+        use utils::diagnostics::{Level, Warning};
+
+        let args = vec!["-D", "dead-end", "-A", "dead-end", "-W", "unlinked-passage", "--allow", "no-such-warning", "--pedantic"];
+        let cfg = config_from_args(args.iter().map(|arg| arg.to_string()).collect());
+        assert_eq!(cfg.lint_levels, vec![(Warning::DeadEnd, Level::Allow), (Warning::UnlinkedPassage, Level::Warning),
+            (Warning::DeadEnd, Level::Error)]);
+        assert!(cfg.pedantic);
+
+        let cfg = config_from_args(vec![]);
+        assert!(cfg.lint_levels.is_empty());
+        assert!(!cfg.pedantic);
+        let opts = zwreec_options(getopts::Options::new());
+        assert_eq!(check_lint_ids(&opts.parse(&args).unwrap()), Err("Unknown warning no-such-warning for -A - run --list-lints to see the warnings.".to_string()));
+        assert_eq!(check_lint_ids(&opts.parse(&args[..4]).unwrap()), Ok(()));
+        assert!(Config::from_args(&args).is_err());
+    }
+
+    #[test]
+    fn test_zcode_version() {
+        let cfg = config_from_args(vec!["--zcode-version".to_string(), "7".to_string()]);
+        assert_eq!(cfg.zcode_version, 7);
+
+        let cfg = config_from_args(vec!["--zcode-version".to_string(), "5".to_string()]);
+        assert_eq!(cfg.zcode_version, 8);
+    }
+
     #[test]
     fn test_generate_sample_zcode() {
         let cfg = config_from_args(vec!["-e".to_string()]);
@@ -530,4 +1658,28 @@ mod tests {
 
         assert!(contains);
     }
+
+    #[test]
+    fn test_test_case() {
+        let cfg = config_from_args(vec!["--test-case".to_string(), "string-routines".to_string(),
+            "--test-case".to_string(), "expression-folding".to_string(), "--test-case".to_string(), "nope".to_string()]);
+        assert_eq!(cfg.test_cases, vec![TestCase::StringRoutines, TestCase::ExpressionFolding]);
+    }
+
+    #[test]
+    fn test_config_file_args() {
+        assert_eq!(config_file_args("\n  # nothing\n\t--zcode-version\t7  \n--separator-length 0#fits"),
+            vec!["--zcode-version", "7", "--separator-length", "0"]);
+
+        // the defaults of the scaffold are valid options once they are not commented out
+        let defaults: String = ::scaffold::ZWREEC_CONFIG.lines()
+            .filter(|line| line.starts_with("# --"))
+            .map(|line| format!("{}\n", &line[2..]))
+            .collect();
+        let args = config_file_args(&defaults);
+        assert!(args.len() >= 10, "{:?}", args);
+        let cfg = config_from_args(args);
+        assert_eq!(cfg.zcode_version, 8);
+        assert_eq!(cfg.main_loop, MainLoop::Links);
+    }
 }
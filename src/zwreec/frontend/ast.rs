@@ -2,19 +2,20 @@
 //! to create and walk through the ast (abstract syntaxtree)
 
 use std::fmt::{Debug, Formatter, Result, Write};
-use std::iter::Scan;
 
 use config::Config;
 use frontend::expressionparser;
 use frontend::lexer::Token;
 use frontend::lexer::Token::{TokMacroIf, TokMacroElseIf, TokExpression};
 
-use ::utils::extensions::{Constructor, ConstructorExt, Peeking, PeekingExt};
+use ::utils::extensions::{BoxedConstructor, BoxedConstructorExt, BoxedScan, BoxedScanExt, Peeking, PeekingExt};
 
 /// This is the state of the AST building operation.
 pub struct ASTBuilder {
     path: Vec<usize>,
-    is_in_if_expression: bool
+
+    /// The path lengths of all if-nodes (and `<<at>>`-nodes) whose condition has not been closed yet
+    if_expression_depths: Vec<usize>
 }
 
 /// The [parser](../parser/index.html) uses these ASTOperations to create the AST.
@@ -38,6 +39,9 @@ pub enum ASTOperation {
     /// Goes one level up in the path.
     Up,
 
+    /// Goes one level up and removes the child that was left.
+    UpDiscard,
+
     /// Goes one level up and adds a child.
     UpChild(Token),
 
@@ -54,42 +58,31 @@ impl ASTBuilder {
     fn new() -> ASTBuilder {
         ASTBuilder {
             path: Vec::new(),
-            is_in_if_expression: false
+            if_expression_depths: Vec::new()
         }
     }
 
     /// Constructs an iterator over all passages.
     ///
-    // This has a concrete return type because of optimization reasons. Read and use it as "Iterator<ASTNode>".
+    // The boxed functions cost one dynamic call per operation, which is negligible compared to
+    // building the nodes. Read and use the return type as "Iterator<ASTNode>".
     pub fn build<I: Iterator<Item=ASTOperation>>(cfg: Config, ops: I)
-        -> Scan<Constructor<Peeking<I, ASTOperation>, ASTBuilder, ASTNode, fn(&mut ASTBuilder, &mut Option<ASTNode>, (ASTOperation, Option<ASTOperation>)) -> Option<ASTNode>>, Config, fn(&mut Config, ASTNode) -> Option<ASTNode>>
+        -> BoxedScan<BoxedConstructor<Peeking<I, ASTOperation>, ASTBuilder, ASTNode>, Config, ASTNode>
     {
         info!("Started to build AST");
 
-        ops.peeking().construct_state(ASTBuilder::new(),
-        {
-            fn construct(builder: &mut ASTBuilder, passage: &mut Option<ASTNode>, op_peek: (ASTOperation, Option<ASTOperation>)) -> Option<ASTNode>
-            {
-                let op_node = builder.operation(passage, op_peek.0);
+        ops.peeking().construct_state_boxed(ASTBuilder::new(), |builder, passage, op_peek| {
+            let op_node = builder.operation(passage, op_peek.0);
 
-                if op_peek.1.is_none() {
-                    info!("Finished building AST");
-                }
-
-                op_node
+            if op_peek.1.is_none() {
+                info!("Finished building AST");
             }
-            construct as fn(&mut ASTBuilder, &mut Option<ASTNode>, (ASTOperation, Option<ASTOperation>)) -> Option<ASTNode> //necessary cast is a known bug
-        }).scan(cfg,
-            {
-                fn scan(cfg: &mut Config, x: ASTNode) -> Option<ASTNode>
-                {
-                    let mut y = x.clone();
-                    y.parse_expressions(cfg);
-                    Some(y)
-                }
-                scan
-            }
-        )
+
+            op_node
+        }).scan_boxed(cfg, |cfg, mut passage| {
+            passage.parse_expressions(cfg);
+            Some(passage)
+        })
     }
 
     /// Calls the matching function to a given ASTOperation.
@@ -101,6 +94,7 @@ impl ASTBuilder {
             ChildDown(child) => self.child_down(current_passage, child),
             ChildUp(child) => self.child_up(current_passage, child),
             Up => self.up(),
+            UpDiscard => self.up_discard(current_passage),
             UpChild(child) => self.up_child(current_passage, child),
             UpChildDown(child) => self.up_child_down(current_passage, child),
             UpSpecial => self.up_special(),
@@ -120,6 +114,7 @@ impl ASTBuilder {
     /// Adds a passage to the path in the AST.
     pub fn add_passage(&mut self, token: Token) -> Option<ASTNode> {
         self.path.clear();
+        self.if_expression_depths.clear();
         Some(ASTNode::Passage(NodePassage { category: token, childs: Vec::new() }))
     }
 
@@ -135,14 +130,17 @@ impl ASTBuilder {
 
     /// Adds a child and adds the child to the current path.
     pub fn child_down(&mut self, current_passage: &mut Option<ASTNode>, token: Token) -> Option<ASTNode> {
-        if token.clone().is_same_token(&TokMacroIf { location: (0, 0) }) ||
-           token.clone().is_same_token(&TokMacroElseIf { location: (0, 0) }) {
-            self.is_in_if_expression = true;
-        }
+        let is_if = token.is_same_token(&TokMacroIf { location: (0, 0) }) ||
+                    token.is_same_token(&TokMacroElseIf { location: (0, 0) }) ||
+                    token.is_same_token(&TokMacroAt { location: (0, 0) });
 
         let ast_count_childs = current_passage.as_ref().unwrap().count_childs(self.path.to_vec());
         let result = self.add_child(current_passage, token);
         self.path.push(ast_count_childs);
+
+        if is_if {
+            self.if_expression_depths.push(self.path.len());
+        }
         result
     }
 
@@ -166,12 +164,24 @@ impl ASTBuilder {
         None
     }
 
+    /// Goes one level up and removes the child that was left, with everything below it.
+    pub fn up_discard(&mut self, current_passage: &mut Option<ASTNode>) -> Option<ASTNode> {
+        if let Some(index) = self.path.pop() {
+            current_passage.as_mut().unwrap().remove_child(self.path.to_vec(), index);
+        }
+        None
+    }
+
     /// This goes one level up or goes out of an if-expression if possible.
+    ///
+    /// The end of an if-condition keeps the path at the if-node, so that the following content
+    /// becomes part of the if-branch. Only the innermost if-node at the current depth is closed,
+    /// any other macro (like `<<print>>`) is left by going one level up.
     pub fn up_special(&mut self) -> Option<ASTNode> {
-        if !self.is_in_if_expression {
-            self.path.pop();
+        if self.if_expression_depths.last() == Some(&self.path.len()) {
+            self.if_expression_depths.pop();
         } else {
-            self.is_in_if_expression = false;
+            self.path.pop();
         }
         None
     }
@@ -263,6 +273,24 @@ impl ASTNode {
         }
     }
 
+    /// Removes the child with the index from the path in the AST.
+    pub fn remove_child(&mut self, path: Vec<usize>, index: usize) {
+        if let Some(first) = path.first() {
+            let mut new_path: Vec<usize> = path.to_vec();
+            new_path.remove(0);
+
+            match self {
+                &mut ASTNode::Default(ref mut node) => node.childs[*first].remove_child(new_path, index),
+                &mut ASTNode::Passage(ref mut node) => node.childs[*first].remove_child(new_path, index),
+            }
+        } else {
+            match self {
+                &mut ASTNode::Default(ref mut node) => { node.childs.remove(index); },
+                &mut ASTNode::Passage(ref mut node) => { node.childs.remove(index); },
+            }
+        }
+    }
+
     /// Counts the childs of the current path in the AST.
     pub fn count_childs(&self, path: Vec<usize>) -> usize {
         if let Some(index) = path.first() {
@@ -397,7 +425,11 @@ mod tests {
 
     /// Creates an AST from the input str by lexing and parsing it.
     fn test_ast(input: &str) -> Vec<ASTNode> {
-        let cfg = Config::default_config();
+        test_ast_with_cfg(input, Config::default_config())
+    }
+
+    /// Creates an AST from the input str with the config.
+    fn test_ast_with_cfg(input: &str, cfg: Config) -> Vec<ASTNode> {
         let mut cursor: Cursor<Vec<u8>> = Cursor::new(input.to_string().into_bytes());
         let tokens = lexer::lex(cfg.clone(), &mut cursor);
         let parser = parser::Parser::new(cfg.clone());
@@ -1102,4 +1134,258 @@ mod tests {
 
         test_expected(expected, ast);
     }
+
+    #[test]
+    fn bar_function_test() {
+        let ast = test_ast("::Start\n<<print bar($hp,10,10)>>");
+
+        let expected = vec!(
+            (vec![0,0]        , TokMacroPrint { location: (2, 3) }),
+            (vec![0,0,0]      , TokExpression),
+            (vec![0,0,0,0]    , TokFunction { location: (2, 9), name: "bar".to_string() }),
+            (vec![0,0,0,0,0]  , TokExpression),
+            (vec![0,0,0,0,0,0], TokVariable { location: (2, 13), name: "$hp".to_string() }),
+            (vec![0,0,0,0,1]  , TokExpression),
+            (vec![0,0,0,0,1,0], TokInt { location: (2, 17), value: 10 }),
+            (vec![0,0,0,0,2]  , TokExpression),
+            (vec![0,0,0,0,2,0], TokInt { location: (2, 20), value: 10 }),
+        );
+
+        test_expected(expected, ast);
+    }
+
+    #[test]
+    fn ternary_expressions_test() {
+        let ast = test_ast("::Start\n<<print $x gt 1 ? \"big\" : 2>>\n<<print true ? 1 : false ? 2 : 3>>\n<<print $a ? $b ? 1 : 2 : 3>>\n");
+
+        let expected = vec!(
+            (vec![0,0]                , TokMacroPrint { location: (2, 3) }),
+            (vec![0,0,0]              , TokExpression),
+            (vec![0,0,0,0]            , TokTernaryIf { location: (2, 17) }),
+            (vec![0,0,0,0,0]          , TokCompOp { location: (2, 12), op_name: "gt".to_string() }),
+            (vec![0,0,0,0,0,0]        , TokVariable { location: (2, 9), name: "$x".to_string() }),
+            (vec![0,0,0,0,0,1]        , TokInt { location: (2, 15), value: 1 }),
+            (vec![0,0,0,0,1]          , TokString { location: (2, 19), value: "big".to_string() }),
+            (vec![0,0,0,0,2]          , TokInt { location: (2, 27), value: 2 }),
+            (vec![0,1]                , TokNewLine { location: (2, 30) }),
+            (vec![0,2]                , TokMacroPrint { location: (3, 3) }),
+            (vec![0,2,0,0]            , TokTernaryIf { location: (3, 14) }),
+            (vec![0,2,0,0,0]          , TokBoolean { location: (3, 9), value: "true".to_string() }),
+            (vec![0,2,0,0,1]          , TokInt { location: (3, 16), value: 1 }),
+            (vec![0,2,0,0,2]          , TokTernaryIf { location: (3, 26) }),
+            (vec![0,2,0,0,2,0]        , TokBoolean { location: (3, 20), value: "false".to_string() }),
+            (vec![0,2,0,0,2,1]        , TokInt { location: (3, 28), value: 2 }),
+            (vec![0,2,0,0,2,2]        , TokInt { location: (3, 32), value: 3 }),
+            (vec![0,3]                , TokNewLine { location: (3, 35) }),
+            (vec![0,4]                , TokMacroPrint { location: (4, 3) }),
+            (vec![0,4,0,0]            , TokTernaryIf { location: (4, 12) }),
+            (vec![0,4,0,0,0]          , TokVariable { location: (4, 9), name: "$a".to_string() }),
+            (vec![0,4,0,0,1]          , TokTernaryIf { location: (4, 17) }),
+            (vec![0,4,0,0,1,0]        , TokVariable { location: (4, 14), name: "$b".to_string() }),
+            (vec![0,4,0,0,1,1]        , TokInt { location: (4, 19), value: 1 }),
+            (vec![0,4,0,0,1,2]        , TokInt { location: (4, 23), value: 2 }),
+            (vec![0,4,0,0,2]          , TokInt { location: (4, 27), value: 3 }),
+            (vec![0,5]                , TokNewLine { location: (4, 30) }),
+        );
+
+        test_expected(expected, ast);
+    }
+
+    /// Returns a link token for the tests.
+    fn link(location: (u64, u64), display_name: &str, passage_name: &str) -> Token {
+        TokPassageLink { location: location, display_name: display_name.to_string(), passage_name: passage_name.to_string() }
+    }
+
+    #[test]
+    fn single_line_if_link_test() {
+        let ast = test_ast("::Start\n<<if $k>>[[Open|Door]]<<endif>> [[Leave|Exit]]");
+
+        let expected = vec!(
+            (vec![0]    , TokPassage { location: (1, 3), name: "Start".to_string() }),
+            (vec![0,0]  , TokMacroIf { location: (2, 3) }),
+            (vec![0,0,0], TokExpression),
+            (vec![0,0,0,0], TokVariable { location: (2, 6), name: "$k".to_string() }),
+            (vec![0,0,1], link((2, 10), "Open", "Door")),
+            (vec![0,1]  , TokMacroEndIf { location: (2, 25) }),
+            (vec![0,2]  , TokText { location: (2, 32), text: " ".to_string() }),
+            (vec![0,3]  , link((2, 33), "Leave", "Exit")),
+        );
+
+        test_expected(expected, ast);
+    }
+
+    #[test]
+    fn single_line_if_else_link_test() {
+        let ast = test_ast("::Start\n<<if $k>>[[Open|Door]]<<else>>[[Knock|Door]]<<endif>>[[Leave|Exit]]");
+
+        let expected = vec!(
+            (vec![0,0]  , TokMacroIf { location: (2, 3) }),
+            (vec![0,0,0], TokExpression),
+            (vec![0,0,1], link((2, 10), "Open", "Door")),
+            (vec![0,1]  , TokMacroElse { location: (2, 25) }),
+            (vec![0,1,0], link((2, 31), "Knock", "Door")),
+            (vec![0,2]  , TokMacroEndIf { location: (2, 47) }),
+            (vec![0,3]  , link((2, 54), "Leave", "Exit")),
+        );
+
+        test_expected(expected, ast);
+    }
+
+    #[test]
+    fn single_line_if_set_link_test() {
+        let ast = test_ast("::Start\n<<if $k>>[[Open|Door][$o = 1]]<<endif>>[[Leave|Exit]]");
+
+        let expected = vec!(
+            (vec![0,0]    , TokMacroIf { location: (2, 3) }),
+            (vec![0,0,1]  , link((2, 10), "Open", "Door")),
+            (vec![0,0,1,0], TokAssign { location: (2, 23), var_name: "$o".to_string(), op_name: "=".to_string() }),
+            (vec![0,1]    , TokMacroEndIf { location: (2, 33) }),
+            (vec![0,2]    , link((2, 40), "Leave", "Exit")),
+        );
+
+        test_expected(expected, ast);
+    }
+
+    #[test]
+    fn single_line_nested_if_link_test() {
+        let ast = test_ast("::Start\n<<if $a>><<if $b>>[[In|Door]]<<endif>>[[Mid|Hall]]<<endif>>[[Out|Exit]]");
+
+        let expected = vec!(
+            (vec![0,0]    , TokMacroIf { location: (2, 3) }),
+            (vec![0,0,0]  , TokExpression),
+            (vec![0,0,1]  , TokMacroIf { location: (2, 12) }),
+            (vec![0,0,1,0], TokExpression),
+            (vec![0,0,1,1], link((2, 19), "In", "Door")),
+            (vec![0,0,2]  , TokMacroEndIf { location: (2, 32) }),
+            (vec![0,0,3]  , link((2, 39), "Mid", "Hall")),
+            (vec![0,1]    , TokMacroEndIf { location: (2, 53) }),
+            (vec![0,2]    , link((2, 60), "Out", "Exit")),
+        );
+
+        test_expected(expected, ast);
+    }
+
+    #[test]
+    fn print_after_if_test() {
+        // the end of the print expression must not be taken for the end of the if-condition
+        let ast = test_ast("::Start\n<<if $k>><<print $k>>[[Open|Door]]<<endif>>[[Leave|Exit]]");
+
+        let expected = vec!(
+            (vec![0,0]  , TokMacroIf { location: (2, 3) }),
+            (vec![0,0,1], TokMacroPrint { location: (2, 12) }),
+            (vec![0,0,2], link((2, 22), "Open", "Door")),
+            (vec![0,1]  , TokMacroEndIf { location: (2, 37) }),
+            (vec![0,2]  , link((2, 44), "Leave", "Exit")),
+        );
+
+        test_expected(expected, ast);
+    }
+
+    #[test]
+    fn otherwise_link_test() {
+        let ast = test_ast("::Start\n<<if $k>>[[Open|Door]]<<endif>><<otherwise [[Give up|End]]>>");
+
+        let expected = vec!(
+            (vec![0,0]  , TokMacroIf { location: (2, 3) }),
+            (vec![0,0,1], link((2, 10), "Open", "Door")),
+            (vec![0,1]  , TokMacroEndIf { location: (2, 25) }),
+            (vec![0,2]  , TokMacroOtherwise { location: (2, 34) }),
+            (vec![0,2,0], link((2, 44), "Give up", "End")),
+        );
+
+        test_expected(expected, ast);
+    }
+
+    #[test]
+    fn otherwise_set_link_test() {
+        let ast = test_ast("::Start\n<<otherwise [[Give up|End][$g = 1]]>>.");
+
+        let expected = vec!(
+            (vec![0,0]    , TokMacroOtherwise { location: (2, 3) }),
+            (vec![0,0,0]  , link((2, 13), "Give up", "End")),
+            (vec![0,0,0,0], TokAssign { location: (2, 28), var_name: "$g".to_string(), op_name: "=".to_string() }),
+            (vec![0,1]    , TokText { location: (2, 38), text: ".".to_string() }),
+        );
+
+        test_expected(expected, ast);
+    }
+
+    #[test]
+    fn set_list_test() {
+        let ast = test_ast("::Start\n<<set $a = 1, $b += 2; $hp = $maxhp>>");
+
+        let expected = vec!(
+            (vec![0,0]    , TokAssign { location: (2, 7), var_name: "$a".to_string(), op_name: "=".to_string() }),
+            (vec![0,0,0]  , TokExpression),
+            (vec![0,0,0,0], TokInt { location: (2, 12), value: 1 }),
+            (vec![0,1]    , TokAssign { location: (2, 15), var_name: "$b".to_string(), op_name: "+=".to_string() }),
+            (vec![0,1,0]  , TokExpression),
+            (vec![0,1,0,0], TokInt { location: (2, 21), value: 2 }),
+            (vec![0,2]    , TokAssign { location: (2, 24), var_name: "$hp".to_string(), op_name: "=".to_string() }),
+            (vec![0,2,0]  , TokExpression),
+            (vec![0,2,0,0], TokVariable { location: (2, 30), name: "$maxhp".to_string() }),
+        );
+
+        assert_eq!(ast[0].count_childs(vec![]), 3);
+        test_expected(expected, ast);
+    }
+
+    #[test]
+    fn map_literal_test() {
+        let ast = test_ast("::Start\n<<set $m = {\"hp\": 10, \"s\": {\"x\": $y}}>>");
+
+        let expected = vec!(
+            (vec![0,0]              , TokAssign { location: (2, 7), var_name: "$m".to_string(), op_name: "=".to_string() }),
+            (vec![0,0,0]            , TokExpression),
+            (vec![0,0,0,0]          , TokMapLiteral { location: (2, 12) }),
+            (vec![0,0,0,0,0]        , TokString { location: (2, 13), value: "hp".to_string() }),
+            (vec![0,0,0,0,1]        , TokExpression),
+            (vec![0,0,0,0,1,0]      , TokInt { location: (2, 19), value: 10 }),
+            (vec![0,0,0,0,2]        , TokString { location: (2, 23), value: "s".to_string() }),
+            (vec![0,0,0,0,3]        , TokExpression),
+            (vec![0,0,0,0,3,0]      , TokMapLiteral { location: (2, 28) }),
+            (vec![0,0,0,0,3,0,0]    , TokString { location: (2, 29), value: "x".to_string() }),
+            (vec![0,0,0,0,3,0,1]    , TokExpression),
+            (vec![0,0,0,0,3,0,1,0]  , TokVariable { location: (2, 34), name: "$y".to_string() }),
+        );
+
+        assert_eq!(ast[0].count_childs(vec![0,0,0]), 4);
+        test_expected(expected, ast);
+    }
+
+    #[test]
+    fn set_list_function_test() {
+        // the commas of the arguments and the string do not separate assignments
+        let ast = test_ast("::Start\n<<set $a = bar($hp,10,10), $b = \"x, y\">>");
+
+        let expected = vec!(
+            (vec![0,0]        , TokAssign { location: (2, 7), var_name: "$a".to_string(), op_name: "=".to_string() }),
+            (vec![0,0,0,0]    , TokFunction { location: (2, 12), name: "bar".to_string() }),
+            (vec![0,0,0,0,2]  , TokExpression),
+            (vec![0,0,0,0,2,0], TokInt { location: (2, 23), value: 10 }),
+            (vec![0,1]        , TokAssign { location: (2, 28), var_name: "$b".to_string(), op_name: "=".to_string() }),
+            (vec![0,1,0,0]    , TokString { location: (2, 33), value: "x, y".to_string() }),
+        );
+
+        assert_eq!(ast[0].count_childs(vec![]), 2);
+        test_expected(expected, ast);
+    }
+
+    #[test]
+    fn set_list_recovery_test() {
+        let mut cfg = Config::default_config();
+        cfg.force = true;
+        let ast = test_ast_with_cfg("::Start\n<<set $a = 1, $b = , $c = 3>>", cfg.clone());
+
+        // the assignment without a value is reported and left out
+        let expected = vec!(
+            (vec![0,0]    , TokAssign { location: (2, 7), var_name: "$a".to_string(), op_name: "=".to_string() }),
+            (vec![0,1]    , TokAssign { location: (2, 22), var_name: "$c".to_string(), op_name: "=".to_string() }),
+            (vec![0,1,0,0], TokInt { location: (2, 27), value: 3 }),
+        );
+
+        assert_eq!(ast[0].count_childs(vec![]), 2);
+        assert_eq!(cfg.error_count(), 1);
+        test_expected(expected, ast);
+    }
 }
@@ -0,0 +1,233 @@
+//! Reports the constructs of a story that zwreec does not support.
+//!
+//! The report only looks at the token stream of the lexer, so it also works for stories that
+//! would not compile. It groups the findings by kind and counts every name, together with the
+//! location of its first use:
+//!
+//! * unknown macros, which are lexed as `<<display>>` of a passage that does not exist
+//! * functions that are not in `evaluate_expression::FUNCTIONS`
+//! * passages tagged `script` or `stylesheet`, which are skipped
+//! * HTML tags outside of `<html>` blocks, which are printed as text
+//! * characters beyond U+FFFF, which are printed as `?`
+//!
+//! Only unknown macros and functions stop the compilation, the rest compiles with a different
+//! output than in Twine.
+//!
+//! # Example
+//!
+//! ```
+//! # extern crate zwreec;
+//! use std::io::Cursor;
+//!
+//! let cfg = zwreec::config::Config::default_config();
+//! let mut twee = Cursor::new("::Start\n<<timed 2>>Hello".to_string().into_bytes());
+//!
+//! let report = zwreec::compat_report(cfg, &mut twee);
+//! assert!(report.is_blocking());
+//! assert_eq!(report.count(zwreec::frontend::compat::CompatKind::UnknownMacro, "timed"), 1);
+//! ```
+
+use std::collections::HashSet;
+use std::fmt;
+
+use frontend::evaluate_expression::FUNCTIONS;
+use frontend::lexer::Token;
+use frontend::lexer::Token::*;
+
+/// The kinds of unsupported constructs, in the order of the report.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum CompatKind {
+    /// A macro that is neither built in nor the name of a passage
+    UnknownMacro,
+
+    /// A function that cannot be called in expressions
+    UnknownFunction,
+
+    /// A passage tagged `script` or `stylesheet`
+    IgnoredPassage,
+
+    /// An HTML tag outside of an `<html>` block
+    HtmlTag,
+
+    /// A character that needs more than one UTF-16 code unit
+    WideCharacter,
+}
+
+static KINDS: [CompatKind; 5] = [CompatKind::UnknownMacro, CompatKind::UnknownFunction,
+    CompatKind::IgnoredPassage, CompatKind::HtmlTag, CompatKind::WideCharacter];
+
+impl CompatKind {
+    /// Returns true if a story using this construct does not compile.
+    pub fn is_blocking(&self) -> bool {
+        match self {
+            &CompatKind::UnknownMacro | &CompatKind::UnknownFunction => true,
+            _ => false
+        }
+    }
+
+    fn title(&self) -> &'static str {
+        match self {
+            &CompatKind::UnknownMacro => "Unknown macros or displays of missing passages",
+            &CompatKind::UnknownFunction => "Unsupported functions",
+            &CompatKind::IgnoredPassage => "Script and stylesheet passages, skipped",
+            &CompatKind::HtmlTag => "HTML tags outside of <html>, printed as text",
+            &CompatKind::WideCharacter => "Characters beyond U+FFFF, printed as ?",
+        }
+    }
+}
+
+/// A used construct with the number of uses.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompatFinding {
+    /// The kind of the construct
+    pub kind: CompatKind,
+
+    /// The name of the macro, function, passage, tag or character
+    pub name: String,
+
+    /// How often it is used
+    pub count: usize,
+
+    /// Where it is used for the first time
+    pub location: (u64, u64),
+}
+
+/// The result of `compat_report`.
+#[derive(Debug, Clone)]
+pub struct CompatReport {
+    /// All findings, ordered by their first use
+    pub findings: Vec<CompatFinding>,
+
+    /// The number of passages
+    pub passages: usize,
+
+    /// The number of passages without any finding
+    pub supported_passages: usize,
+
+    /// The numbers of the passages with findings, counted from 1
+    unsupported_passages: HashSet<usize>,
+}
+
+impl CompatReport {
+    /// Returns true if any construct stops the compilation.
+    pub fn is_blocking(&self) -> bool {
+        self.findings.iter().any(|finding| finding.kind.is_blocking())
+    }
+
+    /// Returns how often `name` is used as construct of `kind`.
+    pub fn count(&self, kind: CompatKind, name: &str) -> usize {
+        self.findings.iter()
+            .find(|finding| finding.kind == kind && finding.name == name)
+            .map(|finding| finding.count)
+            .unwrap_or(0)
+    }
+
+    fn add(&mut self, kind: CompatKind, name: String, location: (u64, u64)) {
+        let passage = self.passages;
+        self.unsupported_passages.insert(passage);
+        for finding in self.findings.iter_mut() {
+            if finding.kind == kind && finding.name == name {
+                finding.count += 1;
+                return;
+            }
+        }
+        self.findings.push(CompatFinding { kind: kind, name: name, count: 1, location: location });
+    }
+}
+
+impl fmt::Display for CompatReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for kind in KINDS.iter() {
+            let findings: Vec<&CompatFinding> = self.findings.iter().filter(|finding| finding.kind == *kind).collect();
+            if findings.is_empty() {
+                continue;
+            }
+
+            try!(writeln!(f, "{}{}:", kind.title(), if kind.is_blocking() { " (blocking)" } else { "" }));
+            for finding in findings {
+                try!(writeln!(f, "    {}: {} {}, first at {}:{}", finding.name, finding.count,
+                    if finding.count == 1 { "use" } else { "uses" }, finding.location.0, finding.location.1));
+            }
+        }
+        write!(f, "{} of {} passages use only supported features", self.supported_passages, self.passages)
+    }
+}
+
+/// Collects the unsupported constructs of the tokens.
+pub fn compat_report<I: Iterator<Item=Token>>(tokens: I) -> CompatReport {
+    let tokens: Vec<Token> = tokens.collect();
+    let passage_names: HashSet<&str> = tokens.iter().filter_map(|token| match token {
+        &TokPassage{ref name, ..} => Some(&name[..]),
+        _ => None
+    }).collect();
+
+    let mut report = CompatReport { findings: Vec::new(), passages: 0, supported_passages: 0, unsupported_passages: HashSet::new() };
+    let mut passage: Option<(String, (u64, u64))> = None;
+
+    for token in tokens.iter() {
+        match token {
+            &TokPassage{ref name, location} => {
+                report.passages += 1;
+                passage = Some((name.clone(), location));
+            },
+            &TokTag{ref tag_name, ..} if tag_name == "script" || tag_name == "stylesheet" => {
+                if let Some((ref name, location)) = passage {
+                    report.add(CompatKind::IgnoredPassage, name.clone(), location);
+                }
+            },
+            &TokMacroDisplay{ref passage_name, location} if !passage_names.contains(&passage_name[..]) => {
+                report.add(CompatKind::UnknownMacro, passage_name.clone(), location);
+            },
+            &TokFunction{ref name, location} if !FUNCTIONS.iter().any(|function| *function == &name[..]) => {
+                report.add(CompatKind::UnknownFunction, name.clone(), location);
+            },
+            &TokText{ref text, location} => {
+                for tag in html_tags(text) {
+                    report.add(CompatKind::HtmlTag, tag, location);
+                }
+                add_wide_characters(&mut report, text, location);
+            },
+            &TokPassageLink{ref display_name, location, ..} => {
+                add_wide_characters(&mut report, display_name, location);
+            },
+            _ => {}
+        }
+    }
+
+    // findings before the first passage do not belong to any passage
+    report.supported_passages = report.passages - report.unsupported_passages.iter().filter(|&&passage| passage > 0).count();
+    report
+}
+
+fn add_wide_characters(report: &mut CompatReport, text: &str, location: (u64, u64)) {
+    for character in text.chars().filter(|&character| character as u32 > 0xffff) {
+        report.add(CompatKind::WideCharacter, format!("U+{:X}", character as u32), location);
+    }
+}
+
+/// Returns the lowercase names of the tags in `text`, like `span` for `<span class="x">`.
+fn html_tags(text: &str) -> Vec<String> {
+    let mut tags = Vec::new();
+    for (start, _) in text.match_indices("<") {
+        let rest = &text[start + 1..];
+        let rest = if rest.starts_with("/") { &rest[1..] } else { rest };
+        let name: String = rest.chars().take_while(|&c| (c as u32) < 0x80 && c.is_alphanumeric()).collect();
+        let after = rest[name.len()..].chars().next();
+        if name.chars().next().map_or(false, |c| c.is_alphabetic())
+            && (after == Some('>') || after == Some(' ') || after == Some('/')) {
+            tags.push(name.to_lowercase());
+        }
+    }
+    tags
+}
+
+#[cfg(test)]
+mod tests {
+    use super::html_tags;
+
+    #[test]
+    fn html_tags_test() {
+        assert_eq!(html_tags("<span class=\"x\">a</span> <BR/>"), vec!["span", "span", "br"]);
+        assert_eq!(html_tags("1 < 2 and <3 or <>"), Vec::<String>::new());
+    }
+}
@@ -9,13 +9,22 @@
 //! expressions. Hence only expressions with limited size are
 //! supported.
 
+use std::collections::{HashMap, HashSet};
+
 use backend::zcode::zfile::{ZOP, Operand, Variable, Constant, LargeConstant, Zfile, Type};
 use backend::codegen;
 use backend::codegen::CodeGenManager;
 use frontend::ast::{ASTNode};
 use frontend::lexer::Token;
-use frontend::lexer::Token::{TokNumOp, TokCompOp, TokLogOp, TokInt, TokBoolean, TokVariable, TokArrayLength, TokArrayAccess, TokFunction, TokString, TokUnaryMinus};
+use frontend::lexer::Token::{TokNumOp, TokCompOp, TokLogOp, TokInt, TokBoolean, TokVariable, TokArrayLength, TokArrayAccess, TokMapLiteral, TokMapAccess, TokFunction, TokString, TokUnaryMinus, TokTernaryIf};
 #[allow(unused_imports)] use config::Config;
+use utils::diagnostics::Warning;
+
+/// The names of the functions that can be called in expressions.
+///
+/// Every name is handled by the `TokFunction` arm of `evaluate_expression`, any other name is
+/// reported as `UnsupportedFunction`.
+pub static FUNCTIONS: &'static [&'static str] = &["bar", "buildtime", "compiler_version", "config", "confirm", "draw", "join", "linkcount", "lower", "passage", "pause_confirm", "plural", "prompt", "random", "shuffle", "substr", "upper"];
 
 /// All the possible errors that can occur during parsing.
 #[derive(Debug)]
@@ -42,12 +51,21 @@ pub enum EvaluateExpressionError {
     /// The type of the function arg is wrong / unexpected
     UnsupportedFunctionArgType { name: String, index: u64, location: (u64, u64) },
 
+    /// The constant bounds of `random()` are in the wrong order
+    InvalidRandomRange { from: i16, to: i16, location: (u64, u64) },
+
+    /// The argument of `config()` is not the name of a feature
+    UnknownConfigName { name: String, location: (u64, u64) },
+
     /// Expression is too complex
     NoTempIdLeftOnStack,
 }
 
 /// This functions evaluates an expression from the AST and returns an `Operand` containing the result.
 ///
+/// Errors that can be recovered from are reported through `error_panic!`, the others are returned,
+/// like an expression that needs more temporary variables than there are.
+///
 /// # Arguments
 /// `node` is the root node of the expression. Mostly the child of `TokExpression` is what you want to give here.
 ///
@@ -56,14 +74,300 @@ pub enum EvaluateExpressionError {
 /// `manager` is the manager from `codegen`. It is required for the symbol table and label ids.
 ///
 /// `out` is the `ZFile` compiling to. It is required for storing strings.
-pub fn evaluate_expression(node: ASTNode, code: &mut Vec<ZOP>, mut manager: &mut CodeGenManager, mut out: &mut Zfile) -> Operand {
+pub fn evaluate_expression(node: ASTNode, code: &mut Vec<ZOP>, mut manager: &mut CodeGenManager, mut out: &mut Zfile) -> Result<Operand, EvaluateExpressionError> {
     let mut temp_ids = CodeGenManager::new_temp_var_vec();
+    let kept = manager.expression_cache.kept_ids();
+    temp_ids.retain(|id| !kept.contains(id));
     evaluate_expression_internal(node, code, &mut temp_ids, manager, &mut out)
 }
 
-/// Evaluates an expression node to Z-code.
+/// Evaluates the expressions in order, like the arguments of a macro. They share the temporary
+/// variables, so the value of one expression is not overwritten by the next one. Returns the
+/// values and the temporary variables that are still free behind them.
+pub fn evaluate_expressions(nodes: Vec<ASTNode>, code: &mut Vec<ZOP>, mut manager: &mut CodeGenManager, mut out: &mut Zfile) -> Result<(Vec<Operand>, Vec<u8>), EvaluateExpressionError> {
+    let mut temp_ids = CodeGenManager::new_temp_var_vec();
+    let kept = manager.expression_cache.kept_ids();
+    temp_ids.retain(|id| !kept.contains(id));
+    let mut values: Vec<Operand> = vec![];
+    for node in nodes.into_iter() {
+        values.push(try!(evaluate_expression_internal(node, code, &mut temp_ids, manager, &mut out)));
+    }
+    Ok((values, temp_ids))
+}
+
+/// The local variables that keep the values of repeated expressions. The code of `+=` uses
+/// the locals 1, 2, 14 and 15 directly, so they are left out.
+static CACHE_IDS: &'static [u8] = &[3, 4, 5, 6];
+
+/// Keeps the values of expressions that occur more than once in a passage.
+///
+/// Only operations on variables and constants are kept, an expression calling a function may
+/// have a different value every time. The first evaluation stores its value in one of the
+/// `CACHE_IDS`, the later ones copy it from there. If all of them are taken, the expression is
+/// evaluated again.
+///
+/// A value is dropped when one of its variables is assigned. Codegen drops all of them around
+/// content that is not run straight through, like `<<if>>` or the routine of a link.
+pub struct ExpressionCache {
+    /// The expressions that occur more than once in the current passage
+    candidates: HashSet<String>,
+
+    /// The kept values with their expression and the variables it reads
+    entries: Vec<(String, Vec<String>, Variable)>,
+
+    /// The count of ternary branches the evaluation is in, their values are not kept
+    conditional: u32,
+}
+
+impl ExpressionCache {
+    /// Creates an empty cache.
+    pub fn new() -> ExpressionCache {
+        ExpressionCache {
+            candidates: HashSet::new(),
+            entries: Vec::new(),
+            conditional: 0,
+        }
+    }
+
+    /// Drops the values of the previous passage and looks for the repeated expressions of the
+    /// passage with these nodes.
+    pub fn start_passage(&mut self, nodes: &Vec<ASTNode>) {
+        let mut counts: HashMap<String, u32> = HashMap::new();
+        for node in nodes.iter() {
+            count_expressions(node, &mut counts);
+        }
+        self.candidates = counts.into_iter().filter(|&(_, count)| count > 1).map(|(key, _)| key).collect();
+        self.entries.clear();
+        self.conditional = 0;
+    }
+
+    /// Drops all kept values.
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    /// Drops the values that read the variable with this name.
+    pub fn invalidate(&mut self, name: &str) {
+        self.entries.retain(|&(_, ref variables, _)| !variables.iter().any(|variable| variable == name));
+    }
+
+    /// Returns the locals that hold a value.
+    fn kept_ids(&self) -> Vec<u8> {
+        self.entries.iter().map(|&(_, _, ref value)| value.id).collect()
+    }
+}
+
+/// Counts the expressions below the node that could be kept. The operands of a repeated
+/// expression are not counted again, the whole expression is taken from the cache.
+fn count_expressions(node: &ASTNode, counts: &mut HashMap<String, u32>) {
+    if let Some((key, _)) = expression_key(node) {
+        let count = counts.entry(key).or_insert(0);
+        *count += 1;
+        if *count > 1 {
+            return;
+        }
+    }
+
+    let childs = match node {
+        &ASTNode::Default(ref n) => &n.childs,
+        &ASTNode::Passage(ref n) => &n.childs
+    };
+    for child in childs.iter() {
+        count_expressions(child, counts);
+    }
+}
+
+/// Returns a key for an operation that only reads variables and constants, along with the
+/// variables it reads. Structurally identical expressions have the same key, no matter where
+/// they are.
+fn expression_key(node: &ASTNode) -> Option<(String, Vec<String>)> {
+    let is_operation = match node {
+        &ASTNode::Default(ref n) => match n.category {
+            TokNumOp { .. } | TokCompOp { .. } | TokLogOp { .. } | TokUnaryMinus { .. } => true,
+            _ => false
+        },
+        _ => false
+    };
+    if !is_operation {
+        return None;
+    }
+
+    let mut variables: Vec<String> = vec![];
+    match operand_key(node, &mut variables) {
+        Some(key) => if variables.is_empty() { None } else { Some((key, variables)) },
+        None => None
+    }
+}
+
+/// Writes the part of an expression key for an operand, see `expression_key`.
+fn operand_key(node: &ASTNode, variables: &mut Vec<String>) -> Option<String> {
+    let n = match node {
+        &ASTNode::Default(ref n) => n,
+        _ => return None
+    };
+
+    let op_name = match n.category {
+        TokInt { value, .. } => return Some(format!("{}", value)),
+        TokBoolean { ref value, .. } => return Some(value.clone()),
+        TokVariable { ref name, .. } => {
+            if !variables.contains(name) {
+                variables.push(name.clone());
+            }
+            return Some(name.clone())
+        },
+        TokNumOp { ref op_name, .. } | TokCompOp { ref op_name, .. } | TokLogOp { ref op_name, .. } => op_name.clone(),
+        TokUnaryMinus { .. } => "neg".to_string(),
+        _ => return None
+    };
+
+    let mut parts: Vec<String> = vec![op_name];
+    for child in n.childs.iter() {
+        match operand_key(child, variables) {
+            Some(part) => parts.push(part),
+            None => return None
+        }
+    }
+    Some(format!("({})", parts.join(" ")))
+}
+
+/// Returns the inclusive range of values an expression can take, if it is known at compile time.
+///
+/// Only integer expressions have a static range: literals, `random()` with literal bounds, the
+/// `variables` with a known range and arithmetic on these. Any other variable may be changed by
+/// any passage, so expressions containing it are never bounded. Returns `None` as well if a bound
+/// leaves the 16 bit range of the Z-Machine, since the runtime value would wrap around.
+pub fn static_range(node: &ASTNode, variables: &HashMap<String, (i32, i32)>) -> Option<(i32, i32)> {
+    let n = match node {
+        &ASTNode::Default(ref n) => n,
+        _ => return None
+    };
+
+    let range = match n.category {
+        TokInt { value, .. } => (value, value),
+        TokVariable { ref name, .. } => match variables.get(name) {
+            Some(&range) => range,
+            None => return None
+        },
+        TokUnaryMinus { .. } if n.childs.len() == 1 => {
+            let (low, high) = match static_range(&n.childs[0], variables) { Some(range) => range, None => return None };
+            (-high, -low)
+        },
+        TokNumOp { ref op_name, .. } if n.childs.len() == 2 => {
+            let (low0, high0) = match static_range(&n.childs[0], variables) { Some(range) => range, None => return None };
+            let (low1, high1) = match static_range(&n.childs[1], variables) { Some(range) => range, None => return None };
+            let corners = |f: &Fn(i32, i32) -> i32| {
+                let values = [f(low0, low1), f(low0, high1), f(high0, low1), f(high0, high1)];
+                (*values.iter().min().unwrap(), *values.iter().max().unwrap())
+            };
+
+            match &**op_name {
+                "+" => (low0 + low1, high0 + high1),
+                "-" => (low0 - high1, high0 - low1),
+                "*" => corners(&|a, b| a * b),
+                // truncating division is monotonic as long as the divisor keeps its sign
+                "/" if low1 > 0 || high1 < 0 => corners(&|a, b| a / b),
+                "%" if low1 > 0 || high1 < 0 => {
+                    let divisor = if low1 > 0 { high1 } else { -low1 };
+                    (if low0 < 0 { -(divisor - 1) } else { 0 }, if high0 > 0 { divisor - 1 } else { 0 })
+                },
+                _ => return None
+            }
+        },
+        TokFunction { ref name, .. } if name == "random" && n.childs.len() == 2 => {
+            let bound = |arg: &ASTNode| match arg {
+                &ASTNode::Default(ref arg) if arg.childs.len() == 1 => match arg.childs[0] {
+                    ASTNode::Default(ref value) => match value.category {
+                        TokInt { value, .. } => Some(value),
+                        _ => None
+                    },
+                    _ => None
+                },
+                _ => None
+            };
+            match (bound(&n.childs[0]), bound(&n.childs[1])) {
+                (Some(from), Some(to)) if from <= to => (from, to),
+                _ => return None
+            }
+        },
+        _ => return None
+    };
+
+    let (low, high) = range;
+    if low < i16::min_value() as i32 || high > i16::max_value() as i32 {
+        None
+    } else {
+        Some(range)
+    }
+}
+
+/// Returns the text of an expression that only concatenates string literals with `+`.
+///
+/// The concatenation is written as a single string, so neither the parts nor a call of `strcat`
+/// end up in the Z-Code.
+fn static_string(node: &ASTNode) -> Option<String> {
+    let n = match node {
+        &ASTNode::Default(ref n) => n,
+        _ => return None
+    };
+
+    match n.category {
+        TokString { ref value, .. } => Some(value.clone()),
+        TokNumOp { ref op_name, .. } if op_name == "+" && n.childs.len() == 2 => {
+            match (static_string(&n.childs[0]), static_string(&n.childs[1])) {
+                (Some(left), Some(right)) => Some(left + &right),
+                _ => None
+            }
+        },
+        _ => None
+    }
+}
+
+/// Evaluates an expression node to Z-code, a repeated expression is taken from the
+/// `ExpressionCache` of the manager.
 fn evaluate_expression_internal(node: ASTNode, code: &mut Vec<ZOP>,
-        temp_ids: &mut Vec<u8>, mut manager: &mut CodeGenManager, mut out: &mut Zfile) -> Operand {
+        temp_ids: &mut Vec<u8>, mut manager: &mut CodeGenManager, mut out: &mut Zfile) -> Result<Operand, EvaluateExpressionError> {
+    let (key, variables) = match expression_key(&node) {
+        Some((key, variables)) => if manager.expression_cache.candidates.contains(&key) {
+            (key, variables)
+        } else {
+            return evaluate_uncached(node, code, temp_ids, manager, out)
+        },
+        None => return evaluate_uncached(node, code, temp_ids, manager, out)
+    };
+
+    let kept = manager.expression_cache.entries.iter().find(|&&(ref entry, _, _)| *entry == key).map(|&(_, _, ref value)| value.clone());
+    if let Some(value) = kept {
+        if let Some(id) = temp_ids.pop() {
+            let copy = Variable { id: id, vartype: value.vartype.clone() };
+            code.push(ZOP::StoreVariable{variable: copy.clone(), value: Operand::Var(value.clone())});
+            code.push(ZOP::CopyVarType{variable: copy.clone(), from: Operand::Var(value)});
+            return Ok(Operand::Var(copy));
+        }
+        return evaluate_uncached(node, code, temp_ids, manager, out);
+    }
+
+    let result = try!(evaluate_uncached(node, code, temp_ids, manager, out));
+    if manager.expression_cache.conditional > 0 {
+        return Ok(result);
+    }
+    if let Operand::Var(ref var) = result {
+        if !CodeGenManager::is_temp_var(var) {
+            return Ok(result.clone());
+        }
+        if let Some(&id) = CACHE_IDS.iter().find(|&id| *id != var.id && temp_ids.contains(id)) {
+            temp_ids.retain(|temp| *temp != id);
+            let value = Variable { id: id, vartype: var.vartype.clone() };
+            code.push(ZOP::StoreVariable{variable: value.clone(), value: result.clone()});
+            code.push(ZOP::CopyVarType{variable: value.clone(), from: result.clone()});
+            manager.expression_cache.entries.push((key, variables, value));
+        }
+    }
+    Ok(result)
+}
+
+/// Evaluates an expression node to Z-code.
+fn evaluate_uncached(node: ASTNode, code: &mut Vec<ZOP>,
+        temp_ids: &mut Vec<u8>, mut manager: &mut CodeGenManager, mut out: &mut Zfile) -> Result<Operand, EvaluateExpressionError> {
     let n = node.clone().as_default();
     let cfg = manager.cfg;
 
@@ -77,13 +381,17 @@ fn evaluate_expression_internal(node: ASTNode, code: &mut Vec<ZOP>,
                 if n.childs.len() >= 1 {
                     return evaluate_expression_internal(n.childs[0].clone(), code, temp_ids, manager, &mut out)
                 } else {
-                    return Operand::Const(Constant { value: 0 })
+                    return Ok(Operand::Const(Constant { value: 0 }))
                 }
             }
 
-            let eval0 = evaluate_expression_internal(n.childs[0].clone(), code, temp_ids, manager, &mut out);
-            let eval1 = evaluate_expression_internal(n.childs[1].clone(), code, temp_ids, manager, &mut out);
-            eval_num_op(&eval0, &eval1, &**op_name, location.clone(), code, temp_ids, manager)
+            if let Some(text) = static_string(&node) {
+                return Ok(Operand::new_string_ref(out.write_string(&text)));
+            }
+
+            let eval0 = try!(evaluate_expression_internal(n.childs[0].clone(), code, temp_ids, manager, &mut out));
+            let eval1 = try!(evaluate_expression_internal(n.childs[1].clone(), code, temp_ids, manager, &mut out));
+            eval_num_op(&eval0, &eval1, &**op_name, location.clone(), code, temp_ids, manager, &mut out)
         },
         TokCompOp { ref op_name, ref location } => {
             if n.childs.len() != 2 {
@@ -94,20 +402,20 @@ fn evaluate_expression_internal(node: ASTNode, code: &mut Vec<ZOP>,
                 if n.childs.len() >= 1 {
                     return evaluate_expression_internal(n.childs[0].clone(), code, temp_ids, manager, &mut out)
                 } else {
-                    return Operand::BoolConst(Constant { value: 0 })
+                    return Ok(Operand::BoolConst(Constant { value: 0 }))
                 }
             }
 
-            let eval0 = evaluate_expression_internal(n.childs[0].clone(), code, temp_ids, manager, &mut out);
-            let eval1 = evaluate_expression_internal(n.childs[1].clone(), code, temp_ids, manager, &mut out);
+            let eval0 = try!(evaluate_expression_internal(n.childs[0].clone(), code, temp_ids, manager, &mut out));
+            let eval1 = try!(evaluate_expression_internal(n.childs[1].clone(), code, temp_ids, manager, &mut out));
             eval_comp_op(&eval0, &eval1, &**op_name, location.clone(), code, temp_ids, manager)
         },
         TokLogOp { ref op_name, ref location } => {
-            let eval0 = evaluate_expression_internal(n.childs[0].clone(), code, temp_ids, manager, &mut out);
+            let eval0 = try!(evaluate_expression_internal(n.childs[0].clone(), code, temp_ids, manager, &mut out));
 
             match &**op_name {
                 "and" | "&&" | "or" | "||" => {
-                    let eval1 = evaluate_expression_internal(n.childs[1].clone(), code, temp_ids, manager, &mut out);
+                    let eval1 = try!(evaluate_expression_internal(n.childs[1].clone(), code, temp_ids, manager, &mut out));
                     eval_and_or(&eval0, &eval1, &**op_name, code, temp_ids)
                 },
                 "not" | "!" => {
@@ -121,60 +429,94 @@ fn evaluate_expression_internal(node: ASTNode, code: &mut Vec<ZOP>,
                     if n.childs.len() >= 1 {
                         return evaluate_expression_internal(n.childs[0].clone(), code, temp_ids, manager, &mut out)
                     } else {
-                        return Operand::BoolConst(Constant { value: 0 })
+                        return Ok(Operand::BoolConst(Constant { value: 0 }))
                     }
                 }
             }
         },
         TokUnaryMinus { .. } => {
-            let eval = evaluate_expression_internal(n.childs[0].clone(), code, temp_ids, manager, &mut out);
+            let eval = try!(evaluate_expression_internal(n.childs[0].clone(), code, temp_ids, manager, &mut out));
             eval_unary_minus(&eval, code, temp_ids)
         },
+        TokTernaryIf { .. } => {
+            let cond = try!(evaluate_expression_internal(n.childs[0].clone(), code, temp_ids, manager, &mut out));
+            if cond.is_const() {
+                // only the taken branch is generated
+                let taken = if cond.const_value() != 0 { 1 } else { 2 };
+                return evaluate_expression_internal(n.childs[taken].clone(), code, temp_ids, manager, &mut out)
+            }
+
+            let result: u8 = try!(pop_temp_id(temp_ids));
+            let label_else = format!("expr_{}", manager.ids_expr.start_next());
+            let label_end = format!("expr_{}", manager.ids_expr.start_next());
+            code.push(ZOP::JE{operand1: cond.clone(), operand2: Operand::new_const(0), jump_to_label: label_else.clone()});
+            free_var_if_temp(&cond, temp_ids);
+
+            // both branches are generated first to unify their types,
+            // only one of them runs so their values can't be kept
+            manager.expression_cache.conditional += 1;
+            let mut then_code: Vec<ZOP> = vec![];
+            let then_eval = try!(evaluate_expression_internal(n.childs[1].clone(), &mut then_code, temp_ids, manager, &mut out));
+            let mut else_code: Vec<ZOP> = vec![];
+            let else_eval = try!(evaluate_expression_internal(n.childs[2].clone(), &mut else_code, temp_ids, manager, &mut out));
+            manager.expression_cache.conditional -= 1;
+            let (vartype, mixed) = branch_type(&then_eval, &else_eval);
+
+            code.extend(then_code);
+            store_branch(&then_eval, result, mixed, code);
+            code.push(ZOP::Jump{jump_to_label: label_end.clone()});
+            code.push(ZOP::Label{name: label_else});
+            code.extend(else_code);
+            store_branch(&else_eval, result, mixed, code);
+            code.push(ZOP::Label{name: label_end});
+
+            free_var_if_temp(&then_eval, temp_ids);
+            free_var_if_temp(&else_eval, temp_ids);
+            Ok(Operand::Var(Variable { id: result, vartype: vartype }))
+        },
         TokInt { ref value, .. } => {
-            Operand::new_large_const(*value as i16)
+            Ok(Operand::new_large_const(*value as i16))
         },
         TokBoolean { ref value, .. } => {
-            boolstr_to_const(&**value)
+            Ok(boolstr_to_const(&**value))
         },
         TokString {ref value, .. } => {
-            Operand::new_string_ref(out.write_string(value) as i16)
+            Ok(Operand::new_string_ref(out.write_string(value)))
         },
         TokVariable { name, .. } => {
-            Operand::Var(manager.symbol_table.get_and_add_symbol_id(name))
+            Ok(Operand::Var(manager.symbol_table.get_and_add_symbol_id(name)))
         },
         TokArrayLength { name, .. } => {
-            let alen: Variable = match temp_ids.pop() {
-                Some(var) => Variable::new(var),
-                None      => error_force_panic!(EvaluateExpressionError::NoTempIdLeftOnStack)
-            };
-            let zero: Variable = match temp_ids.pop() {
-                Some(var) => Variable::new(var),
-                None      => error_force_panic!(EvaluateExpressionError::NoTempIdLeftOnStack)
-            };
+            let alen: Variable = Variable::new(try!(pop_temp_id(temp_ids)));
+            let zero: Variable = Variable::new(try!(pop_temp_id(temp_ids)));
             let var = Operand::Var(manager.symbol_table.get_and_add_symbol_id(name));
             code.push(ZOP::StoreVariable{variable: zero.clone(), value: Operand::new_large_const(0)},);
             code.push(ZOP::LoadW{array_address: var, index: zero.clone(), variable: alen.clone()});
             code.push(ZOP::SetVarType{variable: alen.clone(), vartype: Type::Integer});
             temp_ids.push(zero.id);
-            Operand::new_var(alen.id)
+            Ok(Operand::new_var(alen.id))
         },
         TokArrayAccess { name, index, .. } => {
-            let val: Variable = match temp_ids.pop() {
-                Some(var) => Variable::new(var),
-                None      => error_force_panic!(EvaluateExpressionError::NoTempIdLeftOnStack)
-            };
-            let mem: Variable = match temp_ids.pop() {
-                Some(var) => Variable::new(var),
-                None      => error_force_panic!(EvaluateExpressionError::NoTempIdLeftOnStack)
-            };
-            let ind: Variable = match temp_ids.pop() {
-                Some(var) => Variable::new(var),
-                None      => error_force_panic!(EvaluateExpressionError::NoTempIdLeftOnStack)
-            };
+            let val: Variable = Variable::new(try!(pop_temp_id(temp_ids)));
+            let mem: Variable = Variable::new(try!(pop_temp_id(temp_ids)));
+            let ind: Variable = Variable::new(try!(pop_temp_id(temp_ids)));
             let var = Operand::Var(manager.symbol_table.get_and_add_symbol_id(name));
-            let index = Operand::Var(manager.symbol_table.get_and_add_symbol_id(index));
-            code.push(ZOP::Call2S{jump_to_label: "malloc".to_string(), arg: Operand::new_const(2), result: mem.clone()});
+            let index = match index.parse::<i16>() {
+                Ok(index) => Operand::new_large_const(index),
+                Err(_) => Operand::Var(manager.symbol_table.get_and_add_symbol_id(index))
+            };
+            // the length is in the first word, an index outside of it calls rt_error with the code 4
+            let label_error = format!("expr_{}", manager.ids_expr.start_next());
+            let label_ok = format!("expr_{}", manager.ids_expr.start_next());
+            let out_of_bounds = out.write_string("OUT-OF-BOUNDS");
             code.push(ZOP::StoreVariable{variable: ind.clone(), value: Operand::new_large_const(0)});
+            code.push(ZOP::LoadW{array_address: var.clone(), index: ind.clone(), variable: val.clone()});
+            code.push(ZOP::JL{operand1: index.clone(), operand2: Operand::new_const(0), jump_to_label: label_error.clone()});
+            code.push(ZOP::JL{operand1: index.clone(), operand2: Operand::new_var(val.id), jump_to_label: label_ok.clone()});
+            code.push(ZOP::Label{name: label_error});
+            code.push(ZOP::CallVNA2{jump_to_label: "rt_error".to_string(), arg1: Operand::new_const(4), arg2: Operand::new_string_ref(out_of_bounds)});
+            code.push(ZOP::Label{name: label_ok});
+            code.push(ZOP::Call2S{jump_to_label: "malloc".to_string(), arg: Operand::new_const(2), result: mem.clone()});
             code.push(ZOP::StoreVariable{variable: val.clone(), value: Operand::new_large_const(1)});
             code.push(ZOP::StoreW{array_address: Operand::new_var(mem.id), index: ind.clone(), variable: val.clone()});
             code.push(ZOP::StoreVariable{variable: val.clone(), value: index.clone()});
@@ -185,7 +527,32 @@ fn evaluate_expression_internal(node: ASTNode, code: &mut Vec<ZOP>,
             code.push(ZOP::SetVarType{variable: mem.clone(), vartype: Type::String});
             temp_ids.push(val.id);
             temp_ids.push(ind.id);
-            Operand::new_var(mem.id)
+            Ok(Operand::new_var(mem.id))
+        },
+        TokMapLiteral { .. } => {
+            let size = map_literal_size(&node);
+            let map: Variable = Variable::new_type(try!(pop_temp_id(temp_ids)), Type::Map);
+            let index: Variable = Variable::new(try!(pop_temp_id(temp_ids)));
+            let value: Variable = Variable::new(try!(pop_temp_id(temp_ids)));
+
+            // the nested maps are part of the same block, so they are freed together
+            code.push(ZOP::Call2S{jump_to_label: "malloc".to_string(), arg: Operand::new_large_const(size as i16), result: map.clone()});
+            store_map_word(&map, &index, &value, 0, Operand::new_large_const(size as i16 - 1), code);
+            try!(write_map_literal(&node, 0, &map, &index, &value, code, temp_ids, manager, &mut out));
+
+            code.push(ZOP::SetVarType{variable: map.clone(), vartype: Type::Map});
+            temp_ids.push(value.id);
+            temp_ids.push(index.id);
+            Ok(Operand::Var(map))
+        },
+        TokMapAccess { name, key, location } => {
+            manager.map_accesses.push((manager.current_passage.clone(), location.0, key.clone()));
+            // the type of the value is only known at runtime, map_get sets it
+            let val: Variable = Variable::new_type(try!(pop_temp_id(temp_ids)), Type::None);
+            let var = Operand::Var(manager.symbol_table.get_and_add_symbol_id(name));
+            let key = Operand::new_string_ref(out.write_string(&key));
+            code.push(ZOP::CallVSA3{jump_to_label: "map_get".to_string(), arg1: var, arg2: key, arg3: Operand::new_const(val.id), result: val.clone()});
+            Ok(Operand::Var(val))
         },
         TokFunction { ref name, ref location } => {
             match &**name {
@@ -196,21 +563,21 @@ fn evaluate_expression_internal(node: ASTNode, code: &mut Vec<ZOP>,
                             name: "random".to_string(), location: location.clone(), expected: 2 };
                         error_panic!(cfg => error);
                         if args.len() <= 1 {
-                            return Operand::Const(Constant { value: 0 })
+                            return Ok(Operand::Const(Constant { value: 0 }))
                         } else {
                             warn!("Ignoring the additional arguments.");
                         }
                     }
 
                     if args[0].clone().as_default().childs.len() != 1 || args[1].clone().as_default().childs.len() != 1 {
-                        error_force_panic!(EvaluateExpressionError::InvalidAST);
+                        return Err(EvaluateExpressionError::InvalidAST);
                     }
 
                     let from = args[0].clone().as_default().childs[0].clone();
                     let to = args[1].clone().as_default().childs[0].clone();
 
-                    let from_value = evaluate_expression_internal(from, code, temp_ids, manager, &mut out);
-                    let to_value = evaluate_expression_internal(to, code, temp_ids, manager, &mut out);
+                    let from_value = try!(evaluate_expression_internal(from, code, temp_ids, manager, &mut out));
+                    let to_value = try!(evaluate_expression_internal(to, code, temp_ids, manager, &mut out));
                     codegen::function_random(manager, &from_value, &to_value, code, temp_ids, location.clone())
                 },
                 "prompt" => { // twee function prompt(message, default) - imitates the JS browser input dialog
@@ -220,116 +587,476 @@ fn evaluate_expression_internal(node: ASTNode, code: &mut Vec<ZOP>,
                             name: "prompt".to_string(), location: location.clone(), expected: 2 };
                         error_panic!(cfg => error);
                         if args.len() <= 1 {
-                            return Operand::Const(Constant { value: 0 })
+                            return Ok(Operand::Const(Constant { value: 0 }))
                         } else {
                             warn!("Ignoring the additional arguments.");
                         }
                     }
 
                     if args[0].clone().as_default().childs.len() != 1 || args[1].clone().as_default().childs.len() != 1 {
-                        error_force_panic!(EvaluateExpressionError::InvalidAST);
+                        return Err(EvaluateExpressionError::InvalidAST);
                     }
 
                     let message_n = args[0].clone().as_default().childs[0].clone();
                     let default_n = args[1].clone().as_default().childs[0].clone();
 
-                    let message = evaluate_expression_internal(message_n, code, temp_ids, manager, &mut out);
-                    let default = evaluate_expression_internal(default_n, code, temp_ids, manager, &mut out);
-                    let return_var: Variable = match temp_ids.pop() {
-                        Some(var) => Variable::new(var),
-                        None      => error_force_panic!(EvaluateExpressionError::NoTempIdLeftOnStack)
-                    };
+                    let message = try!(evaluate_expression_internal(message_n, code, temp_ids, manager, &mut out));
+                    let default = try!(evaluate_expression_internal(default_n, code, temp_ids, manager, &mut out));
+                    let return_var: Variable = Variable::new_string(try!(pop_temp_id(temp_ids)));
                     code.push(ZOP::CallVSA2{jump_to_label: "rt_prompt".to_string(), arg1: message.clone(), arg2: default.clone(), result: return_var.clone()});
                     code.push(ZOP::SetVarType{variable: return_var.clone(), vartype: Type::String});
-                    Operand::new_var(return_var.id)
+                    // the arguments are copied by rt_prompt, so their temporaries can be reused
+                    free_var_if_temp(&message, temp_ids);
+                    free_var_if_temp(&default, temp_ids);
+                    Ok(Operand::new_var_string(return_var.id))
                 },
-                "confirm" => {
-                    let state_copy = manager.format_state.clone();
-                    let args = &node.as_default().childs;
+                "addressof" if cfg.debug_build => { // raw value of a variable, e.g. the heap address of a string
+                    let args = node.clone().as_default().childs;
+                    if args.len() != 1 {
+                        let error = EvaluateExpressionError::UnsupportedFunctionArgsLen {
+                            name: "addressof".to_string(), location: location.clone(), expected: 1 };
+                        error_panic!(cfg => error);
+                        if args.len() == 0 {
+                            return Ok(Operand::Const(Constant { value: 0 }))
+                        }
+                    }
+                    if args[0].clone().as_default().childs.len() != 1 {
+                        return Err(EvaluateExpressionError::InvalidAST);
+                    }
+
+                    let variable = match args[0].clone().as_default().childs[0].clone().as_default().category {
+                        TokVariable { name, .. } => manager.symbol_table.get_and_add_symbol_id(name),
+                        _ => {
+                            error_panic!(cfg => EvaluateExpressionError::UnsupportedFunctionArgType {
+                                name: "addressof".to_string(), index: 0, location: location.clone() });
+                            return Ok(Operand::Const(Constant { value: 0 }))
+                        }
+                    };
+                    let address: Variable = Variable::new(try!(pop_temp_id(temp_ids)));
+                    code.push(ZOP::StoreVariable{variable: address.clone(), value: Operand::new_var(variable.id)});
+                    code.push(ZOP::SetVarType{variable: address.clone(), vartype: Type::Integer});
+                    Ok(Operand::new_var(address.id))
+                },
+                "passage" => { // name of the current passage, stored by every passage routine if passage() is used
+                    if node.as_default().childs.len() != 0 {
+                        let error = EvaluateExpressionError::UnsupportedFunctionArgsLen {
+                            name: "passage".to_string(), location: location.clone(), expected: 0 };
+                        error_panic!(cfg => error);
+                        warn!("Ignoring the arguments.");
+                    }
+                    Ok(Operand::new_var_string(19))
+                },
+                "plural" => { // plural(n, singular, plural) - the singular if n is 1, e.g. for "1 coin" and "2 coins"
+                    let args = node.clone().as_default().childs;
+                    if args.len() != 3 {
+                        let error = EvaluateExpressionError::UnsupportedFunctionArgsLen {
+                            name: "plural".to_string(), location: location.clone(), expected: 3 };
+                        error_panic!(cfg => error);
+                        if args.len() < 3 {
+                            return Ok(Operand::Const(Constant { value: 0 }))
+                        } else {
+                            warn!("Ignoring the additional arguments.");
+                        }
+                    }
+                    for arg in args.iter().take(3) {
+                        if arg.clone().as_default().childs.len() != 1 {
+                            return Err(EvaluateExpressionError::InvalidAST);
+                        }
+                    }
+
+                    let count = try!(evaluate_expression_internal(args[0].clone().as_default().childs[0].clone(), code, temp_ids, manager, &mut out));
+                    if count.is_const() {
+                        let chosen = args[plural_form(count.const_value())].clone().as_default().childs[0].clone();
+                        return evaluate_expression_internal(chosen, code, temp_ids, manager, &mut out)
+                    }
+
+                    let singular = try!(evaluate_expression_internal(args[1].clone().as_default().childs[0].clone(), code, temp_ids, manager, &mut out));
+                    let plural = try!(evaluate_expression_internal(args[2].clone().as_default().childs[0].clone(), code, temp_ids, manager, &mut out));
+                    let (vartype, mixed) = branch_type(&singular, &plural);
+                    let result: u8 = try!(pop_temp_id(temp_ids));
+                    let label_singular = format!("expr_{}", manager.ids_expr.start_next());
+                    let label_end = format!("expr_{}", manager.ids_expr.start_next());
+                    code.push(ZOP::JE{operand1: count.clone(), operand2: Operand::new_const(1), jump_to_label: label_singular.clone()});
+                    store_branch(&plural, result, mixed, code);
+                    code.push(ZOP::Jump{jump_to_label: label_end.clone()});
+                    code.push(ZOP::Label{name: label_singular});
+                    store_branch(&singular, result, mixed, code);
+                    code.push(ZOP::Label{name: label_end});
+
+                    free_var_if_temp(&count, temp_ids);
+                    free_var_if_temp(&singular, temp_ids);
+                    free_var_if_temp(&plural, temp_ids);
+                    Ok(Operand::Var(Variable { id: result, vartype: vartype }))
+                },
+                "linkcount" => { // links shown so far, also those of displayed passages, so it belongs behind them
+                    if node.as_default().childs.len() != 0 {
+                        let error = EvaluateExpressionError::UnsupportedFunctionArgsLen {
+                            name: "linkcount".to_string(), location: location.clone(), expected: 0 };
+                        error_panic!(cfg => error);
+                        warn!("Ignoring the arguments.");
+                    }
+                    // global 16 is the counter of system_add_link, which has no type of its own
+                    let count = Variable::new(try!(pop_temp_id(temp_ids)));
+                    code.push(ZOP::StoreVariable{variable: count.clone(), value: Operand::new_var(16)});
+                    code.push(ZOP::SetVarType{variable: count.clone(), vartype: Type::Integer});
+                    Ok(Operand::Var(count))
+                },
+                "buildtime" => { // compile time constant, e.g. for "version compiled on ..."
+                    if node.as_default().childs.len() != 0 {
+                        let error = EvaluateExpressionError::UnsupportedFunctionArgsLen {
+                            name: "buildtime".to_string(), location: location.clone(), expected: 0 };
+                        error_panic!(cfg => error);
+                        warn!("Ignoring the arguments.");
+                    }
+                    Ok(Operand::new_string_ref(out.write_string(&manager.build_time)))
+                },
+                "compiler_version" => { // version of zwreec, e.g. for the credits
+                    if node.as_default().childs.len() != 0 {
+                        let error = EvaluateExpressionError::UnsupportedFunctionArgsLen {
+                            name: "compiler_version".to_string(), location: location.clone(), expected: 0 };
+                        error_panic!(cfg => error);
+                        warn!("Ignoring the arguments.");
+                    }
+                    Ok(Operand::new_string_ref(out.write_string(env!("CARGO_PKG_VERSION"))))
+                },
+                "config" => { // config("no-colours") - a feature of the compilation, known at compile time
+                    let args = node.clone().as_default().childs;
+                    if args.len() != 1 {
+                        let error = EvaluateExpressionError::UnsupportedFunctionArgsLen {
+                            name: "config".to_string(), location: location.clone(), expected: 1 };
+                        error_panic!(cfg => error);
+                        if args.len() == 0 {
+                            return Ok(Operand::BoolConst(Constant { value: 0 }))
+                        } else {
+                            warn!("Ignoring the additional arguments.");
+                        }
+                    }
+                    if args[0].clone().as_default().childs.len() != 1 {
+                        return Err(EvaluateExpressionError::InvalidAST);
+                    }
+
+                    let enabled = match args[0].clone().as_default().childs[0].clone().as_default().category {
+                        TokString { ref value, .. } => match cfg.feature(value) {
+                            Some(enabled) => enabled,
+                            None => {
+                                error_panic!(cfg => EvaluateExpressionError::UnknownConfigName { name: value.clone(), location: location.clone() });
+                                false
+                            }
+                        },
+                        _ => {
+                            error_panic!(cfg => EvaluateExpressionError::UnsupportedFunctionArgType {
+                                name: "config".to_string(), index: 0, location: location.clone() });
+                            false
+                        }
+                    };
+                    Ok(Operand::BoolConst(Constant { value: if enabled { 1 } else { 0 } }))
+                },
+                "confirm" | "pause_confirm" => { // yes/no question, or a question that waits for any key
+                    let args = node.clone().as_default().childs;
+                    if args.len() != 1 {
+                        let error = EvaluateExpressionError::UnsupportedFunctionArgsLen {
+                            name: name.clone(), location: location.clone(), expected: 1 };
+                        error_panic!(cfg => error);
+                        if args.len() == 0 {
+                            return Ok(Operand::Const(Constant { value: 0 }))
+                        } else {
+                            warn!("Ignoring the additional arguments.");
+                        }
+                    }
+                    if args[0].clone().as_default().childs.len() != 1 {
+                        return Err(EvaluateExpressionError::InvalidAST);
+                    }
+
+                    let question = try!(evaluate_expression_internal(args[0].clone().as_default().childs[0].clone(), code, temp_ids, manager, &mut out));
+                    let answer = try!(codegen::function_confirm(manager, &question, name == "pause_confirm", code, temp_ids, location.clone()));
+                    free_var_if_temp(&question, temp_ids);
+                    Ok(answer)
+                },
+                "shuffle" => { // deck of integers for draw(), layout: [length, remaining, values...]
+                    let args = node.clone().as_default().childs;
+                    if args.len() == 0 || args.len() > 253 {
+                        let error = EvaluateExpressionError::UnsupportedFunctionArgsLen {
+                            name: "shuffle".to_string(), location: location.clone(), expected: 1 };
+                        error_panic!(cfg => error);
+                        return Ok(Operand::Const(Constant { value: 0 }))
+                    }
+
+                    let deck: Variable = Variable::new(try!(pop_temp_id(temp_ids)));
+                    let index: Variable = Variable::new(try!(pop_temp_id(temp_ids)));
+                    let value: Variable = Variable::new(try!(pop_temp_id(temp_ids)));
+
+                    // the length word counts the remaining-word and the values, as malloc expects
+                    code.push(ZOP::Call2S{jump_to_label: "malloc".to_string(), arg: Operand::new_const(args.len() as u8 + 2), result: deck.clone()});
+                    code.push(ZOP::StoreVariable{variable: index.clone(), value: Operand::new_large_const(0)});
+                    code.push(ZOP::StoreVariable{variable: value.clone(), value: Operand::new_large_const(args.len() as i16 + 1)});
+                    code.push(ZOP::StoreW{array_address: Operand::new_var(deck.id), index: index.clone(), variable: value.clone()});
+                    code.push(ZOP::Inc{variable: index.id});
+                    code.push(ZOP::StoreVariable{variable: value.clone(), value: Operand::new_large_const(args.len() as i16)});
+                    code.push(ZOP::StoreW{array_address: Operand::new_var(deck.id), index: index.clone(), variable: value.clone()});
+
+                    for (i, arg) in args.iter().enumerate() {
+                        if arg.clone().as_default().childs.len() != 1 {
+                            return Err(EvaluateExpressionError::InvalidAST);
+                        }
+                        let eval = try!(evaluate_expression_internal(arg.clone().as_default().childs[0].clone(), code, temp_ids, manager, &mut out));
+                        match eval {
+                            Operand::StringRef(_) => {
+                                error_panic!(cfg => EvaluateExpressionError::UnsupportedFunctionArgType {
+                                    name: "shuffle".to_string(), index: i as u64, location: location.clone() });
+                            },
+                            Operand::Var(ref var) if var.vartype == Type::String => {
+                                error_panic!(cfg => EvaluateExpressionError::UnsupportedFunctionArgType {
+                                    name: "shuffle".to_string(), index: i as u64, location: location.clone() });
+                            },
+                            _ => {}
+                        }
+                        code.push(ZOP::Inc{variable: index.id});
+                        code.push(ZOP::StoreVariable{variable: value.clone(), value: eval.clone()});
+                        code.push(ZOP::StoreW{array_address: Operand::new_var(deck.id), index: index.clone(), variable: value.clone()});
+                        free_var_if_temp(&eval, temp_ids);
+                    }
+
+                    code.push(ZOP::SetVarType{variable: deck.clone(), vartype: Type::Integer});
+                    temp_ids.push(value.id);
+                    temp_ids.push(index.id);
+                    Ok(Operand::new_var(deck.id))
+                },
+                "draw" => { // removes a random value from a deck created by shuffle()
+                    let args = node.clone().as_default().childs;
                     if args.len() != 1 {
                         let error = EvaluateExpressionError::UnsupportedFunctionArgsLen {
-                            name: "confirm".to_string(), location: location.clone(), expected: 2 };
+                            name: "draw".to_string(), location: location.clone(), expected: 1 };
                         error_panic!(cfg => error);
+                        if args.len() == 0 {
+                            return Ok(Operand::Const(Constant { value: 0 }))
+                        }
                     }
                     if args[0].clone().as_default().childs.len() != 1 {
-                        error_force_panic!(EvaluateExpressionError::InvalidAST);
+                        return Err(EvaluateExpressionError::InvalidAST);
+                    }
+
+                    let deck = try!(evaluate_expression_internal(args[0].clone().as_default().childs[0].clone(), code, temp_ids, manager, &mut out));
+                    let drawn: Variable = Variable::new(try!(pop_temp_id(temp_ids)));
+                    code.push(ZOP::Call2S{jump_to_label: "draw".to_string(), arg: deck.clone(), result: drawn.clone()});
+                    code.push(ZOP::SetVarType{variable: drawn.clone(), vartype: Type::Integer});
+                    free_var_if_temp(&deck, temp_ids);
+                    Ok(Operand::new_var(drawn.id))
+                },
+                "join" => { // join(deck, separator) - the values left in a deck as one string, like "1, 2, 3"
+                    let args = node.clone().as_default().childs;
+                    if args.len() != 2 {
+                        let error = EvaluateExpressionError::UnsupportedFunctionArgsLen {
+                            name: "join".to_string(), location: location.clone(), expected: 2 };
+                        error_panic!(cfg => error);
+                        if args.len() < 2 {
+                            return Ok(Operand::Const(Constant { value: 0 }))
+                        } else {
+                            warn!("Ignoring the additional arguments.");
+                        }
+                    }
+                    for arg in args.iter().take(2) {
+                        if arg.clone().as_default().childs.len() != 1 {
+                            return Err(EvaluateExpressionError::InvalidAST);
+                        }
                     }
-                    let child = args[0].clone().as_default().childs[0].clone().as_default();
-                    let confirm_msg = match child.category {
-                        TokString {ref value, .. } => value,
-                        _ => error_force_panic!(EvaluateExpressionError::InvalidAST)
+
+                    let deck = try!(evaluate_expression_internal(args[0].clone().as_default().childs[0].clone(), code, temp_ids, manager, &mut out));
+                    // only variables and shuffle() can be decks, the routine checks the rest at runtime
+                    let is_deck = match deck {
+                        Operand::Var(ref var) => var.vartype == Type::Integer || var.vartype == Type::None,
+                        _ => false
+                    };
+                    if !is_deck {
+                        error_panic!(cfg => EvaluateExpressionError::UnsupportedFunctionArgType {
+                            name: "join".to_string(), index: 0, location: location.clone() });
+                    }
+                    let separator = try!(evaluate_expression_internal(args[1].clone().as_default().childs[0].clone(), code, temp_ids, manager, &mut out));
+                    let is_string = match separator {
+                        Operand::StringRef(_) => true,
+                        Operand::Var(ref var) => var.vartype == Type::String,
+                        _ => false
                     };
+                    if !is_string {
+                        error_panic!(cfg => EvaluateExpressionError::UnsupportedFunctionArgType {
+                            name: "join".to_string(), index: 1, location: location.clone() });
+                    }
+
+                    free_var_if_temp(&deck, temp_ids);
+                    free_var_if_temp(&separator, temp_ids);
+                    let joined: Variable = Variable::new(try!(pop_temp_id(temp_ids)));
+                    code.push(ZOP::CallVSA2{jump_to_label: "join".to_string(), arg1: deck, arg2: separator, result: joined.clone()});
+                    code.push(ZOP::SetVarType{variable: joined.clone(), vartype: Type::String});
+                    Ok(Operand::new_var_string(joined.id))
+                },
+                "bar" => { // bar(value, max, width) - a bar like [###-------] for health displays
+                    let args = node.clone().as_default().childs;
+                    if args.len() != 3 {
+                        let error = EvaluateExpressionError::UnsupportedFunctionArgsLen {
+                            name: "bar".to_string(), location: location.clone(), expected: 3 };
+                        error_panic!(cfg => error);
+                        if args.len() < 3 {
+                            return Ok(Operand::Const(Constant { value: 0 }))
+                        } else {
+                            warn!("Ignoring the additional arguments.");
+                        }
+                    }
 
-                    let has_confirmed: Variable = match temp_ids.pop() {
-                        Some(var) => Variable::new(var),
-                        None      => error_force_panic!(EvaluateExpressionError::NoTempIdLeftOnStack)
+                    let mut values: Vec<Operand> = vec![];
+                    for (i, arg) in args.iter().take(3).enumerate() {
+                        if arg.clone().as_default().childs.len() != 1 {
+                            return Err(EvaluateExpressionError::InvalidAST);
+                        }
+                        let eval = try!(evaluate_expression_internal(arg.clone().as_default().childs[0].clone(), code, temp_ids, manager, &mut out));
+                        let is_string = match eval {
+                            Operand::StringRef(_) => true,
+                            Operand::Var(ref var) => var.vartype == Type::String,
+                            _ => false
+                        };
+                        if is_string {
+                            error_panic!(cfg => EvaluateExpressionError::UnsupportedFunctionArgType {
+                                name: "bar".to_string(), index: i as u64, location: location.clone() });
+                        }
+                        values.push(eval);
+                    }
+
+                    if values.iter().all(|value| value.is_const()) {
+                        let text = bar_string(values[0].const_value(), values[1].const_value(), values[2].const_value());
+                        return Ok(Operand::new_string_ref(out.write_string(&text)));
+                    }
+
+                    for value in values.iter() {
+                        free_var_if_temp(value, temp_ids);
+                    }
+                    let bar: Variable = Variable::new(try!(pop_temp_id(temp_ids)));
+                    code.push(ZOP::CallVSA3{jump_to_label: "bar".to_string(), arg1: values[0].clone(), arg2: values[1].clone(), arg3: values[2].clone(), result: bar.clone()});
+                    code.push(ZOP::SetVarType{variable: bar.clone(), vartype: Type::String});
+                    Ok(Operand::new_var_string(bar.id))
+                },
+                "upper" | "lower" => { // copy of a string with the ASCII letters converted, other characters are kept
+                    let args = node.clone().as_default().childs;
+                    if args.len() != 1 {
+                        let error = EvaluateExpressionError::UnsupportedFunctionArgsLen {
+                            name: name.clone(), location: location.clone(), expected: 1 };
+                        error_panic!(cfg => error);
+                        if args.len() == 0 {
+                            return Ok(Operand::Const(Constant { value: 0 }))
+                        } else {
+                            warn!("Ignoring the additional arguments.");
+                        }
+                    }
+                    if args[0].clone().as_default().childs.len() != 1 {
+                        return Err(EvaluateExpressionError::InvalidAST);
+                    }
+
+                    let upper = name == "upper";
+                    let arg = args[0].clone().as_default().childs[0].clone();
+                    if let TokString { ref value, .. } = arg.clone().as_default().category {
+                        return Ok(Operand::new_string_ref(out.write_string(&change_case(value, upper))));
+                    }
+
+                    let value = try!(evaluate_expression_internal(arg, code, temp_ids, manager, &mut out));
+                    let converted: Variable = Variable::new(try!(pop_temp_id(temp_ids)));
+                    // numbers and booleans are converted to strings first, so the routine needs the type
+                    match value {
+                        Operand::StringRef(_) => code.push(ZOP::StoreVariable{variable: converted.clone(), value: Operand::new_const(Type::String as u8)}),
+                        Operand::Var(ref var) => code.push(ZOP::GetVarType{variable: var.clone(), result: converted.clone()}),
+                        Operand::BoolConst(_) => code.push(ZOP::StoreVariable{variable: converted.clone(), value: Operand::new_const(Type::Bool as u8)}),
+                        _ => code.push(ZOP::StoreVariable{variable: converted.clone(), value: Operand::new_const(Type::Integer as u8)})
                     };
+                    code.push(ZOP::CallVSA3{jump_to_label: "change_case".to_string(), arg1: value.clone(), arg2: Operand::new_var(converted.id),
+                        arg3: Operand::new_const(if upper { 1 } else { 0 }), result: converted.clone()});
+                    code.push(ZOP::SetVarType{variable: converted.clone(), vartype: Type::String});
+                    free_var_if_temp(&value, temp_ids);
+                    Ok(Operand::new_var_string(converted.id))
+                },
+                "substr" => { // substr(s, start, len) - a copy of len characters from start on, the first character is 0
+                    let args = node.clone().as_default().childs;
+                    if args.len() != 3 {
+                        let error = EvaluateExpressionError::UnsupportedFunctionArgsLen {
+                            name: "substr".to_string(), location: location.clone(), expected: 3 };
+                        error_panic!(cfg => error);
+                        if args.len() < 3 {
+                            return Ok(Operand::Const(Constant { value: 0 }))
+                        } else {
+                            warn!("Ignoring the additional arguments.");
+                        }
+                    }
+                    for arg in args.iter().take(3) {
+                        if arg.clone().as_default().childs.len() != 1 {
+                            return Err(EvaluateExpressionError::InvalidAST);
+                        }
+                    }
 
-                    //let confirm_msg = &args[0].clone().as_default().childs[0].clone();
-                    //println!("confirm_msg: {:?}", confirm_msg);
-                    let if_id = manager.ids_if.start_next();
-                    let true_label = format!("true_{}", if_id);
-                    let false_label = format!("false_{}", if_id);
-                    let repeat_label = format!("repeat_{}", if_id);
-                    let end_label = format!("end_{}", if_id);
-
-                    code.push(ZOP::SetTextStyle{bold: true, reverse: state_copy.inverted, monospace: true, italic: state_copy.italic});
-                    code.push(ZOP::Label{name: repeat_label.to_string()});
-                    code.push(ZOP::PrintOps{text: "------------------------------------------------------------".to_string()});
-                    code.push(ZOP::Newline);
-                    code.push(ZOP::PrintOps{text: "|  ".to_string()+&confirm_msg.to_string()});
-                    code.push(ZOP::Newline);
-                    code.push(ZOP::PrintOps{text: "|  Confirm with the 1-key or deny with the 0-key".to_string()});
-                    code.push(ZOP::Newline);
-                    code.push(ZOP::PrintOps{text: "------------------------------------------------------------".to_string()});
-                    code.push(ZOP::Newline);
-                    code.push(ZOP::ReadChar{local_var_id: has_confirmed.id});
-
-                    //code.push(ZOP::PrintNumVar{variable: has_confirmed.clone()});
-                    code.push(ZOP::JE{operand1: Operand::new_var(has_confirmed.id), operand2: Operand::new_const(48), jump_to_label: false_label.to_string()});
-                    code.push(ZOP::JE{operand1: Operand::new_var(has_confirmed.id), operand2: Operand::new_const(49), jump_to_label: true_label.to_string()});
-                    code.push(ZOP::PrintOps{text: "Error, this key wasn't possible.".to_string()});
-                    code.push(ZOP::Newline);
-                    code.push(ZOP::Jump{jump_to_label: repeat_label.to_string()});
-                    code.push(ZOP::Label{name: true_label.to_string()});
-                    code.push(ZOP::StoreVariable{variable: has_confirmed.clone(), value: Operand::BoolConst(Constant {value: 1})});
-                    code.push(ZOP::Jump{jump_to_label: end_label.to_string()});
-                    code.push(ZOP::Label{name: false_label.to_string()});
-                    code.push(ZOP::StoreVariable{variable: has_confirmed.clone(), value: Operand::BoolConst(Constant {value: 0})});
-                    code.push(ZOP::Label{name: end_label.to_string()});
-                    code.push(ZOP::SetTextStyle{bold: state_copy.bold, reverse: state_copy.inverted, monospace: state_copy.mono, italic: state_copy.italic});
-                    code.push(ZOP::SetVarType{variable: Variable::new(has_confirmed.id), vartype: Type::Bool});
-                    Operand::new_var(has_confirmed.id)
+                    let source = args[0].clone().as_default().childs[0].clone();
+                    let mut bounds: Vec<Operand> = vec![];
+                    for (i, arg) in args.iter().take(3).enumerate().skip(1) {
+                        let eval = try!(evaluate_expression_internal(arg.clone().as_default().childs[0].clone(), code, temp_ids, manager, &mut out));
+                        let is_string = match eval {
+                            Operand::StringRef(_) => true,
+                            Operand::Var(ref var) => var.vartype == Type::String,
+                            _ => false
+                        };
+                        if is_string {
+                            error_panic!(cfg => EvaluateExpressionError::UnsupportedFunctionArgType {
+                                name: "substr".to_string(), index: i as u64, location: location.clone() });
+                        }
+                        bounds.push(eval);
+                    }
+
+                    if let TokString { ref value, .. } = source.clone().as_default().category {
+                        if bounds.iter().all(|bound| bound.is_const()) {
+                            let (text, clamped) = substr_string(value, bounds[0].const_value(), bounds[1].const_value());
+                            if clamped {
+                                let passage = manager.current_passage.clone();
+                                manager.diagnostics.warn(Warning::SubstrBounds, &passage, Some(location.0),
+                                    format!("substr() at {}:{} is out of the bounds of \"{}\", the substring is \"{}\"", location.0, location.1, value, text));
+                            }
+                            return Ok(Operand::new_string_ref(out.write_string(&text)));
+                        }
+                    }
+
+                    let value = try!(evaluate_expression_internal(source, code, temp_ids, manager, &mut out));
+                    let substring: Variable = Variable::new(try!(pop_temp_id(temp_ids)));
+                    // numbers and booleans are converted to strings first, so the routine needs the type
+                    match value {
+                        Operand::StringRef(_) => code.push(ZOP::StoreVariable{variable: substring.clone(), value: Operand::new_const(Type::String as u8)}),
+                        Operand::Var(ref var) => code.push(ZOP::GetVarType{variable: var.clone(), result: substring.clone()}),
+                        Operand::BoolConst(_) => code.push(ZOP::StoreVariable{variable: substring.clone(), value: Operand::new_const(Type::Bool as u8)}),
+                        _ => code.push(ZOP::StoreVariable{variable: substring.clone(), value: Operand::new_const(Type::Integer as u8)})
+                    };
+                    // the line is printed in the warning if start or len are out of range
+                    code.push(ZOP::CallVS2A5{jump_to_label: "substr".to_string(), arg1: value.clone(), arg2: Operand::new_var(substring.id),
+                        arg3: bounds[0].clone(), arg4: bounds[1].clone(), arg5: Operand::new_large_const(location.0 as i16), result: substring.clone()});
+                    code.push(ZOP::SetVarType{variable: substring.clone(), vartype: Type::String});
+                    free_var_if_temp(&value, temp_ids);
+                    for bound in bounds.iter() {
+                        free_var_if_temp(bound, temp_ids);
+                    }
+                    Ok(Operand::new_var_string(substring.id))
                 },
                 _ => {
                     error_panic!(cfg => EvaluateExpressionError::UnsupportedFunction { name: name.clone(), location: location.clone() });
-                    Operand::Const(Constant { value: 0 })
+                    Ok(Operand::Const(Constant { value: 0 }))
                 }
             }
         },
         _ => {
             error_panic!(cfg => EvaluateExpressionError::UnhandledToken { token: n.category.clone() } );
-            Operand::Const(Constant { value: 0 })
+            Ok(Operand::Const(Constant { value: 0 }))
         }
     }
 }
 
 /// Evaluates a numerical operator to Z-Code.
-fn eval_num_op(eval0: &Operand, eval1: &Operand, op_name: &str, location: (u64, u64), code: &mut Vec<ZOP>, temp_ids: &mut Vec<u8>, manager: &CodeGenManager) -> Operand {
+fn eval_num_op(eval0: &Operand, eval1: &Operand, op_name: &str, location: (u64, u64), code: &mut Vec<ZOP>, temp_ids: &mut Vec<u8>, manager: &mut CodeGenManager, out: &mut Zfile) -> Result<Operand, EvaluateExpressionError> {
     if count_constants(eval0, eval1) == 2 {
-        return direct_eval_num_op(eval0, eval1, op_name, location, manager);
+        return Ok(direct_eval_num_op(eval0, eval1, op_name, location, manager));
     }
-    let save_var = determine_save_var(eval0, eval1, temp_ids);
+    let save_var = try!(determine_save_var(eval0, eval1, temp_ids));
     match op_name {
         "+" => {
-            let tmp1: Variable = match temp_ids.pop() {
-                Some(var) => Variable::new(var),
-                None      => error_force_panic!(EvaluateExpressionError::NoTempIdLeftOnStack)
-            };
-            let tmp2: Variable = match temp_ids.pop() {
-                Some(var) => Variable::new(var),
-                None      => error_force_panic!(EvaluateExpressionError::NoTempIdLeftOnStack)
-            };
+            let tmp1: Variable = Variable::new(try!(pop_temp_id(temp_ids)));
+            let tmp2: Variable = Variable::new(try!(pop_temp_id(temp_ids)));
             code.push(ZOP::AddTypes{operand1: eval0.clone(), operand2: eval1.clone(), tmp1: tmp1.clone(), tmp2: tmp2.clone(), save_variable: save_var.clone()});
             free_var_if_temp(&Operand::new_var(tmp1.id), temp_ids);
             free_var_if_temp(&Operand::new_var(tmp2.id), temp_ids);
@@ -343,10 +1070,12 @@ fn eval_num_op(eval0: &Operand, eval1: &Operand, op_name: &str, location: (u64,
             code.push(ZOP::SetVarType{variable: save_var.clone(), vartype: save_var.vartype.clone()});
         },
         "/" => {
+            check_divisor(eval1, code, manager, out);
             code.push(ZOP::Div{operand1: eval0.clone(), operand2: eval1.clone(), save_variable: save_var.clone()});
             code.push(ZOP::SetVarType{variable: save_var.clone(), vartype: save_var.vartype.clone()});
         },
         "%" => {
+            check_divisor(eval1, code, manager, out);
             code.push(ZOP::Mod{operand1: eval0.clone(), operand2: eval1.clone(), save_variable: save_var.clone()});
             code.push(ZOP::SetVarType{variable: save_var.clone(), vartype: save_var.vartype.clone()});
         },
@@ -356,7 +1085,23 @@ fn eval_num_op(eval0: &Operand, eval1: &Operand, op_name: &str, location: (u64,
     };
     free_var_if_both_temp(eval0, eval1, temp_ids);
 
-    Operand::Var(save_var)
+    Ok(Operand::Var(save_var))
+}
+
+/// Calls `rt_error` with the code 3 if the divisor is zero, the Z-Machine doesn't define the
+/// result of a division by zero.
+pub fn check_divisor(divisor: &Operand, code: &mut Vec<ZOP>, manager: &mut CodeGenManager, out: &mut Zfile) {
+    if divisor.is_const() {
+        if divisor.const_value() != 0 {
+            return
+        }
+        warn!("Dividing by zero, this is a runtime error.");
+    }
+    let label_ok = format!("expr_{}", manager.ids_expr.start_next());
+    let message = out.write_string("DIVISION-BY-ZERO");
+    code.push(ZOP::JNE{operand1: divisor.clone(), operand2: Operand::new_const(0), jump_to_label: label_ok.clone()});
+    code.push(ZOP::CallVNA2{jump_to_label: "rt_error".to_string(), arg1: Operand::new_const(3), arg2: Operand::new_string_ref(message)});
+    code.push(ZOP::Label{name: label_ok});
 }
 
 /// Directly evaluates constants.
@@ -366,21 +1111,22 @@ fn direct_eval_num_op(eval0: &Operand, eval1: &Operand, op_name: &str, location:
     let val1 = eval1.const_value();
     match eval0 { &Operand::LargeConst(_) => {out_large = true; }, _ => {} };
     match eval1 { &Operand::LargeConst(_) => {out_large = true; }, _ => {} };
+    // the Z-Machine wraps around on overflow
     let result = match op_name {
         "+" => {
-            val0 + val1
+            val0.wrapping_add(val1)
         },
         "-" => {
-            val0 - val1
+            val0.wrapping_sub(val1)
         },
         "*" => {
-            val0 * val1
+            val0.wrapping_mul(val1)
         },
         "/" => {
-            val0 / val1
+            val0.wrapping_div(val1)
         },
         "%" => {
-            val0 % val1
+            val0.wrapping_rem(val1)
         },
         _ => {
             error_panic!(manager.cfg => EvaluateExpressionError::UnsupportedOperator { op_name: op_name.to_string(), location: location.clone() });
@@ -397,14 +1143,11 @@ fn direct_eval_num_op(eval0: &Operand, eval1: &Operand, op_name: &str, location:
 
 /// Evaluates comparison operators to Z-Code.
 fn eval_comp_op(eval0: &Operand, eval1: &Operand, op_name: &str, location: (u64, u64), code: &mut Vec<ZOP>,
-        temp_ids: &mut Vec<u8>, mut manager: &mut CodeGenManager) -> Operand {
+        temp_ids: &mut Vec<u8>, mut manager: &mut CodeGenManager) -> Result<Operand, EvaluateExpressionError> {
     if count_constants(eval0, eval1) == 2 {
-        return direct_eval_comp_op(eval0, eval1, op_name, location.clone(), manager);
+        return Ok(direct_eval_comp_op(eval0, eval1, op_name, location.clone(), manager));
     }
-    let save_var: Variable = match temp_ids.pop() {
-        Some(var) => Variable::new_bool(var),
-        None      => error_force_panic!(EvaluateExpressionError::NoTempIdLeftOnStack)
-    };
+    let save_var: Variable = Variable::new_bool(try!(pop_temp_id(temp_ids)));
     let label_is_bool = format!("expr_{}", manager.ids_expr.start_next());
     let label_is_string = format!("expr_{}", manager.ids_expr.start_next());
     let label = format!("expr_{}", manager.ids_expr.start_next()); // label return
@@ -546,7 +1289,7 @@ fn eval_comp_op(eval0: &Operand, eval1: &Operand, op_name: &str, location: (u64,
     code.push(ZOP::SetVarType{variable: save_var.clone(), vartype: Type::Bool});
     free_var_if_temp(eval0, temp_ids);
     free_var_if_temp(eval1, temp_ids);
-    Operand::Var(save_var)
+    Ok(Operand::Var(save_var))
 }
 
 /// Directly evaluates the given compare operation.
@@ -576,7 +1319,7 @@ fn direct_eval_comp_op(eval0: &Operand, eval1: &Operand, op_name: &str, location
 
 /// Evaluates both operands and applies an OR operation to them.
 fn eval_and_or(eval0: &Operand, eval1: &Operand, op_name: &str, code: &mut Vec<ZOP>,
-        temp_ids: &mut Vec<u8>) -> Operand {
+        temp_ids: &mut Vec<u8>) -> Result<Operand, EvaluateExpressionError> {
     if count_constants(&eval0, &eval1) == 2 {
         let val0 = eval0.const_value();
         let val1 = eval1.const_value();
@@ -585,10 +1328,10 @@ fn eval_and_or(eval0: &Operand, eval1: &Operand, op_name: &str, code: &mut Vec<Z
             } else {
                 val0 & val1
             };
-        return Operand::BoolConst(Constant { value: if result == 0 { 0 } else { 1 } });
+        return Ok(Operand::BoolConst(Constant { value: if result == 0 { 0 } else { 1 } }));
     }
 
-    let save_var = determine_save_var(eval0, eval1, temp_ids);
+    let save_var = try!(determine_save_var(eval0, eval1, temp_ids));
     if op_name == "or" || op_name == "||" {
         code.push(ZOP::Or{operand1: eval0.clone(), operand2: eval1.clone(), save_variable: save_var.clone()});
     } else {
@@ -596,21 +1339,18 @@ fn eval_and_or(eval0: &Operand, eval1: &Operand, op_name: &str, code: &mut Vec<Z
     }
     code.push(ZOP::SetVarType{variable: save_var.clone(), vartype: Type::Bool});
     free_var_if_both_temp(eval0, eval1, temp_ids);
-    Operand::new_var_bool(save_var.id)
+    Ok(Operand::new_var_bool(save_var.id))
 }
 
 /// Evaluates the operand and applies a NOT operation.
 fn eval_not(eval: &Operand, code: &mut Vec<ZOP>,
-        temp_ids: &mut Vec<u8>, mut manager: &mut CodeGenManager) -> Operand {
+        temp_ids: &mut Vec<u8>, mut manager: &mut CodeGenManager) -> Result<Operand, EvaluateExpressionError> {
     if eval.is_const() {
         let val = eval.const_value();
         let result: u8 = if val != 0 { 0 } else { 1 };
-        return Operand::BoolConst(Constant { value: result });
+        return Ok(Operand::BoolConst(Constant { value: result }));
     }
-    let save_var: Variable = match temp_ids.pop() {
-        Some(var) => Variable::new_bool(var),
-        None      => error_force_panic!(EvaluateExpressionError::NoTempIdLeftOnStack)
-    };
+    let save_var: Variable = Variable::new_bool(try!(pop_temp_id(temp_ids)));
     let label = format!("expr_{}", manager.ids_expr.start_next());
     code.push(ZOP::StoreVariable{ variable: save_var.clone(), value: Operand::new_const(0)});
     code.push(ZOP::JNE{operand1: eval.clone(), operand2: Operand::new_const(0), jump_to_label: label.to_string()});
@@ -618,17 +1358,17 @@ fn eval_not(eval: &Operand, code: &mut Vec<ZOP>,
     code.push(ZOP::Label {name: label.to_string()});
     code.push(ZOP::SetVarType{variable: save_var.clone(), vartype: save_var.vartype.clone()});
     free_var_if_temp(eval, temp_ids);
-    Operand::Var(save_var)
+    Ok(Operand::Var(save_var))
 }
 
 /// Evaluates the operand and applies a unary minus operation.
-fn eval_unary_minus(eval: &Operand, code: &mut Vec<ZOP>, temp_ids: &mut Vec<u8>) -> Operand {
+fn eval_unary_minus(eval: &Operand, code: &mut Vec<ZOP>, temp_ids: &mut Vec<u8>) -> Result<Operand, EvaluateExpressionError> {
     if eval.is_const() {
         let large = match eval { &Operand::LargeConst(_) => { true }, _ => { false } };
         if large {
-            return Operand::new_large_const(-eval.const_value());
+            return Ok(Operand::new_large_const(eval.const_value().wrapping_neg()));
         } else {
-            return Operand::new_const(-eval.const_value() as u8);
+            return Ok(Operand::new_const(-eval.const_value() as u8));
         }
     }
 
@@ -637,25 +1377,136 @@ fn eval_unary_minus(eval: &Operand, code: &mut Vec<ZOP>, temp_ids: &mut Vec<u8>)
             if CodeGenManager::is_temp_var(var) {
                 Variable::new(var.id)
             } else {
-                if let Some(temp) = temp_ids.pop() {
-                    Variable::new(temp)
-                } else {
-                    error_force_panic!(EvaluateExpressionError::NoTempIdLeftOnStack)
-                }
+                Variable::new(try!(pop_temp_id(temp_ids)))
             }
         }, _ => {
-            if let Some(temp) = temp_ids.pop() {
-                Variable::new(temp)
-            } else {
-                error_force_panic!(EvaluateExpressionError::NoTempIdLeftOnStack)
-            }
+            Variable::new(try!(pop_temp_id(temp_ids)))
         }
     };
 
     code.push(ZOP::Sub {operand1: Operand::new_const(0), operand2: eval.clone(), save_variable: save_var.clone()});
     code.push(ZOP::SetVarType{variable: save_var.clone(), vartype: Type::Integer});
 
-    Operand::new_var(save_var.id)
+    Ok(Operand::new_var(save_var.id))
+}
+
+/// Returns the type of the operand if it is known at compile time.
+///
+/// Temporary variables keep the type of the operation that wrote them, every other variable may
+/// hold any type.
+fn static_type(operand: &Operand) -> Option<Type> {
+    match operand {
+        &Operand::StringRef(_) => Some(Type::String),
+        &Operand::BoolConst(_) => Some(Type::Bool),
+        &Operand::Var(ref var) if CodeGenManager::is_temp_var(var) => Some(var.vartype.clone()),
+        &Operand::Var(_) => None,
+        _ => Some(Type::Integer)
+    }
+}
+
+/// Returns the type of an expression that is one of the two values, and whether an integer
+/// value has to be converted to a string for it.
+fn branch_type(then_eval: &Operand, else_eval: &Operand) -> (Type, bool) {
+    let then_type = static_type(then_eval);
+    let else_type = static_type(else_eval);
+    let vartype = match (then_type.clone(), else_type.clone()) {
+        (Some(Type::String), _) | (_, Some(Type::String)) => Type::String,
+        (Some(Type::Bool), Some(Type::Bool)) => Type::Bool,
+        _ => Type::Integer
+    };
+    let mixed = then_type != else_type && then_type.is_some() && else_type.is_some() && vartype == Type::String;
+    (vartype, mixed)
+}
+
+/// Stores the value of a branch of a conditional in the result variable.
+///
+/// If the other branch is a string, an integer is converted to a string.
+fn store_branch(eval: &Operand, result: u8, mixed: bool, code: &mut Vec<ZOP>) {
+    let variable = Variable::new(result);
+    if mixed && static_type(eval) == Some(Type::Integer) {
+        code.push(ZOP::Call2S{jump_to_label: "itoa".to_string(), arg: eval.clone(), result: variable.clone()});
+        code.push(ZOP::SetVarType{variable: variable, vartype: Type::String});
+    } else {
+        code.push(ZOP::StoreVariable{variable: variable.clone(), value: eval.clone()});
+        code.push(ZOP::CopyVarType{variable: variable, from: eval.clone()});
+    }
+}
+
+/// Returns the number of words of a map literal, including the maps nested in it.
+///
+/// A map is the malloc length, the number n of entries, the n keys, the n values and the n
+/// types of the values. The nested maps follow behind it.
+fn map_literal_size(literal: &ASTNode) -> usize {
+    literal.childs().chunks(2).fold(2, |size, entry| size + 3 + match entry.get(1).and_then(nested_map) {
+        Some(nested) => map_literal_size(nested),
+        None => 0
+    })
+}
+
+/// Returns the map literal of the value of a map entry, if it is one.
+fn nested_map(value: &ASTNode) -> Option<&ASTNode> {
+    value.childs().first().and_then(|child| match child.category() {
+        TokMapLiteral { .. } => Some(child),
+        _ => None
+    })
+}
+
+/// Stores the operand at the word of the map, `index` and `value` are overwritten.
+fn store_map_word(map: &Variable, index: &Variable, value: &Variable, word: usize, operand: Operand, code: &mut Vec<ZOP>) {
+    code.push(ZOP::StoreVariable{variable: index.clone(), value: Operand::new_large_const(word as i16)});
+    match operand {
+        Operand::Var(ref var) if var.id == value.id => {},
+        operand => code.push(ZOP::StoreVariable{variable: value.clone(), value: operand})
+    }
+    code.push(ZOP::StoreW{array_address: Operand::new_var(map.id), index: index.clone(), variable: value.clone()});
+}
+
+/// Writes the entries of a map literal to the map at the word `offset`.
+///
+/// The childs of the literal alternate between the keys and the expressions of the values.
+/// Nested maps are written behind the entries, the value is their address. Returns the number
+/// of words written.
+fn write_map_literal(literal: &ASTNode, offset: usize, map: &Variable, index: &Variable, value: &Variable, code: &mut Vec<ZOP>,
+        temp_ids: &mut Vec<u8>, manager: &mut CodeGenManager, mut out: &mut Zfile) -> Result<usize, EvaluateExpressionError> {
+    let entries: Vec<&[ASTNode]> = literal.childs().chunks(2).collect();
+    let count = entries.len();
+    let mut nested_offset = offset + 2 + 3 * count;
+    store_map_word(map, index, value, offset + 1, Operand::new_large_const(count as i16), code);
+
+    for (i, entry) in entries.iter().enumerate() {
+        if entry.len() != 2 || entry[1].childs().len() != 1 {
+            return Err(EvaluateExpressionError::InvalidAST);
+        }
+        let key = match entry[0].category() {
+            TokString { value: key, .. } => key,
+            _ => return Err(EvaluateExpressionError::InvalidAST)
+        };
+        store_map_word(map, index, value, offset + 2 + i, Operand::new_string_ref(out.write_string(&key)), code);
+        manager.map_keys.insert(key);
+
+        if let Some(nested) = nested_map(&entry[1]) {
+            let words = try!(write_map_literal(nested, nested_offset, map, index, value, code, temp_ids, manager, &mut out));
+            code.push(ZOP::Add{operand1: Operand::new_var(map.id), operand2: Operand::new_large_const(2 * nested_offset as i16), save_variable: value.clone()});
+            store_map_word(map, index, value, offset + 2 + count + i, Operand::new_var(value.id), code);
+            store_map_word(map, index, value, offset + 2 + 2 * count + i, Operand::new_const(Type::Map as u8), code);
+            nested_offset += words;
+            continue;
+        }
+
+        let eval = try!(evaluate_expression_internal(entry[1].childs()[0].clone(), code, temp_ids, manager, &mut out));
+        store_map_word(map, index, value, offset + 2 + count + i, eval.clone(), code);
+        match eval {
+            Operand::StringRef(_) => store_map_word(map, index, value, offset + 2 + 2 * count + i, Operand::new_const(Type::String as u8), code),
+            Operand::Var(ref var) => {
+                code.push(ZOP::GetVarType{variable: var.clone(), result: value.clone()});
+                store_map_word(map, index, value, offset + 2 + 2 * count + i, Operand::new_var(value.id), code);
+            },
+            Operand::BoolConst(_) => store_map_word(map, index, value, offset + 2 + 2 * count + i, Operand::new_const(Type::Bool as u8), code),
+            _ => store_map_word(map, index, value, offset + 2 + 2 * count + i, Operand::new_const(Type::Integer as u8), code)
+        };
+        free_var_if_temp(&eval, temp_ids);
+    }
+    Ok(nested_offset - offset)
 }
 
 /// Checks if both operands are temporary variables. If so, the id of the second
@@ -667,6 +1518,7 @@ fn free_var_if_both_temp (eval0: &Operand, eval1: &Operand, temp_ids: &mut Vec<u
                 match eval1 {
                     &Operand::Var(ref var2)=> {
                         if CodeGenManager::is_temp_var(var2) {
+                            debug_assert!(!temp_ids.contains(&var2.id), "temporary variable {} freed twice", var2.id);
                             temp_ids.push(var2.id);
                         }
                     }, _ => {}
@@ -682,6 +1534,7 @@ fn free_var_if_temp (operand: &Operand, temp_ids: &mut Vec<u8>) {
     match operand {
         &Operand::Var(ref var) => {
             if CodeGenManager::is_temp_var(var){
+                debug_assert!(!temp_ids.contains(&var.id), "temporary variable {} freed twice", var.id);
                 temp_ids.push(var.id);
             }
         }, _ => {}
@@ -701,7 +1554,7 @@ fn determine_result_type(a: Type, b: Type) -> Type {
 /// Determines a variable where the result of an operation on operand1 and operand2 should
 /// be saved. if for example both operands are temporary ids, then one of them can be used
 /// to store the result. Otherwise a new temp_id will be popped from the stack.
-fn determine_save_var(operand1: &Operand, operand2: &Operand, temp_ids: &mut Vec<u8>) -> Variable {
+fn determine_save_var(operand1: &Operand, operand2: &Operand, temp_ids: &mut Vec<u8>) -> Result<Variable, EvaluateExpressionError> {
     let type1 = match operand1 {
         &Operand::Var(ref var) => var.vartype.clone(),
         &Operand::StringRef(_) => Type::String,
@@ -718,22 +1571,68 @@ fn determine_save_var(operand1: &Operand, operand2: &Operand, temp_ids: &mut Vec
     match operand1 {
         &Operand::Var(ref var) => {
             if CodeGenManager::is_temp_var(var) {
-                return Variable{id: var.id, vartype: vartype};
+                return Ok(Variable{id: var.id, vartype: vartype});
             }
         }, _ => {}
     };
     match operand2 {
         &Operand::Var(ref var) => {
             if CodeGenManager::is_temp_var(var) {
-                return Variable{id: var.id, vartype: vartype};
+                return Ok(Variable{id: var.id, vartype: vartype});
             }
         }, _ => {}
     };
-    if let Some(temp) = temp_ids.pop() {
-        return Variable{ id: temp, vartype: vartype };
+    Ok(Variable{ id: try!(pop_temp_id(temp_ids)), vartype: vartype })
+}
+
+/// Pops an unused temporary id from the stack.
+///
+/// Fails with `NoTempIdLeftOnStack` if the expression needs more than the 13 temporary ids.
+pub fn pop_temp_id(temp_ids: &mut Vec<u8>) -> Result<u8, EvaluateExpressionError> {
+    temp_ids.pop().ok_or(EvaluateExpressionError::NoTempIdLeftOnStack)
+}
+
+/// Builds the result of `bar()` for constant arguments, just like the `bar` routine does at runtime.
+fn bar_string(value: i16, max: i16, width: i16) -> String {
+    let width = if width > 0 { width as i32 } else { 0 };
+    let filled = if max > 0 {
+        let value = if value < 0 { 0 } else if value > max { max as i32 } else { value as i32 };
+        value * width / max as i32
     } else {
-        error_force_panic!(EvaluateExpressionError::NoTempIdLeftOnStack)
+        0
+    };
+
+    let mut bar = "[".to_string();
+    for cell in 0..width {
+        bar.push(if cell < filled { '#' } else { '-' });
     }
+    bar.push(']');
+    bar
+}
+
+/// Converts the ASCII letters like the `change_case` routine does for `upper()` and `lower()`.
+fn change_case(text: &str, upper: bool) -> String {
+    text.chars().map(|c| match c {
+        'a'...'z' if upper => ((c as u8) - 32) as char,
+        'A'...'Z' if !upper => ((c as u8) + 32) as char,
+        _ => c
+    }).collect()
+}
+
+/// Returns the index of the argument of `plural()` that is chosen for the count.
+fn plural_form(count: i16) -> usize {
+    if count == 1 { 1 } else { 2 }
+}
+
+/// Cuts the substring like the `substr` routine does for `substr()`.
+///
+/// `start` and `len` are clamped to the bounds of the text, the second value tells if they were.
+fn substr_string(text: &str, start: i16, len: i16) -> (String, bool) {
+    let length = text.chars().count() as i16;
+    let first = if start < 0 { 0 } else if start > length { length } else { start };
+    let count = if len < 0 { 0 } else if len > length - first { length - first } else { len };
+    let clamped = first != start || count != len;
+    (text.chars().skip(first as usize).take(count as usize).collect(), clamped)
 }
 
 /// Returns the number of constants, checking operand1 and operand2.
@@ -761,12 +1660,16 @@ fn boolstr_to_const(string: &str) -> Operand {
 // Test functions
 #[cfg(test)]
 mod tests {
-    use backend::zcode::zfile::{Operand, Type, ZOP};
+    use std::collections::HashMap;
+    use backend::zcode::zfile::{Operand, Type, ZOP, Zfile};
     use backend::codegen::CodeGenManager;
     use config::Config;
+    use frontend::ast::{ASTNode, NodeDefault};
+    use frontend::lexer::Token;
+    use frontend::lexer::Token::{TokNumOp, TokString, TokVariable, TokInt, TokUnaryMinus, TokFunction, TokExpression, TokTernaryIf, TokBoolean};
 
-    use super::{boolstr_to_const, count_constants, determine_save_var, direct_eval_comp_op,
-                direct_eval_num_op, eval_and_or, eval_not, eval_unary_minus};
+    use super::{evaluate_expression, static_range, static_string, boolstr_to_const, count_constants, determine_save_var, direct_eval_comp_op,
+                direct_eval_num_op, eval_and_or, eval_not, eval_unary_minus, bar_string, change_case, plural_form, substr_string};
 
     #[test]
     fn test_and_or(){
@@ -776,10 +1679,10 @@ mod tests {
         vec.push(2);
         vec.push(3);
         vec.push(10);
-        assert_eq!(eval_and_or(&Operand::new_large_const(0), &Operand::new_large_const(1), "or", &mut vec2, &mut vec).const_value(),1 as i16);
-        assert_eq!(eval_and_or(&Operand::new_large_const(0), &Operand::new_large_const(1), "and", &mut vec2, &mut vec).const_value(),0 as i16);
-        assert_eq!(eval_and_or(&Operand::new_large_const(0), &Operand::new_large_const(0), "or", &mut vec2, &mut vec).const_value(),0 as i16);
-        assert_eq!(eval_and_or(&Operand::new_large_const(1), &Operand::new_large_const(1), "and", &mut vec2, &mut vec).const_value(),1 as i16);
+        assert_eq!(eval_and_or(&Operand::new_large_const(0), &Operand::new_large_const(1), "or", &mut vec2, &mut vec).unwrap().const_value(),1 as i16);
+        assert_eq!(eval_and_or(&Operand::new_large_const(0), &Operand::new_large_const(1), "and", &mut vec2, &mut vec).unwrap().const_value(),0 as i16);
+        assert_eq!(eval_and_or(&Operand::new_large_const(0), &Operand::new_large_const(0), "or", &mut vec2, &mut vec).unwrap().const_value(),0 as i16);
+        assert_eq!(eval_and_or(&Operand::new_large_const(1), &Operand::new_large_const(1), "and", &mut vec2, &mut vec).unwrap().const_value(),1 as i16);
     }
 
     #[test]
@@ -792,8 +1695,8 @@ mod tests {
         vec.push(2);
         vec.push(3);
         vec.push(10);
-        assert_eq!(eval_not(&Operand::new_large_const(10), &mut vec2, &mut vec, &mut manager).const_value(),0);
-        assert_eq!(eval_not(&Operand::new_const(0), &mut vec2, &mut vec, &mut manager).const_value(),1);
+        assert_eq!(eval_not(&Operand::new_large_const(10), &mut vec2, &mut vec, &mut manager).unwrap().const_value(),0);
+        assert_eq!(eval_not(&Operand::new_const(0), &mut vec2, &mut vec, &mut manager).unwrap().const_value(),1);
     }
 
     #[test]
@@ -804,8 +1707,8 @@ mod tests {
         vec.push(2);
         vec.push(3);
         vec.push(10);
-        assert_eq!(eval_unary_minus(&Operand::new_large_const(10), &mut vec2, &mut vec).const_value(),-10);
-        assert_eq!(eval_unary_minus(&Operand::new_const(10), &mut vec2, &mut vec).const_value(),246);
+        assert_eq!(eval_unary_minus(&Operand::new_large_const(10), &mut vec2, &mut vec).unwrap().const_value(),-10);
+        assert_eq!(eval_unary_minus(&Operand::new_const(10), &mut vec2, &mut vec).unwrap().const_value(),246);
     }
 
     #[test]
@@ -815,7 +1718,7 @@ mod tests {
         vec.push(2);
         vec.push(3);
         vec.push(4);
-        let var = determine_save_var(&Operand::new_var(10), &Operand::new_var(10), &mut vec);
+        let var = determine_save_var(&Operand::new_var(10), &Operand::new_var(10), &mut vec).unwrap();
         assert_eq!(var.id,10);
         assert_eq!(var.vartype,Type::Integer);
     }
@@ -845,6 +1748,18 @@ mod tests {
         assert_eq!(direct_eval_num_op(&Operand::new_large_const(90), &Operand::new_large_const(2), "%", (0x0000000000000000, 0x0000000000000000), &manager).const_value(),0 as i16);
     }
 
+    #[test]
+    fn test_constant_folding_wraps(){
+        // constants are folded with the wrapping 16 bit arithmetic of the Z-Machine
+        let cfg = Config::default_config();
+        let manager = CodeGenManager::new(&cfg);
+        assert_eq!(direct_eval_num_op(&Operand::new_large_const(30000), &Operand::new_large_const(30000), "+", (0x0000000000000000, 0x0000000000000000), &manager).const_value(),-5536 as i16);
+        assert_eq!(direct_eval_num_op(&Operand::new_large_const(-32768), &Operand::new_large_const(-1), "/", (0x0000000000000000, 0x0000000000000000), &manager).const_value(),-32768 as i16);
+        let mut vec2: Vec<ZOP> = Vec::new();
+        let mut vec: Vec<u8> = vec![1, 2, 3];
+        assert_eq!(eval_unary_minus(&Operand::new_large_const(-32768), &mut vec2, &mut vec).unwrap().const_value(),-32768);
+    }
+
     #[test]
     fn test_direct_eval_comp_op(){
         let cfg = Config::default_config();
@@ -874,4 +1789,247 @@ mod tests {
         assert_eq!(direct_eval_comp_op(&Operand::new_large_const(2), &Operand::new_large_const(3), ">", (0x0000000000000000, 0x0000000000000000), &manager).const_value(),0 as i16);
         assert_eq!(direct_eval_comp_op(&Operand::new_large_const(0), &Operand::new_large_const(0), "gt", (0x0000000000000000, 0x0000000000000000), &manager).const_value(),0 as i16);
     }
+
+    fn node(category: Token, childs: Vec<ASTNode>) -> ASTNode {
+        ASTNode::Default(NodeDefault { category: category, childs: childs })
+    }
+
+    fn plus(left: ASTNode, right: ASTNode) -> ASTNode {
+        node(TokNumOp { location: (0, 0), op_name: "+".to_string() }, vec![left, right])
+    }
+
+    #[test]
+    fn test_nested_concatenation_temporaries() {
+        let cfg = Config::default_config();
+        let mut manager = CodeGenManager::new(&cfg);
+        let mut zfile = Zfile::new();
+        let mut code: Vec<ZOP> = Vec::new();
+
+        let string = |value: &str| node(TokString { location: (0, 0), value: value.to_string() }, vec![]);
+        let var = |name: &str| node(TokVariable { location: (0, 0), name: name.to_string() }, vec![]);
+        let expression = plus(plus(string("a"), var("$x")), plus(plus(string("b"), var("$y")), var("$z")));
+        evaluate_expression(expression, &mut code, &mut manager, &mut zfile).unwrap();
+
+        let mut count = 0;
+        for zop in code.iter() {
+            if let &ZOP::AddTypes{ref operand1, ref operand2, ref tmp1, ref tmp2, ref save_variable} = zop {
+                count += 1;
+                assert!(tmp1.id != tmp2.id && tmp1.id != save_variable.id && tmp2.id != save_variable.id);
+                for operand in &[operand1, operand2] {
+                    if let &&Operand::Var(ref var) = operand {
+                        assert!(var.id != tmp1.id && var.id != tmp2.id);
+                    }
+                }
+            }
+        }
+        assert_eq!(count, 4);
+    }
+
+    #[test]
+    fn test_static_string() {
+        let string = |value: &str| node(TokString { location: (0, 0), value: value.to_string() }, vec![]);
+        let var = |name: &str| node(TokVariable { location: (0, 0), name: name.to_string() }, vec![]);
+        assert_eq!(static_string(&plus(plus(string("a"), string("b")), string("c"))), Some("abc".to_string()));
+        assert_eq!(static_string(&plus(string("a"), var("$x"))), None);
+        assert_eq!(static_string(&plus(string("a"), node(TokInt { location: (0, 0), value: 1 }, vec![]))), None);
+
+        // the concatenation is written once and needs no code
+        let cfg = Config::default_config();
+        let mut manager = CodeGenManager::new(&cfg);
+        let mut zfile = Zfile::new();
+        let mut code: Vec<ZOP> = Vec::new();
+        let result = evaluate_expression(plus(plus(string("a"), string("b")), string("c")), &mut code, &mut manager, &mut zfile).unwrap();
+        assert!(code.is_empty());
+        assert_eq!(result.address_value(), zfile.write_string("abc"));
+    }
+
+    #[test]
+    fn test_config_function() {
+        let mut cfg = Config::default_config();
+        cfg.no_colours = true;
+        cfg.force = true;
+        let mut zfile = Zfile::new();
+        let mut code: Vec<ZOP> = Vec::new();
+        let config = |name: &str| node(TokFunction { location: (2, 6), name: "config".to_string() },
+            vec![node(TokExpression, vec![node(TokString { location: (2, 13), value: name.to_string() }, vec![])])]);
+
+        {
+            let mut manager = CodeGenManager::new(&cfg);
+            assert_eq!(evaluate_expression(config("no-colours"), &mut code, &mut manager, &mut zfile).unwrap().const_value(), 1);
+            assert_eq!(evaluate_expression(config("no-unicode"), &mut code, &mut manager, &mut zfile).unwrap().const_value(), 0);
+            assert!(code.is_empty());
+            assert_eq!(cfg.error_count(), 0);
+
+            // an unknown name is an error, with force it is false
+            let unknown = evaluate_expression(config("no-colors"), &mut code, &mut manager, &mut zfile).unwrap();
+            assert_eq!(unknown.const_value(), 0);
+        }
+        assert_eq!(cfg.error_count(), 1);
+    }
+
+    #[test]
+    fn test_shuffle_deck_layout() {
+        let cfg = Config::default_config();
+        let mut manager = CodeGenManager::new(&cfg);
+        let mut zfile = Zfile::new();
+        let mut code: Vec<ZOP> = Vec::new();
+
+        let arg = |value: i32| node(TokExpression, vec![node(TokInt { location: (0, 0), value: value }, vec![])]);
+        let shuffle = node(TokFunction { location: (0, 0), name: "shuffle".to_string() }, vec![arg(1), arg(2), arg(3), arg(4)]);
+        let deck = evaluate_expression(shuffle, &mut code, &mut manager, &mut zfile).unwrap();
+
+        match code[0] {
+            ZOP::Call2S{ref jump_to_label, arg: Operand::Const(ref size), ref result} => {
+                assert_eq!(jump_to_label, "malloc");
+                assert_eq!(size.value, 6);
+                match deck {
+                    Operand::Var(ref var) => assert_eq!(var.id, result.id),
+                    _ => panic!("the deck is not returned")
+                }
+            },
+            _ => panic!("the deck is not allocated first")
+        }
+
+        // length, remaining count and the four values
+        let stored: Vec<i16> = code.iter().filter_map(|zop| match zop {
+            &ZOP::StoreVariable{ref value, ..} => Some(value.const_value()),
+            _ => None
+        }).collect();
+        assert_eq!(stored, vec![0, 5, 4, 1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn test_bar_string() {
+        assert_eq!(bar_string(3, 10, 10), "[###-------]");
+        assert_eq!(bar_string(-5, 10, 4), "[----]");
+        assert_eq!(bar_string(25, 10, 4), "[####]");
+        assert_eq!(bar_string(1, 3, 4), "[#---]");
+        assert_eq!(bar_string(1, 0, 2), "[--]");
+        assert_eq!(bar_string(1, 2, -1), "[]");
+    }
+
+    #[test]
+    fn test_change_case() {
+        assert_eq!(change_case("Hello, World 42!", true), "HELLO, WORLD 42!");
+        assert_eq!(change_case("Hello, World 42!", false), "hello, world 42!");
+        // only ASCII letters are converted
+        assert_eq!(change_case("Grüße", true), "GRüßE");
+    }
+
+    #[test]
+    fn test_plural_form() {
+        assert_eq!(plural_form(1), 1);
+        assert_eq!(plural_form(0), 2);
+        assert_eq!(plural_form(2), 2);
+        assert_eq!(plural_form(-1), 2);
+    }
+
+    #[test]
+    fn test_substr_string() {
+        assert_eq!(substr_string("Hello", 1, 3), ("ell".to_string(), false));
+        assert_eq!(substr_string("Hello", 0, 5), ("Hello".to_string(), false));
+        assert_eq!(substr_string("Hello", 3, 10), ("lo".to_string(), true));
+        assert_eq!(substr_string("Hello", -2, 2), ("He".to_string(), true));
+        assert_eq!(substr_string("Hello", 7, 1), ("".to_string(), true));
+        assert_eq!(substr_string("Hello", 2, -1), ("".to_string(), true));
+    }
+
+    #[test]
+    fn test_bar_runtime_call() {
+        let cfg = Config::default_config();
+        let mut manager = CodeGenManager::new(&cfg);
+        let mut zfile = Zfile::new();
+        let mut code: Vec<ZOP> = Vec::new();
+
+        let arg = |child: ASTNode| node(TokExpression, vec![child]);
+        let int = |value: i32| node(TokInt { location: (0, 0), value: value }, vec![]);
+        let hp = node(TokVariable { location: (0, 0), name: "$hp".to_string() }, vec![]);
+        let bar = node(TokFunction { location: (0, 0), name: "bar".to_string() }, vec![arg(hp), arg(int(10)), arg(int(10))]);
+        let result = evaluate_expression(bar, &mut code, &mut manager, &mut zfile).unwrap();
+
+        match (&code[0], &result) {
+            (&ZOP::CallVSA3{ref jump_to_label, ref result, ..}, &Operand::Var(ref var)) => {
+                assert_eq!(jump_to_label, "bar");
+                assert_eq!(result.id, var.id);
+                assert_eq!(var.vartype, Type::String);
+            },
+            _ => panic!("bar() with a variable is not computed at runtime")
+        }
+    }
+
+    #[test]
+    fn test_static_range() {
+        let int = |value: i32| node(TokInt { location: (0, 0), value: value }, vec![]);
+        let var = || node(TokVariable { location: (0, 0), name: "$x".to_string() }, vec![]);
+        let op = |name: &str, left: ASTNode, right: ASTNode| node(TokNumOp { location: (0, 0), op_name: name.to_string() }, vec![left, right]);
+        let arg = |value: ASTNode| node(TokExpression, vec![value]);
+        let random = |from: ASTNode, to: ASTNode| node(TokFunction { location: (0, 0), name: "random".to_string() }, vec![arg(from), arg(to)]);
+        let none: HashMap<String, (i32, i32)> = HashMap::new();
+
+        assert_eq!(static_range(&int(7), &none), Some((7, 7)));
+        assert_eq!(static_range(&node(TokUnaryMinus { location: (0, 0) }, vec![int(7)]), &none), Some((-7, -7)));
+        assert_eq!(static_range(&random(int(1), int(6)), &none), Some((1, 6)));
+        assert_eq!(static_range(&op("*", random(int(-2), int(3)), int(10)), &none), Some((-20, 30)));
+        assert_eq!(static_range(&op("-", random(int(1), int(6)), random(int(1), int(6))), &none), Some((-5, 5)));
+        assert_eq!(static_range(&op("%", random(int(0), int(99)), int(10)), &none), Some((0, 9)));
+        assert_eq!(static_range(&op("/", int(100), random(int(1), int(4))), &none), Some((25, 100)));
+
+        // unbounded
+        assert_eq!(static_range(&var(), &none), None);
+        assert_eq!(static_range(&op("+", var(), int(1)), &none), None);
+        assert_eq!(static_range(&random(int(1), var()), &none), None);
+        assert_eq!(static_range(&op("/", int(100), random(int(-1), int(1))), &none), None);
+        assert_eq!(static_range(&op("*", int(1000), int(1000)), &none), None);
+
+        // a variable with a known range
+        let mut counter: HashMap<String, (i32, i32)> = HashMap::new();
+        counter.insert("$x".to_string(), (0, 9));
+        assert_eq!(static_range(&op("+", var(), int(1)), &counter), Some((1, 10)));
+        assert_eq!(static_range(&op("*", var(), int(4000)), &counter), None);
+    }
+
+    #[test]
+    fn test_ternary_branches() {
+        let cfg = Config::default_config();
+        let mut manager = CodeGenManager::new(&cfg);
+        let mut zfile = Zfile::new();
+        let mut code: Vec<ZOP> = Vec::new();
+
+        let ternary = |cond: ASTNode, then: ASTNode, otherwise: ASTNode| node(TokTernaryIf { location: (0, 0) }, vec![cond, then, otherwise]);
+        let string = |value: &str| node(TokString { location: (0, 0), value: value.to_string() }, vec![]);
+        let int = |value: i32| node(TokInt { location: (0, 0), value: value }, vec![]);
+
+        // a constant condition only generates the taken branch
+        let taken = evaluate_expression(ternary(node(TokBoolean { location: (0, 0), value: "false".to_string() }, vec![]), string("a"), int(2)),
+            &mut code, &mut manager, &mut zfile).unwrap();
+        assert!(code.is_empty());
+        assert_eq!(taken.const_value(), 2);
+
+        // the integer of a mixed conditional is converted to a string
+        let cond = node(TokVariable { location: (0, 0), name: "$x".to_string() }, vec![]);
+        let result = evaluate_expression(ternary(cond, string("a"), int(2)), &mut code, &mut manager, &mut zfile).unwrap();
+        let jumps = code.iter().filter(|zop| match zop { &&ZOP::JE{..} | &&ZOP::Jump{..} => true, _ => false }).count();
+        let conversions = code.iter().filter(|zop| match zop { &&ZOP::Call2S{ref jump_to_label, ..} => jump_to_label == "itoa", _ => false }).count();
+        assert_eq!(jumps, 2);
+        assert_eq!(conversions, 1);
+        match result {
+            Operand::Var(ref var) => assert_eq!(var.vartype, Type::String),
+            _ => panic!("a conditional with a variable is not computed at runtime")
+        }
+    }
+
+    #[test]
+    fn test_functions_match_function_arms() {
+        // the names of the arms of the TokFunction match, they are the only ones indented by 16
+        // spaces, the debugging functions behind a guard are not listed
+        let source = include_str!("evaluate_expression.rs");
+        let start = source.find("TokFunction { ref name, ref location } => {").unwrap();
+        let end = start + source[start..].find("EvaluateExpressionError::UnsupportedFunction {").unwrap();
+        let mut names: Vec<&str> = source[start..end].lines()
+            .filter(|line| line.starts_with("                \"") && line.contains(" => ") && !line.contains("\" if "))
+            .flat_map(|line| line[..line.find(" => ").unwrap()].trim().split(" | ").map(|name| name.trim_matches('"')))
+            .collect();
+        names.sort();
+        assert_eq!(names, super::FUNCTIONS.to_vec());
+    }
 }
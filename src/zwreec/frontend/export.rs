@@ -0,0 +1,492 @@
+//! Exports the AST as JSON for external code generators.
+//!
+//! The output is versioned by `zwreec_ast_version` and has the following schema:
+//!
+//! ```text
+//! {
+//!     "zwreec_ast_version": 1,
+//!     "passages": [node, ...]
+//! }
+//!
+//! node = {
+//!     "kind": "TokPassage",       // name of the token variant
+//!     "location": [line, column], // 1-based, null for TokExpression
+//!     "byte_offset": 42,          // offset in the input, null without a source map
+//!     "fields": {...},            // payload of the token, e.g. {"name": "Start"}
+//!     "children": [node, ...]
+//! }
+//! ```
+//!
+//! The fields use the same names as the members of `Token`. Strings are JSON strings, `TokInt`
+//! and `TokFloat` values are numbers. Floats that are not finite are exported as strings
+//! (`"inf"`, `"-inf"`, `"NaN"`), because JSON has no representation for them. The value of a
+//! `TokBoolean` is kept as string, just like in the lexer.
+//!
+//! Expressions are exported as a `TokExpression` node with the expression tree as children,
+//! operators contain their operands as children.
+//!
+//! The columns count characters and are moved by tabs like in the error messages. Editors that
+//! address the input by bytes use `ast_to_json_with_source_map`, which adds the byte offset of
+//! every node.
+//!
+//! # Example
+//!
+//! ```
+//! # extern crate zwreec;
+//! use std::io::Cursor;
+//!
+//! let cfg = zwreec::config::Config::default_config();
+//! let mut twee = Cursor::new("::Start\nHello World".to_string().into_bytes());
+//!
+//! let ast = zwreec::frontend_ast(cfg, &mut twee);
+//! let json = zwreec::frontend::export::ast_to_json(&ast);
+//! assert!(json.starts_with("{\"zwreec_ast_version\":1,"));
+//! ```
+
+use frontend::ast::ASTNode;
+use frontend::lexer::{SourceMap, Token};
+use frontend::lexer::Token::*;
+use utils::json;
+
+/// The version of the exported schema.
+///
+/// This is increased on every incompatible change of the output.
+pub const AST_JSON_VERSION: u32 = 1;
+
+/// Returns the passages of the AST as JSON string.
+pub fn ast_to_json(ast: &[ASTNode]) -> String {
+    passages_to_json(ast, None)
+}
+
+/// Returns the passages of the AST as JSON string, with the byte offsets of the nodes in the
+/// input of the map.
+pub fn ast_to_json_with_source_map(ast: &[ASTNode], source_map: &SourceMap) -> String {
+    passages_to_json(ast, Some(source_map))
+}
+
+fn passages_to_json(ast: &[ASTNode], source_map: Option<&SourceMap>) -> String {
+    let passages: Vec<String> = ast.iter().map(|node| node_to_json(node, source_map)).collect();
+    json::object(&[
+        ("zwreec_ast_version", AST_JSON_VERSION.to_string()),
+        ("passages", json::array(&passages)),
+    ])
+}
+
+fn node_to_json(node: &ASTNode, source_map: Option<&SourceMap>) -> String {
+    let (token, childs) = match node {
+        &ASTNode::Passage(ref passage) => (&passage.category, &passage.childs),
+        &ASTNode::Default(ref default) => (&default.category, &default.childs),
+    };
+
+    let children: Vec<String> = childs.iter().map(|child| node_to_json(child, source_map)).collect();
+    json::object(&[
+        ("kind", json::string(&token_kind(token))),
+        ("location", location_to_json(token)),
+        ("byte_offset", byte_offset_to_json(token, source_map)),
+        ("fields", json::object(&token_fields(token))),
+        ("children", json::array(&children)),
+    ])
+}
+
+/// Returns the name of the token variant, e.g. `TokPassage`.
+fn token_kind(token: &Token) -> String {
+    format!("{:?}", token).chars().take_while(|c| c.is_alphanumeric()).collect()
+}
+
+fn location_to_json(token: &Token) -> String {
+    match token {
+        &TokExpression => "null".to_string(),
+        _ => {
+            let (line, column) = token.location();
+            format!("[{},{}]", line, column)
+        }
+    }
+}
+
+fn byte_offset_to_json(token: &Token, source_map: Option<&SourceMap>) -> String {
+    match (token, source_map) {
+        (&TokExpression, _) | (_, None) => "null".to_string(),
+        (_, Some(source_map)) => source_map.location(token.location()).byte_offset.to_string(),
+    }
+}
+
+fn token_fields(token: &Token) -> Vec<(&'static str, String)> {
+    match token {
+        &TokPassage{ref name, ..} |
+        &TokVariable{ref name, ..} |
+        &TokArrayLength{ref name, ..} |
+        &TokFunction{ref name, ..} |
+        &TokMacroPlugin{ref name, ..} |
+        &TokMacroWidget{ref name, ..} |
+        &TokMacroWidgetCall{ref name, ..} => vec![("name", json::string(name))],
+        &TokTag{ref tag_name, ..} => vec![("tag_name", json::string(tag_name))],
+        &TokMacroAllow{ref warning, ..} => vec![("warning", json::string(warning))],
+        &TokPassageLink{ref display_name, ref passage_name, ..} => vec![
+            ("display_name", json::string(display_name)),
+            ("passage_name", json::string(passage_name)),
+        ],
+        &TokText{ref text, ..} => vec![("text", json::string(text))],
+        &TokFormatHeading{rank, ref text, ..} => vec![
+            ("rank", rank.to_string()),
+            ("text", json::string(text)),
+        ],
+        &TokMacroDisplay{ref passage_name, ..} |
+        &TokPreconditionTarget{ref passage_name, ..} => vec![("passage_name", json::string(passage_name))],
+        &TokMacroContentVar{ref var_name, ..} => vec![("var_name", json::string(var_name))],
+        &TokArrayAccess{ref name, ref index, ..} => vec![
+            ("name", json::string(name)),
+            ("index", json::string(index)),
+        ],
+        &TokMapAccess{ref name, ref key, ..} => vec![
+            ("name", json::string(name)),
+            ("key", json::string(key)),
+        ],
+        &TokInt{value, ..} => vec![("value", value.to_string())],
+        &TokFloat{value, ..} => vec![("value", float_to_json(value))],
+        &TokString{ref value, ..} |
+        &TokBoolean{ref value, ..} => vec![("value", json::string(value))],
+        &TokAssign{ref var_name, ref op_name, ..} => vec![
+            ("var_name", json::string(var_name)),
+            ("op_name", json::string(op_name)),
+        ],
+        &TokNumOp{ref op_name, ..} |
+        &TokCompOp{ref op_name, ..} |
+        &TokLogOp{ref op_name, ..} => vec![("op_name", json::string(op_name))],
+        &TokError{ref message, ref raw, ..} => vec![
+            ("message", json::string(message)),
+            ("raw", json::string(raw)),
+        ],
+        // every token is listed, so a new token with fields has to be added here
+        &TokTagStart{..} |
+        &TokTagEnd{..} |
+        &TokVarSetStart{..} |
+        &TokVarSetEnd{..} |
+        &TokPrecondition{..} |
+        &TokFormatBoldStart{..} |
+        &TokFormatBoldEnd{..} |
+        &TokFormatItalicStart{..} |
+        &TokFormatItalicEnd{..} |
+        &TokFormatUnderStart{..} |
+        &TokFormatUnderEnd{..} |
+        &TokFormatStrikeStart{..} |
+        &TokFormatStrikeEnd{..} |
+        &TokFormatSubStart{..} |
+        &TokFormatSubEnd{..} |
+        &TokFormatSupStart{..} |
+        &TokFormatSupEnd{..} |
+        &TokFormatMonoStart{..} |
+        &TokFormatMonoEnd{..} |
+        &TokFormatBulList{..} |
+        &TokFormatNumbList{..} |
+        &TokFormatIndentBlock{..} |
+        &TokFormatHorizontalLine{..} |
+        &TokMacroStart{..} |
+        &TokMacroEnd{..} |
+        &TokMacroSet{..} |
+        &TokMacroIf{..} |
+        &TokMacroElse{..} |
+        &TokMacroElseIf{..} |
+        &TokMacroEndIf{..} |
+        &TokMacroPrint{..} |
+        &TokMacroSilently{..} |
+        &TokMacroEndSilently{..} |
+        &TokMacroNoBr{..} |
+        &TokMacroEndNoBr{..} |
+        &TokMacroOnExit{..} |
+        &TokMacroEndOnExit{..} |
+        &TokMacroBr{..} |
+        &TokMacroToc{..} |
+        &TokMacroConfirm{..} |
+        &TokMacroClear{..} |
+        &TokMacroAt{..} |
+        &TokMacroEndAt{..} |
+        &TokMacroCountdown{..} |
+        &TokMacroOtherwise{..} |
+        &TokMacroEndWidget{..} |
+        &TokParenOpen{..} |
+        &TokParenClose{..} |
+        &TokColon{..} |
+        &TokArgsEnd{..} |
+        &TokArrayStart{..} |
+        &TokArrayEnd{..} |
+        &TokMapLiteral{..} |
+        &TokMapLiteralEnd{..} |
+        &TokSemiColon{..} |
+        &TokTernaryIf{..} |
+        &TokTernaryElse{..} |
+        &TokNewLine{..} |
+        &TokUnaryMinus{..} |
+        &TokExpression => vec![],
+    }
+}
+
+fn float_to_json(value: f32) -> String {
+    if value.is_finite() {
+        format!("{:?}", value)
+    } else {
+        json::string(&format!("{}", value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use config::Config;
+
+    /// A minimal JSON value, just enough to inspect the exported AST.
+    #[derive(Debug, PartialEq)]
+    enum Json {
+        Null,
+        Bool(bool),
+        Number(f64),
+        Str(String),
+        Array(Vec<Json>),
+        Object(Vec<(String, Json)>),
+    }
+
+    impl Json {
+        fn get(&self, key: &str) -> &Json {
+            match self {
+                &Json::Object(ref members) => {
+                    &members.iter().find(|&&(ref k, _)| k == key)
+                        .expect(&format!("missing key {}", key)).1
+                },
+                _ => panic!("not an object: {:?}", self),
+            }
+        }
+
+        fn at(&self, index: usize) -> &Json {
+            match self {
+                &Json::Array(ref values) => &values[index],
+                _ => panic!("not an array: {:?}", self),
+            }
+        }
+
+        fn len(&self) -> usize {
+            match self {
+                &Json::Array(ref values) => values.len(),
+                _ => panic!("not an array: {:?}", self),
+            }
+        }
+
+        fn kind(&self) -> &str {
+            match self.get("kind") {
+                &Json::Str(ref kind) => kind,
+                other => panic!("kind is not a string: {:?}", other),
+            }
+        }
+
+        fn field(&self, name: &str) -> &Json {
+            self.get("fields").get(name)
+        }
+    }
+
+    /// Parses `input` and panics if it is not a single valid JSON value.
+    fn parse(input: &str) -> Json {
+        let chars: Vec<char> = input.chars().collect();
+        let mut pos = 0;
+        let value = parse_value(&chars, &mut pos);
+        skip_whitespace(&chars, &mut pos);
+        assert_eq!(pos, chars.len(), "trailing characters after JSON value");
+        value
+    }
+
+    fn skip_whitespace(chars: &[char], pos: &mut usize) {
+        while *pos < chars.len() && chars[*pos].is_whitespace() {
+            *pos += 1;
+        }
+    }
+
+    fn expect(chars: &[char], pos: &mut usize, c: char) {
+        skip_whitespace(chars, pos);
+        assert_eq!(chars[*pos], c, "unexpected character at {}", *pos);
+        *pos += 1;
+    }
+
+    fn parse_value(chars: &[char], pos: &mut usize) -> Json {
+        skip_whitespace(chars, pos);
+        match chars[*pos] {
+            '{' => {
+                *pos += 1;
+                let mut members = Vec::new();
+                skip_whitespace(chars, pos);
+                if chars[*pos] == '}' {
+                    *pos += 1;
+                    return Json::Object(members);
+                }
+                loop {
+                    skip_whitespace(chars, pos);
+                    let key = parse_string(chars, pos);
+                    expect(chars, pos, ':');
+                    members.push((key, parse_value(chars, pos)));
+                    skip_whitespace(chars, pos);
+                    *pos += 1;
+                    match chars[*pos - 1] {
+                        ',' => continue,
+                        '}' => return Json::Object(members),
+                        c => panic!("unexpected {} in object", c),
+                    }
+                }
+            },
+            '[' => {
+                *pos += 1;
+                let mut values = Vec::new();
+                skip_whitespace(chars, pos);
+                if chars[*pos] == ']' {
+                    *pos += 1;
+                    return Json::Array(values);
+                }
+                loop {
+                    values.push(parse_value(chars, pos));
+                    skip_whitespace(chars, pos);
+                    *pos += 1;
+                    match chars[*pos - 1] {
+                        ',' => continue,
+                        ']' => return Json::Array(values),
+                        c => panic!("unexpected {} in array", c),
+                    }
+                }
+            },
+            '"' => Json::Str(parse_string(chars, pos)),
+            _ => {
+                let start = *pos;
+                while *pos < chars.len() && !",]} \n".contains(chars[*pos]) {
+                    *pos += 1;
+                }
+                let literal: String = chars[start..*pos].iter().cloned().collect();
+                match &literal[..] {
+                    "null" => Json::Null,
+                    "true" => Json::Bool(true),
+                    "false" => Json::Bool(false),
+                    number => Json::Number(number.parse().expect(&format!("invalid literal {}", number))),
+                }
+            }
+        }
+    }
+
+    fn parse_string(chars: &[char], pos: &mut usize) -> String {
+        assert_eq!(chars[*pos], '"');
+        *pos += 1;
+        let mut out = String::new();
+        loop {
+            let c = chars[*pos];
+            *pos += 1;
+            match c {
+                '"' => return out,
+                '\\' => {
+                    let escaped = chars[*pos];
+                    *pos += 1;
+                    match escaped {
+                        'n' => out.push('\n'),
+                        'r' => out.push('\r'),
+                        't' => out.push('\t'),
+                        'u' => {
+                            let hex: String = chars[*pos..*pos + 4].iter().cloned().collect();
+                            *pos += 4;
+                            out.push(::std::char::from_u32(u32::from_str_radix(&hex, 16).unwrap()).unwrap());
+                        },
+                        c => out.push(c),
+                    }
+                },
+                c => {
+                    assert!((c as u32) >= 0x20, "unescaped control character in string");
+                    out.push(c);
+                }
+            }
+        }
+    }
+
+    fn test_json(input: &str) -> Json {
+        let cfg = Config::default_config();
+        let mut cursor = Cursor::new(input.to_string().into_bytes());
+        let ast = ::frontend_ast(cfg, &mut cursor);
+        parse(&ast_to_json(&ast))
+    }
+
+    #[test]
+    fn test_parse_helper() {
+        assert_eq!(parse("{\"a\": [1, true, null, \"\\u0041\\n\"]}"), Json::Object(vec![
+            ("a".to_string(), Json::Array(vec![
+                Json::Number(1.0), Json::Bool(true), Json::Null, Json::Str("A\n".to_string())
+            ]))
+        ]));
+    }
+
+    #[test]
+    fn test_passages() {
+        let json = test_json("::Start\nHello \"World\"\n[[Next]]\n::Next [tag]\nback\n");
+
+        assert_eq!(json.get("zwreec_ast_version"), &Json::Number(1.0));
+        let passages = json.get("passages");
+        assert_eq!(passages.len(), 2);
+
+        let start = passages.at(0);
+        assert_eq!(start.kind(), "TokPassage");
+        assert_eq!(start.field("name"), &Json::Str("Start".to_string()));
+        assert_eq!(start.get("location"), &Json::Array(vec![Json::Number(1.0), Json::Number(1.0)]));
+
+        let text = start.get("children").at(0);
+        assert_eq!(text.kind(), "TokText");
+        assert_eq!(text.field("text"), &Json::Str("Hello \"World\"".to_string()));
+
+        let next = passages.at(1);
+        assert_eq!(next.field("name"), &Json::Str("Next".to_string()));
+        let tag = next.get("children").at(0);
+        assert_eq!(tag.kind(), "TokTag");
+        assert_eq!(tag.field("tag_name"), &Json::Str("tag".to_string()));
+    }
+
+    #[test]
+    fn test_print_expression() {
+        let json = test_json("::Start\n<<print 1+$x>>");
+
+        let print = json.get("passages").at(0).get("children").at(0);
+        assert_eq!(print.kind(), "TokMacroPrint");
+
+        let expression = print.get("children").at(0);
+        assert_eq!(expression.kind(), "TokExpression");
+        assert_eq!(expression.get("location"), &Json::Null);
+
+        let op = expression.get("children").at(0);
+        assert_eq!(op.kind(), "TokNumOp");
+        assert_eq!(op.field("op_name"), &Json::Str("+".to_string()));
+
+        let operands = op.get("children");
+        assert_eq!(operands.len(), 2);
+        assert_eq!(operands.at(0).kind(), "TokInt");
+        assert_eq!(operands.at(0).field("value"), &Json::Number(1.0));
+        assert_eq!(operands.at(1).kind(), "TokVariable");
+        assert_eq!(operands.at(1).field("name"), &Json::Str("$x".to_string()));
+    }
+
+    #[test]
+    fn test_allow_warning() {
+        let json = test_json("::Start\n<<zwreec-allow dead-end>>The end");
+
+        let allow = json.get("passages").at(0).get("children").at(0);
+        assert_eq!(allow.kind(), "TokMacroAllow");
+        assert_eq!(allow.field("warning"), &Json::Str("dead-end".to_string()));
+    }
+
+    #[test]
+    fn test_byte_offsets() {
+        let input = "::Start\nÄrger <<print $x>>";
+        let cfg = Config::default_config();
+        let ast = ::frontend_ast(cfg.clone(), &mut Cursor::new(input.to_string().into_bytes()));
+
+        let plain = parse(&ast_to_json(&ast));
+        assert_eq!(plain.get("passages").at(0).get("byte_offset"), &Json::Null);
+
+        let json = parse(&ast_to_json_with_source_map(&ast, &SourceMap::from_source(input.as_bytes(), cfg.tab_width)));
+        let children = json.get("passages").at(0).get("children");
+        let print = children.at(1);
+        assert_eq!(print.kind(), "TokMacroPrint");
+        assert_eq!(print.get("location"), &Json::Array(vec![Json::Number(2.0), Json::Number(9.0)]));
+        assert_eq!(print.get("byte_offset"), &Json::Number(input.find("print").unwrap() as f64));
+
+        let variable = print.get("children").at(0).get("children").at(0);
+        assert_eq!(variable.get("byte_offset"), &Json::Number(input.find("$x").unwrap() as f64));
+    }
+}
@@ -31,6 +31,15 @@ pub enum ExpressionParserError {
 
     /// Operator is not implemented
     NotImplementedOperator { op: String },
+
+    /// A conditional `?` without its `:`
+    MissingTernaryElse { location: (u64, u64) },
+
+    /// A binary operator without an operand in front of it, like the second `*` of `1**2`
+    MissingLeftOperand { op: String, location: (u64, u64) },
+
+    /// A binary or unary operator without an operand behind it, like the `*` of `(1*)`
+    MissingRightOperand { op: String, location: (u64, u64) },
 }
 
 /// Parses an expression and ASTNodes.
@@ -59,6 +68,14 @@ impl<'a> ExpressionParser<'a> {
 
     /// Parse the expression node and creates mutliple ast nodes.
     fn parse_expressions(&mut self, node: &mut NodeDefault) {
+        if self.parse_ternary(node) {
+            return
+        }
+
+        // an operator is followed by an operand, a missing operand becomes a 0 under --force
+        let mut expect_operand = true;
+        let mut last_operator: Option<Token> = None;
+
         node.childs.reverse();
         while let Some(top) = node.childs.pop() {
             match top.category() {
@@ -68,14 +85,24 @@ impl<'a> ExpressionParser<'a> {
                 tok @ TokFunction { .. } |
                 tok @ TokArrayLength { .. } |
                 tok @ TokArrayAccess { .. } |
+                tok @ TokMapAccess { .. } |
+                tok @ TokMapLiteral { .. } |
                 tok @ TokVariable { .. } => {
                     let childs_copy = top.as_default().childs.to_vec();
                     self.expr_stack.push( ASTNode::Default(NodeDefault { category: tok.clone(), childs: childs_copy }) );
+                    expect_operand = false;
                 },
                 tok @ TokNumOp      { .. } |
                 tok @ TokCompOp     { .. } |
                 tok @ TokLogOp      { .. } |
                 tok @ TokUnaryMinus { .. } => {
+                    if expect_operand && !is_unary(&tok) {
+                        error_panic!(self.cfg => ExpressionParserError::MissingLeftOperand { op: operator_name(&tok), location: tok.location() });
+                        self.push_placeholder(tok.location());
+                    }
+                    expect_operand = true;
+                    last_operator = Some(tok.clone());
+
                     let length = self.oper_stack.len();
 
                     // cycle through the oper_stack stack backwards
@@ -118,11 +145,19 @@ impl<'a> ExpressionParser<'a> {
                     } else {
                         error_panic!(self.cfg => ExpressionParserError::NoParseableSubExpression);
                     }
+                    expect_operand = false;
                 },
                 _ => ()
             }
         }
 
+        if expect_operand {
+            if let Some(tok) = last_operator {
+                error_panic!(self.cfg => ExpressionParserError::MissingRightOperand { op: operator_name(&tok), location: tok.location() });
+                self.push_placeholder(tok.location());
+            }
+        }
+
         // Parse the last elements of the stacks.
         // To avoid an endless loop we try max until stack.len()
         for _ in 0..self.expr_stack.len() {
@@ -150,19 +185,62 @@ impl<'a> ExpressionParser<'a> {
         }
     }
 
+    /// Splits `cond ? a : b` at the first `?` on this level and its matching `:`.
+    ///
+    /// The conditional has the lowest precedence and is right associative, so the three parts are
+    /// parsed as expressions of their own. Returns false if there is no `?` on this level.
+    fn parse_ternary(&self, node: &mut NodeDefault) -> bool {
+        let question = match node.childs.iter().position(|child| child.category().is_same_token(&TokTernaryIf{location: (0, 0)})) {
+            Some(question) => question,
+            None => return false
+        };
+        let location = node.childs[question].category().location();
+
+        let mut depth = 0;
+        let mut colon = None;
+        for (i, child) in node.childs.iter().enumerate().skip(question + 1) {
+            match child.category() {
+                TokTernaryIf { .. } => depth += 1,
+                TokTernaryElse { .. } if depth == 0 => { colon = Some(i); break },
+                TokTernaryElse { .. } => depth -= 1,
+                _ => ()
+            }
+        }
+        let colon = match colon {
+            Some(colon) => colon,
+            None => {
+                error_panic!(self.cfg => ExpressionParserError::MissingTernaryElse { location: location });
+                return false
+            }
+        };
+
+        let mut parts = Vec::new();
+        for &(start, end) in [(0, question), (question + 1, colon), (colon + 1, node.childs.len())].iter() {
+            let mut part = NodeDefault { category: TokExpression, childs: node.childs[start..end].to_vec() };
+            ExpressionParser::parse(&mut part, self.cfg);
+            match part.childs.pop() {
+                Some(root) => parts.push(root),
+                None => {
+                    error_panic!(self.cfg => ExpressionParserError::NoParseableSubExpression);
+                    return false
+                }
+            }
+        }
+
+        node.childs = vec![ASTNode::Default(NodeDefault { category: TokTernaryIf { location: location }, childs: parts })];
+        true
+    }
+
+    /// Pushes the `0` that stands in for a missing operand at `location`.
+    fn push_placeholder(&mut self, location: (u64, u64)) {
+        self.expr_stack.push(ASTNode::Default(NodeDefault { category: TokInt { location: location, value: 0 }, childs: vec![] }));
+    }
+
     /// Creates a node with an operator as the root.
     fn new_operator_node(&mut self) {
         if let Some(top_op) = self.oper_stack.pop() {
 
-            let is_unary: bool = match top_op.clone() {
-                TokLogOp { op_name: op, .. } => match &*op {
-                    "not" => true,
-                    "!"   => true,
-                    _     => false
-                },
-                TokUnaryMinus { .. } => true,
-                _  => false
-            };
+            let is_unary = is_unary(&top_op);
 
             if self.expr_stack.len() > 0 {
                 let e2: ASTNode = match self.expr_stack.pop() {
@@ -254,3 +332,22 @@ impl<'a> ExpressionParser<'a> {
         }
     }
 }
+
+/// Checks if the operator takes only the operand behind it, like `not` and the unary minus.
+fn is_unary(op: &Token) -> bool {
+    match op {
+        &TokLogOp { ref op_name, .. } => op_name == "not" || op_name == "!",
+        &TokUnaryMinus { .. } => true,
+        _ => false
+    }
+}
+
+/// Returns the operator as it is written in the source.
+fn operator_name(op: &Token) -> String {
+    match op {
+        &TokNumOp { ref op_name, .. } |
+        &TokCompOp { ref op_name, .. } |
+        &TokLogOp { ref op_name, .. } => op_name.clone(),
+        _ => "-".to_string()
+    }
+}
@@ -26,17 +26,22 @@
 //! impression of how it is supposed to work, take a look at the uncompiled [source
 //! code](/src/zwreec/frontend/lexer.rs.html#308-819)
 
-use std::io::{BufReader, Read};
+use std::cell::{Cell, RefCell};
+use std::io::{self, BufReader, Read};
+use std::rc::Rc;
 use utils::extensions::{Peeking, PeekingExt, FilteringScan, FilteringScanExt};
-use config::Config;
+use config::{Config, Dialect};
+use utils::diagnostics::Warning;
 
 use self::Token::*;
 
 /// The errors that can occur in the lexer.
+#[derive(Debug)]
 #[allow(missing_docs)]
 pub enum LexerError {
-    /// The character is not expected in this state
-    UnexpectedCharacter { character: char, location: (u64, u64) }
+    /// The character is not expected in this state, `construct` names the macro, link or
+    /// precondition it is part of
+    UnexpectedCharacter { character: char, location: (u64, u64), construct: Option<&'static str> }
 }
 
 /// Stores the state for the custom iterator `scan_filter()`.
@@ -55,6 +60,191 @@ pub struct ScanState {
 
     /// Skip the next Token while post-processing the Tokens
     skip_next: bool,
+
+    /// The last Token ended a macro
+    after_macro: bool,
+
+    /// The tabs and byte offsets of every line read so far
+    source_map: Rc<RefCell<SourceMap>>,
+
+    /// The location of the `/%` of a comment the lexer has not seen the end of
+    open_comment: Rc<Cell<Option<(u64, u64)>>>,
+}
+
+/// The position of a character inside the twee input.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Location {
+    /// The line, starting at 1
+    pub line: u64,
+
+    /// The column an editor shows the character in, starting at 1
+    pub column: u64,
+
+    /// The offset of the first byte of the character from the start of the input
+    pub byte_offset: u64,
+}
+
+/// The tabs and utf8 characters of one line of the input.
+#[derive(Debug, Clone, Default)]
+struct SourceLine {
+    /// The offset of the first byte of the line
+    start: u64,
+
+    /// The (character) columns of the tabs
+    tabs: Vec<u64>,
+
+    /// The column of the character every utf8 continuation byte belongs to
+    continuations: Vec<u64>,
+}
+
+/// Maps the `(line, column)` locations of the tokens to byte offsets in the input.
+///
+/// The columns of the tokens count characters and are moved by tabs (see `tab_width` in the
+/// config), so editor integrations that address the input by bytes look them up here.
+#[derive(Debug, Clone)]
+pub struct SourceMap {
+    /// The lines read so far, indexed by line - 1
+    lines: Vec<SourceLine>,
+
+    /// The number of bytes read so far
+    len: u64,
+
+    /// The character column of the next character
+    column: u64,
+
+    /// The tab width the columns of the tokens are moved by
+    tab_width: u64,
+}
+
+impl SourceMap {
+    /// Creates an empty map for tokens lexed with the tab width.
+    pub fn new(tab_width: u32) -> SourceMap {
+        SourceMap {
+            lines: vec![SourceLine::default()],
+            len: 0,
+            column: 1,
+            tab_width: if tab_width > 1 { tab_width as u64 } else { 1 },
+        }
+    }
+
+    /// Creates the map of the whole input.
+    ///
+    /// A UTF-8 Byte Order Mark is removed by the screener before the lexer sees it, so it is
+    /// skipped here, but counted in the byte offsets.
+    pub fn from_source(source: &[u8], tab_width: u32) -> SourceMap {
+        if source.starts_with(&[0xef, 0xbb, 0xbf]) {
+            SourceMap::after_bom(&source[3..], tab_width)
+        } else {
+            let mut map = SourceMap::new(tab_width);
+            map.push(source);
+            map
+        }
+    }
+
+    /// Creates the map of an input the screener removed the Byte Order Mark from, the byte
+    /// offsets count it like `from_source`.
+    pub fn after_bom(source: &[u8], tab_width: u32) -> SourceMap {
+        let mut map = SourceMap::new(tab_width);
+        map.len = 3;
+        map.lines[0].start = 3;
+        map.push(source);
+        map
+    }
+
+    /// Adds the next bytes of the input.
+    pub fn push(&mut self, bytes: &[u8]) {
+        for &byte in bytes.iter() {
+            match byte {
+                b'\n' => {
+                    self.lines.push(SourceLine { start: self.len + 1, .. SourceLine::default() });
+                    self.column = 1;
+                },
+                b'\t' => {
+                    self.lines.last_mut().unwrap().tabs.push(self.column);
+                    self.column += 1;
+                },
+                // continuation bytes of utf8 characters do not start a new column
+                byte if byte & 0xc0 == 0x80 => {
+                    self.lines.last_mut().unwrap().continuations.push(self.column - 1);
+                },
+                _ => self.column += 1
+            }
+            self.len += 1;
+        }
+    }
+
+    /// Returns the column an editor shows the character at `(line, column)` of rustlex at.
+    fn display_column(&self, location: (u64, u64)) -> u64 {
+        match self.lines.get((location.0 as usize).wrapping_sub(1)) {
+            Some(line) => display_column(&line.tabs, location.1, self.tab_width),
+            None => location.1
+        }
+    }
+
+    /// Returns the full location of a token location.
+    ///
+    /// Locations after the input read so far get the offset of its end.
+    pub fn location(&self, location: (u64, u64)) -> Location {
+        let (line, column) = location;
+        let byte_offset = match self.lines.get((line as usize).wrapping_sub(1)) {
+            Some(source_line) => {
+                let character = character_column(&source_line.tabs, column, self.tab_width);
+                let continuations = source_line.continuations.iter().filter(|&&c| c < character).count() as u64;
+                ::std::cmp::min(source_line.start + character.saturating_sub(1) + continuations, self.len)
+            },
+            None => self.len
+        };
+        Location { line: line, column: column, byte_offset: byte_offset }
+    }
+}
+
+/// A reader that records the tabs and utf8 characters it passes on in a `SourceMap`.
+///
+/// rustlex counts a tab as a single column. The recorded tabs let `scan_fn` move the columns of
+/// the tokens to where an editor with the configured tab width shows them.
+pub struct TabStops<R> {
+    inner: R,
+
+    /// The map of the input read so far
+    source_map: Rc<RefCell<SourceMap>>,
+}
+
+impl<R: Read> Read for TabStops<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let count = try!(self.inner.read(buf));
+        self.source_map.borrow_mut().push(&buf[..count]);
+        Ok(count)
+    }
+}
+
+/// Returns the column an editor shows the character in `column` at, if the line has tabs in the
+/// (ascending) columns `tabs`.
+fn display_column(tabs: &[u64], column: u64, tab_width: u64) -> u64 {
+    let mut shift = 0;
+    for &tab in tabs.iter().take_while(|&&tab| tab < column) {
+        let start = tab + shift;
+        let next_stop = ((start - 1) / tab_width + 1) * tab_width + 1;
+        shift += next_stop - start - 1;
+    }
+    column + shift
+}
+
+/// Returns the character column of the `column` an editor shows, the inverse of
+/// `display_column`. Columns inside of a tab belong to the tab.
+fn character_column(tabs: &[u64], column: u64, tab_width: u64) -> u64 {
+    let mut shift = 0;
+    for &tab in tabs.iter() {
+        let start = tab + shift;
+        if column <= start {
+            break;
+        }
+        let next_stop = ((start - 1) / tab_width + 1) * tab_width + 1;
+        if column < next_stop {
+            return tab;
+        }
+        shift += next_stop - start - 1;
+    }
+    column - shift
 }
 
 /// Builds a Token iterator for twee input.
@@ -64,6 +254,9 @@ pub struct ScanState {
 /// iterator to provide lookahead and the `FilteringScan` iterator to merge adjacent
 /// text tokens and combine variable assignment.
 ///
+/// Unexpected characters are reported right away, invalid tokens like a number that is too
+/// large are passed on as `TokError` and reported by the parser.
+///
 /// # Example
 ///
 /// ```
@@ -76,10 +269,13 @@ pub struct ScanState {
 /// let tokens = zwreec::frontend::lexer::lex(cfg, &mut input);
 /// ```
 #[allow(unused_variables)]
-pub fn lex<R: Read>(cfg: Config, input: R) -> FilteringScan<Peeking<TweeLexer<BufReader<R>>, Token>, ScanState, fn(&mut ScanState, (Token, Option<Token>)) -> Option<Token>>  {
+pub fn lex<R: Read>(cfg: Config, input: R) -> FilteringScan<Peeking<TweeLexer<BufReader<TabStops<R>>>, Token>, ScanState, fn(&mut ScanState, (Token, Option<Token>)) -> Option<Token>>  {
 
+    let source_map = Rc::new(RefCell::new(SourceMap::new(cfg.tab_width)));
+    let input = TabStops { inner: input, source_map: source_map.clone() };
     let mut lexer = TweeLexer::new(BufReader::new(input));
     lexer.cfg = Some(cfg.clone());
+    let open_comment = lexer.open_comment.clone();
 
     info!("Started lexing input");
 
@@ -89,6 +285,9 @@ pub fn lex<R: Read>(cfg: Config, input: R) -> FilteringScan<Peeking<TweeLexer<Bu
             current_text: String::new(),
             current_text_location: (0, 0),
             skip_next: false,
+            after_macro: false,
+            source_map: source_map,
+            open_comment: open_comment,
         },
         {
             fn scan_fn(state: &mut ScanState, elem: (Token, Option<Token>)) -> Option<Token> {
@@ -99,11 +298,19 @@ pub fn lex<R: Read>(cfg: Config, input: R) -> FilteringScan<Peeking<TweeLexer<Bu
 
                 let last_element = elem.1.is_none();
 
-                let ret = match elem {
-                    (x @ TokError {..}, _) => {
-                        error_panic!(state.cfg => x);
-                        None
+                // the next token is formatted the same way, so that text markers are merged
+                let rules = dialect_rules(state.cfg.dialect);
+                let mut elem = (rules.apply_formatting(elem.0), elem.1.map(|next| rules.apply_formatting(next)));
+                if state.cfg.tab_width > 1 {
+                    if let Some(location) = elem.0.location_mut() {
+                        location.1 = state.source_map.borrow().display_column(*location);
                     }
+                }
+
+                let ret = match elem {
+                    // the parser reports invalid tokens and goes on behind them
+                    (x @ TokError {..}, _) => Some(x),
+                    (TokNewLine {..}, _) if state.after_macro && rules.swallow_newline_after_macro => None,
                     (TokText {location, text}, Some(TokText{ .. })) => {
                         if state.current_text.len() == 0 {
                             state.current_text_location = location;
@@ -126,10 +333,30 @@ pub fn lex<R: Read>(cfg: Config, input: R) -> FilteringScan<Peeking<TweeLexer<Bu
                         state.skip_next = true;
                         Some(TokAssign {location: location, var_name: var, op_name: op} )
                     },
+                    (TokMacroBr {location}, Some(TokMacroEnd {..})) => {
+                        state.skip_next = true;
+                        Some(TokMacroBr {location: location})
+                    },
+                    (TokMacroToc {location}, Some(TokMacroEnd {..})) => {
+                        state.skip_next = true;
+                        Some(TokMacroToc {location: location})
+                    },
                     (x, _) => Some(x),
                 };
 
+                state.after_macro = match ret {
+                    Some(TokMacroEnd {..}) | Some(TokMacroBr {..}) | Some(TokMacroToc {..}) => true,
+                    _ => false
+                };
+
                 if last_element {
+                    // the lexer has read the whole input once the last token is known
+                    if let Some(mut location) = state.open_comment.get() {
+                        if state.cfg.tab_width > 1 {
+                            location.1 = state.source_map.borrow().display_column(location);
+                        }
+                        error_panic!(state.cfg => TokError {location: location, message: "Unterminated comment, a comment has to be closed with %/".to_string(), raw: "/%".to_string()});
+                    }
                     info!("Finished lexing input");
                 }
 
@@ -155,6 +382,8 @@ pub enum Token {
     TokVarSetEnd              {location: (u64, u64)},
     TokPassageLink            {location: (u64, u64), display_name: String, passage_name: String},
     TokTag                    {location: (u64, u64), tag_name: String},
+    TokPrecondition           {location: (u64, u64)},
+    TokPreconditionTarget     {location: (u64, u64), passage_name: String},
     TokText                   {location: (u64, u64), text: String},
     TokFormatBoldStart        {location: (u64, u64)}, TokFormatBoldEnd   {location: (u64, u64)},
     TokFormatItalicStart      {location: (u64, u64)}, TokFormatItalicEnd {location: (u64, u64)},
@@ -178,10 +407,25 @@ pub enum Token {
     TokMacroEndIf             {location: (u64, u64)},
     TokMacroPrint             {location: (u64, u64)},
     TokMacroDisplay           {location: (u64, u64), passage_name: String},
+    TokMacroPlugin            {location: (u64, u64), name: String},
     TokMacroSilently          {location: (u64, u64)},
     TokMacroEndSilently       {location: (u64, u64)},
     TokMacroNoBr              {location: (u64, u64)},
     TokMacroEndNoBr           {location: (u64, u64)},
+    TokMacroOnExit            {location: (u64, u64)},
+    TokMacroEndOnExit         {location: (u64, u64)},
+    TokMacroBr                {location: (u64, u64)},
+    TokMacroToc               {location: (u64, u64)},
+    TokMacroConfirm           {location: (u64, u64)},
+    TokMacroClear             {location: (u64, u64)},
+    TokMacroAt                {location: (u64, u64)},
+    TokMacroEndAt             {location: (u64, u64)},
+    TokMacroCountdown         {location: (u64, u64)},
+    TokMacroAllow             {location: (u64, u64), warning: String},
+    TokMacroOtherwise         {location: (u64, u64)},
+    TokMacroWidget            {location: (u64, u64), name: String},
+    TokMacroEndWidget         {location: (u64, u64)},
+    TokMacroWidgetCall        {location: (u64, u64), name: String},
     TokParenOpen              {location: (u64, u64)},
     TokParenClose             {location: (u64, u64)},
     TokVariable               {location: (u64, u64), name: String},
@@ -196,15 +440,20 @@ pub enum Token {
     TokArgsEnd                {location: (u64, u64)},
     TokArrayStart             {location: (u64, u64)},
     TokArrayEnd               {location: (u64, u64)},
+    TokMapLiteral             {location: (u64, u64)},
+    TokMapLiteralEnd          {location: (u64, u64)},
+    TokMapAccess              {location: (u64, u64), name: String, key: String},
     TokAssign                 {location: (u64, u64), var_name: String, op_name: String},
     TokNumOp                  {location: (u64, u64), op_name: String},
     TokCompOp                 {location: (u64, u64), op_name: String},
     TokLogOp                  {location: (u64, u64), op_name: String},
     TokSemiColon              {location: (u64, u64)},
+    TokTernaryIf              {location: (u64, u64)},
+    TokTernaryElse            {location: (u64, u64)},
     TokNewLine                {location: (u64, u64)},
     TokUnaryMinus             {location: (u64, u64)},
     TokExpression,
-    TokError                  {location: (u64, u64), message: String},
+    TokError                  {location: (u64, u64), message: String, raw: String},
 }
 
 impl Token {
@@ -218,6 +467,8 @@ impl Token {
             &TokVarSetEnd{location} |
             &TokPassageLink{location, ..} |
             &TokTag{location, ..} |
+            &TokPrecondition{location} |
+            &TokPreconditionTarget{location, ..} |
             &TokText{location, ..} |
             &TokFormatBoldStart{location} |
             &TokFormatBoldEnd{location} |
@@ -248,10 +499,25 @@ impl Token {
             &TokMacroEndIf{location} |
             &TokMacroPrint{location} |
             &TokMacroDisplay{location, ..} |
+            &TokMacroPlugin{location, ..} |
             &TokMacroSilently{location} |
             &TokMacroEndSilently{location} |
             &TokMacroNoBr{location} |
             &TokMacroEndNoBr{location} |
+            &TokMacroOnExit{location} |
+            &TokMacroEndOnExit{location} |
+            &TokMacroBr{location} |
+            &TokMacroToc{location} |
+            &TokMacroConfirm{location} |
+            &TokMacroClear{location} |
+            &TokMacroAt{location} |
+            &TokMacroEndAt{location} |
+            &TokMacroCountdown{location} |
+            &TokMacroAllow{location, ..} |
+            &TokMacroOtherwise{location} |
+            &TokMacroWidget{location, ..} |
+            &TokMacroEndWidget{location} |
+            &TokMacroWidgetCall{location, ..} |
             &TokParenOpen{location} |
             &TokParenClose{location} |
             &TokVariable{location, ..} |
@@ -266,11 +532,16 @@ impl Token {
             &TokArgsEnd{location} |
             &TokArrayStart{location} |
             &TokArrayEnd{location} |
+            &TokMapLiteral{location} |
+            &TokMapLiteralEnd{location} |
+            &TokMapAccess{location, ..} |
             &TokAssign{location, ..} |
             &TokNumOp{location, ..} |
             &TokCompOp{location, ..} |
             &TokLogOp{location, ..} |
             &TokSemiColon{location} |
+            &TokTernaryIf{location} |
+            &TokTernaryElse{location} |
             &TokNewLine{location} |
             &TokUnaryMinus{location} |
             &TokError{location, ..}
@@ -278,6 +549,99 @@ impl Token {
             &TokExpression => (0, 0)
         }
     }
+
+    /// Returns the location of the token for changing it, `None` for `TokExpression`.
+    fn location_mut(&mut self) -> Option<&mut (u64, u64)> {
+        match self {
+            &mut TokPassage{ref mut location, ..} |
+            &mut TokTagStart{ref mut location} |
+            &mut TokTagEnd{ref mut location} |
+            &mut TokVarSetStart{ref mut location} |
+            &mut TokVarSetEnd{ref mut location} |
+            &mut TokPassageLink{ref mut location, ..} |
+            &mut TokTag{ref mut location, ..} |
+            &mut TokPrecondition{ref mut location} |
+            &mut TokPreconditionTarget{ref mut location, ..} |
+            &mut TokText{ref mut location, ..} |
+            &mut TokFormatBoldStart{ref mut location} |
+            &mut TokFormatBoldEnd{ref mut location} |
+            &mut TokFormatItalicStart{ref mut location} |
+            &mut TokFormatItalicEnd{ref mut location} |
+            &mut TokFormatUnderStart {ref mut location} |
+            &mut TokFormatUnderEnd{ref mut location} |
+            &mut TokFormatStrikeStart{ref mut location} |
+            &mut TokFormatStrikeEnd{ref mut location} |
+            &mut TokFormatSubStart{ref mut location} |
+            &mut TokFormatSubEnd{ref mut location} |
+            &mut TokFormatSupStart{ref mut location} |
+            &mut TokFormatSupEnd{ref mut location} |
+            &mut TokFormatMonoStart{ref mut location} |
+            &mut TokFormatMonoEnd{ref mut location} |
+            &mut TokFormatBulList{ref mut location} |
+            &mut TokFormatNumbList{ref mut location} |
+            &mut TokFormatIndentBlock{ref mut location} |
+            &mut TokFormatHorizontalLine{ref mut location} |
+            &mut TokFormatHeading{ref mut location, ..} |
+            &mut TokMacroStart{ref mut location} |
+            &mut TokMacroEnd{ref mut location} |
+            &mut TokMacroContentVar{ref mut location, ..} |
+            &mut TokMacroSet{ref mut location} |
+            &mut TokMacroIf{ref mut location} |
+            &mut TokMacroElse{ref mut location} |
+            &mut TokMacroElseIf{ref mut location} |
+            &mut TokMacroEndIf{ref mut location} |
+            &mut TokMacroPrint{ref mut location} |
+            &mut TokMacroDisplay{ref mut location, ..} |
+            &mut TokMacroPlugin{ref mut location, ..} |
+            &mut TokMacroSilently{ref mut location} |
+            &mut TokMacroEndSilently{ref mut location} |
+            &mut TokMacroNoBr{ref mut location} |
+            &mut TokMacroEndNoBr{ref mut location} |
+            &mut TokMacroOnExit{ref mut location} |
+            &mut TokMacroEndOnExit{ref mut location} |
+            &mut TokMacroBr{ref mut location} |
+            &mut TokMacroToc{ref mut location} |
+            &mut TokMacroConfirm{ref mut location} |
+            &mut TokMacroClear{ref mut location} |
+            &mut TokMacroAt{ref mut location} |
+            &mut TokMacroEndAt{ref mut location} |
+            &mut TokMacroCountdown{ref mut location} |
+            &mut TokMacroAllow{ref mut location, ..} |
+            &mut TokMacroOtherwise{ref mut location} |
+            &mut TokMacroWidget{ref mut location, ..} |
+            &mut TokMacroEndWidget{ref mut location} |
+            &mut TokMacroWidgetCall{ref mut location, ..} |
+            &mut TokParenOpen{ref mut location} |
+            &mut TokParenClose{ref mut location} |
+            &mut TokVariable{ref mut location, ..} |
+            &mut TokArrayLength{ref mut location, ..} |
+            &mut TokArrayAccess{ref mut location, ..} |
+            &mut TokInt{ref mut location, ..} |
+            &mut TokFloat{ref mut location, ..} |
+            &mut TokString{ref mut location, ..} |
+            &mut TokBoolean{ref mut location, ..} |
+            &mut TokFunction{ref mut location, ..} |
+            &mut TokColon{ref mut location} |
+            &mut TokArgsEnd{ref mut location} |
+            &mut TokArrayStart{ref mut location} |
+            &mut TokArrayEnd{ref mut location} |
+            &mut TokMapLiteral{ref mut location} |
+            &mut TokMapLiteralEnd{ref mut location} |
+            &mut TokMapAccess{ref mut location, ..} |
+            &mut TokAssign{ref mut location, ..} |
+            &mut TokNumOp{ref mut location, ..} |
+            &mut TokCompOp{ref mut location, ..} |
+            &mut TokLogOp{ref mut location, ..} |
+            &mut TokSemiColon{ref mut location} |
+            &mut TokTernaryIf{ref mut location} |
+            &mut TokTernaryElse{ref mut location} |
+            &mut TokNewLine{ref mut location} |
+            &mut TokUnaryMinus{ref mut location} |
+            &mut TokError{ref mut location, ..}
+                => Some(location),
+            &mut TokExpression => None
+        }
+    }
 }
 
 impl Token {
@@ -294,6 +658,8 @@ impl Token {
             (&TokVarSetEnd{..}, &TokVarSetEnd{..}) => true,
             (&TokPassageLink{..}, &TokPassageLink{..}) => true,
             (&TokTag{..}, &TokTag{..}) => true,
+            (&TokPrecondition{..}, &TokPrecondition{..}) => true,
+            (&TokPreconditionTarget{..}, &TokPreconditionTarget{..}) => true,
             (&TokText{..}, &TokText{..}) => true,
             (&TokFormatBoldStart{..}, &TokFormatBoldStart{..}) => true,
             (&TokFormatBoldEnd{..}, &TokFormatBoldEnd{..}) => true,
@@ -324,10 +690,25 @@ impl Token {
             (&TokMacroEndIf{..}, &TokMacroEndIf{..}) => true,
             (&TokMacroPrint{..}, &TokMacroPrint{..}) => true,
             (&TokMacroDisplay{..}, &TokMacroDisplay{..}) => true,
+            (&TokMacroPlugin{..}, &TokMacroPlugin{..}) => true,
             (&TokMacroSilently{..}, &TokMacroSilently{..}) => true,
             (&TokMacroEndNoBr{..}, &TokMacroEndNoBr{..}) => true,
             (&TokMacroNoBr{..}, &TokMacroNoBr{..}) => true,
             (&TokMacroEndSilently{..}, &TokMacroEndSilently{..}) => true,
+            (&TokMacroOnExit{..}, &TokMacroOnExit{..}) => true,
+            (&TokMacroEndOnExit{..}, &TokMacroEndOnExit{..}) => true,
+            (&TokMacroBr{..}, &TokMacroBr{..}) => true,
+            (&TokMacroToc{..}, &TokMacroToc{..}) => true,
+            (&TokMacroConfirm{..}, &TokMacroConfirm{..}) => true,
+            (&TokMacroClear{..}, &TokMacroClear{..}) => true,
+            (&TokMacroAt{..}, &TokMacroAt{..}) => true,
+            (&TokMacroEndAt{..}, &TokMacroEndAt{..}) => true,
+            (&TokMacroCountdown{..}, &TokMacroCountdown{..}) => true,
+            (&TokMacroAllow{..}, &TokMacroAllow{..}) => true,
+            (&TokMacroOtherwise{..}, &TokMacroOtherwise{..}) => true,
+            (&TokMacroWidget{..}, &TokMacroWidget{..}) => true,
+            (&TokMacroEndWidget{..}, &TokMacroEndWidget{..}) => true,
+            (&TokMacroWidgetCall{..}, &TokMacroWidgetCall{..}) => true,
             (&TokParenOpen{..}, &TokParenOpen{..}) => true,
             (&TokParenClose{..}, &TokParenClose{..}) => true,
             (&TokVariable{..}, &TokVariable{..}) => true,
@@ -342,11 +723,16 @@ impl Token {
             (&TokArgsEnd{..}, &TokArgsEnd{..}) => true,
             (&TokArrayStart{..}, &TokArrayStart{..}) => true,
             (&TokArrayEnd{..}, &TokArrayEnd{..}) => true,
+            (&TokMapLiteral{..}, &TokMapLiteral{..}) => true,
+            (&TokMapLiteralEnd{..}, &TokMapLiteralEnd{..}) => true,
+            (&TokMapAccess{..}, &TokMapAccess{..}) => true,
             (&TokAssign{..}, &TokAssign{..}) => true,
             (&TokNumOp{..}, &TokNumOp{..}) => true,
             (&TokCompOp{..}, &TokCompOp{..}) => true,
             (&TokLogOp{..}, &TokLogOp{..}) => true,
             (&TokSemiColon{..}, &TokSemiColon{..}) => true,
+            (&TokTernaryIf{..}, &TokTernaryIf{..}) => true,
+            (&TokTernaryElse{..}, &TokTernaryElse{..}) => true,
             (&TokNewLine{..}, &TokNewLine{..}) => true,
             (&TokUnaryMinus{..}, &TokUnaryMinus{..}) => true,
             (&TokError{..}, &TokError{..}) => true,
@@ -382,13 +768,234 @@ fn unescape(s: String) -> String {
 }
 
 
+/// Returns the form of a passage name that is used to find the passage.
+///
+/// Passage names may contain any character except newlines, `[` and `]`, which start and end
+/// tags and links, and `|`, which separates the text of a link from its target. `$`, `<` and
+/// `>` are taken literally. Whitespace around the name is not part of it, so `[[ Next ]]` links
+/// to `::Next`, just like `[[Next]]` links to the `:: Next ` of a Twine export. Passage
+/// declarations, link targets and `<<display>>` all use this form.
+///
+/// Editors save accented letters either precomposed (NFC) or as the letter followed by a
+/// combining accent (NFD), so the name is composed to NFC. This is only a partial
+/// normalization: it composes the letters of Latin-1 in `COMPOSITIONS`, every other combining
+/// character is kept as it is.
+pub fn normalize_passage_name(name: &str) -> String {
+    compose_accents(name.trim())
+}
+
+/// The letters of Latin-1 that `normalize_passage_name` composes, as the letter, the
+/// combining accent and the precomposed letter.
+static COMPOSITIONS: &'static [(char, char, char)] = &[
+    // grave
+    ('A', '\u{300}', '\u{c0}'), ('E', '\u{300}', '\u{c8}'), ('I', '\u{300}', '\u{cc}'), ('O', '\u{300}', '\u{d2}'),
+    ('U', '\u{300}', '\u{d9}'), ('a', '\u{300}', '\u{e0}'), ('e', '\u{300}', '\u{e8}'), ('i', '\u{300}', '\u{ec}'),
+    ('o', '\u{300}', '\u{f2}'), ('u', '\u{300}', '\u{f9}'),
+    // acute
+    ('A', '\u{301}', '\u{c1}'), ('E', '\u{301}', '\u{c9}'), ('I', '\u{301}', '\u{cd}'), ('O', '\u{301}', '\u{d3}'),
+    ('U', '\u{301}', '\u{da}'), ('Y', '\u{301}', '\u{dd}'), ('a', '\u{301}', '\u{e1}'), ('e', '\u{301}', '\u{e9}'),
+    ('i', '\u{301}', '\u{ed}'), ('o', '\u{301}', '\u{f3}'), ('u', '\u{301}', '\u{fa}'), ('y', '\u{301}', '\u{fd}'),
+    // circumflex
+    ('A', '\u{302}', '\u{c2}'), ('E', '\u{302}', '\u{ca}'), ('I', '\u{302}', '\u{ce}'), ('O', '\u{302}', '\u{d4}'),
+    ('U', '\u{302}', '\u{db}'), ('a', '\u{302}', '\u{e2}'), ('e', '\u{302}', '\u{ea}'), ('i', '\u{302}', '\u{ee}'),
+    ('o', '\u{302}', '\u{f4}'), ('u', '\u{302}', '\u{fb}'),
+    // tilde
+    ('A', '\u{303}', '\u{c3}'), ('N', '\u{303}', '\u{d1}'), ('O', '\u{303}', '\u{d5}'), ('a', '\u{303}', '\u{e3}'),
+    ('n', '\u{303}', '\u{f1}'), ('o', '\u{303}', '\u{f5}'),
+    // diaeresis
+    ('A', '\u{308}', '\u{c4}'), ('E', '\u{308}', '\u{cb}'), ('I', '\u{308}', '\u{cf}'), ('O', '\u{308}', '\u{d6}'),
+    ('U', '\u{308}', '\u{dc}'), ('a', '\u{308}', '\u{e4}'), ('e', '\u{308}', '\u{eb}'), ('i', '\u{308}', '\u{ef}'),
+    ('o', '\u{308}', '\u{f6}'), ('u', '\u{308}', '\u{fc}'), ('y', '\u{308}', '\u{ff}'),
+    // ring above
+    ('A', '\u{30a}', '\u{c5}'), ('a', '\u{30a}', '\u{e5}'),
+    // cedilla
+    ('C', '\u{327}', '\u{c7}'), ('c', '\u{327}', '\u{e7}'),
+];
+
+/// Replaces the letters followed by a combining accent with the precomposed letters.
+fn compose_accents(name: &str) -> String {
+    let mut composed = String::new();
+    let mut last: Option<char> = None;
+    for c in name.chars() {
+        if let Some(letter) = last {
+            if let Some(&(_, _, precomposed)) = COMPOSITIONS.iter().find(|&&(base, mark, _)| base == letter && mark == c) {
+                last = Some(precomposed);
+                continue;
+            }
+            composed.push(letter);
+        }
+        last = Some(c);
+    }
+    if let Some(letter) = last {
+        composed.push(letter);
+    }
+    composed
+}
+
+/// Checks if the character is one of the combining diacritical marks.
+fn is_combining_mark(c: char) -> bool {
+    c >= '\u{300}' && c <= '\u{36f}'
+}
+
+/// Checks if two normalized passage names could be the same name in different normalization
+/// forms.
+///
+/// This finds the names `normalize_passage_name` does not normalize to the same form, like a
+/// letter outside of Latin-1 with a combining accent and the precomposed letter. The names need
+/// to have the same letters besides the accents, a letter with a combining accent matches any
+/// letter outside of ASCII. At least one of the names has to contain a combining accent.
+pub fn differently_normalized(a: &str, b: &str) -> bool {
+    // the letters without their accents, and whether a combining accent follows them
+    fn letters(name: &str) -> Vec<(char, bool)> {
+        let mut letters: Vec<(char, bool)> = vec![];
+        for c in name.chars() {
+            if is_combining_mark(c) {
+                if let Some(last) = letters.last_mut() {
+                    last.1 = true;
+                }
+                continue;
+            }
+            let base = COMPOSITIONS.iter().find(|&&(_, _, precomposed)| precomposed == c).map(|&(base, _, _)| base);
+            letters.push((base.unwrap_or(c), false));
+        }
+        letters
+    }
+
+    let (a_letters, b_letters) = (letters(a), letters(b));
+    a != b && a_letters.len() == b_letters.len() && (a.chars().any(is_combining_mark) || b.chars().any(is_combining_mark)) &&
+        a_letters.iter().zip(b_letters.iter()).all(|(&(x, x_accented), &(y, y_accented))|
+            x == y || (x_accented && y as u32 > 0x7f) || (y_accented && x as u32 > 0x7f))
+}
+
+/// The names of the built-in macros.
+///
+/// Any other name after `<<` is lexed as a short `<<display>>` of the passage with that name,
+/// unless it is the name of a plugin macro or a widget.
+pub static MACROS: &'static [&'static str] = &[
+    "at", "br", "clear", "confirm", "countdown", "display", "else", "elseif", "endat", "endif",
+    "endnobr", "endonexit", "endsilently", "endwidget", "if", "loadgame", "nobr", "onexit", "print",
+    "savegame", "set", "silently", "toc", "widget", "zwreec-allow"];
+
+/// The differences between the dialects of `config::Dialect` that the lexer follows.
+pub struct DialectRules {
+    /// The recognized formatting markers with the marker of the formatting they are lexed as,
+    /// any other marker is kept as text
+    pub formatting: &'static [(&'static str, &'static str)],
+
+    /// Other names of built-in macros with the name of the macro they stand for
+    pub macro_aliases: &'static [(&'static str, &'static str)],
+
+    /// Whether a line break right after the `>>` of a macro is dropped
+    pub swallow_newline_after_macro: bool,
+}
+
+/// The formatting markers of Twee 1.4, the lexer recognizes all of them.
+const TWEE14_FORMATTING: &'static [(&'static str, &'static str)] = &[
+    ("''", "''"), ("//", "//"), ("__", "__"), ("==", "=="), ("~~", "~~"), ("^^", "^^"), ("{{{", "{{{")];
+
+static LEGACY_RULES: DialectRules = DialectRules {
+    formatting: TWEE14_FORMATTING,
+    macro_aliases: &[],
+    swallow_newline_after_macro: false,
+};
+
+static HARLOWE_RULES: DialectRules = DialectRules {
+    // Harlowe strikes text through with `~~` and has no underline, subscript or monospace
+    formatting: &[("''", "''"), ("//", "//"), ("~~", "=="), ("^^", "^^")],
+    macro_aliases: &[],
+    swallow_newline_after_macro: false,
+};
+
+static SUGARCUBE_RULES: DialectRules = DialectRules {
+    formatting: TWEE14_FORMATTING,
+    macro_aliases: &[
+        ("-", "print"), ("/if", "endif"), ("/nobr", "endnobr"), ("/silently", "endsilently"), ("=", "print")],
+    swallow_newline_after_macro: true,
+};
+
+/// Returns the lexer rules of the dialect.
+pub fn dialect_rules(dialect: Dialect) -> &'static DialectRules {
+    match dialect {
+        Dialect::Legacy => &LEGACY_RULES,
+        Dialect::Harlowe => &HARLOWE_RULES,
+        Dialect::SugarCube => &SUGARCUBE_RULES,
+    }
+}
+
+impl DialectRules {
+    /// Returns the name of the built-in macro for an alias, other names are returned as they are.
+    pub fn macro_name(&self, name: String) -> String {
+        match self.macro_aliases.iter().find(|&&(alias, _)| alias == name) {
+            Some(&(_, builtin)) => builtin.to_string(),
+            None => name
+        }
+    }
+
+    /// Lexes a formatting token as the formatting its marker stands for, or as the text of the
+    /// marker if the dialect does not recognize it. Other tokens are returned as they are.
+    fn apply_formatting(&self, token: Token) -> Token {
+        let (marker, start) = match format_marker(&token) {
+            Some(marker) => marker,
+            None => return token
+        };
+        let location = token.location();
+        match self.formatting.iter().find(|&&(source, _)| source == marker) {
+            Some(&(_, lexed_as)) => format_token(lexed_as, start, location),
+            None => TokText {location: location, text: if marker == "{{{" && !start { "}}}" } else { marker }.to_string()},
+        }
+    }
+}
+
+/// Returns the marker of a formatting token and whether the token starts the formatting.
+fn format_marker(token: &Token) -> Option<(&'static str, bool)> {
+    match token {
+        &TokFormatBoldStart {..} => Some(("''", true)),
+        &TokFormatBoldEnd {..} => Some(("''", false)),
+        &TokFormatItalicStart {..} => Some(("//", true)),
+        &TokFormatItalicEnd {..} => Some(("//", false)),
+        &TokFormatUnderStart {..} => Some(("__", true)),
+        &TokFormatUnderEnd {..} => Some(("__", false)),
+        &TokFormatStrikeStart {..} => Some(("==", true)),
+        &TokFormatStrikeEnd {..} => Some(("==", false)),
+        &TokFormatSubStart {..} => Some(("~~", true)),
+        &TokFormatSubEnd {..} => Some(("~~", false)),
+        &TokFormatSupStart {..} => Some(("^^", true)),
+        &TokFormatSupEnd {..} => Some(("^^", false)),
+        &TokFormatMonoStart {..} => Some(("{{{", true)),
+        &TokFormatMonoEnd {..} => Some(("{{{", false)),
+        _ => None
+    }
+}
+
+/// Returns the token that starts or ends the formatting of the marker, the inverse of
+/// `format_marker`.
+fn format_token(marker: &str, start: bool, location: (u64, u64)) -> Token {
+    match (marker, start) {
+        ("''", true) => TokFormatBoldStart {location: location},
+        ("''", false) => TokFormatBoldEnd {location: location},
+        ("//", true) => TokFormatItalicStart {location: location},
+        ("//", false) => TokFormatItalicEnd {location: location},
+        ("__", true) => TokFormatUnderStart {location: location},
+        ("__", false) => TokFormatUnderEnd {location: location},
+        ("==", true) => TokFormatStrikeStart {location: location},
+        ("==", false) => TokFormatStrikeEnd {location: location},
+        ("~~", true) => TokFormatSubStart {location: location},
+        ("~~", false) => TokFormatSubEnd {location: location},
+        ("^^", true) => TokFormatSupStart {location: location},
+        ("^^", false) => TokFormatSupEnd {location: location},
+        (_, true) => TokFormatMonoStart {location: location},
+        (_, false) => TokFormatMonoEnd {location: location},
+    }
+}
+
 // ================================
 // Test functions
 #[cfg(test)]
 mod tests {
     use std::io::Cursor;
     use std::fmt::Write;
-    use config::Config;
+    use config::{Config, Dialect};
+    use utils::error::ErrorRecord;
 
     use super::*;
     use super::Token::*;
@@ -485,6 +1092,57 @@ mod tests {
         assert_tok_eq(expected, tokens);
     }
 
+    #[test]
+    fn comment_passage_boundary_test() {
+        // the declaration of Next closes the comment
+        let tokens = test_lex("::Start\nA /% open\n<<set $x to 1>>\n::Next\nB");
+        let expected = vec!(
+            TokPassage {name: "Start".to_string(), location: (1, 3)},
+            TokText {text: "A ".to_string(), location: (2, 1)},
+            TokPassage {name: "Next".to_string(), location: (4, 3)},
+            TokText {text: "B".to_string(), location: (5, 1)},
+        );
+
+        assert_tok_eq(expected, tokens);
+    }
+
+    #[test]
+    #[should_panic(expected = "Unterminated comment, a comment has to be closed with %/")]
+    fn unterminated_comment_test() {
+        test_lex("::Start\nText /% never closed\n\nmore text");
+    }
+
+    #[test]
+    fn unterminated_comment_force_test() {
+        let mut cfg = Config::default_config();
+        cfg.force = true;
+        let mut cursor: Cursor<Vec<u8>> = Cursor::new("::Start\nText /% never closed".to_string().into_bytes());
+        let tokens: Vec<Token> = lex(cfg.clone(), &mut cursor).collect();
+        assert_eq!(cfg.error_count(), 1);
+        assert_tok_eq(vec!(
+            TokPassage {name: "Start".to_string(), location: (1, 3)},
+            TokText {text: "Text ".to_string(), location: (2, 1)},
+        ), tokens);
+    }
+
+    #[test]
+    fn comment_literal_test() {
+        // strings and monospace keep the markers, HTML comments are independent of them
+        let tokens = test_lex("::Start\n<<print \"/% kept %/\">>\n<html><!-- /% --></html>/% <!-- %/{{{/%}}}");
+        let expected = vec!(
+            TokPassage {name: "Start".to_string(), location: (1, 3)},
+            TokMacroPrint {location: (2, 3)},
+            TokString {value: "/% kept %/".to_string(), location: (2, 9)},
+            TokMacroEnd {location: (2, 21)},
+            TokNewLine {location: (2, 23)},
+            TokFormatMonoStart {location: (3, 35)},
+            TokText {text: "/%".to_string(), location: (3, 38)},
+            TokFormatMonoEnd {location: (3, 40)},
+        );
+
+        assert_tok_eq(expected, tokens);
+    }
+
     #[test]
     fn passage_test() {
         // This should detect the ::Start passage
@@ -532,6 +1190,29 @@ mod tests {
         assert_tok_eq(expected, tokens);
     }
 
+    #[test]
+    fn precondition_test() {
+        // The conditions are expressions, the targets reach to the next ';' or the end of the tags
+        let tokens = test_lex("::Mine [dark requires: $lamp -> Dark End; requires: $rope gt 0 -> Fall]\nContent");
+        let expected = vec!(
+            TokPassage {name: "Mine".to_string(), location: (1, 3)},
+            TokTagStart {location: (1, 8)},
+            TokTag {location: (1, 9), tag_name: "dark".to_string()},
+            TokPrecondition {location: (1, 14)},
+            TokVariable {location: (1, 24), name: "$lamp".to_string()},
+            TokPreconditionTarget {location: (1, 30), passage_name: "Dark End".to_string()},
+            TokPrecondition {location: (1, 43)},
+            TokVariable {location: (1, 53), name: "$rope".to_string()},
+            TokCompOp {location: (1, 59), op_name: "gt".to_string()},
+            TokInt {location: (1, 62), value: 0},
+            TokPreconditionTarget {location: (1, 64), passage_name: "Fall".to_string()},
+            TokTagEnd {location: (1, 71)},
+            TokText {location: (2, 1), text: "Content".to_string()}
+        );
+
+        assert_tok_eq(expected, tokens);
+    }
+
     #[test]
     fn macro_set_test() {
         // This should return a passage with a set macro
@@ -547,6 +1228,32 @@ mod tests {
         assert_tok_eq(expected, tokens);
     }
 
+    #[test]
+    fn map_test() {
+        let tokens = test_lex("::Passage\n<<set $m = {\"hp\": 10, \"s\": {}}>><<print $m[ 'hp' ]>>");
+        let expected = vec!(
+            TokPassage {name: "Passage".to_string(), location: (1, 3)},
+            TokMacroSet {location: (2, 3)},
+            TokAssign {location: (2, 7), var_name: "$m".to_string(), op_name: "=".to_string()},
+            TokMapLiteral {location: (2, 12)},
+            TokString {location: (2, 13), value: "hp".to_string()},
+            TokTernaryElse {location: (2, 17)},
+            TokInt {location: (2, 19), value: 10},
+            TokColon {location: (2, 21)},
+            TokString {location: (2, 23), value: "s".to_string()},
+            TokTernaryElse {location: (2, 26)},
+            TokMapLiteral {location: (2, 28)},
+            TokMapLiteralEnd {location: (2, 29)},
+            TokMapLiteralEnd {location: (2, 30)},
+            TokMacroEnd {location: (2, 31)},
+            TokMacroPrint {location: (2, 35)},
+            TokMapAccess {location: (2, 41), name: "$m".to_string(), key: "hp".to_string()},
+            TokMacroEnd {location: (2, 51)}
+        );
+
+        assert_tok_eq(expected, tokens);
+    }
+
     #[test]
     fn macro_if_test() {
         // This should return a passage with an if macro
@@ -575,6 +1282,339 @@ mod tests {
         assert_tok_eq(expected, tokens);
     }
 
+    #[test]
+    fn passage_name_characters_test() {
+        let tokens = test_lex("::Shop $ Prices\n[[ Shop $ Prices ]][[To the <<left>>|Left <<side>> ]]\n\n::Left <<side>>\n");
+        let names: Vec<String> = tokens.iter().filter_map(|token| match token {
+            &TokPassage{ref name, ..} => Some(name.clone()),
+            &TokPassageLink{ref passage_name, ..} => Some(passage_name.clone()),
+            _ => None
+        }).collect();
+        assert_eq!(names, vec!["Shop $ Prices", "Shop $ Prices", "Left <<side>>", "Left <<side>>"]);
+    }
+
+    #[test]
+    fn unterminated_link_test() {
+        let tokens = test_lex("::Start\n[[Unterminated\ntitle]]\n");
+        assert!(tokens.iter().any(|token| match token {
+            &TokError{ref message, location, ..} => location == (2, 1) && message.starts_with("Unterminated link"),
+            _ => false
+        }), "{:?}", tokens);
+    }
+
+    /// Returns the errors of lexing and parsing the input with `--force`.
+    fn test_parse_force(input: &str) -> Vec<ErrorRecord> {
+        let mut cfg = Config::default_config();
+        cfg.force = true;
+        let mut cursor: Cursor<Vec<u8>> = Cursor::new(input.to_string().into_bytes());
+        ::frontend::parser::Parser::new(cfg.clone()).parse(lex(cfg.clone(), &mut cursor)).count();
+        cfg.reported_errors()
+    }
+
+    /// Returns the message of the only `TokError` in the tokens.
+    fn token_error(tokens: &[Token]) -> (String, (u64, u64)) {
+        let errors: Vec<(String, (u64, u64))> = tokens.iter().filter_map(|token| match token {
+            &TokError{ref message, location, ..} => Some((message.clone(), location)),
+            _ => None
+        }).collect();
+        assert_eq!(errors.len(), 1, "{:?}", tokens);
+        errors[0].clone()
+    }
+
+    fn test_lex_force(input: &str) -> (Vec<Token>, Vec<ErrorRecord>) {
+        let mut cfg = Config::default_config();
+        cfg.force = true;
+        let mut cursor: Cursor<Vec<u8>> = Cursor::new(input.to_string().into_bytes());
+        let tokens = lex(cfg.clone(), &mut cursor).collect();
+        (tokens, cfg.reported_errors())
+    }
+
+    #[test]
+    fn unexpected_character_in_macro_test() {
+        // the rest of the broken macro is not reported again
+        let (tokens, errors) = test_lex_force("::Start\n<<$gold > coins\nText");
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].location, Some((2, 9)));
+        assert_eq!(errors[0].message, "Unexpected character '>' in a macro at 2:9");
+        assert_tok_eq(vec!(
+            TokPassage {name: "Start".to_string(), location: (1, 3)},
+            TokMacroContentVar {var_name: "$gold".to_string(), location: (2, 3)},
+        ), tokens);
+    }
+
+    #[test]
+    fn passage_separator_in_passage_name_test() {
+        // the lexer passes the invalid token on, the parser reports it
+        let (tokens, errors) = test_lex_force("::Start :: Room\nText");
+        assert!(errors.is_empty());
+        assert_eq!(token_error(&tokens), ("Invalid passage name \"Start :: Room\", passage names cannot contain '|', ']' or '::'".to_string(), (1, 3)));
+        assert_tok_eq(vec!(
+            TokText {text: "Text".to_string(), location: (2, 1)},
+        ), tokens[1..].to_vec());
+
+        let errors = test_parse_force("::Start :: Room\nText");
+        assert_eq!(errors[0].location, Some((1, 3)));
+        assert_eq!(errors[0].code, "LexerError::TokError");
+        assert_eq!(errors[0].message, "Invalid passage name \"Start :: Room\", passage names cannot contain '|', ']' or '::' at 1:3");
+    }
+
+    #[test]
+    fn passage_separator_in_passage_content_test() {
+        // only "::" at the start of a line starts a passage
+        let (tokens, errors) = test_lex_force("::Start\nLeft :: right\n::End\nText");
+        assert!(errors.is_empty());
+        assert_tok_eq(vec!(
+            TokPassage {name: "Start".to_string(), location: (1, 3)},
+            TokText {text: "Left :: right".to_string(), location: (2, 1)},
+            TokNewLine {location: (2, 14)},
+            TokPassage {name: "End".to_string(), location: (3, 3)},
+            TokText {text: "Text".to_string(), location: (4, 1)},
+        ), tokens);
+        assert!(test_parse_force("::Start\nLeft :: right\n::End\nText").is_empty());
+    }
+
+    #[test]
+    fn unterminated_string_test() {
+        let (tokens, errors) = test_lex_force("::Start\n<<print \"gold>>\nText");
+        assert!(errors.is_empty());
+        assert_eq!(token_error(&tokens), ("Unterminated string, the \" is not closed before the end of the input".to_string(), (2, 9)));
+
+        let errors = test_parse_force("::Start\n<<print \"gold>>\nText");
+        assert_eq!(errors[0].location, Some((2, 9)));
+        assert_eq!(errors[0].message, "Unterminated string, the \" is not closed before the end of the input at 2:9");
+    }
+
+    #[test]
+    fn number_too_large_test() {
+        let (tokens, errors) = test_lex_force("::Start\n<<print 99999999999>>");
+        assert!(errors.is_empty());
+        assert_eq!(token_error(&tokens), ("The number 99999999999 is too large, numbers have to be at most 2147483647".to_string(), (2, 9)));
+        assert_tok_eq(vec!(
+            TokPassage {name: "Start".to_string(), location: (1, 3)},
+            TokMacroPrint {location: (2, 3)},
+            TokMacroEnd {location: (2, 20)},
+        ), tokens.into_iter().filter(|token| match token { &TokError{..} => false, _ => true }).collect());
+
+        // the parser skips the number, so the print has no expression left
+        let errors = test_parse_force("::Start\n<<print 99999999999>>");
+        assert_eq!(errors[0].location, Some((2, 9)));
+        assert_eq!(errors[0].code, "LexerError::TokError");
+        assert_eq!(errors[0].message, "The number 99999999999 is too large, numbers have to be at most 2147483647 at 2:9");
+        assert!(errors[1..].iter().all(|error| error.code.starts_with("ParserError::")), "{:?}", errors);
+    }
+
+    #[test]
+    fn display_column_test() {
+        assert_eq!(display_column(&[], 5, 8), 5);
+        assert_eq!(display_column(&[1], 2, 8), 9);
+        assert_eq!(display_column(&[1, 2], 3, 8), 17);
+        assert_eq!(display_column(&[3], 4, 8), 9);
+        assert_eq!(display_column(&[3], 2, 8), 2);
+        assert_eq!(display_column(&[1], 2, 4), 5);
+    }
+
+    #[test]
+    fn tab_width_test() {
+        fn expression_columns(tab_width: u32) -> Vec<(u64, u64)> {
+            let mut cfg = Config::default_config();
+            cfg.tab_width = tab_width;
+            let mut cursor: Cursor<Vec<u8>> = Cursor::new("::Start\n\t<<print 1 +>>\n\t\tx\t<<print 2 +>>".to_string().into_bytes());
+            lex(cfg, &mut cursor).filter(|token| match token {
+                &TokInt{..} | &TokNumOp{..} => true,
+                _ => false
+            }).map(|token| token.location()).collect()
+        }
+
+        let single = expression_columns(1);
+        let expanded = expression_columns(8);
+        assert_eq!(single.len(), 4);
+
+        // one tab in front of the first expression, two tabs, x and a tab before the second one
+        assert_eq!(expanded[0], (single[0].0, single[0].1 + 7));
+        assert_eq!(expanded[1], (single[1].0, single[1].1 + 7));
+        assert_eq!(expanded[2], (single[2].0, single[2].1 + 20));
+        assert_eq!(expanded[3], (single[3].0, single[3].1 + 20));
+    }
+
+    #[test]
+    fn character_column_test() {
+        for &(ref tabs, tab_width) in [(vec![], 8), (vec![1], 8), (vec![1, 2], 8), (vec![3], 8), (vec![1, 5], 4)].iter() {
+            for column in 1..12 {
+                assert_eq!(character_column(tabs, display_column(tabs, column, tab_width), tab_width), column);
+            }
+        }
+        // inside of the tab
+        assert_eq!(character_column(&[1], 4, 8), 1);
+    }
+
+    #[test]
+    fn byte_offset_test() {
+        let source = "::Start\näöü € <<print 1 +>>\n\t\"ß\" <<print 2>>";
+        let mut cursor: Cursor<Vec<u8>> = Cursor::new(source.to_string().into_bytes());
+        let mut cfg = Config::default_config();
+        cfg.tab_width = 4;
+        let tokens: Vec<Token> = lex(cfg, &mut cursor).filter(|token| match token {
+            &TokInt{..} | &TokNumOp{..} => true,
+            _ => false
+        }).collect();
+        let map = SourceMap::from_source(source.as_bytes(), 4);
+
+        // the columns count characters, the offsets count bytes
+        let one = map.location(tokens[0].location());
+        assert_eq!((one.line, one.column), (2, 15));
+        assert_eq!(one.byte_offset as usize, source.find("1").unwrap());
+        let plus = map.location(tokens[1].location());
+        assert_eq!(plus.byte_offset as usize, source.find("+").unwrap());
+        let two = map.location(tokens[2].location());
+        assert_eq!((two.line, two.column), (3, 17));
+        assert_eq!(two.byte_offset as usize, source.find("2").unwrap());
+
+        // the Byte Order Mark is counted
+        let mut with_bom = vec![0xef, 0xbb, 0xbf];
+        with_bom.extend(source.bytes());
+        let map = SourceMap::from_source(&with_bom, 4);
+        assert_eq!(map.location(tokens[0].location()).byte_offset as usize, source.find("1").unwrap() + 3);
+        assert_eq!(map.location((1, 1)).byte_offset, 3);
+        assert_eq!(map.location((9, 1)).byte_offset as usize, with_bom.len());
+    }
+
+    #[test]
+    fn macro_toc_test() {
+        let tokens = test_lex("::Passage\nChapters:<<toc>>");
+        let expected = vec!(
+            TokPassage {name: "Passage".to_string(), location: (1, 3)},
+            TokText {location: (2, 1), text: "Chapters:".to_string()},
+            TokMacroToc {location: (2, 12)}
+        );
+
+        assert_tok_eq(expected, tokens);
+    }
+
+    #[test]
+    fn macro_savegame_loadgame_test() {
+        // the built-in handlers make them plugin macros
+        let tokens = test_lex("::Passage\n<<savegame>><<loadgame>>");
+        let expected = vec!(
+            TokPassage {name: "Passage".to_string(), location: (1, 3)},
+            TokMacroPlugin {location: (2, 3), name: "savegame".to_string()},
+            TokMacroEnd {location: (2, 11)},
+            TokMacroPlugin {location: (2, 15), name: "loadgame".to_string()},
+            TokMacroEnd {location: (2, 23)}
+        );
+
+        assert_tok_eq(expected, tokens);
+    }
+
+    #[test]
+    fn macro_plugin_test() {
+        let mut cfg = Config::default_config();
+        cfg.macros.register("sound", |_| vec![]).unwrap();
+        let mut cursor: Cursor<Vec<u8>> = Cursor::new("::Passage\n<<sound 1, $x>>".to_string().into_bytes());
+        let tokens = lex(cfg, &mut cursor).collect();
+        let expected = vec!(
+            TokPassage {name: "Passage".to_string(), location: (1, 3)},
+            TokMacroPlugin {location: (2, 3), name: "sound".to_string()},
+            TokInt {location: (2, 9), value: 1},
+            TokColon {location: (2, 10)},
+            TokVariable {location: (2, 12), name: "$x".to_string()},
+            TokMacroEnd {location: (2, 14)}
+        );
+
+        assert_tok_eq(expected, tokens);
+    }
+
+    #[test]
+    fn macro_countdown_test() {
+        let tokens = test_lex("::Passage\n<<countdown 30s \"End\">>");
+        let expected = vec!(
+            TokPassage {name: "Passage".to_string(), location: (1, 3)},
+            TokMacroCountdown {location: (2, 3)},
+            TokInt {location: (2, 13), value: 30},
+            TokString {location: (2, 17), value: "End".to_string()},
+            TokMacroEnd {location: (2, 22)}
+        );
+
+        assert_tok_eq(expected, tokens);
+    }
+
+    #[test]
+    fn seconds_outside_countdown_test() {
+        // only <<countdown>> knows seconds, elsewhere the s is no part of the number
+        let (tokens, errors) = test_lex_force("::Passage\n<<set $x to 5s>>");
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].location, Some((2, 14)));
+        assert!(errors[0].message.starts_with("Unexpected character 's'"), "{}", errors[0].message);
+        assert!(tokens.contains(&TokInt {location: (2, 13), value: 5}), "{:?}", tokens);
+    }
+
+    #[test]
+    fn macro_clear_test() {
+        let tokens = test_lex("::Passage\n<<clear $deck>>");
+        let expected = vec!(
+            TokPassage {name: "Passage".to_string(), location: (1, 3)},
+            TokMacroClear {location: (2, 3)},
+            TokVariable {location: (2, 9), name: "$deck".to_string()},
+            TokMacroEnd {location: (2, 14)}
+        );
+
+        assert_tok_eq(expected, tokens);
+    }
+
+    #[test]
+    fn dialect_test() {
+        // SugarCube closes macros with a slash and swallows the line break after them
+        let mut cfg = Config::default_config();
+        cfg.dialect = Dialect::SugarCube;
+        let mut cursor: Cursor<Vec<u8>> = Cursor::new("::Passage\n<<if true>>a<</if>>\nb".to_string().into_bytes());
+        let tokens = lex(cfg, &mut cursor).collect();
+        let expected = vec!(
+            TokPassage {name: "Passage".to_string(), location: (1, 3)},
+            TokMacroIf {location: (2, 3)},
+            TokBoolean {location: (2, 6), value: "true".to_string()},
+            TokMacroEnd {location: (2, 10)},
+            TokText {location: (2, 12), text: "a".to_string()},
+            TokMacroEndIf {location: (2, 15)},
+            TokMacroEnd {location: (2, 18)},
+            TokText {location: (3, 1), text: "b".to_string()}
+        );
+
+        assert_tok_eq(expected, tokens);
+        assert_eq!(dialect_rules(Dialect::SugarCube).macro_name("=".to_string()), "print");
+        assert_eq!(dialect_rules(Dialect::Legacy).macro_name("/if".to_string()), "/if");
+    }
+
+    #[test]
+    fn print_alias_without_space_test() {
+        let mut cfg = Config::default_config();
+        cfg.dialect = Dialect::SugarCube;
+        let mut cursor: Cursor<Vec<u8>> = Cursor::new("::Passage\n<<=$x>><<= 1>>".to_string().into_bytes());
+        let tokens = lex(cfg, &mut cursor).collect();
+        let expected = vec!(
+            TokPassage {name: "Passage".to_string(), location: (1, 3)},
+            TokMacroPrint {location: (2, 3)},
+            TokVariable {location: (2, 4), name: "$x".to_string()},
+            TokMacroEnd {location: (2, 6)},
+            TokMacroPrint {location: (2, 10)},
+            TokInt {location: (2, 12), value: 1},
+            TokMacroEnd {location: (2, 13)}
+        );
+
+        assert_tok_eq(expected, tokens);
+    }
+
+    #[test]
+    fn macro_br_test() {
+        let tokens = test_lex("::Passage\n<<br>>a<html><br/></html>");
+        let expected = vec!(
+            TokPassage {name: "Passage".to_string(), location: (1, 3)},
+            TokMacroBr {location: (2, 3)},
+            TokText {location: (2, 7), text: "a".to_string()},
+            TokMacroBr {location: (2, 14)}
+        );
+
+        assert_tok_eq(expected, tokens);
+    }
+
     #[test]
     fn macro_print_test() {
         let tokens = test_lex("::Passage\n<<print \"Test with escaped \\\"Quotes\">>\n<<print $var>>");
@@ -771,6 +1811,25 @@ mod tests {
         assert_tok_eq(expected, tokens);
     }
 
+    #[test]
+    fn story_script_test() {
+        // the content of the script and stylesheet passages of Twine exports is not twee
+        let tokens = test_lex("::StoryScript\nif (a << b) { x = [[1]]; }\n::StoryStylesheet\np::before { content: \"<<\"; }\n\
+            ::Style [stylesheet]\n::Start\nHello");
+        let expected = vec![
+            TokPassage { location: (1, 3), name: "StoryScript".to_string() },
+            TokPassage { location: (3, 3), name: "StoryStylesheet".to_string() },
+            TokPassage { location: (5, 3), name: "Style".to_string() },
+            TokTagStart { location: (5, 9) },
+            TokTag { location: (5, 10), tag_name: "stylesheet".to_string() },
+            TokTagEnd { location: (5, 20) },
+            TokPassage { location: (6, 3), name: "Start".to_string() },
+            TokText { location: (7, 1), text: "Hello".to_string() },
+        ];
+
+        assert_tok_eq(expected, tokens);
+    }
+
     #[test]
     fn escape_line_break_test() {
         let tokens = test_lex("::Start\nTest\\\n<<if true>>\\\nLi\\ne\n<<else>>\nBla\\\n<<endif>>\n\\\nTest");
@@ -794,4 +1853,42 @@ mod tests {
 
         assert_tok_eq(expected, tokens);
     }
+
+    #[test]
+    fn macros_test() {
+        // every listed macro is built in and not a short display of a passage with its name
+        for name in MACROS.iter() {
+            let tokens = test_lex(&format!("::Start\n<<{} \"x\">>", name));
+            assert!(!tokens.iter().any(|token| match token {
+                &TokMacroDisplay{ref passage_name, ..} => passage_name == name,
+                _ => false
+            }), "<<{}>> is not a built-in macro", name);
+        }
+
+        let tokens = test_lex("::Start\n<<timed 2>>");
+        assert_eq!(tokens[1], TokMacroDisplay { location: (2, 3), passage_name: "timed".to_string() });
+    }
+
+    #[test]
+    fn normalize_passage_name_test() {
+        // "Café" and "Crème" written with combining accents
+        assert_eq!(normalize_passage_name(" Cafe\u{301} "), "Caf\u{e9}");
+        assert_eq!(normalize_passage_name("Cre\u{300}me"), "Cr\u{e8}me");
+        assert_eq!(normalize_passage_name("Caf\u{e9}"), "Caf\u{e9}");
+
+        // the accent stays if there is no precomposed letter in Latin-1
+        assert_eq!(normalize_passage_name("O\u{304}saka"), "O\u{304}saka");
+        assert!(differently_normalized("O\u{304}saka", "\u{14c}saka"));
+        assert!(!differently_normalized("\u{14c}saka", "Osaka"));
+        assert!(!differently_normalized("O\u{304}saka", "O\u{304}saka"));
+        assert!(!differently_normalized("O\u{304}saka", "Ukraine"));
+
+        let tokens = test_lex("::Cafe\u{301}\n[[Caf\u{e9}]][[Cafe\u{301}]]");
+        let names: Vec<String> = tokens.iter().filter_map(|token| match token {
+            &TokPassage{ref name, ..} => Some(name.clone()),
+            &TokPassageLink{ref passage_name, ..} => Some(passage_name.clone()),
+            _ => None
+        }).collect();
+        assert_eq!(names, vec!["Caf\u{e9}", "Caf\u{e9}", "Caf\u{e9}"]);
+    }
 }
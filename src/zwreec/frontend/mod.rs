@@ -17,12 +17,15 @@
 //! ```
 //! # extern crate zwreec;
 //! use std::io::Cursor;
+//! use zwreec::frontend::screener::ScreenerError;
 //!
+//! # fn main() { parse().unwrap() }
+//! fn parse() -> Result<(), ScreenerError> {
 //! let cfg = zwreec::config::Config::default_config();
 //! let mut twee = Cursor::new("::Start\nHello World".to_string().into_bytes());
 //!
-//! // Clean Input
-//! let mut cursor = zwreec::frontend::screener::handle_bom_encoding(&mut twee);
+//! // Clean Input, reading the input may fail
+//! let mut cursor = try!(zwreec::frontend::screener::handle_bom_encoding(&mut twee));
 //!
 //! // Generate Token Stream
 //! let tokens = zwreec::frontend::lexer::lex(cfg.clone(), &mut cursor);
@@ -30,11 +33,18 @@
 //! // Parse Tokens
 //! let p = zwreec::frontend::parser::Parser::new(cfg.clone());
 //! let ast: Vec<zwreec::frontend::ast::ASTNode> = zwreec::frontend::ast::ASTBuilder::build(cfg, p.parse(tokens)).collect();
+//! # assert_eq!(ast.len(), 1);
+//! Ok(())
+//! }
 //! ```
 
 pub mod ast;
+pub mod compat;
 pub mod evaluate_expression;
+pub mod export;
 pub mod expressionparser;
 pub mod lexer;
 pub mod parser;
+pub mod plugins;
 pub mod screener;
+pub mod widgets;
@@ -45,6 +45,15 @@ pub enum ParserError {
 
     /// Ending at a non-terminal
     NonTerminalEnd { stack: NonTerminalType },
+
+    /// An `<<else>>`, `<<else if>>` or `<<endif>>` without an open `<<if>>`
+    UnexpectedConditional { token: Token },
+
+    /// An assignment of a `<<set>>` without a value, `token` follows the assignment
+    MissingAssignedValue { token: Token },
+
+    /// The parenthesis opened at `open` is not closed in front of `token`
+    UnclosedParenthesis { open: (u64, u64), token: Token },
 }
 
 /// The Type of nonterminal encountered by the parser.
@@ -73,6 +82,8 @@ pub enum NonTerminalType {
     MonoContent,
     Link,
     Linkf,
+    Otherwisef,
+    Countdownf,
     Macro,
     ElseIf,
     EndIf,
@@ -86,6 +97,8 @@ pub enum NonTerminalType {
     /// Start of the expression definition
     E,
     E2,
+    TernaryThen,
+    TernaryElse,
     T,
     T2,
     B,
@@ -97,6 +110,21 @@ pub enum NonTerminalType {
     H,
     DataType,
     AssignVariable,
+    /// The assignments of a `<<set>>`
+    Assignments,
+    Assignmentsf,
+    AssignValue,
+    AssignEnd,
+    /// A map literal, which is only allowed as the value of an assignment or of another map
+    MapExpression,
+    MapExpressionf,
+    MapLiteral,
+    MapEntries,
+    MapEntriesf,
+    MapValue,
+    /// The arguments of a plugin macro
+    PluginArgs,
+    PluginArgsf,
 }
 
 /// The Type that represents an element of the grammar.
@@ -163,11 +191,20 @@ impl Parser {
                 /// the predictive stack ll(1) parsing routine.
                 fn parse(state: &mut ParseState, token: Option<Token>) -> (ParseResult, Option<ASTOperation>) {
                     match token {
+                        Some(token @ TokError { .. }) => {
+                            error_panic!(state.cfg => token);
+                            // skip the invalid token, the stack still expects what was expected in front of it
+                            (ParseResult::Continue, None)
+                        },
                         Some(token) => match state.stack.pop() {
                             Some(Elem::NonTerminal(non_terminal)) => (ParseResult::Halt, (state.grammar_func)(&state.cfg, non_terminal, Some(token), &mut state.stack)),
                             Some(Elem::Terminal(stack_token)) => {
                                 if stack_token.is_same_token(&token) {
                                     (ParseResult::Continue, None)
+                                } else if let TokParenClose { location } = stack_token {
+                                    error_panic!(state.cfg => ParserError::UnclosedParenthesis{open: location, token: token});
+                                    // continue as if the parenthesis was closed in front of the token
+                                    (ParseResult::Halt, None)
                                 } else {
                                     error_panic!(state.cfg => ParserError::TokenDoNotMatch{token: Some(token), stack: stack_token.clone()});
                                     state.stack.push(Elem::Terminal(stack_token));
@@ -197,6 +234,18 @@ impl Parser {
         )
     }
 
+    /// Checks whether the stack belongs to an open if-construct.
+    ///
+    /// Every `<<if>>` leaves an `EndIf` non-terminal on the stack, which is replaced by the
+    /// expected `<<endif>>` terminal once its `<<else>>` has been read.
+    fn inside_if(stack: &Vec<Elem>) -> bool {
+        stack.iter().any(|elem| match elem {
+            &NonTerminal(EndIf) => true,
+            &Terminal(TokMacroEndIf { .. }) => true,
+            _ => false,
+        })
+    }
+
     /// Apply the LL(1) grammar.
     ///
     /// The match-statement simulates the parsing-table behavior.
@@ -250,13 +299,22 @@ impl Parser {
                 // Tags
                 (Tags, tok @ TokTag { .. } ) => {
                     stack.push(NonTerminal(Tagsf));
-                    stack.push(Terminal(tok));
+                    stack.push(Terminal(tok.clone()));
 
-                    None
+                    Some(AddChild(tok))
+                },
+                (Tags, tok @ TokPrecondition { .. } ) => {
+                    // requires: condition -> Target, the target becomes the last child
+                    stack.push(NonTerminal(Tagsf));
+                    stack.push(NonTerminal(ExpressionList));
+                    stack.push(Terminal(tok.clone()));
+
+                    Some(ChildDown(tok))
                 },
 
                 // tagsf
-                (Tagsf, TokTag { .. } ) => {
+                (Tagsf, TokTag { .. } ) |
+                (Tagsf, TokPrecondition { .. } ) => {
                     stack.push(NonTerminal(Tags));
 
                     None
@@ -305,7 +363,9 @@ impl Parser {
 
                     Some(AddChild(tok))
                 },
-                (PassageContent, tok @ TokNewLine { .. }) => {
+                (PassageContent, tok @ TokNewLine { .. }) |
+                (PassageContent, tok @ TokMacroBr { .. }) |
+                (PassageContent, tok @ TokMacroToc { .. }) => {
                     stack.push(NonTerminal(PassageContent));
                     stack.push(Terminal(tok.clone()));
 
@@ -315,17 +375,38 @@ impl Parser {
                 (PassageContent, TokMacroSet        { .. } ) |
                 (PassageContent, TokMacroIf         { .. } ) |
                 (PassageContent, TokMacroPrint      { .. } ) |
+                (PassageContent, TokMacroConfirm    { .. } ) |
+                (PassageContent, TokMacroClear      { .. } ) |
+                (PassageContent, TokMacroPlugin     { .. } ) |
+                (PassageContent, TokMacroWidget     { .. } ) |
+                (PassageContent, TokMacroWidgetCall { .. } ) |
                 (PassageContent, TokVariable        { .. } ) |
                 (PassageContent, TokArrayLength     { .. } ) |
                 (PassageContent, TokArrayAccess     { .. } ) |
                 (PassageContent, TokMacroSilently   { .. } ) |
                 (PassageContent, TokMacroNoBr   { .. } ) |
+                (PassageContent, TokMacroOnExit { .. } ) |
+                (PassageContent, TokMacroAt     { .. } ) |
+                (PassageContent, TokMacroCountdown { .. } ) |
+                (PassageContent, TokMacroAllow { .. } ) |
+                (PassageContent, TokMacroOtherwise { .. } ) |
                 (PassageContent, TokMacroContentVar { .. } ) => {
                     stack.push(NonTerminal(PassageContent));
                     stack.push(NonTerminal(Macro));
 
                     None
                 },
+                (PassageContent, ref tok @ TokMacroElse   { .. } ) |
+                (PassageContent, ref tok @ TokMacroElseIf { .. } ) |
+                (PassageContent, ref tok @ TokMacroEndIf  { .. } ) if !Parser::inside_if(stack) => {
+                    error_panic!(cfg => ParserError::UnexpectedConditional{token: tok.clone()});
+
+                    // skip the stray token
+                    stack.push(NonTerminal(PassageContent));
+                    stack.push(Terminal(tok.clone()));
+
+                    None
+                },
                 (PassageContent, tok @ TokMacroEndIf { .. } ) => {
                     debug!("pop TokMacroEndIf Passage;");
 
@@ -343,7 +424,10 @@ impl Parser {
                     Some(Up)
                 },
                 (PassageContent, tok @ TokMacroEndSilently { .. } ) |
-                (PassageContent, tok @ TokMacroEndNoBr     { .. } ) => {
+                (PassageContent, tok @ TokMacroEndNoBr     { .. } ) |
+                (PassageContent, tok @ TokMacroEndOnExit   { .. } ) |
+                (PassageContent, tok @ TokMacroEndAt       { .. } ) |
+                (PassageContent, tok @ TokMacroEndWidget   { .. } ) => {
                     Some(ChildUp(tok))
                 },
                 (PassageContent, _) => {
@@ -498,9 +582,15 @@ impl Parser {
 
                     Some(AddChild(tok))
                 },
+                (Macro, tok @ TokMacroAllow { .. } ) => {
+                    stack.push(Terminal(TokMacroEnd {location: (0, 0)} ));
+                    stack.push(Terminal(tok.clone()));
+
+                    Some(AddChild(tok))
+                },
                 (Macro, tok @ TokMacroSet { .. } ) => {
                     stack.push(Terminal(TokMacroEnd {location: (0, 0)} ));
-                    stack.push(NonTerminal(ExpressionList));
+                    stack.push(NonTerminal(Assignments));
                     stack.push(Terminal(tok));
 
                     None
@@ -515,13 +605,23 @@ impl Parser {
 
                     Some(ChildDown(tok))
                 },
-                (Macro, tok @ TokMacroPrint { .. } ) => {
+                (Macro, tok @ TokMacroPrint { .. } ) |
+                (Macro, tok @ TokMacroConfirm { .. } ) |
+                (Macro, tok @ TokMacroClear { .. } ) => {
                     stack.push(Terminal(TokMacroEnd {location: (0, 0)} ));
                     stack.push(NonTerminal(ExpressionList));
                     stack.push(Terminal(tok.clone()));
 
                     Some(ChildDown(tok))
                 }
+                (Macro, tok @ TokMacroPlugin { .. } ) |
+                (Macro, tok @ TokMacroWidgetCall { .. } ) => {
+                    stack.push(Terminal(TokMacroEnd {location: (0, 0)} ));
+                    stack.push(NonTerminal(PluginArgs));
+                    stack.push(Terminal(tok.clone()));
+
+                    Some(ChildDown(tok))
+                },
                 (Macro, tok @ TokMacroSilently { .. } ) => {
                     stack.push(Terminal(TokMacroEnd {location: (0, 0)} ));
                     stack.push(Terminal(TokMacroEndSilently {location: (0, 0)}));
@@ -540,6 +640,63 @@ impl Parser {
 
                     Some(ChildDown(tok))
                 }
+                (Macro, tok @ TokMacroAt { .. } ) => {
+                    // <<at row col>>: the end of the second expression keeps the content in the node
+                    stack.push(Terminal(TokMacroEnd {location: (0, 0)} ));
+                    stack.push(Terminal(TokMacroEndAt {location: (0, 0)}));
+                    stack.push(NonTerminal(PassageContent));
+                    stack.push(Terminal(TokMacroEnd {location: (0, 0)} ));
+                    stack.push(NonTerminal(ExpressionList));
+                    stack.push(NonTerminal(Expression));
+                    stack.push(Terminal(tok.clone()));
+
+                    Some(ChildDown(tok))
+                }
+                (Macro, tok @ TokMacroCountdown { .. } ) => {
+                    // <<countdown seconds "passage">> with an optional passage to cancel to
+                    stack.push(Terminal(TokMacroEnd {location: (0, 0)} ));
+                    stack.push(NonTerminal(Countdownf));
+                    stack.push(NonTerminal(Expression));
+                    stack.push(NonTerminal(Expression));
+                    stack.push(Terminal(tok.clone()));
+
+                    Some(ChildDown(tok))
+                }
+                (Macro, tok @ TokMacroOnExit { .. } ) => {
+                    stack.push(Terminal(TokMacroEnd {location: (0, 0)} ));
+                    stack.push(Terminal(TokMacroEndOnExit {location: (0, 0)}));
+                    stack.push(NonTerminal(PassageContent));
+                    stack.push(Terminal(TokMacroEnd {location: (0, 0)} ));
+                    stack.push(Terminal(tok.clone()));
+
+                    Some(ChildDown(tok))
+                }
+                (Macro, tok @ TokMacroWidget { .. } ) => {
+                    // <<widget "name">>: the body is expanded in place of the calls, see frontend::widgets
+                    stack.push(Terminal(TokMacroEnd {location: (0, 0)} ));
+                    stack.push(Terminal(TokMacroEndWidget {location: (0, 0)}));
+                    stack.push(NonTerminal(PassageContent));
+                    stack.push(Terminal(TokMacroEnd {location: (0, 0)} ));
+                    stack.push(Terminal(tok.clone()));
+
+                    Some(ChildDown(tok))
+                }
+
+                (Macro, tok @ TokMacroOtherwise { .. } ) => {
+                    // <<otherwise [[text|Target]]>>: the link is the only child
+                    stack.push(NonTerminal(Otherwisef));
+                    stack.push(NonTerminal(Link));
+                    stack.push(Terminal(tok.clone()));
+
+                    Some(ChildDown(tok))
+                }
+
+                // Otherwisef
+                (Otherwisef, tok @ TokMacroEnd { .. } ) => {
+                    stack.push(Terminal(tok.clone()));
+
+                    Some(Up)
+                }
 
                 // means <<$var>>
                 (Macro, tok @ TokMacroContentVar { .. }) => {
@@ -581,10 +738,21 @@ impl Parser {
                     None
                 },
 
+                // Countdownf
+                (Countdownf, TokMacroEnd { .. } ) => {
+                    Some(UpSpecial)
+                },
+                (Countdownf, _ ) => {
+                    stack.push(NonTerminal(ExpressionList));
+
+                    None
+                },
+
                 // ExpressionList
                 (ExpressionList, TokVariable    { .. } ) |
                 (ExpressionList, TokArrayLength { .. } ) |
                 (ExpressionList, TokArrayAccess { .. } ) |
+                (ExpressionList, TokMapAccess { .. } ) |
                 (ExpressionList, TokInt         { .. } ) |
                 (ExpressionList, TokString      { .. } ) |
                 (ExpressionList, TokBoolean     { .. } ) |
@@ -625,6 +793,11 @@ impl Parser {
                     debug!("pop ExpressionListf -> TokVarSetEnd");
                     Some(TwoUp)
                 },
+                (ExpressionListf, tok @ TokPreconditionTarget { .. }) => {
+                    stack.push(Terminal(tok.clone()));
+
+                    Some(ChildUp(tok))
+                },
                 (ExpressionListf, _) => {
                     // ExpressionListf -> ε
                     debug!("pop ExpressionListf -> ε");
@@ -635,6 +808,7 @@ impl Parser {
                 (Expression, TokVariable { .. } ) |
                 (Expression, TokArrayLength { .. } ) |
                 (Expression, TokArrayAccess { .. } ) |
+                (Expression, TokMapAccess { .. } ) |
                 (Expression, TokInt      { .. } ) |
                 (Expression, TokString   { .. } ) |
                 (Expression, TokBoolean  { .. } ) |
@@ -670,6 +844,7 @@ impl Parser {
                 // E
                 (E, TokVariable { .. } ) |
                 (E, TokArrayAccess { .. } ) |
+                (E, TokMapAccess { .. } ) |
                 (E, TokArrayLength { .. } ) |
                 (E, TokInt      { .. } ) |
                 (E, TokString   { .. } ) |
@@ -712,15 +887,60 @@ impl Parser {
                     }
                     _ => None
                 },
+                (E2, TokTernaryIf { location }) => {
+                    stack.push(NonTerminal(E2));
+                    stack.push(NonTerminal(T));
+                    stack.push(NonTerminal(TernaryElse));
+                    stack.push(NonTerminal(TernaryThen));
+                    stack.push(NonTerminal(T));
+                    stack.push(Terminal(TokTernaryIf{location: location.clone()}));
+
+                    Some(AddChild(TokTernaryIf{location: location}))
+                },
                 (E2, _) => {
                     // E2 -> ε
                     debug!("pop E2 -> ε");
                     Some(Up)
                 },
 
+                // TernaryThen
+                // like E2, but the expression continues after the matching ':'
+                (TernaryThen, TokLogOp { location, op_name: op }) => match &*op {
+                    "or" | "||" => {
+                        stack.push(NonTerminal(TernaryThen));
+                        stack.push(NonTerminal(T));
+                        stack.push(Terminal(TokLogOp{location: location.clone(), op_name: op.clone()}));
+
+                        Some(AddChild(TokLogOp{location: location, op_name: op}))
+                    }
+                    _ => None
+                },
+                (TernaryThen, TokTernaryIf { location }) => {
+                    stack.push(NonTerminal(TernaryThen));
+                    stack.push(NonTerminal(T));
+                    stack.push(NonTerminal(TernaryElse));
+                    stack.push(NonTerminal(TernaryThen));
+                    stack.push(NonTerminal(T));
+                    stack.push(Terminal(TokTernaryIf{location: location.clone()}));
+
+                    Some(AddChild(TokTernaryIf{location: location}))
+                },
+                (TernaryThen, _) => {
+                    // TernaryThen -> ε
+                    None
+                },
+
+                // TernaryElse
+                (TernaryElse, TokTernaryElse { location }) => {
+                    stack.push(Terminal(TokTernaryElse{location: location.clone()}));
+
+                    Some(AddChild(TokTernaryElse{location: location}))
+                },
+
                 // T
                 (T, TokVariable { .. } ) |
                 (T, TokArrayAccess { .. } ) |
+                (T, TokMapAccess { .. } ) |
                 (T, TokArrayLength { .. } ) |
                 (T, TokInt      { .. } ) |
                 (T, TokString   { .. } ) |
@@ -770,6 +990,7 @@ impl Parser {
                 // B
                 (B, TokVariable { .. } ) |
                 (B, TokArrayAccess { .. } ) |
+                (B, TokMapAccess { .. } ) |
                 (B, TokArrayLength { .. } ) |
                 (B, TokInt      { .. } ) |
                 (B, TokString   { .. } ) |
@@ -819,6 +1040,7 @@ impl Parser {
                 // F
                 (F, TokVariable { .. } ) |
                 (F, TokArrayAccess { .. } ) |
+                (F, TokMapAccess { .. } ) |
                 (F, TokArrayLength { .. } ) |
                 (F, TokInt      { .. } ) |
                 (F, TokString   { .. } ) |
@@ -868,6 +1090,7 @@ impl Parser {
                 // G
                 (G, TokVariable { .. } ) |
                 (G, TokArrayAccess { .. } ) |
+                (G, TokMapAccess { .. } ) |
                 (G, TokArrayLength { .. } ) |
                 (G, TokInt      { .. } ) |
                 (G, TokString   { .. } ) |
@@ -915,6 +1138,9 @@ impl Parser {
                 (G2, TokCompOp     { .. } ) |
                 (G2, TokArgsEnd    { .. } ) |
                 (G2, TokColon      { .. } ) |
+                (G2, TokTernaryIf  { .. } ) |
+                (G2, TokTernaryElse { .. } ) |
+                (G2, TokPreconditionTarget { .. } ) |
                 (G2, TokParenClose { .. } ) => {
                     // G2 -> ε
                     None
@@ -972,13 +1198,33 @@ impl Parser {
 
                     Some(AddChild(tok))
                 },
+                (H, tok @ TokMapAccess { .. } ) => {
+                    stack.push(Terminal(tok.clone()));
+
+                    Some(AddChild(tok))
+                },
                 (H, TokFunction { .. } ) => {
                     stack.push(NonTerminal(Function));
 
                     None
                 },
+                (H, TokVarSetEnd  { .. } ) |
+                (H, TokMacroEnd   { .. } ) |
+                (H, TokSemiColon  { .. } ) |
+                (H, TokCompOp     { .. } ) |
+                (H, TokArgsEnd    { .. } ) |
+                (H, TokColon      { .. } ) |
+                (H, TokTernaryIf  { .. } ) |
+                (H, TokTernaryElse { .. } ) |
+                (H, TokPreconditionTarget { .. } ) |
+                (H, TokParenClose { .. } ) => {
+                    // H -> ε, the operand behind the operator is missing, which the
+                    // ExpressionParser reports with the location of the operator
+                    None
+                },
                 (H, tok @ TokParenOpen { .. } ) => {
-                    stack.push(Terminal(TokParenClose{location: (0, 0)}));
+                    // the expected parenthesis keeps the location of the opening one for errors
+                    stack.push(Terminal(TokParenClose{location: tok.location()}));
                     stack.push(NonTerminal(Expression));
                     stack.push(Terminal(tok.clone()));
 
@@ -997,11 +1243,13 @@ impl Parser {
                 (Functionf, tok @ TokArgsEnd { .. } ) => {
                     stack.push(Terminal(tok));
 
-                    None
+                    // no arguments, leave the function node
+                    Some(Up)
                 },
                 (Functionf, TokVariable { .. } ) |
                 (Functionf, TokArrayLength { .. } ) |
                 (Functionf, TokArrayAccess { .. } ) |
+                (Functionf, TokMapAccess { .. } ) |
                 (Functionf, TokInt      { .. } ) |
                 (Functionf, TokString   { .. } ) |
                 (Functionf, TokBoolean  { .. } ) |
@@ -1015,6 +1263,7 @@ impl Parser {
                 // Arguments
                 (Arguments, TokVariable { .. } ) |
                 (Arguments, TokArrayAccess { .. } ) |
+                (Arguments, TokMapAccess { .. } ) |
                 (Arguments, TokArrayLength { .. } ) |
                 (Arguments, TokInt      { .. } ) |
                 (Arguments, TokString   { .. } ) |
@@ -1043,6 +1292,33 @@ impl Parser {
                     None
                 },
 
+                // PluginArgs
+                (PluginArgs, TokMacroEnd { .. } ) => {
+                    // a plugin macro without arguments
+                    Some(UpSpecial)
+                },
+                (PluginArgs, _) => {
+                    stack.push(NonTerminal(PluginArgsf));
+                    stack.push(NonTerminal(Expression));
+
+                    None
+                },
+
+                // PluginArgsf
+                (PluginArgsf, tok @ TokColon { .. } ) => {
+                    stack.push(NonTerminal(PluginArgs));
+                    stack.push(Terminal(tok));
+
+                    None
+                },
+                (PluginArgsf, TokMacroEnd { .. } ) => {
+                    Some(UpSpecial)
+                },
+                (PluginArgsf, _) => {
+                    // PluginArgsf -> ε
+                    None
+                },
+
                 // AssignVariable
                 (AssignVariable, tok @ TokAssign { .. } ) => {
                     stack.push(NonTerminal(E));
@@ -1051,6 +1327,131 @@ impl Parser {
                     Some(ChildDown(tok))
                 },
 
+                // Assignments
+                // Every assignment of the list becomes a node of its own, like the assignments
+                // of consecutive <<set>> macros.
+                (Assignments, tok @ TokAssign { .. } ) => {
+                    stack.push(NonTerminal(AssignValue));
+                    stack.push(Terminal(tok.clone()));
+
+                    Some(ChildDown(tok))
+                },
+                (Assignments, _) => {
+                    // a <<set>> of an expression without assignment
+                    stack.push(NonTerminal(ExpressionList));
+
+                    None
+                },
+
+                // Assignmentsf
+                (Assignmentsf, tok @ TokColon { .. } ) |
+                (Assignmentsf, tok @ TokSemiColon { .. } ) => {
+                    stack.push(NonTerminal(Assignments));
+                    stack.push(Terminal(tok));
+
+                    None
+                },
+                (Assignmentsf, _) => {
+                    // Assignmentsf -> ε
+                    None
+                },
+
+                // AssignValue
+                (AssignValue, tok @ TokColon { .. } ) |
+                (AssignValue, tok @ TokSemiColon { .. } ) |
+                (AssignValue, tok @ TokMacroEnd { .. } ) => {
+                    error_panic!(cfg => ParserError::MissingAssignedValue{token: tok});
+
+                    // drop the assignment and continue with the next one
+                    stack.push(NonTerminal(Assignmentsf));
+
+                    Some(UpDiscard)
+                },
+                (AssignValue, TokMapLiteral { .. } ) => {
+                    stack.push(NonTerminal(AssignEnd));
+                    stack.push(NonTerminal(MapExpression));
+
+                    None
+                },
+                (AssignValue, _) => {
+                    stack.push(NonTerminal(AssignEnd));
+                    stack.push(NonTerminal(E));
+
+                    None
+                },
+
+                // AssignEnd
+                (AssignEnd, _) => {
+                    stack.push(NonTerminal(Assignmentsf));
+
+                    Some(Up)
+                },
+
+                // MapExpression
+                // The map is wrapped into an expression node like the other values, the keys
+                // and the expressions of the values alternate in the childs of the map.
+                (MapExpression, TokMapLiteral { .. } ) => {
+                    stack.push(NonTerminal(MapExpressionf));
+                    stack.push(NonTerminal(MapLiteral));
+
+                    Some(ChildDown(TokExpression))
+                },
+
+                // MapExpressionf
+                (MapExpressionf, _) => {
+                    // leave the expression node
+                    Some(Up)
+                },
+
+                // MapLiteral
+                (MapLiteral, tok @ TokMapLiteral { .. } ) => {
+                    stack.push(NonTerminal(MapEntries));
+                    stack.push(Terminal(tok.clone()));
+
+                    Some(ChildDown(tok))
+                },
+
+                // MapEntries
+                (MapEntries, tok @ TokMapLiteralEnd { .. } ) => {
+                    // an empty map or a comma behind the last entry
+                    stack.push(Terminal(tok));
+
+                    Some(Up)
+                },
+                (MapEntries, tok @ TokString { .. } ) => {
+                    stack.push(NonTerminal(MapEntriesf));
+                    stack.push(NonTerminal(MapValue));
+                    stack.push(Terminal(TokTernaryElse {location: (0, 0)}));
+                    stack.push(Terminal(tok.clone()));
+
+                    Some(AddChild(tok))
+                },
+
+                // MapValue
+                (MapValue, TokMapLiteral { .. } ) => {
+                    stack.push(NonTerminal(MapExpression));
+
+                    None
+                },
+                (MapValue, _) => {
+                    stack.push(NonTerminal(E));
+
+                    None
+                },
+
+                // MapEntriesf
+                (MapEntriesf, tok @ TokColon { .. } ) => {
+                    stack.push(NonTerminal(MapEntries));
+                    stack.push(Terminal(tok));
+
+                    None
+                },
+                (MapEntriesf, tok @ TokMapLiteralEnd { .. } ) => {
+                    stack.push(Terminal(tok));
+
+                    Some(Up)
+                },
+
                 // DataType
                 (DataType, tok @ TokInt { .. } ) => {
                     stack.push(Terminal(tok.clone()));
@@ -0,0 +1,197 @@
+//! Macros added to the compiler by its users.
+//!
+//! A plugin macro is registered under its name in the `macros` of the
+//! [config](../../config/struct.Config.html). The lexer reads `<<name>>` and `<<name args>>` of a
+//! registered name as a plugin macro instead of a short `<<display>>`, the arguments are
+//! expressions separated by commas. The codegen evaluates the arguments and emits the Z-Code
+//! the handler returns for the call.
+//!
+//! The names of the built-in macros cannot be registered.
+//!
+//! The built-in macros without a body are registered through the same mechanism:
+//! `MacroRegistry::new()` adds the handlers of `<<savegame>>` and `<<loadgame>>`, which are
+//! lexed, parsed and compiled like a plugin macro. The other built-in macros like `<<print>>`,
+//! `<<set>>` and `<<if>>` still have their own tokens, the parser builds their AST with the rules
+//! of the grammar (an `<<if>>` has a body and `<<else>>` branches) and the codegen has a case for
+//! each of them, which a `MacroHandler` cannot describe.
+//!
+//! # Example
+//!
+//! ```
+//! # extern crate zwreec;
+//! use std::io::Cursor;
+//! use zwreec::backend::zcode::zfile::ZOP;
+//!
+//! let mut cfg = zwreec::config::Config::default_config();
+//! cfg.macros.register("beep", |_| vec![ZOP::Print{text: "Beep!".to_string()}]).unwrap();
+//!
+//! let mut input = Cursor::new("::Start\n<<beep>>".to_string().into_bytes());
+//! let passages = zwreec::compiled_ops(cfg, &mut input);
+//! assert!(passages[0].1.iter().any(|op| match op {
+//!     &ZOP::Print{ref text} => text == "Beep!",
+//!     _ => false
+//! }));
+//! ```
+
+use std::sync::Arc;
+
+use backend::zcode::zfile::{Operand, Variable, ZOP};
+use frontend::lexer::MACROS;
+
+/// A use of a plugin macro in a passage.
+pub struct MacroCall {
+    /// The name of the macro
+    pub name: String,
+
+    /// The location of the macro name
+    pub location: (u64, u64),
+
+    /// The evaluated arguments
+    pub args: Vec<Operand>,
+
+    /// True inside of `<<silently>>`, where the macro should not print anything
+    pub silent: bool,
+
+    /// A number that is unique for every call in the story, for the labels of the code
+    pub id: u32,
+
+    /// A local variable the code may use, it holds none of the arguments
+    pub temp: Variable,
+}
+
+/// Returns the Z-Code for a call of a plugin macro.
+pub type MacroHandler = Arc<Fn(&MacroCall) -> Vec<ZOP> + Send + Sync>;
+
+/// The errors of registering a plugin macro.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PluginError {
+    /// The name is the name of a built-in macro
+    BuiltinMacro { name: String },
+
+    /// A macro with the name is registered already
+    AlreadyRegistered { name: String },
+
+    /// The name is empty or contains characters that cannot be part of a macro name
+    InvalidName { name: String },
+}
+
+/// The macros with a handler of a compilation, the built-in ones and the plugin macros.
+#[derive(Clone)]
+pub struct MacroRegistry {
+    /// The name, the handler and whether the macro is built in
+    handlers: Vec<(String, MacroHandler, bool)>,
+}
+
+impl MacroRegistry {
+    /// Creates a registry with the handlers of the built-in macros and without plugin macros.
+    pub fn new() -> MacroRegistry {
+        let savegame_handler: MacroHandler = Arc::new(savegame);
+        let loadgame_handler: MacroHandler = Arc::new(loadgame);
+        MacroRegistry { handlers: vec![
+            ("savegame".to_string(), savegame_handler, true),
+            ("loadgame".to_string(), loadgame_handler, true),
+        ] }
+    }
+
+    /// Registers the handler for the macro with the name.
+    pub fn register<F>(&mut self, name: &str, handler: F) -> Result<(), PluginError>
+        where F: Fn(&MacroCall) -> Vec<ZOP> + Send + Sync + 'static {
+        if name.is_empty() || name.chars().any(|c| c == ' ' || c == '>' || c == '$' || c == '\n') {
+            return Err(PluginError::InvalidName { name: name.to_string() });
+        }
+        if MACROS.contains(&name) {
+            return Err(PluginError::BuiltinMacro { name: name.to_string() });
+        }
+        if self.contains(name) {
+            return Err(PluginError::AlreadyRegistered { name: name.to_string() });
+        }
+        self.handlers.push((name.to_string(), Arc::new(handler), false));
+        Ok(())
+    }
+
+    /// Checks if a macro with the name has a handler, a plugin macro or a built-in one.
+    pub fn contains(&self, name: &str) -> bool {
+        self.get(name).is_some()
+    }
+
+    /// Returns the handler of the macro with the name.
+    pub fn get(&self, name: &str) -> Option<MacroHandler> {
+        self.handlers.iter().find(|&&(ref registered, _, _)| registered == name).map(|&(_, ref handler, _)| handler.clone())
+    }
+
+    /// Returns the names of the registered plugin macros.
+    pub fn names(&self) -> Vec<&str> {
+        self.handlers.iter().filter(|&&(_, _, builtin)| !builtin).map(|&(ref name, _, _)| &name[..]).collect()
+    }
+}
+
+/// `<<savegame>>`: the interpreter asks for the file or slot, save stores 2 when a restore
+/// continues the game here.
+fn savegame(call: &MacroCall) -> Vec<ZOP> {
+    let failed_label = format!("savegame_failed{}", call.id);
+    let restored_label = format!("savegame_restored{}", call.id);
+    let end_label = format!("savegame_end{}", call.id);
+
+    let mut code: Vec<ZOP> = vec![ZOP::Save{result: call.temp.clone()}];
+    if !call.silent {
+        code.extend(vec![
+            ZOP::JE{operand1: Operand::new_var(call.temp.id), operand2: Operand::new_const(0), jump_to_label: failed_label.to_string()},
+            ZOP::JE{operand1: Operand::new_var(call.temp.id), operand2: Operand::new_const(2), jump_to_label: restored_label.to_string()},
+            ZOP::Print{text: "Saved.".to_string()},
+            ZOP::Jump{jump_to_label: end_label.to_string()},
+            ZOP::Label{name: failed_label},
+            ZOP::Print{text: "Save failed.".to_string()},
+            ZOP::Jump{jump_to_label: end_label.to_string()},
+            ZOP::Label{name: restored_label},
+            ZOP::Print{text: "Loaded.".to_string()},
+            ZOP::Label{name: end_label},
+        ]);
+    }
+    code
+}
+
+/// `<<loadgame>>`: restore only continues here if it failed, otherwise the game continues
+/// after the `<<savegame>>` that wrote the file.
+fn loadgame(call: &MacroCall) -> Vec<ZOP> {
+    let mut code: Vec<ZOP> = vec![ZOP::Restore{result: call.temp.clone()}];
+    if !call.silent {
+        code.push(ZOP::Print{text: "Load failed.".to_string()});
+    }
+    code
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MacroCall, MacroRegistry, PluginError};
+    use backend::zcode::zfile::{Variable, ZOP};
+
+    #[test]
+    fn test_register() {
+        let mut macros = MacroRegistry::new();
+        assert_eq!(macros.register("sound", |_| vec![]), Ok(()));
+        assert!(macros.contains("sound"));
+        assert_eq!(macros.names(), vec!["sound"]);
+
+        assert_eq!(macros.register("sound", |_| vec![]), Err(PluginError::AlreadyRegistered { name: "sound".to_string() }));
+        assert_eq!(macros.register("print", |_| vec![]), Err(PluginError::BuiltinMacro { name: "print".to_string() }));
+        assert_eq!(macros.register("two words", |_| vec![]), Err(PluginError::InvalidName { name: "two words".to_string() }));
+        assert!(!macros.contains("print"));
+        assert_eq!(macros.register("savegame", |_| vec![]), Err(PluginError::BuiltinMacro { name: "savegame".to_string() }));
+    }
+
+    #[test]
+    fn test_builtin_handlers() {
+        // the built-in handlers are registered, but are no plugin macros
+        let macros = MacroRegistry::new();
+        assert!(macros.contains("savegame") && macros.contains("loadgame"));
+        assert!(macros.names().is_empty());
+
+        let call = MacroCall { name: "savegame".to_string(), location: (1, 3), args: vec![], silent: true, id: 4, temp: Variable::new(15) };
+        let handler = macros.get("savegame").unwrap();
+        let code = handler(&call);
+        assert_eq!(code.len(), 1);
+        assert!(match code[0] { ZOP::Save{ref result} => result.id == 15, _ => false });
+        let call = MacroCall { silent: false, ..call };
+        assert!(handler(&call).iter().any(|op| match op { &ZOP::Label{ref name} => name == "savegame_failed4", _ => false }));
+    }
+}
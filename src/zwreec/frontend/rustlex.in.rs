@@ -4,16 +4,42 @@ rustlex! TweeLexer {
     //
     // Every unmatched character leads to a callback. Usually a callback causes a
     // lexer error, but in some specific cases there's no need for an error and
-    // callbacks are ignored.
+    // callbacks are ignored. A run of unmatched characters is reported once, at
+    // its first character, and so is the rest of a macro after its first error.
+    // Without a config the error can't be reported and stops the lexer, unless it
+    // is `silent`.
     callback => |lexer:&TweeLexer<R>, ch: char, location: (u64, u64)| {
         if !lexer.ignore_callback {
-            let cfg = lexer.cfg.clone();
-            error_panic!(&cfg.unwrap() => LexerError::UnexpectedCharacter { character: ch.clone(), location: location.clone() } );
+            let continues_run = match lexer.last_unexpected.get() {
+                Some((line, column)) => line == location.0 && column + 1 == location.1,
+                None => false
+            };
+            let macro_reported = lexer.open_macro.get().is_some() && lexer.open_macro.get() == lexer.reported_macro.get();
+            lexer.last_unexpected.set(Some(location));
+            if !continues_run && !macro_reported {
+                lexer.reported_macro.set(lexer.open_macro.get());
+                let construct = if lexer.open_macro.get().is_some() {
+                    Some("macro")
+                } else if lexer.in_link {
+                    Some("link")
+                } else if lexer.in_precondition {
+                    Some("precondition")
+                } else {
+                    None
+                };
+                let error = LexerError::UnexpectedCharacter { character: ch, location: location, construct: construct };
+                match lexer.cfg {
+                    Some(ref cfg) => error_panic!(cfg => error),
+                    None if lexer.silent => {},
+                    None => error_force_panic!(error)
+                }
+            }
         }
     }
 
     // Additional information to describe the lexer state.
     property cfg: Option<Config> = None;
+    property silent:bool = false;
     property ignore_callback:bool = true;
     property ignore_this_passage:bool = true;
     property format_bold_open:bool = false;
@@ -23,8 +49,15 @@ rustlex! TweeLexer {
     property format_sub_open:bool = false;
     property format_sup_open:bool = false;
     property in_link:bool = false;
+    property in_otherwise:bool = false;
+    property in_precondition:bool = false;
     property function_parens:usize = 0;
     property heading_rank:u8 = 0;
+    property open_comment:Rc<Cell<Option<(u64, u64)>>> = Rc::new(Cell::new(None));
+    property passage_name:RefCell<String> = RefCell::new(String::new());
+    property open_macro:Cell<Option<(u64, u64)>> = Cell::new(None);
+    property last_unexpected:Cell<Option<(u64, u64)>> = Cell::new(None);
+    property reported_macro:Cell<Option<(u64, u64)>> = Cell::new(None);
 
     // In the following regular expressions (regex) used by rustlex are listed.
     //
@@ -42,26 +75,36 @@ rustlex! TweeLexer {
     let LETTER = ['a'-'z''A'-'Z'];
     let HTTP = ("http"("s")?|"ftp")"://"[^"/$.?# "'\n''\t']+"."[^" "'\n''\t']+;
 
-    let COMMENT = "/%" ([^"%"]*(("%")*[^"%/"])?)* ("%")* "%/";
+    let COMMENT_START = "/%";
+    let COMMENT_END = "%/";
+    let COMMENT_TEXT = [^"%"'\n']+ | "%" | NEWLINE;
     let HTML_START = "<html" (" "[^">"]*)? ">";
     let HTML_END = "</html>";
     let HTML_DOCTYPE = "<!DOCTYPE" (" "[^">"]*)? ">";
     let HTML_TAGNAME = "a" | "abbr" | "acronym" | "address" | "applet" | "area" | "article" | "aside" | "audio" | "b" | "base" | "basefont" | "bdi" | "bdo" | "big" | "blockquote" | "body" | "br" | "button" | "canvas" | "caption" | "center" | "cite" |  "code" | "col" | "colgroup" | "datalist" | "dd" | "del" | "details" | "dfn" | "dialog" | "dir" | "div" | "dl" | "dt" | "em" | "embed" | "fieldset" | "figcaption" | "figure" | "font" | "footer" | "form" | "frame" | "frameset" | "h"("1"|"2"|"3"|"4"|"5"|"6") | "head" | "header" | "hr" | "i" | "iframe" | "img" | "input" | "ins" | "kbd" | "keygen" | "label" | "legend" | "li" | "link" | "main" | "map" | "mark" | "menu" | "menuitem" | "meta" | "meter" | "nav" | "noframes" | "noscript" | "object" | "ol" | "optgroup" | "option" | "output" | "p" | "param" | "pre" | "progress" | "q" | "rp" | "rt" | "ruby" | "s" | "samp" | "script" | "section" | "select" | "small" | "source" | "span" | "strike" | "strong" | "style" | "sub" | "summary" | "sup" | "table" | "tbody" | "td" | "textarea" | "tfoot" | "th" | "thead" | "time" | "title" | "tr" | "track" | "tt" | "u" | "ul" | "var" | "video" | "wbr";
     let HTML_TAG = "<" HTML_TAGNAME (" "[^">"]*)? ">" | "</" HTML_TAGNAME ">";
+    let HTML_BR = "<br" " "* "/"? ">";
     let HTML_COMMENT_START = "<!--";
     let HTML_COMMENT_END = "-->";
     let HTML_TEXT = .;
     let ESCAPED_NEWLINE = '\\' NEWLINE;
 
     let PASSAGE_START = "::" ':'*;
+    let COMMENT_PASSAGE_START = NEWLINE PASSAGE_START;
+    // a line of an ignored passage, which does not start a new passage
+    let IGNORED_LINE = [^':''\n'] [^'\n']* NEWLINE? | ':' [^':''\n'] [^'\n']* NEWLINE? | ':'? NEWLINE;
 
-    let PASSAGENAME_CHAR_START = [^"[]$<>:|" '\n'];
+    // See `normalize_passage_name` for the characters allowed in passage names.
+    let PASSAGENAME_CHAR_START = [^"[]:|" '\n'];
     let PASSAGENAME_CHAR = ":"? PASSAGENAME_CHAR_START;
     let PASSAGENAME = PASSAGENAME_CHAR_START PASSAGENAME_CHAR* ':'?;
+    let PASSAGENAME_INVALID = PASSAGENAME? (['|'']'] | "::") [^'\n''[']*;
 
     let TAG_START = '[';
     let TAG_END = ']';
     let TAG = ['a'-'z''A'-'Z''0'-'9''.''_']+;
+    let TAG_REQUIRES = "requires:";
+    let PRECONDITION_TARGET = "->" [^"];" '\n']+;
 
     let TEXT_CHAR_START = [^"!#"'\n''\\'] | '\\'[^'\n'] | HTTP;
     let TEXT_CHAR = [^"/'_=~^{@<[" '\n''\\'] | '\\'[^'\n'] | HTTP;
@@ -71,7 +114,8 @@ rustlex! TweeLexer {
     let VARIABLE_CHAR = LETTER | DIGIT | UNDERSCORE;
     let VARIABLE = '$' (LETTER | UNDERSCORE) VARIABLE_CHAR*;
     let VARIABLE_LENGTH = VARIABLE ".length";
-    let ARRAY_ACCESS = VARIABLE '[' WHITESPACE* VARIABLE WHITESPACE* ']';
+    let ARRAY_ACCESS = VARIABLE '[' WHITESPACE* (VARIABLE | DIGIT+) WHITESPACE* ']';
+    let MAP_ACCESS = VARIABLE '[' WHITESPACE* STRING WHITESPACE* ']';
 
     let FORMAT_ITALIC = "//";
     let FORMAT_BOLD = "''";
@@ -95,20 +139,31 @@ rustlex! TweeLexer {
     let LINK_TEXT = [^'\n'"|[]"]+;
     let LINK_SIMPLE = "[[" (PASSAGENAME | VARIABLE) "]";
     let LINK_LABELED = "[[" LINK_TEXT "|" (PASSAGENAME | VARIABLE) "]";
+    let LINK_UNTERMINATED = "[[" [^'\n'"]"]* NEWLINE;
 
     let MACRO_START = "<<";
     let MACRO_END = ">>";
-    let MACRONAME = [^" >"'\n']* ( WHITESPACE+ "if")?;
+    // a name never starts with `=`, so that the print alias also works without a space
+    let MACRONAME = ([^" >="'\n'] [^" >"'\n']*)? ( WHITESPACE+ "if")?;
+    let MACRO_PRINT_ALIAS = "=";
+    let MACRO_ALLOW_WARNING = ['a'-'z''0'-'9''-']+;
     let MACRO_DISPLAY_PASSAGENAME = [^'"''>'' ''\t''\n'] ([^">"]*(">"[^">"])?)* [^'"''>'' ''\t''\n'] | [^"'>"' ''\t''\n'] ([^">"]*(">"[^">"])?)* [^"'>"' ''\t''\n'];
 
     let INT = DIGIT+;
+    let SECONDS = DIGIT+ 's';
     let FLOAT = (DIGIT+ "." DIGIT*) | (DIGIT* "." DIGIT+) | "Infinity";
     let STRING = '"' ([^'\\''"']|'\\'.)* '"' | "'" ([^'\\'"'"]|'\\'.)* "'";
+    // a string is only unterminated if its quote is not closed before the end of the input
+    let STRING_UNTERMINATED = '"' ([^'\\''"']|'\\'.)* | "'" ([^'\\'"'"]|'\\'.)*;
     let BOOL = "true" | "false";
     let ASSIGN = "=" | "to" | "+=" | "-=" | "*=" | "/=";
     let NUM_OP = ["+-*/%"];
     let COMP_OP = "is" | "==" | "eq" | "!=" | "neq" | ">" | "gt" | ">=" | "gte" | "<" | "lt" | "<=" | "lte";
     let LOG_OP = "and" | "&&" | "or" | "||" | "not" | "!";
+    let TERNARY_IF = '?';
+    let TERNARY_ELSE = ':';
+    let MAP_START = '{';
+    let MAP_END = '}';
 
     let FUNCTION_NAME = (LETTER | UNDERSCORE) VARIABLE_CHAR*;
     let FUNCTION = FUNCTION_NAME '(';
@@ -127,7 +182,7 @@ rustlex! TweeLexer {
             let s =  lexer.yystr();
             let trimmed = &s[2 .. s.len()-1];
             let name = &trimmed.to_string();
-            Some(TokPassageLink {location: lexer.yylloc(), display_name: name.clone(), passage_name: name.clone()} )
+            Some(TokPassageLink {location: lexer.yylloc(), display_name: name.clone(), passage_name: normalize_passage_name(name)} )
         }
         LINK_LABELED
                     => |lexer:&mut TweeLexer<R>| {
@@ -138,10 +193,16 @@ rustlex! TweeLexer {
             let matches = &trimmed.split("|").collect::<Vec<&str>>();
             assert_eq!(matches.len(), 2);
             let text = matches[0].to_string();
-            let name = matches[1].to_string();
+            let name = normalize_passage_name(matches[1]);
             Some(TokPassageLink {location: lexer.yylloc(), display_name: text, passage_name: name} )
         }
+        LINK_UNTERMINATED
+                    => |lexer:&mut TweeLexer<R>| -> Option<Token> {
+            lexer.NEWLINE_PASSAGE_CONTENT();
+            Some(TokError {location: lexer.yylloc(), message: "Unterminated link, a link has to be closed with ]] on the same line".to_string(), raw: lexer.yystr().trim_right().to_string()} )
+        }
         MACRO_START => |lexer:&mut TweeLexer<R>| -> Option<Token>{
+            lexer.open_macro.set(Some(lexer.yylloc()));
             lexer.PASSAGE_CONTENT_MACRO();
             None
         }
@@ -194,8 +255,10 @@ rustlex! TweeLexer {
             Some(TokNewLine {location: lexer.yylloc()} )
         }
         // The following matched regex are ignored in this state.
-        COMMENT     => |lexer:&mut TweeLexer<R>| -> Option<Token> {
-            lexer.NON_NEWLINE_PASSAGE_CONTENT();
+        COMMENT_START
+                    => |lexer:&mut TweeLexer<R>| -> Option<Token> {
+            lexer.open_comment.set(Some(lexer.yylloc()));
+            lexer.COMMENT();
             None
         }
         HTML_DOCTYPE
@@ -221,17 +284,43 @@ rustlex! TweeLexer {
         VARIABLE    => |lexer:&mut TweeLexer<R>| Some(TokVariable{location: lexer.yylloc(), name: lexer.yystr()})
         ARRAY_ACCESS
                     => |lexer:&mut TweeLexer<R>| Some(TokArrayAccess{location: lexer.yylloc(), name: lexer.yystr()[..].split('[').next().unwrap().to_string(), index: lexer.yystr()[..].split('[').nth(1).unwrap().split(']').next().unwrap().trim().to_string() } )
+        MAP_ACCESS  => |lexer:&mut TweeLexer<R>| {
+            let s = lexer.yystr();
+            let open = s.find('[').unwrap();
+            let key = s[open+1 .. s.len()-1].trim().to_string();
+            Some(TokMapAccess{location: lexer.yylloc(), name: s[..open].to_string(), key: unescape(key)})
+        }
         VARIABLE_LENGTH
                     => |lexer:&mut TweeLexer<R>| Some(TokArrayLength{location: lexer.yylloc(), name: lexer.yystr()[..].split('.').next().unwrap().to_string()} )
-        INT         => |lexer:&mut TweeLexer<R>| Some(TokInt     {location: lexer.yylloc(), value: lexer.yystr()[..].parse().unwrap()})
-        FLOAT       => |lexer:&mut TweeLexer<R>| Some(TokFloat   {location: lexer.yylloc(), value: lexer.yystr()[..].parse().unwrap()})
+        INT         => |lexer:&mut TweeLexer<R>| {
+            let s = lexer.yystr();
+            match s.parse() {
+                Ok(value) => Some(TokInt {location: lexer.yylloc(), value: value}),
+                Err(_) => Some(TokError {location: lexer.yylloc(), message: format!("The number {} is too large, numbers have to be at most {}", s, i32::max_value()), raw: s})
+            }
+        }
+        FLOAT       => |lexer:&mut TweeLexer<R>| {
+            let s = lexer.yystr();
+            match s.parse() {
+                Ok(value) => Some(TokFloat {location: lexer.yylloc(), value: value}),
+                Err(_) => Some(TokError {location: lexer.yylloc(), message: format!("The number {} is not a valid floating point number", s), raw: s})
+            }
+        }
         STRING      => |lexer:&mut TweeLexer<R>| Some(TokString  {location: lexer.yylloc(), value: unescape(lexer.yystr())})
+        STRING_UNTERMINATED
+                    => |lexer:&mut TweeLexer<R>| {
+            let s = lexer.yystr();
+            let quote = s[..1].to_string();
+            Some(TokError {location: lexer.yylloc(), message: format!("Unterminated string, the {} is not closed before the end of the input", quote), raw: s})
+        }
         BOOL        => |lexer:&mut TweeLexer<R>| Some(TokBoolean {location: lexer.yylloc(), value: lexer.yystr()})
     }
     I_OPERATORS {
         NUM_OP      => |lexer:&mut TweeLexer<R>| Some(TokNumOp   {location: lexer.yylloc(), op_name: lexer.yystr()})
         COMP_OP     => |lexer:&mut TweeLexer<R>| Some(TokCompOp  {location: lexer.yylloc(), op_name: lexer.yystr()})
         LOG_OP      => |lexer:&mut TweeLexer<R>| Some(TokLogOp   {location: lexer.yylloc(), op_name: lexer.yystr()})
+        TERNARY_IF  => |lexer:&mut TweeLexer<R>| Some(TokTernaryIf   {location: lexer.yylloc()})
+        TERNARY_ELSE => |lexer:&mut TweeLexer<R>| Some(TokTernaryElse {location: lexer.yylloc()})
     }
     I_EXPRESSION {
         :I_OPERANDS
@@ -247,9 +336,12 @@ rustlex! TweeLexer {
         PAREN_OPEN  => |lexer:&mut TweeLexer<R>| Some(TokParenOpen {location: lexer.yylloc()})
         PAREN_CLOSE => |lexer:&mut TweeLexer<R>| Some(TokParenClose{location: lexer.yylloc()})
         SEMI_COLON  => |lexer:&mut TweeLexer<R>| Some(TokSemiColon {location: lexer.yylloc()})
+        COLON       => |lexer:&mut TweeLexer<R>| Some(TokColon     {location: lexer.yylloc()})
         ASSIGN      => |lexer:&mut TweeLexer<R>| {
             Some(TokAssign    {location: lexer.yylloc(), var_name: "".to_string(), op_name: lexer.yystr()})
         }
+        MAP_START   => |lexer:&mut TweeLexer<R>| Some(TokMapLiteral    {location: lexer.yylloc()})
+        MAP_END     => |lexer:&mut TweeLexer<R>| Some(TokMapLiteralEnd {location: lexer.yylloc()})
     }
     FUNCTION_ARGS {
         :I_OPERANDS
@@ -263,6 +355,8 @@ rustlex! TweeLexer {
             if lexer.function_parens == 0 {
                 if lexer.in_link {
                     lexer.PASSAGE_CONTENT_LINK_VARIABLE_SET();
+                } else if lexer.in_precondition {
+                    lexer.TAG_PRECONDITION();
                 } else {
                     lexer.PASSAGE_CONTENT_MACRO_CONTENT();
                 }
@@ -291,17 +385,32 @@ rustlex! TweeLexer {
 
     // This state recognizes a passage declaration. Everything until a newline
     // or tag is considered as passagename. It is entered when matching a
-    // PASSAGE_START regex and left when matching a NEWLINE regex.
+    // PASSAGE_START regex and left when matching a NEWLINE regex. The content of
+    // the StoryStylesheet and StoryScript passages of Twine exports is ignored.
     PASSAGE {
         PASSAGENAME => |lexer:&mut TweeLexer<R>| {
-            Some(TokPassage {name: lexer.yystr().trim().to_string(), location: lexer.yylloc()} )
+            let name = normalize_passage_name(&lexer.yystr());
+            if name == "StoryStylesheet" || name == "StoryScript" {
+                lexer.ignore_this_passage = true;
+            }
+            *lexer.passage_name.borrow_mut() = name.clone();
+            Some(TokPassage {name: name, location: lexer.yylloc()} )
+        }
+        PASSAGENAME_INVALID
+                    => |lexer:&mut TweeLexer<R>| {
+            Some(TokError {location: lexer.yylloc(), message: format!("Invalid passage name \"{}\", passage names cannot contain '|', ']' or '::'", lexer.yystr().trim()), raw: lexer.yystr()} )
         }
         TAG_START   => |lexer:&mut TweeLexer<R>| {
             lexer.TAG_CONTENT();
             Some(TokTagStart {location: lexer.yylloc()})
         }
         NEWLINE     => |lexer:&mut TweeLexer<R>| -> Option<Token>{
-            lexer.NEWLINE_PASSAGE_CONTENT();
+            if !lexer.ignore_this_passage {
+                lexer.NEWLINE_PASSAGE_CONTENT();
+            } else {
+                lexer.ignore_callback = true;
+                lexer.IGNORED_PASSAGE_CONTENT();
+            }
             None
         }
     }
@@ -309,6 +418,7 @@ rustlex! TweeLexer {
     // This state recognizes tags. It is entered when matching a TAG_START regex
     // and left when matching a TAG_END regex. Unmatched characters will lead to
     // a callback. There are some specific tags that make ignore the current passage.
+    // A "requires:" starts a precondition of the passage, see TAG_PRECONDITION.
     TAG_CONTENT {
         TAG         => |lexer:&mut TweeLexer<R>| -> Option<Token> {
             match lexer.yystr().as_ref() {
@@ -317,6 +427,14 @@ rustlex! TweeLexer {
             }
             Some(TokTag {location: lexer.yylloc(), tag_name: lexer.yystr().to_string()})
         }
+        TAG_REQUIRES
+                    => |lexer:&mut TweeLexer<R>| {
+            lexer.in_precondition = true;
+            lexer.TAG_PRECONDITION();
+            Some(TokPrecondition {location: lexer.yylloc()})
+        }
+        // separates a precondition from the following tags
+        SEMI_COLON  => |_    :&mut TweeLexer<R>| -> Option<Token> { None }
         TAG_END     => |lexer:&mut TweeLexer<R>| {
             lexer.ignore_callback = true;
             lexer.TAG_END_WAIT_FOR_NEWLINE();
@@ -326,6 +444,22 @@ rustlex! TweeLexer {
         :I_IGNORE_WHITESPACE
     }
 
+    // This state recognizes the condition of a precondition in the tags, like
+    // `[requires: $hasLamp -> DarkDeath]`. It is entered when matching a
+    // TAG_REQUIRES regex and left when matching the passage after the arrow, which
+    // reaches to the next ';' or the end of the tags.
+    TAG_PRECONDITION {
+        PRECONDITION_TARGET
+                    => |lexer:&mut TweeLexer<R>| {
+            lexer.in_precondition = false;
+            lexer.TAG_CONTENT();
+            Some(TokPreconditionTarget {location: lexer.yylloc(), passage_name: normalize_passage_name(&lexer.yystr()[2..])})
+        }
+        :I_EXPRESSION
+        // The following matched regex are ignored in this state.
+        :I_IGNORE_WHITESPACE
+    }
+
     // This state waits for a newline after one or several tags to finish a passage
     // declaration. It is entered when matching a TAG_END regex and left when
     // matching a NEWLINE regex. Unmatched characters will lead to a callback.
@@ -336,12 +470,29 @@ rustlex! TweeLexer {
                 lexer.ignore_callback = false;
                 lexer.NEWLINE_PASSAGE_CONTENT();
             } else {
-                lexer.INITIAL();
+                lexer.IGNORED_PASSAGE_CONTENT();
             }
             None
         }
     }
 
+    // This state skips the content of a passage tagged stylesheet or script, or of
+    // a StoryStylesheet or StoryScript passage, line by line. It is entered after
+    // the declaration of the passage and left when matching a PASSAGE_START regex
+    // at the start of a line, so a `::` within a line of CSS keeps the passage
+    // going. In this state callbacks are ignored.
+    IGNORED_PASSAGE_CONTENT {
+        PASSAGE_START
+                    => |lexer:&mut TweeLexer<R>| -> Option<Token> {
+            lexer.ignore_callback = false;
+            lexer.ignore_this_passage = false;
+            lexer.PASSAGE();
+            None
+        }
+        IGNORED_LINE
+                    => |_:&mut TweeLexer<R>| -> Option<Token> { None }
+    }
+
     // This state manages passage content while looking at the first character in a
     // newline. It is entered after matching a newline within passage content or after
     // matching a passage declaration. It is left when matching any character.
@@ -427,7 +578,11 @@ rustlex! TweeLexer {
     PASSAGE_CONTENT_LINK_VARIABLE_CHECK {
         LINK_CLOSE  => |lexer:&mut TweeLexer<R>| -> Option<Token> {
             lexer.in_link = false;
-            lexer.NON_NEWLINE_PASSAGE_CONTENT();
+            if lexer.in_otherwise {
+                lexer.PASSAGE_CONTENT_MACRO_CONTENT_OTHERWISE();
+            } else {
+                lexer.NON_NEWLINE_PASSAGE_CONTENT();
+            }
             None
         }
         LINK_OPEN   => |lexer:&mut TweeLexer<R>| -> Option<Token> {
@@ -455,20 +610,29 @@ rustlex! TweeLexer {
     PASSAGE_CONTENT_LINK_WAIT_FOR_CLOSE {
         LINK_CLOSE  => |lexer:&mut TweeLexer<R>| -> Option<Token> {
             lexer.in_link = false;
-            lexer.NON_NEWLINE_PASSAGE_CONTENT();
+            if lexer.in_otherwise {
+                lexer.PASSAGE_CONTENT_MACRO_CONTENT_OTHERWISE();
+            } else {
+                lexer.NON_NEWLINE_PASSAGE_CONTENT();
+            }
             None
         }
     }
 
     // This state recognizes a macro. It is entered when matching a MACRO_START
-    // regex and left when matching a MACRONAME, VARIABLE or WHITESPACE regex.
-    // A matched macroname that doesn't correspond to a built-in macro indicates a
-    // short display macro. A matched variable indicates a short print macro.
+    // regex and left when matching a MACRONAME, MACRO_PRINT_ALIAS, VARIABLE or
+    // WHITESPACE regex. A `=` starts a print macro in dialects that alias it.
+    // A matched macroname that doesn't correspond to a built-in or plugin macro
+    // or a widget indicates a short display macro. A matched variable indicates a short print macro.
     // Whitespace after an opening `<<` aborts. Unmatched characters will lead
     // to a callback.
     PASSAGE_CONTENT_MACRO {
         MACRONAME   => |lexer:&mut TweeLexer<R>| -> Option<Token> {
             let replaced_string = str::replace(lexer.yystr().trim(),  " ", "");
+            let replaced_string = match lexer.cfg {
+                Some(ref cfg) => dialect_rules(cfg.dialect).macro_name(replaced_string),
+                None => replaced_string
+            };
             match replaced_string.as_ref() {
                 "set" => {
                     lexer.PASSAGE_CONTENT_MACRO_CONTENT();
@@ -494,6 +658,14 @@ rustlex! TweeLexer {
                     lexer.PASSAGE_CONTENT_MACRO_CONTENT();
                     Some(TokMacroPrint {location: lexer.yylloc()} )
                 },
+                "confirm" => {
+                    lexer.PASSAGE_CONTENT_MACRO_CONTENT();
+                    Some(TokMacroConfirm {location: lexer.yylloc()} )
+                },
+                "clear" => {
+                    lexer.PASSAGE_CONTENT_MACRO_CONTENT();
+                    Some(TokMacroClear {location: lexer.yylloc()} )
+                },
                 "display" => {
                     lexer.PASSAGE_CONTENT_MACRO_CONTENT_DISPLAY();
                     None
@@ -514,17 +686,82 @@ rustlex! TweeLexer {
                     lexer.PASSAGE_CONTENT_MACRO_CONTENT();
                     Some(TokMacroEndNoBr {location: lexer.yylloc()} )
                 },
+                "at" => {
+                    lexer.PASSAGE_CONTENT_MACRO_CONTENT();
+                    Some(TokMacroAt {location: lexer.yylloc()} )
+                },
+                "endat" => {
+                    lexer.PASSAGE_CONTENT_MACRO_CONTENT();
+                    Some(TokMacroEndAt {location: lexer.yylloc()} )
+                },
+                "countdown" => {
+                    lexer.PASSAGE_CONTENT_MACRO_CONTENT_COUNTDOWN();
+                    Some(TokMacroCountdown {location: lexer.yylloc()} )
+                },
+                "zwreec-allow" => {
+                    lexer.PASSAGE_CONTENT_MACRO_CONTENT_ALLOW();
+                    None
+                },
+                "widget" => {
+                    lexer.PASSAGE_CONTENT_MACRO_CONTENT_WIDGET();
+                    None
+                },
+                "endwidget" => {
+                    lexer.PASSAGE_CONTENT_MACRO_CONTENT();
+                    Some(TokMacroEndWidget {location: lexer.yylloc()} )
+                },
+                "br" => {
+                    lexer.PASSAGE_CONTENT_MACRO_CONTENT();
+                    Some(TokMacroBr {location: lexer.yylloc()} )
+                },
+                "toc" => {
+                    lexer.PASSAGE_CONTENT_MACRO_CONTENT();
+                    Some(TokMacroToc {location: lexer.yylloc()} )
+                },
+                "onexit" => {
+                    lexer.PASSAGE_CONTENT_MACRO_CONTENT();
+                    Some(TokMacroOnExit {location: lexer.yylloc()} )
+                },
+                "endonexit" => {
+                    lexer.PASSAGE_CONTENT_MACRO_CONTENT();
+                    Some(TokMacroEndOnExit {location: lexer.yylloc()} )
+                },
+                "otherwise" => {
+                    lexer.in_otherwise = true;
+                    lexer.PASSAGE_CONTENT_MACRO_CONTENT_OTHERWISE();
+                    Some(TokMacroOtherwise {location: lexer.yylloc()} )
+                },
+                name if lexer.cfg.as_ref().map_or(false, |cfg| cfg.macros.contains(name)) => {
+                    lexer.PASSAGE_CONTENT_MACRO_CONTENT();
+                    Some(TokMacroPlugin {location: lexer.yylloc(), name: name.to_string()} )
+                },
+                name if lexer.cfg.as_ref().map_or(false, |cfg| cfg.widgets.iter().any(|widget| widget == name)) => {
+                    lexer.PASSAGE_CONTENT_MACRO_CONTENT();
+                    Some(TokMacroWidgetCall {location: lexer.yylloc(), name: name.to_string()} )
+                },
                 _ => {
                     lexer.PASSAGE_CONTENT_MACRO_CONTENT_SHORT_DISPLAY();
                     Some(TokMacroDisplay {location: lexer.yylloc(), passage_name: replaced_string.to_string()} )
                 }
             }
         }
+        MACRO_PRINT_ALIAS
+                    => |lexer:&mut TweeLexer<R>| -> Option<Token> {
+            let is_alias = lexer.cfg.as_ref().map_or(false, |cfg| dialect_rules(cfg.dialect).macro_name("=".to_string()) == "print");
+            if is_alias {
+                lexer.PASSAGE_CONTENT_MACRO_CONTENT();
+                Some(TokMacroPrint {location: lexer.yylloc()} )
+            } else {
+                lexer.PASSAGE_CONTENT_MACRO_CONTENT_SHORT_DISPLAY();
+                Some(TokMacroDisplay {location: lexer.yylloc(), passage_name: "=".to_string()} )
+            }
+        }
         VARIABLE    => |lexer:&mut TweeLexer<R>| {
             lexer.PASSAGE_CONTENT_MACRO_CONTENT_SHORT_PRINT();
             Some(TokMacroContentVar {location: lexer.yylloc(), var_name: lexer.yystr()} )
         }
         WHITESPACE  => |lexer:&mut TweeLexer<R>| -> Option<Token> {
+            lexer.open_macro.set(None);
             lexer.NON_NEWLINE_PASSAGE_CONTENT();
             None
         }
@@ -535,9 +772,34 @@ rustlex! TweeLexer {
     // Every non valid expression will lead to a callback.
     PASSAGE_CONTENT_MACRO_CONTENT {
         MACRO_END   => |lexer:&mut TweeLexer<R>| {
+            lexer.open_macro.set(None);
+            lexer.NON_NEWLINE_PASSAGE_CONTENT();
+            Some(TokMacroEnd {location: lexer.yylloc()} )
+        }
+        :I_EXPRESSION
+        // The following matched regex are ignored in this state.
+        :I_IGNORE_NEWLINE
+        :I_IGNORE_WHITESPACE
+    }
+
+    // This state recognizes the expressions of a countdown macro, which may
+    // also be seconds like 30s. It is entered when matching a MACRONAME regex
+    // and left when matching a MACRO_END regex. Every non valid expression
+    // will lead to a callback.
+    PASSAGE_CONTENT_MACRO_CONTENT_COUNTDOWN {
+        MACRO_END   => |lexer:&mut TweeLexer<R>| {
+            lexer.open_macro.set(None);
             lexer.NON_NEWLINE_PASSAGE_CONTENT();
             Some(TokMacroEnd {location: lexer.yylloc()} )
         }
+        // the seconds of <<countdown 30s "passage">> are a plain number
+        SECONDS     => |lexer:&mut TweeLexer<R>| {
+            let s = lexer.yystr();
+            match s[..s.len()-1].parse() {
+                Ok(value) => Some(TokInt {location: lexer.yylloc(), value: value}),
+                Err(_) => Some(TokError {location: lexer.yylloc(), message: format!("The time {} is too long", s), raw: s})
+            }
+        }
         :I_EXPRESSION
         // The following matched regex are ignored in this state.
         :I_IGNORE_NEWLINE
@@ -550,15 +812,90 @@ rustlex! TweeLexer {
     // regex. Unmatched characters will lead to a callback.
     PASSAGE_CONTENT_MACRO_CONTENT_DISPLAY {
         MACRO_END   => |lexer:&mut TweeLexer<R>| {
+            lexer.open_macro.set(None);
             lexer.NON_NEWLINE_PASSAGE_CONTENT();
             Some(TokMacroEnd {location: lexer.yylloc()} )
         }
         MACRO_DISPLAY_PASSAGENAME
                     => |lexer:&mut TweeLexer<R>| {
-            Some(TokMacroDisplay {location: lexer.yylloc(), passage_name: lexer.yystr().trim().to_string()} )
+            Some(TokMacroDisplay {location: lexer.yylloc(), passage_name: normalize_passage_name(&lexer.yystr())} )
+        }
+        STRING      => |lexer:&mut TweeLexer<R>| {
+            Some(TokMacroDisplay {passage_name: normalize_passage_name(&unescape(lexer.yystr())), location: lexer.yylloc()})
+        }
+        // The following matched regex are ignored in this state.
+        :I_IGNORE_NEWLINE
+        :I_IGNORE_WHITESPACE
+    }
+
+    // This state recognizes the id of the warning in a zwreec-allow macro, as
+    // plain text or string. It is entered when matching a MACRONAME regex and
+    // left when matching a MACRO_END regex. Unmatched characters will lead to a
+    // callback.
+    PASSAGE_CONTENT_MACRO_CONTENT_ALLOW {
+        MACRO_END   => |lexer:&mut TweeLexer<R>| {
+            lexer.open_macro.set(None);
+            lexer.NON_NEWLINE_PASSAGE_CONTENT();
+            Some(TokMacroEnd {location: lexer.yylloc()} )
+        }
+        MACRO_ALLOW_WARNING
+                    => |lexer:&mut TweeLexer<R>| {
+            Some(TokMacroAllow {location: lexer.yylloc(), warning: lexer.yystr()} )
+        }
+        STRING      => |lexer:&mut TweeLexer<R>| {
+            Some(TokMacroAllow {warning: unescape(lexer.yystr()), location: lexer.yylloc()})
+        }
+        // The following matched regex are ignored in this state.
+        :I_IGNORE_NEWLINE
+        :I_IGNORE_WHITESPACE
+    }
+
+    // This state recognizes the name of a widget definition. It is entered when
+    // matching a MACRONAME regex and left when matching a MACRO_END regex. The
+    // name has to be a string. Unmatched characters will lead to a callback.
+    PASSAGE_CONTENT_MACRO_CONTENT_WIDGET {
+        MACRO_END   => |lexer:&mut TweeLexer<R>| {
+            lexer.open_macro.set(None);
+            lexer.NON_NEWLINE_PASSAGE_CONTENT();
+            Some(TokMacroEnd {location: lexer.yylloc()} )
         }
         STRING      => |lexer:&mut TweeLexer<R>| {
-            Some(TokMacroDisplay {passage_name: unescape(lexer.yystr()), location: lexer.yylloc()})
+            Some(TokMacroWidget {name: unescape(lexer.yystr()), location: lexer.yylloc()})
+        }
+        // The following matched regex are ignored in this state.
+        :I_IGNORE_NEWLINE
+        :I_IGNORE_WHITESPACE
+    }
+
+    // This state recognizes the link of an otherwise macro. It is entered when
+    // matching a MACRONAME regex and after the link was closed, and left when
+    // matching a MACRO_END regex. Unmatched characters will lead to a callback.
+    PASSAGE_CONTENT_MACRO_CONTENT_OTHERWISE {
+        MACRO_END   => |lexer:&mut TweeLexer<R>| {
+            lexer.open_macro.set(None);
+            lexer.in_otherwise = false;
+            lexer.NON_NEWLINE_PASSAGE_CONTENT();
+            Some(TokMacroEnd {location: lexer.yylloc()} )
+        }
+        LINK_SIMPLE => |lexer:&mut TweeLexer<R>| {
+            lexer.in_link = true;
+            lexer.PASSAGE_CONTENT_LINK_VARIABLE_CHECK();
+            let s =  lexer.yystr();
+            let trimmed = &s[2 .. s.len()-1];
+            let name = &trimmed.to_string();
+            Some(TokPassageLink {location: lexer.yylloc(), display_name: name.clone(), passage_name: normalize_passage_name(name)} )
+        }
+        LINK_LABELED
+                    => |lexer:&mut TweeLexer<R>| {
+            lexer.in_link = true;
+            lexer.PASSAGE_CONTENT_LINK_VARIABLE_CHECK();
+            let s =  lexer.yystr();
+            let trimmed = &s[2 .. s.len()-1];
+            let matches = &trimmed.split("|").collect::<Vec<&str>>();
+            assert_eq!(matches.len(), 2);
+            let text = matches[0].to_string();
+            let name = normalize_passage_name(matches[1]);
+            Some(TokPassageLink {location: lexer.yylloc(), display_name: text, passage_name: name} )
         }
         // The following matched regex are ignored in this state.
         :I_IGNORE_NEWLINE
@@ -570,6 +907,7 @@ rustlex! TweeLexer {
     // matching a MACRO_END regex. Unmatched characters will lead to a callback.
     PASSAGE_CONTENT_MACRO_CONTENT_SHORT_PRINT {
         MACRO_END   => |lexer:&mut TweeLexer<R>| {
+            lexer.open_macro.set(None);
             lexer.NON_NEWLINE_PASSAGE_CONTENT();
             Some(TokMacroEnd {location: lexer.yylloc()} )
         }
@@ -583,6 +921,7 @@ rustlex! TweeLexer {
     // matching a MACRO_END regex. Unmatched characters will lead to a callback.
     PASSAGE_CONTENT_MACRO_CONTENT_SHORT_DISPLAY {
         MACRO_END   => |lexer:&mut TweeLexer<R>| {
+            lexer.open_macro.set(None);
             lexer.NON_NEWLINE_PASSAGE_CONTENT();
             Some(TokMacroEnd {location: lexer.yylloc()} )
         }
@@ -605,6 +944,32 @@ rustlex! TweeLexer {
         :I_IGNORE_WHITESPACE
     }
 
+    // This state skips a comment. It is entered when matching a COMMENT_START regex
+    // and left when matching a COMMENT_END regex. A comment cannot span passages, a
+    // passage declaration closes it with the open-comment warning of the passage the
+    // comment started in. `lex` reports a comment that is still open at the end of
+    // the input.
+    COMMENT {
+        COMMENT_END => |lexer:&mut TweeLexer<R>| -> Option<Token> {
+            lexer.open_comment.set(None);
+            lexer.NON_NEWLINE_PASSAGE_CONTENT();
+            None
+        }
+        COMMENT_PASSAGE_START
+                    => |lexer:&mut TweeLexer<R>| -> Option<Token> {
+            if let (Some((line, column)), Some(cfg)) = (lexer.open_comment.get(), lexer.cfg.as_ref()) {
+                cfg.record_lexer_warning(Warning::OpenComment, &lexer.passage_name.borrow(), Some(line),
+                    format!("The comment opened at {}:{} is not closed before the passage in line {}, it ends there", line, column, lexer.yylloc().0 + 1));
+            }
+            lexer.open_comment.set(None);
+            lexer.PASSAGE();
+            None
+        }
+        // The following matched regex are ignored in this state.
+        COMMENT_TEXT
+                    => |_    :&mut TweeLexer<R>| -> Option<Token> { None }
+    }
+
     // This state filters HTML. Everything except HTML tags and comments is matched
     // as text (or newline). It is entered when matching a HTML_START regex and
     // left when matching a HTML_END regex.
@@ -621,6 +986,8 @@ rustlex! TweeLexer {
         }
         HTML_TEXT   => |lexer:&mut TweeLexer<R>| Some(TokText {location: lexer.yylloc(), text: lexer.yystr()})
         NEWLINE     => |lexer:&mut TweeLexer<R>| Some(TokNewLine {location: lexer.yylloc()})
+        // Line break tags are the only tags that are kept, they act like <<br>>
+        HTML_BR     => |lexer:&mut TweeLexer<R>| Some(TokMacroBr {location: lexer.yylloc()})
         // The following matched regex are ignored in this state.
         HTML_TAG    => |_    :&mut TweeLexer<R>| -> Option<Token> { None }
     }
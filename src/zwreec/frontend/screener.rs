@@ -3,35 +3,82 @@
 use std::error::Error;
 use std::io::{BufReader,Cursor,Read};
 
+/// The errors that can occur while reading the input.
+#[derive(Debug, Clone, PartialEq)]
+#[allow(missing_docs)]
+pub enum ScreenerError {
+    /// The input could not be read to the end
+    ReadFailed { why: String },
+
+    /// The input is not valid UTF-8, the byte at `offset` is the first invalid one
+    InvalidUtf8 { offset: usize },
+}
+
 /// Checks for and removes a UTF-8 Byte Order Mark (BOM) from the input stream.
-pub fn handle_bom_encoding<'a, R: Read>(input: &'a mut R) -> Cursor<Vec<u8>> {
+///
+/// Returns an error if reading the input fails or if it is not valid UTF-8, an input that is
+/// too short for a story is only logged.
+pub fn handle_bom_encoding<'a, R: Read>(input: &'a mut R) -> Result<Cursor<Vec<u8>>, ScreenerError> {
     info!("Started screening input file.");
     let mut reader = BufReader::new(input);
-    let mut content = String::new();
-    match reader.read_to_string(&mut content) {
-        Err(why) => error!("Couldn't read {}", Error::description(&why)),
-        Ok(_) => (),
-    };
+    let mut bytes: Vec<u8> = vec![];
+    if let Err(why) = reader.read_to_end(&mut bytes) {
+        return Err(ScreenerError::ReadFailed { why: Error::description(&why).to_string() });
+    }
+    if let Err(why) = ::std::str::from_utf8(&bytes) {
+        return Err(ScreenerError::InvalidUtf8 { offset: why.valid_up_to() });
+    }
 
-    let mut bytes: Vec<u8> = content.bytes().collect();
     if bytes.len() < 5 {
         error!("The file is too short for a valid twee file");
     }
-    let has_bom = if &bytes[0..3] == [0xef, 0xbb, 0xbf] {
-        true
-    } else {
-        false
-    };
-    if has_bom {
+    if bytes.starts_with(&[0xef, 0xbb, 0xbf]) {
         debug!("File has UTF-8 Byte Order Mark (BOM): Removing the first three bytes from the file");
-        bytes.remove(0);
-        bytes.remove(0);
-        bytes.remove(0);
+        bytes.drain(..3);
     }
 
     let cursor: Cursor<Vec<u8>> = Cursor::new(bytes);
 
     info!("Finished screening input file.");
 
-    cursor
+    Ok(cursor)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{self, Cursor, Read};
+
+    use super::*;
+
+    /// Returns the bytes of `data` and then fails.
+    struct FailingReader {
+        data: Cursor<Vec<u8>>,
+    }
+
+    impl Read for FailingReader {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            match try!(self.data.read(buf)) {
+                0 => Err(io::Error::new(io::ErrorKind::Other, "disk unplugged")),
+                n => Ok(n)
+            }
+        }
+    }
+
+    #[test]
+    fn test_remove_bom() {
+        let mut input = Cursor::new(b"\xef\xbb\xbf::Start\nHello".to_vec());
+        assert_eq!(handle_bom_encoding(&mut input).unwrap().into_inner(), b"::Start\nHello".to_vec());
+    }
+
+    #[test]
+    fn test_read_errors() {
+        let mut input = FailingReader { data: Cursor::new(b"::Start\nHello".to_vec()) };
+        match handle_bom_encoding(&mut input) {
+            Err(ScreenerError::ReadFailed { .. }) => (),
+            other => panic!("Expected a read error, got {:?}", other),
+        }
+
+        let mut input = Cursor::new(b"::Start\nHe\xffllo".to_vec());
+        assert_eq!(handle_bom_encoding(&mut input).unwrap_err(), ScreenerError::InvalidUtf8 { offset: 10 });
+    }
 }
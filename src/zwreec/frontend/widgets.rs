@@ -0,0 +1,274 @@
+//! Macros written in Twee itself, like the widgets of SugarCube.
+//!
+//! A passage tagged `widget` defines widgets with `<<widget "name">>...<<endwidget>>`, which
+//! the other passages call like a macro with `<<name args>>`. The arguments are expressions
+//! separated by commas. The widgets are expanded at compile time: every call is replaced by a
+//! copy of the body. Every argument is evaluated once in front of the body and assigned to a
+//! variable of the expansion, which replaces `$args[n]` in the body. `$args.length` is
+//! replaced by the number of arguments.
+//!
+//! Variables of the body whose names start with `$_` belong to the expansion. They are renamed
+//! with the name of the widget and the number of widgets expanded around the call, so a widget
+//! and the widget it calls do not share them. The calls of a widget at the same depth reuse the
+//! variables of the arguments and the `$_` variables, an expansion is done with them before the
+//! next one starts, and the story does not run out of global variables for every call site.
+//! All other variables are the variables of the story.
+//!
+//! The lexer has to know the names of the widgets before it reads the first call, so
+//! `widget_names` looks them up in the source first. The passages tagged `widget` are left
+//! out of the story.
+//!
+//! # Example
+//!
+//! ```
+//! # extern crate zwreec;
+//! use std::io::Cursor;
+//!
+//! let cfg = zwreec::config::Config::default_config();
+//! let twee = "::Start\n<<greet \"Bob\">>\n\n::Widgets [widget]\n<<widget \"greet\">>Hello <<print $args[0]>><<endwidget>>";
+//! let mut input = Cursor::new(twee.to_string().into_bytes());
+//!
+//! let passages = zwreec::compiled_ops(cfg, &mut input);
+//! assert_eq!(passages.len(), 1);
+//! assert_eq!(passages[0].0, "Start");
+//! ```
+
+use std::io::BufReader;
+
+use config::Config;
+use frontend::ast::{ASTNode, NodeDefault};
+use frontend::lexer::{Token, TweeLexer};
+use frontend::lexer::Token::{TokArrayAccess, TokArrayLength, TokAssign, TokExpression, TokInt, TokMacroContentVar,
+    TokMacroEndWidget, TokMacroWidget, TokMacroWidgetCall, TokMapAccess, TokTag, TokVariable};
+
+/// The tag of the passages that define widgets.
+pub static WIDGET_TAG: &'static str = "widget";
+
+/// The errors of defining and expanding widgets.
+#[derive(Debug)]
+pub enum WidgetError {
+    /// A `<<widget>>` in a passage without the `widget` tag
+    OutsideWidgetPassage { name: String, location: (u64, u64) },
+
+    /// A second widget with the name of another one
+    DuplicateWidget { name: String, location: (u64, u64) },
+
+    /// A widget calls itself, directly or through other widgets, `chain` lists the calls
+    RecursiveWidget { chain: Vec<String>, location: (u64, u64) },
+
+    /// `$args[index]` in a widget called with fewer arguments
+    MissingArgument { name: String, index: usize, location: (u64, u64) },
+}
+
+/// A widget with its body.
+struct Widget {
+    name: String,
+    body: Vec<ASTNode>,
+}
+
+/// Looks up the names of the widgets defined in the source, in the order of the definitions.
+///
+/// The source is lexed once without widgets, so definitions in comments are skipped like the
+/// lexer skips them later. The lexer is silent in this pass, its errors are reported when the
+/// story is lexed. Definitions outside of passages tagged
+/// `widget` are found as well, so that `expand_widgets` can report them.
+pub fn widget_names(source: &str) -> Vec<String> {
+    let mut names: Vec<String> = vec![];
+    if !source.contains("<<widget") {
+        return names;
+    }
+    let mut lexer = TweeLexer::new(BufReader::new(source.as_bytes()));
+    lexer.silent = true;
+    for token in lexer {
+        if let TokMacroWidget { name, .. } = token {
+            if !names.contains(&name) {
+                names.push(name);
+            }
+        }
+    }
+    names
+}
+
+/// Checks if the passage is tagged `widget`.
+pub fn is_widget_passage(passage: &ASTNode) -> bool {
+    passage.childs().iter().any(|child| match child.category() {
+        TokTag { ref tag_name, .. } => tag_name == WIDGET_TAG,
+        _ => false
+    })
+}
+
+/// Expands the calls of the widgets and leaves out the passages that define them.
+///
+/// # Panics
+/// Panics for duplicate or recursive widgets, definitions outside of widget passages and
+/// missing arguments unless the `force` option is set. With `force` the definition or the
+/// call is skipped and a missing argument is 0.
+pub fn expand_widgets(cfg: &Config, passages: Vec<ASTNode>) -> Vec<ASTNode> {
+    let mut widgets: Vec<Widget> = vec![];
+    let mut story: Vec<ASTNode> = vec![];
+    for passage in passages.into_iter() {
+        if !is_widget_passage(&passage) {
+            story.push(passage);
+            continue;
+        }
+
+        // everything besides the definitions is ignored, like in SugarCube
+        for child in passage.childs().iter() {
+            if let TokMacroWidget { ref name, location } = child.category() {
+                if widgets.iter().any(|widget| widget.name == *name) {
+                    error_panic!(cfg => WidgetError::DuplicateWidget { name: name.clone(), location: location });
+                    continue;
+                }
+                let body = child.childs().iter().filter(|node| match node.category() {
+                    TokMacroEndWidget { .. } => false,
+                    _ => true
+                }).cloned().collect();
+                widgets.push(Widget { name: name.clone(), body: body });
+            }
+        }
+    }
+
+    let mut expander = Expander { cfg: cfg, widgets: widgets };
+    story.into_iter().map(|passage| match passage {
+        ASTNode::Passage(mut node) => {
+            let childs = ::std::mem::replace(&mut node.childs, vec![]);
+            node.childs = expander.expand_nodes(childs, &mut vec![]);
+            ASTNode::Passage(node)
+        },
+        node => node
+    }).collect()
+}
+
+/// The state of the expansion of all passages.
+struct Expander<'a> {
+    cfg: &'a Config,
+    widgets: Vec<Widget>,
+}
+
+impl<'a> Expander<'a> {
+    /// Replaces the calls of widgets in the nodes, `chain` are the widgets expanded around them.
+    fn expand_nodes(&mut self, nodes: Vec<ASTNode>, chain: &mut Vec<String>) -> Vec<ASTNode> {
+        let mut expanded: Vec<ASTNode> = vec![];
+        for node in nodes.into_iter() {
+            match node {
+                ASTNode::Default(NodeDefault { category: TokMacroWidget { name, location }, .. }) => {
+                    error_panic!(self.cfg => WidgetError::OutsideWidgetPassage { name: name, location: location });
+                },
+                ASTNode::Default(NodeDefault { category: TokMacroWidgetCall { name, location }, childs }) => {
+                    let call = self.expand_call(&name, location, childs, chain);
+                    expanded.extend(call);
+                },
+                ASTNode::Default(NodeDefault { category, childs }) => {
+                    let childs = self.expand_nodes(childs, chain);
+                    expanded.push(ASTNode::Default(NodeDefault { category: category, childs: childs }));
+                },
+                passage => expanded.push(passage)
+            }
+        }
+        expanded
+    }
+
+    /// Returns the body of the widget for a call with the arguments in `childs`.
+    fn expand_call(&mut self, name: &str, location: (u64, u64), childs: Vec<ASTNode>, chain: &mut Vec<String>) -> Vec<ASTNode> {
+        if chain.iter().any(|outer| outer == name) {
+            let mut chain = chain.clone();
+            chain.push(name.to_string());
+            error_panic!(self.cfg => WidgetError::RecursiveWidget { chain: chain, location: location });
+            return vec![];
+        }
+
+        let body = match self.widgets.iter().find(|widget| widget.name == name) {
+            Some(widget) => widget.body.clone(),
+            // the definition was skipped and reported already
+            None => return vec![]
+        };
+
+        let args: Vec<ASTNode> = childs.into_iter().filter_map(|child| match child {
+            ASTNode::Default(NodeDefault { category: TokExpression, mut childs }) if !childs.is_empty() => Some(childs.remove(0)),
+            _ => None
+        }).collect();
+
+        // a widget is in the chain at most once, so the depth tells the expansions apart
+        let suffix = format!("#{}.{}", name, chain.len());
+
+        // the arguments belong to the caller, so they are assigned as they are
+        let mut nodes: Vec<ASTNode> = args.into_iter().enumerate().map(|(index, arg)| ASTNode::Default(NodeDefault {
+            category: TokAssign { location: location, var_name: argument_name(index, &suffix), op_name: "to".to_string() },
+            childs: vec![ASTNode::Default(NodeDefault { category: TokExpression, childs: vec![arg] })],
+        })).collect();
+        let count = nodes.len();
+        nodes.extend(body.into_iter().map(|node| self.substitute(node, name, count, &suffix)));
+
+        chain.push(name.to_string());
+        let expanded = self.expand_nodes(nodes, chain);
+        chain.pop();
+        expanded
+    }
+
+    /// Replaces the arguments in the node by the variables of the `count` arguments and renames
+    /// its local variables.
+    fn substitute(&self, node: ASTNode, name: &str, count: usize, suffix: &str) -> ASTNode {
+        let node = match node {
+            ASTNode::Default(node) => node,
+            passage => return passage
+        };
+
+        match node.category {
+            TokArrayAccess { name: ref array, ref index, location } if array == "$args" => {
+                if let Ok(index) = index.parse::<usize>() {
+                    if index < count {
+                        return ASTNode::Default(NodeDefault { category: TokVariable { location: location, name: argument_name(index, suffix) }, childs: vec![] });
+                    }
+                    error_panic!(self.cfg => WidgetError::MissingArgument { name: name.to_string(), index: index, location: location });
+                    return ASTNode::Default(NodeDefault { category: TokInt { location: location, value: 0 }, childs: vec![] });
+                }
+            },
+            TokArrayLength { name: ref array, location } if array == "$args" => {
+                return ASTNode::Default(NodeDefault { category: TokInt { location: location, value: count as i32 }, childs: vec![] });
+            },
+            _ => {}
+        }
+
+        ASTNode::Default(NodeDefault {
+            category: rename_local(node.category, suffix),
+            childs: node.childs.into_iter().map(|child| self.substitute(child, name, count, suffix)).collect(),
+        })
+    }
+}
+
+/// Returns the variable of the argument `index` of an expansion. The `#` of the suffix is in
+/// the middle of the name, so it is neither a variable of the story nor a renamed one.
+fn argument_name(index: usize, suffix: &str) -> String {
+    format!("$_args{}.{}", suffix, index)
+}
+
+/// Appends the suffix to the variables of the token that start with `$_`.
+fn rename_local(token: Token, suffix: &str) -> Token {
+    let rename = |name: String| if name.starts_with("$_") { name + suffix } else { name };
+    match token {
+        TokVariable { location, name } => TokVariable { location: location, name: rename(name) },
+        TokArrayLength { location, name } => TokArrayLength { location: location, name: rename(name) },
+        TokArrayAccess { location, name, index } => TokArrayAccess { location: location, name: rename(name), index: rename(index) },
+        TokMapAccess { location, name, key } => TokMapAccess { location: location, name: rename(name), key: key },
+        TokAssign { location, var_name, op_name } => TokAssign { location: location, var_name: rename(var_name), op_name: op_name },
+        TokMacroContentVar { location, var_name } => TokMacroContentVar { location: location, var_name: rename(var_name) },
+        token => token
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::widget_names;
+
+    #[test]
+    fn test_widget_names() {
+        let source = "::Widgets [widget]\n<<widget \"healthbar\">>HP<<endwidget>>\n<<widget 'bar'>><<endwidget>>\n\
+                      <<widgets>><<widget>><<widget \"bar\">>";
+        assert_eq!(widget_names(source), vec!["healthbar", "bar"]);
+        assert!(widget_names("::Start\nNo widgets").is_empty());
+
+        // a definition in a comment is no widget
+        let source = "::Widgets [widget]\n/% <<widget \"hidden\">>old<<endwidget>> %/\n<<widget \"shown\">>new<<endwidget>>";
+        assert_eq!(widget_names(source), vec!["shown"]);
+    }
+}
@@ -71,6 +71,16 @@
 //! }
 //! ```
 //!
+//! Without files, `compile_in_memory` takes the Twee source and returns the Zcode:
+//!
+//! ```
+//! let cfg = zwreec::config::Config::from_args(&["--reproducible"]).unwrap();
+//! let zcode = zwreec::compile_in_memory(cfg, "::Start\nHello World");
+//!
+//! // the header starts with the version of the Z-Machine
+//! assert_eq!(zcode[0], 8);
+//! ```
+//!
 //! # Reference Binary Implementation
 //!
 //! Zwreecs [Github-Repository](https://github.com/Drakulix/zwreec) contains a
@@ -103,11 +113,16 @@ extern crate time;
 
 #[macro_use] pub mod utils;
 pub mod backend;
+pub mod compiler;
 pub mod config;
 pub mod frontend;
+pub mod outputs;
+pub mod scaffold;
 
 use config::{Config,TestCase};
-use std::io::{Read,Write};
+use std::io;
+use std::io::{Cursor,Read,Write};
+use std::sync::Arc;
 use utils::extensions::cached;
 
 
@@ -118,6 +133,9 @@ use utils::extensions::cached;
 /// to generate a tokenstream, `frontend::parser` and `frontend::ast` to generate
 /// the Abstract Syntax Tree and lastly `frontend::codegen` to generate the Zcode.
 ///
+/// Returns the capabilities of the interpreter the story needs, see
+/// `backend::zcode::capabilities`.
+///
 /// # Example
 ///
 /// ```no_run
@@ -134,11 +152,32 @@ use utils::extensions::cached;
 ///
 /// zwreec::compile(cfg, &mut input, &mut output);
 /// ```
-#[allow(unused_variables)]
-pub fn compile<R: Read, W: Write>(cfg: Config, input: &mut R, output: &mut W) {
+pub fn compile<R: Read, W: Write>(cfg: Config, input: &mut R, output: &mut W) -> backend::zcode::capabilities::CapabilityReport {
+    run_compiler(cfg, input, output).capabilities
+}
 
+/// Compiles a Twee Input to Zcode like `compile`
+///
+/// Returns the manifest of the output instead of the capabilities, which also holds the bytes
+/// of every passage, see `backend::attest`.
+pub fn compile_with_manifest<R: Read, W: Write>(cfg: Config, input: &mut R, output: &mut W) -> backend::attest::Manifest {
+    run_compiler(cfg, input, output)
+}
+
+/// Compiles a Twee Input to Zcode without any content the story did not ask for
+///
+/// This is `compile` after `Config::disable_optional_content`, which disables e.g. the easter
+/// egg. Returns the manifest of the output, which attributes every routine to a passage or to
+/// the runtime, see `backend::attest`.
+pub fn attest<R: Read, W: Write>(mut cfg: Config, input: &mut R, output: &mut W) -> backend::attest::Manifest {
+    cfg.disable_optional_content();
+    run_compiler(cfg, input, output)
+}
+
+/// Runs the compiler-chain of `compile` and returns the manifest of the output.
+fn run_compiler<R: Read, W: Write>(mut cfg: Config, input: &mut R, output: &mut W) -> backend::attest::Manifest {
     // check the data if it has a bom
-    let cursor = frontend::screener::handle_bom_encoding(input);
+    let cursor = prepare_input(&mut cfg, input);
 
     // tokenize
     let cfg_tokens = cfg.clone();
@@ -162,8 +201,15 @@ pub fn compile<R: Read, W: Write>(cfg: Config, input: &mut R, output: &mut W) {
         frontend::ast::ASTBuilder::build(cfg_ast, ast_ops)
     });
 
+    // the calls of widgets can come before their definitions, so widgets need all passages
+    let ast: Box<Iterator<Item=frontend::ast::ASTNode>> = if cfg.widgets.is_empty() {
+        Box::new(ast.filter(|passage| !frontend::widgets::is_widget_passage(passage)))
+    } else {
+        Box::new(frontend::widgets::expand_widgets(&cfg, ast.collect()).into_iter())
+    };
+
     // create code
-    backend::codegen::generate_zcode(&cfg, ast.inspect(|ref passage| {
+    let manifest = backend::codegen::generate_zcode(&cfg, ast.inspect(|ref passage| {
         debug!("{:?}", passage);
     }), output);
 
@@ -181,13 +227,223 @@ pub fn compile<R: Read, W: Write>(cfg: Config, input: &mut R, output: &mut W) {
         Err(x) => panic!(x),
         _ => {}
     }
+
+    manifest
+}
+
+/// Reads the input with `frontend::screener::handle_bom_encoding` and reports the errors of
+/// reading it.
+fn screen_input<R: Read>(cfg: &Config, input: &mut R) -> Cursor<Vec<u8>> {
+    match frontend::screener::handle_bom_encoding(input) {
+        Ok(cursor) => cursor,
+        Err(why) => {
+            // with --force the input is compiled as if it was empty
+            error_panic!(cfg => why);
+            Cursor::new(vec![])
+        }
+    }
+}
+
+/// A reader that counts the bytes read from the inner one.
+struct CountingReader<'a, R: 'a> {
+    inner: &'a mut R,
+    count: usize,
+}
+
+impl<'a, R: Read> Read for CountingReader<'a, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = try!(self.inner.read(buf));
+        self.count += read;
+        Ok(read)
+    }
+}
+
+/// Screens the input like `screen_input` and looks up the widgets it defines, so the lexer
+/// reads their calls.
+fn prepare_input<R: Read>(cfg: &mut Config, input: &mut R) -> Cursor<Vec<u8>> {
+    let mut counted = CountingReader { inner: input, count: 0 };
+    let cursor = screen_input(cfg, &mut counted);
+    cfg.source_size = cursor.get_ref().len();
+    // the byte offsets of the errors count a removed Byte Order Mark, like an editor does
+    cfg.source_map = Some(Arc::new(if counted.count == cursor.get_ref().len() + 3 {
+        frontend::lexer::SourceMap::after_bom(cursor.get_ref(), cfg.tab_width)
+    } else {
+        frontend::lexer::SourceMap::from_source(cursor.get_ref(), cfg.tab_width)
+    }));
+    if cfg.embed_source {
+        cfg.source = cursor.get_ref().clone();
+    }
+    cfg.widgets = frontend::widgets::widget_names(&String::from_utf8_lossy(cursor.get_ref()));
+    cursor
+}
+
+/// Compiles Twee source to Zcode in memory
+///
+/// This is `compile` with a `Cursor` around the source and the output.
+///
+/// # Example
+///
+/// ```
+/// let cfg = zwreec::config::Config::default_config();
+/// let zcode = zwreec::compile_in_memory(cfg, "::Start\nHello World");
+/// assert!(zcode.len() > 64);
+/// ```
+pub fn compile_in_memory(cfg: Config, twee: &str) -> Vec<u8> {
+    let mut input = Cursor::new(twee.as_bytes());
+    let mut output = Cursor::new(Vec::new());
+    compile(cfg, &mut input, &mut output);
+    output.into_inner()
+}
+
+/// Returns the Twee source appended to a story compiled with `--embed-source`
+///
+/// Returns `None` if the story has no source or it is damaged. See `backend::zcode::archive`
+/// for the format.
+///
+/// # Example
+///
+/// ```
+/// let cfg = zwreec::config::Config::from_args(&["--embed-source"]).unwrap();
+/// let zcode = zwreec::compile_in_memory(cfg, "::Start\nHello World");
+///
+/// assert_eq!(zwreec::extract_source(&zcode).unwrap(), "::Start\nHello World");
+/// ```
+pub fn extract_source(story: &[u8]) -> Option<String> {
+    backend::zcode::archive::extract_source(story).and_then(|source| String::from_utf8(source).ok())
+}
+
+/// Runs only the frontend and returns the Abstract Syntax Tree
+///
+/// This processes `input: Read` the same way as `compile`, but stops after building the AST.
+/// Together with `frontend::export::ast_to_json` this allows external code generators to
+/// reuse the Twee frontend.
+///
+/// # Example
+///
+/// ```
+/// # extern crate zwreec;
+/// use std::io::Cursor;
+///
+/// let cfg = zwreec::config::Config::default_config();
+/// let mut input = Cursor::new("::Start\nHello World".to_string().into_bytes());
+///
+/// let ast = zwreec::frontend_ast(cfg, &mut input);
+/// assert_eq!(ast.len(), 1);
+/// ```
+pub fn frontend_ast<R: Read>(mut cfg: Config, input: &mut R) -> Vec<frontend::ast::ASTNode> {
+    let mut cursor = prepare_input(&mut cfg, input);
+    build_ast(&cfg, &mut cursor)
+}
+
+/// Builds the AST of an input prepared by `prepare_input` with the same config.
+fn build_ast<R: Read>(cfg: &Config, input: &mut R) -> Vec<frontend::ast::ASTNode> {
+    let tokens = frontend::lexer::lex(cfg.clone(), input);
+    let ops = frontend::parser::Parser::new(cfg.clone()).parse(tokens);
+    let ast = frontend::ast::ASTBuilder::build(cfg.clone(), ops).collect();
+    frontend::widgets::expand_widgets(cfg, ast)
+}
+
+/// Lists the constructs of a Twee Input that zwreec does not support
+///
+/// This only runs the lexer, so it reports all unsupported constructs at once instead of
+/// stopping at the first error like `compile`. See `frontend::compat` for the reported kinds.
+///
+/// # Example
+///
+/// ```
+/// # extern crate zwreec;
+/// use std::io::Cursor;
+///
+/// let cfg = zwreec::config::Config::default_config();
+/// let mut input = Cursor::new("::Start\nHello World".to_string().into_bytes());
+///
+/// let report = zwreec::compat_report(cfg, &mut input);
+/// assert_eq!(report.supported_passages, 1);
+/// ```
+pub fn compat_report<R: Read>(mut cfg: Config, input: &mut R) -> frontend::compat::CompatReport {
+    let mut cursor = prepare_input(&mut cfg, input);
+    frontend::compat::compat_report(frontend::lexer::lex(cfg, &mut cursor))
+}
+
+/// Compiles a Twee Input and returns the text of all strings in the Zcode
+///
+/// The strings are decoded from the Zcode bytes, so this shows the user-visible text as the
+/// interpreter will see it. It is meant for regression tests of the text encoding.
+///
+/// # Example
+///
+/// ```
+/// # extern crate zwreec;
+/// use std::io::Cursor;
+///
+/// let cfg = zwreec::config::Config::default_config();
+/// let mut input = Cursor::new("::Start\nHello World".to_string().into_bytes());
+///
+/// let strings = zwreec::compiled_strings(cfg, &mut input);
+/// assert!(strings.contains(&"Hello World".to_string()));
+/// ```
+pub fn compiled_strings<R: Read>(cfg: Config, input: &mut R) -> Vec<String> {
+    let ast = frontend_ast(cfg.clone(), input);
+    backend::codegen::generate_strings(&cfg, ast.into_iter())
+}
+
+/// Compiles a Twee Input and returns the Z-Code instructions of every passage
+///
+/// The instructions are returned before they are written to a file, so labels and strings are
+/// still readable. The passages keep the order of the input.
+///
+/// # Example
+///
+/// ```
+/// # extern crate zwreec;
+/// use std::io::Cursor;
+///
+/// let cfg = zwreec::config::Config::default_config();
+/// let mut input = Cursor::new("::Start\nHello World".to_string().into_bytes());
+///
+/// let passages = zwreec::compiled_ops(cfg, &mut input);
+/// assert_eq!(passages[0].0, "Start");
+/// ```
+pub fn compiled_ops<R: Read>(cfg: Config, input: &mut R) -> Vec<(String, Vec<backend::zcode::zfile::ZOP>)> {
+    let ast = frontend_ast(cfg.clone(), input);
+    backend::codegen::generate_ops(&cfg, ast.into_iter())
+}
+
+/// Compiles a Twee Input and returns every instruction of the story with the Zcode
+///
+/// Unlike `compiled_ops` this includes the code in front of the first routine and the runtime
+/// routines, in the order they are written. This allows tests to run the whole story.
+///
+/// # Example
+///
+/// ```
+/// # extern crate zwreec;
+/// use std::io::Cursor;
+///
+/// let cfg = zwreec::config::Config::default_config();
+/// let mut input = Cursor::new("::Start\nHello World".to_string().into_bytes());
+///
+/// let story = zwreec::compiled_story_ops(cfg, &mut input);
+/// assert_eq!(story.zcode[0], 8);
+/// ```
+pub fn compiled_story_ops<R: Read>(mut cfg: Config, input: &mut R) -> backend::codegen::StoryOps {
+    let mut cursor = prepare_input(&mut cfg, input);
+    let ast = build_ast(&cfg, &mut cursor);
+    backend::codegen::generate_story_ops(&cfg, ast.into_iter())
 }
 
 /// Run internal library tests.
 ///
 /// This function is used to circumvent certain parts of the compiler toolchain.
-/// It currently only processes `TestCase::ZcodeBackend` which creates a Zcode
-/// file using all available OP-Codes.
+/// `TestCase::ZcodeBackend` creates a Zcode file using all available OP-Codes,
+/// `TestCase::StringRoutines` one that calls the string routines. Both need an
+/// output. `TestCase::ExpressionFolding` compiles a passage with a constant
+/// expression and checks that it was evaluated at compile time.
+/// `TestCase::InjectUnattributed` attests a passage with an injected routine and
+/// checks that the manifest flags it.
+///
+/// Returns an error if no test case is given, a test case misses its output
+/// or a check failed.
 ///
 /// **Warning:** This function should be considered unstable and might be removed
 /// in later versions.
@@ -204,18 +460,70 @@ pub fn compile<R: Read, W: Write>(cfg: Config, input: &mut R, output: &mut W) {
 /// let mut input: Option<File> = None;
 /// let mut output = Some(File::create(Path::new("a.z8")).unwrap());
 ///
-/// zwreec::test_library(cfg, &mut input, &mut output);
+/// zwreec::test_library(cfg, &mut input, &mut output).unwrap();
 /// ```
 #[allow(unused_variables)]
-pub fn test_library<R: Read, W: Write>(cfg: Config, input: &mut Option<R>, output: &mut Option<W>) {
-    for case in cfg.test_cases {
+pub fn test_library<R: Read, W: Write>(cfg: Config, input: &mut Option<R>, output: &mut Option<W>) -> Result<(), String> {
+    if cfg.test_cases.is_empty() {
+        return Err("No test case given".to_string());
+    }
+
+    for case in cfg.test_cases.iter() {
         match case {
-            TestCase::ZcodeBackend => {
+            &TestCase::ZcodeBackend => {
                 match output.as_mut() {
                      Some(o) => backend::zcode::temp_create_zcode_example(o),
-                     None => error!("TestCase::ZcodeBackend requires output!"),
+                     None => return Err("TestCase::ZcodeBackend requires output!".to_string()),
                 }
-            }
+            },
+            &TestCase::StringRoutines => {
+                match output.as_mut() {
+                     Some(o) => backend::zcode::string_routines_example(o),
+                     None => return Err("TestCase::StringRoutines requires output!".to_string()),
+                }
+            },
+            &TestCase::ExpressionFolding => try!(check_expression_folding(cfg.clone())),
+            &TestCase::InjectUnattributed => try!(check_injected_unattributed(cfg.clone())),
         }
     }
+
+    Ok(())
+}
+
+/// Attests a passage with the routine and the bytes of `Zfile::inject_unattributed` and checks
+/// that the manifest flags both.
+fn check_injected_unattributed(cfg: Config) -> Result<(), String> {
+    let mut input = std::io::Cursor::new("::Start\nHello World".to_string().into_bytes());
+    let manifest = attest(cfg, &mut input, &mut std::io::Cursor::new(Vec::new()));
+    let names: Vec<&str> = manifest.unattributed().iter().map(|entry| &entry.name[..]).collect();
+
+    if names == vec!["injected", backend::attest::BYTES_NAME] {
+        Ok(())
+    } else {
+        Err(format!("TestCase::InjectUnattributed: expected the injected routine and bytes to be unattributed, got\n{}", manifest))
+    }
+}
+
+/// Compiles `<<print 2 * 3 + 4>>` and checks that only the folded result is printed.
+fn check_expression_folding(cfg: Config) -> Result<(), String> {
+    use backend::zcode::zfile::ZOP;
+
+    let mut input = std::io::Cursor::new("::Start\n<<print 2 * 3 + 4>>".to_string().into_bytes());
+    let passages = compiled_ops(cfg, &mut input);
+    let ops = &passages[0].1;
+
+    let folded = ops.iter().any(|op| match op {
+        &ZOP::Print{ref text} => text == "10",
+        _ => false
+    });
+    let computed = ops.iter().any(|op| match op {
+        &ZOP::Add{..} | &ZOP::Mul{..} => true,
+        _ => false
+    });
+
+    if folded && !computed {
+        Ok(())
+    } else {
+        Err(format!("TestCase::ExpressionFolding: 2 * 3 + 4 was not folded into 10, got {:?}", ops))
+    }
 }
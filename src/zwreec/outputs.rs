@@ -0,0 +1,220 @@
+//! Plans which inputs of `zwreec` are compiled to which output files.
+//!
+//! Without `--batch` all inputs form one story, with it every input is a story of its own and
+//! gets an output named after it. `-o` names a file, a directory or stdout.
+//!
+//! # Example
+//!
+//! ```
+//! use zwreec::outputs::{InputMode, OutputTarget, plan_outputs};
+//!
+//! let inputs = vec!["one.twee".to_string(), "two.twee".to_string()];
+//! let plan = plan_outputs(&inputs, OutputTarget::Directory("build/".to_string()), InputMode::Batch).unwrap();
+//! assert_eq!(plan[1], (vec!["two.twee".to_string()], Some("build/two.z8".to_string())));
+//! ```
+
+use std::fmt;
+use std::path::Path;
+
+/// How several inputs are compiled.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum InputMode {
+    /// One input, or stdin without any input
+    Single,
+
+    /// The passages of all inputs form one story
+    Concatenate,
+
+    /// Every input is a story of its own
+    Batch,
+}
+
+/// Where `-o` points to.
+#[derive(Debug, Clone, PartialEq)]
+pub enum OutputTarget {
+    /// No `-o` given
+    Default,
+
+    /// `-o -`
+    Stdout,
+
+    /// A file, which may exist already
+    File(String),
+
+    /// An existing directory or a path ending with a separator
+    Directory(String),
+}
+
+/// The reasons why the outputs for the inputs can not be planned.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PlanError {
+    /// Batch mode needs an output for every input, so `-o` must not name a file
+    BatchOutputIsFile(String),
+
+    /// Batch mode can not write several stories to stdout
+    BatchOutputIsStdout,
+
+    /// Batch mode without any input
+    BatchWithoutInput,
+
+    /// Two inputs of a batch get the same output file
+    DuplicateOutput(String),
+}
+
+impl fmt::Display for PlanError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &PlanError::BatchOutputIsFile(ref path) => write!(f,
+                "-o {} names a file, but --batch writes one story per input. Use a directory like '-o {}/' instead", path, path),
+            &PlanError::BatchOutputIsStdout => write!(f, "--batch writes one story per input and can not write to stdout"),
+            &PlanError::BatchWithoutInput => write!(f, "--batch needs at least one INPUT"),
+            &PlanError::DuplicateOutput(ref path) => write!(f,
+                "Several inputs would be compiled to {}, rename them or compile them separately", path),
+        }
+    }
+}
+
+/// Returns true if the path ends with a separator, Windows separators included.
+pub fn ends_with_separator(path: &str) -> bool {
+    path.ends_with('/') || path.ends_with('\\')
+}
+
+/// Classifies the argument of `-o`, a path is a directory if it exists as one or ends with a
+/// separator.
+pub fn output_target(opt_o: Option<String>) -> OutputTarget {
+    match opt_o {
+        None => OutputTarget::Default,
+        Some(ref name) if name == "-" => OutputTarget::Stdout,
+        Some(name) => if ends_with_separator(&name) || Path::new(&name).is_dir() {
+            OutputTarget::Directory(name)
+        } else {
+            OutputTarget::File(name)
+        }
+    }
+}
+
+/// Returns the output name for an input, like `story.z8` for `stories\story.twee`.
+pub fn output_name(input: &str) -> String {
+    let file_name = match input.rfind(|c: char| c == '/' || c == '\\') {
+        Some(index) => &input[index + 1..],
+        None => input
+    };
+    let stem = match file_name.rfind('.') {
+        Some(index) if index > 0 => &file_name[..index],
+        _ => file_name
+    };
+    format!("{}.z8", stem)
+}
+
+/// Joins the directory and the file name with the separator the directory uses.
+pub fn join_path(directory: &str, name: &str) -> String {
+    if ends_with_separator(directory) {
+        format!("{}{}", directory, name)
+    } else if directory.contains('\\') && !directory.contains('/') {
+        format!("{}\\{}", directory, name)
+    } else {
+        format!("{}/{}", directory, name)
+    }
+}
+
+/// Plans which inputs are compiled to which output.
+///
+/// Returns the inputs of every story together with its output path, `None` is stdout. Without
+/// any input the story is read from stdin. A directory gets the outputs named after the inputs,
+/// a merged story is named after its first input. Without `-o` the single or merged story is
+/// written to `a.z8` and the stories of a batch are named after their inputs.
+pub fn plan_outputs(inputs: &[String], target: OutputTarget, mode: InputMode) -> Result<Vec<(Vec<String>, Option<String>)>, PlanError> {
+    if mode != InputMode::Batch {
+        let output = match target {
+            OutputTarget::Default => Some("a.z8".to_string()),
+            OutputTarget::Stdout => None,
+            OutputTarget::File(path) => Some(path),
+            OutputTarget::Directory(directory) => {
+                let name = inputs.first().map(|input| output_name(input)).unwrap_or("a.z8".to_string());
+                Some(join_path(&directory, &name))
+            }
+        };
+        return Ok(vec![(inputs.to_vec(), output)]);
+    }
+
+    if inputs.is_empty() {
+        return Err(PlanError::BatchWithoutInput);
+    }
+
+    let mut plan: Vec<(Vec<String>, Option<String>)> = Vec::new();
+    for input in inputs.iter() {
+        let output = match target {
+            OutputTarget::Default => output_name(input),
+            OutputTarget::Stdout => return Err(PlanError::BatchOutputIsStdout),
+            OutputTarget::File(ref path) => return Err(PlanError::BatchOutputIsFile(path.clone())),
+            OutputTarget::Directory(ref directory) => join_path(directory, &output_name(input)),
+        };
+        if plan.iter().any(|&(_, ref planned)| planned.as_ref() == Some(&output)) {
+            return Err(PlanError::DuplicateOutput(output));
+        }
+        plan.push((vec![input.clone()], Some(output)));
+    }
+    Ok(plan)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{InputMode, OutputTarget, PlanError, output_name, output_target, plan_outputs};
+
+    fn strings(values: &[&str]) -> Vec<String> {
+        values.iter().map(|value| value.to_string()).collect()
+    }
+
+    fn outputs(inputs: &[&str], target: OutputTarget, mode: InputMode) -> Result<Vec<Option<String>>, PlanError> {
+        plan_outputs(&strings(inputs), target, mode).map(|plan| plan.into_iter().map(|(_, output)| output).collect())
+    }
+
+    #[test]
+    fn test_output_name() {
+        assert_eq!(output_name("story.twee"), "story.z8");
+        assert_eq!(output_name("stories/the.end.twee"), "the.end.z8");
+        assert_eq!(output_name("C:\\stories\\story.tw"), "story.z8");
+        assert_eq!(output_name("./.hidden"), ".hidden.z8");
+        assert_eq!(output_name("story"), "story.z8");
+    }
+
+    #[test]
+    fn test_output_target() {
+        assert_eq!(output_target(None), OutputTarget::Default);
+        assert_eq!(output_target(Some("-".to_string())), OutputTarget::Stdout);
+        assert_eq!(output_target(Some("build/".to_string())), OutputTarget::Directory("build/".to_string()));
+        assert_eq!(output_target(Some("build\\".to_string())), OutputTarget::Directory("build\\".to_string()));
+        assert_eq!(output_target(Some("not-a-directory.z8".to_string())), OutputTarget::File("not-a-directory.z8".to_string()));
+    }
+
+    #[test]
+    fn test_plan_single_and_merged() {
+        let file = || OutputTarget::File("out.z8".to_string());
+        assert_eq!(outputs(&["a.twee"], OutputTarget::Default, InputMode::Single), Ok(vec![Some("a.z8".to_string())]));
+        assert_eq!(outputs(&[], OutputTarget::Stdout, InputMode::Single), Ok(vec![None]));
+        assert_eq!(outputs(&["a.twee"], file(), InputMode::Single), Ok(vec![Some("out.z8".to_string())]));
+        assert_eq!(outputs(&["x/intro.twee", "x/end.twee"], file(), InputMode::Concatenate), Ok(vec![Some("out.z8".to_string())]));
+        assert_eq!(outputs(&["x/intro.twee", "x/end.twee"], OutputTarget::Directory("build".to_string()), InputMode::Concatenate),
+            Ok(vec![Some("build/intro.z8".to_string())]));
+        assert_eq!(outputs(&[], OutputTarget::Directory("build\\".to_string()), InputMode::Single), Ok(vec![Some("build\\a.z8".to_string())]));
+
+        // the merged story reads all inputs
+        let plan = plan_outputs(&strings(&["intro.twee", "end.twee"]), OutputTarget::Default, InputMode::Concatenate).unwrap();
+        assert_eq!(plan, vec![(strings(&["intro.twee", "end.twee"]), Some("a.z8".to_string()))]);
+    }
+
+    #[test]
+    fn test_plan_batch() {
+        let inputs = ["one.twee", "more\\two.twee"];
+        assert_eq!(outputs(&inputs, OutputTarget::Default, InputMode::Batch),
+            Ok(vec![Some("one.z8".to_string()), Some("two.z8".to_string())]));
+        assert_eq!(outputs(&inputs, OutputTarget::Directory("C:\\build".to_string()), InputMode::Batch),
+            Ok(vec![Some("C:\\build\\one.z8".to_string()), Some("C:\\build\\two.z8".to_string())]));
+        assert_eq!(outputs(&inputs, OutputTarget::File("out.z8".to_string()), InputMode::Batch),
+            Err(PlanError::BatchOutputIsFile("out.z8".to_string())));
+        assert_eq!(outputs(&inputs, OutputTarget::Stdout, InputMode::Batch), Err(PlanError::BatchOutputIsStdout));
+        assert_eq!(outputs(&[], OutputTarget::Default, InputMode::Batch), Err(PlanError::BatchWithoutInput));
+        assert_eq!(outputs(&["a/story.twee", "b/story.twee"], OutputTarget::Default, InputMode::Batch),
+            Err(PlanError::DuplicateOutput("story.z8".to_string())));
+    }
+}
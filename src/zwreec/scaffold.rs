@@ -0,0 +1,157 @@
+//! Creates the files of a new story project for `zwreec --init`.
+//!
+//! A project is a directory with the Twee files `main.twee` and `snippets.twee` and the options
+//! of the story in `zwreec.config`. The passages of both Twee files form one story when they are
+//! compiled together, the comments in the files show the call of zwreec that builds it, see
+//! `build_command`. The title of the story is the name of the directory.
+//!
+//! # Example
+//!
+//! ```
+//! # extern crate zwreec;
+//! use std::env;
+//!
+//! let directory = env::temp_dir().join("zwreec-scaffold-example");
+//! let files = zwreec::scaffold::init_project(&directory, true).unwrap();
+//! assert_eq!(files.len(), zwreec::scaffold::TEMPLATES.len());
+//!
+//! // without force the files of a project are never overwritten
+//! assert!(zwreec::scaffold::init_project(&directory, false).is_err());
+//! ```
+
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// The main file with the passages every story needs, `{title}` is replaced by the title and
+/// `{build}` by the `build_command`.
+pub static MAIN_TWEE: &'static str = "::StoryTitle
+{title}
+
+::StoryAuthor
+Anonymous
+
+::Start
+/% Build the story with
+
+     {build}
+
+   The passages of all inputs form one story. zwreec.config holds the options of the story
+   with their defaults, zwreec --help -v lists all of them. %/
+You wake up in a small room. <<set $gold to 3>><<purse>>
+[[Look around|Room]]
+
+::Room
+There is a door in front of you.
+[[Open the door|Outside]]
+[[Go back to bed|Start]]
+
+::Outside [ending]
+The sun is shining. <<purse>>
+The End.
+";
+
+/// The file with the widgets the passages of `main.twee` call.
+pub static SNIPPETS_TWEE: &'static str = "::Widgets [widget]
+/% A widget is called like a macro from every passage of the story. The widgets can be in any
+   of the inputs, zwreec looks them up before it reads the first passage. %/
+<<widget \"purse\">>You have <<print $gold>> <<print plural($gold, \"coin\", \"coins\")>>.<<endwidget>>
+";
+
+/// The options of the story for `--config`, with the defaults commented out.
+pub static ZWREEC_CONFIG: &'static str = "# The options zwreec reads with --config zwreec.config, as on the command line.
+# Every line holds an option and its value, a # starts a comment. An option can be given
+# here or on the command line, not in both. The lines below show the defaults, remove
+# the # in front of one to change it.
+
+# the passage the story begins with
+# --start-passage Start
+
+# 'links' waits for a link to be chosen, 'none' quits after the start passage
+# --main-loop links
+
+# the story format of the passages: legacy (Twee 1.4), harlowe or sugarcube
+# --dialect legacy
+
+# 7 for interpreters without support for version 8
+# --zcode-version 8
+
+# the length of the line above the links, 0 fits it to the screen
+# --separator-length 39
+
+# the same story file for the same source
+# --reproducible
+
+# also show the warnings about style
+# --pedantic
+";
+
+/// The names of the files of a project with their content.
+pub static TEMPLATES: &'static [(&'static str, &'static str)] = &[
+    ("main.twee", MAIN_TWEE),
+    ("snippets.twee", SNIPPETS_TWEE),
+    ("zwreec.config", ZWREEC_CONFIG),
+];
+
+/// The errors of creating a project.
+#[derive(Debug)]
+#[allow(missing_docs)]
+pub enum InitError {
+    /// A file of the project exists already and `force` is not set
+    FileExists { path: PathBuf },
+
+    /// The directory or a file can not be written
+    Io { path: PathBuf, message: String },
+}
+
+impl fmt::Display for InitError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &InitError::FileExists { ref path } =>
+                write!(f, "The file {} exists already, use --force-init to overwrite it", path.display()),
+            &InitError::Io { ref path, ref message } =>
+                write!(f, "Couldn't write {}: {}", path.display(), message),
+        }
+    }
+}
+
+/// Creates the directory with the files of a new project and returns their paths.
+///
+/// Nothing is written if one of the files exists, unless `force` is set. A directory that
+/// exists already is kept, only the files of the project are added.
+pub fn init_project(directory: &Path, force: bool) -> Result<Vec<PathBuf>, InitError> {
+    let paths: Vec<PathBuf> = TEMPLATES.iter().map(|&(name, _)| directory.join(name)).collect();
+    if !force {
+        if let Some(path) = paths.iter().find(|path| path.exists()) {
+            return Err(InitError::FileExists { path: path.clone() });
+        }
+    }
+
+    if let Err(why) = fs::create_dir_all(directory) {
+        return Err(InitError::Io { path: directory.to_path_buf(), message: Error::description(&why).to_string() });
+    }
+
+    let title = project_title(directory);
+    for (path, &(_, template)) in paths.iter().zip(TEMPLATES.iter()) {
+        let content = template.replace("{title}", &title).replace("{build}", &build_command(&title));
+        if let Err(why) = fs::File::create(path).and_then(|mut file| file.write_all(content.as_bytes())) {
+            return Err(InitError::Io { path: path.clone(), message: Error::description(&why).to_string() });
+        }
+    }
+    Ok(paths)
+}
+
+/// Returns the call of zwreec that builds the story with the title in its project directory.
+pub fn build_command(title: &str) -> String {
+    format!("zwreec --config zwreec.config -o {}.z8 main.twee snippets.twee", title)
+}
+
+/// Returns the title of the story in the directory, which is the name of the directory.
+pub fn project_title(directory: &Path) -> String {
+    match directory.file_name() {
+        Some(name) => name.to_string_lossy().into_owned(),
+        None => "My Story".to_string()
+    }
+}
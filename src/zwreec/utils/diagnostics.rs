@@ -0,0 +1,361 @@
+//! Collection of the warnings of the compiler
+//!
+//! Every warning has a stable id, which is printed with it and which authors use to allow it
+//! with `<<zwreec-allow id>>` in the source. A `<<zwreec-allow>>` before any other content of a
+//! passage allows the warning in the whole passage, everywhere else it is allowed on the line of
+//! the macro and the next one. Allowed warnings are only counted, `-v` lists them.
+//!
+//! Every warning has a default `Level`. The pedantic warnings of the `Info` level are only
+//! shown with `--pedantic`. On the command line `-A id` allows a warning in the whole story,
+//! `-W id` shows it and `-D id` denies it, which reports it as an error. `--list-lints` lists
+//! the warnings with their default levels.
+
+use std::sync::Arc;
+
+use frontend::lexer::SourceMap;
+
+/// The warnings that can be allowed with `<<zwreec-allow>>`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Warning {
+    /// All links of a passage are inside of `<<if>>` blocks
+    ConditionalLinks,
+
+    /// A passage prints nothing and has no links
+    DeadEnd,
+
+    /// The key of a map access is in none of the map literals
+    MissingMapKey,
+
+    /// A `/%` comment is not closed before the next passage, which closes it
+    OpenComment,
+
+    /// A passage needs more bytes than `--warn-passage-size`
+    PassageSize,
+
+    /// The constant arguments of `substr()` are out of the bounds of the string
+    SubstrBounds,
+
+    /// The name in a `<<zwreec-allow>>` is not the id of a warning
+    UnknownWarning,
+
+    /// No link, `<<display>>` or option leads to a passage
+    UnlinkedPassage,
+
+    /// Content of a passage comes after a return, like the one of `<<countdown>>`
+    UnreachableContent,
+}
+
+/// All warnings in the order of their documentation.
+pub static WARNINGS: &'static [Warning] = &[
+    Warning::ConditionalLinks, Warning::DeadEnd, Warning::MissingMapKey, Warning::OpenComment, Warning::PassageSize, Warning::SubstrBounds, Warning::UnknownWarning,
+    Warning::UnlinkedPassage, Warning::UnreachableContent];
+
+/// What is done with a warning.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Level {
+    /// The warning is not shown
+    Allow,
+
+    /// The warning is only shown with `--pedantic`
+    Info,
+
+    /// The warning is shown
+    Warning,
+
+    /// The warning is reported as an error
+    Error,
+}
+
+impl Level {
+    /// Returns the name of the level in `--list-lints`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            &Level::Allow => "allow",
+            &Level::Info => "pedantic",
+            &Level::Warning => "warn",
+            &Level::Error => "deny",
+        }
+    }
+}
+
+impl Warning {
+    /// Returns the id of the warning used in `<<zwreec-allow>>`.
+    pub fn id(&self) -> &'static str {
+        match self {
+            &Warning::ConditionalLinks => "conditional-links",
+            &Warning::DeadEnd => "dead-end",
+            &Warning::MissingMapKey => "missing-map-key",
+            &Warning::OpenComment => "open-comment",
+            &Warning::PassageSize => "passage-size",
+            &Warning::SubstrBounds => "substr-bounds",
+            &Warning::UnknownWarning => "unknown-warning",
+            &Warning::UnlinkedPassage => "unlinked-passage",
+            &Warning::UnreachableContent => "unreachable-content",
+        }
+    }
+
+    /// Returns the level of the warning unless the command line sets another one.
+    pub fn default_level(&self) -> Level {
+        match self {
+            &Warning::UnlinkedPassage => Level::Info,
+            _ => Level::Warning
+        }
+    }
+
+    /// Returns what the warning is about in `--list-lints`.
+    pub fn description(&self) -> &'static str {
+        match self {
+            &Warning::ConditionalLinks => "All links of a passage are inside of <<if>> blocks",
+            &Warning::DeadEnd => "A passage prints nothing and has no links",
+            &Warning::MissingMapKey => "The key of a map access is in none of the map literals, the access returns 0",
+            &Warning::OpenComment => "A /% comment is not closed before the next passage, which closes it",
+            &Warning::PassageSize => "A passage needs more bytes than --warn-passage-size",
+            &Warning::SubstrBounds => "The constant arguments of substr() are out of the bounds of the string",
+            &Warning::UnknownWarning => "The name in a <<zwreec-allow>> is not the id of a warning",
+            &Warning::UnlinkedPassage => "No link, <<display>> or option leads to a passage",
+            &Warning::UnreachableContent => "Content of a passage comes after a return, like the one of <<countdown>>",
+        }
+    }
+
+    /// Returns the warning with the id.
+    pub fn from_id(id: &str) -> Option<Warning> {
+        WARNINGS.iter().find(|warning| warning.id() == id).map(|warning| *warning)
+    }
+}
+
+/// Returns the list of `--list-lints`, a line with the id, the default level and the
+/// description of every warning.
+pub fn lint_list() -> String {
+    WARNINGS.iter().map(|warning| format!("{:<20} {:<9} {}\n", warning.id(), warning.default_level().name(), warning.description())).collect()
+}
+
+/// A warning that occurred in a passage.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    /// The kind of warning
+    pub warning: Warning,
+
+    /// The level of the warning when it occurred
+    pub level: Level,
+
+    /// The passage the warning occurred in
+    pub passage: String,
+
+    /// The line the warning occurred on, `None` for warnings about the whole passage
+    pub line: Option<u64>,
+
+    /// The offset of the first byte of the line, `None` without a line or a source map
+    pub byte_offset: Option<u64>,
+
+    /// The text printed for the warning
+    pub message: String,
+}
+
+/// A warning allowed by `<<zwreec-allow>>`.
+#[derive(Debug, Clone)]
+struct Allow {
+    warning: Warning,
+    passage: String,
+
+    /// The line of the macro, `None` if it allows the warning in the whole passage
+    line: Option<u64>,
+}
+
+impl Allow {
+    /// Checks if this allows the diagnostic, a line allows it on its line and the next one.
+    fn allows(&self, diagnostic: &Diagnostic) -> bool {
+        self.warning == diagnostic.warning && self.passage == diagnostic.passage && match (self.line, diagnostic.line) {
+            (None, _) => true,
+            (Some(allowed), Some(line)) => line == allowed || line == allowed + 1,
+            (Some(_), None) => false
+        }
+    }
+}
+
+/// The collector of all warnings of a compilation.
+pub struct Diagnostics {
+    /// The warnings allowed so far
+    allows: Vec<Allow>,
+
+    /// The levels of the command line, later ones win
+    levels: Vec<(Warning, Level)>,
+
+    /// Whether the warnings of the `Info` level are shown
+    pedantic: bool,
+
+    /// The warnings that were printed
+    shown: Vec<Diagnostic>,
+
+    /// The warnings that were allowed and not printed
+    suppressed: Vec<Diagnostic>,
+
+    /// The warnings of the `Error` level, which are reported as errors
+    denied: Vec<Diagnostic>,
+
+    /// The map of the lines to byte offsets
+    source_map: Option<Arc<SourceMap>>,
+}
+
+impl Diagnostics {
+    /// Creates an empty collector with the default levels.
+    pub fn new() -> Diagnostics {
+        Diagnostics::with_levels(&[], false)
+    }
+
+    /// Creates an empty collector with the levels of `-A`, `-W` and `-D`, with `pedantic`
+    /// the warnings of the `Info` level are shown.
+    pub fn with_levels(levels: &[(Warning, Level)], pedantic: bool) -> Diagnostics {
+        Diagnostics { allows: vec![], levels: levels.to_vec(), pedantic: pedantic, shown: vec![], suppressed: vec![], denied: vec![], source_map: None }
+    }
+
+    /// Sets the map the byte offsets of the lines of the warnings are looked up in.
+    pub fn with_source_map(mut self, source_map: Option<Arc<SourceMap>>) -> Diagnostics {
+        self.source_map = source_map;
+        self
+    }
+
+    /// Returns the level of the warning, the last one set on the command line or the default.
+    pub fn level(&self, warning: Warning) -> Level {
+        let level = self.levels.iter().rev().find(|&&(set, _)| set == warning).map(|&(_, level)| level);
+        level.unwrap_or(warning.default_level())
+    }
+
+    /// Allows the warning in the passage, on the line and the next one or in the whole passage
+    /// if `line` is `None`.
+    pub fn allow(&mut self, warning: Warning, passage: &str, line: Option<u64>) {
+        self.allows.push(Allow { warning: warning, passage: passage.to_string(), line: line });
+    }
+
+    /// Prints the warning with its id, unless it is allowed.
+    ///
+    /// `<<zwreec-allow>>` wins over the level of the command line. Denied warnings are not
+    /// printed here, the caller reports the ones of `denied()` as errors.
+    pub fn warn(&mut self, warning: Warning, passage: &str, line: Option<u64>, message: String) {
+        let level = self.level(warning);
+        let byte_offset = match (line, self.source_map.as_ref()) {
+            (Some(line), Some(source_map)) => Some(source_map.location((line, 1)).byte_offset),
+            _ => None
+        };
+        let diagnostic = Diagnostic { warning: warning, level: level, passage: passage.to_string(), line: line, byte_offset: byte_offset, message: message };
+        if self.allows.iter().any(|allow| allow.allows(&diagnostic)) {
+            self.suppressed.push(diagnostic);
+            return;
+        }
+        match level {
+            Level::Allow => {},
+            Level::Info if !self.pedantic => {},
+            Level::Info | Level::Warning => {
+                warn!("{} [{}]", diagnostic.message, warning.id());
+                self.shown.push(diagnostic);
+            },
+            Level::Error => self.denied.push(diagnostic)
+        }
+    }
+
+    /// Returns the warnings that were printed.
+    pub fn shown(&self) -> &[Diagnostic] {
+        &self.shown
+    }
+
+    /// Returns the warnings that were allowed.
+    pub fn suppressed(&self) -> &[Diagnostic] {
+        &self.suppressed
+    }
+
+    /// Returns the warnings that were denied.
+    pub fn denied(&self) -> &[Diagnostic] {
+        &self.denied
+    }
+
+    /// Prints how many warnings were allowed, and lists them at the info level.
+    pub fn report_suppressed(&self) {
+        if self.suppressed.is_empty() {
+            return;
+        }
+        warn!("{} warning(s) allowed by <<zwreec-allow>>, use -v to list them", self.suppressed.len());
+        for diagnostic in self.suppressed.iter() {
+            info!("Allowed: {} [{}]", diagnostic.message, diagnostic.warning.id());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use frontend::lexer::SourceMap;
+    use super::{lint_list, Diagnostics, Level, Warning, WARNINGS};
+
+    #[test]
+    fn test_warning_ids() {
+        for warning in WARNINGS.iter() {
+            assert_eq!(Warning::from_id(warning.id()), Some(*warning));
+        }
+        assert_eq!(Warning::from_id("no-such-warning"), None);
+    }
+
+    #[test]
+    fn test_allow_scopes() {
+        let mut diagnostics = Diagnostics::new();
+        diagnostics.allow(Warning::SubstrBounds, "Start", Some(3));
+        diagnostics.allow(Warning::PassageSize, "Start", None);
+
+        diagnostics.warn(Warning::SubstrBounds, "Start", Some(4), "next line".to_string());
+        diagnostics.warn(Warning::PassageSize, "Start", None, "whole passage".to_string());
+        diagnostics.warn(Warning::SubstrBounds, "Start", Some(5), "later line".to_string());
+        diagnostics.warn(Warning::SubstrBounds, "Other", Some(3), "other passage".to_string());
+        diagnostics.warn(Warning::ConditionalLinks, "Start", None, "other warning".to_string());
+
+        let messages = |list: &[super::Diagnostic]| list.iter().map(|d| d.message.clone()).collect::<Vec<String>>();
+        assert_eq!(messages(diagnostics.suppressed()), vec!["next line", "whole passage"]);
+        assert_eq!(messages(diagnostics.shown()), vec!["later line", "other passage", "other warning"]);
+    }
+
+    #[test]
+    fn test_byte_offsets() {
+        let source = "::Start\n\u{e4}\u{f6}\u{fc}\n\u{20ac} text\n";
+        let mut diagnostics = Diagnostics::new().with_source_map(Some(Arc::new(SourceMap::from_source(source.as_bytes(), 1))));
+        diagnostics.warn(Warning::SubstrBounds, "Start", Some(3), "on the third line".to_string());
+        diagnostics.warn(Warning::DeadEnd, "Start", None, "in the whole passage".to_string());
+        assert_eq!(diagnostics.shown()[0].byte_offset, Some(source.find("\u{20ac}").unwrap() as u64));
+        assert_eq!(diagnostics.shown()[1].byte_offset, None);
+
+        let mut without = Diagnostics::new();
+        without.warn(Warning::SubstrBounds, "Start", Some(3), "on the third line".to_string());
+        assert_eq!(without.shown()[0].byte_offset, None);
+    }
+
+    #[test]
+    fn test_levels() {
+        let levels = [(Warning::DeadEnd, Level::Allow), (Warning::SubstrBounds, Level::Error), (Warning::DeadEnd, Level::Warning)];
+        let mut diagnostics = Diagnostics::with_levels(&levels, false);
+        assert_eq!(diagnostics.level(Warning::DeadEnd), Level::Warning);
+        assert_eq!(diagnostics.level(Warning::UnlinkedPassage), Level::Info);
+
+        diagnostics.allow(Warning::SubstrBounds, "Start", None);
+        diagnostics.warn(Warning::SubstrBounds, "Start", Some(2), "allowed in the source".to_string());
+        diagnostics.warn(Warning::SubstrBounds, "Other", Some(2), "denied".to_string());
+        diagnostics.warn(Warning::DeadEnd, "Other", None, "shown again".to_string());
+        diagnostics.warn(Warning::UnlinkedPassage, "Other", None, "pedantic".to_string());
+
+        let messages = |list: &[super::Diagnostic]| list.iter().map(|d| d.message.clone()).collect::<Vec<String>>();
+        assert_eq!(messages(diagnostics.suppressed()), vec!["allowed in the source"]);
+        assert_eq!(messages(diagnostics.denied()), vec!["denied"]);
+        assert_eq!(messages(diagnostics.shown()), vec!["shown again"]);
+
+        let mut pedantic = Diagnostics::with_levels(&[(Warning::DeadEnd, Level::Allow)], true);
+        pedantic.warn(Warning::UnlinkedPassage, "Other", None, "pedantic".to_string());
+        pedantic.warn(Warning::DeadEnd, "Other", None, "allowed".to_string());
+        assert_eq!(messages(pedantic.shown()), vec!["pedantic"]);
+    }
+
+    #[test]
+    fn test_lint_list() {
+        let list = lint_list();
+        assert_eq!(list.lines().count(), WARNINGS.len());
+        for warning in WARNINGS.iter() {
+            assert!(list.lines().any(|line| line.starts_with(&format!("{} ", warning.id()))), "{} is not listed", warning.id());
+        }
+        assert!(list.contains("unlinked-passage     pedantic"));
+    }
+}
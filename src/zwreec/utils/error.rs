@@ -3,15 +3,29 @@
 //! This module contains Display implementations for the errors defined in the core modules (frontend/backend),
 //! allowing to log them easily. Also contains macros to log and panic - depending on the Config module - because of an error,
 //! making the actual error handling code in the other modules much shorter and easier to use.
+//!
+//! Every reported error is kept as an `ErrorRecord` in the config. With `--error-format json`
+//! the record is printed as one JSON object per line instead of the log message:
+//!
+//! ```text
+//! {"file":"story.twee","line":2,"col":12,"severity":"error","message":"Unexpected <<else>> without <<if>> at 2:12","code":"ParserError::UnexpectedConditional"}
+//! ```
 
-use std::fmt::{Display, Formatter, Result, Write};
+use std::fmt::{Debug, Display, Formatter, Result, Write};
+use std::io;
+
+use config::{Config, ErrorFormat};
 
 use frontend::lexer::Token;
 use frontend::lexer::LexerError;
 use frontend::parser::ParserError;
 use frontend::expressionparser::ExpressionParserError;
+use frontend::screener::ScreenerError;
+use frontend::widgets::WidgetError;
 use frontend::evaluate_expression::EvaluateExpressionError;
 use backend::codegen::CodeGenError;
+use config::FEATURES;
+use utils::json;
 
 /// Report an error
 ///
@@ -38,16 +52,24 @@ use backend::codegen::CodeGenError;
 ///
 /// # Panics
 ///
-/// If the config does not specify the `force` option a panic is generated
+/// If the config does not specify the `force` option a panic is generated, its message ends with
+/// the message of the error. With `force` set it still panics as soon as more errors than
+/// `max_errors` were reported.
 #[macro_export]
 macro_rules! error_panic(
     ($cfg:expr => $($arg:tt)+) => (
         {
             if !$cfg.force {
-                error!("{}", $($arg)*);
-                panic!("Config is set to panic at any error. Try setting the --force flag to ignore this and other errors.")
+                let error = &($($arg)*);
+                $crate::utils::error::report(&$cfg, error, false);
+                panic!("Config is set to panic at any error. Try setting the --force flag to ignore this and other errors: {}",
+                    $crate::utils::error::ErrorRecord::new(error, None).message)
             } else {
-                warn!("{}", $($arg)*);
+                $crate::utils::error::report(&$cfg, &($($arg)*), true);
+                if $cfg.report_error() {
+                    error!("Too many errors ({}), aborting.", $cfg.error_count());
+                    panic!("Reached the maximum number of errors. Use --max-errors to raise the limit.")
+                }
             }
         }
     )
@@ -55,9 +77,12 @@ macro_rules! error_panic(
 
 /// Report an error and panic in any case
 ///
+/// With a config the error is reported like in `error_panic!`, so it is kept and printed in the
+/// `error_format` of the config.
+///
 /// # Syntax
 ///
-/// `error_panic!(MyError)`
+/// `error_force_panic!(MyError)` or `error_force_panic!(cfg => MyError)`
 ///
 /// # Example
 ///
@@ -67,14 +92,23 @@ macro_rules! error_panic(
 /// # use zwreec::frontend::expressionparser::ExpressionParserError;
 /// # fn main() {
 /// let cfg = zwreec::config::Config::default_config();
-/// error_force_panic!(ExpressionParserError::OperStackIsEmpty);
+/// error_force_panic!(cfg => ExpressionParserError::OperStackIsEmpty);
 /// # }
 /// ```
 /// # Panics
 ///
-/// This macro panics and does not return
+/// This macro panics and does not return. With a config the panic message ends with the message
+/// of the error.
 #[macro_export]
 macro_rules! error_force_panic(
+    ($cfg:expr => $($arg:tt)+) => (
+        {
+            let error = &($($arg)*);
+            $crate::utils::error::report(&$cfg, error, false);
+            panic!("Can't continue. This error is not recoverable and not ignorable through --force: {}",
+                $crate::utils::error::ErrorRecord::new(error, None).message);
+        }
+    );
     ($($arg:tt)+) => (
         {
             error!("{}", $($arg)*);
@@ -83,10 +117,234 @@ macro_rules! error_force_panic(
     )
 );
 
+/// The details of an error besides its message.
+pub trait ErrorDetails: Display + Debug {
+    /// Returns the name of the type of the error, like `ParserError`.
+    fn error_type(&self) -> &'static str;
+
+    /// Returns the line and column the error refers to.
+    fn location(&self) -> Option<(u64, u64)> {
+        None
+    }
+
+    /// Returns the stable code of the error, the type and the name of the variant like
+    /// `ParserError::UnexpectedConditional`.
+    fn code(&self) -> String {
+        format!("{}::{}", self.error_type(), variant_name(self))
+    }
+}
+
+/// Returns the name of the enum variant from the `Debug` output of the error.
+fn variant_name<E: Debug + ?Sized>(error: &E) -> String {
+    format!("{:?}", error).chars().take_while(|c| c.is_alphanumeric()).collect()
+}
+
+/// A reported error.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ErrorRecord {
+    /// The input file, `None` for stdin
+    pub file: Option<String>,
+
+    /// The line and column the error refers to
+    pub location: Option<(u64, u64)>,
+
+    /// The offset of the byte the location refers to, from the start of the input
+    pub byte_offset: Option<u64>,
+
+    /// Always `"error"`, even if `--force` goes on behind it
+    pub severity: &'static str,
+
+    /// The message without the header of the log message
+    pub message: String,
+
+    /// The code of the error, see `ErrorDetails::code()`
+    pub code: String,
+}
+
+impl ErrorRecord {
+    /// Creates the record of an error in the input file `file`.
+    pub fn new<E: ErrorDetails>(error: &E, file: Option<String>) -> ErrorRecord {
+        let text = format!("{}", error);
+        // the message is the last line of the header, like "[!!!] Unexpected <<else>>"
+        let message = text.rsplit("[!!!] ").next().unwrap_or("").trim().to_string();
+        ErrorRecord {
+            file: file,
+            location: error.location(),
+            byte_offset: None,
+            severity: "error",
+            message: message,
+            code: error.code(),
+        }
+    }
+
+    /// Returns the record as a JSON object with the keys `file`, `line`, `col`, `byte_offset`,
+    /// `severity`, `message` and `code`. Unknown values are `null`.
+    pub fn to_json(&self) -> String {
+        let (line, col) = match self.location {
+            Some((line, col)) => (line.to_string(), col.to_string()),
+            None => ("null".to_string(), "null".to_string())
+        };
+        json::object(&[
+            ("file", self.file.as_ref().map(|file| json::string(file)).unwrap_or("null".to_string())),
+            ("line", line),
+            ("col", col),
+            ("byte_offset", self.byte_offset.map(|offset| offset.to_string()).unwrap_or("null".to_string())),
+            ("severity", json::string(self.severity)),
+            ("message", json::string(&self.message)),
+            ("code", json::string(&self.code)),
+        ])
+    }
+}
+
+/// Prints an error in the `error_format` of the config and keeps its record.
+///
+/// Used by `error_panic!` and `error_force_panic!`, `recovered` is true if the compilation goes
+/// on because of `force`. The text of a recovered error is logged as a warning.
+pub fn report<E: ErrorDetails>(cfg: &Config, error: &E, recovered: bool) {
+    let mut record = ErrorRecord::new(error, cfg.source_name.clone());
+    record.byte_offset = record.location.and_then(|location| cfg.byte_offset(location));
+    match cfg.error_format {
+        ErrorFormat::Text => if recovered {
+            warn!("{}", error);
+        } else {
+            error!("{}", error);
+        },
+        ErrorFormat::Json => {
+            let _ = io::Write::write_fmt(&mut io::stderr(), format_args!("{}\n", record.to_json()));
+        }
+    }
+    cfg.record_error(record);
+}
+
+impl ErrorDetails for Token {
+    fn error_type(&self) -> &'static str {
+        "LexerError"
+    }
+
+    fn location(&self) -> Option<(u64, u64)> {
+        Some(Token::location(self))
+    }
+}
+
+impl ErrorDetails for LexerError {
+    fn error_type(&self) -> &'static str {
+        "LexerError"
+    }
+
+    fn location(&self) -> Option<(u64, u64)> {
+        match self {
+            &LexerError::UnexpectedCharacter { location, .. } => Some(location)
+        }
+    }
+}
+
+impl ErrorDetails for ScreenerError {
+    fn error_type(&self) -> &'static str {
+        "ScreenerError"
+    }
+}
+
+impl ErrorDetails for ParserError {
+    fn error_type(&self) -> &'static str {
+        "ParserError"
+    }
+
+    fn location(&self) -> Option<(u64, u64)> {
+        match self {
+            &ParserError::TokenDoNotMatch { token: Some(ref token), .. } |
+            &ParserError::StackIsEmpty { ref token } |
+            &ParserError::NoProjection { ref token, .. } |
+            &ParserError::UnexpectedConditional { ref token } |
+            &ParserError::MissingAssignedValue { ref token } => Some(token.location()),
+            &ParserError::UnclosedParenthesis { open, .. } => Some(open),
+            _ => None
+        }
+    }
+}
+
+impl ErrorDetails for ExpressionParserError {
+    fn error_type(&self) -> &'static str {
+        "ExpressionParserError"
+    }
+
+    fn location(&self) -> Option<(u64, u64)> {
+        match self {
+            &ExpressionParserError::DisallowedOperator { ref op } => Some(op.location()),
+            &ExpressionParserError::MissingTernaryElse { location } |
+            &ExpressionParserError::MissingLeftOperand { location, .. } |
+            &ExpressionParserError::MissingRightOperand { location, .. } => Some(location),
+            _ => None
+        }
+    }
+}
+
+impl ErrorDetails for CodeGenError {
+    fn error_type(&self) -> &'static str {
+        "CodeGenError"
+    }
+
+    fn location(&self) -> Option<(u64, u64)> {
+        match self {
+            &CodeGenError::NoMatch { ref token } |
+            &CodeGenError::UnsupportedExpression { ref token } |
+            &CodeGenError::UnsupportedIfExpression { ref token } |
+            &CodeGenError::UnsupportedElseIfExpression { ref token } |
+            &CodeGenError::UnsupportedLongExpression { ref token, .. } => Some(token.location()),
+            &CodeGenError::PassageNameExpected { location, .. } => Some(location),
+            &CodeGenError::MultipleOnExit { second, .. } => Some(second),
+            &CodeGenError::CouldNotEvaluateExpression { ref error } => error.location(),
+            _ => None
+        }
+    }
+
+    fn code(&self) -> String {
+        // errors of expressions keep their own code
+        match self {
+            &CodeGenError::CouldNotEvaluateExpression { ref error } => error.code(),
+            _ => format!("{}::{}", self.error_type(), variant_name(self))
+        }
+    }
+}
+
+impl ErrorDetails for WidgetError {
+    fn error_type(&self) -> &'static str {
+        "WidgetError"
+    }
+
+    fn location(&self) -> Option<(u64, u64)> {
+        match self {
+            &WidgetError::OutsideWidgetPassage { location, .. } |
+            &WidgetError::DuplicateWidget { location, .. } |
+            &WidgetError::RecursiveWidget { location, .. } |
+            &WidgetError::MissingArgument { location, .. } => Some(location)
+        }
+    }
+}
+
+impl ErrorDetails for EvaluateExpressionError {
+    fn error_type(&self) -> &'static str {
+        "EvaluateExpressionError"
+    }
+
+    fn location(&self) -> Option<(u64, u64)> {
+        match self {
+            &EvaluateExpressionError::NumericOperatorNeedsTwoArguments { location, .. } |
+            &EvaluateExpressionError::UnsupportedOperator { location, .. } |
+            &EvaluateExpressionError::UnsupportedFunction { location, .. } |
+            &EvaluateExpressionError::UnsupportedFunctionArgsLen { location, .. } |
+            &EvaluateExpressionError::UnsupportedFunctionArgType { location, .. } |
+            &EvaluateExpressionError::InvalidRandomRange { location, .. } |
+            &EvaluateExpressionError::UnknownConfigName { location, .. } => Some(location),
+            &EvaluateExpressionError::UnhandledToken { ref token } => Some(token.location()),
+            _ => None
+        }
+    }
+}
+
 impl Display for Token {
     fn fmt(&self, f: &mut Formatter) -> Result {
         match self {
-            &Token::TokError{ref message, ..} => try!(f.write_str(&*message)),
+            &Token::TokError{ref message, location} => try!(f.write_fmt(format_args!("{} at {}:{}", message, location.0, location.1))),
             _ => try!(f.write_fmt(format_args!("{:?}", self))),
         };
         Ok(())
@@ -97,7 +355,10 @@ impl Display for LexerError {
     fn fmt(&self, f: &mut Formatter) -> Result {
         try!(f.write_str("[!!!] Critical Lexer Error\n[!!!] "));
         match self {
-            &LexerError::UnexpectedCharacter { character, location } => {
+            &LexerError::UnexpectedCharacter { character, location, construct: Some(construct) } => {
+                try!(f.write_fmt(format_args!("Unexpected character '{}' in a {} at {}:{}", character, construct, location.0, location.1)))
+            },
+            &LexerError::UnexpectedCharacter { character, location, construct: None } => {
                 try!(f.write_fmt(format_args!("Unexpected character '{}' at {}:{}", character, location.0, location.1)))
             }
         };
@@ -105,6 +366,21 @@ impl Display for LexerError {
     }
 }
 
+impl Display for ScreenerError {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        try!(f.write_str("[!!!] Critical Input Error\n[!!!] "));
+        match self {
+            &ScreenerError::ReadFailed { ref why } => {
+                try!(f.write_fmt(format_args!("Couldn't read the input: {}", why)))
+            },
+            &ScreenerError::InvalidUtf8 { offset } => {
+                try!(f.write_fmt(format_args!("The input is not valid UTF-8, the byte at offset {} is invalid", offset)))
+            }
+        };
+        Ok(())
+    }
+}
+
 impl Display for ParserError {
     fn fmt(&self, f: &mut Formatter) -> Result {
         try!(f.write_str("[!!!] Critical Parser Error\n[!!!] "));
@@ -117,6 +393,18 @@ impl Display for ParserError {
             &ParserError::StackIsEmpty{ref token} => try!(f.write_fmt(format_args!("Tokens left but Stack is empty. Token:{:?}", token))),
             &ParserError::NoProjection{ref token, ref stack} => try!(f.write_fmt(format_args!("No Projection found for Token:{:?} and NonTerminal:{:?}", token, stack))),
             &ParserError::NonTerminalEnd{ref stack} => try!(f.write_fmt(format_args!("NonTerminal:{:?} is no allowed End", stack))),
+            &ParserError::UnexpectedConditional{ref token} => {
+                let name = match token {
+                    &Token::TokMacroElse{..} => "<<else>>",
+                    &Token::TokMacroElseIf{..} => "<<else if>>",
+                    _ => "<<endif>>",
+                };
+                try!(f.write_fmt(format_args!("Unexpected {} without <<if>> at {}:{}", name, token.location().0, token.location().1)))
+            },
+            &ParserError::MissingAssignedValue{ref token} =>
+                try!(f.write_fmt(format_args!("Assignment without a value in front of {}:{}", token.location().0, token.location().1))),
+            &ParserError::UnclosedParenthesis{open: (line, ch), ref token} =>
+                try!(f.write_fmt(format_args!("The parenthesis opened at {}:{} is not closed in front of {}:{}", line, ch, token.location().0, token.location().1))),
         };
         Ok(())
     }
@@ -146,6 +434,15 @@ impl Display for ExpressionParserError {
             },
             &ExpressionParserError::NotImplementedOperator { ref op } => {
                 try!(f.write_fmt(format_args!("This operator is not implemented: '{}'", op)))
+            },
+            &ExpressionParserError::MissingTernaryElse { location: (line, ch) } => {
+                try!(f.write_fmt(format_args!("The conditional '?' at {}:{} has no ':'", line, ch)))
+            },
+            &ExpressionParserError::MissingLeftOperand { ref op, location: (line, ch) } => {
+                try!(f.write_fmt(format_args!("The operator '{}' at {}:{} has no operand in front of it", op, line, ch)))
+            },
+            &ExpressionParserError::MissingRightOperand { ref op, location: (line, ch) } => {
+                try!(f.write_fmt(format_args!("The operator '{}' at {}:{} has no operand behind it", op, line, ch)))
             }
         };
         Ok(())
@@ -154,6 +451,11 @@ impl Display for ExpressionParserError {
 
 impl Display for CodeGenError {
     fn fmt(&self, f: &mut Formatter) -> Result {
+        // errors of expressions bring their own header
+        if let &CodeGenError::CouldNotEvaluateExpression { ref error } = self {
+            return Display::fmt(error, f);
+        }
+
         try!(f.write_str("[!!!] Critical Codegen Error:\n[!!!] "));
         match self {
             &CodeGenError::CouldNotWriteToOutput { ref why } => {
@@ -177,12 +479,43 @@ impl Display for CodeGenError {
             &CodeGenError::NoMatch { ref token } => {
                 try!(f.write_fmt(format_args!("Can't find any AST operation for token: {}", token)))
             },
+            &CodeGenError::PassageNameExpected { ref name, index, location: (line, ch) } => {
+                try!(f.write_fmt(format_args!("Argument #{} of <<{}>> at {}:{} has to be the name of a passage in quotes", index, name, line, ch)))
+            },
             &CodeGenError::PassageDoesNotExist { ref name } => {
                 try!(f.write_fmt(format_args!("Referenced passage '{}' but the passage does not exist", name)))
             },
+            &CodeGenError::DifferentlyNormalizedPassage { ref name, ref candidate } => {
+                try!(f.write_fmt(format_args!("Referenced passage '{}' but the passage does not exist. The passage '{}' looks the same, \
+                    but its accents are other unicode characters. Save both names in the same unicode normalization form (NFC)",
+                    name, candidate)))
+            },
+            &CodeGenError::TooManyLinks { links, capacity } => {
+                try!(f.write_fmt(format_args!("A passage shows up to {} links, but the heap only has room for {} of them", links, capacity)))
+            },
+            &CodeGenError::TooManyVariables { ref name } => {
+                try!(f.write_fmt(format_args!("The variable '{}' does not fit, all 231 global variables of the story are taken already", name)))
+            },
+            &CodeGenError::StringBeyondPackedAddresses { ref text, address, version } => {
+                try!(f.write_fmt(format_args!("The string \"{}\" starts at {:#x}, which is beyond the packed addresses of Z-Machine version {} - \
+                    the story is too large", text, address, version)))
+            },
+            &CodeGenError::SourceDoesNotFit { end, limit } => {
+                try!(f.write_fmt(format_args!("The story ends at {:#x}, but the file length of the header only reaches {:#x} - \
+                    the story is too large to append its source with --embed-source", end, limit)))
+            },
+            &CodeGenError::DeniedWarning { id, ref passage, ref message } => {
+                try!(f.write_fmt(format_args!("{} [{}] in passage '{}', denied with -D {}", message, id, passage, id)))
+            },
+            &CodeGenError::UnknownMacro { ref name, ref widgets } => {
+                try!(f.write_fmt(format_args!("<<{}>> is neither a passage nor a widget, the widgets are: {}", name, widgets.join(", "))))
+            },
             &CodeGenError::InvalidAST => {
                 try!(f.write_str("Internal error: Unexpected AST node. This should not happen. Report a bug please."))
             },
+            &CodeGenError::MultipleOnExit { ref passage, first, second } => {
+                try!(f.write_fmt(format_args!("Passage '{}' has more than one <<onexit>> block, at {}:{} and at {}:{}", passage, first.0, first.1, second.0, second.1)))
+            },
             &CodeGenError::NoStartPassage => {
                 try!(f.write_str("Start passage does not exist or can not be found. Every Twee file needs a passage with the name 'Start'."))
             },
@@ -194,6 +527,28 @@ impl Display for CodeGenError {
             },
             &CodeGenError::CouldNotFindSymbolId { id } => {
                 try!(f.write_fmt(format_args!("Could not find symbol ID '{}' in symbol table. Report a bug.", id)))
+            },
+            &CodeGenError::CouldNotEvaluateExpression { .. } => {}
+        };
+        Ok(())
+    }
+}
+
+impl Display for WidgetError {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        try!(f.write_str("[!!!] Critical Widget Error\n[!!!] "));
+        match self {
+            &WidgetError::OutsideWidgetPassage { ref name, location: (line, ch) } => {
+                try!(f.write_fmt(format_args!("The widget '{}' at {}:{} is not in a passage tagged 'widget'", name, line, ch)))
+            },
+            &WidgetError::DuplicateWidget { ref name, location: (line, ch) } => {
+                try!(f.write_fmt(format_args!("The widget '{}' at {}:{} is defined already", name, line, ch)))
+            },
+            &WidgetError::RecursiveWidget { ref chain, location: (line, ch) } => {
+                try!(f.write_fmt(format_args!("The widget call at {}:{} is recursive: {}", line, ch, chain.join(" -> "))))
+            },
+            &WidgetError::MissingArgument { ref name, index, location: (line, ch) } => {
+                try!(f.write_fmt(format_args!("$args[{}] at {}:{}: the widget '{}' was called with fewer arguments", index, line, ch, name)))
             }
         };
         Ok(())
@@ -216,6 +571,13 @@ impl Display for EvaluateExpressionError {
             &EvaluateExpressionError::UnsupportedFunctionArgType { ref name, index, location: (line, ch) } => {
                 try!(f.write_fmt(format_args!("Function '{}' at {}:{}: Unsupported argument type at argument #{}", name, line, ch, index)))
             }
+            &EvaluateExpressionError::InvalidRandomRange { from, to, location: (line, ch) } => {
+                try!(f.write_fmt(format_args!("Function 'random' at {}:{}: The range from {} to {} is empty, the first argument has to be the lower bound", line, ch, from, to)))
+            }
+            &EvaluateExpressionError::UnknownConfigName { ref name, location: (line, ch) } => {
+                let names: Vec<&str> = FEATURES.iter().map(|feature| feature.name).collect();
+                try!(f.write_fmt(format_args!("Function 'config' at {}:{}: '{}' is not a feature, use one of: {}", line, ch, name, names.join(", "))))
+            }
             &EvaluateExpressionError::InvalidAST => {
                 try!(f.write_str("Internal error: Unsupported AST node. This should not happen. Report a bug please."));
             },
@@ -4,13 +4,26 @@
 //! that wrap certain functionality, that is used to filter/construct/lookahead all objects
 //! passed and iterated upon in the compiler, making it possible to have most of the compiler
 //! chain to be lazy_evaluated and even multi-threaded.
+//!
+//! Closures can't be named, so functions that return one of these iterators either cast a
+//! nested `fn` to a function pointer or use the boxed variants (`BoxedConstructor`,
+//! `BoxedScan`), which cost one dynamic call per element.
 
-use std::iter::Peekable;
+use std::iter::{Peekable, Scan};
 use std::sync::mpsc;
 use std::thread;
 
 /// An iterator that performs a lookahead of 1, utilizing the existing Peakable Iterator
 /// Can be stacked to perform an even greater lookahead
+///
+/// # Example
+///
+/// ```
+/// use zwreec::utils::extensions::PeekingExt;
+///
+/// let pairs: Vec<(u8, Option<u8>)> = vec![1, 2].into_iter().peeking().collect();
+/// assert_eq!(pairs, vec![(1, Some(2)), (2, None)]);
+/// ```
 #[must_use = "iterator adaptors are lazy and do nothing unless consumed"]
 #[derive(Clone)]
 pub struct Peeking<I, A> where
@@ -63,6 +76,19 @@ impl<A: Clone, I: Sized+Iterator<Item=A>> PeekingExt for I {
 }
 
 /// An iterator to maintain state while iterating another iterator and allows filtering
+///
+/// # Example
+///
+/// ```
+/// use zwreec::utils::extensions::FilteringScanExt;
+///
+/// // running sum, but only emit even sums
+/// let sums: Vec<u8> = vec![1, 1, 1, 1].into_iter().scan_filter(0, |sum, x| {
+///     *sum += x;
+///     if *sum % 2 == 0 { Some(*sum) } else { None }
+/// }).collect();
+/// assert_eq!(sums, vec![2, 4]);
+/// ```
 #[must_use = "iterator adaptors are lazy and do nothing unless consumed"]
 #[derive(Clone)]
 pub struct FilteringScan<I, St, F> {
@@ -194,6 +220,26 @@ impl<A:Clone, I: Sized+Iterator<Item=A>>ParserExt for I {
 
 /// An iterator to maintain state while iterating another iterator and allows to hold the currently active
 /// object to modify it (/construct it), until the next element is created
+///
+/// The function gets the state, the element under construction and the next input. Returning
+/// `Some(new)` finishes the current element and starts constructing `new`, the last element is
+/// returned once the input is exhausted.
+///
+/// # Example
+///
+/// ```
+/// use zwreec::utils::extensions::ConstructorExt;
+///
+/// // join words into lines, a "\n" starts a new line
+/// let lines: Vec<String> = vec!["a", "b", "\n", "c"].into_iter().construct_state((), |_, line, word| {
+///     match (line.as_mut(), word) {
+///         (Some(_), "\n") => Some(String::new()),
+///         (Some(line), word) => { line.push_str(word); None },
+///         (None, word) => Some(word.to_string()),
+///     }
+/// }).collect();
+/// assert_eq!(lines, vec!["ab".to_string(), "c".to_string()]);
+/// ```
 #[must_use = "iterator adaptors are lazy and do nothing unless consumed"]
 #[derive(Clone)]
 pub struct Constructor<I, St, B, F>
@@ -258,6 +304,78 @@ impl<I: Sized+Iterator>ConstructorExt for I {
     }
 }
 
+/// A Constructor Iterator with a boxed function, so it can be named in return types.
+pub type BoxedConstructor<I, St, B> =
+    Constructor<I, St, B, Box<FnMut(&mut St, &mut Option<B>, <I as Iterator>::Item) -> Option<B>>>;
+
+/// An extension to sized Iterators that returns a Constructor Iterator with a boxed function
+pub trait BoxedConstructorExt: Sized+Iterator {
+
+    /// Consumes this Iterator and returns a BoxedConstructor Iterator
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use zwreec::utils::extensions::{BoxedConstructor, BoxedConstructorExt};
+    ///
+    /// fn pairs<I: Iterator<Item=u8>>(iter: I) -> BoxedConstructor<I, (), Vec<u8>> {
+    ///     iter.construct_state_boxed((), |_, pair, x| {
+    ///         if pair.as_ref().map_or(false, |pair| pair.len() < 2) {
+    ///             pair.as_mut().unwrap().push(x);
+    ///             None
+    ///         } else {
+    ///             Some(vec![x])
+    ///         }
+    ///     })
+    /// }
+    ///
+    /// let result: Vec<Vec<u8>> = pairs(vec![1, 2, 3].into_iter()).collect();
+    /// assert_eq!(result, vec![vec![1, 2], vec![3]]);
+    /// ```
+    fn construct_state_boxed<St, B, F>(self, state: St, f: F) -> BoxedConstructor<Self, St, B>
+        where B: Clone, F: FnMut(&mut St, &mut Option<B>, Self::Item) -> Option<B> + 'static;
+}
+
+impl<I: Sized+Iterator> BoxedConstructorExt for I {
+    fn construct_state_boxed<St, B, F>(self, state: St, f: F) -> BoxedConstructor<Self, St, B>
+        where B: Clone, F: FnMut(&mut St, &mut Option<B>, I::Item) -> Option<B> + 'static,
+    {
+        Constructor{iter: self, state: state, f: Box::new(f), current_elem: None}
+    }
+}
+
+/// A `std::iter::Scan` with a boxed function, so it can be named in return types.
+pub type BoxedScan<I, St, B> = Scan<I, St, Box<FnMut(&mut St, <I as Iterator>::Item) -> Option<B>>>;
+
+/// An extension to sized Iterators that returns a `Scan` with a boxed function
+pub trait BoxedScanExt: Sized+Iterator {
+
+    /// Consumes this Iterator and returns a BoxedScan Iterator
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use zwreec::utils::extensions::{BoxedScan, BoxedScanExt};
+    ///
+    /// fn running_sum<I: Iterator<Item=u32>>(iter: I) -> BoxedScan<I, u32, u32> {
+    ///     iter.scan_boxed(0, |sum, x| { *sum += x; Some(*sum) })
+    /// }
+    ///
+    /// assert_eq!(running_sum(vec![1, 2, 3].into_iter()).collect::<Vec<u32>>(), vec![1, 3, 6]);
+    /// ```
+    fn scan_boxed<St, B, F>(self, state: St, f: F) -> BoxedScan<Self, St, B>
+        where F: FnMut(&mut St, Self::Item) -> Option<B> + 'static;
+}
+
+impl<I: Sized+Iterator> BoxedScanExt for I {
+    fn scan_boxed<St, B, F>(self, state: St, f: F) -> BoxedScan<Self, St, B>
+        where F: FnMut(&mut St, I::Item) -> Option<B> + 'static,
+    {
+        let f: Box<FnMut(&mut St, I::Item) -> Option<B>> = Box::new(f);
+        self.scan(state, f)
+    }
+}
+
 /// An Iterator that performs all iteration in a seperate therad and caches them
 pub struct Cached<A: Send> {
     rx: mpsc::Receiver<A>
@@ -271,6 +389,18 @@ impl<A: Send> Iterator for Cached<A> {
 
 /// A function to create a cached Iterator by passing the old Iterator by closure.
 /// If the underlying iterator does not support Send you can construct it in the closure
+///
+/// A panic in the thread ends the cached Iterator and is returned by joining the handle.
+///
+/// # Example
+///
+/// ```
+/// use zwreec::utils::extensions::cached;
+///
+/// let (iter, handle) = cached(|| vec![1, 2, 3].into_iter().map(|x| x * 2));
+/// assert_eq!(iter.collect::<Vec<u8>>(), vec![2, 4, 6]);
+/// assert!(handle.join().is_ok());
+/// ```
 pub fn cached<A: Send + 'static, I: Sized + Iterator<Item=A>, F: FnOnce() -> I + Send + 'static>(constructor: F) -> (Cached<A>, thread::JoinHandle<()>)
 {
     let (tx, rx) = mpsc::channel();
@@ -406,4 +536,91 @@ mod tests {
 
         assert_eq!(result, vec![1, 0, 4, 2]);
     }
+
+    #[test]
+    fn peeking_empty_and_single_test() {
+        let empty: Vec<(u8, Option<u8>)> = Vec::new().into_iter().peeking().collect();
+        assert_eq!(empty, vec![]);
+
+        let single: Vec<(u8, Option<u8>)> = vec![7].into_iter().peeking().collect();
+        assert_eq!(single, vec![(7, None)]);
+
+        // stacking performs a lookahead of 2
+        let mut stacked = vec![1, 2, 3].into_iter().peeking().peeking();
+        assert_eq!(stacked.next(), Some(((1, Some(2)), Some((2, Some(3))))));
+        assert_eq!(stacked.next(), Some(((2, Some(3)), Some((3, None)))));
+        assert_eq!(stacked.next(), Some(((3, None), None)));
+        assert_eq!(stacked.next(), None);
+        assert_eq!(stacked.next(), None);
+    }
+
+    #[test]
+    fn filtering_scan_empty_test() {
+        let result: Vec<u8> = Vec::new().into_iter().scan_filter(0, |_: &mut u8, x: u8| Some(x)).collect();
+        assert_eq!(result, vec![]);
+    }
+
+    #[test]
+    fn construct_empty_and_single_test() {
+        let empty: Vec<u8> = Vec::<u8>::new().into_iter().construct_state((), |_, _, i| Some(i)).collect();
+        assert_eq!(empty, vec![]);
+
+        let single: Vec<u8> = vec![4].into_iter().construct_state((), |_, _, i| Some(i)).collect();
+        assert_eq!(single, vec![4]);
+
+        // nothing started, nothing returned
+        let none: Vec<u8> = vec![1, 2].into_iter().construct_state((), |_, _, _| None).collect();
+        assert_eq!(none, vec![]);
+    }
+
+    #[test]
+    fn construct_state_carried_test() {
+        // the state counts all inputs, each element records the count when it was started
+        let result: Vec<(u8, u8)> = vec![1, 0, 0, 1, 0].into_iter().construct_state(0, |count, elem, i| {
+            *count += 1;
+            if i == 0 && elem.is_some() {
+                elem.as_mut().unwrap().1 += 1;
+                None
+            } else {
+                Some((*count, 0))
+            }
+        }).collect();
+        assert_eq!(result, vec![(1, 2), (4, 1)]);
+    }
+
+    #[test]
+    fn boxed_variants_test() {
+        fn construct<I: Iterator<Item=u8>>(iter: I) -> BoxedConstructor<I, u8, u8> {
+            iter.construct_state_boxed(0, |_, _, i| Some(i))
+        }
+        fn scan<I: Iterator<Item=u8>>(iter: I) -> BoxedScan<I, u8, u8> {
+            iter.scan_boxed(0, |max, x| { if x > *max { *max = x }; Some(*max) })
+        }
+
+        assert_eq!(construct(vec![1, 2].into_iter()).collect::<Vec<u8>>(), vec![1, 2]);
+        assert_eq!(scan(vec![1, 3, 2].into_iter()).collect::<Vec<u8>>(), vec![1, 3, 3]);
+        assert_eq!(scan(construct(Vec::new().into_iter())).collect::<Vec<u8>>(), vec![]);
+    }
+
+    #[test]
+    fn cached_test() {
+        let (iter, handle) = cached(|| vec![1, 2, 3].into_iter());
+        assert_eq!(iter.collect::<Vec<u8>>(), vec![1, 2, 3]);
+        assert!(handle.join().is_ok());
+    }
+
+    #[test]
+    fn cached_error_propagation_test() {
+        let (iter, handle) = cached(|| vec![1, 2, 3].into_iter().map(|x| {
+            if x == 3 {
+                panic!("failed at 3");
+            }
+            x
+        }));
+
+        // the iterator ends at the panic, the error is kept in the handle
+        assert_eq!(iter.collect::<Vec<u8>>(), vec![1, 2]);
+        let error = handle.join().unwrap_err();
+        assert_eq!(error.downcast_ref::<&str>(), Some(&"failed at 3"));
+    }
 }
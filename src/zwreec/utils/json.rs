@@ -0,0 +1,62 @@
+//! Minimal helpers to write JSON without pulling in an additional dependency.
+//!
+//! Values are passed around as already encoded `String`s, so objects and arrays can be nested
+//! by encoding the inner values first.
+
+/// Returns `value` as a quoted JSON string.
+///
+/// Quotes, backslashes and all control characters are escaped, everything else is kept as is.
+pub fn string(value: &str) -> String {
+    let mut out = String::with_capacity(value.len() + 2);
+    out.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Returns a JSON object with the given members in order.
+///
+/// The values have to be valid JSON already.
+pub fn object(members: &[(&str, String)]) -> String {
+    let members: Vec<String> = members.iter().map(|&(ref key, ref value)| {
+        format!("{}:{}", string(key), value)
+    }).collect();
+    format!("{{{}}}", members.join(","))
+}
+
+/// Returns a JSON array of the already encoded values.
+pub fn array(values: &[String]) -> String {
+    format!("[{}]", values.join(","))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_string_escaping() {
+        assert_eq!(string("Hello"), "\"Hello\"");
+        assert_eq!(string("a \"b\" \\c"), "\"a \\\"b\\\" \\\\c\"");
+        assert_eq!(string("line\nbreak\ttab\r"), "\"line\\nbreak\\ttab\\r\"");
+        assert_eq!(string("\u{1}"), "\"\\u0001\"");
+        assert_eq!(string("äöü"), "\"äöü\"");
+    }
+
+    #[test]
+    fn test_nesting() {
+        let inner = array(&[string("a"), "1".to_string()]);
+        assert_eq!(object(&[("x", inner), ("y", "null".to_string())]), "{\"x\":[\"a\",1],\"y\":null}");
+        assert_eq!(object(&[]), "{}");
+        assert_eq!(array(&[]), "[]");
+    }
+}
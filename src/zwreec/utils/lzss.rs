@@ -0,0 +1,159 @@
+//! A small LZSS compression without pulling in an additional dependency.
+//!
+//! The compressed data is a sequence of groups of up to 8 items, each group starts with a flag
+//! byte. The items are ordered from the lowest bit of the flags to the highest. For a set bit the
+//! item is a reference to earlier data in two bytes: the distance - 1 in the upper 12 bits and the
+//! length - 3 in the lower 4 bits. For a cleared bit the item is a single literal byte.
+//!
+//! # Example
+//!
+//! ```
+//! use zwreec::utils::lzss;
+//!
+//! let text = "::Start\nHello World\n::Second\nHello World".as_bytes();
+//! let compressed = lzss::compress(text);
+//! assert!(compressed.len() < text.len());
+//! assert_eq!(lzss::decompress(&compressed).unwrap(), text);
+//! ```
+
+use std::cmp;
+use std::collections::HashMap;
+
+/// The largest distance of a reference
+const WINDOW: usize = 4096;
+
+/// The shortest data a reference is used for
+const MIN_MATCH: usize = 3;
+
+/// The longest data of a reference
+const MAX_MATCH: usize = 18;
+
+/// The number of earlier positions of the same three bytes that are compared with the data
+const MAX_CANDIDATES: usize = 32;
+
+/// Returns the compressed data.
+pub fn compress(data: &[u8]) -> Vec<u8> {
+    let mut out: Vec<u8> = Vec::with_capacity(data.len() / 2);
+    let mut positions: HashMap<(u8, u8, u8), Vec<usize>> = HashMap::new();
+    let mut flags_index = 0;
+    let mut items = 8;
+    let mut pos = 0;
+    while pos < data.len() {
+        if items == 8 {
+            flags_index = out.len();
+            out.push(0);
+            items = 0;
+        }
+
+        let (distance, length) = longest_match(data, pos, &positions);
+        let step = if length >= MIN_MATCH {
+            out[flags_index] |= 1 << items;
+            let reference = ((distance - 1) << 4) | (length - MIN_MATCH);
+            out.push((reference >> 8) as u8);
+            out.push(reference as u8);
+            length
+        } else {
+            out.push(data[pos]);
+            1
+        };
+
+        for start in pos..pos + step {
+            if start + MIN_MATCH <= data.len() {
+                positions.entry((data[start], data[start + 1], data[start + 2])).or_insert(vec![]).push(start);
+            }
+        }
+        pos += step;
+        items += 1;
+    }
+    out
+}
+
+/// Returns the distance and the length of the longest earlier data the data at `pos` starts with.
+fn longest_match(data: &[u8], pos: usize, positions: &HashMap<(u8, u8, u8), Vec<usize>>) -> (usize, usize) {
+    if pos + MIN_MATCH > data.len() {
+        return (0, 0);
+    }
+    let candidates = match positions.get(&(data[pos], data[pos + 1], data[pos + 2])) {
+        Some(candidates) => candidates,
+        None => return (0, 0)
+    };
+
+    let max = cmp::min(MAX_MATCH, data.len() - pos);
+    let mut best = (0, 0);
+    for &start in candidates.iter().rev().take(MAX_CANDIDATES) {
+        if pos - start > WINDOW {
+            break;
+        }
+        // the reference may overlap the data at pos, it is copied a byte at a time
+        let length = (0..max).take_while(|&i| data[start + i] == data[pos + i]).count();
+        if length > best.1 {
+            best = (pos - start, length);
+        }
+    }
+    best
+}
+
+/// Returns the data of `compress`, or `None` if the compressed data is cut off or refers to data
+/// in front of its start.
+pub fn decompress(data: &[u8]) -> Option<Vec<u8>> {
+    let mut out: Vec<u8> = Vec::with_capacity(data.len() * 2);
+    let mut pos = 0;
+    while pos < data.len() {
+        let flags = data[pos];
+        pos += 1;
+        for item in 0..8 {
+            if pos >= data.len() {
+                break;
+            }
+            if flags & (1 << item) == 0 {
+                out.push(data[pos]);
+                pos += 1;
+                continue;
+            }
+
+            if pos + 1 >= data.len() {
+                return None;
+            }
+            let reference = (data[pos] as usize) << 8 | data[pos + 1] as usize;
+            pos += 2;
+            let (distance, length) = ((reference >> 4) + 1, (reference & 0xf) + MIN_MATCH);
+            if distance > out.len() {
+                return None;
+            }
+            let start = out.len() - distance;
+            for i in 0..length {
+                let byte = out[start + i];
+                out.push(byte);
+            }
+        }
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let repeated: Vec<u8> = (0..5000).map(|i| (i % 7) as u8).collect();
+        let text = "::Start\nÄpfel und Birnen <<set $x to 1>>\n[[Ende]]".as_bytes().to_vec();
+        for data in vec![vec![], vec![42], vec![1, 2], repeated, text].iter() {
+            assert_eq!(&decompress(&compress(data)).unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn test_references() {
+        // the run of a's is a literal and a reference that overlaps itself
+        assert_eq!(compress(b"aaaaaaaaaa"), vec![0x02, b'a', 0x00, 0x06]);
+        assert_eq!(decompress(&[0x02, b'a', 0x00, 0x06]).unwrap(), b"aaaaaaaaaa".to_vec());
+    }
+
+    #[test]
+    fn test_invalid_data() {
+        // a reference in front of the start and a reference without its second byte
+        assert_eq!(decompress(&[0x01, 0x00, 0x00]), None);
+        assert_eq!(decompress(&[0x02, b'a', 0x00]), None);
+    }
+}
@@ -4,4 +4,7 @@
 //! For actual usage take a look at the corresponding submodule.
 
 #[macro_use] pub mod error;
+pub mod diagnostics;
 pub mod extensions;
+pub mod json;
+pub mod lzss;
@@ -0,0 +1,183 @@
+//! Benchmarks of the compile throughput
+//!
+//! Three generated stories stand for the typical inputs: one of mostly text, one of mostly
+//! expressions and `<<if>>` blocks and one with many links. Each is compiled into memory, the
+//! wall time and the size of the Z-Code are printed and compared against `baseline.txt`.
+//!
+//! The benchmarks only run with `ZWREEC_BENCH=1`, in a release build to get useful times:
+//!
+//! ```sh
+//! ZWREEC_BENCH=1 cargo test --release benchmark -- --nocapture
+//! ```
+//!
+//! A benchmark fails if it takes more than `TIME_FACTOR` times the time or more than
+//! `SIZE_FACTOR` times the size in the baseline, so only real regressions are caught on other
+//! machines. After an intended change, or to use the times of your own machine, write the
+//! baseline again with `UPDATE_BENCH_BASELINE=1`. The baseline is only written then, a
+//! benchmark without an entry in it fails.
+//!
+//! The sizes don't depend on the machine, so `baseline_sizes_test` compares them on every test
+//! run, without `ZWREEC_BENCH`. It fails as well if a benchmark has no entry in the baseline.
+
+extern crate time;
+extern crate zwreec;
+use std::env;
+use std::fs::File;
+use std::io::{Cursor, Read, Write};
+use std::path::Path;
+use support;
+
+/// The file with the times and sizes to compare against
+static BASELINE: &'static str = "./tests/benchmarks/baseline.txt";
+
+/// How much slower than the baseline a benchmark may be
+static TIME_FACTOR: f64 = 3.0;
+
+/// How much larger than the baseline the output of a benchmark may be
+static SIZE_FACTOR: f64 = 1.1;
+
+/// The result of compiling one story.
+#[derive(Debug, Clone, PartialEq)]
+struct Measurement {
+    name: String,
+    millis: u64,
+    bytes: usize,
+}
+
+/// Returns the generated stories with their names.
+fn stories() -> Vec<(&'static str, String)> {
+    vec![
+        ("text-heavy", support::text_heavy(1, 100, 6)),
+        ("logic-heavy", support::logic_heavy(2, 60, 50)),
+        ("link-heavy", support::link_heavy(3, 400, 8)),
+    ]
+}
+
+/// Compiles the story into memory and measures it.
+fn measure(name: &str, story: &str) -> Measurement {
+    let mut input = Cursor::new(story.to_string().into_bytes());
+    let mut output = Cursor::new(Vec::new());
+
+    let start = time::precise_time_ns();
+    zwreec::compile(zwreec::config::Config::default_config(), &mut input, &mut output);
+    let millis = (time::precise_time_ns() - start) / 1_000_000;
+
+    Measurement { name: name.to_string(), millis: millis, bytes: output.into_inner().len() }
+}
+
+/// Reads the baseline, a missing file is an empty baseline.
+fn read_baseline() -> Vec<Measurement> {
+    let mut text = String::new();
+    if let Ok(mut file) = File::open(Path::new(BASELINE)) {
+        file.read_to_string(&mut text).unwrap();
+    }
+    parse_baseline(&text)
+}
+
+/// Parses the baseline, every line has the name, the milliseconds and the bytes of a benchmark.
+/// Empty lines and lines starting with `#` are skipped.
+fn parse_baseline(text: &str) -> Vec<Measurement> {
+    text.lines().filter(|line| !line.trim().is_empty() && !line.starts_with("#")).map(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        assert!(fields.len() == 3, "invalid baseline line {:?}", line);
+        Measurement {
+            name: fields[0].to_string(),
+            millis: fields[1].parse().expect("invalid time in the baseline"),
+            bytes: fields[2].parse().expect("invalid size in the baseline"),
+        }
+    }).collect()
+}
+
+/// Writes the measurements in the format of `parse_baseline`.
+fn format_baseline(measurements: &[Measurement]) -> String {
+    let mut text = "# name milliseconds bytes, written with UPDATE_BENCH_BASELINE=1\n".to_string();
+    for measurement in measurements {
+        text.push_str(&format!("{} {} {}\n", measurement.name, measurement.millis, measurement.bytes));
+    }
+    text
+}
+
+/// Describes how the measurement exceeds the bounds of the baseline, if it does.
+fn regression(measurement: &Measurement, baseline: &Measurement) -> Option<String> {
+    // times below a few milliseconds are mostly noise
+    let max_millis = (baseline.millis.max(5) as f64 * TIME_FACTOR) as u64;
+    let max_bytes = (baseline.bytes as f64 * SIZE_FACTOR) as usize;
+    if measurement.millis > max_millis {
+        Some(format!("{} took {} ms, the baseline is {} ms", measurement.name, measurement.millis, baseline.millis))
+    } else if measurement.bytes > max_bytes {
+        Some(format!("{} needs {} bytes, the baseline is {} bytes", measurement.name, measurement.bytes, baseline.bytes))
+    } else {
+        None
+    }
+}
+
+#[test]
+fn benchmark_test() {
+    if env::var("ZWREEC_BENCH").map(|value| value != "1").unwrap_or(true) {
+        return;
+    }
+    let update = env::var("UPDATE_BENCH_BASELINE").map(|value| value == "1").unwrap_or(false);
+    let baseline = read_baseline();
+
+    let mut measurements: Vec<Measurement> = vec![];
+    let mut failed: Vec<String> = vec![];
+    for (name, story) in stories() {
+        let measurement = measure(name, &story);
+        println!("{}: {} ms, {} bytes of Z-Code from {} bytes of Twee", name, measurement.millis, measurement.bytes, story.len());
+
+        match baseline.iter().find(|entry| entry.name == name) {
+            _ if update => {},
+            Some(entry) => if let Some(problem) = regression(&measurement, entry) {
+                failed.push(problem);
+            },
+            None => failed.push(format!("{} has no entry in the baseline", name)),
+        }
+        measurements.push(measurement);
+    }
+
+    if update {
+        File::create(Path::new(BASELINE)).unwrap().write_all(format_baseline(&measurements).as_bytes()).unwrap();
+    }
+    assert!(failed.is_empty(), "{} benchmark(s) are slower or larger than the baseline or missing in it, run with UPDATE_BENCH_BASELINE=1 if this is intended\n{}",
+        failed.len(), failed.join("\n"));
+}
+
+#[test]
+fn baseline_sizes_test() {
+    if env::var("UPDATE_BENCH_BASELINE").map(|value| value == "1").unwrap_or(false) {
+        return;
+    }
+    let baseline = read_baseline();
+    for (name, story) in stories() {
+        let entry = baseline.iter().find(|entry| entry.name == name).unwrap_or_else(||
+            panic!("{} has no entry in {}, write it with ZWREEC_BENCH=1 UPDATE_BENCH_BASELINE=1", name, BASELINE));
+        let measurement = measure(name, &story);
+        let max_bytes = (entry.bytes as f64 * SIZE_FACTOR) as usize;
+        assert!(measurement.bytes <= max_bytes, "{} needs {} bytes, the baseline is {} bytes", name, measurement.bytes, entry.bytes);
+    }
+}
+
+#[test]
+fn baseline_test() {
+    let measurements = vec![
+        Measurement { name: "text-heavy".to_string(), millis: 120, bytes: 90000 },
+        Measurement { name: "link-heavy".to_string(), millis: 2, bytes: 100 },
+    ];
+    assert_eq!(parse_baseline(&format_baseline(&measurements)), measurements);
+
+    let slower = Measurement { name: "text-heavy".to_string(), millis: 400, bytes: 90000 };
+    assert!(regression(&slower, &measurements[0]).is_some());
+    let noisy = Measurement { name: "link-heavy".to_string(), millis: 14, bytes: 110 };
+    assert_eq!(regression(&noisy, &measurements[1]), None);
+    let larger = Measurement { name: "link-heavy".to_string(), millis: 2, bytes: 111 };
+    assert!(regression(&larger, &measurements[1]).is_some());
+}
+
+#[test]
+fn generators_test() {
+    // the same seed generates the same story, every story compiles
+    assert_eq!(support::logic_heavy(7, 3, 10), support::logic_heavy(7, 3, 10));
+    for story in [support::text_heavy(1, 3, 2), support::logic_heavy(2, 3, 10), support::link_heavy(3, 5, 4)].iter() {
+        assert!(measure("small", story).bytes > 0);
+    }
+}
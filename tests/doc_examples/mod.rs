@@ -0,0 +1,80 @@
+//! The longer examples of the documentation as normal tests
+//!
+//! The examples in the module docs are doctests, but the end-to-end example of the crate
+//! documentation is `no_run`, because it reads its input from the command line. The tests here
+//! run the same steps, so a change of the documented API is caught even if doctests are skipped.
+
+extern crate zwreec;
+extern crate getopts;
+use std::env;
+use std::error::Error;
+use std::fs::File;
+use std::io::{Cursor, Read};
+use std::path::Path;
+
+/// The input of the examples
+static INPUT: &'static str = "./tests/integration/should-compile/PassageLinks.twee";
+
+#[test]
+fn command_line_compiler_test() {
+    // the example of the crate documentation, with the output in the temporary directory
+    let cfg = zwreec::config::Config::default_config();
+    let mut input = match File::open(Path::new(INPUT)) {
+        Ok(file) => file,
+        Err(why) => { panic!("Couldn't open input: {}", Error::description(&why)); }
+    };
+    let output_path = env::temp_dir().join("zwreec-doc-example.z8");
+    {
+        let mut output = match File::create(&output_path) {
+            Ok(file) => file,
+            Err(why) => { panic!("Couldn't open output: {}", Error::description(&why)); }
+        };
+
+        zwreec::compile(cfg, &mut input, &mut output);
+    }
+
+    let mut zcode = Vec::new();
+    File::open(&output_path).unwrap().read_to_end(&mut zcode).unwrap();
+    assert_eq!(zcode[0], 8);
+
+    // the same input compiled in memory gives the same file
+    let mut twee = String::new();
+    File::open(Path::new(INPUT)).unwrap().read_to_string(&mut twee).unwrap();
+    let cfg = zwreec::config::Config::from_args(&["--reproducible"]).unwrap();
+    let mut file_cfg = zwreec::config::Config::default_config();
+    file_cfg.reproducible = true;
+    assert_eq!(zwreec::compile_in_memory(cfg, &twee), zwreec::compile_in_memory(file_cfg, &twee));
+}
+
+#[test]
+fn frontend_pipeline_test() {
+    // the example of the frontend module, compared with the AST of `frontend_ast`
+    let cfg = zwreec::config::Config::default_config();
+    let source = "::Start\nHello World\n[[Next]]\n::Next\nBye";
+    let mut twee = Cursor::new(source.to_string().into_bytes());
+
+    let mut cursor = zwreec::frontend::screener::handle_bom_encoding(&mut twee).unwrap();
+    let tokens = zwreec::frontend::lexer::lex(cfg.clone(), &mut cursor);
+    let p = zwreec::frontend::parser::Parser::new(cfg.clone());
+    let ast: Vec<zwreec::frontend::ast::ASTNode> = zwreec::frontend::ast::ASTBuilder::build(cfg.clone(), p.parse(tokens)).collect();
+
+    let expected = zwreec::frontend_ast(cfg, &mut Cursor::new(source.to_string().into_bytes()));
+    assert_eq!(ast.len(), 2);
+    assert_eq!(format!("{:?}", ast), format!("{:?}", expected));
+}
+
+#[test]
+fn config_from_matches_test() {
+    // the example of the config module with fixed arguments
+    let args: Vec<String> = vec!["zwreec".to_string(), "-F".to_string(), "no-colours".to_string()];
+    let opts = zwreec::config::zwreec_options(getopts::Options::new());
+
+    let matches = match opts.parse(&args[1..]) {
+        Ok(m) => m,
+        Err(f) => { panic!(f.to_string()) }
+    };
+
+    let cfg = zwreec::config::Config::from_matches(&matches);
+    assert!(cfg.no_colours);
+    assert_eq!(cfg.no_colours, zwreec::config::Config::from_args(&args[1..]).unwrap().no_colours);
+}
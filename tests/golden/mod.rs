@@ -0,0 +1,113 @@
+//! Golden tests for the user-visible text of the should-compile fixtures
+//!
+//! Every fixture with a snapshot in this folder is compiled and the decoded strings of the Z-Code
+//! are compared against the snapshot `<fixture>.txt`. Run the tests with `UPDATE_GOLDEN=1` to
+//! write the snapshots of all fixtures again, e.g. after an intended change of the text.
+//!
+//! A few fixtures also have the accepted Z-Code `<fixture>.z8` in this folder, the compiled bytes
+//! have to stay identical to it. `UPDATE_GOLDEN=1` writes these files again as well.
+//!
+//! The snapshots `HelloWorld.txt`, `If-Else.txt`, `OnExit.txt`, `PassageName.txt`,
+//! `SingleLineIfLinks.txt` and `StoryDataStart.txt` were written by hand from the texts of the
+//! fixtures and the strings every story has, like the names of the passages and `true`/`false`.
+//! They were not produced by the compiler, and the `.z8` files of `BYTES` are not checked in
+//! yet, so `golden_bytes_test` fails until they are. Generate all of them with
+//! `UPDATE_GOLDEN=1 cargo test golden` and review the diff before relying on them.
+
+extern crate zwreec;
+use std::env;
+use std::fs;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::Path;
+use support::TESTFOLDER_PASS as FIXTURES;
+use support::test_compile_with_cfg;
+
+/// The folder with the snapshots
+static GOLDEN: &'static str = "./tests/golden/";
+
+/// Fixtures which do not compile with the default config and cannot get a snapshot
+static NEEDS_CONFIG: [&'static str; 2] = ["AddressOf", "CustomStart"];
+
+/// Fixtures whose whole Z-Code is compared byte by byte, they use `write_string` between the
+/// routines and print texts more than once
+static BYTES: [&'static str; 4] = ["CaseConversion", "HelloWorld", "Maps", "PassageName"];
+
+/// Compiles the fixture and returns its strings as snapshot: sorted, without duplicates and
+/// quoted so that whitespace and line breaks are visible.
+fn snapshot(fixture: &str) -> String {
+    let path = format!("{}{}.twee", FIXTURES, fixture);
+    let mut input = File::open(Path::new(&path)).unwrap();
+    let mut strings = zwreec::compiled_strings(zwreec::config::Config::default_config(), &mut input);
+    strings.sort();
+    strings.dedup();
+
+    strings.iter().map(|string| format!("{:?}\n", string)).collect()
+}
+
+/// Returns the names of all fixtures without the `.twee` extension.
+fn fixtures() -> Vec<String> {
+    let mut names: Vec<String> = fs::read_dir(FIXTURES).unwrap().filter_map(|entry| {
+        let path = entry.unwrap().path();
+        match path.extension() {
+            Some(extension) if extension == "twee" => path.file_stem().map(|stem| stem.to_string_lossy().into_owned()),
+            _ => None
+        }
+    }).collect();
+    names.sort();
+    names
+}
+
+#[test]
+fn golden_strings_test() {
+    let update = env::var("UPDATE_GOLDEN").map(|value| value == "1").unwrap_or(false);
+
+    let mut failed: Vec<String> = vec![];
+    for fixture in fixtures() {
+        let golden_path = format!("{}{}.txt", GOLDEN, fixture);
+        if update {
+            if !NEEDS_CONFIG.contains(&&*fixture) {
+                File::create(Path::new(&golden_path)).unwrap().write_all(snapshot(&fixture).as_bytes()).unwrap();
+            }
+            continue;
+        }
+
+        let mut golden = String::new();
+        match File::open(Path::new(&golden_path)) {
+            Ok(mut file) => { file.read_to_string(&mut golden).unwrap(); },
+            Err(_) => continue
+        }
+        if snapshot(&fixture) != golden {
+            failed.push(fixture);
+        }
+    }
+
+    assert!(failed.is_empty(), "the text of {:?} changed, run with UPDATE_GOLDEN=1 if this is intended", failed);
+}
+
+#[test]
+fn golden_bytes_test() {
+    let update = env::var("UPDATE_GOLDEN").map(|value| value == "1").unwrap_or(false);
+
+    let mut failed: Vec<String> = vec![];
+    for fixture in BYTES.iter() {
+        let golden_path = format!("{}{}.z8", GOLDEN, fixture);
+        let zcode = test_compile_with_cfg(format!("{}{}.twee", FIXTURES, fixture), zwreec::config::Config::default_config());
+        if update {
+            File::create(Path::new(&golden_path)).unwrap().write_all(&zcode).unwrap();
+            continue;
+        }
+
+        let mut golden: Vec<u8> = vec![];
+        match File::open(Path::new(&golden_path)) {
+            Ok(mut file) => { file.read_to_end(&mut golden).unwrap(); },
+            Err(_) => { failed.push(format!("{} has no accepted Z-Code", fixture)); continue; }
+        }
+        if zcode != golden {
+            let first = zcode.iter().zip(golden.iter()).position(|(a, b)| a != b).unwrap_or(::std::cmp::min(zcode.len(), golden.len()));
+            failed.push(format!("{} differs from byte {:#x} on", fixture, first));
+        }
+    }
+
+    assert!(failed.is_empty(), "the Z-Code changed, run with UPDATE_GOLDEN=1 if this is intended\n{}", failed.join("\n"));
+}
@@ -0,0 +1,387 @@
+//! Integration tests of the options of `Config` and the library API of `zwreec`
+
+extern crate zwreec;
+use std::fs::File;
+use std::io::Cursor;
+use support::{TESTFOLDER_FAIL, TESTFOLDER_PASS, test_compile, test_compile_with_cfg};
+
+#[test]
+fn addressof_debug_build_test() {
+    use zwreec::backend::zcode::zfile::{Operand, Type, ZOP, Zfile};
+
+    let mut cfg = zwreec::config::Config::default_config();
+    cfg.debug_build = true;
+    test_compile_with_cfg(TESTFOLDER_PASS.to_string() + "AddressOf.twee", cfg.clone());
+
+    // the value of the globals of $s and $literal is copied and printed as an integer
+    let mut input = File::open(TESTFOLDER_PASS.to_string() + "AddressOf.twee").unwrap();
+    let passages = zwreec::compiled_ops(cfg.clone(), &mut input);
+    let copied: Vec<u8> = passages[0].1.windows(2).filter_map(|ops| match (&ops[0], &ops[1]) {
+        (&ZOP::StoreVariable{ref variable, value: Operand::Var(ref global)}, &ZOP::SetVarType{variable: ref typed, vartype: Type::Integer})
+            if typed.id == variable.id => Some(global.id),
+        _ => None
+    }).collect();
+    assert_eq!(copied, vec![25, 26]);
+
+    // the concatenated string is on the heap, the literal is not
+    let machine = ::properties::story::run_fixture(cfg, "AddressOf", "");
+    let address = |prefix: &str| -> i32 {
+        let start = machine.transcript.find(prefix).expect(&machine.transcript) + prefix.len();
+        machine.transcript[start..].split_whitespace().next().unwrap().parse().unwrap()
+    };
+    let heap = Zfile::new().heap_start as i32..(machine.word(0x0e) as u16 as i32);
+    let string = address("heap 1 is stored at ");
+    assert!(string >= heap.start && string < heap.end, "{} is not in {:?}", string, heap);
+    let literal = address("static is stored at ");
+    assert!(literal < heap.start || literal >= heap.end, "{} is in {:?}", literal, heap);
+}
+
+#[test]
+#[should_panic]
+fn addressof_without_debug_build_test() {
+    test_compile(TESTFOLDER_PASS.to_string() + "AddressOf.twee");
+}
+
+#[test]
+fn custom_start_passage_test() {
+    let mut cfg = zwreec::config::Config::default_config();
+    cfg.start_passage = Some("Intro".to_string());
+    test_compile_with_cfg(TESTFOLDER_PASS.to_string() + "CustomStart.twee", cfg);
+}
+
+#[test]
+fn first_passage_fallback_test() {
+    let mut cfg = zwreec::config::Config::default_config();
+    cfg.first_passage_fallback = true;
+    test_compile_with_cfg(TESTFOLDER_PASS.to_string() + "CustomStart.twee", cfg);
+}
+
+#[test]
+fn story_data_start_test() {
+    test_compile(TESTFOLDER_PASS.to_string() + "StoryDataStart.twee");
+}
+
+#[test]
+#[should_panic]
+fn missing_custom_start_passage_test() {
+    let mut cfg = zwreec::config::Config::default_config();
+    cfg.start_passage = Some("Missing".to_string());
+    test_compile_with_cfg(TESTFOLDER_PASS.to_string() + "CustomStart.twee", cfg);
+}
+
+#[test]
+#[should_panic]
+fn no_start_passage_without_fallback_test() {
+    test_compile(TESTFOLDER_PASS.to_string() + "CustomStart.twee");
+}
+
+#[test]
+#[should_panic]
+fn no_start_passage_test() {
+    test_compile(TESTFOLDER_FAIL.to_string() + "NoStartPassage.twee");
+}
+
+#[test]
+fn no_start_passage_reported_test() {
+    use std::thread;
+
+    // the error is not recoverable, but it is kept like the others, even with --force
+    let cfg = zwreec::config::Config::from_args(&["--force", "--error-format", "json"]).unwrap();
+    let compile_cfg = cfg.clone();
+    assert!(thread::spawn(move || test_compile_with_cfg(TESTFOLDER_FAIL.to_string() + "NoStartPassage.twee", compile_cfg)).join().is_err());
+
+    let errors = cfg.reported_errors();
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0].code, "CodeGenError::NoStartPassage");
+    assert_eq!(errors[0].severity, "error");
+}
+
+#[test]
+fn compiler_recompile_test() {
+    let mut compiler = zwreec::compiler::Compiler::new(zwreec::config::Config::default_config());
+
+    assert_eq!(compiler.compile_str("::Start\nHello World"), 0);
+    assert_eq!(compiler.passages().len(), 1);
+    let first = compiler.zcode().to_vec();
+    assert_eq!(0x08, first[0]);
+
+    // edit the source
+    assert_eq!(compiler.compile_str("::Start\nHello [[World]]\n::World\nHello again"), 0);
+    assert_eq!(compiler.passages().len(), 2);
+    assert_eq!(0x08, compiler.zcode()[0]);
+    assert!(compiler.zcode() != &first[..]);
+
+    // unchanged source keeps the last result
+    let second = compiler.zcode().to_vec();
+    assert_eq!(compiler.compile_str("::Start\nHello [[World]]\n::World\nHello again"), 0);
+    assert_eq!(compiler.zcode(), &second[..]);
+}
+
+#[test]
+fn test_library_test() {
+    use zwreec::config::{Config, TestCase};
+
+    let mut input: Option<File> = None;
+    let mut no_output: Option<Cursor<Vec<u8>>> = None;
+
+    // nothing to run
+    assert!(zwreec::test_library(Config::default_config(), &mut input, &mut no_output).is_err());
+
+    let mut cfg = Config::default_config();
+    cfg.test_cases.push(TestCase::ZcodeBackend);
+    assert!(zwreec::test_library(cfg, &mut input, &mut no_output).is_err());
+
+    let mut cfg = Config::default_config();
+    cfg.test_cases.push(TestCase::ExpressionFolding);
+    assert_eq!(zwreec::test_library(cfg, &mut input, &mut no_output), Ok(()));
+
+    let mut cfg = Config::default_config();
+    cfg.test_cases.push(TestCase::StringRoutines);
+    let mut output = Some(Cursor::new(vec![]));
+    assert_eq!(zwreec::test_library(cfg, &mut input, &mut output), Ok(()));
+    assert_eq!(output.unwrap().into_inner()[0], 0x08);
+}
+
+#[test]
+fn attest_test() {
+    use std::path::Path;
+    use zwreec::backend::attest::Attribution;
+    use zwreec::config::{Config, TestCase};
+
+    let path = TESTFOLDER_PASS.to_string() + "HelloWorld.twee";
+    let mut input = File::open(Path::new(&path)).unwrap();
+    let mut output = Cursor::new(vec![]);
+    let manifest = zwreec::attest(Config::default_config(), &mut input, &mut output);
+    assert!(manifest.is_attributed(), "{}", manifest);
+    assert!(manifest.entries.iter().any(|entry| entry.attribution == Attribution::Passage("Start".to_string())));
+
+    // the routines of the easter egg are not part of the documented runtime
+    let mut input = File::open(Path::new(&path)).unwrap();
+    let ast = zwreec::frontend_ast(Config::default_config(), &mut input);
+    let manifest = zwreec::backend::codegen::generate_zcode(&Config::default_config(), ast.into_iter(), &mut Cursor::new(vec![]));
+    assert!(manifest.unattributed().iter().any(|entry| entry.name == "easter_egg_start"));
+
+    // the test-only hook injects a routine and bytes of unknown origin, which fail the attestation
+    let mut cfg = Config::default_config();
+    cfg.test_cases.push(TestCase::InjectUnattributed);
+    let mut input = File::open(Path::new(&path)).unwrap();
+    let manifest = zwreec::attest(cfg.clone(), &mut input, &mut Cursor::new(vec![]));
+    assert!(!manifest.is_attributed(), "{}", manifest);
+    assert!(manifest.unattributed().iter().any(|entry| entry.name == "injected"));
+    let mut no_input: Option<File> = None;
+    assert_eq!(zwreec::test_library(cfg, &mut no_input, &mut None::<Cursor<Vec<u8>>>), Ok(()));
+}
+
+#[test]
+#[should_panic(expected = "Passage 'Cellar' prints nothing and has no links, the story ends without a word when it is entered. Tag it with [ending] if this is intended [dead-end] in passage 'Cellar', denied with -D dead-end")]
+fn deny_warning_test() {
+    let cfg = zwreec::config::Config::from_args(&["-D", "dead-end"]).unwrap();
+    zwreec::compile_in_memory(cfg, "::Start\nYou see a cellar.\n[[Cellar]]\n\n::Cellar\n<<set $x to 1>>\n");
+}
+
+#[test]
+fn deny_warning_force_test() {
+    let cfg = zwreec::config::Config::from_args(&["-D", "dead-end", "--force"]).unwrap();
+    let errors = cfg.clone();
+    zwreec::compile_in_memory(cfg, "::Start\nYou see a cellar.\n[[Cellar]]\n\n::Cellar\n<<set $x to 1>>\n");
+
+    let errors = errors.reported_errors();
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0].code, "CodeGenError::DeniedWarning");
+    assert!(errors[0].message.contains("[dead-end] in passage 'Cellar'"), "{}", errors[0].message);
+}
+
+#[test]
+fn unknown_warning_test() {
+    let error = zwreec::config::Config::from_args(&["-W", "dead-end", "-D", "dead-ends"]).err();
+    assert_eq!(error, Some("Unknown warning dead-ends for -D - run --list-lints to see the warnings.".to_string()));
+}
+
+#[test]
+fn allow_warning_test() {
+    // the story still compiles, with -D only the other warning would stop it
+    let cfg = zwreec::config::Config::from_args(&["-A", "dead-end", "-D", "conditional-links"]).unwrap();
+    zwreec::compile_in_memory(cfg, "::Start\nYou see a cellar.\n[[Cellar]]\n\n::Cellar\n<<set $x to 1>>\n");
+}
+
+#[test]
+fn embed_source_test() {
+    use std::io::Read;
+
+    let mut source = String::new();
+    File::open(TESTFOLDER_PASS.to_string() + "PassageLinks.twee").unwrap().read_to_string(&mut source).unwrap();
+    let plain = zwreec::compile_in_memory(zwreec::config::Config::from_args(&["--reproducible"]).unwrap(), &source);
+    let story = zwreec::compile_in_memory(zwreec::config::Config::from_args(&["--reproducible", "--embed-source"]).unwrap(), &source);
+
+    assert_eq!(zwreec::extract_source(&story).unwrap(), source);
+    assert_eq!(zwreec::extract_source(&plain), None);
+
+    // interpreters load the story up to the file length, which is the story without the source
+    let length = zwreec::backend::zcode::archive::file_length(&story).unwrap();
+    assert!(length >= plain.len() && length < story.len());
+    assert_eq!(&story[..0x1a], &plain[..0x1a]);
+    assert_eq!(&story[0x1e..plain.len()], &plain[0x1e..]);
+    assert!(story[plain.len()..length].iter().all(|&byte| byte == 0));
+
+    // verify compares the checksum with the sum of the bytes behind the header
+    let checksum = story[0x40..length].iter().fold(0u16, |sum, &byte| sum.wrapping_add(byte as u16));
+    assert_eq!((story[0x1c] as u16) << 8 | story[0x1d] as u16, checksum);
+}
+
+#[test]
+fn embed_source_version_test() {
+    // version 7 counts the file length in units of 4 bytes
+    let source = "::Start\nHello World";
+    let story = zwreec::compile_in_memory(zwreec::config::Config::from_args(&["--embed-source", "--zcode-version", "7"]).unwrap(), source);
+    assert_eq!(story[0], 7);
+    let length = zwreec::backend::zcode::archive::file_length(&story).unwrap();
+    assert_eq!(length % 4, 0);
+    assert_eq!(((story[0x1a] as usize) << 8 | story[0x1b] as usize) * 4, length);
+    assert_eq!(zwreec::extract_source(&story).unwrap(), source);
+}
+
+#[test]
+fn compiler_prepares_input_test() {
+    // the source is kept for --embed-source and the widgets are looked up on every compilation
+    let cfg = zwreec::config::Config::from_args(&["--embed-source"]).unwrap();
+    let mut compiler = zwreec::compiler::Compiler::new(cfg);
+    let source = "::Start\n<<hello>>\n\n::Widgets [widget]\n<<widget \"hello\">>Hello World<<endwidget>>\n";
+    assert_eq!(compiler.compile_str(source), 0);
+    assert_eq!(zwreec::extract_source(compiler.zcode()).unwrap(), source);
+
+    // every compilation counts its own errors
+    let mut cfg = zwreec::config::Config::from_args(&["--force"]).unwrap();
+    cfg.max_errors = 2;
+    let mut compiler = zwreec::compiler::Compiler::new(cfg);
+    for edit in 0..3 {
+        assert_eq!(compiler.compile_str(&format!("::Start\n{}<<else>>", edit)), 1);
+    }
+}
+
+#[test]
+fn init_project_test() {
+    use std::env;
+    use std::fs;
+    use std::io::{Read, Write};
+
+    let directory = env::temp_dir().join("zwreec-init-test");
+    let _ = fs::remove_dir_all(&directory);
+    let files = zwreec::scaffold::init_project(&directory, false).unwrap();
+
+    // the Twee files are merged like several inputs of the binary
+    let mut source = String::new();
+    for file in files.iter().filter(|file| file.extension().map(|extension| extension == "twee").unwrap_or(false)) {
+        File::open(file).unwrap().read_to_string(&mut source).unwrap();
+        source.push('\n');
+    }
+    assert!(source.contains(&zwreec::scaffold::build_command("zwreec-init-test")));
+    assert!(source.contains("zwreec --config zwreec.config -o zwreec-init-test.z8 main.twee snippets.twee"));
+    let passages = zwreec::compiled_ops(zwreec::config::Config::default_config(), &mut Cursor::new(source.into_bytes()));
+    let names: Vec<&str> = passages.iter().map(|&(ref name, _)| &name[..]).collect();
+    assert!(["Start", "Room", "Outside"].iter().all(|passage| names.contains(passage)));
+    assert!(!names.contains(&"Widgets"));
+    assert!(directory.join("zwreec.config").is_file());
+
+    // an edited project is only overwritten with force
+    File::create(&files[0]).unwrap().write_all(b"::Start\nEdited").unwrap();
+    match zwreec::scaffold::init_project(&directory, false) {
+        Err(zwreec::scaffold::InitError::FileExists { path }) => assert_eq!(path, files[0]),
+        result => panic!("existing project was not refused: {:?}", result)
+    }
+    let mut edited = String::new();
+    File::open(&files[0]).unwrap().read_to_string(&mut edited).unwrap();
+    assert_eq!(edited, "::Start\nEdited");
+
+    zwreec::scaffold::init_project(&directory, true).unwrap();
+    let mut restored = String::new();
+    File::open(&files[0]).unwrap().read_to_string(&mut restored).unwrap();
+    assert!(restored.starts_with("::StoryTitle\nzwreec-init-test\n"));
+}
+
+#[test]
+fn init_binary_test() {
+    use std::env;
+    use std::fs;
+    use std::io::{Read, Write};
+    use std::process::Command;
+    use support::zwreec_binary;
+
+    let parent = env::temp_dir().join("zwreec-init-binary");
+    let _ = fs::remove_dir_all(&parent);
+    fs::create_dir_all(&parent).unwrap();
+    let init = |force: bool| {
+        let mut command = Command::new(zwreec_binary());
+        command.current_dir(&parent).arg("--init").arg("story");
+        if force {
+            command.arg("--force-init");
+        }
+        command.output().unwrap()
+    };
+
+    // the success message is the call that builds the story
+    let created = init(false);
+    assert!(created.status.success(), "{}", String::from_utf8_lossy(&created.stderr));
+    let message = String::from_utf8_lossy(&created.stdout).into_owned();
+    assert!(message.contains(&zwreec::scaffold::build_command("story")), "{}", message);
+
+    let directory = parent.join("story");
+    let build = Command::new(zwreec_binary()).current_dir(&directory)
+        .args(&["--config", "zwreec.config", "-o", "story.z8", "main.twee", "snippets.twee"]).output().unwrap();
+    assert!(build.status.success(), "{}", String::from_utf8_lossy(&build.stderr));
+    let mut story: Vec<u8> = vec![];
+    File::open(directory.join("story.z8")).unwrap().read_to_end(&mut story).unwrap();
+    assert_eq!(story[0], 8);
+
+    // an option of the config file is read like one on the command line
+    File::create(directory.join("zwreec.config")).unwrap().write_all(b"--zcode-version 7 # smaller interpreters\n").unwrap();
+    let build = Command::new(zwreec_binary()).current_dir(&directory)
+        .args(&["--config", "zwreec.config", "-w", "-o", "story.z8", "main.twee", "snippets.twee"]).output().unwrap();
+    assert!(build.status.success(), "{}", String::from_utf8_lossy(&build.stderr));
+    let mut story: Vec<u8> = vec![];
+    File::open(directory.join("story.z8")).unwrap().read_to_end(&mut story).unwrap();
+    assert_eq!(story[0], 7);
+
+    // the existing project is refused and kept without --force-init
+    File::create(directory.join("main.twee")).unwrap().write_all(b"::Start\nEdited").unwrap();
+    let refused = init(false);
+    assert_eq!(refused.status.code(), Some(1));
+    let mut edited = String::new();
+    File::open(directory.join("main.twee")).unwrap().read_to_string(&mut edited).unwrap();
+    assert_eq!(edited, "::Start\nEdited");
+
+    assert!(init(true).status.success());
+    let mut restored = String::new();
+    File::open(directory.join("main.twee")).unwrap().read_to_string(&mut restored).unwrap();
+    assert!(restored.starts_with("::StoryTitle\nstory\n"));
+    fs::remove_dir_all(&parent).unwrap();
+}
+
+#[test]
+fn plan_outputs_existing_directory_test() {
+    use std::env;
+    use std::fs;
+    use std::path::Path;
+    use zwreec::outputs::{InputMode, OutputTarget, output_name, output_target, plan_outputs};
+
+    let directory = env::temp_dir().join("zwreec-plan-outputs");
+    fs::create_dir_all(&directory).unwrap();
+    let name = directory.to_str().unwrap().to_string();
+
+    // an existing directory does not need a trailing separator
+    let target = output_target(Some(name.clone()));
+    assert_eq!(target, OutputTarget::Directory(name.clone()));
+
+    // the stories of a batch are compiled into it, named after their inputs
+    let inputs = vec![TESTFOLDER_PASS.to_string() + "HelloWorld.twee", TESTFOLDER_PASS.to_string() + "PassageLinks.twee"];
+    let plan = plan_outputs(&inputs, target, InputMode::Batch).unwrap();
+    assert_eq!(plan.len(), 2);
+    for (story, output) in plan {
+        let output = output.unwrap();
+        assert_eq!(Path::new(&output).parent(), Some(directory.as_path()));
+        let mut input = File::open(Path::new(&story[0])).unwrap();
+        let mut file = File::create(&output).unwrap();
+        zwreec::compile(zwreec::config::Config::default_config(), &mut input, &mut file);
+        assert!(directory.join(output_name(&story[0])).is_file());
+    }
+    fs::remove_dir_all(&directory).unwrap();
+}
@@ -0,0 +1,708 @@
+//! Integration tests of the generated code for macros, expressions, links and functions
+
+extern crate zwreec;
+use std::fs::File;
+use std::io::Cursor;
+use std::path::Path;
+use support::{TESTFOLDER_FAIL, TESTFOLDER_PASS, contains_utf16_string, count_utf16_string, test_compile, test_compile_with_cfg};
+
+#[test]
+fn expressions_test() {
+    test_compile(TESTFOLDER_PASS.to_string() + "Expressions.twee");
+}
+
+#[test]
+fn helloworld_test() {
+    test_compile(TESTFOLDER_PASS.to_string() + "HelloWorld.twee");
+}
+
+#[test]
+fn long_text_test() {
+    test_compile(TESTFOLDER_PASS.to_string() + "HelloWorld.twee");
+}
+
+#[test]
+fn passage_links_test() {
+    test_compile(TESTFOLDER_PASS.to_string() + "PassageLinks.twee");
+}
+
+#[test]
+fn random_test() {
+    test_compile(TESTFOLDER_PASS.to_string() + "Random.twee");
+}
+
+#[test]
+fn random_expanded_test() {
+    test_compile(TESTFOLDER_PASS.to_string() + "RandomExpanded.twee");
+}
+
+#[test]
+fn if_else_test() {
+    test_compile(TESTFOLDER_PASS.to_string() + "If-Else.twee");
+}
+
+#[test]
+fn current_status_test() {
+    test_compile(TESTFOLDER_PASS.to_string() + "CurrentStatus.twee");
+}
+
+#[test]
+fn prompt_and_string_index_access_test() {
+    test_compile(TESTFOLDER_PASS.to_string() + "textadventure.twee");
+}
+
+#[test]
+fn prompt_assign_test() {
+    test_compile(TESTFOLDER_PASS.to_string() + "PromptAssign.twee");
+}
+
+#[test]
+fn case_conversion_test() {
+    use zwreec::backend::zcode::zfile::ZOP;
+
+    let path = TESTFOLDER_PASS.to_string() + "CaseConversion.twee";
+    test_compile(path.clone());
+
+    // the entered name is converted at runtime, the constant string already by the compiler
+    let mut input = File::open(Path::new(&path)).unwrap();
+    let passages = zwreec::compiled_ops(zwreec::config::Config::default_config(), &mut input);
+    let (_, ref start) = passages[0];
+    let conversions = start.iter().filter(|op| match op {
+        &&ZOP::CallVSA3{ref jump_to_label, ..} => jump_to_label == "change_case",
+        _ => false
+    }).count();
+    assert_eq!(1, conversions);
+}
+
+#[test]
+fn substr_test() {
+    use zwreec::backend::zcode::zfile::ZOP;
+
+    let path = TESTFOLDER_PASS.to_string() + "Substr.twee";
+    let zcode = test_compile_with_cfg(path.clone(), zwreec::config::Config::default_config());
+    assert!(contains_utf16_string(&zcode, "ell"));
+    // the length is clamped to the end of the string
+    assert!(contains_utf16_string(&zcode, "lo"));
+
+    // only the substring of the entered word is left for the runtime
+    let mut input = File::open(Path::new(&path)).unwrap();
+    let passages = zwreec::compiled_ops(zwreec::config::Config::default_config(), &mut input);
+    let (_, ref start) = passages[0];
+    let calls = start.iter().filter(|op| match op {
+        &&ZOP::CallVS2A5{ref jump_to_label, ..} => jump_to_label == "substr",
+        _ => false
+    }).count();
+    assert_eq!(1, calls);
+}
+
+#[test]
+fn plural_test() {
+    use zwreec::backend::zcode::zfile::{Operand, ZOP};
+
+    let path = TESTFOLDER_PASS.to_string() + "Plural.twee";
+    let zcode = test_compile_with_cfg(path.clone(), zwreec::config::Config::default_config());
+    // constant counts choose the form at compile time
+    assert!(contains_utf16_string(&zcode, "coin") && !contains_utf16_string(&zcode, "coins"));
+    assert!(contains_utf16_string(&zcode, "gems") && !contains_utf16_string(&zcode, "gem"));
+    assert!(contains_utf16_string(&zcode, "ruby") && contains_utf16_string(&zcode, "rubies"));
+
+    // $count is compared with 1 at runtime, user variables start at global 25
+    let mut input = File::open(Path::new(&path)).unwrap();
+    let passages = zwreec::compiled_ops(zwreec::config::Config::default_config(), &mut input);
+    let (_, ref start) = passages[0];
+    assert!(start.iter().any(|op| match op {
+        &ZOP::JE{operand1: Operand::Var(ref count), operand2: Operand::Const(ref one), ..} => count.id >= 25 && one.value == 1,
+        _ => false
+    }));
+}
+
+#[test]
+fn toc_test() {
+    use zwreec::backend::zcode::zfile::ZOP;
+
+    let path = TESTFOLDER_PASS.to_string() + "Toc.twee";
+    let zcode = test_compile_with_cfg(path.clone(), zwreec::config::Config::default_config());
+    assert!(contains_utf16_string(&zcode, "The Middle"));
+
+    // only the passages tagged [menu] are linked, Start calls the generated routine
+    let mut input = File::open(Path::new(&path)).unwrap();
+    let passages = zwreec::compiled_ops(zwreec::config::Config::default_config(), &mut input);
+    let &(_, ref toc) = passages.iter().find(|&&(ref name, _)| name == "system_toc").unwrap();
+    let links: Vec<String> = toc.iter().filter_map(|op| match op {
+        &ZOP::Call2NWithAddress{ref jump_to_label, ref address} if jump_to_label == "system_add_link" => Some(address.clone()),
+        _ => None
+    }).collect();
+    assert_eq!(links, vec!["The Beginning", "The Middle", "The End"]);
+
+    let &(_, ref start) = passages.iter().find(|&&(ref name, _)| name == "Start").unwrap();
+    assert!(start.iter().any(|op| match op {
+        &ZOP::Call1N{ref jump_to_label} => jump_to_label == "system_toc",
+        _ => false
+    }));
+}
+
+#[test]
+fn clear_test() {
+    use zwreec::backend::zcode::zfile::{Operand, ZOP};
+
+    let path = TESTFOLDER_PASS.to_string() + "Clear.twee";
+    test_compile(path.clone());
+
+    // <<clear>> passes the array of $deck and the id of its variable to rt_clear
+    let mut input = File::open(Path::new(&path)).unwrap();
+    let passages = zwreec::compiled_ops(zwreec::config::Config::default_config(), &mut input);
+    let &(_, ref start) = passages.iter().find(|&&(ref name, _)| name == "Start").unwrap();
+    assert!(start.iter().any(|op| match op {
+        &ZOP::CallVNA2{ref jump_to_label, arg1: Operand::Var(ref var), arg2: Operand::Const(ref id)} => jump_to_label == "rt_clear" && var.id == id.value,
+        _ => false
+    }));
+}
+
+#[test]
+fn savegame_test() {
+    use zwreec::backend::zcode::zfile::{Operand, ZOP};
+
+    let path = TESTFOLDER_PASS.to_string() + "SaveGame.twee";
+    test_compile(path.clone());
+
+    // the result of save decides which message is printed, restore only returns if it failed
+    let mut input = File::open(Path::new(&path)).unwrap();
+    let passages = zwreec::compiled_ops(zwreec::config::Config::default_config(), &mut input);
+    let &(_, ref start) = passages.iter().find(|&&(ref name, _)| name == "Start").unwrap();
+    let result = start.iter().filter_map(|op| match op {
+        &ZOP::Save{ref result} => Some(result.id),
+        _ => None
+    }).next().expect("<<savegame>> emits no save");
+    let checks: Vec<u8> = start.iter().filter_map(|op| match op {
+        &ZOP::JE{operand1: Operand::Var(ref var), operand2: Operand::Const(ref constant), ..} if var.id == result => Some(constant.value),
+        _ => None
+    }).collect();
+    assert_eq!(checks, vec![0, 2]);
+
+    let &(_, ref cave) = passages.iter().find(|&&(ref name, _)| name == "Cave").unwrap();
+    assert!(cave.iter().any(|op| match op { &ZOP::Restore{..} => true, _ => false }));
+}
+
+#[test]
+fn plugin_macro_test() {
+    use zwreec::backend::zcode::zfile::{Operand, ZOP};
+
+    // the handler prints how it was called
+    let mut cfg = zwreec::config::Config::default_config();
+    cfg.macros.register("sound", |call| {
+        let first = match call.args.first() {
+            Some(&Operand::Const(ref constant)) => constant.value.to_string(),
+            _ => "-".to_string(),
+        };
+        vec![ZOP::Print{text: format!("{} {} {} {}", call.name, call.args.len(), first, call.silent)}]
+    }).unwrap();
+
+    let mut input = Cursor::new("::Start\n<<sound 7, \"door\">><<sound>><<silently>><<sound 1>><<endsilently>>".to_string().into_bytes());
+    let passages = zwreec::compiled_ops(cfg, &mut input);
+    let &(_, ref start) = passages.iter().find(|&&(ref name, _)| name == "Start").unwrap();
+    let texts: Vec<&str> = start.iter().filter_map(|op| match op {
+        &ZOP::Print{ref text} if text.starts_with("sound") => Some(&text[..]),
+        _ => None
+    }).collect();
+    assert_eq!(texts, vec!["sound 2 7 false", "sound 0 - false", "sound 1 1 true"]);
+
+    // the arguments and the temporary of the handler are in different variables
+    let mut cfg = zwreec::config::Config::default_config();
+    cfg.macros.register("pair", |call| {
+        let mut ids: Vec<String> = call.args.iter().map(|arg| match arg {
+            &Operand::Var(ref var) => var.id.to_string(),
+            _ => "-".to_string(),
+        }).collect();
+        ids.push(call.temp.id.to_string());
+        vec![ZOP::Print{text: format!("pair {}", ids.join(" "))}]
+    }).unwrap();
+    let mut input = Cursor::new("::Start\n<<set $a to 1>><<set $b to 2>><<pair $a + 1, $b + 2>>".to_string().into_bytes());
+    let passages = zwreec::compiled_ops(cfg, &mut input);
+    let &(_, ref start) = passages.iter().find(|&&(ref name, _)| name == "Start").unwrap();
+    let text = start.iter().filter_map(|op| match op {
+        &ZOP::Print{ref text} if text.starts_with("pair") => Some(text.clone()),
+        _ => None
+    }).next().unwrap();
+    let ids: Vec<&str> = text.split(' ').skip(1).collect();
+    assert_eq!(ids.len(), 3, "{}", text);
+    assert!(!ids.contains(&"-") && ids[0] != ids[1] && ids[0] != ids[2] && ids[1] != ids[2], "{}", text);
+}
+
+#[test]
+fn constant_concatenation_test() {
+    use zwreec::backend::zcode::zfile::ZOP;
+
+    let path = TESTFOLDER_PASS.to_string() + "ConstantConcat.twee";
+    let zcode = test_compile_with_cfg(path.clone(), zwreec::config::Config::default_config());
+    assert_eq!(count_utf16_string(&zcode, "concatenated"), 1);
+    assert_eq!(count_utf16_string(&zcode, "prefix"), 1);
+    assert!(!contains_utf16_string(&zcode, "con") && !contains_utf16_string(&zcode, "enated"));
+
+    // no add_types and thus no strcat at runtime
+    let mut input = File::open(Path::new(&path)).unwrap();
+    let passages = zwreec::compiled_ops(zwreec::config::Config::default_config(), &mut input);
+    let &(_, ref start) = passages.iter().find(|&&(ref name, _)| name == "Start").unwrap();
+    assert!(!start.iter().any(|op| match op { &ZOP::AddTypes{..} => true, _ => false }));
+}
+
+#[test]
+fn buildtime_test() {
+    test_compile(TESTFOLDER_PASS.to_string() + "BuildTime.twee");
+}
+
+#[test]
+fn passage_name_test() {
+    let zcode = test_compile_with_cfg(TESTFOLDER_PASS.to_string() + "PassageName.twee",
+                                      zwreec::config::Config::default_config());
+    assert!(contains_utf16_string(&zcode, "Start"));
+    // the string literal and passage() share the name of the passage
+    assert_eq!(count_utf16_string(&zcode, "Cellar"), 1);
+}
+
+#[test]
+fn link_count_test() {
+    test_compile(TESTFOLDER_PASS.to_string() + "LinkCount.twee");
+}
+
+#[test]
+fn passage_style_reset_test() {
+    use zwreec::backend::zcode::zfile::ZOP;
+
+    // every passage resets the style on entry, before the label where a display starts
+    let styles = |cfg: zwreec::config::Config| {
+        let mut input = Cursor::new("::Start\n''Bold'' [[Next]]\n::Next\nPlain".to_string().into_bytes());
+        zwreec::compiled_ops(cfg, &mut input).into_iter().map(|(_, code)| {
+            let entry = code.iter().position(|op| match op {
+                &ZOP::Label{ref name} => name.starts_with("passage_entry"),
+                _ => false
+            }).unwrap();
+            code[..entry].iter().filter_map(|op| match op {
+                &ZOP::SetTextStyle{bold, reverse, monospace, italic} => Some((bold, reverse, monospace, italic)),
+                _ => None
+            }).collect::<Vec<_>>()
+        }).collect::<Vec<_>>()
+    };
+
+    let roman = (false, false, false, false);
+    assert_eq!(styles(zwreec::config::Config::default_config()), vec![vec![roman], vec![roman]]);
+
+    let mut cfg = zwreec::config::Config::default_config();
+    cfg.default_italic = true;
+    assert_eq!(styles(cfg), vec![vec![roman, (false, false, false, true)], vec![roman, (false, false, false, true)]]);
+}
+
+#[test]
+fn many_links_test() {
+    test_compile(TESTFOLDER_PASS.to_string()+"ManyLinks.twee");
+}
+
+#[test]
+fn separator_test() {
+    use zwreec::backend::zcode::zfile::Bytes;
+    use zwreec::backend::zcode::ztext;
+
+    let mut cfg = zwreec::config::Config::default_config();
+    cfg.separator_char = '*';
+    cfg.separator_length = 24;
+    let zcode = test_compile_with_cfg(TESTFOLDER_PASS.to_string() + "PassageLinks.twee", cfg);
+
+    let mut separator = Bytes{bytes: Vec::new()};
+    ztext::encode(&mut separator, "************************", &Vec::new());
+    assert!(zcode.windows(separator.bytes.len()).any(|window| window == &separator.bytes[..]));
+}
+
+#[test]
+fn otherwise_test() {
+    test_compile(TESTFOLDER_PASS.to_string() + "OtherwiseFires.twee");
+    test_compile(TESTFOLDER_PASS.to_string() + "OtherwiseSkipped.twee");
+
+    let mut cfg = zwreec::config::Config::default_config();
+    cfg.no_dead_end_quit = true;
+    let zcode = test_compile_with_cfg(TESTFOLDER_PASS.to_string() + "OtherwiseFires.twee", cfg);
+    assert!(zcode != test_compile_with_cfg(TESTFOLDER_PASS.to_string() + "OtherwiseFires.twee", zwreec::config::Config::default_config()));
+}
+
+#[test]
+fn countdown_test() {
+    test_compile(TESTFOLDER_PASS.to_string() + "Countdown.twee");
+}
+
+#[test]
+fn countdown_redraw_test() {
+    use zwreec::backend::zcode::zfile::{ZOP, Zfile};
+
+    // the passage hands the seconds to the runtime
+    let mut input = Cursor::new("::Start\nHurry: <<countdown 30s \"Late\">>\n::Late\nToo late.".to_string().into_bytes());
+    let passages = zwreec::compiled_ops(zwreec::config::Config::default_config(), &mut input);
+    let (_, ref start) = passages[0];
+    assert!(start.iter().any(|op| match op {
+        &ZOP::CallVNA2{ref jump_to_label, ref arg1, ..} => jump_to_label == "rt_countdown" && arg1.is_const() && arg1.const_value() == 30,
+        _ => false
+    }));
+
+    // the timed read calls the tick routine, which ends it every second to redraw the number
+    let routines = Zfile::new().countdown_routines();
+    let has = |f: &Fn(&ZOP) -> bool| routines.iter().any(|op| f(op));
+    assert!(has(&|op| match op { &ZOP::Routine{ref name, ..} => name == "rt_countdown_tick", _ => false }));
+    assert!(has(&|op| match op { &ZOP::ReadCharTimer{ref routine, ..} => routine == "rt_countdown_tick", _ => false }));
+    assert!(has(&|op| match op { &ZOP::SetCursorOperand{..} => true, _ => false }));
+    assert!(has(&|op| match op { &ZOP::EraseLine => true, _ => false }));
+
+    // interpreters without timed input ask for a key instead
+    assert!(has(&|op| match op { &ZOP::Print{ref text} => text.contains("press a key"), _ => false }));
+}
+
+#[test]
+fn unreachable_content_test() {
+    use zwreec::backend::zcode::zfile::ZOP;
+
+    let path = TESTFOLDER_PASS.to_string() + "UnreachableContent.twee";
+    test_compile(path.clone());
+
+    // no passage falls through into the routine behind it, and Start returns only at the end
+    let mut input = File::open(Path::new(&path)).unwrap();
+    let passages = zwreec::compiled_ops(zwreec::config::Config::default_config(), &mut input);
+    for &(ref name, ref ops) in passages.iter() {
+        match ops.last() {
+            Some(&ZOP::Ret{..}) | Some(&ZOP::Quit) => (),
+            other => panic!("Passage '{}' ends with {:?}", name, other),
+        }
+    }
+    let (_, ref start) = passages[0];
+    assert!(!start.iter().any(|op| match op {
+        &ZOP::Call2NWithAddress{ref address, ..} => address == "Explosion",
+        _ => false
+    }));
+}
+
+#[test]
+#[should_panic]
+fn countdown_passage_variable_test() {
+    test_compile(TESTFOLDER_FAIL.to_string() + "CountdownPassageVariable.twee");
+}
+
+#[test]
+fn config_function_test() {
+    let path = TESTFOLDER_PASS.to_string() + "Config.twee";
+    let zcode = test_compile_with_cfg(path.clone(), zwreec::config::Config::default_config());
+    assert!(contains_utf16_string(&zcode, "colour display recommended"));
+    assert!(!contains_utf16_string(&zcode, "monochrome build"));
+    assert!(contains_utf16_string(&zcode, "with easter egg"));
+    assert!(contains_utf16_string(&zcode, env!("CARGO_PKG_VERSION")));
+
+    let mut cfg = zwreec::config::Config::default_config();
+    cfg.no_colours = true;
+    cfg.easter_egg = false;
+    let zcode = test_compile_with_cfg(path, cfg);
+    assert!(contains_utf16_string(&zcode, "monochrome build"));
+    assert!(!contains_utf16_string(&zcode, "colour display recommended"));
+    assert!(!contains_utf16_string(&zcode, "with easter egg"));
+}
+
+#[test]
+#[should_panic]
+fn config_unknown_name_test() {
+    test_compile(TESTFOLDER_FAIL.to_string() + "ConfigUnknownName.twee");
+}
+
+#[test]
+fn bar_test() {
+    let zcode = test_compile_with_cfg(TESTFOLDER_PASS.to_string() + "Bar.twee", zwreec::config::Config::default_config());
+    assert!(contains_utf16_string(&zcode, "[###-------]"));
+}
+
+#[test]
+fn single_line_if_links_test() {
+    test_compile(TESTFOLDER_PASS.to_string() + "SingleLineIfLinks.twee");
+}
+
+#[test]
+fn onexit_test() {
+    test_compile(TESTFOLDER_PASS.to_string() + "OnExit.twee");
+}
+
+#[test]
+fn tic_tac_toe_test() {
+    test_compile(TESTFOLDER_PASS.to_string() + "Tic-Tac-Toe.twee");
+}
+
+#[test]
+fn unsupported_formatting_test() {
+    test_compile(TESTFOLDER_PASS.to_string() + "UnsupportedFormatting.twee");
+}
+
+#[test]
+#[should_panic]
+fn random_empty_range_test() {
+    test_compile(TESTFOLDER_FAIL.to_string() + "RandomEmptyRange.twee");
+}
+
+#[test]
+#[should_panic]
+fn stubbed_passage_test() {
+    test_compile(TESTFOLDER_FAIL.to_string() + "StubbedPassage.twee");
+}
+
+#[test]
+fn stubbed_passage_force_test() {
+    use zwreec::backend::zcode::zfile::ZOP;
+
+    // with --force the passage with the too long expression only prints the error
+    let path = TESTFOLDER_FAIL.to_string() + "StubbedPassage.twee";
+    let mut cfg = zwreec::config::Config::default_config();
+    cfg.force = true;
+    test_compile_with_cfg(path.clone(), cfg.clone());
+    assert_eq!(1, cfg.error_count());
+
+    let mut input = File::open(Path::new(&path)).unwrap();
+    let passages = zwreec::compiled_ops(cfg, &mut input);
+    let (_, ref start) = passages[0];
+    let (ref name, ref broken) = passages[1];
+    assert_eq!(name, "Broken");
+    assert!(start.iter().any(|op| match op {
+        &ZOP::Call2NWithAddress{ref address, ..} => address == "Broken",
+        _ => false
+    }));
+    assert!(broken.iter().any(|op| match op {
+        &ZOP::PrintOps{ref text} => text == "[[passage 'Broken' failed to compile: No temporary identifier left on the stack. Expression is too long.]]",
+        _ => false
+    }));
+}
+
+#[test]
+fn ternary_test() {
+    let zcode = test_compile_with_cfg(TESTFOLDER_PASS.to_string() + "Ternary.twee",
+                                      zwreec::config::Config::default_config());
+    // both branches of a runtime condition are compiled
+    assert!(contains_utf16_string(&zcode, "alive"));
+    assert!(contains_utf16_string(&zcode, "dead"));
+    assert!(contains_utf16_string(&zcode, "strong"));
+    // a constant condition only compiles the taken branch
+    assert!(contains_utf16_string(&zcode, "taken"));
+    assert!(!contains_utf16_string(&zcode, "skipped"));
+}
+
+#[test]
+fn capability_report_test() {
+    use zwreec::backend::zcode::capabilities::Capability;
+    use zwreec::backend::zcode::zfile::{Operand, ZOP};
+    use zwreec::config::Config;
+
+    let capabilities = |flags: &[&str], twee: &str| {
+        let mut cfg = Config::from_args(flags).unwrap();
+        cfg.disable_optional_content();
+        let report = zwreec::compile(cfg, &mut Cursor::new(twee.as_bytes()), &mut Cursor::new(Vec::new()));
+        (report.required, report.optional)
+    };
+
+    assert_eq!(capabilities(&[], "::Start\nHello World"), (vec![Capability::Colours], vec![]));
+    assert_eq!(capabilities(&["-F", "transition-pause"], "::Start\nHello World"),
+        (vec![Capability::Colours], vec![Capability::TimedInput]));
+    assert_eq!(capabilities(&["-F", "force-unicode"], "::Start\nH\u{e4}llo \u{263a}"),
+        (vec![Capability::Unicode, Capability::Colours], vec![]));
+    assert_eq!(capabilities(&["-F", "no-colours"], "::Start\nBoom in <<countdown 3 \"End\">>\n::End\n<<savegame>>"),
+        (vec![Capability::Windows, Capability::SaveRestore], vec![Capability::TimedInput]));
+
+    // plugin macros can play sounds and set true colours
+    let mut cfg = Config::default_config();
+    cfg.disable_optional_content();
+    cfg.macros.register("beep", |call| {
+        vec![ZOP::SoundEffect{number: call.args.first().cloned().unwrap_or(Operand::new_const(1))}]
+    }).unwrap();
+    cfg.macros.register("sky", |_| {
+        vec![ZOP::SetTrueColor{foreground: Operand::new_large_const(0x7c00), background: Operand::new_large_const(-2)}]
+    }).unwrap();
+    let report = zwreec::compile(cfg, &mut Cursor::new("::Start
+<<beep 2>><<sky>>Blue".as_bytes()), &mut Cursor::new(Vec::new()));
+    assert_eq!((report.required, report.optional), (vec![Capability::Colours, Capability::TrueColour, Capability::Sound], vec![]));
+}
+
+#[test]
+fn widgets_test() {
+    use zwreec::backend::zcode::zfile::ZOP;
+
+    let path = TESTFOLDER_PASS.to_string() + "Widgets.twee";
+    let mut input = File::open(Path::new(&path)).unwrap();
+    let passages = zwreec::compiled_ops(zwreec::config::Config::default_config(), &mut input);
+    let names: Vec<&str> = passages.iter().map(|&(ref name, _)| &name[..]).collect();
+    assert_eq!(names, vec!["Start", "Fight"]);
+
+    // status calls healthbar, so both passages print its text
+    let prints = |passage: &str| {
+        let &(_, ref code) = passages.iter().find(|&&(ref name, _)| name == passage).unwrap();
+        code.iter().filter_map(|op| match op {
+            &ZOP::Print{ref text} => Some(text.clone()),
+            _ => None
+        }).collect::<Vec<String>>().concat()
+    };
+    assert!(prints("Start").contains("HP: "));
+    assert!(prints("Fight").contains("HP: "));
+}
+
+#[test]
+fn maps_test() {
+    use zwreec::backend::zcode::zfile::{Operand, ZOP};
+
+    test_compile(TESTFOLDER_PASS.to_string() + "Maps.twee");
+
+    let path = TESTFOLDER_PASS.to_string() + "Maps.twee";
+    let mut input = File::open(Path::new(&path)).unwrap();
+    let passages = zwreec::compiled_ops(zwreec::config::Config::default_config(), &mut input);
+    let code: Vec<&ZOP> = passages.iter().flat_map(|&(_, ref code)| code.iter()).collect();
+
+    // $hero holds the nested map of its stats in the same block
+    let sizes: Vec<i16> = code.iter().filter_map(|op| match *op {
+        &ZOP::Call2S{ref jump_to_label, arg: Operand::LargeConst(ref size), ..} if jump_to_label == "malloc" => Some(size.value),
+        _ => None
+    }).collect();
+    assert_eq!(sizes, vec![11, 16]);
+
+    let lookups = code.iter().filter(|op| match **op {
+        &ZOP::CallVSA3{ref jump_to_label, ..} => jump_to_label == "map_get",
+        _ => false
+    }).count();
+    assert_eq!(lookups, 7);
+}
+
+#[test]
+fn unicode_normalization_test() {
+    test_compile(TESTFOLDER_PASS.to_string() + "UnicodeNormalization.twee");
+
+    let path = TESTFOLDER_PASS.to_string() + "UnicodeNormalization.twee";
+    let mut input = File::open(Path::new(&path)).unwrap();
+    let passages = zwreec::compiled_ops(zwreec::config::Config::default_config(), &mut input);
+    let names: Vec<&str> = passages.iter().map(|&(ref name, _)| &name[..]).collect();
+    assert_eq!(names, vec!["Start", "Caf\u{e9}", "Cr\u{e8}me"]);
+}
+
+#[test]
+fn trailing_whitespace_test() {
+    // Twine exports may keep the whitespace behind the name in the declaration
+    test_compile(TESTFOLDER_PASS.to_string() + "TrailingWhitespace.twee");
+
+    let path = TESTFOLDER_PASS.to_string() + "TrailingWhitespace.twee";
+    let mut input = File::open(Path::new(&path)).unwrap();
+    let passages = zwreec::compiled_ops(zwreec::config::Config::default_config(), &mut input);
+    let names: Vec<&str> = passages.iter().map(|&(ref name, _)| &name[..]).collect();
+    assert_eq!(names, vec!["Start", "Cave"]);
+}
+
+#[test]
+#[should_panic(expected = "looks the same, but its accents are other unicode characters")]
+fn differently_normalized_link_test() {
+    test_compile(TESTFOLDER_FAIL.to_string() + "DifferentlyNormalizedLink.twee");
+}
+
+/// Returns the passages the preconditions of `passage` redirect to, with the evaluated
+/// conditions in front of the first text.
+fn precondition_targets(path: String, passage: &str) -> Vec<String> {
+    use zwreec::backend::zcode::zfile::ZOP;
+
+    test_compile(path.clone());
+
+    let mut input = File::open(Path::new(&path)).unwrap();
+    let passages = zwreec::compiled_ops(zwreec::config::Config::default_config(), &mut input);
+    let &(_, ref code) = passages.iter().find(|&&(ref name, _)| name == passage).unwrap();
+    let first_text = code.iter().position(|op| match op {
+        &ZOP::PrintOps{..} => true,
+        _ => false
+    }).unwrap();
+
+    // every guard skips the redirect if its condition is met
+    let guards = code[..first_text].iter().filter(|op| match op {
+        &&ZOP::JNE{ref jump_to_label, ..} => jump_to_label.starts_with("precondition_met"),
+        _ => false
+    }).count();
+    let targets: Vec<String> = code[..first_text].iter().filter_map(|op| match op {
+        &ZOP::Call1N{ref jump_to_label} if jump_to_label != "mem_free" => Some(jump_to_label.clone()),
+        _ => None
+    }).collect();
+    assert_eq!(guards, targets.len());
+    targets
+}
+
+#[test]
+fn precondition_test() {
+    let targets = precondition_targets(TESTFOLDER_PASS.to_string() + "Precondition.twee", "MineShaft");
+    assert_eq!(targets, vec!["DarkDeath"]);
+}
+
+#[test]
+fn multiple_preconditions_test() {
+    // the preconditions are checked in the order they are declared
+    let targets = precondition_targets(TESTFOLDER_PASS.to_string() + "Preconditions.twee", "MineShaft");
+    assert_eq!(targets, vec!["Dark Death", "Fall"]);
+}
+
+#[test]
+#[should_panic]
+fn precondition_target_test() {
+    test_compile(TESTFOLDER_FAIL.to_string() + "PreconditionTarget.twee");
+}
+
+/// Counts the ops in the code of a passage of CommonSubexpressions.twee that match.
+fn count_ops<F: Fn(&zwreec::backend::zcode::zfile::ZOP) -> bool>(passage: &str, matches: F) -> usize {
+    let path = TESTFOLDER_PASS.to_string() + "CommonSubexpressions.twee";
+    let mut input = File::open(Path::new(&path)).unwrap();
+    let passages = zwreec::compiled_ops(zwreec::config::Config::default_config(), &mut input);
+    let &(_, ref code) = passages.iter().find(|&&(ref name, _)| name == passage).unwrap();
+    code.iter().filter(|op| matches(op)).count()
+}
+
+#[test]
+fn common_subexpression_test() {
+    use zwreec::backend::zcode::zfile::ZOP;
+
+    test_compile(TESTFOLDER_PASS.to_string() + "CommonSubexpressions.twee");
+
+    // $strength + $bonus is added once, the second print takes the kept value
+    let additions = count_ops("Start", |op| match op {
+        &ZOP::AddTypes{..} => true,
+        _ => false
+    });
+    assert_eq!(additions, 1);
+}
+
+#[test]
+fn common_subexpression_assignment_test() {
+    use zwreec::backend::zcode::zfile::ZOP;
+
+    // $bonus + 1 and both prints of $strength + $bonus, which is evaluated again after the set
+    let additions = count_ops("Training", |op| match op {
+        &ZOP::AddTypes{..} => true,
+        _ => false
+    });
+    assert_eq!(additions, 3);
+}
+
+#[test]
+fn common_subexpression_function_test() {
+    use zwreec::backend::zcode::zfile::ZOP;
+
+    // every call of random() has its own value
+    let calls = count_ops("Dice", |op| match op {
+        &ZOP::Random{..} => true,
+        _ => false
+    });
+    assert_eq!(calls, 2);
+}
+
+#[test]
+fn reserved_output_test() {
+    // compile reserves the memory for the size of the source, generate_zcode alone does not
+    let path = TESTFOLDER_PASS.to_string() + "textadventure.twee";
+    let mut cfg = zwreec::config::Config::default_config();
+    cfg.reproducible = true;
+
+    let mut input = File::open(Path::new(&path)).unwrap();
+    let mut reserved = Cursor::new(Vec::new());
+    zwreec::compile(cfg.clone(), &mut input, &mut reserved);
+
+    let mut input = File::open(Path::new(&path)).unwrap();
+    let ast = zwreec::frontend_ast(cfg.clone(), &mut input);
+    let mut unreserved = Cursor::new(Vec::new());
+    assert_eq!(cfg.source_size, 0);
+    zwreec::backend::codegen::generate_zcode(&cfg, ast.into_iter(), &mut unreserved);
+
+    assert_eq!(reserved.into_inner(), unreserved.into_inner());
+}
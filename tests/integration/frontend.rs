@@ -0,0 +1,397 @@
+//! Integration tests of the lexer and parser: syntax errors, dialects, comments and the
+//! characters of passages
+
+extern crate zwreec;
+use std::fs::File;
+use std::path::Path;
+use support::{TESTFOLDER_FAIL, TESTFOLDER_PASS, ast_tokens, contains_utf16_string, test_compile, test_compile_with_cfg};
+
+#[test]
+fn zscii_test() {
+    test_compile(TESTFOLDER_PASS.to_string() + "ZSCII.twee");
+}
+
+#[test]
+fn ascii_test() {
+    test_compile(TESTFOLDER_PASS.to_string() + "ASCII.twee");
+}
+
+#[test]
+fn unicode_test() {
+    test_compile(TESTFOLDER_PASS.to_string() + "Unicode.twee");
+}
+
+#[test]
+fn dialect_macro_newline_test() {
+    use zwreec::config::{Config, Dialect};
+    use zwreec::frontend::lexer::Token::TokNewLine;
+
+    let path = TESTFOLDER_PASS.to_string() + "DialectMacroNewline.twee";
+    let newlines = |dialect: Dialect| {
+        let mut cfg = Config::default_config();
+        cfg.dialect = dialect;
+        test_compile_with_cfg(path.clone(), cfg.clone());
+        ast_tokens(path.clone(), cfg).iter().filter(|token| match token {
+            &&TokNewLine{..} => true,
+            _ => false
+        }).count()
+    };
+
+    // SugarCube drops the line breaks right after <<set>>, <<if>> and <<endif>>, not after "gold."
+    assert_eq!(newlines(Dialect::Legacy), newlines(Dialect::SugarCube) + 3);
+    assert_eq!(newlines(Dialect::Harlowe), newlines(Dialect::Legacy));
+}
+
+#[test]
+fn comments_test() {
+    use zwreec::frontend::lexer::Token::*;
+
+    let path = TESTFOLDER_PASS.to_string() + "Comments.twee";
+    let zcode = test_compile_with_cfg(path.clone(), zwreec::config::Config::default_config());
+    assert!(!contains_utf16_string(&zcode, "hidden treasure") && !contains_utf16_string(&zcode, "hidden text"));
+
+    // the commented-out macros are skipped, the comment in Next ends at the declaration of Last
+    let tokens = ast_tokens(path, zwreec::config::Config::default_config());
+    assert!(!tokens.iter().any(|token| match token { &TokMacroSet{..} | &TokMacroPrint{..} | &TokMacroDisplay{..} => true, _ => false }));
+    assert!(tokens.iter().any(|token| match token { &TokPassage{ref name, ..} => name == "Last", _ => false }));
+}
+
+#[test]
+fn dialect_formatting_test() {
+    use zwreec::config::{Config, Dialect};
+    use zwreec::frontend::lexer::Token::*;
+
+    let path = TESTFOLDER_PASS.to_string() + "DialectFormatting.twee";
+    let tokens = |dialect: Dialect| {
+        let mut cfg = Config::default_config();
+        cfg.dialect = dialect;
+        ast_tokens(path.clone(), cfg)
+    };
+
+    // Twee 1.4 reads ~~ as subscript, Harlowe as strikethrough and __ as text
+    let twee = tokens(Dialect::Legacy);
+    assert!(twee.iter().any(|token| match token { &TokFormatSubStart{..} => true, _ => false }));
+    assert!(twee.iter().any(|token| match token { &TokFormatUnderStart{..} => true, _ => false }));
+
+    let harlowe = tokens(Dialect::Harlowe);
+    assert!(harlowe.iter().any(|token| match token { &TokFormatStrikeStart{..} => true, _ => false }));
+    assert!(!harlowe.iter().any(|token| match token { &TokFormatSubStart{..} | &TokFormatUnderStart{..} => true, _ => false }));
+    assert!(harlowe.iter().any(|token| match token { &TokText{ref text, ..} => text == " and __kept__.", _ => false }));
+}
+
+#[test]
+fn trim_verbatim_test() {
+    test_compile(TESTFOLDER_PASS.to_string() + "TrimVerbatim.twee");
+}
+
+#[test]
+fn story_script_test() {
+    test_compile(TESTFOLDER_PASS.to_string() + "StoryScript.twee");
+}
+
+#[test]
+#[should_panic]
+fn expression_double_operators_test() {
+    test_compile(TESTFOLDER_FAIL.to_string() + "ExpressionDoubleOperators.twee");
+}
+
+#[test]
+#[should_panic]
+fn expression_wrong_parentheses1_test() {
+    test_compile(TESTFOLDER_FAIL.to_string() + "ExpressionsWrongParentheses1.twee");
+}
+
+#[test]
+#[should_panic]
+fn expression_wrong_parentheses2_test() {
+    test_compile(TESTFOLDER_FAIL.to_string() + "ExpressionsWrongParentheses2.twee");
+}
+
+/// Compiles the story with `--force` and returns the errors that were reported.
+fn forced_errors(path: String) -> Vec<zwreec::utils::error::ErrorRecord> {
+    let mut cfg = zwreec::config::Config::default_config();
+    cfg.force = true;
+    test_compile_with_cfg(path, cfg.clone());
+    cfg.reported_errors()
+}
+
+#[test]
+fn expression_double_operators_location_test() {
+    // the second '*' of <<print 1**2>> has no operand, a 0 takes its place
+    let errors = forced_errors(TESTFOLDER_FAIL.to_string() + "ExpressionDoubleOperators.twee");
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0].code, "ExpressionParserError::MissingLeftOperand");
+    assert_eq!(errors[0].location, Some((2, 11)));
+    assert_eq!(errors[0].message, "The operator '*' at 2:11 has no operand in front of it");
+}
+
+#[test]
+fn expression_unclosed_parenthesis_location_test() {
+    let errors = forced_errors(TESTFOLDER_FAIL.to_string() + "ExpressionsWrongParentheses1.twee");
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0].code, "ParserError::UnclosedParenthesis");
+    assert_eq!(errors[0].location, Some((2, 9)));
+    assert_eq!(errors[0].message, "The parenthesis opened at 2:9 is not closed in front of 2:17");
+}
+
+#[test]
+fn expression_missing_operand_location_test() {
+    let mut cfg = zwreec::config::Config::default_config();
+    cfg.force = true;
+    zwreec::compile_in_memory(cfg.clone(), "::Start\n<<print (1*)+2>>\n");
+
+    let errors = cfg.reported_errors();
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0].code, "ExpressionParserError::MissingRightOperand");
+    assert_eq!(errors[0].location, Some((2, 11)));
+}
+
+#[test]
+fn passage_name_characters_test() {
+    test_compile(TESTFOLDER_PASS.to_string() + "PassageNameCharacters.twee");
+}
+
+#[test]
+#[should_panic]
+fn unterminated_link_title_test() {
+    test_compile(TESTFOLDER_FAIL.to_string() + "UnterminatedLinkTitle.twee");
+}
+
+#[test]
+#[should_panic]
+fn duplicate_passages() {
+    test_compile(TESTFOLDER_FAIL.to_string() + "DuplicatePassage.twee");
+}
+
+#[test]
+#[should_panic]
+fn invalid_macro_test() {
+   test_compile(TESTFOLDER_FAIL.to_string() + "InvalidMacro.twee");
+}
+
+#[test]
+#[should_panic]
+fn invalid_passage_link() {
+    test_compile(TESTFOLDER_FAIL.to_string() + "InvalidPassageLink.twee");
+}
+
+#[test]
+#[should_panic]
+fn multiple_else_test() {
+    test_compile(TESTFOLDER_FAIL.to_string() + "MultipleElse.twee");
+}
+
+#[test]
+#[should_panic]
+fn multiple_endif_test() {
+    test_compile(TESTFOLDER_FAIL.to_string() + "MultipleEndIf.twee");
+}
+
+#[test]
+#[should_panic]
+fn link_to_story_script_test() {
+    test_compile(TESTFOLDER_FAIL.to_string() + "LinkToStoryScript.twee");
+}
+
+#[test]
+#[should_panic]
+fn multiple_onexit_test() {
+    test_compile(TESTFOLDER_FAIL.to_string() + "MultipleOnExit.twee");
+}
+
+#[test]
+#[should_panic(expected = "Unexpected <<else>> without <<if>> at 2:12")]
+fn stray_else_test() {
+    test_compile(TESTFOLDER_FAIL.to_string() + "StrayElse.twee");
+}
+
+#[test]
+fn stray_else_force_test() {
+    // with --force the stray <<else>> is reported once and skipped
+    let mut cfg = zwreec::config::Config::default_config();
+    cfg.force = true;
+    test_compile_with_cfg(TESTFOLDER_FAIL.to_string() + "StrayElse.twee", cfg.clone());
+    assert_eq!(1, cfg.error_count());
+    assert_eq!(cfg.reported_errors()[0].message, "Unexpected <<else>> without <<if>> at 2:12");
+}
+
+#[test]
+fn error_format_json_test() {
+    let mut cfg = zwreec::config::Config::from_args(&["--force", "--error-format", "json"]).unwrap();
+    cfg.source_name = Some("StrayElse.twee".to_string());
+    test_compile_with_cfg(TESTFOLDER_FAIL.to_string() + "StrayElse.twee", cfg.clone());
+
+    let errors = cfg.reported_errors();
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0].location, Some((2, 12)));
+    assert_eq!(errors[0].to_json(), "{\"file\":\"StrayElse.twee\",\"line\":2,\"col\":12,\"byte_offset\":19,\"severity\":\"error\",\
+        \"message\":\"Unexpected <<else>> without <<if>> at 2:12\",\"code\":\"ParserError::UnexpectedConditional\"}");
+}
+
+#[test]
+fn error_byte_offset_test() {
+    // the columns count characters, the offsets count the bytes of the umlauts twice
+    let source = "::Start\n\u{e4}\u{f6}\u{fc} <<else>>\n";
+    let mut cfg = zwreec::config::Config::from_args(&["--force", "--error-format", "json"]).unwrap();
+    cfg.source_name = Some("Umlauts.twee".to_string());
+    zwreec::compile_in_memory(cfg.clone(), source);
+
+    let errors = cfg.reported_errors();
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0].location, Some((2, 7)));
+    assert_eq!(errors[0].byte_offset, Some(source.find("else").unwrap() as u64));
+    assert!(errors[0].to_json().contains(&format!("\"col\":7,\"byte_offset\":{},", source.find("else").unwrap())));
+
+    // a Byte Order Mark is counted, though the screener removes it
+    let cfg = zwreec::config::Config::from_args(&["--force"]).unwrap();
+    let mut input = ::std::io::Cursor::new([&[0xefu8, 0xbb, 0xbf][..], source.as_bytes()].concat());
+    zwreec::compile(cfg.clone(), &mut input, &mut ::std::io::Cursor::new(Vec::new()));
+    assert_eq!(cfg.reported_errors()[0].byte_offset, Some(source.find("else").unwrap() as u64 + 3));
+}
+
+#[test]
+fn widgets_test() {
+    use zwreec::frontend::lexer::Token::{TokArrayAccess, TokArrayLength, TokAssign, TokMacroWidget, TokMacroWidgetCall, TokPassage, TokVariable};
+
+    let path = TESTFOLDER_PASS.to_string() + "Widgets.twee";
+    test_compile(path.clone());
+
+    // the calls are replaced by the bodies and the passage with the definitions is left out
+    let tokens = ast_tokens(path, zwreec::config::Config::default_config());
+    let passages: Vec<&str> = tokens.iter().filter_map(|token| match token {
+        &TokPassage{ref name, ..} => Some(&name[..]),
+        _ => None
+    }).collect();
+    assert_eq!(passages, vec!["Start", "Fight"]);
+    assert!(!tokens.iter().any(|token| match token {
+        &TokMacroWidget{..} | &TokMacroWidgetCall{..} => true,
+        &TokArrayAccess{ref name, ..} | &TokArrayLength{ref name, ..} => name == "$args",
+        _ => false
+    }));
+
+    // the expansion of healthbar in status has its own $_bar and gets $hp passed on
+    let assigned: Vec<&str> = tokens.iter().filter_map(|token| match token {
+        &TokAssign{ref var_name, ..} => Some(&var_name[..]),
+        _ => None
+    }).collect();
+    assert_eq!(assigned, vec!["$_args#healthbar.0.0", "$_bar#healthbar.0", "$hp", "$_args#status.0.0", "$_args#status.0.1",
+        "$_args#healthbar.1.0", "$_bar#healthbar.1"]);
+    assert_eq!(tokens.iter().filter(|token| match token { &&TokVariable{ref name, ..} => name == "$hp", _ => false }).count(), 1);
+}
+
+#[test]
+fn widget_arguments_test() {
+    use zwreec::frontend::lexer::Token::{TokFunction, TokVariable};
+
+    // the argument is evaluated once, the body reads it twice from the variable of the expansion
+    let path = TESTFOLDER_PASS.to_string() + "WidgetArguments.twee";
+    test_compile(path.clone());
+    let tokens = ast_tokens(path, zwreec::config::Config::default_config());
+    assert_eq!(tokens.iter().filter(|token| match token { &&TokFunction{ref name, ..} => name == "draw", _ => false }).count(), 1);
+    assert_eq!(tokens.iter().filter(|token| match token { &&TokVariable{ref name, ..} => name == "$_args#twice.0.0", _ => false }).count(), 2);
+}
+
+#[test]
+fn widget_call_sites_test() {
+    // every call uses the two variables of the arguments again, so 150 calls fit in the globals
+    let calls: String = (0..150).map(|i| format!("<<pair {}, {}>>", i, i + 1)).collect();
+    let source = format!("::Start\n{}\n::Widgets [widget]\n<<widget \"pair\">><<print $args[0] + $args[1]>><<endwidget>>", calls);
+    let cfg = zwreec::config::Config::default_config();
+    zwreec::compile(cfg.clone(), &mut ::std::io::Cursor::new(source.into_bytes()), &mut ::std::io::Cursor::new(Vec::new()));
+    assert!(cfg.reported_errors().is_empty());
+}
+
+#[test]
+#[should_panic(expected = "all 231 global variables of the story are taken already")]
+fn too_many_variables_test() {
+    let sets: String = (0..232).map(|i| format!("<<set $v{} to {}>>", i, i)).collect();
+    let source = format!("::Start\n{}", sets);
+    let mut output = ::std::io::Cursor::new(Vec::new());
+    zwreec::compile(zwreec::config::Config::default_config(), &mut ::std::io::Cursor::new(source.into_bytes()), &mut output);
+}
+
+#[test]
+#[should_panic]
+fn recursive_widget_test() {
+    test_compile(TESTFOLDER_FAIL.to_string() + "RecursiveWidget.twee");
+}
+
+#[test]
+fn recursive_widget_force_test() {
+    let mut cfg = zwreec::config::Config::default_config();
+    cfg.force = true;
+    test_compile_with_cfg(TESTFOLDER_FAIL.to_string() + "RecursiveWidget.twee", cfg.clone());
+
+    let errors = cfg.reported_errors();
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0].code, "WidgetError::RecursiveWidget");
+    assert!(errors[0].message.ends_with("is recursive: countdown_loop -> again -> countdown_loop"), "{}", errors[0].message);
+}
+
+#[test]
+#[should_panic]
+fn misspelled_widget_test() {
+    test_compile(TESTFOLDER_FAIL.to_string() + "MisspelledWidget.twee");
+}
+
+#[test]
+#[should_panic]
+fn passage_not_allowed_chars1_test() {
+    test_compile(TESTFOLDER_FAIL.to_string() + "PassageNotAllowedChars1.twee");
+}
+
+#[test]
+#[should_panic]
+fn passage_not_allowed_chars2_test() {
+    test_compile(TESTFOLDER_FAIL.to_string() + "PassageNotAllowedChars2.twee");
+}
+
+#[test]
+#[should_panic]
+fn wrong_formatting_test() {
+    test_compile(TESTFOLDER_FAIL.to_string() + "WrongFormatting.twee");
+}
+
+#[test]
+fn too_many_errors_test() {
+    use std::thread;
+
+    // --force goes on after an error, but only up to max_errors
+    let mut cfg = zwreec::config::Config::default_config();
+    cfg.force = true;
+    cfg.max_errors = 3;
+    let compile_cfg = cfg.clone();
+    let panic = thread::spawn(move || test_compile_with_cfg(TESTFOLDER_FAIL.to_string() + "TooManyErrors.twee", compile_cfg)).join().err().unwrap();
+    let message = panic.downcast_ref::<&str>().map(|message| message.to_string()).or(panic.downcast_ref::<String>().cloned());
+    assert_eq!(message, Some("Reached the maximum number of errors. Use --max-errors to raise the limit.".to_string()));
+
+    assert_eq!(cfg.error_count(), 3);
+    let errors = cfg.reported_errors();
+    assert_eq!(errors.len(), 3);
+    assert!(errors.iter().all(|error| error.severity == "error" && !error.message.is_empty()), "{:?}", errors.iter().map(|error| &error.message).collect::<Vec<_>>());
+}
+
+#[test]
+fn compat_report_test() {
+    use zwreec::frontend::compat::CompatKind;
+
+    let path = TESTFOLDER_FAIL.to_string() + "CompatReport.twee";
+    let mut input = File::open(Path::new(&path)).unwrap();
+    let report = zwreec::compat_report(zwreec::config::Config::default_config(), &mut input);
+
+    assert!(report.is_blocking());
+    assert_eq!(report.count(CompatKind::UnknownMacro, "timed"), 2);
+    assert_eq!(report.count(CompatKind::UnknownMacro, "endtimed"), 1);
+    assert_eq!(report.count(CompatKind::UnknownMacro, "Plain"), 0);
+    assert_eq!(report.count(CompatKind::UnknownFunction, "either"), 1);
+    assert_eq!(report.count(CompatKind::UnknownFunction, "random"), 0);
+    assert_eq!(report.count(CompatKind::IgnoredPassage, "Style"), 1);
+    assert_eq!(report.count(CompatKind::IgnoredPassage, "Script"), 1);
+    assert_eq!(report.count(CompatKind::HtmlTag, "span"), 2);
+    assert_eq!(report.count(CompatKind::WideCharacter, "U+1F389"), 1);
+    assert_eq!((report.supported_passages, report.passages), (1, 5));
+
+    let text = report.to_string();
+    assert!(text.contains("Unsupported functions (blocking):\n    either: 1 use, first at 4:9\n"));
+    assert!(text.ends_with("1 of 5 passages use only supported features"));
+}
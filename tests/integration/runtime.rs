@@ -0,0 +1,111 @@
+//! Integration tests of the runtime routines: memory, runtime errors, decks and the main loop
+
+extern crate zwreec;
+use properties::story;
+use support::{TESTFOLDER_FAIL, TESTFOLDER_PASS, contains_utf16_string, test_compile, test_compile_with_cfg};
+
+#[test]
+fn oom_message_test() {
+    let path = TESTFOLDER_PASS.to_string() + "HelloWorld.twee";
+    let zcode = test_compile_with_cfg(path.clone(), zwreec::config::Config::default_config());
+    assert!(contains_utf16_string(&zcode, "MALLOC-FAIL"));
+
+    let mut cfg = zwreec::config::Config::default_config();
+    cfg.oom_message = "Der Speicher ist voll, bitte neu starten".to_string();
+    cfg.oom_collect = true;
+    let zcode = test_compile_with_cfg(path, cfg);
+    assert!(contains_utf16_string(&zcode, "Der Speicher ist voll, bitte neu starten"));
+    assert!(!contains_utf16_string(&zcode, "MALLOC-FAIL"));
+}
+
+#[test]
+fn heap_stats_test() {
+    let path = TESTFOLDER_PASS.to_string() + "HeapStats.twee";
+    let without = test_compile_with_cfg(path.clone(), zwreec::config::Config::default_config());
+
+    // the counters and the check for H at the links are added
+    let mut cfg = zwreec::config::Config::default_config();
+    cfg.heap_stats = true;
+    let with = test_compile_with_cfg(path, cfg);
+    assert!(with.len() > without.len());
+}
+
+#[test]
+fn runtime_error_dead_end_test() {
+    let path = TESTFOLDER_PASS.to_string() + "RuntimeError.twee";
+    let zcode = test_compile_with_cfg(path, zwreec::config::Config::default_config());
+    // passages without links are only runtime errors with an error passage
+    assert!(contains_utf16_string(&zcode, "DEAD-END"));
+    let zcode = test_compile_with_cfg(TESTFOLDER_PASS.to_string() + "HelloWorld.twee", zwreec::config::Config::default_config());
+    assert!(!contains_utf16_string(&zcode, "DEAD-END"));
+}
+
+#[test]
+fn buildtime_reproducible_test() {
+    let mut cfg = zwreec::config::Config::default_config();
+    cfg.reproducible = true;
+    let zcode = test_compile_with_cfg(TESTFOLDER_PASS.to_string() + "BuildTime.twee", cfg);
+    assert!(contains_utf16_string(&zcode, "1970-01-01 00:00"));
+}
+
+#[test]
+fn join_test() {
+    let path = TESTFOLDER_PASS.to_string() + "Join.twee";
+    let zcode = test_compile_with_cfg(path, zwreec::config::Config::default_config());
+    assert!(contains_utf16_string(&zcode, ", ") && contains_utf16_string(&zcode, " and "));
+
+    // a new deck is joined in the order of its values, draw() swaps the drawn value behind the
+    // remaining ones
+    let machine = story::run_fixture(zwreec::config::Config::default_config(), "Join", "");
+    assert!(machine.transcript.contains("You carry 1, 2, 3."), "printed {:?}", machine.transcript);
+    let rest = match machine.global(26) {
+        1 => "3 and 2",
+        2 => "1 and 3",
+        3 => "1 and 2",
+        lost => panic!("draw() returned {}, which is not in the deck", lost)
+    };
+    assert!(machine.transcript.contains(&format!("After the fall: {}", rest)), "printed {:?}", machine.transcript);
+}
+
+#[test]
+#[should_panic]
+fn join_separator_test() {
+    test_compile(TESTFOLDER_FAIL.to_string() + "JoinSeparator.twee");
+}
+
+#[test]
+#[should_panic]
+fn join_no_deck_test() {
+    test_compile(TESTFOLDER_FAIL.to_string() + "JoinNoDeck.twee");
+}
+
+#[test]
+fn transition_pause_test() {
+    let mut cfg = zwreec::config::Config::default_config();
+    cfg.transition_pause = true;
+    let zcode = test_compile_with_cfg(TESTFOLDER_PASS.to_string() + "PassageLinks.twee", cfg);
+
+    // the timed read_char is directly followed by the erase_window of the passage transition
+    let timed_read = [0xf6, 0x53, 0x01, zwreec::backend::zcode::zfile::TRANSITION_PAUSE];
+    let pause = zcode.windows(4).position(|window| window == &timed_read[..]).expect("no timed read_char");
+    let mut erase = Vec::new();
+    zwreec::backend::zcode::op::op_erase_window(-1, &mut erase);
+    assert_eq!(&zcode[pause + 7..pause + 7 + erase.len()], &erase[..]);
+}
+
+#[test]
+fn main_loop_test() {
+    use zwreec::config::{Config, MainLoop};
+    let path = TESTFOLDER_PASS.to_string() + "NoMainLoop.twee";
+
+    let links = test_compile_with_cfg(path.clone(), Config::default_config());
+    let mut cfg = Config::default_config();
+    cfg.main_loop = MainLoop::Quit;
+    let quit = test_compile_with_cfg(path.clone(), cfg);
+    let mut cfg = Config::default_config();
+    cfg.main_loop = MainLoop::Passage("Turn".to_string());
+    let turns = test_compile_with_cfg(path, cfg);
+
+    assert!(links != quit);
+    assert!(links != turns);
+}
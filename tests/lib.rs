@@ -1,6 +1,29 @@
 //! Extern test-lib for zwreec to test the whole zwreec library
 //!
-//! For more documentation see the `integration` module
+//! All suites are compiled into this one test binary, so adding a suite does not add the time
+//! to link another binary. The suites are:
+//!
+//! * `integration`: compiles the fixtures, split into `frontend`, `codegen`, `runtime` and `cli`
+//! * `golden`: compares the text and the Z-Code of the fixtures against snapshots, `UPDATE_GOLDEN=1` writes them
+//! * `zop_snapshots`: compares the instructions of small passages against snapshots
+//! * `properties`: runs random expressions on a simulator, `PROPERTY_SEED` picks other trees
+//! * `doc_examples`: checks the examples of the documentation
+//! * `benchmarks`: measures the compile time, only with `ZWREEC_BENCH=1`
+//!
+//! Run a single suite by its module path, e.g. `cargo test integration::frontend`, or a single
+//! test by its name.
+//!
+//! Tests that take more than a few seconds have the `slow_` prefix and only run with
+//! `ZWREEC_SLOW_TESTS=1`, otherwise they pass at once. `cargo test slow_` with the variable set
+//! runs only them. New suites use `support` for compiling fixtures and generating inputs instead
+//! of their own helpers.
 
 extern crate zwreec;
+mod benchmarks;
+mod doc_examples;
+mod golden;
 mod integration;
+mod properties;
+mod support;
+#[path = "zop-snapshots/mod.rs"]
+mod zop_snapshots;
@@ -0,0 +1,833 @@
+//! Property tests for the evaluation of expressions
+//!
+//! Random expression trees over integer and boolean constants and a few preset variables are
+//! printed in a passage, compiled and run on a small simulator of the generated instructions.
+//! The printed text has to match a reference interpreter, which uses the 16 bit arithmetic of
+//! the Z-Machine: the results wrap around and divisions truncate towards zero. The Z-Machine
+//! halts on a division by zero, so the divisors are always constants other than zero.
+//!
+//! The trees use a fixed seed. Run the tests with `PROPERTY_SEED=<number>` to check other trees
+//! or with `PROPERTY_SEED=random` to pick a new seed on every run. `random()` is never generated,
+//! so the results do not depend on the random generator of the story. With `ZWREEC_SLOW_TESTS=1`
+//! `slow_expression_properties_test` checks many more trees of the same seed.
+//!
+//! The simulator also runs passages of the should-compile fixtures whose output depends on the
+//! links added at runtime, like the fallback of `<<otherwise>>`, or on the keys pressed, like
+//! `<<countdown>>`. The stories that need the runtime routines, like the handling of runtime
+//! errors, run on the simulator of whole stories in `story`.
+
+extern crate zwreec;
+extern crate time;
+use std::collections::HashMap;
+use std::env;
+use std::fs::File;
+use std::io::{Cursor, Read};
+use std::path::Path;
+use zwreec::backend::zcode::zfile::{Operand, Type, ZOP, Zfile};
+use support::{slow_tests, Random, TESTFOLDER_PASS};
+
+pub mod story;
+
+/// The number of expressions checked per run
+static TREES: usize = 2000;
+
+/// The number of expressions checked by `slow_expression_properties_test`
+static SLOW_TREES: usize = 50000;
+
+/// The seed used without `PROPERTY_SEED`
+static SEED: u64 = 0x5eed;
+
+/// The deepest nesting of the generated expressions
+static DEPTH: u32 = 4;
+
+/// The integer variables set before the expression is printed
+static INT_VARIABLES: [(&'static str, i16); 4] = [("$a", 300), ("$b", -3), ("$c", 0), ("$d", 20000)];
+
+/// The boolean variables set before the expression is printed
+static BOOL_VARIABLES: [(&'static str, bool); 2] = [("$t", true), ("$f", false)];
+
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    Int(i16),
+    Bool(bool),
+}
+
+impl Value {
+    fn int(&self) -> i16 {
+        match self { &Value::Int(value) => value, _ => panic!("{:?} is not an integer", self) }
+    }
+
+    fn bool(&self) -> bool {
+        match self { &Value::Bool(value) => value, _ => panic!("{:?} is not a boolean", self) }
+    }
+
+    /// The text `<<print>>` shows for the value.
+    fn text(&self) -> String {
+        match self {
+            &Value::Int(value) => format!("{}", value),
+            &Value::Bool(value) => format!("{}", value),
+        }
+    }
+}
+
+/// An expression tree. Operators only get operands of the types they are defined for.
+#[derive(Debug, Clone)]
+enum Expr {
+    Int(i16),
+    Bool(bool),
+    Var(&'static str),
+    Minus(Box<Expr>),
+    Not(Box<Expr>),
+    Binary(&'static str, Box<Expr>, Box<Expr>),
+    Ternary(Box<Expr>, Box<Expr>, Box<Expr>),
+}
+
+impl Expr {
+    /// Returns a random integer expression.
+    fn int(random: &mut Random, depth: u32) -> Expr {
+        if depth == 0 || random.below(4) == 0 {
+            return match random.below(3) {
+                0 => Expr::Var(random.pick(&INT_VARIABLES).0),
+                // mostly small numbers, but large enough ones to overflow
+                1 => Expr::Int(random.below(30001) as i16),
+                _ => Expr::Int(random.below(21) as i16),
+            }
+        }
+
+        match random.below(5) {
+            0 => Expr::Minus(Box::new(Expr::int(random, depth - 1))),
+            1 => Expr::Ternary(Box::new(Expr::bool(random, depth - 1)), Box::new(Expr::int(random, depth - 1)), Box::new(Expr::int(random, depth - 1))),
+            2 => {
+                let divisor = Expr::Int(1 + random.below(9) as i16);
+                let divisor = if random.below(2) == 0 { divisor } else { Expr::Minus(Box::new(divisor)) };
+                Expr::Binary(random.pick(&["/", "%"]), Box::new(Expr::int(random, depth - 1)), Box::new(divisor))
+            },
+            _ => Expr::Binary(random.pick(&["+", "-", "*"]), Box::new(Expr::int(random, depth - 1)), Box::new(Expr::int(random, depth - 1))),
+        }
+    }
+
+    /// Returns a random boolean expression.
+    fn bool(random: &mut Random, depth: u32) -> Expr {
+        if depth == 0 || random.below(4) == 0 {
+            return match random.below(2) {
+                0 => Expr::Var(random.pick(&BOOL_VARIABLES).0),
+                _ => Expr::Bool(random.below(2) == 0),
+            }
+        }
+
+        match random.below(5) {
+            0 => Expr::Not(Box::new(Expr::bool(random, depth - 1))),
+            1 => Expr::Ternary(Box::new(Expr::bool(random, depth - 1)), Box::new(Expr::bool(random, depth - 1)), Box::new(Expr::bool(random, depth - 1))),
+            2 => Expr::Binary(random.pick(&["and", "or"]), Box::new(Expr::bool(random, depth - 1)), Box::new(Expr::bool(random, depth - 1))),
+            _ => Expr::Binary(random.pick(&["is", "==", "neq", "!=", "lt", "lte", "gt", "gte"]),
+                Box::new(Expr::int(random, depth - 1)), Box::new(Expr::int(random, depth - 1))),
+        }
+    }
+
+    /// Writes the expression in Twee syntax.
+    fn twee(&self) -> String {
+        match self {
+            &Expr::Int(value) => format!("{}", value),
+            &Expr::Bool(value) => format!("{}", value),
+            &Expr::Var(name) => name.to_string(),
+            &Expr::Minus(ref expr) => format!("-({})", expr.twee()),
+            // "not(" would be lexed as a function
+            &Expr::Not(ref expr) => format!("not ({})", expr.twee()),
+            &Expr::Binary(op, ref left, ref right) => format!("({} {} {})", left.twee(), op, right.twee()),
+            &Expr::Ternary(ref cond, ref then, ref otherwise) => format!("({} ? {} : {})", cond.twee(), then.twee(), otherwise.twee()),
+        }
+    }
+
+    /// The reference interpreter.
+    fn eval(&self) -> Value {
+        match self {
+            &Expr::Int(value) => Value::Int(value),
+            &Expr::Bool(value) => Value::Bool(value),
+            &Expr::Var(name) => {
+                match INT_VARIABLES.iter().find(|&&(variable, _)| variable == name) {
+                    Some(&(_, value)) => Value::Int(value),
+                    None => Value::Bool(BOOL_VARIABLES.iter().find(|&&(variable, _)| variable == name).unwrap().1)
+                }
+            },
+            &Expr::Minus(ref expr) => Value::Int(expr.eval().int().wrapping_neg()),
+            &Expr::Not(ref expr) => Value::Bool(!expr.eval().bool()),
+            &Expr::Ternary(ref cond, ref then, ref otherwise) => if cond.eval().bool() { then.eval() } else { otherwise.eval() },
+            &Expr::Binary(op, ref left, ref right) => {
+                let (left, right) = (left.eval(), right.eval());
+                match op {
+                    "and" => Value::Bool(left.bool() && right.bool()),
+                    "or" => Value::Bool(left.bool() || right.bool()),
+                    "+" => Value::Int(left.int().wrapping_add(right.int())),
+                    "-" => Value::Int(left.int().wrapping_sub(right.int())),
+                    "*" => Value::Int(left.int().wrapping_mul(right.int())),
+                    "/" => Value::Int(left.int().wrapping_div(right.int())),
+                    "%" => Value::Int(left.int().wrapping_rem(right.int())),
+                    "is" | "==" => Value::Bool(left.int() == right.int()),
+                    "neq" | "!=" => Value::Bool(left.int() != right.int()),
+                    "lt" => Value::Bool(left.int() < right.int()),
+                    "lte" => Value::Bool(left.int() <= right.int()),
+                    "gt" => Value::Bool(left.int() > right.int()),
+                    "gte" => Value::Bool(left.int() >= right.int()),
+                    _ => panic!("unknown operator {}", op)
+                }
+            },
+        }
+    }
+}
+
+/// Compiles a passage that sets the variables and prints the expression, and returns its
+/// instructions.
+fn snippet_ops(expression: &str) -> Vec<ZOP> {
+    let mut passage = "::Start\n".to_string();
+    for &(name, value) in INT_VARIABLES.iter() {
+        passage.push_str(&format!("<<set {} to {}>>", name, value));
+    }
+    for &(name, value) in BOOL_VARIABLES.iter() {
+        passage.push_str(&format!("<<set {} to {}>>", name, value));
+    }
+    passage.push_str(&format!("<<print {}>>", expression));
+
+    let mut input = Cursor::new(passage.into_bytes());
+    let mut passages = zwreec::compiled_ops(zwreec::config::Config::default_config(), &mut input);
+    assert_eq!(passages.len(), 1);
+    passages.pop().unwrap().1
+}
+
+/// Returns the instructions of the passage `name` of a should-compile fixture.
+fn fixture_ops(fixture: &str, name: &str) -> Vec<ZOP> {
+    let mut input = File::open(Path::new(&format!("{}{}.twee", TESTFOLDER_PASS, fixture))).unwrap();
+    let passages = zwreec::compiled_ops(zwreec::config::Config::default_config(), &mut input);
+    passages.into_iter().find(|passage| passage.0 == name).expect("the fixture has no such passage").1
+}
+
+/// Runs the instructions of a passage that only computes and prints, and returns the printed
+/// text. The calls of the passage, like freeing the memory, are skipped, adding a link only
+/// counts it in global 16.
+fn simulate(code: &[ZOP]) -> String {
+    run(code, &mut |_| panic!("the simulated passage reads a key")).0
+}
+
+/// A called routine: where it returns to, the local variables of the caller and what happens
+/// with the return value.
+struct Frame {
+    pc: usize,
+    locals: [i16; 15],
+    returned: Returned,
+}
+
+enum Returned {
+    /// The value is stored in the variable, if there is one
+    Store(Option<u8>),
+
+    /// The routine of a timed `read_char` at the pc of the frame, which ends the read with 0 if
+    /// it returns true and continues it otherwise
+    Timer(u8),
+}
+
+/// Runs the instructions like `simulate`, but calls the routines of `code` and answers every
+/// `read_char` with `read`, which gets the text on the screen and returns the key or `None` to
+/// let the timer run out.
+///
+/// The interpreter announces timed input in the header, its other flags and the memory are 0 at
+/// the start. Returns the printed text and the skipped calls in the order they were made.
+fn run(code: &[ZOP], read: &mut FnMut(&str) -> Option<i16>) -> (String, Vec<String>) {
+    let mut labels: HashMap<&String, usize> = HashMap::new();
+    for (index, zop) in code.iter().enumerate() {
+        match zop {
+            &ZOP::Label{ref name} | &ZOP::Routine{ref name, ..} => { labels.insert(name, index); },
+            _ => {}
+        }
+    }
+
+    let mut variables = [0i16; 256];
+    let mut types = [Type::None as u8; 256];
+    let mut output = String::new();
+    let mut cursor = 0;
+    let mut calls: Vec<String> = vec![];
+    let mut frames: Vec<Frame> = vec![];
+    let mut memory: HashMap<i32, u8> = HashMap::new();
+    memory.insert(0x01, 0x80);
+
+    let value = |variables: &[i16; 256], operand: &Operand| match operand {
+        &Operand::Var(ref var) => variables[var.id as usize],
+        &Operand::Const(ref constant) => constant.value as i16,
+        &Operand::LargeConst(ref constant) => constant.value,
+        &Operand::BoolConst(ref constant) => constant.value as i16,
+        &Operand::StringRef(ref address) | &Operand::Pointer(ref address) => address.value as i16,
+    };
+    let type_of = |types: &[u8; 256], operand: &Operand| match operand {
+        &Operand::Var(ref var) => types[var.id as usize],
+        &Operand::BoolConst(_) => Type::Bool as u8,
+        &Operand::StringRef(_) => Type::String as u8,
+        _ => Type::Integer as u8,
+    };
+    let address = |variables: &[i16; 256], operand: &Operand| value(variables, operand) as u16 as i32;
+    let load_word = |memory: &HashMap<i32, u8>, address: i32|
+        ((*memory.get(&address).unwrap_or(&0) as u16) << 8 | *memory.get(&(address + 1)).unwrap_or(&0) as u16) as i16;
+    let store_word = |memory: &mut HashMap<i32, u8>, address: i32, word: i16| {
+        memory.insert(address, (word as u16 >> 8) as u8);
+        memory.insert(address + 1, word as u8);
+    };
+
+    let mut pc = 0;
+    while pc < code.len() {
+        let mut jump: Option<&String> = None;
+        let mut next_pc: Option<usize> = None;
+        match &code[pc] {
+            &ZOP::Call2NWithAddress{ref jump_to_label, ..} if jump_to_label == "system_add_link" => variables[16] += 1,
+            &ZOP::Call2NWithAddress{ref jump_to_label, ref address} if jump_to_label == "system_follow_link" => calls.push(address.clone()),
+            &ZOP::Routine{..} | &ZOP::Label{..} | &ZOP::Call2NWithAddress{..} => {},
+            &ZOP::SetColor{..} | &ZOP::SetTextStyle{..} => {},
+            &ZOP::Call1N{ref jump_to_label} if !labels.contains_key(jump_to_label) => calls.push(jump_to_label.clone()),
+            &ZOP::CallVNA2{ref jump_to_label, ..} if !labels.contains_key(jump_to_label) => calls.push(jump_to_label.clone()),
+            &ZOP::CallVSA2{ref jump_to_label, ..} if !labels.contains_key(jump_to_label) => calls.push(jump_to_label.clone()),
+            &ZOP::Call1N{ref jump_to_label} => {
+                frames.push(enter(&mut variables, pc + 1, &[], Returned::Store(None)));
+                jump = Some(jump_to_label);
+            },
+            &ZOP::CallVNA2{ref jump_to_label, ref arg1, ref arg2} => {
+                let args = [value(&variables, arg1), value(&variables, arg2)];
+                frames.push(enter(&mut variables, pc + 1, &args, Returned::Store(None)));
+                jump = Some(jump_to_label);
+            },
+            &ZOP::CallVSA2{ref jump_to_label, ref arg1, ref arg2, ref result} => {
+                let args = [value(&variables, arg1), value(&variables, arg2)];
+                frames.push(enter(&mut variables, pc + 1, &args, Returned::Store(Some(result.id))));
+                jump = Some(jump_to_label);
+            },
+            &ZOP::Ret{value: ref returned} => {
+                let returned = value(&variables, returned);
+                let frame = match frames.pop() {
+                    Some(frame) => frame,
+                    None => break
+                };
+                for (index, local) in frame.locals.iter().enumerate() {
+                    variables[index + 1] = *local;
+                }
+                match frame.returned {
+                    Returned::Store(Some(result)) => { variables[result as usize] = returned; next_pc = Some(frame.pc) },
+                    Returned::Store(None) => next_pc = Some(frame.pc),
+                    Returned::Timer(_) if returned == 0 => next_pc = Some(frame.pc),
+                    Returned::Timer(result) => { variables[result as usize] = 0; next_pc = Some(frame.pc + 1) },
+                }
+            },
+            &ZOP::Quit => break,
+            &ZOP::ReadCharTimer{local_var_id, ref routine, ..} => match read(&output) {
+                Some(key) => variables[local_var_id as usize] = key,
+                None => {
+                    frames.push(enter(&mut variables, pc, &[], Returned::Timer(local_var_id)));
+                    jump = Some(routine);
+                }
+            },
+            &ZOP::ReadChar{local_var_id} =>
+                variables[local_var_id as usize] = read(&output).expect("the simulated read_char has no timer"),
+            &ZOP::GetCursor{ref store_addr} => {
+                let column = output.chars().count() as i16 + 1;
+                store_word(&mut memory, address(&variables, store_addr), 1);
+                store_word(&mut memory, address(&variables, store_addr) + 2, column);
+            },
+            &ZOP::SetCursorOperand{ref col, ..} => cursor = value(&variables, col) as usize - 1,
+            &ZOP::EraseLine => output.truncate(cursor),
+            &ZOP::LoadW{ref array_address, ref index, ref variable} => {
+                let word = address(&variables, array_address) + 2 * variables[index.id as usize] as i32;
+                variables[variable.id as usize] = load_word(&memory, word);
+            },
+            &ZOP::StoreW{ref array_address, ref index, ref variable} => {
+                let word = address(&variables, array_address) + 2 * variables[index.id as usize] as i32;
+                store_word(&mut memory, word, variables[variable.id as usize]);
+            },
+            &ZOP::LoadBOperand{ref array_address, ref index, ref variable} => {
+                let byte = address(&variables, array_address) + value(&variables, index) as i32;
+                variables[variable.id as usize] = *memory.get(&byte).unwrap_or(&0) as i16;
+            },
+            &ZOP::StoreVariable{ref variable, value: ref operand} => variables[variable.id as usize] = value(&variables, operand),
+            &ZOP::SetVarType{ref variable, ref vartype} => types[variable.id as usize] = vartype.clone() as u8,
+            &ZOP::CopyVarType{ref variable, ref from} => types[variable.id as usize] = type_of(&types, from),
+            &ZOP::GetVarType{ref variable, ref result} => variables[result.id as usize] = types[variable.id as usize] as i16,
+            &ZOP::Inc{variable} => variables[variable as usize] = variables[variable as usize].wrapping_add(1),
+            &ZOP::Dec{variable} => variables[variable as usize] = variables[variable as usize].wrapping_sub(1),
+            &ZOP::AddTypes{ref operand1, ref operand2, ref save_variable, ..} => {
+                assert!(type_of(&types, operand1) != Type::String as u8 && type_of(&types, operand2) != Type::String as u8,
+                    "the simulator does not support strings");
+                variables[save_variable.id as usize] = value(&variables, operand1).wrapping_add(value(&variables, operand2));
+                types[save_variable.id as usize] = Type::Integer as u8;
+            },
+            &ZOP::Add{ref operand1, ref operand2, ref save_variable} =>
+                variables[save_variable.id as usize] = value(&variables, operand1).wrapping_add(value(&variables, operand2)),
+            &ZOP::Sub{ref operand1, ref operand2, ref save_variable} =>
+                variables[save_variable.id as usize] = value(&variables, operand1).wrapping_sub(value(&variables, operand2)),
+            &ZOP::Mul{ref operand1, ref operand2, ref save_variable} =>
+                variables[save_variable.id as usize] = value(&variables, operand1).wrapping_mul(value(&variables, operand2)),
+            &ZOP::Div{ref operand1, ref operand2, ref save_variable} =>
+                variables[save_variable.id as usize] = value(&variables, operand1).wrapping_div(value(&variables, operand2)),
+            &ZOP::Mod{ref operand1, ref operand2, ref save_variable} =>
+                variables[save_variable.id as usize] = value(&variables, operand1).wrapping_rem(value(&variables, operand2)),
+            &ZOP::And{ref operand1, ref operand2, ref save_variable} =>
+                variables[save_variable.id as usize] = value(&variables, operand1) & value(&variables, operand2),
+            &ZOP::Or{ref operand1, ref operand2, ref save_variable} =>
+                variables[save_variable.id as usize] = value(&variables, operand1) | value(&variables, operand2),
+            &ZOP::Not{ref operand, ref result} => variables[result.id as usize] = !value(&variables, operand),
+            &ZOP::JE{ref operand1, ref operand2, ref jump_to_label} =>
+                if value(&variables, operand1) == value(&variables, operand2) { jump = Some(jump_to_label) },
+            &ZOP::JNE{ref operand1, ref operand2, ref jump_to_label} =>
+                if value(&variables, operand1) != value(&variables, operand2) { jump = Some(jump_to_label) },
+            &ZOP::JL{ref operand1, ref operand2, ref jump_to_label} =>
+                if value(&variables, operand1) < value(&variables, operand2) { jump = Some(jump_to_label) },
+            &ZOP::JLE{ref operand1, ref operand2, ref jump_to_label} =>
+                if value(&variables, operand1) <= value(&variables, operand2) { jump = Some(jump_to_label) },
+            &ZOP::JG{ref operand1, ref operand2, ref jump_to_label} =>
+                if value(&variables, operand1) > value(&variables, operand2) { jump = Some(jump_to_label) },
+            &ZOP::JGE{ref operand1, ref operand2, ref jump_to_label} =>
+                if value(&variables, operand1) >= value(&variables, operand2) { jump = Some(jump_to_label) },
+            &ZOP::Jump{ref jump_to_label} => jump = Some(jump_to_label),
+            &ZOP::Print{ref text} | &ZOP::PrintOps{ref text} => output.push_str(text),
+            &ZOP::PrintNumVar{ref variable} => output.push_str(&format!("{}", variables[variable.id as usize])),
+            &ZOP::PrintVar{ref variable} => {
+                let content = variables[variable.id as usize];
+                let vartype = types[variable.id as usize];
+                if vartype == Type::Bool as u8 {
+                    output.push_str(if content != 0 { "true" } else { "false" });
+                } else {
+                    assert!(vartype != Type::String as u8, "the simulator does not support strings");
+                    output.push_str(&format!("{}", content));
+                }
+            },
+            &ZOP::Newline => output.push('\n'),
+            zop => panic!("the simulator does not support {:?}", zop)
+        }
+
+        pc = match jump {
+            Some(label) => *labels.get(label).expect("jump to a label outside of the passage"),
+            None => next_pc.unwrap_or(pc + 1)
+        };
+    }
+    (output, calls)
+}
+
+/// Calls a routine with the arguments in its first local variables and returns the frame to
+/// leave it again.
+fn enter(variables: &mut [i16; 256], pc: usize, args: &[i16], returned: Returned) -> Frame {
+    let mut locals = [0i16; 15];
+    for index in 0..15 {
+        locals[index] = variables[index + 1];
+        variables[index + 1] = if index < args.len() { args[index] } else { 0 };
+    }
+    Frame { pc: pc, locals: locals, returned: returned }
+}
+
+/// Returns the seed of this run.
+fn seed() -> u64 {
+    match env::var("PROPERTY_SEED") {
+        Ok(ref seed) if seed == "random" => time::precise_time_ns(),
+        Ok(seed) => seed.parse().expect("PROPERTY_SEED is neither a number nor 'random'"),
+        Err(_) => SEED
+    }
+}
+
+/// Checks `trees` random expressions against the reference interpreter.
+fn check_expressions(trees: usize) {
+    let seed = seed();
+    let mut random = Random::new(seed);
+
+    for tree in 0..trees {
+        let expr = if random.below(2) == 0 { Expr::int(&mut random, DEPTH) } else { Expr::bool(&mut random, DEPTH) };
+        let twee = expr.twee();
+        let expected = expr.eval().text();
+        let printed = simulate(&snippet_ops(&twee));
+        assert!(printed == expected, "<<print {}>> printed {:?} instead of {:?} (tree {} of seed {})",
+            twee, printed, expected, tree, seed);
+    }
+}
+
+#[test]
+fn expression_properties_test() {
+    check_expressions(TREES);
+}
+
+#[test]
+fn slow_expression_properties_test() {
+    if !slow_tests() {
+        return;
+    }
+    check_expressions(SLOW_TREES);
+}
+
+#[test]
+fn simulator_test() {
+    assert_eq!(simulate(&snippet_ops("$a + $b * 2")), "294");
+    assert_eq!(simulate(&snippet_ops("$d + $d")), "-25536");
+    assert_eq!(simulate(&snippet_ops("($a gt 5) and not ($f)")), "true");
+}
+
+#[test]
+fn otherwise_test() {
+    // the fallback link is only added if the conditional link is not
+    let fires = simulate(&fixture_ops("OtherwiseFires", "Start"));
+    assert!(fires.contains("Give up[1]") && !fires.contains("Unlock"), "printed {:?}", fires);
+
+    let skipped = simulate(&fixture_ops("OtherwiseSkipped", "Start"));
+    assert!(skipped.contains("Unlock the door[1]") && !skipped.contains("Give up"), "printed {:?}", skipped);
+}
+
+#[test]
+fn link_count_test() {
+    // linkcount() reads the counter of the two links added before
+    let printed = simulate(&fixture_ops("LinkCount", "Start"));
+    assert!(printed.contains("You have 2 choices."), "printed {:?}", printed);
+}
+
+#[test]
+fn countdown_test() {
+    let mut code = fixture_ops("Countdown", "Start");
+    code.extend(Zfile::new().countdown_routines());
+
+    // the timer runs out every second and the remaining seconds replace the ones before
+    let mut screens: Vec<String> = vec![];
+    let (printed, calls) = run(&code, &mut |screen| { screens.push(screen.to_string()); None });
+    assert!(printed.ends_with("cut it: 0"), "printed {:?}", printed);
+    let text = &printed[..printed.len() - 1];
+    assert_eq!(screens, vec![format!("{}3", text), format!("{}2", text), format!("{}1", text)]);
+    assert_eq!(calls.last().map(|call| &call[..]), Some("Explosion"));
+
+    // a key cancels the countdown
+    let mut reads = 0;
+    let (printed, calls) = run(&code, &mut |_| { reads += 1; Some(' ' as i16) });
+    assert_eq!(reads, 1);
+    assert_eq!(printed, format!("{}3", text));
+    assert_eq!(calls.last().map(|call| &call[..]), Some("Defused"));
+}
+
+#[test]
+fn countdown_leaves_passage_test() {
+    use zwreec::config::Config;
+
+    // the countdown leaves Start like a chosen link: only the link of Late is left, and the
+    // <<onexit>> of Start sets $left
+    let twee = "::Start\n<<onexit>><<set $left to 1>><<endonexit>>[[Stay]] <<countdown 2s \"Late\">>\n\n\
+        ::Stay\nStayed.\n\n::Late\nToo late. [[Start]]\n";
+    let machine = story::run_story(Config::default_config(), twee, "");
+    assert!(machine.transcript.contains("Too late."), "printed {:?}", machine.transcript);
+    assert_eq!(machine.ending, story::Ending::OutOfKeys);
+    assert_eq!(machine.global(16), 1);
+    assert_eq!(machine.global(25), 1);
+}
+
+#[test]
+fn runtime_error_passage_test() {
+    use zwreec::config::Config;
+
+    // the copies exhaust the heap, the error passage gets the code and the message
+    let machine = story::run_fixture(Config::default_config(), "RuntimeError", "11");
+    assert_eq!(machine.global(20), 1);
+    assert_eq!(machine.string(machine.global(21)), "MALLOC-FAIL");
+    assert!(machine.transcript.contains("The copies fill the room (error 1: MALLOC-FAIL)."), "printed {:?}", machine.transcript);
+    assert!(machine.transcript.contains("You made it out."), "printed {:?}", machine.transcript);
+    assert_eq!(machine.ending, story::Ending::Quit);
+}
+
+#[test]
+fn runtime_error_recovery_test() {
+    use zwreec::config::Config;
+
+    // the doubled texts take about 11400 words, the heap has room for them
+    let machine = story::run_fixture(Config::default_config(), "RuntimeErrorRecovery", "11");
+    assert!(machine.transcript.contains("The manual has 5760 characters."), "printed {:?}", machine.transcript);
+    assert!(!machine.transcript.contains("The printer jams"), "printed {:?}", machine.transcript);
+
+    // the heap of half_memory is about 7400 words, the error passage leads back into the story
+    let mut cfg = Config::default_config();
+    cfg.half_memory = true;
+    let machine = story::run_fixture(cfg, "RuntimeErrorRecovery", "11");
+    assert!(!machine.transcript.contains("The manual has"), "printed {:?}", machine.transcript);
+    assert!(machine.transcript.contains("The printer jams (error 1: MALLOC-FAIL)."), "printed {:?}", machine.transcript);
+    assert!(machine.transcript.contains("You sit at the desk again."), "printed {:?}", machine.transcript);
+    assert_eq!(machine.ending, story::Ending::OutOfKeys);
+    assert_eq!(machine.depth(), 3);
+
+    // without a passage named RuntimeError or tagged error-handler a dead end just quits
+    let twee = "::Start\n[[Wait]]\n\n::Wait\nNothing happens.";
+    let machine = story::run_story(Config::default_config(), twee, "1");
+    assert!(machine.transcript.contains("Nothing happens."), "printed {:?}", machine.transcript);
+    assert_eq!(machine.global(20), 0);
+    assert_eq!(machine.ending, story::Ending::Quit);
+}
+
+#[test]
+fn division_by_zero_test() {
+    use zwreec::config::Config;
+
+    let machine = story::run_fixture(Config::default_config(), "DivisionByZero", "111");
+    assert_eq!(machine.global(20), 3);
+    assert_eq!(machine.string(machine.global(21)), "DIVISION-BY-ZERO");
+    assert!(!machine.transcript.contains("Everybody gets"), "printed {:?}", machine.transcript);
+    assert_eq!(machine.transcript.matches("Nobody is left (error 3: DIVISION-BY-ZERO).").count(), 4);
+
+    // every error leaves the routines of the failed passage, only system_start, system_error_run
+    // and system_check_links are left while the story waits for the next key
+    assert_eq!(machine.ending, story::Ending::OutOfKeys);
+    assert_eq!(machine.depth(), 3);
+}
+
+#[test]
+fn out_of_bounds_test() {
+    use zwreec::config::Config;
+
+    let twee = "::Start\n<<set $name to \"Ada\">><<set $i to 3>><<print $name[$i]>>\n\n::RuntimeError [ending]\nerror <<print $error>>";
+    let machine = story::run_story(Config::default_config(), twee, "");
+    assert_eq!(machine.global(20), 4);
+    assert_eq!(machine.string(machine.global(21)), "OUT-OF-BOUNDS");
+    assert!(machine.transcript.contains("error 4"), "printed {:?}", machine.transcript);
+}
+
+#[test]
+fn maps_test() {
+    use zwreec::config::Config;
+
+    let machine = story::run_fixture(Config::default_config(), "Maps", "1");
+    let transcript = &machine.transcript;
+    assert!(transcript.contains("Bob has 10 HP."), "printed {:?}", transcript);
+    assert!(transcript.contains("Dex: 5"), "printed {:?}", transcript);
+    assert!(transcript.contains("{name: Alice, stats: {str: 3, dex: 5}}"), "printed {:?}", transcript);
+    // the missing key is the integer 0, the warning is only reported by the compiler
+    assert!(transcript.contains("}}\n0"), "printed {:?}", transcript);
+    assert!(!transcript.contains("missing"), "printed {:?}", transcript);
+    assert!(transcript.contains("Bob lives."), "printed {:?}", transcript);
+}
+
+#[test]
+fn bar_test() {
+    use zwreec::config::Config;
+
+    // 20000 * 20 does not fit into 16 bits, the bar is filled to 13 of 20 cells anyway
+    let twee = "::Start\n<<print bar(3, 10, 10)>> <<print bar(20000, 30000, 20)>> <<print bar(30000, 30000, 8)>> <<print bar(5, 0, 4)>>\n";
+    let machine = story::run_story(Config::default_config(), twee, "");
+    assert!(machine.transcript.contains("[###-------] [#############-------] [########] [----]"), "printed {:?}", machine.transcript);
+}
+
+#[test]
+fn join_test() {
+    use zwreec::config::Config;
+
+    // shuffle() keeps the order of the values, draw() takes one of them
+    let machine = story::run_fixture(Config::default_config(), "Join", "");
+    let transcript = &machine.transcript;
+    assert!(transcript.contains("You carry 1, 2, 3."), "printed {:?}", transcript);
+    assert!(["1 and 2", "1 and 3", "3 and 2", "2 and 3", "3 and 1", "2 and 1"].iter()
+        .any(|rest| transcript.contains(&format!("After the fall: {}", rest))), "printed {:?}", transcript);
+
+    // a single value has no separator and an empty deck is an empty string
+    let twee = "::Start\n<<set $deck = shuffle(7)>>[<<print join($deck, \", \")>>]\n\
+        <<set $drawn = draw($deck)>>[<<print join($deck, \", \")>>]\n";
+    let machine = story::run_story(Config::default_config(), twee, "");
+    assert!(machine.transcript.contains("[7]\n[]"), "printed {:?}", machine.transcript);
+}
+
+#[test]
+fn heap_stats_test() {
+    use zwreec::config::Config;
+
+    // H at the links prints the statistics and waits for the link
+    let mut cfg = Config::default_config();
+    cfg.heap_stats = true;
+    let machine = story::run_fixture(cfg, "HeapStats", "1H2");
+    let transcript = &machine.transcript;
+    let line = transcript.lines().find(|line| line.starts_with("heap: high water ")).expect(&format!("printed {:?}", transcript));
+    let numbers: Vec<u32> = line.split(|c: char| !c.is_digit(10)).filter(|part| !part.is_empty()).map(|part| part.parse().unwrap()).collect();
+    assert_eq!(numbers.len(), 4, "printed {:?}", line);
+    assert_eq!(line, format!("heap: high water {} of {} words, {} allocs, {} GC frees", numbers[0], numbers[1], numbers[2], numbers[3]));
+    assert!(numbers[2] > 0, "printed {:?}", line);
+    assert!(numbers[0] <= numbers[1], "printed {:?}", line);
+    assert!(transcript.contains("Press H at the links"), "printed {:?}", transcript);
+
+    // without heap_stats H is no link
+    let machine = story::run_fixture(Config::default_config(), "HeapStats", "1H2");
+    assert!(!machine.transcript.contains("heap: high water"), "printed {:?}", machine.transcript);
+}
+
+#[test]
+fn widget_arguments_test() {
+    use zwreec::config::Config;
+
+    // draw() in the argument runs once, so both prints show the same value
+    let machine = story::run_fixture(Config::default_config(), "WidgetArguments", "");
+    let transcript = &machine.transcript;
+    assert!(["1", "2", "3"].iter().any(|value| transcript.contains(&format!("Drawn {} and {}.", value, value))), "printed {:?}", transcript);
+    assert_eq!(transcript.matches(", ").count(), 1, "printed {:?}", transcript);
+}
+
+#[test]
+fn nested_map_outlives_parent_test() {
+    use zwreec::config::Config;
+
+    // mem_free at the end of Start must keep the block of the old $hero for $stats
+    let twee = "::Start\n<<set $hero to {\"name\": \"Alice\", \"stats\": {\"str\": 3, \"dex\": 4}}>>\n\
+        <<set $stats to $hero[\"stats\"]>><<set $hero to 0>>\n[[Next]]\n\n\
+        ::Next\n<<set $filler to {\"a\": 111, \"b\": 222, \"c\": 333}>>\nStr <<print $stats[\"str\"]>> dex <<print $stats[\"dex\"]>>\n";
+    let machine = story::run_story(Config::default_config(), twee, "1");
+    assert!(machine.transcript.contains("Str 3 dex 4"), "printed {:?}", machine.transcript);
+}
+
+#[test]
+fn oom_collect_test() {
+    use zwreec::config::Config;
+
+    // every copy takes more than 1/12 of the heap, fifteen of them don't fit at once
+    let twee = format!("::Start\n<<set $text to \"0123456789\">>\n{}{}Length <<print $copy.length>>\n",
+        "<<set $text to $text + $text>>\n".repeat(6), "<<set $copy to $text + $text>>\n".repeat(15));
+
+    let machine = story::run_story(Config::default_config(), &twee, "");
+    assert!(machine.transcript.contains("MALLOC-FAIL"), "printed {:?}", machine.transcript);
+
+    // the garbage collector runs behind the assignments and keeps the values of the variables
+    let mut cfg = Config::default_config();
+    cfg.oom_collect = true;
+    let machine = story::run_story(cfg, &twee, "");
+    assert!(machine.transcript.contains("Length 1280"), "printed {:?}", machine.transcript);
+    assert!(!machine.transcript.contains("MALLOC-FAIL"), "printed {:?}", machine.transcript);
+    let heap_stats_pos = Zfile::new().heap_stats_pos as usize;
+    assert!(machine.word(heap_stats_pos + 2) > 0, "mem_free freed nothing");
+
+    // the copies behind a collection take the freed blocks again instead of the rest of the
+    // heap, and the request of the last collection is cleared
+    let heap_start = Zfile::new().heap_start as u32;
+    let static_addr = machine.word(0x0e) as u16 as u32;
+    let threshold = heap_start + (static_addr - 2 - heap_start) / 4 * 3;
+    assert!((machine.global(26) as u16 as u32) < threshold, "$copy at {:#x} is behind the threshold {:#x}", machine.global(26), threshold);
+    assert!((machine.word(static_addr as usize - 2) as u16 as u32) < static_addr - 2);
+    assert_eq!(machine.word(heap_stats_pos + 4), 0);
+}
+
+#[test]
+fn clear_test() {
+    use zwreec::config::Config;
+
+    // $deck is the first variable, the length word of the deck is kept
+    let machine = story::run_fixture(Config::default_config(), "Clear", "");
+    let deck = machine.global(25) as u16 as usize;
+    assert_eq!(machine.word(deck), 4);
+    assert_eq!((1..5).map(|word| machine.word(deck + 2 * word)).collect::<Vec<i16>>(), vec![0, 0, 0, 0]);
+    assert!(machine.transcript.contains("Drawn: 0"), "printed {:?}", machine.transcript);
+
+    // a number is no array, the memory at its address is left alone
+    let twee = "::Start\n<<set $n to 64>><<clear $n>><<set $s to \"ab\" + $n>><<clear $s>>\n<<print $n>> <<print $s.length>>";
+    let zcode = zwreec::compiled_story_ops(Config::default_config(), &mut Cursor::new(twee.as_bytes())).zcode;
+    let machine = story::run_story(Config::default_config(), twee, "");
+    assert!(machine.transcript.contains("64 4"), "printed {:?}", machine.transcript);
+    assert_eq!((64..72).map(|address| machine.word(address)).collect::<Vec<i16>>(),
+        (64..72).map(|address| ((zcode[address] as u16) << 8 | zcode[address + 1] as u16) as i16).collect::<Vec<i16>>());
+    let s = machine.global(26) as u16 as usize;
+    assert_eq!((1..5).map(|word| machine.word(s + 2 * word)).collect::<Vec<i16>>(), vec![0, 0, 0, 0]);
+}
+
+#[test]
+fn malloc_zeroes_test() {
+    use zwreec::config::Config;
+
+    // mem_free fills the text of Start with -1, itoa takes the first 7 words of it again but
+    // only writes the length and one digit
+    let twee = "::Start\n<<set $junk to \"abcdefghijklmnopqrstuvwxyz\" + \"abcdefghijklmnopqrstuvwxyz\">><<set $junk to 0>>\n[[Next]]\n\n\
+        ::Next\n<<set $n to 7>><<set $s to \"\" + $n>><<print $s>>\n[[Start]]\n";
+    let machine = story::run_story(Config::default_config(), twee, "1");
+    assert!(machine.transcript.contains("7"), "printed {:?}", machine.transcript);
+    let heap_start = Zfile::new().heap_start as usize;
+    assert_eq!((0..7).map(|word| machine.word(heap_start + 2 * word)).collect::<Vec<i16>>(), vec![1, '7' as i16, 0, 0, 0, 0, 0]);
+}
+
+#[test]
+fn shuffle_test() {
+    use zwreec::config::Config;
+
+    // every value is drawn once, then the empty deck draws 0
+    let machine = story::run_fixture(Config::default_config(), "Shuffle", "");
+    let transcript = &machine.transcript;
+    let line = transcript.lines().find(|line| line.matches(", ").count() == 3).expect(&format!("printed {:?}", transcript));
+    let mut drawn: Vec<i16> = line.split(", ").map(|value| value.trim().parse().unwrap()).collect();
+    drawn.sort();
+    assert_eq!(drawn, vec![1, 2, 3, 4]);
+    assert!(transcript.contains("Empty deck: 0"), "printed {:?}", transcript);
+
+    // with auto_reshuffle the empty deck is refilled
+    let mut cfg = Config::default_config();
+    cfg.auto_reshuffle = true;
+    let machine = story::run_fixture(cfg, "Shuffle", "");
+    let transcript = &machine.transcript;
+    assert!((1..5).any(|value| transcript.contains(&format!("Empty deck: {}", value))), "printed {:?}", transcript);
+}
+
+#[test]
+fn reshuffle_test() {
+    use zwreec::config::Config;
+
+    // the confirmation stops the story in the middle of the passage, before the epilogue frees anything
+    let first = "::Start\n<<set $deck = shuffle(1, 2, 3, 4)>>\n<<confirm \"Continue?\">>\n";
+    let machine = story::run_story(Config::default_config(), first, "");
+    assert_eq!(machine.ending, story::Ending::OutOfKeys);
+    let old_deck = machine.global(25) as u16 as usize;
+    assert_eq!(machine.word(old_deck + 2), 4);
+
+    // the block of the first deck is free as soon as the second one is stored
+    let twice = "::Start\n<<set $deck = shuffle(1, 2, 3, 4)>>\n<<set $deck = shuffle(5, 6)>>\n<<confirm \"Continue?\">>\n";
+    let machine = story::run_story(Config::default_config(), twice, "");
+    assert_eq!(machine.ending, story::Ending::OutOfKeys);
+    assert!(machine.global(25) as u16 as usize != old_deck);
+    assert!(machine.word(old_deck) < 0, "the old deck at {:#x} was not freed", old_deck);
+    assert_eq!(machine.word(machine.global(25) as u16 as usize + 2), 2);
+}
+
+#[test]
+fn confirm_test() {
+    use zwreec::config::Config;
+
+    // 3 is ignored, 1 answers yes, the pause takes any key
+    let machine = story::run_fixture(Config::default_config(), "Confirm", "31x12");
+    let transcript = &machine.transcript;
+    assert!(transcript.contains("Open the door?"), "printed {:?}", transcript);
+    assert!(transcript.contains("The door creaks open."), "printed {:?}", transcript);
+    assert!(!transcript.contains("You leave it closed."), "printed {:?}", transcript);
+    assert!(transcript.contains("Continue?"), "printed {:?}", transcript);
+    assert!(transcript.contains("bold"), "printed {:?}", transcript);
+    assert_eq!(machine.ending, story::Ending::OutOfKeys);
+
+    // 2 answers no
+    let machine = story::run_fixture(Config::default_config(), "Confirm", "2x2");
+    let transcript = &machine.transcript;
+    assert!(transcript.contains("You leave it closed."), "printed {:?}", transcript);
+    assert!(!transcript.contains("The door creaks open."), "printed {:?}", transcript);
+    assert!(!transcript.contains("bold"), "printed {:?}", transcript);
+}
+
+#[test]
+fn at_test() {
+    use zwreec::config::Config;
+
+    // row 40 and column 200 are clamped to the screen of 25 lines with 80 columns, every
+    // <<endat>> moves the cursor back to where the <<at>> found it
+    let machine = story::run_fixture(Config::default_config(), "At", "");
+    let transcript = &machine.transcript;
+    assert!(transcript.contains("Score: 0"), "printed {:?}", transcript);
+    assert!(transcript.contains("clamped to the bottom right"), "printed {:?}", transcript);
+    assert!(transcript.contains("Back where we were."), "printed {:?}", transcript);
+    assert_eq!(machine.cursor.len(), 4, "moved the cursor to {:?}", machine.cursor);
+    assert_eq!(machine.cursor[0], (1, 1));
+    assert_eq!(machine.cursor[2], (25, 80));
+    assert_eq!(machine.cursor[1].0, 1);
+    assert_eq!(machine.cursor[3].0, 1);
+}
+
+#[test]
+fn embed_source_test() {
+    use zwreec::config::Config;
+
+    // the chunk behind the file length changes nothing the interpreter runs
+    let machine = story::run_fixture(Config::default_config(), "Maps", "1");
+    let mut cfg = Config::default_config();
+    cfg.embed_source = true;
+    let embedded = story::run_fixture(cfg.clone(), "Maps", "1");
+    assert_eq!(embedded.transcript, machine.transcript);
+    assert_eq!(embedded.ending, machine.ending);
+
+    let mut twee = String::new();
+    File::open(Path::new(&format!("{}Maps.twee", TESTFOLDER_PASS))).unwrap().read_to_string(&mut twee).unwrap();
+    let story = zwreec::compiled_story_ops(cfg, &mut Cursor::new(twee.as_bytes()));
+    assert_eq!(zwreec::extract_source(&story.zcode).unwrap(), twee);
+}
@@ -0,0 +1,506 @@
+//! A simulator of whole stories
+//!
+//! `run` of the property tests only knows the instructions of the passages, so it skips the
+//! runtime routines. This simulator runs all instructions `zwreec::compiled_story_ops`
+//! records, from the code in front of the first routine to the runtime routines, on the memory
+//! of the compiled story. The global variables are kept in that memory like on an interpreter,
+//! since `mem_free` reads them from there, and every routine has its own stack.
+//!
+//! The screen is only a transcript, everything printed is appended to it, no matter which
+//! window or cursor position it is printed to. The keys are taken from a list and the story
+//! stops when it waits for a key after the last one. Timed input never gets a key, the timer
+//! always runs out.
+//!
+//! The integration tests of the runtime use it as well to check what a fixture prints.
+
+extern crate zwreec;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Cursor, Read};
+use std::path::Path;
+use zwreec::backend::zcode::zfile::{Operand, ZOP};
+use zwreec::config::Config;
+use support::TESTFOLDER_PASS;
+
+/// The instructions a story may run before the simulator gives up on it
+static STEP_LIMIT: usize = 10000000;
+
+/// The number of columns of the simulated screen, the transcript is not wrapped
+static SCREEN_WIDTH: u8 = 80;
+
+/// The number of lines of the simulated screen
+static SCREEN_HEIGHT: u8 = 25;
+
+/// Why the simulated story stopped.
+#[derive(Debug, PartialEq)]
+pub enum Ending {
+    /// The story quit
+    Quit,
+
+    /// The story waited for a key after the last one
+    OutOfKeys,
+}
+
+/// A routine that was called: where it returns to, the local variables and the stack of the
+/// caller and what happens with the return value.
+struct Frame {
+    pc: usize,
+    locals: [i16; 15],
+    stack: Vec<i16>,
+    returned: Returned,
+}
+
+enum Returned {
+    /// The value is stored in the variable, if there is one
+    Store(Option<u8>),
+
+    /// The routine of a timed `read_char` at the pc of the frame, which ends the read with 0 if
+    /// it returns true and reads again otherwise
+    Timer(u8),
+}
+
+/// The state of a simulated story.
+pub struct Machine {
+    /// The dynamic memory and the rest of the story
+    memory: Vec<u8>,
+
+    /// The address of the global variables
+    globals: usize,
+
+    locals: [i16; 15],
+    stack: Vec<i16>,
+    frames: Vec<Frame>,
+
+    /// The state of the random generator, it starts with the same seed every time
+    random: u32,
+
+    /// The keys that were not read yet, the next one last
+    keys: Vec<i16>,
+
+    /// Everything the story printed
+    pub transcript: String,
+
+    /// Why the story stopped
+    pub ending: Ending,
+
+    /// The row and the column of every `set_cursor` with operands
+    pub cursor: Vec<(i16, i16)>,
+}
+
+/// Compiles the Twee source with the config and runs the story with the keys, see `Machine`.
+pub fn run_story(cfg: Config, twee: &str, keys: &str) -> Machine {
+    let mut input = Cursor::new(twee.as_bytes());
+    let story = zwreec::compiled_story_ops(cfg, &mut input);
+    Machine::new(&story.zcode, keys).run(&story.ops, &story.labels)
+}
+
+/// Runs the should-compile fixture with the config and the keys.
+pub fn run_fixture(cfg: Config, fixture: &str, keys: &str) -> Machine {
+    let mut twee = String::new();
+    File::open(Path::new(&format!("{}{}.twee", TESTFOLDER_PASS, fixture))).unwrap().read_to_string(&mut twee).unwrap();
+    run_story(cfg, &twee, keys)
+}
+
+impl Machine {
+    /// Creates a machine with the memory of the Z-Code. The interpreter announces timed input
+    /// and a screen of 25 lines with 80 columns in the header.
+    fn new(zcode: &[u8], keys: &str) -> Machine {
+        let mut memory = zcode.to_vec();
+        if memory.len() < 0x10000 {
+            memory.resize(0x10000, 0);
+        }
+        memory[0x01] |= 0x80;
+        memory[0x20] = SCREEN_HEIGHT;
+        memory[0x21] = SCREEN_WIDTH;
+        let globals = (memory[0x0c] as usize) << 8 | memory[0x0d] as usize;
+        Machine {
+            memory: memory,
+            globals: globals,
+            locals: [0; 15],
+            stack: vec![],
+            frames: vec![],
+            random: 0x5eed,
+            keys: keys.chars().rev().map(|key| key as i16).collect(),
+            transcript: String::new(),
+            ending: Ending::Quit,
+            cursor: vec![],
+        }
+    }
+
+    /// Returns the number of routines that were called and did not return yet.
+    pub fn depth(&self) -> usize {
+        self.frames.len()
+    }
+
+    /// Returns the word at the address.
+    pub fn word(&self, address: usize) -> i16 {
+        ((self.memory[address] as u16) << 8 | self.memory[address + 1] as u16) as i16
+    }
+
+    fn set_word(&mut self, address: usize, word: i16) {
+        self.memory[address] = (word as u16 >> 8) as u8;
+        self.memory[address + 1] = word as u8;
+    }
+
+    /// Returns the value of the global variable, e.g. 20 for `$error`.
+    pub fn global(&self, id: u8) -> i16 {
+        assert!(id >= 16, "variable {} is not a global", id);
+        self.word(self.globals + 2 * (id as usize - 16))
+    }
+
+    /// Returns the string written by `Zfile::write_string` or built at runtime at the address:
+    /// the number of characters and then every character in one word.
+    pub fn string(&self, address: i16) -> String {
+        let address = address as u16 as usize;
+        let length = self.word(address) as usize;
+        (0..length).map(|index| self.word(address + 2 + 2 * index) as u16 as u32)
+            .map(|code| ::std::char::from_u32(code).unwrap_or('?')).collect()
+    }
+
+    /// Reads a variable, 0 pops the stack.
+    fn read(&mut self, id: u8) -> i16 {
+        match id {
+            0 => self.stack.pop().expect("the simulated story pops an empty stack"),
+            1 ... 15 => self.locals[id as usize - 1],
+            _ => self.global(id)
+        }
+    }
+
+    /// Writes a variable, 0 pushes it.
+    fn write(&mut self, id: u8, value: i16) {
+        match id {
+            0 => self.stack.push(value),
+            1 ... 15 => self.locals[id as usize - 1] = value,
+            _ => {
+                let address = self.globals + 2 * (id as usize - 16);
+                self.set_word(address, value);
+            }
+        }
+    }
+
+    /// Writes a variable that is given by its number, like the operand of `inc`: 0 replaces
+    /// the top of the stack instead of pushing.
+    fn write_indirect(&mut self, id: u8, value: i16) {
+        if id == 0 {
+            self.stack.pop().expect("the simulated story writes to an empty stack");
+        }
+        self.write(id, value);
+    }
+
+    fn value(&mut self, operand: &Operand) -> i16 {
+        match operand {
+            &Operand::Var(ref var) => self.read(var.id),
+            &Operand::Const(ref constant) => constant.value as i16,
+            &Operand::LargeConst(ref constant) => constant.value,
+            &Operand::BoolConst(ref constant) => constant.value as i16,
+            &Operand::StringRef(ref address) | &Operand::Pointer(ref address) => address.value as i16,
+        }
+    }
+
+    fn address(&mut self, operand: &Operand) -> usize {
+        self.value(operand) as u16 as usize
+    }
+
+    /// Returns a random number in `1..range`, the generator is a simple linear congruential one.
+    fn random(&mut self, range: i16) -> i16 {
+        self.random = self.random.wrapping_mul(1103515245).wrapping_add(12345);
+        ((self.random >> 16) % range as u32) as i16 + 1
+    }
+
+    fn print(&mut self, text: &str) {
+        self.transcript.push_str(text);
+    }
+
+    /// Enters the routine at `pc` with the arguments and returns the pc of its first instruction.
+    fn call(&mut self, pc: usize, return_to: usize, args: &[i16], returned: Returned) -> usize {
+        let mut locals = [0i16; 15];
+        for (index, arg) in args.iter().enumerate() {
+            locals[index] = *arg;
+        }
+        self.frames.push(Frame {
+            pc: return_to,
+            locals: ::std::mem::replace(&mut self.locals, locals),
+            stack: ::std::mem::replace(&mut self.stack, vec![]),
+            returned: returned,
+        });
+        pc + 1
+    }
+
+    /// Leaves the current routine with the value and returns the pc to continue at.
+    fn ret(&mut self, value: i16) -> Option<usize> {
+        let frame = match self.frames.pop() {
+            Some(frame) => frame,
+            None => return None
+        };
+        self.locals = frame.locals;
+        self.stack = frame.stack;
+        match frame.returned {
+            Returned::Store(Some(result)) => self.write(result, value),
+            Returned::Store(None) => {},
+            Returned::Timer(_) if value == 0 => {},
+            Returned::Timer(result) => {
+                self.write(result, 0);
+                return Some(frame.pc + 1);
+            }
+        }
+        Some(frame.pc)
+    }
+
+    /// Runs the instructions until the story quits or runs out of keys.
+    fn run(mut self, code: &[ZOP], labels: &[(String, u16)]) -> Machine {
+        let mut indices: HashMap<&str, usize> = HashMap::new();
+        for (index, zop) in code.iter().enumerate() {
+            match zop {
+                &ZOP::Label{ref name} | &ZOP::Routine{ref name, ..} => { indices.insert(&name[..], index); },
+                _ => {}
+            }
+        }
+        // only the routines can be called by their address
+        let mut routines: HashMap<u16, usize> = HashMap::new();
+        let mut addresses: HashMap<&str, u16> = HashMap::new();
+        for &(ref name, address) in labels.iter() {
+            addresses.insert(&name[..], address);
+            if let Some(&index) = indices.get(&name[..]) {
+                if let ZOP::Routine{..} = code[index] {
+                    routines.insert(address, index);
+                }
+            }
+        }
+        let index_of = |label: &str| *indices.get(label).unwrap_or_else(|| panic!("the story has no label {}", label));
+
+        let mut pc = 0;
+        let mut steps = 0;
+        while pc < code.len() {
+            steps += 1;
+            if steps > STEP_LIMIT {
+                let start = self.transcript.len().saturating_sub(200);
+                panic!("the simulated story did not stop, it printed {:?} last", &self.transcript[start..]);
+            }
+
+            let mut next = pc + 1;
+            match &code[pc] {
+                &ZOP::Routine{..} | &ZOP::Label{..} => {},
+                &ZOP::SetColor{..} | &ZOP::SetColorVar{..} | &ZOP::SetTextStyle{..} => {},
+                &ZOP::EraseWindow{..} | &ZOP::EraseLine | &ZOP::SplitWindow{..} | &ZOP::SetWindow{..} => {},
+                &ZOP::SetCursor{..} => {},
+                &ZOP::SetCursorOperand{ref row, ref col} => {
+                    let position = (self.value(row), self.value(col));
+                    self.cursor.push(position);
+                },
+                &ZOP::SetTrueColor{ref foreground, ref background} => { self.value(foreground); self.value(background); },
+                &ZOP::SoundEffect{ref number} => { self.value(number); },
+                &ZOP::GetCursor{ref store_addr} => {
+                    let address = self.address(store_addr);
+                    let column = self.transcript.rsplit('\n').next().unwrap_or("").chars().count() as i16 + 1;
+                    self.set_word(address, 1);
+                    self.set_word(address + 2, column);
+                },
+                &ZOP::Print{ref text} | &ZOP::PrintOps{ref text} => self.print(text),
+                &ZOP::Newline => self.print("\n"),
+                &ZOP::PrintNumVar{ref variable} => {
+                    let number = self.read(variable.id);
+                    self.print(&format!("{}", number));
+                },
+                &ZOP::PrintUnicode{c} => self.transcript.push(::std::char::from_u32(c as u32).unwrap_or('?')),
+                &ZOP::PrintUnicodeVar{ref var} => {
+                    let c = self.read(var.id) as u16 as u32;
+                    self.transcript.push(::std::char::from_u32(c).unwrap_or('?'));
+                },
+                &ZOP::PrintChar{ref var} => {
+                    let c = self.read(var.id) as u16 as u32;
+                    self.transcript.push(if c == 13 { '\n' } else { ::std::char::from_u32(c).unwrap_or('?') });
+                },
+                &ZOP::Call1N{ref jump_to_label} => next = self.call(index_of(jump_to_label), pc + 1, &[], Returned::Store(None)),
+                &ZOP::Call1NVar{variable} => {
+                    let address = self.read(variable) as u16;
+                    if address != 0 {
+                        let routine = *routines.get(&address).expect("call of an address that is no routine");
+                        next = self.call(routine, pc + 1, &[], Returned::Store(None));
+                    }
+                },
+                &ZOP::Call2NWithAddress{ref jump_to_label, ref address} => {
+                    let arg = *addresses.get(&address[..]).expect("the address of an unknown routine") as i16;
+                    next = self.call(index_of(jump_to_label), pc + 1, &[arg], Returned::Store(None));
+                },
+                &ZOP::Call2NWithArg{ref jump_to_label, ref arg} => {
+                    let args = [self.value(arg)];
+                    next = self.call(index_of(jump_to_label), pc + 1, &args, Returned::Store(None));
+                },
+                &ZOP::Call2S{ref jump_to_label, ref arg, ref result} => {
+                    let args = [self.value(arg)];
+                    next = self.call(index_of(jump_to_label), pc + 1, &args, Returned::Store(Some(result.id)));
+                },
+                &ZOP::CallVNA2{ref jump_to_label, ref arg1, ref arg2} => {
+                    let args = [self.value(arg1), self.value(arg2)];
+                    next = self.call(index_of(jump_to_label), pc + 1, &args, Returned::Store(None));
+                },
+                &ZOP::CallVNA3{ref jump_to_label, ref arg1, ref arg2, ref arg3} => {
+                    let args = [self.value(arg1), self.value(arg2), self.value(arg3)];
+                    next = self.call(index_of(jump_to_label), pc + 1, &args, Returned::Store(None));
+                },
+                &ZOP::CallVSA2{ref jump_to_label, ref arg1, ref arg2, ref result} => {
+                    let args = [self.value(arg1), self.value(arg2)];
+                    next = self.call(index_of(jump_to_label), pc + 1, &args, Returned::Store(Some(result.id)));
+                },
+                &ZOP::CallVSA3{ref jump_to_label, ref arg1, ref arg2, ref arg3, ref result} => {
+                    let args = [self.value(arg1), self.value(arg2), self.value(arg3)];
+                    next = self.call(index_of(jump_to_label), pc + 1, &args, Returned::Store(Some(result.id)));
+                },
+                &ZOP::CallVS2A5{ref jump_to_label, ref arg1, ref arg2, ref arg3, ref arg4, ref arg5, ref result} => {
+                    let args = [self.value(arg1), self.value(arg2), self.value(arg3), self.value(arg4), self.value(arg5)];
+                    next = self.call(index_of(jump_to_label), pc + 1, &args, Returned::Store(Some(result.id)));
+                },
+                &ZOP::Ret{ref value} => {
+                    let value = self.value(value);
+                    match self.ret(value) {
+                        Some(pc) => next = pc,
+                        None => break
+                    }
+                },
+                &ZOP::Catch{ref result} => {
+                    let frame = self.frames.len() as i16;
+                    self.write(result.id, frame);
+                },
+                &ZOP::Throw{ref value, ref frame} => {
+                    let value = self.value(value);
+                    let frame = self.value(frame) as usize;
+                    assert!(frame > 0 && frame <= self.frames.len(), "throw to frame {} of {}", frame, self.frames.len());
+                    self.frames.truncate(frame);
+                    next = self.ret(value).unwrap();
+                },
+                &ZOP::Quit => {
+                    self.ending = Ending::Quit;
+                    break;
+                },
+                &ZOP::ReadChar{local_var_id} => match self.keys.pop() {
+                    Some(key) => self.write(local_var_id, key),
+                    None => {
+                        self.ending = Ending::OutOfKeys;
+                        break;
+                    }
+                },
+                &ZOP::ReadCharTimer{local_var_id, ref routine, ..} =>
+                    next = self.call(index_of(routine), pc, &[], Returned::Timer(local_var_id)),
+                &ZOP::Save{ref result} | &ZOP::Restore{ref result} => self.write(result.id, 0),
+                &ZOP::Piracy{ref jump_to_label} => next = index_of(jump_to_label),
+                &ZOP::StoreVariable{ref variable, ref value} => {
+                    let value = self.value(value);
+                    self.write_indirect(variable.id, value);
+                },
+                &ZOP::PushVar{ref variable} => {
+                    let value = self.read(variable.id);
+                    self.stack.push(value);
+                },
+                &ZOP::PullVar{ref variable} => {
+                    let value = self.stack.pop().expect("the simulated story pulls from an empty stack");
+                    self.write_indirect(variable.id, value);
+                },
+                &ZOP::Inc{variable} => {
+                    let value = self.read(variable).wrapping_add(1);
+                    self.write(variable, value);
+                },
+                &ZOP::Dec{variable} => {
+                    let value = self.read(variable).wrapping_sub(1);
+                    self.write(variable, value);
+                },
+                &ZOP::LoadW{ref array_address, ref index, ref variable} => {
+                    let address = self.address(array_address) + 2 * self.read(index.id) as u16 as usize;
+                    let word = self.word(address & 0xffff);
+                    self.write(variable.id, word);
+                },
+                &ZOP::StoreW{ref array_address, ref index, ref variable} => {
+                    let address = self.address(array_address) + 2 * self.read(index.id) as u16 as usize;
+                    let word = self.read(variable.id);
+                    self.set_word(address & 0xffff, word);
+                },
+                &ZOP::StoreB{ref array_address, ref index, ref variable} => {
+                    let address = self.address(array_address) + self.read(index.id) as u16 as usize;
+                    let byte = self.read(variable.id) as u8;
+                    self.memory[address & 0xffff] = byte;
+                },
+                &ZOP::StoreBOperand{ref array_address, ref index, ref operand} => {
+                    let address = self.address(array_address) + self.address(index);
+                    let byte = self.value(operand) as u8;
+                    self.memory[address & 0xffff] = byte;
+                },
+                &ZOP::LoadBOperand{ref array_address, ref index, ref variable} => {
+                    let address = self.address(array_address) + self.address(index);
+                    let byte = self.memory[address & 0xffff] as i16;
+                    self.write(variable.id, byte);
+                },
+                &ZOP::CopyTable{ref first, ref second, ref size} => {
+                    let (first, second, size) = (self.address(first), self.address(second), self.value(size));
+                    let length = (size as i32).abs() as usize;
+                    if second == 0 {
+                        for index in 0..length {
+                            self.memory[first + index] = 0;
+                        }
+                    } else if size < 0 || second < first {
+                        for index in 0..length {
+                            self.memory[second + index] = self.memory[first + index];
+                        }
+                    } else {
+                        for index in (0..length).rev() {
+                            self.memory[second + index] = self.memory[first + index];
+                        }
+                    }
+                },
+                &ZOP::Random{ref range, ref variable} => {
+                    let range = self.value(range);
+                    let number = if range > 0 { self.random(range) } else { self.random = range as u32; 0 };
+                    self.write(variable.id, number);
+                },
+                &ZOP::Add{ref operand1, ref operand2, ref save_variable} => {
+                    let value = self.value(operand1).wrapping_add(self.value(operand2));
+                    self.write(save_variable.id, value);
+                },
+                &ZOP::Sub{ref operand1, ref operand2, ref save_variable} => {
+                    let value = self.value(operand1).wrapping_sub(self.value(operand2));
+                    self.write(save_variable.id, value);
+                },
+                &ZOP::Mul{ref operand1, ref operand2, ref save_variable} => {
+                    let value = self.value(operand1).wrapping_mul(self.value(operand2));
+                    self.write(save_variable.id, value);
+                },
+                &ZOP::Div{ref operand1, ref operand2, ref save_variable} => {
+                    let (dividend, divisor) = (self.value(operand1), self.value(operand2));
+                    assert!(divisor != 0, "the simulated story divides by zero");
+                    self.write(save_variable.id, dividend.wrapping_div(divisor));
+                },
+                &ZOP::Mod{ref operand1, ref operand2, ref save_variable} => {
+                    let (dividend, divisor) = (self.value(operand1), self.value(operand2));
+                    assert!(divisor != 0, "the simulated story divides by zero");
+                    self.write(save_variable.id, dividend.wrapping_rem(divisor));
+                },
+                &ZOP::And{ref operand1, ref operand2, ref save_variable} => {
+                    let value = self.value(operand1) & self.value(operand2);
+                    self.write(save_variable.id, value);
+                },
+                &ZOP::Or{ref operand1, ref operand2, ref save_variable} => {
+                    let value = self.value(operand1) | self.value(operand2);
+                    self.write(save_variable.id, value);
+                },
+                &ZOP::Not{ref operand, ref result} => {
+                    let value = !self.value(operand);
+                    self.write(result.id, value);
+                },
+                &ZOP::JE{ref operand1, ref operand2, ref jump_to_label} =>
+                    if self.value(operand1) == self.value(operand2) { next = index_of(jump_to_label) },
+                &ZOP::JNE{ref operand1, ref operand2, ref jump_to_label} =>
+                    if self.value(operand1) != self.value(operand2) { next = index_of(jump_to_label) },
+                &ZOP::JL{ref operand1, ref operand2, ref jump_to_label} =>
+                    if self.value(operand1) < self.value(operand2) { next = index_of(jump_to_label) },
+                &ZOP::JLE{ref operand1, ref operand2, ref jump_to_label} =>
+                    if self.value(operand1) <= self.value(operand2) { next = index_of(jump_to_label) },
+                &ZOP::JG{ref operand1, ref operand2, ref jump_to_label} =>
+                    if self.value(operand1) > self.value(operand2) { next = index_of(jump_to_label) },
+                &ZOP::JGE{ref operand1, ref operand2, ref jump_to_label} =>
+                    if self.value(operand1) >= self.value(operand2) { next = index_of(jump_to_label) },
+                &ZOP::Jump{ref jump_to_label} => next = index_of(jump_to_label),
+                zop => panic!("the story simulator does not support {:?}", zop)
+            }
+            pc = next;
+        }
+        self
+    }
+}
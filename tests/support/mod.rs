@@ -0,0 +1,225 @@
+//! The plumbing shared by the test suites
+//!
+//! Compiling the fixtures and searching the Z-Code for strings is used by all suites that check
+//! the output of the compiler, the generators create inputs of any size from a seed.
+//!
+//! The generated stories only depend on their seed and size, so a test can create large inputs
+//! without checking them in. They are built for the benchmarks, but any test that needs many or
+//! long passages, like tests of the memory limits, can use them.
+//!
+//! `slow_tests()` tells whether the tests with the `slow_` prefix run, see the crate docs.
+
+extern crate zwreec;
+use std::env;
+use std::error::Error;
+use std::fs::File;
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+
+/// Checks if the slow tests are enabled with `ZWREEC_SLOW_TESTS=1`.
+///
+/// A test with the `slow_` prefix returns at once otherwise. `#[ignore]` cannot be lifted by the
+/// environment, so the tests check it themselves like the benchmarks do.
+pub fn slow_tests() -> bool {
+    env::var("ZWREEC_SLOW_TESTS").map(|value| value == "1").unwrap_or(false)
+}
+
+/// Returns the path of the `zwreec` binary that cargo builds next to the test binaries.
+///
+/// The test binaries are in the folder of the binary or in its `deps` folder.
+pub fn zwreec_binary() -> PathBuf {
+    let exe = env::current_exe().unwrap();
+    let folder = exe.parent().unwrap();
+    let name = format!("zwreec{}", env::consts::EXE_SUFFIX);
+    match folder.join(&name) {
+        ref binary if binary.is_file() => binary.clone(),
+        _ => folder.parent().unwrap().join(&name)
+    }
+}
+
+/// The folder where integration tests are stored that should work
+pub static TESTFOLDER_PASS: &'static str = "./tests/integration/should-compile/";
+
+/// The folder where integration tests are stored that should fail
+pub static TESTFOLDER_FAIL: &'static str = "./tests/integration/should-fail/";
+
+/// Compile a file into a buffer and checks if the compilation finished without any errors
+pub fn test_compile(input_filename: String) {
+    test_compile_with_cfg(input_filename, zwreec::config::Config::default_config());
+}
+
+/// Compile a file with the specified config into a buffer and checks if the compilation finished
+/// without any errors. Returns the generated Z-Code.
+pub fn test_compile_with_cfg(input_filename: String, cfg: zwreec::config::Config) -> Vec<u8> {
+    let path = Path::new(&input_filename);
+    let mut input = match File::open(path) {
+        Err(why) => {
+            panic!("Couldn't open {}: {}",
+                           path.display(), Error::description(&why))
+        },
+        Ok(file) => {
+            file
+        }
+    };
+
+    let vec: Vec<u8> = vec![];
+    let mut output = Cursor::new(vec);
+
+    zwreec::compile(cfg, &mut input, &mut output);
+
+    let outvec = output.into_inner();
+
+    // check that the z-code version is 8
+    // this ensures that at least some z-code was emitted
+    assert_eq!(0x08, outvec[0]);
+
+    outvec
+}
+
+/// Checks if the Z-Code contains the utf16 string as written by `Zfile::write_string`
+pub fn contains_utf16_string(zcode: &Vec<u8>, string: &str) -> bool {
+    count_utf16_string(zcode, string) > 0
+}
+
+/// Counts how often the utf16 string as written by `Zfile::write_string` occurs in the Z-Code
+pub fn count_utf16_string(zcode: &Vec<u8>, string: &str) -> usize {
+    let chars: Vec<u16> = string.chars().map(|c| c as u16).collect();
+    let mut bytes: Vec<u8> = vec![(chars.len() >> 8) as u8, (chars.len() & 0xff) as u8];
+    for c in chars.iter() {
+        bytes.push((c >> 8) as u8);
+        bytes.push((c & 0xff) as u8);
+    }
+    zcode.windows(bytes.len()).filter(|window| *window == &bytes[..]).count()
+}
+
+/// Returns the tokens of all nodes in the AST of a file, parents before their childs
+pub fn ast_tokens(input_filename: String, cfg: zwreec::config::Config) -> Vec<zwreec::frontend::lexer::Token> {
+    use zwreec::frontend::ast::ASTNode;
+
+    fn collect(node: &ASTNode, tokens: &mut Vec<zwreec::frontend::lexer::Token>) {
+        let (category, childs) = match node {
+            &ASTNode::Default(ref node) => (&node.category, &node.childs),
+            &ASTNode::Passage(ref node) => (&node.category, &node.childs),
+        };
+        tokens.push(category.clone());
+        for child in childs.iter() {
+            collect(child, tokens);
+        }
+    }
+
+    let mut input = File::open(Path::new(&input_filename)).unwrap();
+    let mut tokens = vec![];
+    for node in zwreec::frontend_ast(cfg, &mut input).iter() {
+        collect(node, &mut tokens);
+    }
+    tokens
+}
+
+/// A xorshift generator, so that a seed always creates the same output.
+pub struct Random {
+    state: u64,
+}
+
+impl Random {
+    /// Creates a generator, a seed of 0 is replaced by 1.
+    pub fn new(seed: u64) -> Random {
+        Random { state: if seed == 0 { 1 } else { seed } }
+    }
+
+    /// Returns a number below `range`.
+    pub fn below(&mut self, range: u64) -> u64 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        self.state % range
+    }
+
+    /// Returns one of the choices.
+    pub fn pick<T: Clone>(&mut self, choices: &[T]) -> T {
+        let index = self.below(choices.len() as u64) as usize;
+        choices[index].clone()
+    }
+}
+
+/// The words of the generated text
+static WORDS: [&'static str; 24] = [
+    "the", "a", "old", "lantern", "door", "opens", "slowly", "and", "you", "hear", "wind",
+    "behind", "stone", "walls", "of", "castle", "light", "falls", "on", "dusty", "floor",
+    "somewhere", "water", "drips"];
+
+/// Returns a sentence of 4 to 15 words with a capital letter and a full stop.
+pub fn sentence(random: &mut Random) -> String {
+    let count = 4 + random.below(12);
+    let words: Vec<&str> = (0..count).map(|_| random.pick(&WORDS)).collect();
+    let text = words.join(" ");
+    format!("{}{}.", &text[..1].to_uppercase(), &text[1..])
+}
+
+/// Returns the name of the passage with the number, the first one is `Start`.
+pub fn passage_name(number: usize) -> String {
+    if number == 0 { "Start".to_string() } else { format!("Room{}", number) }
+}
+
+/// Generates a story of mostly text: `passages` passages with `paragraphs` paragraphs of a few
+/// sentences each and a link to the next passage.
+pub fn text_heavy(seed: u64, passages: usize, paragraphs: usize) -> String {
+    let mut random = Random::new(seed);
+    let mut story = String::new();
+    for number in 0..passages {
+        story.push_str(&format!("::{}\n", passage_name(number)));
+        for _ in 0..paragraphs {
+            for _ in 0..1 + random.below(5) {
+                story.push_str(&sentence(&mut random));
+                story.push(' ');
+            }
+            story.push_str("\n\n");
+        }
+        story.push_str(&format!("[[Go on|{}]]\n\n", passage_name((number + 1) % passages)));
+    }
+    story
+}
+
+/// Generates a story of mostly logic: `passages` passages with `expressions` assignments and
+/// `<<if>>` blocks each over the same ten variables, which are initialized in `Start`.
+pub fn logic_heavy(seed: u64, passages: usize, expressions: usize) -> String {
+    let mut random = Random::new(seed);
+    let mut story = String::new();
+    for number in 0..passages {
+        story.push_str(&format!("::{}\n", passage_name(number)));
+        if number == 0 {
+            for variable in 0..10 {
+                story.push_str(&format!("<<set $v{} to {}>>", variable, variable));
+            }
+            story.push('\n');
+        }
+        for _ in 0..expressions {
+            let (a, b, c) = (random.below(10), random.below(10), random.below(10));
+            let constant = 1 + random.below(20);
+            match random.below(3) {
+                0 => story.push_str(&format!("<<set $v{} to ($v{} + {}) % 1000>>", a, b, constant)),
+                1 => story.push_str(&format!("<<if $v{} gt $v{} and $v{} lt {}>>Higher<<else>>Lower<<endif>>", a, b, c, constant * 10)),
+                _ => story.push_str(&format!("<<print $v{} * {} - $v{}>> ", a, constant, b)),
+            }
+            story.push('\n');
+        }
+        story.push_str(&format!("[[Go on|{}]]\n\n", passage_name((number + 1) % passages)));
+    }
+    story
+}
+
+/// Generates a story of mostly links: `passages` passages with `links` links each to random
+/// other passages, every passage is linked from the one before.
+pub fn link_heavy(seed: u64, passages: usize, links: usize) -> String {
+    let mut random = Random::new(seed);
+    let mut story = String::new();
+    for number in 0..passages {
+        story.push_str(&format!("::{}\nYou are in room {}.\n", passage_name(number), number));
+        story.push_str(&format!("[[Next room|{}]]\n", passage_name((number + 1) % passages)));
+        for link in 1..links {
+            let target = random.below(passages as u64) as usize;
+            story.push_str(&format!("[[Door {}|{}]]\n", link, passage_name(target)));
+        }
+        story.push('\n');
+    }
+    story
+}
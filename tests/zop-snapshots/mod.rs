@@ -0,0 +1,96 @@
+//! Snapshot tests for the instructions generated for single constructs
+//!
+//! Every `<construct>.twee` in this folder exercises one construct of the Twee language. The
+//! instructions generated for its passages are compared against `<construct>.txt`, which lists
+//! every passage followed by its instructions, one `ZOP` per line in its `Debug` form.
+//!
+//! Run the tests with `UPDATE_ZOP_SNAPSHOTS=1` to write all snapshots again after an intended
+//! change of the code generation, or to write the snapshot of a new construct. The snapshots
+//! are checked in, a construct without one fails the test.
+
+extern crate zwreec;
+use std::env;
+use std::fs;
+use std::fs::File;
+use std::io::{Cursor, Read, Write};
+use std::path::Path;
+
+/// The folder with the constructs and their snapshots
+static SNAPSHOTS: &'static str = "./tests/zop-snapshots/";
+
+/// Compiles the construct and renders the instructions of all its passages.
+fn render(construct: &str) -> String {
+    let mut twee = String::new();
+    File::open(Path::new(&format!("{}{}.twee", SNAPSHOTS, construct))).unwrap().read_to_string(&mut twee).unwrap();
+
+    let mut input = Cursor::new(twee.into_bytes());
+    let mut rendered = String::new();
+    for (name, ops) in zwreec::compiled_ops(zwreec::config::Config::default_config(), &mut input) {
+        rendered.push_str(&format!("::{}\n", name));
+        for op in ops {
+            rendered.push_str(&format!("{:?}\n", op));
+        }
+    }
+    rendered
+}
+
+/// Returns the names of all constructs without the `.twee` extension.
+fn constructs() -> Vec<String> {
+    let mut names: Vec<String> = fs::read_dir(SNAPSHOTS).unwrap().filter_map(|entry| {
+        let path = entry.unwrap().path();
+        match path.extension() {
+            Some(extension) if extension == "twee" => path.file_stem().map(|stem| stem.to_string_lossy().into_owned()),
+            _ => None
+        }
+    }).collect();
+    names.sort();
+    names
+}
+
+/// Describes the first line where `actual` differs from `expected`.
+fn first_difference(expected: &str, actual: &str) -> String {
+    let expected_lines: Vec<&str> = expected.lines().collect();
+    let actual_lines: Vec<&str> = actual.lines().collect();
+    let mut line = 0;
+    while line < expected_lines.len() && line < actual_lines.len() && expected_lines[line] == actual_lines[line] {
+        line += 1;
+    }
+
+    let show = |lines: &Vec<&str>| if line < lines.len() { lines[line].to_string() } else { "<end of snapshot>".to_string() };
+    format!("line {}:\n  - {}\n  + {}", line + 1, show(&expected_lines), show(&actual_lines))
+}
+
+#[test]
+fn zop_snapshots_test() {
+    let update = env::var("UPDATE_ZOP_SNAPSHOTS").map(|value| value == "1").unwrap_or(false);
+
+    let mut failed: Vec<String> = vec![];
+    for construct in constructs() {
+        let snapshot_path = format!("{}{}.txt", SNAPSHOTS, construct);
+        let rendered = render(&construct);
+
+        let mut snapshot = String::new();
+        let exists = match File::open(Path::new(&snapshot_path)) {
+            Ok(mut file) => { file.read_to_string(&mut snapshot).unwrap(); true },
+            Err(_) => false
+        };
+        if update {
+            File::create(Path::new(&snapshot_path)).unwrap().write_all(rendered.as_bytes()).unwrap();
+            if !exists {
+                println!("wrote the new snapshot {}", snapshot_path);
+            }
+            continue;
+        }
+        if !exists {
+            failed.push(format!("{} has no snapshot {}", construct, snapshot_path));
+            continue;
+        }
+
+        if rendered != snapshot {
+            failed.push(format!("{} changed at {}", construct, first_difference(&snapshot, &rendered)));
+        }
+    }
+
+    assert!(failed.is_empty(), "the instructions of {} construct(s) changed or have no snapshot, run with UPDATE_ZOP_SNAPSHOTS=1 if this is intended\n{}",
+        failed.len(), failed.join("\n"));
+}